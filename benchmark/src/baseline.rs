@@ -0,0 +1,112 @@
+use crate::report::Report;
+use serde::Deserialize;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// The parts of an earlier JSON report a run is compared against.
+#[derive(Debug, Deserialize)]
+pub struct Baseline {
+    scenarios: Vec<Scenario>,
+    #[serde(default)]
+    sweep: Vec<SweepStep>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Scenario {
+    scenario: String,
+    mode: String,
+    ops_per_sec: f64,
+    p99_us: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct SweepStep {
+    values: u32,
+    ops_per_sec: f64,
+    p99_us: u64,
+}
+
+impl Baseline {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let file = std::fs::File::open(path)?;
+        serde_json::from_reader(io::BufReader::new(file))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Prints each measurement next to its baseline and returns how many
+    /// lost more than `threshold` percent of their throughput or gained more
+    /// than that on their p99. Measurements the baseline lacks are skipped.
+    pub fn compare(
+        &self,
+        report: &Report,
+        threshold: f64,
+        out: &mut impl Write,
+    ) -> io::Result<usize> {
+        let mut rows = Vec::new();
+        for stats in &report.scenarios {
+            let found = self
+                .scenarios
+                .iter()
+                .find(|old| old.scenario == stats.scenario && old.mode == stats.mode);
+            if let Some(old) = found {
+                let name = format!("{} ({})", stats.scenario, stats.mode);
+                rows.push((
+                    name,
+                    old.ops_per_sec,
+                    stats.ops_per_sec,
+                    old.p99_us,
+                    stats.percentiles.p99_us,
+                ));
+            }
+        }
+        for step in &report.sweep {
+            if let Some(old) = self.sweep.iter().find(|old| old.values == step.values) {
+                let name = format!("sweep {} values", step.values);
+                rows.push((
+                    name,
+                    old.ops_per_sec,
+                    step.ops_per_sec,
+                    old.p99_us,
+                    step.percentiles.p99_us,
+                ));
+            }
+        }
+
+        writeln!(out, "Baseline Comparison (threshold {threshold}%):")?;
+        writeln!(
+            out,
+            "  {:<28} {:>12} {:>12} {:>8} {:>10} {:>10} {:>8}",
+            "measurement", "base ops/s", "ops/s", "change", "base p99", "p99", "change"
+        )?;
+        let mut regressions = 0;
+        for (name, old_throughput, throughput, old_p99, p99) in rows {
+            let throughput_change = change(old_throughput, throughput);
+            let p99_change = change(old_p99 as f64, p99 as f64);
+            let regressed = throughput_change < -threshold || p99_change > threshold;
+            regressions += regressed as usize;
+            writeln!(
+                out,
+                "  {name:<28} {old_throughput:>12.0} {throughput:>12.0} {throughput_change:>+7.1}% {old_p99:>10} {p99:>10} {p99_change:>+7.1}%{}",
+                if regressed { "  REGRESSED" } else { "" }
+            )?;
+        }
+        writeln!(
+            out,
+            "  {}",
+            match regressions {
+                0 => "no regressions".to_string(),
+                n => format!("{n} regressed"),
+            }
+        )?;
+        Ok(regressions)
+    }
+}
+
+/// How far `new` is from `old`, in percent of `old`.
+fn change(old: f64, new: f64) -> f64 {
+    if old > 0.0 {
+        (new - old) / old * 100.0
+    } else {
+        0.0
+    }
+}
@@ -0,0 +1,105 @@
+/// Values below this are counted exactly; every power of two above it is
+/// split into half as many equal steps, keeping 3 significant digits.
+const SUB_BUCKETS: u64 = 2048;
+const SUB_BUCKET_BITS: u32 = SUB_BUCKETS.trailing_zeros();
+const HALF: u64 = SUB_BUCKETS / 2;
+
+/// The largest latency tracked, about 19 hours in microseconds; longer ones
+/// are counted as this.
+const MAX_VALUE: u64 = (1 << 36) - 1;
+
+/// An HDR histogram of latencies in microseconds: fixed memory however many
+/// are recorded, with every quantile within 0.1% of the exact one.
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+    sum: u64,
+    min: u64,
+    max: u64,
+}
+
+fn index(value: u64) -> usize {
+    let bucket = (64 - (value | (SUB_BUCKETS - 1)).leading_zeros()) - SUB_BUCKET_BITS;
+    (u64::from(bucket) * HALF + (value >> bucket)) as usize
+}
+
+/// The largest value counted at `index`.
+fn highest_at(index: usize) -> u64 {
+    let index = index as u64;
+    let bucket = (index / HALF).saturating_sub(1);
+    let sub = index - bucket * HALF;
+    (((sub + 1) << bucket) - 1).min(MAX_VALUE)
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            counts: vec![0; index(MAX_VALUE) + 1],
+            total: 0,
+            sum: 0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&mut self, micros: u64) {
+        let micros = micros.min(MAX_VALUE);
+        self.counts[index(micros)] += 1;
+        self.total += 1;
+        self.sum += micros;
+        self.min = self.min.min(micros);
+        self.max = self.max.max(micros);
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.total += other.total;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    pub fn len(&self) -> u64 {
+        self.total
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.total == 0
+    }
+
+    pub fn min(&self) -> u64 {
+        if self.is_empty() {
+            0
+        } else {
+            self.min
+        }
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    pub fn mean(&self) -> u64 {
+        self.sum.checked_div(self.total).unwrap_or(0)
+    }
+
+    /// The latency at or under which `percentile` percent of those recorded
+    /// fall, and how many that is.
+    pub fn percentile(&self, percentile: f64) -> (u64, u64) {
+        let wanted =
+            ((percentile / 100.0 * self.total as f64).ceil() as u64).clamp(1, self.total.max(1));
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= wanted {
+                return (highest_at(index).min(self.max), seen);
+            }
+        }
+        (self.max, self.total)
+    }
+}
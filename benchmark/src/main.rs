@@ -1,17 +1,256 @@
+mod baseline;
+mod histogram;
+mod report;
+mod soak;
+mod stream;
+
+use baseline::Baseline;
+use clap::{Parser, ValueEnum};
+use histogram::Histogram;
+use map8x32_protocol::{DEFAULT_SOCKET_PATH, OP_DELETE_BY_KEY, OP_GET, OP_LIST_ALL, OP_SET};
+use report::{Format, Percentiles, Report, Stats, SweepStep};
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::PathBuf;
 use std::time::{Duration, Instant};
+use stream::{Stream, Target};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::UnixStream;
 use tokio::time::timeout;
 
-const SOCKET_PATH: &str = "/tmp/map8x32.sock";
-const OP_SET: u8 = 1;
-const OP_GET: u8 = 2;
-const OP_DELETE_BY_KEY: u8 = 3;
-const OP_DELETE_ALL: u8 = 4;
-const OP_LIST_ALL: u8 = 5;
+#[derive(Debug, Parser)]
+#[command(
+    name = "map8x32-benchmark",
+    about = "Load generator and latency report for a Map8x32 server"
+)]
+struct Args {
+    #[arg(long, default_value = DEFAULT_SOCKET_PATH, help = "Unix socket to connect to")]
+    socket: PathBuf,
+    #[arg(
+        long,
+        help = "Connect over TCP to this address instead of the Unix socket"
+    )]
+    tcp: Option<String>,
+    #[arg(
+        long,
+        default_value_t = 50_000,
+        help = "Operations in each of the SET, GET and DELETE scenarios"
+    )]
+    iterations: u32,
+    #[arg(long, default_value_t = 50, help = "Operations in the LIST scenario")]
+    list_iterations: u32,
+    #[arg(
+        long,
+        default_value_t = 20,
+        help = "Workers in the concurrent scenario"
+    )]
+    workers: u32,
+    #[arg(
+        long,
+        default_value_t = 100,
+        help = "SET+GET pairs each concurrent worker sends"
+    )]
+    ops_per_worker: u32,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_parser = clap::value_parser!(u32).range(1..),
+        default_values_t = [1, 10, 100, 1_000, 10_000, 100_000],
+        help = "Value counts the GET response-size sweep reads back"
+    )]
+    sweep_sizes: Vec<u32>,
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        help = "Scenarios to run, comma-separated [default: all but soak]"
+    )]
+    scenarios: Vec<Scenario>,
+    #[arg(
+        long,
+        help = "Also run the scenarios with a new connection per operation, and compare"
+    )]
+    connect_per_op: bool,
+    #[arg(
+        long,
+        value_enum,
+        help = "Write every result as a JSON or CSV report, to stdout in place of the text one unless --output-file is given"
+    )]
+    output: Option<Format>,
+    #[arg(
+        long,
+        requires = "output",
+        help = "Write the --output report to this file instead of stdout"
+    )]
+    output_file: Option<PathBuf>,
+    #[arg(
+        long,
+        value_parser = parse_duration,
+        default_value = "0s",
+        help = "Run each scenario this long first, without recording, e.g. 5s"
+    )]
+    warmup: Duration,
+    #[arg(
+        long,
+        value_parser = parse_duration,
+        help = "Run each scenario for this long instead of a fixed number of operations, e.g. 30s"
+    )]
+    duration: Option<Duration>,
+    #[arg(
+        long,
+        help = "Compare against this earlier --output json report, exiting with status 2 on a regression"
+    )]
+    baseline: Option<PathBuf>,
+    #[arg(
+        long,
+        default_value_t = 10.0,
+        help = "Percent of throughput lost or p99 gained over the baseline that counts as a regression"
+    )]
+    regression_threshold: f64,
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(u32).range(1..=1_000_000),
+        help = "Send at this many ops/sec, shared by the concurrent workers, timing each from its scheduled send"
+    )]
+    rate: Option<u32>,
+    #[arg(
+        long,
+        value_parser = parse_duration,
+        default_value = "1h",
+        help = "How long the soak scenario runs"
+    )]
+    soak_duration: Duration,
+    #[arg(
+        long,
+        default_value_t = 1_000,
+        value_parser = clap::value_parser!(u32).range(1..=1_000_000),
+        help = "Operations per second the soak scenario sends"
+    )]
+    soak_rate: u32,
+    #[arg(
+        long,
+        value_parser = parse_interval,
+        default_value = "30s",
+        help = "How often the soak scenario samples the server's memory"
+    )]
+    soak_sample_interval: Duration,
+    #[arg(
+        long,
+        default_value_t = 20.0,
+        help = "Percent the soak's memory may grow from its first half to its second before it fails"
+    )]
+    soak_max_growth: f64,
+}
+
+/// Parses a number with an `ms`, `s`, `m` or `h` suffix; a bare number is
+/// seconds.
+fn parse_duration(text: &str) -> Result<Duration, String> {
+    let split = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let scale = match unit {
+        "ms" => 0.001,
+        "" | "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => return Err(format!("unknown unit {unit:?}, expected ms, s, m or h")),
+    };
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("{text:?} is not a duration"))?;
+    Duration::try_from_secs_f64(number * scale).map_err(|e| e.to_string())
+}
+
+/// A [`parse_duration`] that must not be zero.
+fn parse_interval(text: &str) -> Result<Duration, String> {
+    match parse_duration(text)? {
+        Duration::ZERO => Err("must be longer than zero".to_string()),
+        interval => Ok(interval),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Scenario {
+    Consistency,
+    Set,
+    Get,
+    Delete,
+    List,
+    Concurrent,
+    Sweep,
+    /// Not part of the default set, since it runs for `--soak-duration`.
+    Soak,
+}
+
+/// How a scenario's operations reach the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// One connection per worker, reused for every operation.
+    Persistent,
+    /// A fresh connection for every operation, so connection setup is
+    /// part of each measurement.
+    ConnectPerOp,
+}
+
+impl Mode {
+    fn name(self) -> &'static str {
+        match self {
+            Mode::Persistent => "persistent connections",
+            Mode::ConnectPerOp => "connect per operation",
+        }
+    }
 
-async fn send_op(op: u8, key: u8, value: u32) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let mut stream = UnixStream::connect(SOCKET_PATH).await?;
+    /// How reports name the mode.
+    fn id(self) -> &'static str {
+        match self {
+            Mode::Persistent => "persistent",
+            Mode::ConnectPerOp => "connect-per-op",
+        }
+    }
+}
+
+/// A worker's way to the server: in persistent mode the stream is kept
+/// between operations, and dropped after an error so the next operation
+/// starts clean on a new one.
+struct Connection {
+    target: Target,
+    mode: Mode,
+    stream: Option<Stream>,
+}
+
+impl Connection {
+    fn new(target: &Target, mode: Mode) -> Self {
+        Connection {
+            target: target.clone(),
+            mode,
+            stream: None,
+        }
+    }
+
+    async fn send_op(
+        &mut self,
+        op: u8,
+        key: u8,
+        value: u32,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut stream = match self.stream.take() {
+            Some(stream) => stream,
+            None => self.target.connect().await?,
+        };
+        let response = send_op(&mut stream, op, key, value).await?;
+        if self.mode == Mode::Persistent {
+            self.stream = Some(stream);
+        }
+        Ok(response)
+    }
+}
+
+async fn send_op(
+    stream: &mut Stream,
+    op: u8,
+    key: u8,
+    value: u32,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
     let mut buf = [0u8; 6];
     buf[0] = op;
     buf[1] = key;
@@ -53,87 +292,125 @@ async fn send_op(op: u8, key: u8, value: u32) -> Result<Vec<u8>, Box<dyn std::er
     Ok(response)
 }
 
-async fn set_test(iterations: u32) -> (u32, Vec<u64>) {
-    let mut successes = 0;
-    let mut times = Vec::new();
-
-    for i in 0..iterations {
-        let op_start = Instant::now();
-        if let Ok(resp) = send_op(OP_SET, (i % 256) as u8, i).await {
-            times.push(op_start.elapsed().as_micros() as u64);
-            if !resp.is_empty() && resp[0] == 1 {
-                successes += 1;
-            }
-        }
+/// The timer only fires to the millisecond, so the last of the wait is spent
+/// yielding, or every fixed-rate send would start up to a millisecond late.
+async fn wait_until(deadline: Instant) {
+    const TIMER_RESOLUTION: Duration = Duration::from_millis(1);
+    if let Some(early) = deadline.checked_sub(TIMER_RESOLUTION) {
+        tokio::time::sleep_until(early.into()).await;
     }
-
-    times.sort();
-    (successes, times)
-}
-
-async fn get_test(iterations: u32) -> (u32, Vec<u64>) {
-    let mut successes = 0;
-    let mut times = Vec::new();
-
-    for i in 0..iterations {
-        let op_start = Instant::now();
-        if let Ok(resp) = send_op(OP_GET, (i % 256) as u8, 0).await {
-            times.push(op_start.elapsed().as_micros() as u64);
-            if !resp.is_empty() {
-                successes += 1;
-            }
-        }
+    while Instant::now() < deadline {
+        tokio::task::yield_now().await;
     }
+}
 
-    times.sort();
-    (successes, times)
+/// A scenario's successes, latencies and how long it took.
+type Measured = (u32, Histogram, Duration);
+
+/// How long a scenario's loop runs: a warmup whose samples are thrown away,
+/// then either a fixed number of operations or a fixed time.
+#[derive(Debug, Clone, Copy)]
+struct Run {
+    warmup: Duration,
+    duration: Option<Duration>,
+    ops: u32,
+    /// In fixed-rate mode, the gap between scheduled sends.
+    every: Option<Duration>,
 }
 
-async fn delete_test(iterations: u32) -> (u32, Vec<u64>) {
-    let mut successes = 0;
-    let mut times = Vec::new();
+impl Run {
+    /// Calls `op` with a counter that keeps rising from the warmup into the
+    /// measured run, timing only the latter, and returns its successes,
+    /// latencies and wall-clock length. `op` returns whether the server
+    /// reported success, or `None` when no answer came back, which is not
+    /// recorded.
+    ///
+    /// At a fixed rate each operation has a send time set in advance, and
+    /// its latency runs from then rather than from when the previous one
+    /// finished, so time spent queued behind a stall is counted.
+    async fn measure(self, mut op: impl AsyncFnMut(u32) -> Option<bool>) -> Measured {
+        let mut i = 0u32;
+        let warming = Instant::now();
+        while warming.elapsed() < self.warmup {
+            op(i).await;
+            i = i.wrapping_add(1);
+        }
 
-    for i in 0..iterations {
-        let op_start = Instant::now();
-        if let Ok(resp) = send_op(OP_DELETE_BY_KEY, (i % 256) as u8, 0).await {
-            times.push(op_start.elapsed().as_micros() as u64);
-            if !resp.is_empty() {
-                successes += 1;
+        let mut successes = 0;
+        let mut times = Histogram::default();
+        let (start, mut done) = (Instant::now(), 0);
+        while match self.duration {
+            Some(duration) => start.elapsed() < duration,
+            None => done < self.ops,
+        } {
+            let op_start = match self.every {
+                Some(every) => {
+                    let intended = start + every * done;
+                    wait_until(intended).await;
+                    intended
+                }
+                None => Instant::now(),
+            };
+            if let Some(succeeded) = op(i).await {
+                times.record(op_start.elapsed().as_micros() as u64);
+                successes += succeeded as u32;
             }
+            i = i.wrapping_add(1);
+            done += 1;
         }
+        (successes, times, start.elapsed())
     }
+}
 
-    times.sort();
-    (successes, times)
+async fn set_test(target: &Target, mode: Mode, run: Run) -> Measured {
+    let mut connection = Connection::new(target, mode);
+    run.measure(async |i| {
+        let resp = connection.send_op(OP_SET, (i % 256) as u8, i).await.ok()?;
+        Some(!resp.is_empty() && resp[0] == 1)
+    })
+    .await
 }
 
-async fn list_test(iterations: u32) -> (u32, Vec<u64>) {
-    let mut successes = 0;
-    let mut times = Vec::new();
+async fn get_test(target: &Target, mode: Mode, run: Run) -> Measured {
+    let mut connection = Connection::new(target, mode);
+    run.measure(async |i| {
+        let resp = connection.send_op(OP_GET, (i % 256) as u8, 0).await.ok()?;
+        Some(!resp.is_empty())
+    })
+    .await
+}
 
-    for _ in 0..iterations {
-        let op_start = Instant::now();
-        if let Ok(resp) = send_op(OP_LIST_ALL, 0, 0).await {
-            times.push(op_start.elapsed().as_micros() as u64);
-            if !resp.is_empty() && resp[0] == 1 {
-                successes += 1;
-            }
-        }
-    }
+async fn delete_test(target: &Target, mode: Mode, run: Run) -> Measured {
+    let mut connection = Connection::new(target, mode);
+    run.measure(async |i| {
+        let resp = connection
+            .send_op(OP_DELETE_BY_KEY, (i % 256) as u8, 0)
+            .await
+            .ok()?;
+        Some(!resp.is_empty())
+    })
+    .await
+}
 
-    times.sort();
-    (successes, times)
+async fn list_test(target: &Target, mode: Mode, run: Run) -> Measured {
+    let mut connection = Connection::new(target, mode);
+    run.measure(async |_| {
+        let resp = connection.send_op(OP_LIST_ALL, 0, 0).await.ok()?;
+        Some(!resp.is_empty() && resp[0] == 1)
+    })
+    .await
 }
 
-async fn consistency_test() -> bool {
+async fn consistency_test(target: &Target) -> bool {
+    let mut connection = Connection::new(target, Mode::Persistent);
     let key = 42u8;
     let value = 12345u32;
 
-    if send_op(OP_SET, key, value).await.is_err() {
+    if connection.send_op(OP_SET, key, value).await.is_err() {
         return false;
     }
 
-    let get_resp = send_op(OP_GET, key, 0).await;
+    let get_resp = connection.send_op(OP_GET, key, 0).await;
     if get_resp.is_err() {
         return false;
     }
@@ -152,11 +429,11 @@ async fn consistency_test() -> bool {
         return false;
     }
 
-    if send_op(OP_DELETE_BY_KEY, key, 0).await.is_err() {
+    if connection.send_op(OP_DELETE_BY_KEY, key, 0).await.is_err() {
         return false;
     }
 
-    let get_resp2 = send_op(OP_GET, key, 0).await;
+    let get_resp2 = connection.send_op(OP_GET, key, 0).await;
     if get_resp2.is_err() {
         return false;
     }
@@ -164,97 +441,382 @@ async fn consistency_test() -> bool {
     resp2.is_empty() || resp2[0] == 0
 }
 
-async fn concurrent_test(workers: u32, ops_per_worker: u32) -> (u32, Vec<u64>) {
+/// Runs `workers` at once, each with `run` to itself, timing SET+GET pairs.
+async fn concurrent_test(target: &Target, mode: Mode, workers: u32, run: Run) -> Measured {
     let mut handles = Vec::new();
-    let mut all_times = Vec::new();
+    let mut all_times = Histogram::default();
 
     for worker_id in 0..workers {
+        let mut connection = Connection::new(target, mode);
         let handle = tokio::spawn(async move {
-            let mut local_successes = 0;
-            let mut local_times = Vec::new();
-            for i in 0..ops_per_worker {
-                let key = ((worker_id * ops_per_worker + i) % 256) as u8;
-                let value = worker_id * 1000 + i;
-
-                let op_start = Instant::now();
-                if send_op(OP_SET, key, value).await.is_ok() {
-                    if let Ok(resp) = send_op(OP_GET, key, 0).await {
-                        local_times.push(op_start.elapsed().as_micros() as u64);
-                        if !resp.is_empty() && resp[0] == 1 {
-                            local_successes += 1;
-                        }
-                    }
-                }
-            }
-            (local_successes, local_times)
+            run.measure(async move |i| {
+                let key = (worker_id.wrapping_mul(run.ops).wrapping_add(i) % 256) as u8;
+                let value = worker_id.wrapping_mul(1000).wrapping_add(i);
+                connection.send_op(OP_SET, key, value).await.ok()?;
+                let resp = connection.send_op(OP_GET, key, 0).await.ok()?;
+                Some(!resp.is_empty() && resp[0] == 1)
+            })
+            .await
         });
         handles.push(handle);
     }
 
-    let mut total_successes = 0;
+    let (mut total_successes, mut elapsed) = (0, Duration::ZERO);
     for handle in handles {
-        if let Ok((successes, mut times)) = handle.await {
+        if let Ok((successes, times, worker_elapsed)) = handle.await {
             total_successes += successes;
-            all_times.append(&mut times);
+            all_times.merge(&times);
+            elapsed = elapsed.max(worker_elapsed);
         }
     }
 
-    all_times.sort();
-    (total_successes, all_times)
+    (total_successes, all_times, elapsed)
+}
+
+const SWEEP_KEY: u8 = 200;
+
+/// Stores `count` values under `key` over one connection, pipelining SETs in
+/// batches so large lists fill quickly.
+async fn fill_key(
+    stream: &mut Stream,
+    key: u8,
+    count: u32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    stream
+        .write_all(&[OP_DELETE_BY_KEY, key, 0, 0, 0, 0])
+        .await?;
+    stream.read_u8().await?;
+
+    let mut sent = 0;
+    while sent < count {
+        let batch = (count - sent).min(4096);
+        let mut request = Vec::with_capacity(batch as usize * 6);
+        for i in 0..batch {
+            request.push(OP_SET);
+            request.push(key);
+            request.extend_from_slice(&(sent + i).to_le_bytes());
+        }
+        stream.write_all(&request).await?;
+        let mut statuses = vec![0u8; batch as usize];
+        stream.read_exact(&mut statuses).await?;
+        if statuses.iter().any(|&status| status != 1) {
+            return Err("server refused a SET while filling".into());
+        }
+        sent += batch;
+    }
+    Ok(())
 }
 
-fn print_stats(name: &str, success: u32, times: &[u64]) {
-    if times.is_empty() {
-        println!("{}: No operations completed", name);
-        return;
+/// Reads `key` into `values`, which is reused so the benchmark's own
+/// allocations do not add to the response cost it measures.
+async fn timed_get(
+    stream: &mut Stream,
+    key: u8,
+    values: &mut Vec<u8>,
+) -> Result<u32, Box<dyn std::error::Error>> {
+    stream.write_all(&[OP_GET, key, 0, 0, 0, 0]).await?;
+    if stream.read_u8().await? != 1 {
+        return Err("GET returned no values".into());
     }
+    let count = stream.read_u32_le().await?;
+    values.resize(count as usize * 4, 0);
+    stream.read_exact(values).await?;
+    Ok(count)
+}
 
-    let min = times[0];
-    let max = times[times.len() - 1];
-    let avg = times.iter().sum::<u64>() / times.len() as u64;
-    let p99_idx = ((times.len() as f64 * 0.99) as usize).min(times.len() - 1);
-    let p99 = times[p99_idx];
-    let total_time = times.iter().sum::<u64>();
-    let ops_per_sec = if total_time > 0 {
-        (success as f64 * 1_000_000.0) / total_time as f64
-    } else {
-        0.0
+/// GET latency and throughput as the returned list grows, over one
+/// persistent connection so connection setup does not mask response cost.
+/// Each size is read a fixed number of times, fewer the larger it is,
+/// unless `run` gives a duration. Each size's step is added to `steps` as
+/// soon as it is measured.
+async fn size_sweep(
+    target: &Target,
+    sizes: &[u32],
+    run: impl Fn(u32) -> Run,
+    verbose: bool,
+    steps: &mut Vec<SweepStep>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut stream = target.connect().await?;
+    let mut values = Vec::new();
+    if verbose {
+        println!("GET Response-Size Sweep:");
+        println!(
+            "  {:>8} {:>6} {:>8} {:>8} {:>8} {:>8} {:>8} {:>12} {:>10}",
+            "values", "ops", "p50 μs", "p90 μs", "p95 μs", "p99 μs", "p99.9 μs", "ops/sec", "MB/s"
+        );
+    }
+    for &size in sizes {
+        fill_key(&mut stream, SWEEP_KEY, size).await?;
+        let mut failed = None;
+        let (iterations, times, elapsed) = run((200_000 / size).clamp(20, 2_000))
+            .measure(
+                async |_| match timed_get(&mut stream, SWEEP_KEY, &mut values).await {
+                    Ok(_) => Some(true),
+                    Err(e) => {
+                        failed.get_or_insert(e);
+                        None
+                    }
+                },
+            )
+            .await;
+        if let Some(e) = failed {
+            return Err(e);
+        }
+        let ops_per_sec = report::throughput(iterations, elapsed);
+
+        let response_bytes = 5.0 + size as f64 * 4.0;
+        let step = SweepStep {
+            values: size,
+            ops: iterations,
+            percentiles: Percentiles::new(&times),
+            ops_per_sec,
+            mb_per_sec: ops_per_sec * response_bytes / 1_000_000.0,
+        };
+        if verbose {
+            println!(
+                "  {:>8} {:>6} {:>8} {:>8} {:>8} {:>8} {:>8} {:>12.0} {:>10.1}",
+                step.values,
+                step.ops,
+                step.percentiles.p50_us,
+                step.percentiles.p90_us,
+                step.percentiles.p95_us,
+                step.percentiles.p99_us,
+                step.percentiles.p99_9_us,
+                step.ops_per_sec,
+                step.mb_per_sec
+            );
+        }
+        steps.push(step);
+    }
+    stream
+        .write_all(&[OP_DELETE_BY_KEY, SWEEP_KEY, 0, 0, 0, 0])
+        .await?;
+    stream.read_u8().await?;
+    Ok(())
+}
+
+/// Runs the selected SET, GET, DELETE, LIST and concurrent scenarios in
+/// `mode`, returning each one's results.
+async fn run_scenarios(args: &Args, mode: Mode) -> Vec<Stats> {
+    let verbose = args.prints_text();
+    if verbose {
+        println!("Mode: {}", mode.name());
+    }
+    let target = &args.target();
+    let mut results = Vec::new();
+    let mut record = |scenario, name: String, (successes, times, elapsed): Measured| {
+        let stats = Stats::new(scenario, name, mode.id(), successes, &times, elapsed);
+        if verbose {
+            stats.print();
+        }
+        results.push(stats);
     };
 
-    println!("{}:", name);
-    println!(
-        "  {} ops - min: {}μs, avg: {}μs, max: {}μs, p99: {}μs ({:.0} ops/sec)",
-        success, min, avg, max, p99, ops_per_sec
-    );
+    if args.runs(Scenario::Set) {
+        let measured = set_test(target, mode, args.run(args.iterations)).await;
+        record("set", "SET Operations".into(), measured);
+    }
+
+    if args.runs(Scenario::Get) {
+        let measured = get_test(target, mode, args.run(args.iterations)).await;
+        record("get", "GET Operations".into(), measured);
+    }
+
+    if args.runs(Scenario::Delete) {
+        let measured = delete_test(target, mode, args.run(args.iterations)).await;
+        record("delete", "DELETE Operations".into(), measured);
+    }
+
+    if args.runs(Scenario::List) {
+        let measured = list_test(target, mode, args.run(args.list_iterations)).await;
+        record("list", "LIST Operations".into(), measured);
+    }
+
+    if args.runs(Scenario::Concurrent) {
+        let mut run = args.run(args.ops_per_worker);
+        run.every = run.every.map(|every| every * args.workers);
+        let measured = concurrent_test(target, mode, args.workers, run).await;
+        let each = match args.duration {
+            Some(duration) => format!("{duration:?}"),
+            None => format!("{} ops", args.ops_per_worker),
+        };
+        let name = format!("Concurrent Test ({} workers, {each} each)", args.workers);
+        record("concurrent", name, measured);
+    }
+    results
+}
+
+impl Args {
+    fn runs(&self, scenario: Scenario) -> bool {
+        match self.scenarios.is_empty() {
+            true => scenario != Scenario::Soak,
+            false => self.scenarios.contains(&scenario),
+        }
+    }
+
+    /// A scenario's loop, `ops` operations long unless `--duration` is set.
+    fn run(&self, ops: u32) -> Run {
+        Run {
+            warmup: self.warmup,
+            duration: self.duration,
+            ops,
+            every: self
+                .rate
+                .map(|rate| Duration::from_secs_f64(1.0 / rate as f64)),
+        }
+    }
+
+    fn target(&self) -> Target {
+        match &self.tcp {
+            Some(addr) => Target::Tcp(addr.clone()),
+            None => Target::Unix(self.socket.clone()),
+        }
+    }
+
+    /// Whether the human-readable report goes to stdout, which it does unless
+    /// a structured one is written there instead.
+    fn prints_text(&self) -> bool {
+        self.output.is_none() || self.output_file.is_some()
+    }
 }
 
 #[tokio::main]
 async fn main() {
-    println!("MAP8X32 BENCHMARK");
-    println!("=================");
-
-    let iterations = 50_000;
+    let args = Args::parse();
+    let baseline = match args.baseline.as_deref().map(Baseline::load).transpose() {
+        Ok(baseline) => baseline,
+        Err(e) => {
+            eprintln!("Failed to read the baseline: {e}");
+            std::process::exit(1);
+        }
+    };
+    let verbose = args.prints_text();
+    if verbose {
+        println!("MAP8X32 BENCHMARK");
+        println!("=================");
+        if !args.warmup.is_zero() {
+            println!("Warmup: {:?} per scenario, not recorded", args.warmup);
+        }
+        if let Some(duration) = args.duration {
+            println!("Duration: {duration:?} per scenario");
+        }
+        if let Some(rate) = args.rate {
+            println!("Rate: {rate} ops/sec, latency measured from each scheduled send");
+        }
+    }
 
-    let (set_success, set_times) = set_test(iterations).await;
-    print_stats("SET Operations", set_success, &set_times);
+    let target = args.target();
+    let mut report = Report {
+        warmup_secs: args.warmup.as_secs_f64(),
+        duration_secs: args.duration.map(|duration| duration.as_secs_f64()),
+        rate: args.rate,
+        ..Report::default()
+    };
+    if args.runs(Scenario::Consistency) {
+        let consistent = consistency_test(&target).await;
+        if verbose {
+            println!("Consistency Test:");
+            println!("  {}", if consistent { "PASS" } else { "FAIL" });
+        }
+        report.consistency = Some(consistent);
+    }
 
-    let (get_success, get_times) = get_test(iterations).await;
-    print_stats("GET Operations", get_success, &get_times);
+    let persistent = run_scenarios(&args, Mode::Persistent).await;
+    if args.connect_per_op {
+        let per_op = run_scenarios(&args, Mode::ConnectPerOp).await;
+        if verbose {
+            println!("Connection Reuse (ops/sec):");
+            println!(
+                "  {:<12} {:>12} {:>12} {:>8}",
+                "scenario", "persistent", "per-op", "speedup"
+            );
+            for (reused, fresh) in persistent.iter().zip(&per_op) {
+                let (name, reused, fresh) =
+                    (reused.scenario, reused.ops_per_sec, fresh.ops_per_sec);
+                let speedup = if fresh > 0.0 { reused / fresh } else { 0.0 };
+                println!("  {name:<12} {reused:>12.0} {fresh:>12.0} {speedup:>7.1}x");
+            }
+        }
+        report.scenarios.extend(persistent);
+        report.scenarios.extend(per_op);
+    } else {
+        report.scenarios.extend(persistent);
+    }
 
-    let (del_success, del_times) = delete_test(iterations).await;
-    print_stats("DELETE Operations", del_success, &del_times);
+    if args.runs(Scenario::Sweep) {
+        if let Err(e) = size_sweep(
+            &target,
+            &args.sweep_sizes,
+            |ops| Run {
+                every: None,
+                ..args.run(ops)
+            },
+            verbose,
+            &mut report.sweep,
+        )
+        .await
+        {
+            eprintln!("  size sweep failed: {e}");
+        }
+    }
 
-    let (list_success, list_times) = list_test(50).await;
-    print_stats("LIST Operations", list_success, &list_times);
+    if args.runs(Scenario::Soak) {
+        let plan = soak::Plan {
+            duration: args.soak_duration,
+            rate: args.soak_rate,
+            sample_interval: args.soak_sample_interval,
+            max_growth: args.soak_max_growth,
+        };
+        match soak::run(&target, plan, verbose).await {
+            Ok(((successes, times, elapsed), soak)) => {
+                let name = format!("Soak Operations ({} ops/sec)", args.soak_rate);
+                let stats = Stats::new(
+                    "soak",
+                    name,
+                    Mode::Persistent.id(),
+                    successes,
+                    &times,
+                    elapsed,
+                );
+                if verbose {
+                    stats.print();
+                }
+                report.scenarios.push(stats);
+                report.soak = Some(soak);
+            }
+            Err(e) => eprintln!("  soak test failed: {e}"),
+        }
+    }
 
-    println!("Consistency Test:");
-    let consistent = consistency_test().await;
-    println!("  {}", if consistent { "PASS" } else { "FAIL" });
+    if let Some(format) = args.output {
+        let written = match &args.output_file {
+            Some(path) => File::create(path)
+                .and_then(|file| report::write(format, &mut BufWriter::new(file), &report)),
+            None => report::write(format, &mut io::stdout().lock(), &report),
+        };
+        if let Err(e) = written {
+            eprintln!("Failed to write the report: {e}");
+            std::process::exit(1);
+        }
+    }
 
-    let (conc_success, conc_times) = concurrent_test(20, 100).await;
-    print_stats(
-        "Concurrent Test (20 workers, 100 ops each)",
-        conc_success,
-        &conc_times,
-    );
+    let mut failed = report.soak.as_ref().is_some_and(|soak| !soak.passed);
+    if let Some(baseline) = &baseline {
+        let threshold = args.regression_threshold;
+        let compared = if verbose {
+            baseline.compare(&report, threshold, &mut io::stdout().lock())
+        } else {
+            baseline.compare(&report, threshold, &mut io::stderr().lock())
+        };
+        match compared {
+            Ok(regressions) => failed |= regressions > 0,
+            Err(e) => {
+                eprintln!("Failed to print the baseline comparison: {e}");
+                std::process::exit(1);
+            }
+        }
+    }
+    if failed {
+        std::process::exit(2);
+    }
 }
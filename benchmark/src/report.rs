@@ -0,0 +1,229 @@
+use crate::histogram::Histogram;
+use crate::soak::Soak;
+use serde::Serialize;
+use std::io::{self, Write};
+use std::time::Duration;
+
+/// The structured formats `--output` writes the report in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// One object holding every scenario, sweep step and distribution.
+    Json,
+    /// A header, then one row per scenario and sweep step.
+    Csv,
+}
+
+/// Percentiles in the latency distribution table.
+const DISTRIBUTION: [f64; 8] = [50.0, 75.0, 90.0, 95.0, 99.0, 99.9, 99.99, 100.0];
+
+#[derive(Debug, Serialize)]
+pub struct Percentile {
+    pub percentile: f64,
+    pub latency_us: u64,
+    pub ops: u64,
+}
+
+/// The percentiles every scenario and sweep step reports.
+#[derive(Debug, Serialize)]
+pub struct Percentiles {
+    pub p50_us: u64,
+    pub p90_us: u64,
+    pub p95_us: u64,
+    pub p99_us: u64,
+    pub p99_9_us: u64,
+}
+
+impl Percentiles {
+    pub fn new(times: &Histogram) -> Self {
+        let percentile = |percentile| times.percentile(percentile).0;
+        Percentiles {
+            p50_us: percentile(50.0),
+            p90_us: percentile(90.0),
+            p95_us: percentile(95.0),
+            p99_us: percentile(99.0),
+            p99_9_us: percentile(99.9),
+        }
+    }
+}
+
+/// Operations per second over `elapsed` of wall-clock time.
+pub fn throughput(ops: u32, elapsed: Duration) -> f64 {
+    match elapsed.as_secs_f64() {
+        0.0 => 0.0,
+        secs => ops as f64 / secs,
+    }
+}
+
+/// One scenario's results in one connection mode.
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub scenario: &'static str,
+    pub name: String,
+    pub mode: &'static str,
+    pub ops: u64,
+    pub successes: u32,
+    pub elapsed_secs: f64,
+    pub min_us: u64,
+    pub avg_us: u64,
+    #[serde(flatten)]
+    pub percentiles: Percentiles,
+    pub max_us: u64,
+    pub ops_per_sec: f64,
+    pub distribution: Vec<Percentile>,
+}
+
+impl Stats {
+    pub fn new(
+        scenario: &'static str,
+        name: String,
+        mode: &'static str,
+        successes: u32,
+        times: &Histogram,
+        elapsed: Duration,
+    ) -> Self {
+        Stats {
+            scenario,
+            name,
+            mode,
+            ops: times.len(),
+            successes,
+            elapsed_secs: elapsed.as_secs_f64(),
+            min_us: times.min(),
+            avg_us: times.mean(),
+            percentiles: Percentiles::new(times),
+            max_us: times.max(),
+            ops_per_sec: throughput(successes, elapsed),
+            distribution: DISTRIBUTION
+                .into_iter()
+                .filter(|_| !times.is_empty())
+                .map(|wanted| {
+                    let (latency_us, ops) = times.percentile(wanted);
+                    Percentile {
+                        percentile: wanted,
+                        latency_us,
+                        ops,
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    /// Prints the latencies, with their distribution.
+    pub fn print(&self) {
+        if self.ops == 0 {
+            println!("{}: No operations completed", self.name);
+            return;
+        }
+        println!("{}:", self.name);
+        println!(
+            "  {} ops in {:.2}s - min: {}μs, avg: {}μs, p50: {}μs, p90: {}μs, p95: {}μs, p99: {}μs, p99.9: {}μs, max: {}μs ({:.0} ops/sec)",
+            self.successes,
+            self.elapsed_secs,
+            self.min_us,
+            self.avg_us,
+            self.percentiles.p50_us,
+            self.percentiles.p90_us,
+            self.percentiles.p95_us,
+            self.percentiles.p99_us,
+            self.percentiles.p99_9_us,
+            self.max_us,
+            self.ops_per_sec
+        );
+        println!(
+            "    {:>10} {:>12} {:>10}",
+            "percentile", "latency (μs)", "ops"
+        );
+        for row in &self.distribution {
+            println!(
+                "    {:>10} {:>12} {:>10}",
+                format!("{}%", row.percentile),
+                row.latency_us,
+                row.ops
+            );
+        }
+    }
+}
+
+/// One size in the GET response-size sweep.
+#[derive(Debug, Serialize)]
+pub struct SweepStep {
+    pub values: u32,
+    pub ops: u32,
+    #[serde(flatten)]
+    pub percentiles: Percentiles,
+    pub ops_per_sec: f64,
+    pub mb_per_sec: f64,
+}
+
+/// Everything a run measured.
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    /// How long each scenario ran unmeasured before its samples were kept.
+    pub warmup_secs: f64,
+    /// How long each scenario was measured for, if by time rather than by
+    /// operation count.
+    pub duration_secs: Option<f64>,
+    /// The fixed send rate, if latencies are timed from scheduled sends.
+    pub rate: Option<u32>,
+    pub consistency: Option<bool>,
+    pub scenarios: Vec<Stats>,
+    pub sweep: Vec<SweepStep>,
+    pub soak: Option<Soak>,
+}
+
+const CSV_HEADER: &str = "section,scenario,mode,values,ops,successes,elapsed_secs,min_us,avg_us,p50_us,p90_us,p95_us,p99_us,p99_9_us,max_us,ops_per_sec,mb_per_sec";
+
+pub fn write(format: Format, out: &mut impl Write, report: &Report) -> io::Result<()> {
+    match format {
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut *out, report)?;
+            writeln!(out)?;
+        }
+        Format::Csv => {
+            writeln!(out, "{CSV_HEADER}")?;
+            if let Some(passed) = report.consistency {
+                writeln!(
+                    out,
+                    "consistency,consistency,,,1,{},,,,,,,,,,,",
+                    passed as u8
+                )?;
+            }
+            for stats in &report.scenarios {
+                writeln!(
+                    out,
+                    "scenario,{},{},,{},{},{:.3},{},{},{},{},{},{},{},{},{:.0},",
+                    stats.scenario,
+                    stats.mode,
+                    stats.ops,
+                    stats.successes,
+                    stats.elapsed_secs,
+                    stats.min_us,
+                    stats.avg_us,
+                    stats.percentiles.p50_us,
+                    stats.percentiles.p90_us,
+                    stats.percentiles.p95_us,
+                    stats.percentiles.p99_us,
+                    stats.percentiles.p99_9_us,
+                    stats.max_us,
+                    stats.ops_per_sec
+                )?;
+            }
+            for step in &report.sweep {
+                writeln!(
+                    out,
+                    "sweep,sweep,persistent,{},{},,,,,{},{},{},{},{},,{:.0},{:.1}",
+                    step.values,
+                    step.ops,
+                    step.percentiles.p50_us,
+                    step.percentiles.p90_us,
+                    step.percentiles.p95_us,
+                    step.percentiles.p99_us,
+                    step.percentiles.p99_9_us,
+                    step.ops_per_sec,
+                    step.mb_per_sec
+                )?;
+            }
+        }
+    }
+    out.flush()
+}
@@ -0,0 +1,185 @@
+use crate::histogram::Histogram;
+use crate::stream::Target;
+use crate::{send_op, Measured};
+use map8x32_protocol::{OP_DELETE_BY_KEY, OP_GET, OP_INFO, OP_SET, STATUS_OK};
+use serde::Serialize;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::{interval, MissedTickBehavior};
+
+/// Operations in each key's turn of the soak workload: SETs, then GETs, then
+/// a DELETE that clears the key, so the data stays bounded and memory should
+/// level off.
+const CYCLE: u32 = 10;
+const CYCLE_SETS: u32 = 6;
+
+/// Fewer samples than this cannot tell growth from start-up.
+const MIN_SAMPLES: usize = 4;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Plan {
+    pub duration: Duration,
+    pub rate: u32,
+    pub sample_interval: Duration,
+    pub max_growth: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Sample {
+    pub elapsed_secs: f64,
+    pub ops: u64,
+    /// The server's resident set, from `/proc`, when it runs on this host.
+    pub rss_bytes: Option<u64>,
+    /// The data memory INFO estimates.
+    pub memory_bytes: Option<u64>,
+}
+
+/// What the soak observed, and whether memory stayed bounded.
+#[derive(Debug, Serialize)]
+pub struct Soak {
+    pub rate: u32,
+    pub max_growth_percent: f64,
+    /// `rss_bytes` when every sample has it, otherwise `memory_bytes`.
+    pub judged_on: &'static str,
+    /// How much the later half's peak exceeds the earlier half's, or `None`
+    /// with too few samples to say.
+    pub growth_percent: Option<f64>,
+    pub passed: bool,
+    pub samples: Vec<Sample>,
+}
+
+/// The server's resident set, from `VmRSS` in its `/proc` status.
+fn rss_bytes(pid: i32) -> Option<u64> {
+    let status = std::fs::read_to_string(format!("/proc/{pid}/status")).ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// The memory estimate from INFO, asked over a connection of its own.
+async fn memory_bytes(target: &Target) -> Option<u64> {
+    let mut stream = target.connect().await.ok()?;
+    stream.write_all(&[OP_INFO, 0, 0, 0, 0, 0]).await.ok()?;
+    if stream.read_u8().await.ok()? != STATUS_OK {
+        return None;
+    }
+    let mut fields = [0u8; 32];
+    stream.read_exact(&mut fields).await.ok()?;
+    Some(u64::from_le_bytes(fields[24..32].try_into().ok()?))
+}
+
+/// How far the peak of the later half of `samples` is above the peak of
+/// the earlier half, in percent.
+fn growth(samples: &[u64]) -> Option<f64> {
+    if samples.len() < MIN_SAMPLES {
+        return None;
+    }
+    let (early, late) = samples.split_at(samples.len() / 2);
+    let early = *early.iter().max()? as f64;
+    let late = *late.iter().max()? as f64;
+    Some(if early > 0.0 {
+        (late - early) / early * 100.0
+    } else {
+        0.0
+    })
+}
+
+/// Sends the soak workload at `plan.rate` over one connection for
+/// `plan.duration`, sampling the server's memory every
+/// `plan.sample_interval`.
+pub async fn run(
+    target: &Target,
+    plan: Plan,
+    verbose: bool,
+) -> Result<(Measured, Soak), Box<dyn std::error::Error>> {
+    let mut stream = target.connect().await?;
+    let pid = stream.peer_pid();
+    if verbose {
+        println!(
+            "Soak Test ({} ops/sec for {:?}, sampling every {:?}):",
+            plan.rate, plan.duration, plan.sample_interval
+        );
+        println!(
+            "  {:>10} {:>12} {:>14} {:>14}",
+            "elapsed", "ops", "rss (KiB)", "data (KiB)"
+        );
+    }
+
+    let mut pace = interval(Duration::from_secs(1) / plan.rate.max(1));
+    pace.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut sampling = interval(plan.sample_interval);
+    sampling.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    let (mut successes, mut times, mut ops) = (0, Histogram::default(), 0u64);
+    let mut samples = Vec::new();
+    let start = Instant::now();
+    while start.elapsed() < plan.duration {
+        tokio::select! {
+            _ = sampling.tick() => {
+                let sample = Sample {
+                    elapsed_secs: start.elapsed().as_secs_f64(),
+                    ops,
+                    rss_bytes: pid.and_then(rss_bytes),
+                    memory_bytes: memory_bytes(target).await,
+                };
+                if verbose {
+                    let kib = |bytes: Option<u64>| bytes.map_or("-".to_string(), |bytes| (bytes / 1024).to_string());
+                    println!(
+                        "  {:>9.1}s {:>12} {:>14} {:>14}",
+                        sample.elapsed_secs, sample.ops, kib(sample.rss_bytes), kib(sample.memory_bytes)
+                    );
+                }
+                samples.push(sample);
+            }
+            _ = pace.tick() => {
+                let i = ops as u32;
+                let key = (i / CYCLE % 256) as u8;
+                let op = match i % CYCLE {
+                    step if step < CYCLE_SETS => OP_SET,
+                    step if step < CYCLE - 1 => OP_GET,
+                    _ => OP_DELETE_BY_KEY,
+                };
+                let op_start = Instant::now();
+                let response = send_op(&mut stream, op, key, i).await?;
+                times.record(op_start.elapsed().as_micros() as u64);
+                successes += (op != OP_SET || response.first() == Some(&STATUS_OK)) as u32;
+                ops += 1;
+            }
+        }
+    }
+    let elapsed = start.elapsed();
+
+    let rss: Option<Vec<u64>> = samples.iter().map(|sample| sample.rss_bytes).collect();
+    let (judged_on, judged) = match rss {
+        Some(rss) if !rss.is_empty() => ("rss_bytes", rss),
+        _ => (
+            "memory_bytes",
+            samples
+                .iter()
+                .filter_map(|sample| sample.memory_bytes)
+                .collect(),
+        ),
+    };
+    let growth_percent = growth(&judged);
+    let soak = Soak {
+        rate: plan.rate,
+        max_growth_percent: plan.max_growth,
+        judged_on,
+        growth_percent,
+        passed: growth_percent.is_none_or(|growth| growth <= plan.max_growth),
+        samples,
+    };
+    if verbose {
+        match soak.growth_percent {
+            Some(growth) => println!(
+                "  {judged_on} grew {growth:.1}% from the first half's peak to the second's (limit {}%): {}",
+                plan.max_growth,
+                if soak.passed { "PASS" } else { "FAIL" }
+            ),
+            None => println!(
+                "  fewer than {MIN_SAMPLES} memory samples, too few to judge growth; run longer or sample more often"
+            ),
+        }
+    }
+    Ok(((successes, times, elapsed), soak))
+}
@@ -0,0 +1,330 @@
+mod migrate;
+mod replay;
+mod script;
+mod transfer;
+
+use clap::{Parser, Subcommand};
+use map8x32_client::{Client, ClientConfig};
+use map8x32_protocol::DEFAULT_SOCKET_PATH;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use transfer::Format;
+
+#[derive(Debug, Parser)]
+#[command(name = "map8x32-cli", about = "Command-line tool for a Map8x32 server")]
+struct Args {
+    #[arg(long, default_value = DEFAULT_SOCKET_PATH, help = "Unix socket to connect to")]
+    socket: PathBuf,
+    #[arg(
+        long,
+        help = "Connect over TCP to this address instead of the Unix socket"
+    )]
+    tcp: Option<String>,
+    #[arg(long, help = "Authenticate with the token in this file")]
+    auth_token_file: Option<PathBuf>,
+    #[arg(long, default_value_t = 0, help = "Database to work on")]
+    db: u8,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Write every key and its values, read with LIST_ALL
+    Export {
+        #[arg(long, value_enum, default_value = "json")]
+        format: Format,
+        #[arg(long, short, help = "Write to this file instead of stdout")]
+        output: Option<PathBuf>,
+    },
+    /// Append the keys and values in an export with batched SETs
+    Import {
+        #[arg(long, value_enum, default_value = "json")]
+        format: Format,
+        #[arg(long, short, help = "Read from this file instead of stdin")]
+        input: Option<PathBuf>,
+        #[arg(long, default_value_t = 256, help = "SETs to send per round trip")]
+        batch: usize,
+        #[arg(
+            long,
+            value_name = "TOKEN",
+            help = "Clear the database with DELETE_ALL first, confirming with the server's token"
+        )]
+        replace: Option<u32>,
+    },
+    /// Print every command the server runs, as it runs them, until stopped
+    Monitor,
+    /// Print every connection the server is serving, read with CLIENT_LIST
+    Clients,
+    /// Close a connection the server is serving, by the ID `clients` prints
+    Kill {
+        #[arg(help = "ID of the connection to close")]
+        id: u64,
+    },
+    /// Print the server's runtime tunables, or change one
+    Config {
+        #[arg(help = "Tunable to print or change; every one if left out")]
+        name: Option<String>,
+        #[arg(requires = "name", help = "Value to set the tunable to")]
+        value: Option<String>,
+    },
+    /// Run one command per line, printing a result line for each
+    Run {
+        #[arg(long, short, help = "Read commands from this file instead of stdin")]
+        file: Option<PathBuf>,
+    },
+    /// Send the requests in a server's --capture file again, with their timing
+    Replay {
+        #[arg(help = "Capture file to send")]
+        capture: PathBuf,
+        #[arg(
+            long,
+            default_value_t = 1.0,
+            help = "How many times faster than captured to send; 0 sends as fast as the server reads"
+        )]
+        speed: f64,
+    },
+    /// Copy every key and its values to another server or database
+    Migrate {
+        #[arg(
+            long,
+            required_unless_present = "to_tcp",
+            conflicts_with = "to_tcp",
+            help = "Unix socket of the destination server"
+        )]
+        to_socket: Option<PathBuf>,
+        #[arg(long, help = "TCP address of the destination server")]
+        to_tcp: Option<String>,
+        #[arg(
+            long,
+            help = "Authenticate to the destination with the token in this file"
+        )]
+        to_auth_token_file: Option<PathBuf>,
+        #[arg(long, help = "Destination database; the source's if left out")]
+        to_db: Option<u8>,
+        #[arg(long, default_value_t = 256, help = "SETs to send per round trip")]
+        batch: usize,
+        #[arg(
+            long,
+            default_value_t = 0,
+            help = "Values to write per second at most; 0 for no limit"
+        )]
+        rate: u64,
+        #[arg(
+            long,
+            value_name = "TOKEN",
+            help = "Clear the destination database with DELETE_ALL first, confirming with its server's token"
+        )]
+        replace: Option<u32>,
+    },
+}
+
+async fn connect(args: &Args) -> io::Result<Client> {
+    open(
+        &args.socket,
+        args.tcp.as_deref(),
+        args.auth_token_file.as_deref(),
+        args.db,
+    )
+    .await
+}
+
+async fn open(
+    socket: &Path,
+    tcp: Option<&str>,
+    auth_token_file: Option<&Path>,
+    db: u8,
+) -> io::Result<Client> {
+    let mut config = match tcp {
+        Some(addr) => ClientConfig::tcp(addr),
+        None => ClientConfig::unix(socket),
+    };
+    if let Some(path) = auth_token_file {
+        config = config.with_auth_token(std::fs::read_to_string(path)?.trim());
+    }
+    let mut client = Client::connect(&config).await?;
+    client.hello().await?;
+    if db != 0 {
+        client.select(db).await?;
+    }
+    Ok(client)
+}
+
+async fn run(args: Args) -> io::Result<()> {
+    if let Command::Replay { capture, speed } = &args.command {
+        if !speed.is_finite() || *speed < 0.0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--speed must be 0 or more",
+            ));
+        }
+        let target = match &args.tcp {
+            Some(addr) => replay::Target::Tcp(addr.clone()),
+            None => replay::Target::Unix(args.socket.clone()),
+        };
+        return replay::run(capture, target, *speed).await;
+    }
+    let mut client = connect(&args).await?;
+    match args.command {
+        Command::Export { format, output } => {
+            let mut entries = client.list_all().await?;
+            entries.sort_unstable_by_key(|(key, _)| *key);
+            match output {
+                Some(path) => {
+                    transfer::write(format, &mut BufWriter::new(File::create(path)?), &entries)?
+                }
+                None => transfer::write(format, &mut io::stdout().lock(), &entries)?,
+            }
+            let values: usize = entries.iter().map(|(_, values)| values.len()).sum();
+            eprintln!("exported {} keys, {values} values", entries.len());
+        }
+        Command::Import {
+            format,
+            input,
+            batch,
+            replace,
+        } => {
+            let writes = match input {
+                Some(path) => transfer::read(format, BufReader::new(File::open(path)?))?,
+                None => transfer::read(format, io::stdin().lock())?,
+            };
+            if let Some(token) = replace {
+                client.delete_all(token).await?;
+            }
+            for chunk in writes.chunks(batch.max(1)) {
+                client.set_batch(chunk).await?;
+            }
+            let keys: BTreeSet<u8> = writes.iter().map(|(key, _)| *key).collect();
+            eprintln!("imported {} values into {} keys", writes.len(), keys.len());
+        }
+        Command::Monitor => {
+            let mut monitor = client.monitor().await?;
+            loop {
+                let commands = monitor.next().await?;
+                let mut out = io::stdout().lock();
+                for command in commands {
+                    writeln!(
+                        out,
+                        "{} db={} peer=\"{}\" op={} key={} value={} status={} micros={} command=\"{}\"",
+                        command.at_unix_ms,
+                        command.db,
+                        command.peer,
+                        command.op,
+                        command.key,
+                        command.value,
+                        command.status,
+                        command.micros,
+                        command.command
+                    )?;
+                }
+                out.flush()?;
+            }
+        }
+        Command::Clients => {
+            let mut out = io::stdout().lock();
+            for client in client.client_list().await? {
+                writeln!(
+                    out,
+                    "id={} peer=\"{}\" connected_unix_ms={} requests={} bytes_in={} bytes_out={} last_op={}",
+                    client.id,
+                    client.peer,
+                    client.connected_unix_ms,
+                    client.requests,
+                    client.bytes_in,
+                    client.bytes_out,
+                    client.last_op
+                )?;
+            }
+        }
+        Command::Kill { id } => {
+            if !client.client_kill(id).await? {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("server has no connection {id}"),
+                ));
+            }
+        }
+        Command::Config { name, value } => match (name, value) {
+            (Some(name), Some(value)) => client.set_config(&name, &value).await?,
+            (Some(name), None) => match client.config(&name).await? {
+                Some(value) => println!("{name}={value}"),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("server has no tunable {name}"),
+                    ))
+                }
+            },
+            (None, _) => {
+                let mut out = io::stdout().lock();
+                for (name, value) in client.config_all().await? {
+                    writeln!(out, "{name}={value}")?;
+                }
+            }
+        },
+        Command::Run { file } => {
+            let steps = match file {
+                Some(path) => script::parse(BufReader::new(File::open(path)?))?,
+                None => script::parse(io::stdin().lock())?,
+            };
+            let failed = script::run(&mut client, &steps, &mut io::stdout().lock()).await?;
+            if failed > 0 {
+                return Err(io::Error::other(format!(
+                    "{failed} of {} commands failed",
+                    steps.len()
+                )));
+            }
+        }
+        Command::Replay { .. } => unreachable!("replayed without a client"),
+        Command::Migrate {
+            to_socket,
+            to_tcp,
+            to_auth_token_file,
+            to_db,
+            batch,
+            rate,
+            replace,
+        } => {
+            let to_db = to_db.unwrap_or(args.db);
+            let to_socket = to_socket.unwrap_or_default();
+            let same_db = to_db == args.db
+                && match (&to_tcp, &args.tcp) {
+                    (Some(to), Some(from)) => to == from,
+                    (None, None) => to_socket == args.socket,
+                    _ => false,
+                };
+            if same_db {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the destination is the source database",
+                ));
+            }
+            let mut destination = open(
+                &to_socket,
+                to_tcp.as_deref(),
+                to_auth_token_file.as_deref(),
+                to_db,
+            )
+            .await?;
+            if let Some(token) = replace {
+                destination.delete_all(token).await?;
+            }
+            migrate::run(&mut client, &mut destination, migrate::Pace { batch, rate }).await?;
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run(Args::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("map8x32-cli: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use map8x32_client::Client;
+use std::collections::BTreeMap;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Keys asked for per SCAN.
+const SCAN_COUNT: u8 = 64;
+/// How often progress is printed at most.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How a migration is paced.
+#[derive(Debug, Clone, Copy)]
+pub struct Pace {
+    /// SETs sent per round trip.
+    pub batch: usize,
+    /// Values written per second at most, or 0 for no limit.
+    pub rate: u64,
+}
+
+/// Copies every key `source` finds with SCAN, and the values GET then reads,
+/// by appending them to `destination` with batched SETs, printing progress
+/// as it goes. Keys deleted partway through are skipped. Then compares how
+/// many values each key holds on both sides and fails if any differ.
+pub async fn run(source: &mut Client, destination: &mut Client, pace: Pace) -> io::Result<()> {
+    let started = Instant::now();
+    let mut printed = started;
+    let (mut keys, mut values) = (0, 0u64);
+    let mut writes = Vec::new();
+    let mut cursor = 0;
+    loop {
+        let (next, batch) = source.scan(cursor, SCAN_COUNT).await?;
+        for key in batch {
+            let Some(read) = source.get(key).await? else {
+                continue;
+            };
+            keys += 1;
+            writes.extend(read.into_iter().map(|value| (key, value)));
+        }
+        for chunk in writes.chunks(pace.batch.max(1)) {
+            if pace.rate > 0 {
+                let due = started + Duration::from_secs_f64(values as f64 / pace.rate as f64);
+                tokio::time::sleep_until(due.into()).await;
+            }
+            destination.set_batch(chunk).await?;
+            values += chunk.len() as u64;
+        }
+        writes.clear();
+        if printed.elapsed() >= PROGRESS_INTERVAL {
+            eprintln!("migrated {keys} keys, {values} values so far");
+            printed = Instant::now();
+        }
+        if next == 0 {
+            break;
+        }
+        cursor = next;
+    }
+    eprintln!(
+        "migrated {keys} keys, {values} values in {:?}",
+        started.elapsed()
+    );
+    verify(source, destination).await
+}
+
+/// How many values each existing key holds.
+async fn counts(client: &mut Client) -> io::Result<BTreeMap<u8, u64>> {
+    let mut counts = BTreeMap::new();
+    let mut cursor = 0;
+    loop {
+        let (next, keys) = client.scan(cursor, SCAN_COUNT).await?;
+        for key in keys {
+            if let Some(count) = client.count(key).await? {
+                counts.insert(key, count);
+            }
+        }
+        if next == 0 {
+            return Ok(counts);
+        }
+        cursor = next;
+    }
+}
+
+async fn verify(source: &mut Client, destination: &mut Client) -> io::Result<()> {
+    let expected = counts(source).await?;
+    let found = counts(destination).await?;
+    let total = |counts: &BTreeMap<u8, u64>| counts.values().sum::<u64>();
+    eprintln!(
+        "source holds {} keys, {} values; destination holds {} keys, {} values",
+        expected.len(),
+        total(&expected),
+        found.len(),
+        total(&found)
+    );
+    let differing: Vec<String> = (0..=u8::MAX)
+        .filter(|key| expected.get(key) != found.get(key))
+        .map(|key| key.to_string())
+        .collect();
+    if !differing.is_empty() {
+        return Err(io::Error::other(format!(
+            "value counts differ for keys {}",
+            differing.join(", ")
+        )));
+    }
+    Ok(())
+}
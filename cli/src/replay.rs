@@ -0,0 +1,130 @@
+use map8x32_protocol::capture::{read_capture, Captured};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+/// Where the capture is sent.
+#[derive(Debug, Clone)]
+pub enum Target {
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+/// What one connection of the capture sent again.
+#[derive(Debug, Default)]
+struct Replayed {
+    requests: usize,
+    /// How far the latest request was sent after it was due.
+    behind: Duration,
+    answered_bytes: u64,
+}
+
+/// Sends every captured connection's requests on a connection of its own,
+/// each as long after the first as it arrived in the capture, divided by
+/// `speed`, or as fast
+/// as the server reads them when `speed` is 0. Answers are read and
+/// dropped. Prints what was sent once every connection is done.
+pub async fn run(path: &Path, target: Target, speed: f64) -> io::Result<()> {
+    let records = read_capture(&std::fs::read(path)?)?;
+    let first_us = records.first().map_or(0, |record| record.at_us);
+    let captured =
+        Duration::from_micros(records.last().map_or(0, |record| record.at_us - first_us));
+    let mut connections: BTreeMap<u64, Vec<(Duration, Captured)>> = BTreeMap::new();
+    for record in records {
+        let due = match speed {
+            0.0 => Duration::ZERO,
+            speed => Duration::from_secs_f64((record.at_us - first_us) as f64 / 1e6 / speed),
+        };
+        connections
+            .entry(record.connection)
+            .or_default()
+            .push((due, record.event));
+    }
+    let started = Instant::now();
+    let tasks: Vec<_> = connections
+        .into_values()
+        .map(|events| {
+            let target = target.clone();
+            tokio::spawn(async move {
+                let (first_due, _) = events[0];
+                tokio::time::sleep_until((started + first_due).into()).await;
+                match target {
+                    #[cfg(unix)]
+                    Target::Unix(path) => {
+                        replay(UnixStream::connect(path).await?, events, started).await
+                    }
+                    #[cfg(not(unix))]
+                    Target::Unix(path) => Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        format!(
+                            "cannot connect to {}: this platform has no Unix sockets; pass --tcp",
+                            path.display()
+                        ),
+                    )),
+                    Target::Tcp(addr) => {
+                        replay(TcpStream::connect(addr).await?, events, started).await
+                    }
+                }
+            })
+        })
+        .collect();
+    let mut total = Replayed::default();
+    let connections = tasks.len();
+    for task in tasks {
+        let replayed = task.await.map_err(io::Error::other)??;
+        total.requests += replayed.requests;
+        total.behind = total.behind.max(replayed.behind);
+        total.answered_bytes += replayed.answered_bytes;
+    }
+    eprintln!(
+        "replayed {} requests on {connections} connections in {:?} (captured over {captured:?}); at most {:?} behind schedule, {} bytes answered",
+        total.requests,
+        started.elapsed(),
+        total.behind,
+        total.answered_bytes
+    );
+    Ok(())
+}
+
+async fn replay<S>(
+    stream: S,
+    events: Vec<(Duration, Captured)>,
+    started: Instant,
+) -> io::Result<Replayed>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut read, mut write) = tokio::io::split(stream);
+    let answers = tokio::spawn(async move {
+        let mut buf = vec![0; 64 * 1024];
+        let mut answered = 0;
+        loop {
+            match read.read(&mut buf).await {
+                Ok(0) | Err(_) => return answered,
+                Ok(len) => answered += len as u64,
+            }
+        }
+    });
+    let mut replayed = Replayed::default();
+    for (due, event) in events {
+        let due = started + due;
+        tokio::time::sleep_until(due.into()).await;
+        replayed.behind = replayed.behind.max(due.elapsed());
+        match event {
+            Captured::Request(bytes) => {
+                write.write_all(&bytes).await?;
+                replayed.requests += 1;
+            }
+            Captured::Closed => break,
+        }
+    }
+    // The server closes its side once it has answered everything sent.
+    write.shutdown().await?;
+    replayed.answered_bytes = answers.await.unwrap_or_default();
+    Ok(replayed)
+}
@@ -0,0 +1,180 @@
+use map8x32_client::{Client, Ttl};
+use std::io::{self, BufRead, Write};
+use std::time::Duration;
+
+/// One command of a script, as `SET 7 10` or `get 7` spells it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    Ping,
+    Select { db: u8 },
+    Set { key: u8, value: u32 },
+    SetExpiring { key: u8, value: u32, ttl_ms: u64 },
+    Get { key: u8 },
+    Delete { key: u8 },
+    Ttl { key: u8 },
+    Persist { key: u8 },
+    Count { key: u8 },
+    Sum { key: u8 },
+    List,
+    DeleteAll { token: u32 },
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn step(words: &[&str]) -> Result<Step, String> {
+    let number = |index: usize, what: &str| {
+        words
+            .get(index)
+            .ok_or_else(|| format!("missing {what}"))
+            .map(|word| word.to_string())
+    };
+    let key = || {
+        number(1, "key")?
+            .parse()
+            .map_err(|_| "keys must be 0-255".to_string())
+    };
+    let value = || {
+        number(2, "value")?
+            .parse()
+            .map_err(|_| "values must be u32s".to_string())
+    };
+    let (step, arity) = match words[0].to_ascii_uppercase().as_str() {
+        "PING" => (Step::Ping, 1),
+        "SELECT" => {
+            let db = number(1, "database")?
+                .parse()
+                .map_err(|_| "databases must be 0-255".to_string())?;
+            (Step::Select { db }, 2)
+        }
+        "SET" => (
+            Step::Set {
+                key: key()?,
+                value: value()?,
+            },
+            3,
+        ),
+        "SET_EXPIRING" => {
+            let ttl_ms = number(3, "ttl_ms")?
+                .parse()
+                .map_err(|_| "ttl_ms must be a u64".to_string())?;
+            (
+                Step::SetExpiring {
+                    key: key()?,
+                    value: value()?,
+                    ttl_ms,
+                },
+                4,
+            )
+        }
+        "GET" => (Step::Get { key: key()? }, 2),
+        "DELETE" => (Step::Delete { key: key()? }, 2),
+        "TTL" => (Step::Ttl { key: key()? }, 2),
+        "PERSIST" => (Step::Persist { key: key()? }, 2),
+        "COUNT" => (Step::Count { key: key()? }, 2),
+        "SUM" => (Step::Sum { key: key()? }, 2),
+        "LIST" => (Step::List, 1),
+        "DELETE_ALL" => {
+            let token = number(1, "token")?
+                .parse()
+                .map_err(|_| "tokens must be u32s".to_string())?;
+            (Step::DeleteAll { token }, 2)
+        }
+        command => return Err(format!("unknown command {command}")),
+    };
+    if words.len() > arity {
+        return Err(format!("{} takes {} arguments", words[0], arity - 1));
+    }
+    Ok(step)
+}
+
+/// Reads one command per line, skipping blank lines and `#` comments, and
+/// returns each with its line number. Fails on the first line that does
+/// not parse, before anything runs.
+pub fn parse(input: impl BufRead) -> io::Result<Vec<(usize, Step)>> {
+    let mut steps = Vec::new();
+    for (number, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.split('#').next().unwrap_or_default();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        let step = step(&words).map_err(|e| invalid(format!("line {}: {e}", number + 1)))?;
+        steps.push((number + 1, step));
+    }
+    Ok(steps)
+}
+
+fn values(values: &[u32]) -> String {
+    let values: Vec<String> = values.iter().map(u32::to_string).collect();
+    values.join(" ")
+}
+
+fn found(found: bool) -> String {
+    if found { "OK" } else { "NOT_FOUND" }.to_string()
+}
+
+/// What the server answered `step` with, as one line.
+async fn answer(client: &mut Client, step: &Step) -> map8x32_client::Result<String> {
+    Ok(match *step {
+        Step::Ping => client.ping().await?,
+        Step::Select { db } => client.select(db).await.map(|()| found(true))?,
+        Step::Set { key, value } => client.set(key, value).await.map(|()| found(true))?,
+        Step::SetExpiring { key, value, ttl_ms } => client
+            .set_expiring(key, value, Duration::from_millis(ttl_ms))
+            .await
+            .map(|()| found(true))?,
+        Step::Get { key } => match client.get(key).await? {
+            Some(found) => values(&found),
+            None => found(false),
+        },
+        Step::Delete { key } => found(client.delete(key).await?),
+        Step::Ttl { key } => match client.ttl(key).await? {
+            Some(Ttl::Persistent) => "persistent".to_string(),
+            Some(Ttl::Expires(left)) => format!("{}ms", left.as_millis()),
+            None => found(false),
+        },
+        Step::Persist { key } => found(client.persist(key).await?),
+        Step::Count { key } => match client.count(key).await? {
+            Some(count) => count.to_string(),
+            None => found(false),
+        },
+        Step::Sum { key } => match client.sum(key).await? {
+            Some(sum) => sum.to_string(),
+            None => found(false),
+        },
+        Step::List => {
+            let mut entries = client.list_all().await?;
+            entries.sort_unstable_by_key(|(key, _)| *key);
+            let entries: Vec<String> = entries
+                .iter()
+                .map(|(key, found)| format!("{key}={}", values(found).replace(' ', ",")))
+                .collect();
+            entries.join(" ")
+        }
+        Step::DeleteAll { token } => client.delete_all(token).await.map(|()| found(true))?,
+    })
+}
+
+/// Runs every step in order, writing one result line per step to `out`,
+/// and returns how many failed. A failure does not stop the steps after it.
+pub async fn run(
+    client: &mut Client,
+    steps: &[(usize, Step)],
+    out: &mut impl Write,
+) -> io::Result<usize> {
+    let mut failed = 0;
+    for (number, step) in steps {
+        match answer(client, step).await {
+            Ok(result) => writeln!(out, "{result}")?,
+            Err(e) => {
+                failed += 1;
+                writeln!(out, "ERROR line {number}: {e}")?;
+            }
+        }
+    }
+    out.flush()?;
+    Ok(failed)
+}
@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, Write};
+
+/// The text formats `export` writes and `import` reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// An array of `{"key": <key>, "values": [...]}` objects, one per line.
+    Json,
+    /// A `key,value` header, then one row per value in stored order.
+    Csv,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    key: u8,
+    values: Vec<u32>,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub fn write(format: Format, out: &mut impl Write, entries: &[(u8, Vec<u32>)]) -> io::Result<()> {
+    match format {
+        Format::Json => {
+            writeln!(out, "[")?;
+            for (i, (key, values)) in entries.iter().enumerate() {
+                let entry = Entry {
+                    key: *key,
+                    values: values.clone(),
+                };
+                let separator = if i + 1 < entries.len() { "," } else { "" };
+                writeln!(out, "  {}{separator}", serde_json::to_string(&entry)?)?;
+            }
+            writeln!(out, "]")?;
+        }
+        Format::Csv => {
+            writeln!(out, "key,value")?;
+            for (key, values) in entries {
+                for value in values {
+                    writeln!(out, "{key},{value}")?;
+                }
+            }
+        }
+    }
+    out.flush()
+}
+
+/// Reads what [`write`] wrote back as `(key, value)` pairs, in order.
+pub fn read(format: Format, input: impl BufRead) -> io::Result<Vec<(u8, u32)>> {
+    match format {
+        Format::Json => {
+            let entries: Vec<Entry> =
+                serde_json::from_reader(input).map_err(|e| invalid(e.to_string()))?;
+            Ok(entries
+                .into_iter()
+                .flat_map(|entry| {
+                    entry
+                        .values
+                        .into_iter()
+                        .map(move |value| (entry.key, value))
+                })
+                .collect())
+        }
+        Format::Csv => {
+            let mut writes = Vec::new();
+            for (number, line) in input.lines().enumerate() {
+                let line = line?;
+                let line = line.trim();
+                if line.is_empty() || (number == 0 && line == "key,value") {
+                    continue;
+                }
+                let error = |message: &str| invalid(format!("line {}: {message}", number + 1));
+                let Some((key, value)) = line.split_once(',') else {
+                    return Err(error("expected key,value"));
+                };
+                writes.push((
+                    key.trim()
+                        .parse()
+                        .map_err(|_| error("keys must be 0-255"))?,
+                    value
+                        .trim()
+                        .parse()
+                        .map_err(|_| error("values must be u32s"))?,
+                ));
+            }
+            Ok(writes)
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use crate::{Event, Reconnecting, Result};
+use futures_util::StreamExt;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::task::JoinHandle;
+
+pub const DEFAULT_CACHE_CAPACITY: usize = 16;
+
+/// How often [`Cached::get`] was answered without asking the server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// Tells the watcher of this entry from one left behind by an entry
+    /// evicted before.
+    id: u64,
+    /// The key's values as last pushed, `None` until the first push and
+    /// after the subscription broke.
+    values: Option<Option<Vec<u32>>>,
+    used: Instant,
+    watcher: JoinHandle<()>,
+}
+
+type Entries = Arc<Mutex<HashMap<u8, Entry>>>;
+
+/// A [`Reconnecting`] client that remembers what GET read, for keys read
+/// often and changed rarely. Each cached key is followed with SUBSCRIBE on a
+/// connection of its own, and the values it pushes after every change
+/// replace what the cache holds, so a cached read is never more than one
+/// push behind the server. Up to `capacity` keys are cached, the least
+/// recently read dropped first along with its connection.
+///
+/// The cache only learns of a change from its push, including a change made
+/// through [`Cached::connection`]; a read that must see a write just made
+/// calls [`Cached::invalidate`] first.
+#[derive(Debug)]
+pub struct Cached {
+    connection: Reconnecting,
+    capacity: usize,
+    entries: Entries,
+    next_id: u64,
+    stats: CacheStats,
+}
+
+impl Cached {
+    pub fn new(connection: Reconnecting, capacity: usize) -> Cached {
+        Cached {
+            connection,
+            capacity,
+            entries: Arc::default(),
+            next_id: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// The connection reads that missed the cache are sent on, for writes
+    /// and every other command.
+    pub fn connection(&mut self) -> &mut Reconnecting {
+        &mut self.connection
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// `key`'s values as the cache holds them, or as GET reads them when it
+    /// holds none, in which case the key is subscribed to and cached from
+    /// its first push on.
+    pub async fn get(&mut self, key: u8) -> Result<Option<Vec<u32>>> {
+        if let Some(values) = self.cached(key) {
+            self.stats.hits += 1;
+            return Ok(values);
+        }
+        self.stats.misses += 1;
+        self.watch(key);
+        self.connection
+            .run_idempotent(async |client| client.get(key).await)
+            .await
+    }
+
+    /// Forgets `key`'s values until the server pushes them again; the
+    /// subscription is kept.
+    pub fn invalidate(&mut self, key: u8) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(&key) {
+            entry.values = None;
+        }
+    }
+
+    fn cached(&self, key: u8) -> Option<Option<Vec<u32>>> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(&key)?;
+        entry.used = Instant::now();
+        entry.values.clone()
+    }
+
+    /// Subscribes to `key` unless it is already, evicting the least recently
+    /// read key to make room.
+    fn watch(&mut self, key: u8) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.contains_key(&key) {
+            return;
+        }
+        if entries.len() >= self.capacity {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(key, _)| *key);
+            if let Some(entry) = oldest.and_then(|oldest| entries.remove(&oldest)) {
+                entry.watcher.abort();
+            }
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        let shared = self.entries.clone();
+        let mut changes = Box::pin(self.connection.subscribe(key));
+        let watcher = tokio::spawn(async move {
+            while let Some(event) = changes.next().await {
+                let mut entries = shared.lock().unwrap();
+                let Some(entry) = entries.get_mut(&key).filter(|entry| entry.id == id) else {
+                    return;
+                };
+                entry.values = match event {
+                    Event::Changed { values, .. } if values.is_empty() => Some(None),
+                    Event::Changed { values, .. } => Some(Some(values)),
+                    Event::Resubscribed => None,
+                };
+            }
+            // The subscription is over for good, so the key must be read
+            // from the server again.
+            let mut entries = shared.lock().unwrap();
+            if entries.get(&key).is_some_and(|entry| entry.id == id) {
+                entries.remove(&key);
+            }
+        });
+        entries.insert(
+            key,
+            Entry {
+                id,
+                values: None,
+                used: Instant::now(),
+                watcher,
+            },
+        );
+    }
+}
+
+impl Drop for Cached {
+    fn drop(&mut self) {
+        for entry in self.entries.lock().unwrap().values() {
+            entry.watcher.abort();
+        }
+    }
+}
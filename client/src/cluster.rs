@@ -0,0 +1,357 @@
+use crate::{Client, ClientConfig, KeyStats, Map8x32Error, Result, Sample, Ttl};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// One server instance and the key range it owns.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Shard {
+    pub first_key: u8,
+    pub last_key: u8,
+    pub unix_path: Option<PathBuf>,
+    pub tcp_addr: Option<String>,
+}
+
+impl Shard {
+    pub fn owns(&self, key: u8) -> bool {
+        (self.first_key..=self.last_key).contains(&key)
+    }
+}
+
+/// How the keyspace is split across server instances. Every key belongs to
+/// exactly one shard.
+///
+/// The text form has one shard per line: the key range (`first-last`, or a
+/// single key), then `unix:<path>` and/or `tcp:<addr>`. With both, the
+/// client races them as [`ClientConfig`] does. `#` starts a comment.
+///
+/// ```text
+/// 0-127    unix:/tmp/map8x32-a.sock
+/// 128-255  unix:/tmp/map8x32-b.sock tcp:10.0.0.2:7832
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Topology {
+    shards: Vec<Shard>,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn unowned(first: u8, last: u8) -> io::Error {
+    if first == last {
+        invalid(format!("key {first} has no shard"))
+    } else {
+        invalid(format!("keys {first}-{last} have no shard"))
+    }
+}
+
+impl Topology {
+    /// Checks that `shards` cover every key exactly once.
+    pub fn new(mut shards: Vec<Shard>) -> io::Result<Topology> {
+        shards.sort_by_key(|shard| shard.first_key);
+        let mut next = Some(0u8);
+        for shard in &shards {
+            if shard.first_key > shard.last_key {
+                return Err(invalid(format!(
+                    "shard {}-{} has its range reversed",
+                    shard.first_key, shard.last_key
+                )));
+            }
+            if shard.unix_path.is_none() && shard.tcp_addr.is_none() {
+                return Err(invalid(format!(
+                    "shard {}-{} has no address",
+                    shard.first_key, shard.last_key
+                )));
+            }
+            match next {
+                Some(expected) if shard.first_key == expected => {}
+                Some(expected) if shard.first_key > expected => {
+                    return Err(unowned(expected, shard.first_key - 1));
+                }
+                _ => {
+                    return Err(invalid(format!(
+                        "shard {}-{} overlaps the one before it",
+                        shard.first_key, shard.last_key
+                    )));
+                }
+            }
+            next = shard.last_key.checked_add(1);
+        }
+        if let Some(expected) = next {
+            return Err(unowned(expected, 255));
+        }
+        Ok(Topology { shards })
+    }
+
+    pub fn parse(text: &str) -> io::Result<Topology> {
+        let mut shards = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |message: &str| invalid(format!("line {}: {message}", number + 1));
+            let mut fields = line.split_whitespace();
+            let range = fields.next().unwrap_or_default();
+            let (first, last) = range.split_once('-').unwrap_or((range, range));
+            let key = |text: &str| text.parse::<u8>().map_err(|_| error("keys must be 0-255"));
+            let mut shard = Shard {
+                first_key: key(first)?,
+                last_key: key(last)?,
+                unix_path: None,
+                tcp_addr: None,
+            };
+            for address in fields {
+                match address.split_once(':') {
+                    Some(("unix", path)) if shard.unix_path.is_none() => {
+                        shard.unix_path = Some(PathBuf::from(path));
+                    }
+                    Some(("tcp", addr)) if shard.tcp_addr.is_none() => {
+                        shard.tcp_addr = Some(addr.to_string());
+                    }
+                    _ => return Err(error("addresses are one unix:<path> and/or one tcp:<addr>")),
+                }
+            }
+            shards.push(shard);
+        }
+        Topology::new(shards)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Topology> {
+        let text = std::fs::read_to_string(path)?;
+        Topology::parse(&text).map_err(|e| invalid(format!("{}: {e}", path.display())))
+    }
+
+    pub fn shards(&self) -> &[Shard] {
+        &self.shards
+    }
+
+    /// Index into [`Topology::shards`] of the shard owning `key`.
+    pub fn shard_for(&self, key: u8) -> usize {
+        self.shards.partition_point(|shard| shard.last_key < key)
+    }
+}
+
+impl fmt::Display for Topology {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for shard in &self.shards {
+            write!(f, "{}-{}", shard.first_key, shard.last_key)?;
+            if let Some(path) = &shard.unix_path {
+                write!(f, " unix:{}", path.display())?;
+            }
+            if let Some(addr) = &shard.tcp_addr {
+                write!(f, " tcp:{addr}")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// One connection per shard of a [`Topology`], routing each keyed command
+/// to the shard that owns the key. Commands over the whole keyspace fan out
+/// to every shard in key order.
+#[derive(Debug)]
+pub struct Cluster {
+    topology: Topology,
+    clients: Vec<Client>,
+}
+
+impl Cluster {
+    /// Connects to every shard and negotiates the protocol version with
+    /// each. `config` supplies everything but the addresses: stagger,
+    /// timeout and auth token.
+    pub async fn connect(topology: Topology, config: &ClientConfig) -> Result<Cluster> {
+        let mut clients = Vec::with_capacity(topology.shards.len());
+        for shard in &topology.shards {
+            let config = ClientConfig {
+                unix_path: shard.unix_path.clone(),
+                tcp_addr: shard.tcp_addr.clone(),
+                ..config.clone()
+            };
+            let connected = async {
+                let mut client = Client::connect(&config).await?;
+                client.hello().await?;
+                Ok::<_, Map8x32Error>(client)
+            };
+            let client = connected.await.map_err(|e| {
+                e.context(format_args!("shard {}-{}", shard.first_key, shard.last_key))
+            })?;
+            clients.push(client);
+        }
+        Ok(Cluster { topology, clients })
+    }
+
+    pub fn topology(&self) -> &Topology {
+        &self.topology
+    }
+
+    /// The connection to the shard owning `key`, for commands the cluster
+    /// does not wrap.
+    pub fn shard(&mut self, key: u8) -> &mut Client {
+        &mut self.clients[self.topology.shard_for(key)]
+    }
+
+    pub async fn set(&mut self, key: u8, value: u32) -> Result<()> {
+        self.shard(key).set(key, value).await
+    }
+
+    pub async fn get(&mut self, key: u8) -> Result<Option<Vec<u32>>> {
+        self.shard(key).get(key).await
+    }
+
+    pub async fn set_sorted(&mut self, key: u8, value: u32) -> Result<()> {
+        self.shard(key).set_sorted(key, value).await
+    }
+
+    pub async fn get_sorted_range(
+        &mut self,
+        key: u8,
+        min: u32,
+        max: u32,
+    ) -> Result<Option<Vec<u32>>> {
+        self.shard(key).get_sorted_range(key, min, max).await
+    }
+
+    pub async fn get_filter(&mut self, key: u8, min: u32, max: u32) -> Result<Option<Vec<u32>>> {
+        self.shard(key).get_filter(key, min, max).await
+    }
+
+    pub async fn sum(&mut self, key: u8) -> Result<Option<u64>> {
+        self.shard(key).sum(key).await
+    }
+
+    pub async fn min(&mut self, key: u8) -> Result<Option<u32>> {
+        self.shard(key).min(key).await
+    }
+
+    pub async fn max(&mut self, key: u8) -> Result<Option<u32>> {
+        self.shard(key).max(key).await
+    }
+
+    pub async fn avg(&mut self, key: u8) -> Result<Option<f64>> {
+        self.shard(key).avg(key).await
+    }
+
+    pub async fn count(&mut self, key: u8) -> Result<Option<u64>> {
+        self.shard(key).count(key).await
+    }
+
+    pub async fn key_stats(&mut self, key: u8) -> Result<KeyStats> {
+        self.shard(key).key_stats(key).await
+    }
+
+    pub async fn memory_usage(&mut self, key: u8) -> Result<Option<u64>> {
+        self.shard(key).memory_usage(key).await
+    }
+
+    pub async fn get_last_n(&mut self, key: u8, n: u32) -> Result<Option<Vec<u32>>> {
+        self.shard(key).get_last_n(key, n).await
+    }
+
+    pub async fn get_first_n(&mut self, key: u8, n: u32) -> Result<Option<Vec<u32>>> {
+        self.shard(key).get_first_n(key, n).await
+    }
+
+    pub async fn set_expiring(&mut self, key: u8, value: u32, ttl: Duration) -> Result<()> {
+        self.shard(key).set_expiring(key, value, ttl).await
+    }
+
+    pub async fn set_timed(&mut self, key: u8, value: u32, at_ms: u64) -> Result<()> {
+        self.shard(key).set_timed(key, value, at_ms).await
+    }
+
+    pub async fn get_timed(&mut self, key: u8) -> Result<Option<Vec<Sample>>> {
+        self.shard(key).get_timed(key).await
+    }
+
+    pub async fn delete(&mut self, key: u8) -> Result<bool> {
+        self.shard(key).delete(key).await
+    }
+
+    pub async fn delete_if(&mut self, key: u8, expected: u32) -> Result<bool> {
+        self.shard(key).delete_if(key, expected).await
+    }
+
+    pub async fn delete_at(&mut self, key: u8, index: u32) -> Result<Option<u32>> {
+        self.shard(key).delete_at(key, index).await
+    }
+
+    pub async fn dedup(&mut self, key: u8) -> Result<Option<u32>> {
+        self.shard(key).dedup(key).await
+    }
+
+    pub async fn getdel(&mut self, key: u8) -> Result<Option<Vec<u32>>> {
+        self.shard(key).getdel(key).await
+    }
+
+    pub async fn getset(&mut self, key: u8, value: Option<u32>) -> Result<Vec<u32>> {
+        self.shard(key).getset(key, value).await
+    }
+
+    pub async fn sort(&mut self, key: u8, descending: bool) -> Result<bool> {
+        self.shard(key).sort(key, descending).await
+    }
+
+    pub async fn ttl(&mut self, key: u8) -> Result<Option<Ttl>> {
+        self.shard(key).ttl(key).await
+    }
+
+    pub async fn persist(&mut self, key: u8) -> Result<bool> {
+        self.shard(key).persist(key).await
+    }
+
+    pub async fn touch(&mut self, key: u8, ttl_ms: u32) -> Result<bool> {
+        self.shard(key).touch(key, ttl_ms).await
+    }
+
+    pub async fn get_versioned(&mut self, key: u8) -> Result<(u64, Vec<u32>)> {
+        self.shard(key).get_versioned(key).await
+    }
+
+    pub async fn replace_if(
+        &mut self,
+        key: u8,
+        expected_version: u64,
+        values: &[u32],
+    ) -> Result<bool> {
+        self.shard(key)
+            .replace_if(key, expected_version, values)
+            .await
+    }
+
+    pub async fn update<F>(&mut self, key: u8, f: F) -> Result<Vec<u32>>
+    where
+        F: FnMut(&[u32]) -> Vec<u32>,
+    {
+        self.shard(key).update(key, f).await
+    }
+
+    /// Every key across the cluster, ordered by key. Keys a server holds
+    /// outside its shard's range are left out.
+    pub async fn list_all(&mut self) -> Result<Vec<(u8, Vec<u32>)>> {
+        let mut entries = Vec::new();
+        for (shard, client) in self.topology.shards.iter().zip(&mut self.clients) {
+            let mut owned: Vec<_> = client
+                .list_all()
+                .await?
+                .into_iter()
+                .filter(|(key, _)| shard.owns(*key))
+                .collect();
+            owned.sort_unstable_by_key(|(key, _)| *key);
+            entries.append(&mut owned);
+        }
+        Ok(entries)
+    }
+
+    /// Clears every shard. Not atomic across shards: a failure part-way
+    /// leaves the earlier ones cleared.
+    pub async fn delete_all(&mut self, token: u32) -> Result<()> {
+        for client in &mut self.clients {
+            client.delete_all(token).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,162 @@
+use map8x32_protocol::StatusError;
+use std::borrow::Cow;
+use std::{fmt, io};
+
+pub type Result<T, E = Map8x32Error> = std::result::Result<T, E>;
+
+/// Why a client call failed.
+#[derive(Debug)]
+pub enum Map8x32Error {
+    /// The connection could not be made or broke.
+    Io(io::Error),
+    /// The server sent something that is not a valid answer.
+    Protocol(Cow<'static, str>),
+    /// The server refused the request with a status.
+    Status {
+        error: StatusError,
+        message: Cow<'static, str>,
+    },
+    /// Connecting or waiting for a pooled connection took too long.
+    Timeout(Cow<'static, str>),
+    /// The call was not made because its arguments or the config cannot
+    /// work, such as a config without an address.
+    InvalidInput(Cow<'static, str>),
+    /// [`Reconnecting`](crate::Reconnecting) gave up after `attempts`
+    /// connection attempts in a row.
+    RetriesExhausted {
+        attempts: u32,
+        last: Box<Map8x32Error>,
+    },
+}
+
+impl Map8x32Error {
+    pub(crate) fn protocol(message: impl Into<Cow<'static, str>>) -> Self {
+        Map8x32Error::Protocol(message.into())
+    }
+
+    pub(crate) fn invalid_input(message: impl Into<Cow<'static, str>>) -> Self {
+        Map8x32Error::InvalidInput(message.into())
+    }
+
+    /// The status the server refused the request with, or `None` when the
+    /// call failed for another reason.
+    pub fn status(&self) -> Option<StatusError> {
+        match self {
+            Map8x32Error::Status { error, .. } => Some(*error),
+            _ => None,
+        }
+    }
+
+    /// Whether the connection is gone or never came up, so the call might
+    /// succeed on a new one; refusals and bad answers are not.
+    pub fn is_disconnect(&self) -> bool {
+        match self {
+            Map8x32Error::Io(e) => matches!(
+                e.kind(),
+                io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::NotConnected
+                    | io::ErrorKind::UnexpectedEof
+                    | io::ErrorKind::NotFound
+            ),
+            Map8x32Error::Timeout(_) => true,
+            _ => false,
+        }
+    }
+
+    /// The closest `io::ErrorKind`, for callers that fold this into an
+    /// `io::Error`.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Map8x32Error::Io(e) => e.kind(),
+            Map8x32Error::Protocol(_) => io::ErrorKind::InvalidData,
+            Map8x32Error::Status { error, .. } => match error {
+                StatusError::BadRequest | StatusError::Conflict | StatusError::PayloadTooLarge => {
+                    io::ErrorKind::InvalidInput
+                }
+                StatusError::UnsupportedVersion => io::ErrorKind::Unsupported,
+                StatusError::CapacityExceeded => io::ErrorKind::StorageFull,
+                StatusError::KeyNotAllocated | StatusError::Unauthorized => {
+                    io::ErrorKind::PermissionDenied
+                }
+                StatusError::ReadOnly => io::ErrorKind::ReadOnlyFilesystem,
+                StatusError::Unavailable => io::ErrorKind::ResourceBusy,
+                StatusError::Throttled => io::ErrorKind::QuotaExceeded,
+                StatusError::Cancelled => io::ErrorKind::Interrupted,
+                StatusError::Timeout => io::ErrorKind::TimedOut,
+                StatusError::Stale | StatusError::InternalError => io::ErrorKind::Other,
+                StatusError::Unexpected(_) => io::ErrorKind::InvalidData,
+            },
+            Map8x32Error::Timeout(_) => io::ErrorKind::TimedOut,
+            Map8x32Error::InvalidInput(_) => io::ErrorKind::InvalidInput,
+            Map8x32Error::RetriesExhausted { last, .. } => last.kind(),
+        }
+    }
+
+    /// Prefixes the error's message with `context`, e.g. the shard it came
+    /// from.
+    pub(crate) fn context(self, context: impl fmt::Display) -> Self {
+        let prefixed = |message: Cow<'static, str>| Cow::Owned(format!("{context}: {message}"));
+        match self {
+            Map8x32Error::Io(e) => {
+                Map8x32Error::Io(io::Error::new(e.kind(), format!("{context}: {e}")))
+            }
+            Map8x32Error::Protocol(message) => Map8x32Error::Protocol(prefixed(message)),
+            Map8x32Error::Status { error, message } => Map8x32Error::Status {
+                error,
+                message: prefixed(message),
+            },
+            Map8x32Error::Timeout(message) => Map8x32Error::Timeout(prefixed(message)),
+            Map8x32Error::InvalidInput(message) => Map8x32Error::InvalidInput(prefixed(message)),
+            Map8x32Error::RetriesExhausted { attempts, last } => Map8x32Error::RetriesExhausted {
+                attempts,
+                last: Box::new(last.context(context)),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Map8x32Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Map8x32Error::Io(e) => e.fmt(f),
+            Map8x32Error::Protocol(message)
+            | Map8x32Error::Status { message, .. }
+            | Map8x32Error::Timeout(message)
+            | Map8x32Error::InvalidInput(message) => f.write_str(message),
+            Map8x32Error::RetriesExhausted { attempts, last } => {
+                write!(f, "gave up reconnecting after {attempts} attempts: {last}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Map8x32Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Map8x32Error::Io(e) => Some(e),
+            Map8x32Error::Status { error, .. } => Some(error),
+            Map8x32Error::RetriesExhausted { last, .. } => Some(last.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Map8x32Error {
+    fn from(error: io::Error) -> Self {
+        Map8x32Error::Io(error)
+    }
+}
+
+/// Keeps the typed error inside, where `get_ref` and `downcast_ref` find
+/// it, unless it was an `io::Error` to begin with.
+impl From<Map8x32Error> for io::Error {
+    fn from(error: Map8x32Error) -> Self {
+        match error {
+            Map8x32Error::Io(e) => e,
+            error => io::Error::new(error.kind(), error),
+        }
+    }
+}
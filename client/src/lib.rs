@@ -0,0 +1,1729 @@
+mod cache;
+mod cluster;
+mod error;
+mod monitor;
+mod multiplex;
+mod pipeline;
+mod pool;
+mod reconnect;
+#[cfg(all(feature = "shm", unix))]
+mod shm;
+mod subscription;
+
+pub use cache::{CacheStats, Cached, DEFAULT_CACHE_CAPACITY};
+pub use cluster::{Cluster, Shard, Topology};
+pub use error::{Map8x32Error, Result};
+pub use map8x32_protocol::codec::{
+    Comparison, ConnectedClient, Health, MonitoredOp, OpLatency, PersistenceState, Quantiles,
+    Request, Response,
+};
+#[cfg(all(feature = "shm", unix))]
+pub use map8x32_protocol::shm::Mirrored;
+pub use map8x32_protocol::timed::{Sample, Summary};
+pub use map8x32_protocol::StatusError;
+use map8x32_protocol::*;
+pub use monitor::Monitor;
+pub use multiplex::Multiplexed;
+pub use pipeline::Pipeline;
+pub use pool::{Pool, PoolConfig, Pooled, DEFAULT_CHECKOUT_TIMEOUT, DEFAULT_MAX_CONNECTIONS};
+pub use reconnect::{Reconnecting, RetryPolicy};
+#[cfg(all(feature = "shm", unix))]
+pub use shm::ShmReader;
+use std::borrow::Cow;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+pub use subscription::Event;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::time::{sleep, timeout};
+
+pub const DEFAULT_STAGGER: Duration = Duration::from_millis(50);
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+pub const MAX_UPDATE_ATTEMPTS: usize = 64;
+
+/// Where and how a [`Client`] connects.
+///
+/// When both `unix_path` and `tcp_addr` are set, the Unix socket is tried
+/// first and the TCP address is raced against it after `stagger` (or as soon
+/// as the Unix attempt fails), keeping whichever connects first. Platforms
+/// without Unix sockets, such as Windows, connect over TCP only, so a
+/// `unix_path` there fails at once and falls back to `tcp_addr`.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub unix_path: Option<PathBuf>,
+    pub tcp_addr: Option<String>,
+    pub stagger: Duration,
+    pub connect_timeout: Duration,
+    /// Sent with AUTH right after connecting, for servers that require it.
+    pub auth_token: Option<String>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            unix_path: Some(PathBuf::from(DEFAULT_SOCKET_PATH)),
+            tcp_addr: None,
+            stagger: DEFAULT_STAGGER,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            auth_token: None,
+        }
+    }
+}
+
+impl ClientConfig {
+    pub fn unix(path: impl Into<PathBuf>) -> Self {
+        ClientConfig {
+            unix_path: Some(path.into()),
+            ..ClientConfig::default()
+        }
+    }
+
+    pub fn tcp(addr: impl Into<String>) -> Self {
+        ClientConfig {
+            unix_path: None,
+            tcp_addr: Some(addr.into()),
+            ..ClientConfig::default()
+        }
+    }
+
+    pub fn with_tcp_fallback(mut self, addr: impl Into<String>) -> Self {
+        self.tcp_addr = Some(addr.into());
+        self
+    }
+
+    pub fn with_stagger(mut self, stagger: Duration) -> Self {
+        self.stagger = stagger;
+        self
+    }
+
+    pub fn with_auth_token(mut self, token: impl Into<String>) -> Self {
+        self.auth_token = Some(token.into());
+        self
+    }
+}
+
+#[derive(Debug)]
+pub enum Stream {
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            #[cfg(unix)]
+            Stream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+            Stream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            #[cfg(unix)]
+            Stream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+            Stream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            #[cfg(unix)]
+            Stream::Unix(s) => Pin::new(s).poll_flush(cx),
+            Stream::Tcp(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            #[cfg(unix)]
+            Stream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+            Stream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn connect_unix(path: &PathBuf) -> io::Result<Stream> {
+    UnixStream::connect(path).await.map(Stream::Unix)
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(path: &PathBuf) -> io::Result<Stream> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "cannot connect to {}: this platform has no Unix sockets; configure a TCP address",
+            path.display()
+        ),
+    ))
+}
+
+async fn connect_tcp(addr: &str) -> io::Result<Stream> {
+    let stream = TcpStream::connect(addr).await?;
+    stream.set_nodelay(true)?;
+    Ok(Stream::Tcp(stream))
+}
+
+async fn connect_racing(path: &PathBuf, addr: &str, stagger: Duration) -> io::Result<Stream> {
+    let unix = connect_unix(path);
+    let delay = sleep(stagger);
+    tokio::pin!(unix, delay);
+
+    tokio::select! {
+        res = &mut unix => return match res {
+            Ok(stream) => Ok(stream),
+            Err(_) => connect_tcp(addr).await,
+        },
+        _ = &mut delay => {}
+    }
+
+    let tcp = connect_tcp(addr);
+    tokio::pin!(tcp);
+
+    tokio::select! {
+        res = &mut unix => match res {
+            Ok(stream) => Ok(stream),
+            Err(_) => tcp.await,
+        },
+        res = &mut tcp => match res {
+            Ok(stream) => Ok(stream),
+            Err(_) => unix.await,
+        },
+    }
+}
+
+/// The highest mutation sequence number observed by a group of clients.
+///
+/// Clients that share a session (for example every connection in a pool)
+/// fence their reads on it, so a read never observes state older than a
+/// write the session has already seen acknowledged. Requires protocol
+/// version 2, negotiated with [`Client::hello`].
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    seq: Arc<AtomicU64>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Session::default()
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq.load(Ordering::Acquire)
+    }
+
+    fn observe(&self, seq: u64) {
+        self.seq.fetch_max(seq, Ordering::AcqRel);
+    }
+}
+
+/// A write the server refused, as reported by [`Client::dead_letters`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadLetter {
+    pub at_unix_ms: u64,
+    pub op: u8,
+    pub key: u8,
+    pub value: u32,
+    pub reason: u8,
+}
+
+/// A command the server took at least its slow-log threshold to handle, as
+/// reported by [`Client::slow_log`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlowOp {
+    pub at_unix_ms: u64,
+    pub op: u8,
+    /// 0 for commands without a key.
+    pub key: u8,
+    pub duration: Duration,
+    pub request_bytes: u32,
+}
+
+/// A copy of the whole store, as returned by [`Client::sync_full`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FullSync {
+    /// Sequence number of the last mutation the copy includes.
+    pub seq: u64,
+    /// Every key with its values, ordered by key.
+    pub entries: Vec<(u8, Vec<u32>)>,
+}
+
+/// A value written with [`Client::set_typed`]. Each key holds one type,
+/// fixed by the first value written to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    U32(u32),
+    U64(u64),
+    /// At most 255 bytes.
+    Bytes(Vec<u8>),
+}
+
+impl Value {
+    fn value_type(&self) -> u8 {
+        match self {
+            Value::U32(_) => VALUE_TYPE_U32,
+            Value::U64(_) => VALUE_TYPE_U64,
+            Value::Bytes(_) => VALUE_TYPE_BYTES,
+        }
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        match self {
+            Value::U32(value) => value.to_le_bytes().to_vec(),
+            Value::U64(value) => value.to_le_bytes().to_vec(),
+            Value::Bytes(bytes) => bytes.clone(),
+        }
+    }
+}
+
+/// How long a key has left to live, as reported by [`Client::ttl`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ttl {
+    /// The key holds a value that never expires.
+    Persistent,
+    /// The key's last value expires after this long.
+    Expires(Duration),
+}
+
+/// A key range registered to an owning application.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Allocation {
+    pub first_key: u8,
+    pub last_key: u8,
+    pub name: String,
+    pub contact: String,
+    pub policy: String,
+}
+
+/// Server statistics, as reported by [`Client::info`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Info {
+    pub uptime: Duration,
+    pub keys: u64,
+    pub values: u64,
+    pub memory_bytes: u64,
+    pub hits: u64,
+    pub misses: u64,
+    /// Commands executed, by operation name.
+    pub ops: Vec<(String, u64)>,
+    /// How the database's persistence is holding up; `None` without a data
+    /// directory.
+    pub persistence: Option<PersistenceState>,
+    /// Every registered key range, as [`Client::allocations`] lists them.
+    pub allocations: Vec<Allocation>,
+    /// Writes refused by the memory quota or the key and value limits.
+    pub quota_rejections: u64,
+    /// The most keys the database holds; `None` for no limit.
+    pub max_keys: Option<u64>,
+    /// The most values the database holds across its keys; `None` for no
+    /// limit.
+    pub max_values: Option<u64>,
+}
+
+/// A key's access counters, as reported by [`Client::key_stats`]. They
+/// count from the server's startup and survive the key's deletion.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub sets: u64,
+    /// When a keyed command last named the key, in Unix milliseconds; 0 if
+    /// none has.
+    pub last_access_ms: u64,
+}
+
+fn refused(status: u8, message: impl Into<Cow<'static, str>>) -> Map8x32Error {
+    Map8x32Error::Status {
+        error: StatusError::new(status),
+        message: message.into(),
+    }
+}
+
+fn status_error(status: u8) -> Map8x32Error {
+    let error = StatusError::new(status);
+    Map8x32Error::Status {
+        error,
+        message: error.to_string().into(),
+    }
+}
+
+#[derive(Debug)]
+pub struct Client {
+    stream: Stream,
+    version: u8,
+    session: Option<Session>,
+}
+
+impl Client {
+    pub async fn connect(config: &ClientConfig) -> Result<Client> {
+        let attempt = async {
+            match (&config.unix_path, &config.tcp_addr) {
+                (Some(path), Some(addr)) => Ok(connect_racing(path, addr, config.stagger).await?),
+                (Some(path), None) => Ok(connect_unix(path).await?),
+                (None, Some(addr)) => Ok(connect_tcp(addr).await?),
+                (None, None) => Err(Map8x32Error::invalid_input(
+                    "no unix socket path or tcp address configured",
+                )),
+            }
+        };
+
+        let stream = timeout(config.connect_timeout, attempt)
+            .await
+            .map_err(|_| Map8x32Error::Timeout("connect timed out".into()))??;
+        let mut client = Client {
+            stream,
+            version: MIN_PROTOCOL_VERSION,
+            session: None,
+        };
+        if let Some(token) = &config.auth_token {
+            client.authenticate(token).await?;
+        }
+        Ok(client)
+    }
+
+    /// Sends AUTH; servers with auth enabled refuse every other command
+    /// except HELLO until this succeeds.
+    pub async fn authenticate(&mut self, token: &str) -> Result<()> {
+        let mut request = Frame::new(OP_AUTH, 0, token.len() as u32).encode().to_vec();
+        request.extend_from_slice(token.as_bytes());
+        self.stream.write_all(&request).await?;
+        match self.status().await? {
+            STATUS_OK => Ok(()),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Switches this connection to database `db`; the data commands that
+    /// follow see only that database. Sequence numbers are counted per
+    /// database, so a [`Session`] should stay on one.
+    pub async fn select(&mut self, db: u8) -> Result<()> {
+        match self.send(OP_SELECT, db, 0).await? {
+            STATUS_OK => Ok(()),
+            STATUS_BAD_REQUEST => Err(refused(
+                STATUS_BAD_REQUEST,
+                format!("server has no database {db}"),
+            )),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Queues this connection's later commands at `class`, one of the
+    /// `PRIORITY_*` constants, so the server runs them before or after
+    /// other connections'. Classes above the one the server gives this
+    /// connection's user are refused.
+    pub async fn prioritize(&mut self, class: u8) -> Result<()> {
+        match self.send(OP_PRIORITY, class, 0).await? {
+            STATUS_OK => Ok(()),
+            STATUS_UNAUTHORIZED => Err(refused(
+                STATUS_UNAUTHORIZED,
+                format!("priority {class} is above this connection's"),
+            )),
+            status => Err(status_error(status)),
+        }
+    }
+
+    pub fn is_tcp(&self) -> bool {
+        matches!(self.stream, Stream::Tcp(_))
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn join_session(&mut self, session: Session) {
+        self.session = Some(session);
+    }
+
+    async fn send(&mut self, op: u8, key: u8, value: u32) -> Result<u8> {
+        self.stream
+            .write_all(&Frame::new(op, key, value).encode())
+            .await?;
+        self.status().await
+    }
+
+    /// Reads a response's status, answering any keepalive probes ahead of
+    /// it with PONG.
+    async fn status(&mut self) -> Result<u8> {
+        loop {
+            match self.stream.read_u8().await? {
+                STATUS_PROBE => {
+                    self.stream
+                        .write_all(&Frame::new(OP_PONG, 0, 0).encode())
+                        .await?
+                }
+                status => return Ok(status),
+            }
+        }
+    }
+
+    async fn send_write(&mut self, op: u8, key: u8, value: u32) -> Result<u8> {
+        self.send_write_request(&Frame::new(op, key, value).encode())
+            .await
+    }
+
+    async fn send_write_request(&mut self, request: &[u8]) -> Result<u8> {
+        self.stream.write_all(request).await?;
+        self.read_write_ack().await
+    }
+
+    async fn read_write_ack(&mut self) -> Result<u8> {
+        let status = self.status().await?;
+        if self.version >= SESSION_PROTOCOL_VERSION {
+            let seq = self.stream.read_u64_le().await?;
+            if let Some(session) = &self.session {
+                session.observe(seq);
+            }
+        }
+        Ok(status)
+    }
+
+    /// Sends a read, preceded by a sequence fence when this client belongs
+    /// to a session. Returns the read's status and whether the fence held.
+    async fn send_read(&mut self, op: u8, key: u8) -> Result<(u8, bool)> {
+        self.send_read_request(&Frame::new(op, key, 0).encode())
+            .await
+    }
+
+    async fn send_read_request(&mut self, read: &[u8]) -> Result<(u8, bool)> {
+        let fence = match &self.session {
+            Some(session) if self.version >= SESSION_PROTOCOL_VERSION && session.seq() > 0 => {
+                Some(session.seq())
+            }
+            _ => None,
+        };
+
+        let mut request = Vec::with_capacity(2 * FRAME_LEN + 8);
+        if let Some(seq) = fence {
+            request.extend_from_slice(&Frame::new(OP_AWAIT_SEQ, 0, 0).encode());
+            request.extend_from_slice(&seq.to_le_bytes());
+        }
+        request.extend_from_slice(read);
+        self.stream.write_all(&request).await?;
+
+        let mut fresh = true;
+        if fence.is_some() {
+            let fence_status = self.status().await?;
+            self.stream.read_u64_le().await?;
+            fresh = fence_status == STATUS_OK;
+        }
+        Ok((self.status().await?, fresh))
+    }
+
+    fn stale(&self) -> Map8x32Error {
+        let seq = self.session.as_ref().map_or(0, Session::seq);
+        refused(
+            STATUS_STALE,
+            format!("server has not applied session sequence {seq} yet"),
+        )
+    }
+
+    async fn read_values(&mut self) -> Result<Vec<u32>> {
+        let count = self.stream.read_u32_le().await?;
+        let mut values = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            values.push(self.stream.read_u32_le().await?);
+        }
+        Ok(values)
+    }
+
+    /// Checks that the server is answering, returning its release version.
+    /// Works before AUTH and never touches stored data.
+    pub async fn ping(&mut self) -> Result<String> {
+        match self.send(OP_PING, 0, 0).await? {
+            STATUS_OK => {
+                let mut version = vec![0u8; self.stream.read_u8().await? as usize];
+                self.stream.read_exact(&mut version).await?;
+                Ok(String::from_utf8_lossy(&version).into_owned())
+            }
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Asks the server to probe this connection while it is idle, returning
+    /// whether it will; only TCP connections to a server run with
+    /// `--keepalive-ms` are probed. Probes are answered as responses are
+    /// read, so a connection left unused for two intervals is closed.
+    pub async fn keepalive(&mut self) -> Result<bool> {
+        match self.send(OP_KEEPALIVE, 0, 0).await? {
+            STATUS_OK => Ok(true),
+            STATUS_NOT_FOUND => Ok(false),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Negotiates the protocol version, returning the version agreed on.
+    pub async fn hello(&mut self) -> Result<u8> {
+        match self
+            .send(OP_HELLO, PROTOCOL_VERSION, PROTOCOL_MAGIC)
+            .await?
+        {
+            STATUS_OK => {
+                let version = self.stream.read_u8().await?;
+                let magic = self.stream.read_u32_le().await?;
+                if magic != PROTOCOL_MAGIC {
+                    return Err(Map8x32Error::protocol(
+                        "server answered HELLO with a bad magic",
+                    ));
+                }
+                self.version = version;
+                Ok(version)
+            }
+            STATUS_UNSUPPORTED_VERSION => {
+                let server_version = self.stream.read_u8().await?;
+                Err(refused(
+                    STATUS_UNSUPPORTED_VERSION,
+                    format!("server speaks protocol version {server_version}"),
+                ))
+            }
+            status => Err(status_error(status)),
+        }
+    }
+
+    pub async fn set(&mut self, key: u8, value: u32) -> Result<()> {
+        match self.send_write(OP_SET, key, value).await? {
+            STATUS_OK => Ok(()),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Sends every SET in `writes` before reading any answer, saving a round
+    /// trip per value. Each write is still applied and answered on its own,
+    /// so on failure the writes before it stay stored; the first failure is
+    /// returned once every answer is in. Keep batches to a few thousand
+    /// writes so the answers fit in the socket buffers.
+    pub async fn set_batch(&mut self, writes: &[(u8, u32)]) -> Result<()> {
+        let mut request = Vec::with_capacity(writes.len() * FRAME_LEN);
+        for &(key, value) in writes {
+            request.extend_from_slice(&Frame::new(OP_SET, key, value).encode());
+        }
+        self.stream.write_all(&request).await?;
+        let mut result = Ok(());
+        for _ in writes {
+            let status = self.read_write_ack().await?;
+            if status != STATUS_OK && result.is_ok() {
+                result = Err(status_error(status));
+            }
+        }
+        result
+    }
+
+    pub async fn get(&mut self, key: u8) -> Result<Option<Vec<u32>>> {
+        let (status, fresh) = self.send_read(OP_GET, key).await?;
+        let values = match status {
+            STATUS_OK => Some(self.read_values().await?),
+            STATUS_NOT_FOUND => None,
+            status => return Err(status_error(status)),
+        };
+        if !fresh {
+            return Err(self.stale());
+        }
+        Ok(values)
+    }
+
+    /// The newest `n` of `key`'s values, oldest first.
+    pub async fn get_last_n(&mut self, key: u8, n: u32) -> Result<Option<Vec<u32>>> {
+        self.get_end(OP_GET_LAST_N, key, n).await
+    }
+
+    /// The oldest `n` of `key`'s values.
+    pub async fn get_first_n(&mut self, key: u8, n: u32) -> Result<Option<Vec<u32>>> {
+        self.get_end(OP_GET_FIRST_N, key, n).await
+    }
+
+    async fn get_end(&mut self, op: u8, key: u8, n: u32) -> Result<Option<Vec<u32>>> {
+        let (status, fresh) = self
+            .send_read_request(&Frame::new(op, key, n).encode())
+            .await?;
+        let values = match status {
+            STATUS_OK => Some(self.read_values().await?),
+            STATUS_NOT_FOUND => None,
+            status => return Err(status_error(status)),
+        };
+        if !fresh {
+            return Err(self.stale());
+        }
+        Ok(values)
+    }
+
+    /// Inserts `value` into `key` after any equal values. A key written only
+    /// this way stays in ascending order for [`Client::get_sorted_range`].
+    pub async fn set_sorted(&mut self, key: u8, value: u32) -> Result<()> {
+        match self.send_write(OP_SET_SORTED, key, value).await? {
+            STATUS_OK => Ok(()),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// The values between `min` and `max` inclusive, in ascending order. A
+    /// sorted key is binary-searched; any other is scanned.
+    pub async fn get_sorted_range(
+        &mut self,
+        key: u8,
+        min: u32,
+        max: u32,
+    ) -> Result<Option<Vec<u32>>> {
+        let mut request = Frame::new(OP_GET_SORTED_RANGE, key, min).encode().to_vec();
+        request.extend_from_slice(&max.to_le_bytes());
+        let (status, fresh) = self.send_read_request(&request).await?;
+        let values = match status {
+            STATUS_OK => Some(self.read_values().await?),
+            STATUS_NOT_FOUND => None,
+            status => return Err(status_error(status)),
+        };
+        if !fresh {
+            return Err(self.stale());
+        }
+        Ok(values)
+    }
+
+    /// The distinct values `a` and `b` hold as `comparison` selects, worked
+    /// out by the server so neither key's values cross the connection. A
+    /// key that does not exist compares as empty; returns `None` if neither
+    /// does.
+    pub async fn compare(
+        &mut self,
+        a: u8,
+        b: u8,
+        comparison: Comparison,
+    ) -> Result<Option<Vec<u32>>> {
+        let value = u32::from(b) | comparison.flag() << 8;
+        let (status, fresh) = self
+            .send_read_request(&Frame::new(OP_COMPARE, a, value).encode())
+            .await?;
+        let values = match status {
+            STATUS_OK => Some(self.read_values().await?),
+            STATUS_NOT_FOUND => None,
+            status => return Err(status_error(status)),
+        };
+        if !fresh {
+            return Err(self.stale());
+        }
+        Ok(values)
+    }
+
+    /// The values of `key` between `min` and `max` inclusive, in the order
+    /// they are stored, filtered by the server.
+    pub async fn get_filter(&mut self, key: u8, min: u32, max: u32) -> Result<Option<Vec<u32>>> {
+        let mut request = Frame::new(OP_GET_FILTER, key, min).encode().to_vec();
+        request.extend_from_slice(&max.to_le_bytes());
+        let (status, fresh) = self.send_read_request(&request).await?;
+        let values = match status {
+            STATUS_OK => Some(self.read_values().await?),
+            STATUS_NOT_FOUND => None,
+            status => return Err(status_error(status)),
+        };
+        if !fresh {
+            return Err(self.stale());
+        }
+        Ok(values)
+    }
+
+    /// The sum of `key`'s values, or `None` if the key does not exist.
+    pub async fn sum(&mut self, key: u8) -> Result<Option<u64>> {
+        self.aggregate(OP_SUM, key).await
+    }
+
+    /// The smallest of `key`'s values, or `None` if the key does not exist.
+    pub async fn min(&mut self, key: u8) -> Result<Option<u32>> {
+        Ok(self.aggregate(OP_MIN, key).await?.map(|min| min as u32))
+    }
+
+    /// The largest of `key`'s values, or `None` if the key does not exist.
+    pub async fn max(&mut self, key: u8) -> Result<Option<u32>> {
+        Ok(self.aggregate(OP_MAX, key).await?.map(|max| max as u32))
+    }
+
+    /// The mean of `key`'s values, or `None` if the key does not exist.
+    pub async fn avg(&mut self, key: u8) -> Result<Option<f64>> {
+        Ok(self.aggregate(OP_AVG, key).await?.map(f64::from_bits))
+    }
+
+    /// How many values `key` holds, or `None` if it does not exist.
+    pub async fn count(&mut self, key: u8) -> Result<Option<u64>> {
+        self.aggregate(OP_COUNT, key).await
+    }
+
+    async fn aggregate(&mut self, op: u8, key: u8) -> Result<Option<u64>> {
+        let (status, fresh) = self.send_read(op, key).await?;
+        let result = match status {
+            STATUS_OK => Some(self.stream.read_u64_le().await?),
+            STATUS_NOT_FOUND => None,
+            status => return Err(status_error(status)),
+        };
+        if !fresh {
+            return Err(self.stale());
+        }
+        Ok(result)
+    }
+
+    /// Roughly how many bytes `key` takes in the server's memory, or `None`
+    /// if it does not exist.
+    pub async fn memory_usage(&mut self, key: u8) -> Result<Option<u64>> {
+        self.memory(&Frame::new(OP_MEMUSAGE, key, 0).encode()).await
+    }
+
+    /// Roughly how many bytes every key of the selected database takes,
+    /// summed; unlike INFO's estimate it counts spare capacity.
+    pub async fn total_memory_usage(&mut self) -> Result<u64> {
+        let bytes = self.memory(&Frame::new(OP_MEMUSAGE, 0, 1).encode()).await?;
+        Ok(bytes.unwrap_or(0))
+    }
+
+    async fn memory(&mut self, request: &[u8]) -> Result<Option<u64>> {
+        let (status, fresh) = self.send_read_request(request).await?;
+        let bytes = match status {
+            STATUS_OK => Some(self.stream.read_u64_le().await?),
+            STATUS_NOT_FOUND => None,
+            status => return Err(status_error(status)),
+        };
+        if !fresh {
+            return Err(self.stale());
+        }
+        Ok(bytes)
+    }
+
+    /// Appends `value` to `key` for `ttl`, rounded down to milliseconds.
+    /// Reads skip it once it has expired, and the server purges it soon
+    /// after. A `ttl` over `u32::MAX` milliseconds fails with
+    /// [`Map8x32Error::InvalidInput`].
+    pub async fn set_expiring(&mut self, key: u8, value: u32, ttl: Duration) -> Result<()> {
+        let ttl_ms = u32::try_from(ttl.as_millis())
+            .map_err(|_| Map8x32Error::invalid_input(format!("ttl is at most {} ms", u32::MAX)))?;
+        let mut request = Frame::new(OP_SET_EXPIRING, key, value).encode().to_vec();
+        request.extend_from_slice(&ttl_ms.to_le_bytes());
+        match self.send_write_request(&request).await? {
+            STATUS_OK => Ok(()),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Appends `value` to `key` as taken at `at_ms`, in Unix milliseconds,
+    /// or when the server receives it with 0. A key that holds other values
+    /// refuses it with [`StatusError::Conflict`].
+    pub async fn set_timed(&mut self, key: u8, value: u32, at_ms: u64) -> Result<()> {
+        let mut request = Frame::new(OP_SET_TIMED, key, value).encode().to_vec();
+        request.extend_from_slice(&at_ms.to_le_bytes());
+        match self.send_write_request(&request).await? {
+            STATUS_OK => Ok(()),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Appends all of `values` to `key` in one step, so no read sees only
+    /// some of them, and returns how many values the key then holds.
+    pub async fn push(&mut self, key: u8, values: &[u32]) -> Result<u32> {
+        let count = u32::try_from(values.len())
+            .ok()
+            .filter(|&count| count <= MAX_REPLACE_VALUES)
+            .ok_or_else(|| {
+                Map8x32Error::invalid_input(format!(
+                    "at most {MAX_REPLACE_VALUES} values can be written at once"
+                ))
+            })?;
+        let mut request = Vec::with_capacity(FRAME_LEN + values.len() * 4);
+        request.extend_from_slice(&Frame::new(OP_PUSH, key, count).encode());
+        for value in values {
+            request.extend_from_slice(&value.to_le_bytes());
+        }
+        match self.send_write_request(&request).await? {
+            STATUS_OK => Ok(self.stream.read_u32_le().await?),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// The samples of a key written with [`Client::set_timed`]: the
+    /// summaries of the windows already rolled up, then the recent values.
+    pub async fn get_timed(&mut self, key: u8) -> Result<Option<Vec<Sample>>> {
+        match self.get(key).await? {
+            Some(words) => timed::decode(&words)
+                .map(Some)
+                .ok_or_else(|| Map8x32Error::protocol(format!("key {key} holds no timed samples"))),
+            None => Ok(None),
+        }
+    }
+
+    /// Appends a typed value to `key`. Writing a value of a different type
+    /// than the key already holds is refused with [`StatusError::Conflict`].
+    pub async fn set_typed(&mut self, key: u8, value: &Value) -> Result<()> {
+        let payload = value.payload();
+        if payload.len() > MAX_BLOB_LEN {
+            return Err(Map8x32Error::invalid_input(format!(
+                "values are at most {MAX_BLOB_LEN} bytes"
+            )));
+        }
+        let mut request = Frame::new(OP_SET_TYPED, key, 1 + payload.len() as u32)
+            .encode()
+            .to_vec();
+        request.push(value.value_type());
+        request.extend_from_slice(&payload);
+        match self.send_write_request(&request).await? {
+            STATUS_OK => Ok(()),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// A key's values decoded by the type they were written as; keys written
+    /// with [`Client::set`] come back as [`Value::U32`].
+    pub async fn get_typed(&mut self, key: u8) -> Result<Option<Vec<Value>>> {
+        let (status, fresh) = self.send_read(OP_GET_TYPED, key).await?;
+        let values = match status {
+            STATUS_OK => {
+                let value_type = self.stream.read_u8().await?;
+                let count = self.stream.read_u32_le().await?;
+                let mut values = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let value = match value_type {
+                        VALUE_TYPE_U32 => Value::U32(self.stream.read_u32_le().await?),
+                        VALUE_TYPE_U64 => Value::U64(self.stream.read_u64_le().await?),
+                        VALUE_TYPE_BYTES => {
+                            let mut bytes = vec![0u8; self.stream.read_u8().await? as usize];
+                            self.stream.read_exact(&mut bytes).await?;
+                            Value::Bytes(bytes)
+                        }
+                        _ => {
+                            return Err(Map8x32Error::protocol(format!(
+                                "server sent unknown value type {value_type}"
+                            )))
+                        }
+                    };
+                    values.push(value);
+                }
+                Some(values)
+            }
+            STATUS_NOT_FOUND => None,
+            status => return Err(status_error(status)),
+        };
+        if !fresh {
+            return Err(self.stale());
+        }
+        Ok(values)
+    }
+
+    /// Removes `key`, returning whether it existed.
+    pub async fn delete(&mut self, key: u8) -> Result<bool> {
+        match self.send_write(OP_DELETE_BY_KEY, key, 0).await? {
+            STATUS_OK => Ok(true),
+            STATUS_NOT_FOUND => Ok(false),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// How long until `key`'s last value expires, or `None` if the key does
+    /// not exist.
+    pub async fn ttl(&mut self, key: u8) -> Result<Option<Ttl>> {
+        let (status, fresh) = self.send_read(OP_TTL, key).await?;
+        let ttl = match status {
+            STATUS_OK => Some(match self.stream.read_u64_le().await? {
+                0 => Ttl::Persistent,
+                ttl_ms => Ttl::Expires(Duration::from_millis(ttl_ms)),
+            }),
+            STATUS_NOT_FOUND => None,
+            status => return Err(status_error(status)),
+        };
+        if !fresh {
+            return Err(self.stale());
+        }
+        Ok(ttl)
+    }
+
+    /// Clears the expiry of every value `key` holds, so none of them expire.
+    /// Returns `false` if the key does not exist.
+    pub async fn persist(&mut self, key: u8) -> Result<bool> {
+        match self.send_write(OP_PERSIST, key, 0).await? {
+            STATUS_OK => Ok(true),
+            STATUS_NOT_FOUND => Ok(false),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Counts an access to `key` without reading its values, and with a
+    /// nonzero `ttl_ms` moves the expiry of each of its expiring values to
+    /// `ttl_ms` from now. Returns `false` if the key does not exist.
+    pub async fn touch(&mut self, key: u8, ttl_ms: u32) -> Result<bool> {
+        match self.send_write(OP_TOUCH, key, ttl_ms).await? {
+            STATUS_OK => Ok(true),
+            STATUS_NOT_FOUND => Ok(false),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Deletes `key` only if it holds exactly `expected` values, so values
+    /// another client wrote since they were counted are not lost. Returns
+    /// `false` if the key does not exist or holds a different number.
+    pub async fn delete_if(&mut self, key: u8, expected: u32) -> Result<bool> {
+        match self.send_write(OP_DELETE_IF, key, expected).await? {
+            STATUS_OK => Ok(true),
+            STATUS_NOT_FOUND | STATUS_CONFLICT => Ok(false),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Removes the value at `index` among `key`'s values, counting from the
+    /// oldest, and returns it; `None` if the key has no value there.
+    pub async fn delete_at(&mut self, key: u8, index: u32) -> Result<Option<u32>> {
+        match self.send_write(OP_DELETE_AT, key, index).await? {
+            STATUS_OK => Ok(Some(self.stream.read_u32_le().await?)),
+            STATUS_NOT_FOUND => Ok(None),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Removes every value of `key` equal to an earlier one, keeping the
+    /// first of each, and returns how many were removed; `None` if the key
+    /// does not exist.
+    pub async fn dedup(&mut self, key: u8) -> Result<Option<u32>> {
+        match self.send_write(OP_DEDUP, key, 0).await? {
+            STATUS_OK => Ok(Some(self.stream.read_u32_le().await?)),
+            STATUS_NOT_FOUND => Ok(None),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Deletes `key` and returns the values it held, in one step, so a
+    /// single consumer can take a queue's contents without racing the
+    /// producers; `None` if the key does not exist.
+    pub async fn getdel(&mut self, key: u8) -> Result<Option<Vec<u32>>> {
+        match self.send_write(OP_GETDEL, key, 0).await? {
+            STATUS_OK => Ok(Some(self.read_values().await?)),
+            STATUS_NOT_FOUND => Ok(None),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Replaces `key`'s values with `value`, or deletes the key when it is
+    /// `None`, and returns the values it held, in one step, so a buffer can
+    /// be rotated and what was in it processed; empty if it held none.
+    pub async fn getset(&mut self, key: u8, value: Option<u32>) -> Result<Vec<u32>> {
+        let mut request = Vec::with_capacity(FRAME_LEN + 4);
+        request.extend_from_slice(&Frame::new(OP_GETSET, key, value.is_some() as u32).encode());
+        if let Some(value) = value {
+            request.extend_from_slice(&value.to_le_bytes());
+        }
+        match self.send_write_request(&request).await? {
+            STATUS_OK => self.read_values().await,
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Sorts `key`'s values in place, ascending or, with `descending`,
+    /// descending. Returns `false` if the key does not exist.
+    pub async fn sort(&mut self, key: u8, descending: bool) -> Result<bool> {
+        match self.send_write(OP_SORT, key, descending as u32).await? {
+            STATUS_OK => Ok(true),
+            STATUS_NOT_FOUND => Ok(false),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Moves `from`'s values to `to` in one step and deletes `from`. `to`'s
+    /// values are replaced or, with `merge`, kept with `from`'s appended.
+    /// Returns `false` if `from` does not exist.
+    pub async fn rename(&mut self, from: u8, to: u8, merge: bool) -> Result<bool> {
+        let value = u32::from(to) | (merge as u32) << 8;
+        match self.send_write(OP_RENAME, from, value).await? {
+            STATUS_OK => Ok(true),
+            STATUS_NOT_FOUND => Ok(false),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Exchanges `a`'s values with `b`'s in one step, so a dataset written to
+    /// a staging key can replace the live one without readers seeing it
+    /// half-written. Returns `false` if neither key exists.
+    pub async fn swap(&mut self, a: u8, b: u8) -> Result<bool> {
+        match self.send_write(OP_SWAP, a, b.into()).await? {
+            STATUS_OK => Ok(true),
+            STATUS_NOT_FOUND => Ok(false),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Copies `from`'s values to `to` in one step, leaving `from` as it is.
+    /// `to`'s values are replaced or, with `append`, kept with the copy
+    /// appended. Returns `false` if `from` does not exist.
+    pub async fn copy(&mut self, from: u8, to: u8, append: bool) -> Result<bool> {
+        let value = u32::from(to) | (append as u32) << 8;
+        match self.send_write(OP_COPY, from, value).await? {
+            STATUS_OK => Ok(true),
+            STATUS_NOT_FOUND => Ok(false),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Puts back the values `key` held before it was last deleted, as
+    /// recorded in the server's snapshot and log. Returns `false` if no
+    /// deletion is recorded since the last snapshot.
+    pub async fn restore_key(&mut self, key: u8) -> Result<bool> {
+        match self.send_write(OP_RESTORE_KEY, key, 0).await? {
+            STATUS_OK => Ok(true),
+            STATUS_NOT_FOUND => Ok(false),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Clears the database. The server refuses it with
+    /// [`StatusError::Unauthorized`] unless `token` is the one it is
+    /// configured to take.
+    pub async fn delete_all(&mut self, token: u32) -> Result<()> {
+        match self.send_write(OP_DELETE_ALL, 0, token).await? {
+            STATUS_OK => Ok(()),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Every key with its values. Each key is read at its own point in
+    /// time, so with writes landing meanwhile the keys may not agree; see
+    /// [`Client::list_snapshot`].
+    pub async fn list_all(&mut self) -> Result<Vec<(u8, Vec<u32>)>> {
+        self.list(&Frame::new(OP_LIST_ALL, 0, 0)).await
+    }
+
+    /// Every key with its values as they all stood at a single point in
+    /// time, holding the server's writers off while it copies them.
+    pub async fn list_snapshot(&mut self) -> Result<Vec<(u8, Vec<u32>)>> {
+        self.list(&Frame::new(OP_LIST_ALL, 1, 0)).await
+    }
+
+    /// Every key in `first..=last` with its values, in key order.
+    pub async fn list_range(&mut self, first: u8, last: u8) -> Result<Vec<(u8, Vec<u32>)>> {
+        if first > last {
+            return Err(Map8x32Error::invalid_input(format!(
+                "key range {first}..={last} is empty"
+            )));
+        }
+        self.list(&Frame::new(OP_LIST_RANGE, first, u32::from(last)))
+            .await
+    }
+
+    /// Gives `key` `tag`, or with 0 clears its tag, for
+    /// [`Client::list_tagged`] and [`Client::delete_tagged`] to find it by.
+    /// Returns `false` if the key does not exist.
+    pub async fn tag(&mut self, key: u8, tag: u8) -> Result<bool> {
+        match self.send_write(OP_TAG, key, tag.into()).await? {
+            STATUS_OK => Ok(true),
+            STATUS_NOT_FOUND => Ok(false),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Every key carrying `tag`, which must not be 0, with its values, in
+    /// key order.
+    pub async fn list_tagged(&mut self, tag: u8) -> Result<Vec<(u8, Vec<u32>)>> {
+        self.list(&Frame::new(OP_LIST_TAGGED, tag, 0)).await
+    }
+
+    /// Deletes every key carrying `tag`, which must not be 0. Returns
+    /// `false` if no key carries it.
+    pub async fn delete_tagged(&mut self, tag: u8) -> Result<bool> {
+        match self.send_write(OP_DELETE_TAGGED, tag, 0).await? {
+            STATUS_OK => Ok(true),
+            STATUS_NOT_FOUND => Ok(false),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Deletes each of `keys` in one step and returns a status per key, in
+    /// the same order: `STATUS_OK` where it was deleted, `STATUS_NOT_FOUND`
+    /// where it did not exist, or why it could not be deleted.
+    pub async fn delete_many(&mut self, keys: &[u8]) -> Result<Vec<u8>> {
+        let count = u32::try_from(keys.len())
+            .ok()
+            .filter(|&count| count <= MAX_DELETE_MANY_KEYS)
+            .ok_or_else(|| {
+                Map8x32Error::invalid_input(format!(
+                    "at most {MAX_DELETE_MANY_KEYS} keys can be deleted at once"
+                ))
+            })?;
+        let mut request = Vec::with_capacity(FRAME_LEN + keys.len());
+        request.extend_from_slice(&Frame::new(OP_DELETE_MANY, 0, count).encode());
+        request.extend_from_slice(keys);
+        match self.send_write_request(&request).await? {
+            STATUS_OK => {
+                let count = self.stream.read_u32_le().await?;
+                let mut statuses = vec![0; count as usize];
+                self.stream.read_exact(&mut statuses).await?;
+                Ok(statuses)
+            }
+            status => Err(status_error(status)),
+        }
+    }
+
+    async fn list(&mut self, request: &Frame) -> Result<Vec<(u8, Vec<u32>)>> {
+        let (status, fresh) = self.send_read_request(&request.encode()).await?;
+        if status != STATUS_OK {
+            return Err(status_error(status));
+        }
+        let key_count = self.stream.read_u32_le().await?;
+        let mut entries = Vec::with_capacity(key_count as usize);
+        for _ in 0..key_count {
+            let key = self.stream.read_u8().await?;
+            entries.push((key, self.read_values().await?));
+        }
+        if !fresh {
+            return Err(self.stale());
+        }
+        Ok(entries)
+    }
+
+    /// A key picked uniformly from those that exist, or `None` if the
+    /// database is empty.
+    pub async fn random_key(&mut self) -> Result<Option<u8>> {
+        let (status, fresh) = self.send_read(OP_RANDOM_KEY, 0).await?;
+        let key = match status {
+            STATUS_OK => Some(self.stream.read_u8().await?),
+            STATUS_NOT_FOUND => None,
+            status => return Err(status_error(status)),
+        };
+        if !fresh {
+            return Err(self.stale());
+        }
+        Ok(key)
+    }
+
+    /// A key picked uniformly from those holding u32s, with one of its values
+    /// picked uniformly, or `None` if there are none.
+    pub async fn random_value(&mut self) -> Result<Option<(u8, u32)>> {
+        let request = Frame::new(OP_RANDOM_KEY, 0, 1).encode();
+        let (status, fresh) = self.send_read_request(&request).await?;
+        let sample = match status {
+            STATUS_OK => Some((
+                self.stream.read_u8().await?,
+                self.stream.read_u32_le().await?,
+            )),
+            STATUS_NOT_FOUND => None,
+            status => return Err(status_error(status)),
+        };
+        if !fresh {
+            return Err(self.stale());
+        }
+        Ok(sample)
+    }
+
+    /// The next batch of up to `count` existing keys from `cursor` on, in key
+    /// order, with the cursor to pass next; start from 0 and stop when the
+    /// returned cursor is 0 again. A count of 0 uses the server's default.
+    /// Keys that exist throughout a scan are returned exactly once.
+    pub async fn scan(&mut self, cursor: u32, count: u8) -> Result<(u32, Vec<u8>)> {
+        let request = Frame::new(OP_SCAN, count, cursor).encode();
+        let (status, fresh) = self.send_read_request(&request).await?;
+        if status != STATUS_OK {
+            return Err(status_error(status));
+        }
+        let next = self.stream.read_u32_le().await?;
+        let mut keys = vec![0; self.stream.read_u32_le().await? as usize];
+        self.stream.read_exact(&mut keys).await?;
+        if !fresh {
+            return Err(self.stale());
+        }
+        Ok((next, keys))
+    }
+
+    /// Has the server write a checksummed dump of the selected database to
+    /// `name` in its dump directory, for an ad-hoc backup. [`Client::sync_full`]
+    /// fetches the same data over the connection instead.
+    pub async fn dump(&mut self, name: &str) -> Result<()> {
+        let mut request = Frame::new(OP_DUMP, 0, name.len() as u32).encode().to_vec();
+        request.extend_from_slice(name.as_bytes());
+        self.stream.write_all(&request).await?;
+        match self.status().await? {
+            STATUS_OK => Ok(()),
+            STATUS_BAD_REQUEST => Err(refused(
+                STATUS_BAD_REQUEST,
+                "dump names are letters, digits, '.', '-' and '_', not starting with '.'",
+            )),
+            STATUS_UNAVAILABLE => Err(refused(STATUS_UNAVAILABLE, "server has no dump directory")),
+            STATUS_INTERNAL_ERROR => Err(refused(
+                STATUS_INTERNAL_ERROR,
+                "server failed to write the dump",
+            )),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Loads the dump `name` from the server's dump directory into the
+    /// selected database, replacing everything in it or, with `merge`,
+    /// appending each key's values to what the key already holds. Returns
+    /// `false` if there is no dump of that name.
+    pub async fn restore(&mut self, name: &str, merge: bool) -> Result<bool> {
+        let mut request = Frame::new(OP_RESTORE, merge as u8, name.len() as u32)
+            .encode()
+            .to_vec();
+        request.extend_from_slice(name.as_bytes());
+        match self.send_write_request(&request).await? {
+            STATUS_OK => Ok(true),
+            STATUS_NOT_FOUND => Ok(false),
+            STATUS_BAD_REQUEST => Err(refused(
+                STATUS_BAD_REQUEST,
+                "bad dump name, or the dump is corrupt or of an unsupported version",
+            )),
+            STATUS_CONFLICT => Err(refused(
+                STATUS_CONFLICT,
+                "dump holds a key with another value type than the database",
+            )),
+            STATUS_UNAVAILABLE => Err(refused(
+                STATUS_UNAVAILABLE,
+                "server has no dump directory or is shedding writes",
+            )),
+            STATUS_INTERNAL_ERROR => Err(refused(
+                STATUS_INTERNAL_ERROR,
+                "server cannot read the dump",
+            )),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Fetches a copy of every key and its values, for seeding another
+    /// instance or taking a backup. The body is checked against the server's
+    /// CRC-32 before it is parsed.
+    pub async fn sync_full(&mut self) -> Result<FullSync> {
+        match self.send(OP_SYNC_FULL, 0, 0).await? {
+            STATUS_OK => {
+                let mut body = vec![0u8; self.stream.read_u32_le().await? as usize];
+                self.stream.read_exact(&mut body).await?;
+                let checksum = self.stream.read_u32_le().await?;
+                let invalid = |message: &'static str| Map8x32Error::protocol(message);
+                if crc32(&body) != checksum {
+                    return Err(invalid("SYNC_FULL checksum mismatch"));
+                }
+                let truncated = || invalid("truncated SYNC_FULL body");
+                let mut body = body.as_slice();
+                let mut take = |len: usize| -> Result<&[u8]> {
+                    if body.len() < len {
+                        return Err(truncated());
+                    }
+                    let (head, rest) = body.split_at(len);
+                    body = rest;
+                    Ok(head)
+                };
+                let u32_at = |bytes: &[u8]| u32::from_le_bytes(bytes.try_into().unwrap());
+                let seq = u64::from_le_bytes(take(8)?.try_into().unwrap());
+                let key_count = u32_at(take(4)?);
+                let mut entries = Vec::new();
+                for _ in 0..key_count {
+                    let key = take(1)?[0];
+                    let count = u32_at(take(4)?) as usize;
+                    let values = take(count.checked_mul(4).ok_or_else(truncated)?)?
+                        .chunks_exact(4)
+                        .map(u32_at)
+                        .collect();
+                    entries.push((key, values));
+                }
+                Ok(FullSync { seq, entries })
+            }
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Returns `key`'s current version and values; a missing key has no
+    /// values.
+    pub async fn get_versioned(&mut self, key: u8) -> Result<(u64, Vec<u32>)> {
+        let (status, fresh) = self.send_read(OP_GET_VERSIONED, key).await?;
+        if status != STATUS_OK {
+            return Err(status_error(status));
+        }
+        let version = self.stream.read_u64_le().await?;
+        let values = self.read_values().await?;
+        if !fresh {
+            return Err(self.stale());
+        }
+        Ok((version, values))
+    }
+
+    /// Replaces `key`'s values if the key is still at `expected_version`.
+    /// Returns `false` if another write got there first. Empty `values`
+    /// removes the key.
+    pub async fn replace_if(
+        &mut self,
+        key: u8,
+        expected_version: u64,
+        values: &[u32],
+    ) -> Result<bool> {
+        let count = u32::try_from(values.len())
+            .ok()
+            .filter(|&count| count <= MAX_REPLACE_VALUES)
+            .ok_or_else(|| {
+                Map8x32Error::invalid_input(format!(
+                    "at most {MAX_REPLACE_VALUES} values can be written at once"
+                ))
+            })?;
+        let mut request = Vec::with_capacity(FRAME_LEN + 8 + values.len() * 4);
+        request.extend_from_slice(&Frame::new(OP_REPLACE_IF, key, count).encode());
+        request.extend_from_slice(&expected_version.to_le_bytes());
+        for value in values {
+            request.extend_from_slice(&value.to_le_bytes());
+        }
+        match self.send_write_request(&request).await? {
+            STATUS_OK => Ok(true),
+            STATUS_CONFLICT => Ok(false),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Atomically rewrites `key`'s values with `f`, re-reading and retrying
+    /// whenever a concurrent write changes the key in between. `f` may run
+    /// several times and should not have side effects. Returns the values
+    /// that were stored.
+    pub async fn update<F>(&mut self, key: u8, mut f: F) -> Result<Vec<u32>>
+    where
+        F: FnMut(&[u32]) -> Vec<u32>,
+    {
+        for _ in 0..MAX_UPDATE_ATTEMPTS {
+            let (version, current) = self.get_versioned(key).await?;
+            let updated = f(&current);
+            if self.replace_if(key, version, &updated).await? {
+                return Ok(updated);
+            }
+        }
+        Err(refused(
+            STATUS_CONFLICT,
+            format!("update of key {key} kept conflicting after {MAX_UPDATE_ATTEMPTS} attempts"),
+        ))
+    }
+
+    pub async fn info(&mut self) -> Result<Info> {
+        match self.send(OP_INFO, 0, 0).await? {
+            STATUS_OK => self.read_info().await,
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// How long each operation the server has run since it started spent
+    /// waiting for a command processor and then running on it, for telling
+    /// a queue that backs up from a command that is slow.
+    pub async fn latencies(&mut self) -> Result<Vec<OpLatency>> {
+        match self.send(OP_INFO, 1, 0).await? {
+            STATUS_OK => {
+                self.read_info().await?;
+                let mut latencies = Vec::new();
+                for _ in 0..self.stream.read_u8().await? {
+                    let op = self.stream.read_u8().await?;
+                    let (wait, run) = (self.read_quantiles().await?, self.read_quantiles().await?);
+                    latencies.push(OpLatency { op, wait, run });
+                }
+                Ok(latencies)
+            }
+            status => Err(status_error(status)),
+        }
+    }
+
+    async fn read_info(&mut self) -> Result<Info> {
+        let mut info = Info {
+            uptime: Duration::from_millis(self.stream.read_u64_le().await?),
+            keys: self.stream.read_u64_le().await?,
+            values: self.stream.read_u64_le().await?,
+            memory_bytes: self.stream.read_u64_le().await?,
+            hits: self.stream.read_u64_le().await?,
+            misses: self.stream.read_u64_le().await?,
+            ops: Vec::new(),
+            persistence: None,
+            allocations: Vec::new(),
+            quota_rejections: 0,
+            max_keys: None,
+            max_values: None,
+        };
+        for _ in 0..self.stream.read_u8().await? {
+            let mut name = vec![0u8; self.stream.read_u8().await? as usize];
+            self.stream.read_exact(&mut name).await?;
+            let count = self.stream.read_u64_le().await?;
+            info.ops
+                .push((String::from_utf8_lossy(&name).into_owned(), count));
+        }
+        if self.stream.read_u8().await? == 1 {
+            let health = self.stream.read_u8().await?;
+            info.persistence = Some(PersistenceState {
+                health: Health::from_u8(health).ok_or_else(|| {
+                    Map8x32Error::protocol(format!("unknown persistence health {health}"))
+                })?,
+                failures: self.stream.read_u64_le().await?,
+            });
+        }
+        info.allocations = self.read_allocations().await?;
+        info.quota_rejections = self.stream.read_u64_le().await?;
+        let limit = |limit: u64| (limit > 0).then_some(limit);
+        info.max_keys = limit(self.stream.read_u64_le().await?);
+        info.max_values = limit(self.stream.read_u64_le().await?);
+        Ok(info)
+    }
+
+    async fn read_quantiles(&mut self) -> Result<Quantiles> {
+        Ok(Quantiles {
+            count: self.stream.read_u64_le().await?,
+            p50: self.stream.read_u64_le().await?,
+            p90: self.stream.read_u64_le().await?,
+            p99: self.stream.read_u64_le().await?,
+            p999: self.stream.read_u64_le().await?,
+            max: self.stream.read_u64_le().await?,
+        })
+    }
+
+    pub async fn key_stats(&mut self, key: u8) -> Result<KeyStats> {
+        match self.send(OP_KEYSTATS, key, 0).await? {
+            STATUS_OK => Ok(KeyStats {
+                hits: self.stream.read_u64_le().await?,
+                misses: self.stream.read_u64_le().await?,
+                sets: self.stream.read_u64_le().await?,
+                last_access_ms: self.stream.read_u64_le().await?,
+            }),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Fetches the server's buffer of rejected writes, optionally clearing it.
+    pub async fn dead_letters(&mut self, drain: bool) -> Result<Vec<DeadLetter>> {
+        match self.send(OP_DEAD_LETTERS, 0, drain as u32).await? {
+            STATUS_OK => {
+                let count = self.stream.read_u32_le().await?;
+                let mut entries = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    entries.push(DeadLetter {
+                        at_unix_ms: self.stream.read_u64_le().await?,
+                        op: self.stream.read_u8().await?,
+                        key: self.stream.read_u8().await?,
+                        value: self.stream.read_u32_le().await?,
+                        reason: self.stream.read_u8().await?,
+                    });
+                }
+                Ok(entries)
+            }
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Fetches the server's buffer of slow commands, optionally clearing it.
+    pub async fn slow_log(&mut self, drain: bool) -> Result<Vec<SlowOp>> {
+        match self.send(OP_SLOW_LOG, 0, drain as u32).await? {
+            STATUS_OK => {
+                let count = self.stream.read_u32_le().await?;
+                let mut entries = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    entries.push(SlowOp {
+                        at_unix_ms: self.stream.read_u64_le().await?,
+                        op: self.stream.read_u8().await?,
+                        key: self.stream.read_u8().await?,
+                        duration: Duration::from_micros(self.stream.read_u64_le().await?),
+                        request_bytes: self.stream.read_u32_le().await?,
+                    });
+                }
+                Ok(entries)
+            }
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Every connection the server is serving, this one included, ordered
+    /// by connection ID.
+    pub async fn client_list(&mut self) -> Result<Vec<ConnectedClient>> {
+        self.clients(OP_CLIENT_LIST).await
+    }
+
+    /// This connection, as the server sees it.
+    pub async fn client_info(&mut self) -> Result<ConnectedClient> {
+        self.clients(OP_CLIENT_INFO)
+            .await?
+            .pop()
+            .ok_or_else(|| Map8x32Error::protocol("server answered CLIENT_INFO with no client"))
+    }
+
+    /// Closes connection `id`, as [`Client::client_list`] numbers it.
+    /// Returns `false` if the server is not serving such a connection.
+    pub async fn client_kill(&mut self, id: u64) -> Result<bool> {
+        let mut request = Frame::new(OP_CLIENT_KILL, 0, 0).encode().to_vec();
+        request.extend_from_slice(&id.to_le_bytes());
+        self.stream.write_all(&request).await?;
+        match self.status().await? {
+            STATUS_OK => Ok(true),
+            STATUS_NOT_FOUND => Ok(false),
+            status => Err(status_error(status)),
+        }
+    }
+
+    async fn clients(&mut self, op: u8) -> Result<Vec<ConnectedClient>> {
+        match self.send(op, 0, 0).await? {
+            STATUS_OK => {
+                let count = self.stream.read_u32_le().await?;
+                let mut clients = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let id = self.stream.read_u64_le().await?;
+                    let uid = self.stream.read_u32_le().await?;
+                    let connected_unix_ms = self.stream.read_u64_le().await?;
+                    let requests = self.stream.read_u64_le().await?;
+                    let bytes_in = self.stream.read_u64_le().await?;
+                    let bytes_out = self.stream.read_u64_le().await?;
+                    let last_op = self.stream.read_u8().await?;
+                    let mut peer = vec![0u8; self.stream.read_u8().await? as usize];
+                    self.stream.read_exact(&mut peer).await?;
+                    clients.push(ConnectedClient {
+                        id,
+                        uid: (uid != u32::MAX).then_some(uid),
+                        peer: String::from_utf8_lossy(&peer).into_owned(),
+                        connected_unix_ms,
+                        requests,
+                        bytes_in,
+                        bytes_out,
+                        last_op,
+                    });
+                }
+                Ok(clients)
+            }
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Every tunable the server can change at runtime, with its current
+    /// value.
+    pub async fn config_all(&mut self) -> Result<Vec<(String, String)>> {
+        self.config_request("").await?.ok_or_else(|| {
+            Map8x32Error::protocol("server answered a read of every tunable with NOT_FOUND")
+        })
+    }
+
+    /// The current value of tunable `name`, or `None` if the server has no
+    /// such tunable.
+    pub async fn config(&mut self, name: &str) -> Result<Option<String>> {
+        let settings = self.config_request(name).await?;
+        Ok(settings.and_then(|settings| {
+            settings
+                .into_iter()
+                .find_map(|(setting, value)| (setting == name).then_some(value))
+        }))
+    }
+
+    async fn config_request(&mut self, name: &str) -> Result<Option<Vec<(String, String)>>> {
+        let mut request = Frame::new(OP_CONFIG, 0, name.len() as u32)
+            .encode()
+            .to_vec();
+        request.extend_from_slice(name.as_bytes());
+        self.stream.write_all(&request).await?;
+        match self.status().await? {
+            STATUS_OK => {
+                let mut settings = Vec::new();
+                for _ in 0..self.stream.read_u8().await? {
+                    let mut name = vec![0u8; self.stream.read_u8().await? as usize];
+                    self.stream.read_exact(&mut name).await?;
+                    let mut value = vec![0u8; self.stream.read_u8().await? as usize];
+                    self.stream.read_exact(&mut value).await?;
+                    settings.push((
+                        String::from_utf8_lossy(&name).into_owned(),
+                        String::from_utf8_lossy(&value).into_owned(),
+                    ));
+                }
+                Ok(Some(settings))
+            }
+            STATUS_NOT_FOUND => Ok(None),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Changes tunable `name` on the running server until it restarts or
+    /// reloads its config file.
+    pub async fn set_config(&mut self, name: &str, value: &str) -> Result<()> {
+        let payload = format!("{name}={value}");
+        let mut request = Frame::new(OP_CONFIG, 1, payload.len() as u32)
+            .encode()
+            .to_vec();
+        request.extend_from_slice(payload.as_bytes());
+        self.stream.write_all(&request).await?;
+        match self.status().await? {
+            STATUS_OK => Ok(()),
+            STATUS_NOT_FOUND => Err(refused(
+                STATUS_NOT_FOUND,
+                format!("server has no tunable {name}"),
+            )),
+            STATUS_BAD_REQUEST => Err(refused(
+                STATUS_BAD_REQUEST,
+                format!("{name} cannot be set to {value:?}"),
+            )),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Records `allocation` in the server's key-range registry. Fails with
+    /// [`StatusError::Conflict`] if it overlaps an existing range.
+    pub async fn register_allocation(&mut self, allocation: &Allocation) -> Result<()> {
+        let mut payload = vec![allocation.last_key];
+        for field in [&allocation.name, &allocation.contact, &allocation.policy] {
+            let len = u8::try_from(field.len()).map_err(|_| {
+                Map8x32Error::invalid_input("allocation fields are limited to 255 bytes")
+            })?;
+            payload.push(len);
+            payload.extend_from_slice(field.as_bytes());
+        }
+        let mut request = Frame::new(
+            OP_ALLOC_REGISTER,
+            allocation.first_key,
+            payload.len() as u32,
+        )
+        .encode()
+        .to_vec();
+        request.extend_from_slice(&payload);
+        self.stream.write_all(&request).await?;
+
+        match self.status().await? {
+            STATUS_OK => Ok(()),
+            STATUS_CONFLICT => Err(refused(
+                STATUS_CONFLICT,
+                "key range overlaps an existing allocation",
+            )),
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Removes the allocation starting at `first_key`, returning whether it existed.
+    pub async fn release_allocation(&mut self, first_key: u8) -> Result<bool> {
+        match self.send(OP_ALLOC_RELEASE, first_key, 0).await? {
+            STATUS_OK => Ok(true),
+            STATUS_NOT_FOUND => Ok(false),
+            status => Err(status_error(status)),
+        }
+    }
+
+    pub async fn allocations(&mut self) -> Result<Vec<Allocation>> {
+        match self.send(OP_ALLOC_LIST, 0, 0).await? {
+            STATUS_OK => self.read_allocations().await,
+            status => Err(status_error(status)),
+        }
+    }
+
+    /// Reads `[count: u32]` and that many ALLOC_LIST entries.
+    async fn read_allocations(&mut self) -> Result<Vec<Allocation>> {
+        let count = self.stream.read_u32_le().await?;
+        let mut allocations = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let first_key = self.stream.read_u8().await?;
+            let last_key = self.stream.read_u8().await?;
+            let mut fields = Vec::with_capacity(3);
+            for _ in 0..3 {
+                let mut bytes = vec![0u8; self.stream.read_u8().await? as usize];
+                self.stream.read_exact(&mut bytes).await?;
+                fields.push(String::from_utf8_lossy(&bytes).into_owned());
+            }
+            let [name, contact, policy] = <[String; 3]>::try_from(fields).unwrap();
+            allocations.push(Allocation {
+                first_key,
+                last_key,
+                name,
+                contact,
+                policy,
+            });
+        }
+        Ok(allocations)
+    }
+}
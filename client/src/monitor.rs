@@ -0,0 +1,70 @@
+use crate::{status_error, Client, Map8x32Error, Result};
+use map8x32_protocol::codec::{self, MonitoredOp, Request, Response, CODEC_NATIVE};
+use map8x32_protocol::*;
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const READ_CHUNK: usize = 4096;
+
+/// A connection that only carries the server's feed of the commands every
+/// connection runs. Built with [`Client::monitor`].
+#[derive(Debug)]
+pub struct Monitor {
+    client: Client,
+    input: Vec<u8>,
+    consumed: usize,
+}
+
+impl Client {
+    /// Sends MONITOR, after which the connection carries nothing else.
+    pub async fn monitor(self) -> Result<Monitor> {
+        let mut monitor = Monitor {
+            client: self,
+            input: Vec::with_capacity(READ_CHUNK),
+            consumed: 0,
+        };
+        monitor
+            .client
+            .stream
+            .write_all(&Frame::new(OP_MONITOR, 0, 0).encode())
+            .await?;
+        monitor.read().await?;
+        Ok(monitor)
+    }
+}
+
+impl Monitor {
+    /// The commands run since the last call, waiting for at least one.
+    pub async fn next(&mut self) -> Result<Vec<MonitoredOp>> {
+        loop {
+            let commands = self.read().await?;
+            if !commands.is_empty() {
+                return Ok(commands);
+            }
+        }
+    }
+
+    async fn read(&mut self) -> Result<Vec<MonitoredOp>> {
+        let codec = codec::select(CODEC_NATIVE, self.client.version).unwrap();
+        loop {
+            match codec.decode_response(&Request::Monitor, &self.input[self.consumed..]) {
+                Ok(Some((response, len))) => {
+                    self.consumed += len;
+                    return match response {
+                        Response::Monitored(commands) => Ok(commands),
+                        response => Err(status_error(response.status())),
+                    };
+                }
+                Ok(None) => {
+                    self.input.drain(..self.consumed);
+                    self.consumed = 0;
+                    self.input.reserve(READ_CHUNK);
+                    if self.client.stream.read_buf(&mut self.input).await? == 0 {
+                        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                    }
+                }
+                Err(e) => return Err(Map8x32Error::protocol(e.to_string())),
+            }
+        }
+    }
+}
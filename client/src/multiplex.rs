@@ -0,0 +1,305 @@
+use crate::{status_error, Client, Map8x32Error, Result, Session, Stream};
+use map8x32_protocol::codec::{self, split_chunk, Codec, Request, Response, CODEC_NATIVE};
+use map8x32_protocol::*;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+const READ_CHUNK: usize = 4096;
+
+/// The requests sent but not yet answered, by ID; `None` once the
+/// connection broke.
+type Waiting = Arc<Mutex<Option<HashMap<u32, (Request, oneshot::Sender<Result<Response>>)>>>>;
+type Outgoing = mpsc::UnboundedSender<Vec<u8>>;
+
+/// A connection that carries many requests at once: each is tagged with a
+/// request ID, and the server answers them as they finish rather than in
+/// the order they were sent, in chunks so a long answer does not hold up
+/// the rest. Built with [`Client::multiplex`]; clones share the
+/// connection, which closes once the last of them is dropped.
+///
+/// The server makes no promise about the order requests in flight together
+/// run in, so wait for a write's answer before sending a request that must
+/// see it.
+#[derive(Debug, Clone)]
+pub struct Multiplexed {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    /// Whole requests for the task writing the connection, so a caller
+    /// that stops waiting never leaves half a request on it.
+    outgoing: Outgoing,
+    waiting: Waiting,
+    next_id: AtomicU32,
+    codec: &'static dyn Codec,
+    reader: JoinHandle<()>,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+impl Shared {
+    /// Writes `request` under a new ID, returning the ID and where its
+    /// answer arrives.
+    fn send(&self, request: Request) -> Result<(u32, oneshot::Receiver<Result<Response>>)> {
+        let id = loop {
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            if id != PROBE_REQUEST_ID {
+                break id;
+            }
+        };
+        let mut out = id.to_le_bytes().to_vec();
+        self.codec.encode_request(&request, &mut out);
+        let (respond_to, answer) = oneshot::channel();
+        match self.waiting.lock().unwrap().as_mut() {
+            Some(waiting) => waiting.insert(id, (request, respond_to)),
+            None => return Err(closed()),
+        };
+        self.outgoing.send(out).map_err(|_| closed())?;
+        Ok((id, answer))
+    }
+}
+
+/// Cancels a request whose caller stopped waiting before it was answered.
+struct Abandoned<'a> {
+    shared: &'a Shared,
+    id: Option<u32>,
+}
+
+impl Drop for Abandoned<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let _ = self.shared.send(Request::Cancel { id });
+        }
+    }
+}
+
+impl Client {
+    /// Sends CHUNKED and MULTIPLEX, after which the connection carries
+    /// tagged requests and chunked answers.
+    pub async fn multiplex(mut self) -> Result<Multiplexed> {
+        match self.send(OP_CHUNKED, 0, 0).await? {
+            STATUS_OK => {}
+            status => return Err(status_error(status)),
+        }
+        self.stream
+            .write_all(&Frame::new(OP_MULTIPLEX, 0, 0).encode())
+            .await?;
+        // The answer, and any probe ahead of it, is a chunk of one byte.
+        loop {
+            self.stream.read_u32_le().await?;
+            match self.stream.read_u8().await? {
+                STATUS_PROBE => {
+                    self.stream
+                        .write_all(&Frame::new(OP_PONG, 0, 0).encode())
+                        .await?
+                }
+                STATUS_OK => break,
+                status => return Err(status_error(status)),
+            }
+        }
+        let codec = codec::select(CODEC_NATIVE, self.version).unwrap();
+        let (read, write) = tokio::io::split(self.stream);
+        let (outgoing, requests) = mpsc::unbounded_channel();
+        tokio::spawn(write_requests(write, requests));
+        let waiting = Arc::new(Mutex::new(Some(HashMap::new())));
+        let reader = tokio::spawn(read_answers(
+            read,
+            outgoing.clone(),
+            waiting.clone(),
+            codec,
+            self.session,
+        ));
+        Ok(Multiplexed {
+            shared: Arc::new(Shared {
+                outgoing,
+                waiting,
+                next_id: AtomicU32::new(0),
+                codec,
+                reader,
+            }),
+        })
+    }
+}
+
+impl Multiplexed {
+    /// Sends any request but HELLO, SELECT_CODEC, MULTIPLEX, CANCEL, PONG,
+    /// SUBSCRIBE and MONITOR, which change how the rest of the connection
+    /// is read or are not answered, and waits for its answer. Dropping the
+    /// future before then sends CANCEL, so the server stops working on a
+    /// read nobody waits for.
+    pub async fn request(&self, request: Request) -> Result<Response> {
+        if matches!(
+            request,
+            Request::Hello { .. }
+                | Request::SelectCodec { .. }
+                | Request::Multiplex
+                | Request::Cancel { .. }
+                | Request::Pong
+                | Request::Subscribe { .. }
+                | Request::SubscribeEvents { .. }
+                | Request::Monitor
+        ) {
+            return Err(Map8x32Error::invalid_input(format!(
+                "op {} cannot be multiplexed",
+                request.op()
+            )));
+        }
+        let (id, answer) = self.shared.send(request)?;
+        let mut abandoned = Abandoned {
+            shared: &self.shared,
+            id: Some(id),
+        };
+        let answer = answer.await.unwrap_or_else(|_| Err(closed()));
+        abandoned.id = None;
+        answer
+    }
+
+    pub async fn set(&self, key: u8, value: u32) -> Result<()> {
+        match self.request(Request::Set { key, value }).await? {
+            Response::Status(STATUS_OK)
+            | Response::Ack {
+                status: STATUS_OK, ..
+            } => Ok(()),
+            Response::Status(status) | Response::Ack { status, .. } => Err(status_error(status)),
+            _ => Err(Map8x32Error::protocol("unexpected answer to SET")),
+        }
+    }
+
+    pub async fn get(&self, key: u8) -> Result<Option<Vec<u32>>> {
+        match self.request(Request::Get { key }).await? {
+            Response::Values(values) => Ok(Some(values)),
+            Response::Status(STATUS_NOT_FOUND) => Ok(None),
+            Response::Status(status) => Err(status_error(status)),
+            _ => Err(Map8x32Error::protocol("unexpected answer to GET")),
+        }
+    }
+}
+
+fn closed() -> Map8x32Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        "multiplexed connection closed",
+    )
+    .into()
+}
+
+async fn write_requests(
+    mut write: WriteHalf<Stream>,
+    mut requests: mpsc::UnboundedReceiver<Vec<u8>>,
+) {
+    while let Some(request) = requests.recv().await {
+        if write.write_all(&request).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn read_more(
+    read: &mut ReadHalf<Stream>,
+    input: &mut Vec<u8>,
+    consumed: &mut usize,
+) -> Result<()> {
+    input.drain(..*consumed);
+    *consumed = 0;
+    input.reserve(READ_CHUNK);
+    match read.read_buf(input).await? {
+        0 => Err(closed()),
+        _ => Ok(()),
+    }
+}
+
+/// Puts each answer together from its chunks and hands it to the request
+/// with its ID, and answers keepalive probes, until the connection breaks;
+/// every request still waiting then fails.
+async fn read_answers(
+    mut read: ReadHalf<Stream>,
+    outgoing: Outgoing,
+    waiting: Waiting,
+    codec: &'static dyn Codec,
+    session: Option<Session>,
+) {
+    let mut input = Vec::with_capacity(READ_CHUNK);
+    let mut consumed = 0;
+    let mut partial: HashMap<u32, Vec<u8>> = HashMap::new();
+    let failure = loop {
+        let chunk = input.get(consumed..).filter(|tagged| tagged.len() >= 4);
+        let Some((id, (bytes, more, len))) =
+            chunk.and_then(|tagged| Some((&tagged[..4], split_chunk(&tagged[4..])?)))
+        else {
+            match read_more(&mut read, &mut input, &mut consumed).await {
+                Ok(()) => continue,
+                Err(e) => break e,
+            }
+        };
+        let id = u32::from_le_bytes(id.try_into().unwrap());
+        let body = partial.entry(id).or_default();
+        body.extend_from_slice(bytes);
+        consumed += 4 + len;
+        if more {
+            continue;
+        }
+        let body = partial.remove(&id).unwrap_or_default();
+        let request = match id {
+            PROBE_REQUEST_ID => Request::Keepalive,
+            id => match waiting
+                .lock()
+                .unwrap()
+                .as_ref()
+                .and_then(|waiting| waiting.get(&id))
+            {
+                Some((request, _)) => request.clone(),
+                None => break Map8x32Error::protocol(format!("answer to unknown request {id}")),
+            },
+        };
+        let response = match codec.decode_response(&request, &body) {
+            Ok(Some((response, len))) if len == body.len() => response,
+            Ok(_) => break Map8x32Error::protocol(format!("malformed answer to request {id}")),
+            Err(e) => break Map8x32Error::protocol(e.to_string()),
+        };
+        if id == PROBE_REQUEST_ID {
+            let mut pong = PROBE_REQUEST_ID.to_le_bytes().to_vec();
+            codec.encode_request(&Request::Pong, &mut pong);
+            if outgoing.send(pong).is_err() {
+                break closed();
+            }
+            continue;
+        }
+        if let (
+            Response::Ack { seq, .. }
+            | Response::Removed { seq, .. }
+            | Response::Taken { seq, .. }
+            | Response::DeletedMany { seq, .. },
+            Some(session),
+        ) = (&response, &session)
+        {
+            session.observe(*seq);
+        }
+        let answered = waiting
+            .lock()
+            .unwrap()
+            .as_mut()
+            .and_then(|waiting| waiting.remove(&id));
+        if let Some((_, respond_to)) = answered {
+            let _ = respond_to.send(Ok(response));
+        }
+    };
+    let message = failure.to_string();
+    let unanswered = waiting.lock().unwrap().take().unwrap_or_default();
+    for (_, (_, respond_to)) in unanswered {
+        let _ = respond_to.send(Err(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            message.clone(),
+        )
+        .into()));
+    }
+}
@@ -0,0 +1,128 @@
+use crate::{Client, Map8x32Error, Result};
+use map8x32_protocol::codec::{self, Request, Response, CODEC_NATIVE};
+use map8x32_protocol::*;
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const READ_CHUNK: usize = 4096;
+
+/// Commands queued on a [`Client`] to go out in one write, answered in the
+/// order they were queued. Built with [`Client::pipeline`].
+///
+/// Each command is still applied and answered on its own: a refusal is
+/// returned as that command's response without stopping the ones after it.
+#[derive(Debug)]
+pub struct Pipeline<'a> {
+    client: &'a mut Client,
+    requests: Vec<Request>,
+}
+
+impl Client {
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline {
+            client: self,
+            requests: Vec::new(),
+        }
+    }
+}
+
+impl Pipeline<'_> {
+    /// Queues any request but HELLO, SELECT_CODEC and PONG, which change
+    /// how the rest of the connection is read or are not answered.
+    pub fn request(mut self, request: Request) -> Self {
+        self.requests.push(request);
+        self
+    }
+
+    pub fn set(self, key: u8, value: u32) -> Self {
+        self.request(Request::Set { key, value })
+    }
+
+    pub fn get(self, key: u8) -> Self {
+        self.request(Request::Get { key })
+    }
+
+    pub fn delete(self, key: u8) -> Self {
+        self.request(Request::DeleteByKey { key })
+    }
+
+    pub fn list_all(self) -> Self {
+        self.request(Request::ListAll { snapshot: false })
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Writes every queued request, then reads their responses. Keep
+    /// pipelines to a few thousand commands so the responses fit in the
+    /// socket buffers.
+    pub async fn send(self) -> Result<Vec<Response>> {
+        let Pipeline { client, requests } = self;
+        if let Some(request) = requests.iter().find(|request| {
+            matches!(
+                request,
+                Request::Hello { .. } | Request::SelectCodec { .. } | Request::Pong
+            )
+        }) {
+            return Err(Map8x32Error::invalid_input(format!(
+                "op {} cannot be pipelined",
+                request.op()
+            )));
+        }
+        let codec = codec::select(CODEC_NATIVE, client.version).unwrap();
+        let mut out = Vec::with_capacity(requests.len() * FRAME_LEN);
+        for request in &requests {
+            codec.encode_request(request, &mut out);
+        }
+        client.stream.write_all(&out).await?;
+
+        let mut responses = Vec::with_capacity(requests.len());
+        let mut input = Vec::with_capacity(READ_CHUNK);
+        let mut consumed = 0;
+        for request in &requests {
+            let response = loop {
+                // Keepalive probes only arrive ahead of a response.
+                if input.get(consumed) == Some(&STATUS_PROBE) {
+                    consumed += 1;
+                    client
+                        .stream
+                        .write_all(&Frame::new(OP_PONG, 0, 0).encode())
+                        .await?;
+                    continue;
+                }
+                match codec.decode_response(request, &input[consumed..]) {
+                    Ok(Some((response, len))) => {
+                        consumed += len;
+                        break response;
+                    }
+                    Ok(None) => {
+                        input.drain(..consumed);
+                        consumed = 0;
+                        input.reserve(READ_CHUNK);
+                        if client.stream.read_buf(&mut input).await? == 0 {
+                            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                        }
+                    }
+                    Err(e) => return Err(Map8x32Error::protocol(e.to_string())),
+                }
+            };
+            if let (
+                Response::Ack { seq, .. }
+                | Response::Removed { seq, .. }
+                | Response::Taken { seq, .. }
+                | Response::DeletedMany { seq, .. },
+                Some(session),
+            ) = (&response, &client.session)
+            {
+                session.observe(*seq);
+            }
+            responses.push(response);
+        }
+        Ok(responses)
+    }
+}
@@ -0,0 +1,189 @@
+use crate::{Client, ClientConfig, Map8x32Error, Result};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::timeout;
+
+pub const DEFAULT_MAX_CONNECTIONS: usize = 16;
+pub const DEFAULT_CHECKOUT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How a [`Pool`] connects and how many connections it keeps.
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    pub client: ClientConfig,
+    /// Opened up front, so the first requests do not pay for connecting.
+    pub min_connections: usize,
+    /// Checked out at once; further checkouts wait for one to come back.
+    pub max_connections: usize,
+    pub checkout_timeout: Duration,
+    /// PING an idle connection before handing it out, replacing it if the
+    /// server does not answer.
+    pub health_check: bool,
+}
+
+impl PoolConfig {
+    pub fn new(client: ClientConfig) -> Self {
+        PoolConfig {
+            client,
+            min_connections: 0,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            checkout_timeout: DEFAULT_CHECKOUT_TIMEOUT,
+            health_check: true,
+        }
+    }
+
+    pub fn with_min_connections(mut self, min: usize) -> Self {
+        self.min_connections = min;
+        self
+    }
+
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max;
+        self
+    }
+
+    pub fn with_checkout_timeout(mut self, checkout_timeout: Duration) -> Self {
+        self.checkout_timeout = checkout_timeout;
+        self
+    }
+
+    pub fn with_health_check(mut self, health_check: bool) -> Self {
+        self.health_check = health_check;
+        self
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    config: PoolConfig,
+    idle: Mutex<Vec<Client>>,
+    slots: Arc<Semaphore>,
+}
+
+/// Connections to one server shared between tasks, each checked out by one
+/// task at a time. Cloning a pool shares its connections.
+///
+/// Connections go back as they were left: one that ran SELECT stays on that
+/// database, and one that failed mid-command should be dropped with
+/// [`Pooled::discard`] rather than handed to the next task.
+#[derive(Debug, Clone)]
+pub struct Pool {
+    shared: Arc<Shared>,
+}
+
+impl Pool {
+    /// Opens `min_connections` connections, each negotiating the protocol
+    /// version, and fails if any cannot be.
+    pub async fn connect(config: PoolConfig) -> Result<Pool> {
+        if config.max_connections == 0 || config.min_connections > config.max_connections {
+            return Err(Map8x32Error::invalid_input(
+                "a pool needs 1 to max_connections connections, and at least min_connections",
+            ));
+        }
+        let mut idle = Vec::with_capacity(config.max_connections);
+        for _ in 0..config.min_connections {
+            idle.push(open(&config.client).await?);
+        }
+        Ok(Pool {
+            shared: Arc::new(Shared {
+                slots: Arc::new(Semaphore::new(config.max_connections)),
+                idle: Mutex::new(idle),
+                config,
+            }),
+        })
+    }
+
+    /// Hands out an idle connection, or opens one while fewer than
+    /// `max_connections` are checked out, waiting up to `checkout_timeout`
+    /// for one to come back otherwise.
+    pub async fn checkout(&self) -> Result<Pooled> {
+        let shared = &self.shared;
+        let permit = timeout(
+            shared.config.checkout_timeout,
+            shared.slots.clone().acquire_owned(),
+        )
+        .await
+        .map_err(|_| {
+            Map8x32Error::Timeout(
+                "no pooled connection came free within the checkout timeout".into(),
+            )
+        })?
+        .expect("pool semaphore is never closed");
+
+        while let Some(mut client) = self.take_idle() {
+            if !shared.config.health_check
+                || healthy(&mut client, shared.config.checkout_timeout).await
+            {
+                return Ok(self.pooled(client, permit));
+            }
+        }
+        let client = open(&shared.config.client).await?;
+        Ok(self.pooled(client, permit))
+    }
+
+    /// Connections waiting in the pool, not counting those checked out.
+    pub fn idle(&self) -> usize {
+        self.shared.idle.lock().unwrap().len()
+    }
+
+    fn take_idle(&self) -> Option<Client> {
+        self.shared.idle.lock().unwrap().pop()
+    }
+
+    fn pooled(&self, client: Client, permit: OwnedSemaphorePermit) -> Pooled {
+        Pooled {
+            client: Some(client),
+            pool: self.shared.clone(),
+            _permit: permit,
+        }
+    }
+}
+
+/// Whether the connection answers PING within `limit`.
+async fn healthy(client: &mut Client, limit: Duration) -> bool {
+    matches!(timeout(limit, client.ping()).await, Ok(Ok(_)))
+}
+
+async fn open(config: &ClientConfig) -> Result<Client> {
+    let mut client = Client::connect(config).await?;
+    client.hello().await?;
+    Ok(client)
+}
+
+/// A checked-out connection, returned to its pool when dropped.
+#[derive(Debug)]
+pub struct Pooled {
+    client: Option<Client>,
+    pool: Arc<Shared>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Pooled {
+    /// Closes the connection instead of returning it to the pool.
+    pub fn discard(mut self) {
+        self.client = None;
+    }
+}
+
+impl Deref for Pooled {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for Pooled {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for Pooled {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.pool.idle.lock().unwrap().push(client);
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use crate::subscription::{self, Event};
+use crate::{Client, ClientConfig, Map8x32Error, Result};
+use futures_util::Stream;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// How [`Reconnecting`] retries after losing its connection: up to
+/// `max_retries` reconnects in a row, `initial_backoff` apart at first and
+/// doubling up to `max_backoff`.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Run an idempotent command again on the new connection when the old
+    /// one failed under it. Other commands return the error either way,
+    /// since the server may have applied them before the connection broke.
+    pub replay_idempotent: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+            replay_idempotent: true,
+        }
+    }
+}
+
+/// A [`Client`] that connects again, backing off between attempts, when its
+/// connection breaks. A new connection negotiates the protocol version,
+/// sends AUTH if the config has a token, and returns to the database last
+/// chosen with [`Reconnecting::select`].
+#[derive(Debug)]
+pub struct Reconnecting {
+    config: ClientConfig,
+    policy: RetryPolicy,
+    client: Option<Client>,
+    db: u8,
+}
+
+impl Reconnecting {
+    pub async fn connect(config: ClientConfig, policy: RetryPolicy) -> Result<Reconnecting> {
+        let mut reconnecting = Reconnecting {
+            config,
+            policy,
+            client: None,
+            db: 0,
+        };
+        reconnecting.client().await?;
+        Ok(reconnecting)
+    }
+
+    /// Runs `command`, reconnecting first if the last connection broke. If
+    /// the connection breaks under it, the error is returned and the next
+    /// call reconnects.
+    pub async fn run<T>(
+        &mut self,
+        command: impl AsyncFnOnce(&mut Client) -> Result<T>,
+    ) -> Result<T> {
+        let result = command(self.client().await?).await;
+        if result.as_ref().is_err_and(Map8x32Error::is_disconnect) {
+            self.client = None;
+        }
+        result
+    }
+
+    /// Like [`Reconnecting::run`] for a command that is safe to repeat,
+    /// such as a read, which is run again on a new connection when the old
+    /// one breaks under it, if the policy allows.
+    pub async fn run_idempotent<T>(
+        &mut self,
+        mut command: impl AsyncFnMut(&mut Client) -> Result<T>,
+    ) -> Result<T> {
+        let mut replays = 0;
+        loop {
+            match command(self.client().await?).await {
+                Err(e) if e.is_disconnect() => {
+                    self.client = None;
+                    if !self.policy.replay_idempotent || replays == self.policy.max_retries {
+                        return Err(e);
+                    }
+                    replays += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Switches to database `db`, now and on every later connection.
+    pub async fn select(&mut self, db: u8) -> Result<()> {
+        self.run_idempotent(async |client| client.select(db).await)
+            .await?;
+        self.db = db;
+        Ok(())
+    }
+
+    /// Follows `key` on a connection of its own, made with the same config,
+    /// policy and database, yielding its values now and after each change.
+    /// A broken connection is replaced and the key subscribed again, so the
+    /// stream only ends once the policy's retries run out or the server
+    /// refuses the subscription.
+    pub fn subscribe(&self, key: u8) -> impl Stream<Item = Event> {
+        let connection = Reconnecting {
+            config: self.config.clone(),
+            policy: self.policy.clone(),
+            client: None,
+            db: self.db,
+        };
+        subscription::watch(connection, key)
+    }
+
+    /// The live connection, opening one if there is none.
+    async fn client(&mut self) -> Result<&mut Client> {
+        if self.client.is_none() {
+            self.client = Some(self.reconnect().await?);
+        }
+        Ok(self.client.as_mut().unwrap())
+    }
+
+    async fn reconnect(&self) -> Result<Client> {
+        let mut backoff = self.policy.initial_backoff;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match self.open().await {
+                Err(e) if e.is_disconnect() && attempts <= self.policy.max_retries => {
+                    sleep(backoff).await;
+                    backoff = (backoff * 2).min(self.policy.max_backoff);
+                }
+                Err(e) if e.is_disconnect() => {
+                    return Err(Map8x32Error::RetriesExhausted {
+                        attempts,
+                        last: Box::new(e),
+                    })
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn open(&self) -> Result<Client> {
+        let mut client = Client::connect(&self.config).await?;
+        client.hello().await?;
+        if self.db != 0 {
+            client.select(self.db).await?;
+        }
+        Ok(client)
+    }
+}
@@ -0,0 +1,76 @@
+use crate::{Map8x32Error, Result};
+use map8x32_protocol::shm::{Mirrored, ShmMirror};
+use std::ffi::c_void;
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::AtomicU32;
+
+/// A read-only mapping of the mirror a server keeps with `--shm-mirror`,
+/// answering GETs on database 0 without a round trip. Reads trail the
+/// server's acknowledged writes by however long it takes to copy them in,
+/// and a server that restarts replaces the file, so open it again after one.
+#[derive(Debug)]
+pub struct ShmReader {
+    ptr: *mut c_void,
+    words: usize,
+}
+
+// SAFETY: the mapping is only reached through `&[AtomicU32]`, which any
+// thread may share.
+unsafe impl Send for ShmReader {}
+unsafe impl Sync for ShmReader {}
+
+impl ShmReader {
+    pub fn open(path: impl AsRef<Path>) -> Result<ShmReader> {
+        let file = File::open(path)?;
+        let words = file.metadata()?.len() as usize / 4;
+        if words == 0 {
+            return Err(Map8x32Error::protocol("the mirror file is empty"));
+        }
+        // SAFETY: a fresh read-only shared mapping of the whole file; the
+        // kernel picks the address, so nothing else is overwritten.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                words * 4,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let reader = ShmReader { ptr, words };
+        if reader.mirror().is_none() {
+            return Err(Map8x32Error::protocol(
+                "not a mirror file this client understands",
+            ));
+        }
+        Ok(reader)
+    }
+
+    fn mirror(&self) -> Option<ShmMirror<'_>> {
+        // SAFETY: `ptr` maps `words` page-aligned words that stay mapped
+        // until `self` drops, and every access to them is an atomic load.
+        let words = unsafe { std::slice::from_raw_parts(self.ptr as *const AtomicU32, self.words) };
+        ShmMirror::open(words)
+    }
+
+    /// `key`'s values as last mirrored. [`Mirrored::TooLarge`] means the
+    /// key outgrew its slot and must be read with [`Client::get`](crate::Client::get).
+    pub fn get(&self, key: u8) -> Mirrored {
+        self.mirror()
+            .expect("checked when the mirror was opened")
+            .read(key)
+    }
+}
+
+impl Drop for ShmReader {
+    fn drop(&mut self) {
+        // SAFETY: `ptr` and the length are what `mmap` returned and took.
+        unsafe { libc::munmap(self.ptr, self.words * 4) };
+    }
+}
@@ -0,0 +1,84 @@
+use crate::{status_error, Client, Reconnecting, Result};
+use futures_util::stream::{self, Stream};
+use map8x32_protocol::*;
+
+/// What a [`Reconnecting::subscribe`] stream yields.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The key's values: as they stood when the subscription started, then
+    /// after each change, empty once the key is gone. Changes made in quick
+    /// succession may arrive as one.
+    Changed { key: u8, values: Vec<u32> },
+    /// The connection broke and a new one subscribed again. Changes made
+    /// in between show up only in the `Changed` that follows.
+    Resubscribed,
+}
+
+impl Client {
+    /// Sends SUBSCRIBE, after which the connection only carries pushes.
+    async fn start_subscription(&mut self, key: u8) -> Result<()> {
+        match self.send(OP_SUBSCRIBE, key, 0).await? {
+            STATUS_OK => Ok(()),
+            status => Err(status_error(status)),
+        }
+    }
+
+    async fn next_push(&mut self) -> Result<Vec<u32>> {
+        match self.status().await? {
+            STATUS_OK => self.read_values().await,
+            STATUS_NOT_FOUND => Ok(Vec::new()),
+            status => Err(status_error(status)),
+        }
+    }
+}
+
+struct Watch {
+    connection: Reconnecting,
+    key: u8,
+    subscribed: bool,
+    started: bool,
+}
+
+impl Watch {
+    /// The next event, or `None` once no new connection could be made
+    /// within the retry policy or the server refused the subscription.
+    async fn next(&mut self) -> Option<Event> {
+        let key = self.key;
+        loop {
+            if !self.subscribed {
+                self.connection
+                    .run_idempotent(async move |client| client.start_subscription(key).await)
+                    .await
+                    .ok()?;
+                self.subscribed = true;
+                if self.started {
+                    return Some(Event::Resubscribed);
+                }
+                self.started = true;
+            }
+            match self
+                .connection
+                .run(async |client| client.next_push().await)
+                .await
+            {
+                Ok(values) => return Some(Event::Changed { key, values }),
+                Err(e) if e.is_disconnect() => self.subscribed = false,
+                Err(_) => return None,
+            }
+        }
+    }
+}
+
+/// Follows `key` on `connection`, which must carry nothing else.
+pub(crate) fn watch(connection: Reconnecting, key: u8) -> impl Stream<Item = Event> {
+    let watch = Watch {
+        connection,
+        key,
+        subscribed: false,
+        started: false,
+    };
+    stream::unfold(watch, async |mut watch| {
+        let event = watch.next().await?;
+        Some((event, watch))
+    })
+}
@@ -0,0 +1,84 @@
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CapRule {
+    pub first_key: u8,
+    pub last_key: u8,
+    pub max_values: usize,
+}
+
+impl CapRule {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.first_key > self.last_key {
+            return Err(format!(
+                "cap key range {}..={} is empty",
+                self.first_key, self.last_key
+            ));
+        }
+        if self.max_values == 0 {
+            return Err(format!(
+                "cap for keys {}..={} must keep at least one value",
+                self.first_key, self.last_key
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The most values each key keeps, from the configured per-key-range
+/// rules. A key several rules cover takes the smallest cap.
+#[derive(Debug, Clone)]
+pub struct Caps {
+    limits: [Option<usize>; 256],
+}
+
+impl Default for Caps {
+    fn default() -> Self {
+        Caps {
+            limits: [None; 256],
+        }
+    }
+}
+
+impl Caps {
+    pub fn new(rules: &[CapRule]) -> Self {
+        let mut caps = Caps::default();
+        for rule in rules {
+            for key in rule.first_key..=rule.last_key {
+                let limit = &mut caps.limits[key as usize];
+                *limit = Some(limit.map_or(rule.max_values, |n| n.min(rule.max_values)));
+            }
+        }
+        caps
+    }
+
+    pub fn get(&self, key: u8) -> Option<usize> {
+        self.limits[key as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(first_key: u8, last_key: u8, max_values: usize) -> CapRule {
+        CapRule {
+            first_key,
+            last_key,
+            max_values,
+        }
+    }
+
+    #[test]
+    fn overlapping_rules_take_the_smallest_cap() {
+        let caps = Caps::new(&[rule(0, 9, 5), rule(5, 14, 3), rule(8, 8, 4)]);
+        assert_eq!(caps.get(0), Some(5));
+        assert_eq!(caps.get(5), Some(3));
+        assert_eq!(caps.get(8), Some(3));
+        assert_eq!(caps.get(14), Some(3));
+        assert_eq!(caps.get(15), None);
+        assert!(rule(2, 1, 1).validate().is_err());
+        assert!(rule(1, 2, 0).validate().is_err());
+    }
+}
@@ -0,0 +1,2204 @@
+use crate::db::{self, Database, DatabaseStats};
+use crate::deadletter::DeadLetter;
+use crate::dispatch::{Dispatcher, LaneReceiver, Lanes};
+use crate::dump::{self, Dump};
+use crate::persistence::Mutation;
+use crate::registry::{Allocation, RegisterError};
+use crate::StorageType;
+use map8x32_protocol::codec::{Aggregate, Codec, Comparison, Info, KeyStats, Request, Response};
+use map8x32_protocol::*;
+use std::io;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use tokio::sync::oneshot;
+use tracing::{error, info};
+
+#[derive(Debug)]
+pub enum Command {
+    Set {
+        key: u8,
+        value: u32,
+        mode: SetMode,
+        respond_to: oneshot::Sender<SeqAck>,
+    },
+    Get {
+        key: u8,
+        respond_to: oneshot::Sender<GetResponse>,
+    },
+    /// GET answered in `codec` straight from the stored values into `out`,
+    /// a buffer the caller hands back and forth, rather than with a copy.
+    GetEncoded {
+        key: u8,
+        codec: &'static dyn Codec,
+        out: Vec<u8>,
+        respond_to: oneshot::Sender<Encoded>,
+    },
+    GetSortedRange {
+        key: u8,
+        min: u32,
+        max: u32,
+        respond_to: oneshot::Sender<GetResponse>,
+    },
+    GetFilter {
+        key: u8,
+        min: u32,
+        max: u32,
+        respond_to: oneshot::Sender<GetResponse>,
+    },
+    Compare {
+        a: u8,
+        b: u8,
+        comparison: Comparison,
+        respond_to: oneshot::Sender<Result<Vec<u32>, u8>>,
+    },
+    Aggregate {
+        key: u8,
+        aggregate: Aggregate,
+        respond_to: oneshot::Sender<Result<u64, u8>>,
+    },
+    GetEnd {
+        key: u8,
+        n: u32,
+        end: End,
+        respond_to: oneshot::Sender<GetResponse>,
+    },
+    DeleteByKey {
+        key: u8,
+        respond_to: oneshot::Sender<SeqAck>,
+    },
+    DeleteIf {
+        key: u8,
+        expected: u32,
+        respond_to: oneshot::Sender<SeqAck>,
+    },
+    Ttl {
+        key: u8,
+        respond_to: oneshot::Sender<Option<u64>>,
+    },
+    MemoryUsage {
+        key: Option<u8>,
+        respond_to: oneshot::Sender<Option<u64>>,
+    },
+    KeyStats {
+        key: u8,
+        respond_to: oneshot::Sender<KeyStats>,
+    },
+    Persist {
+        key: u8,
+        respond_to: oneshot::Sender<SeqAck>,
+    },
+    Touch {
+        key: u8,
+        ttl_ms: u32,
+        respond_to: oneshot::Sender<SeqAck>,
+    },
+    DeleteAt {
+        key: u8,
+        index: u32,
+        respond_to: oneshot::Sender<(SeqAck, Option<u32>)>,
+    },
+    Dedup {
+        key: u8,
+        respond_to: oneshot::Sender<(SeqAck, Option<u32>)>,
+    },
+    Push {
+        key: u8,
+        values: Vec<u32>,
+        respond_to: oneshot::Sender<(SeqAck, Option<u32>)>,
+    },
+    GetDel {
+        key: u8,
+        respond_to: oneshot::Sender<(SeqAck, Option<Vec<u32>>)>,
+    },
+    GetSet {
+        key: u8,
+        value: Option<u32>,
+        respond_to: oneshot::Sender<(SeqAck, Option<Vec<u32>>)>,
+    },
+    Sort {
+        key: u8,
+        descending: bool,
+        respond_to: oneshot::Sender<SeqAck>,
+    },
+    DeleteAll {
+        token: u32,
+        respond_to: oneshot::Sender<SeqAck>,
+    },
+    Swap {
+        a: u8,
+        b: u8,
+        respond_to: oneshot::Sender<SeqAck>,
+    },
+    Rename {
+        from: u8,
+        to: u8,
+        merge: bool,
+        respond_to: oneshot::Sender<SeqAck>,
+    },
+    Copy {
+        from: u8,
+        to: u8,
+        append: bool,
+        respond_to: oneshot::Sender<SeqAck>,
+    },
+    ListAll {
+        snapshot: bool,
+        respond_to: oneshot::Sender<ListAllResponse>,
+    },
+    ListRange {
+        first: u8,
+        last: u8,
+        respond_to: oneshot::Sender<ListAllResponse>,
+    },
+    Tag {
+        key: u8,
+        tag: u8,
+        respond_to: oneshot::Sender<SeqAck>,
+    },
+    ListTagged {
+        tag: u8,
+        respond_to: oneshot::Sender<ListAllResponse>,
+    },
+    DeleteTagged {
+        tag: u8,
+        respond_to: oneshot::Sender<SeqAck>,
+    },
+    DeleteMany {
+        keys: Vec<u8>,
+        respond_to: oneshot::Sender<(SeqAck, Vec<u8>)>,
+    },
+    RandomKey {
+        with_value: bool,
+        respond_to: oneshot::Sender<Option<(u8, Option<u32>)>>,
+    },
+    Scan {
+        cursor: u32,
+        count: u8,
+        respond_to: oneshot::Sender<Option<(u32, Vec<u8>)>>,
+    },
+    AwaitSeq {
+        seq: u64,
+        respond_to: oneshot::Sender<SeqAck>,
+    },
+    DeadLetters {
+        drain: bool,
+        respond_to: oneshot::Sender<Vec<DeadLetter>>,
+    },
+    AllocRegister {
+        allocation: Allocation,
+        respond_to: oneshot::Sender<u8>,
+    },
+    AllocRelease {
+        first_key: u8,
+        respond_to: oneshot::Sender<u8>,
+    },
+    AllocList {
+        respond_to: oneshot::Sender<Vec<Allocation>>,
+    },
+    GetVersioned {
+        key: u8,
+        respond_to: oneshot::Sender<(u64, Vec<u32>)>,
+    },
+    ReplaceIf {
+        key: u8,
+        expected: u64,
+        values: Vec<u32>,
+        respond_to: oneshot::Sender<SeqAck>,
+    },
+    RestoreKey {
+        key: u8,
+        respond_to: oneshot::Sender<SeqAck>,
+    },
+    Info {
+        latencies: bool,
+        respond_to: oneshot::Sender<Info>,
+    },
+    SyncFull {
+        respond_to: oneshot::Sender<FullSyncResponse>,
+    },
+    SetTyped {
+        key: u8,
+        value_type: u8,
+        words: Vec<u32>,
+        respond_to: oneshot::Sender<SeqAck>,
+    },
+    GetTyped {
+        key: u8,
+        respond_to: oneshot::Sender<Option<TypedWords>>,
+    },
+    Dump {
+        name: String,
+        respond_to: oneshot::Sender<u8>,
+    },
+    Restore {
+        name: String,
+        merge: bool,
+        respond_to: oneshot::Sender<SeqAck>,
+    },
+}
+
+/// Where a SET puts its value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetMode {
+    Append,
+    Sorted,
+    Expiring { ttl_ms: u32 },
+}
+
+/// Which end of a key's values GET_FIRST_N and GET_LAST_N read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum End {
+    Oldest,
+    Newest,
+}
+
+impl SetMode {
+    pub fn op(self) -> u8 {
+        match self {
+            SetMode::Append => OP_SET,
+            SetMode::Sorted => OP_SET_SORTED,
+            SetMode::Expiring { .. } => OP_SET_EXPIRING,
+        }
+    }
+
+    fn mutation(self, key: u8, value: u32) -> Mutation {
+        match self {
+            SetMode::Append => Mutation::Set { key, value },
+            SetMode::Sorted => Mutation::InsertSorted { key, value },
+            SetMode::Expiring { ttl_ms } => Mutation::SetExpiring {
+                key,
+                value,
+                deadline: db::unix_ms() + u64::from(ttl_ms),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SeqAck {
+    pub status: u8,
+    pub seq: u64,
+}
+
+impl SeqAck {
+    fn mutated(status: u8, storage: &Database) -> Self {
+        SeqAck {
+            status,
+            seq: storage.record_mutation(),
+        }
+    }
+
+    fn unchanged(status: u8, storage: &Database) -> Self {
+        SeqAck {
+            status,
+            seq: storage.applied_seq(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum GetResponse {
+    Found(Vec<u32>),
+    NotFound,
+}
+
+/// An answer already encoded, with its status.
+#[derive(Debug)]
+pub struct Encoded {
+    pub status: u8,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct ListAllResponse {
+    pub entries: Vec<(u8, Vec<u32>)>,
+}
+
+/// A typed key's stored words; see [`db::to_words`].
+#[derive(Debug)]
+pub struct TypedWords {
+    pub value_type: u8,
+    pub words: Vec<u32>,
+}
+
+#[derive(Debug)]
+pub struct FullSyncResponse {
+    pub seq: u64,
+    pub entries: Vec<(u8, Vec<u32>)>,
+}
+
+/// Runs commands as the dispatcher queues them. A command that panics is
+/// dropped unanswered, which its caller answers INTERNAL_ERROR for; the
+/// panic is logged and counted, and the processor carries on with the
+/// commands queued behind it against the same storage.
+///
+/// A SET that appends is taken together with the SETs queued right behind
+/// it for the same key, up to [`Database::write_batch`] of them, unless
+/// the database transforms values, and the run applied with
+/// [`run_appends`].
+pub(crate) async fn command_processor(mut receiver: LaneReceiver, storage: StorageType) {
+    let mut lanes = Lanes::default();
+    while let Some((sent, command)) = lanes.next(&mut receiver).await {
+        storage.stats.queued.fetch_sub(1, Ordering::Relaxed);
+        let op = command.op();
+        let deadline = sent.deadline;
+        let mut timers = vec![storage.latencies.time(op, sent)];
+        let batch = match command {
+            Command::Set {
+                key,
+                value,
+                mode: SetMode::Append,
+                respond_to,
+            } if storage.write_batch > 1 && storage.transforms.is_empty() => {
+                let mut appends = vec![(value, respond_to)];
+                let appends_to_key = |next: &Command| matches!(next, Command::Set { key: next, mode: SetMode::Append, .. } if *next == key);
+                while appends.len() < storage.write_batch {
+                    let Some((
+                        sent,
+                        Command::Set {
+                            value, respond_to, ..
+                        },
+                    )) = lanes.next_if(&mut receiver, appends_to_key)
+                    else {
+                        break;
+                    };
+                    storage.stats.queued.fetch_sub(1, Ordering::Relaxed);
+                    timers.push(storage.latencies.time(op, sent));
+                    appends.push((value, respond_to));
+                }
+                Ok((key, appends))
+            }
+            command => Err(command),
+        };
+        if let Err(panic) = std::panic::catch_unwind(AssertUnwindSafe(|| match batch {
+            Ok((key, appends)) => run_appends(&storage, key, appends),
+            Err(command) => run_command(&storage, command, deadline),
+        })) {
+            DatabaseStats::count(&storage.stats.processor_panics);
+            let message = panic
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown");
+            error!(
+                db = storage.id,
+                op,
+                panic = message,
+                "command processor panicked; restarted it"
+            );
+            storage.clear_poison();
+        }
+    }
+}
+
+/// Runs SETs appending to `key`, queued back to back, as [`run_command`]
+/// would one after another, but logging them under one WAL lock and
+/// appending them with one lookup of the key's entry. A run that would not
+/// be admitted whole runs one SET at a time instead.
+fn run_appends(storage: &StorageType, key: u8, appends: Vec<(u32, oneshot::Sender<SeqAck>)>) {
+    let batches = appends.len() > 1
+        && storage.registry.permits_write(key)
+        && storage.value_type(key) == VALUE_TYPE_U32
+        && storage.admits_pushes(key, appends.len());
+    if !batches {
+        for (value, respond_to) in appends {
+            let set = Command::Set {
+                key,
+                value,
+                mode: SetMode::Append,
+                respond_to,
+            };
+            run_command(storage, set, None);
+        }
+        return;
+    }
+    let mut logged = Vec::with_capacity(appends.len());
+    let mut failed = None;
+    match storage.lock_wal() {
+        Ok(mut wal) => {
+            for &(value, _) in &appends {
+                if let Err(status) = storage.append(&mut wal, &Mutation::Set { key, value }) {
+                    failed = Some(status);
+                    break;
+                }
+                logged.push(value);
+            }
+            storage.push_all(key, &logged);
+        }
+        Err(status) => failed = Some(status),
+    }
+    storage
+        .stats
+        .batched_sets
+        .fetch_add(logged.len() as u64, Ordering::Relaxed);
+    for (index, (value, respond_to)) in appends.into_iter().enumerate() {
+        storage.count_set(key);
+        storage.touch(key);
+        let ack = match failed {
+            Some(status) if index >= logged.len() => {
+                storage.dead_letters.record(OP_SET, key, value, status);
+                SeqAck::unchanged(status, storage)
+            }
+            _ => SeqAck::mutated(STATUS_OK, storage),
+        };
+        let _ = respond_to.send(ack);
+    }
+}
+
+/// Runs one command. Reads that can take long are dropped unanswered once
+/// abandoned or past `deadline`.
+fn run_command(storage: &StorageType, command: Command, deadline: Option<Instant>) {
+    let expired = || deadline.is_some_and(|deadline| Instant::now() >= deadline);
+    match command {
+        Command::Set {
+            key,
+            value,
+            mode,
+            respond_to,
+        } => {
+            storage.count_set(key);
+            storage.touch(key);
+            let op = mode.op();
+            if !storage.registry.permits_write(key) {
+                storage
+                    .dead_letters
+                    .record(op, key, value, STATUS_KEY_NOT_ALLOCATED);
+                let _ = respond_to.send(SeqAck::unchanged(STATUS_KEY_NOT_ALLOCATED, storage));
+                return;
+            }
+            if storage.value_type(key) != VALUE_TYPE_U32 {
+                storage.dead_letters.record(op, key, value, STATUS_CONFLICT);
+                let _ = respond_to.send(SeqAck::unchanged(STATUS_CONFLICT, storage));
+                return;
+            }
+            let original = value;
+            let value = if storage.transforms.is_empty() {
+                Some(value)
+            } else {
+                let previous = storage.map.get(&key).and_then(|values| values.last());
+                storage.transforms.apply(key, value, previous)
+            };
+            let ack = match value {
+                Some(_) if !storage.admits_push(key) => {
+                    storage
+                        .dead_letters
+                        .record(op, key, original, STATUS_CAPACITY_EXCEEDED);
+                    SeqAck::unchanged(STATUS_CAPACITY_EXCEEDED, storage)
+                }
+                Some(normalized) => {
+                    let mutation = mode.mutation(key, normalized);
+                    match storage.log(mutation.clone()) {
+                        Ok(_wal) => {
+                            mutation.apply(storage);
+                            SeqAck::mutated(STATUS_OK, storage)
+                        }
+                        Err(status) => {
+                            storage.dead_letters.record(op, key, original, status);
+                            SeqAck::unchanged(status, storage)
+                        }
+                    }
+                }
+                None => SeqAck::unchanged(STATUS_OK, storage),
+            };
+            let _ = respond_to.send(ack);
+        }
+        Command::Get { key, respond_to } => {
+            DatabaseStats::count(&storage.stats.get_ops);
+            storage.touch(key);
+            let response = if let Some(values) = storage.live_values(key) {
+                storage.count_get(key, true);
+                GetResponse::Found(values)
+            } else {
+                storage.count_get(key, false);
+                GetResponse::NotFound
+            };
+            let _ = respond_to.send(response);
+        }
+        Command::GetEncoded {
+            key,
+            codec,
+            mut out,
+            respond_to,
+        } => {
+            DatabaseStats::count(&storage.stats.get_ops);
+            storage.touch(key);
+            let found = storage
+                .with_live_values(key, |values| codec.encode_values(values, &mut out))
+                .is_some();
+            storage.count_get(key, found);
+            let status = if found {
+                STATUS_OK
+            } else {
+                codec.encode_response(&Response::Status(STATUS_NOT_FOUND), &mut out);
+                STATUS_NOT_FOUND
+            };
+            let _ = respond_to.send(Encoded { status, bytes: out });
+        }
+        Command::GetSortedRange {
+            key,
+            min,
+            max,
+            respond_to,
+        } => {
+            DatabaseStats::count(&storage.stats.get_ops);
+            storage.touch(key);
+            let response = if let Some(values) = storage.live_values(key) {
+                storage.count_get(key, true);
+                if values.is_sorted() {
+                    let start = values.partition_point(|value| *value < min);
+                    let end = values.partition_point(|value| *value <= max).max(start);
+                    GetResponse::Found(values[start..end].to_vec())
+                } else {
+                    // A plain SET or REPLACE_IF left the key out of order, so
+                    // scan it and answer as if it were sorted.
+                    let mut in_range: Vec<u32> = values
+                        .into_iter()
+                        .filter(|value| (min..=max).contains(value))
+                        .collect();
+                    in_range.sort_unstable();
+                    GetResponse::Found(in_range)
+                }
+            } else {
+                storage.count_get(key, false);
+                GetResponse::NotFound
+            };
+            let _ = respond_to.send(response);
+        }
+        Command::GetFilter {
+            key,
+            min,
+            max,
+            respond_to,
+        } => {
+            DatabaseStats::count(&storage.stats.get_ops);
+            storage.touch(key);
+            let response = if let Some(mut values) = storage.live_values(key) {
+                storage.count_get(key, true);
+                values.retain(|value| (min..=max).contains(value));
+                GetResponse::Found(values)
+            } else {
+                storage.count_get(key, false);
+                GetResponse::NotFound
+            };
+            let _ = respond_to.send(response);
+        }
+        Command::Compare {
+            a,
+            b,
+            comparison,
+            respond_to,
+        } => {
+            DatabaseStats::count(&storage.stats.get_ops);
+            storage.touch(a);
+            storage.touch(b);
+            storage.count_get(a, storage.map.contains_key(&a));
+            storage.count_get(b, storage.map.contains_key(&b));
+            let result = storage.compare(a, b, comparison);
+            let _ = respond_to.send(result);
+        }
+        Command::Aggregate {
+            key,
+            aggregate,
+            respond_to,
+        } => {
+            DatabaseStats::count(&storage.stats.get_ops);
+            storage.touch(key);
+            let result = match storage.live_values(key) {
+                None => {
+                    storage.count_get(key, false);
+                    Err(STATUS_NOT_FOUND)
+                }
+                Some(_) if storage.value_type(key) != VALUE_TYPE_U32 => Err(STATUS_CONFLICT),
+                Some(values) => {
+                    storage.count_get(key, true);
+                    let sum = || values.iter().copied().map(u64::from).sum::<u64>();
+                    Ok(match aggregate {
+                        Aggregate::Sum => sum(),
+                        Aggregate::Min => values.iter().min().copied().map_or(0, u64::from),
+                        Aggregate::Max => values.iter().max().copied().map_or(0, u64::from),
+                        Aggregate::Avg => (sum() as f64 / values.len() as f64).to_bits(),
+                        Aggregate::Count => values.len() as u64,
+                    })
+                }
+            };
+            let _ = respond_to.send(result);
+        }
+        Command::GetEnd {
+            key,
+            n,
+            end,
+            respond_to,
+        } => {
+            DatabaseStats::count(&storage.stats.get_ops);
+            storage.touch(key);
+            let response = if let Some(mut values) = storage.live_values(key) {
+                storage.count_get(key, true);
+                let n = values.len().min(n as usize);
+                match end {
+                    End::Oldest => values.truncate(n),
+                    End::Newest => values = values.split_off(values.len() - n),
+                }
+                GetResponse::Found(values)
+            } else {
+                storage.count_get(key, false);
+                GetResponse::NotFound
+            };
+            let _ = respond_to.send(response);
+        }
+        Command::DeleteByKey { key, respond_to } => {
+            DatabaseStats::count(&storage.stats.delete_ops);
+            storage.touch(key);
+            let ack = if !storage.registry.permits_write(key) {
+                storage
+                    .dead_letters
+                    .record(OP_DELETE_BY_KEY, key, 0, STATUS_KEY_NOT_ALLOCATED);
+                SeqAck::unchanged(STATUS_KEY_NOT_ALLOCATED, storage)
+            } else if !storage.map.contains_key(&key) {
+                SeqAck::unchanged(STATUS_NOT_FOUND, storage)
+            } else {
+                match storage.log(Mutation::DeleteKey { key }) {
+                    Ok(_wal) => {
+                        storage.remove(key);
+                        SeqAck::mutated(STATUS_OK, storage)
+                    }
+                    Err(status) => {
+                        storage
+                            .dead_letters
+                            .record(OP_DELETE_BY_KEY, key, 0, status);
+                        SeqAck::unchanged(status, storage)
+                    }
+                }
+            };
+            let _ = respond_to.send(ack);
+        }
+        Command::DeleteIf {
+            key,
+            expected,
+            respond_to,
+        } => {
+            DatabaseStats::count(&storage.stats.delete_ops);
+            storage.touch(key);
+            let refuse = |status| {
+                storage
+                    .dead_letters
+                    .record(OP_DELETE_IF, key, expected, status);
+                SeqAck::unchanged(status, storage)
+            };
+            let ack = if !storage.registry.permits_write(key) {
+                refuse(STATUS_KEY_NOT_ALLOCATED)
+            } else if !storage.map.contains_key(&key) {
+                SeqAck::unchanged(STATUS_NOT_FOUND, storage)
+            } else if storage.live_values(key).map_or(0, |values| values.len()) != expected as usize
+            {
+                refuse(STATUS_CONFLICT)
+            } else {
+                match storage.log(Mutation::DeleteKey { key }) {
+                    Ok(_wal) => {
+                        storage.remove(key);
+                        SeqAck::mutated(STATUS_OK, storage)
+                    }
+                    Err(status) => refuse(status),
+                }
+            };
+            let _ = respond_to.send(ack);
+        }
+        Command::Ttl { key, respond_to } => {
+            storage.touch(key);
+            let _ = respond_to.send(storage.ttl(key));
+        }
+        Command::KeyStats { key, respond_to } => {
+            let _ = respond_to.send(storage.key_stats(key));
+        }
+        Command::MemoryUsage { key, respond_to } => {
+            let bytes = match key {
+                Some(key) => {
+                    storage.touch(key);
+                    storage.memory_usage(key)
+                }
+                None => Some(storage.total_memory_usage()),
+            };
+            let _ = respond_to.send(bytes);
+        }
+        Command::Persist { key, respond_to } => {
+            storage.touch(key);
+            let refuse = |status| {
+                storage.dead_letters.record(OP_PERSIST, key, 0, status);
+                SeqAck::unchanged(status, storage)
+            };
+            let ack = if !storage.registry.permits_write(key) {
+                refuse(STATUS_KEY_NOT_ALLOCATED)
+            } else {
+                match storage.lock_wal() {
+                    Err(status) => refuse(status),
+                    Ok(mut wal) => {
+                        let at_ms = db::unix_ms();
+                        if !storage.has_live_at(key, 0, at_ms) {
+                            SeqAck::unchanged(STATUS_NOT_FOUND, storage)
+                        } else {
+                            match storage.append(&mut wal, &Mutation::Persist { key, at_ms }) {
+                                Err(status) => refuse(status),
+                                Ok(()) => {
+                                    storage.persist(key, at_ms);
+                                    SeqAck::mutated(STATUS_OK, storage)
+                                }
+                            }
+                        }
+                    }
+                }
+            };
+            let _ = respond_to.send(ack);
+        }
+        Command::Touch {
+            key,
+            ttl_ms: 0,
+            respond_to,
+        } => {
+            storage.touch(key);
+            let status = match storage.has_live_at(key, 0, db::unix_ms()) {
+                true => STATUS_OK,
+                false => STATUS_NOT_FOUND,
+            };
+            let _ = respond_to.send(SeqAck::unchanged(status, storage));
+        }
+        Command::Touch {
+            key,
+            ttl_ms,
+            respond_to,
+        } => {
+            storage.touch(key);
+            let refuse = |status| {
+                storage.dead_letters.record(OP_TOUCH, key, ttl_ms, status);
+                SeqAck::unchanged(status, storage)
+            };
+            let ack = if !storage.registry.permits_write(key) {
+                refuse(STATUS_KEY_NOT_ALLOCATED)
+            } else {
+                match storage.lock_wal() {
+                    Err(status) => refuse(status),
+                    Ok(mut wal) => {
+                        let at_ms = db::unix_ms();
+                        let refresh = Mutation::RefreshExpiry {
+                            key,
+                            deadline: at_ms + u64::from(ttl_ms),
+                            at_ms,
+                        };
+                        if !storage.has_live_at(key, 0, at_ms) {
+                            SeqAck::unchanged(STATUS_NOT_FOUND, storage)
+                        } else {
+                            match storage.append(&mut wal, &refresh) {
+                                Err(status) => refuse(status),
+                                Ok(()) => {
+                                    refresh.apply(storage);
+                                    SeqAck::mutated(STATUS_OK, storage)
+                                }
+                            }
+                        }
+                    }
+                }
+            };
+            let _ = respond_to.send(ack);
+        }
+        Command::DeleteAt {
+            key,
+            index,
+            respond_to,
+        } => {
+            DatabaseStats::count(&storage.stats.delete_ops);
+            storage.touch(key);
+            let refuse = |status| {
+                storage
+                    .dead_letters
+                    .record(OP_DELETE_AT, key, index, status);
+                (SeqAck::unchanged(status, storage), None)
+            };
+            let answer = if !storage.registry.permits_write(key) {
+                refuse(STATUS_KEY_NOT_ALLOCATED)
+            } else if storage.map.contains_key(&key) && storage.value_type(key) != VALUE_TYPE_U32 {
+                refuse(STATUS_CONFLICT)
+            } else {
+                match storage.lock_wal() {
+                    Err(status) => refuse(status),
+                    Ok(mut wal) => {
+                        let at_ms = db::unix_ms();
+                        if !storage.has_live_at(key, index, at_ms) {
+                            (SeqAck::unchanged(STATUS_NOT_FOUND, storage), None)
+                        } else {
+                            match storage
+                                .append(&mut wal, &Mutation::RemoveAt { key, index, at_ms })
+                            {
+                                Err(status) => refuse(status),
+                                Ok(()) => {
+                                    let value = storage.remove_at(key, index, at_ms);
+                                    (SeqAck::mutated(STATUS_OK, storage), value)
+                                }
+                            }
+                        }
+                    }
+                }
+            };
+            let _ = respond_to.send(answer);
+        }
+        Command::Dedup { key, respond_to } => {
+            storage.touch(key);
+            let refuse = |status| {
+                storage.dead_letters.record(OP_DEDUP, key, 0, status);
+                (SeqAck::unchanged(status, storage), None)
+            };
+            let answer = if !storage.registry.permits_write(key) {
+                refuse(STATUS_KEY_NOT_ALLOCATED)
+            } else if storage.map.contains_key(&key) && storage.value_type(key) != VALUE_TYPE_U32 {
+                refuse(STATUS_CONFLICT)
+            } else {
+                match storage.lock_wal() {
+                    Err(status) => refuse(status),
+                    Ok(mut wal) => {
+                        let at_ms = db::unix_ms();
+                        if !storage.has_live_at(key, 0, at_ms) {
+                            (SeqAck::unchanged(STATUS_NOT_FOUND, storage), None)
+                        } else {
+                            match storage.append(&mut wal, &Mutation::Dedup { key, at_ms }) {
+                                Err(status) => refuse(status),
+                                Ok(()) => {
+                                    let removed = storage.dedup(key, at_ms) as u32;
+                                    (SeqAck::mutated(STATUS_OK, storage), Some(removed))
+                                }
+                            }
+                        }
+                    }
+                }
+            };
+            let _ = respond_to.send(answer);
+        }
+        Command::Push {
+            key,
+            values,
+            respond_to,
+        } => {
+            storage.count_set(key);
+            storage.touch(key);
+            let count = values.len() as u32;
+            let refuse = |status| {
+                storage.dead_letters.record(OP_PUSH, key, count, status);
+                (SeqAck::unchanged(status, storage), None)
+            };
+            let held = || storage.with_live_values(key, <[u32]>::len).unwrap_or(0) as u32;
+            let answer = if !storage.registry.permits_write(key) {
+                refuse(STATUS_KEY_NOT_ALLOCATED)
+            } else if storage.value_type(key) != VALUE_TYPE_U32 {
+                refuse(STATUS_CONFLICT)
+            } else {
+                // Each value is transformed as a SET of it right after the
+                // ones before would have been.
+                let mut pushed = Vec::with_capacity(values.len());
+                let mut previous = storage.map.get(&key).and_then(|values| values.last());
+                for value in values {
+                    let value = if storage.transforms.is_empty() {
+                        Some(value)
+                    } else {
+                        storage.transforms.apply(key, value, previous)
+                    };
+                    if let Some(value) = value {
+                        pushed.push(value);
+                        previous = Some(value);
+                    }
+                }
+                if pushed.is_empty() {
+                    (SeqAck::unchanged(STATUS_OK, storage), Some(held()))
+                } else if !storage.admits_pushes(key, pushed.len()) {
+                    DatabaseStats::count(&storage.stats.quota_rejections);
+                    refuse(STATUS_CAPACITY_EXCEEDED)
+                } else {
+                    let mutation = Mutation::Push {
+                        key,
+                        values: pushed,
+                    };
+                    match storage.log(mutation.clone()) {
+                        Ok(_wal) => {
+                            mutation.apply(storage);
+                            (SeqAck::mutated(STATUS_OK, storage), Some(held()))
+                        }
+                        Err(status) => refuse(status),
+                    }
+                }
+            };
+            let _ = respond_to.send(answer);
+        }
+        Command::GetDel { key, respond_to } => {
+            DatabaseStats::count(&storage.stats.delete_ops);
+            storage.touch(key);
+            let refuse = |status| {
+                storage.dead_letters.record(OP_GETDEL, key, 0, status);
+                (SeqAck::unchanged(status, storage), None)
+            };
+            let answer = if !storage.registry.permits_write(key) {
+                refuse(STATUS_KEY_NOT_ALLOCATED)
+            } else if storage.map.contains_key(&key) && storage.value_type(key) != VALUE_TYPE_U32 {
+                refuse(STATUS_CONFLICT)
+            } else {
+                // Read under the WAL lock, so no write lands between the read
+                // and the delete.
+                match storage.lock_wal() {
+                    Err(status) => refuse(status),
+                    Ok(mut wal) => match storage.live_values(key) {
+                        None => (SeqAck::unchanged(STATUS_NOT_FOUND, storage), None),
+                        Some(values) => {
+                            match storage.append(&mut wal, &Mutation::DeleteKey { key }) {
+                                Err(status) => refuse(status),
+                                Ok(()) => {
+                                    storage.remove(key);
+                                    (SeqAck::mutated(STATUS_OK, storage), Some(values))
+                                }
+                            }
+                        }
+                    },
+                }
+            };
+            let _ = respond_to.send(answer);
+        }
+        Command::GetSet {
+            key,
+            value,
+            respond_to,
+        } => {
+            DatabaseStats::count(&storage.stats.replace_ops);
+            storage.touch(key);
+            let refuse = |status| {
+                storage
+                    .dead_letters
+                    .record(OP_GETSET, key, value.unwrap_or_default(), status);
+                (SeqAck::unchanged(status, storage), None)
+            };
+            let values: Vec<u32> = value.into_iter().collect();
+            let answer = if !storage.registry.permits_write(key) {
+                refuse(STATUS_KEY_NOT_ALLOCATED)
+            } else if storage.map.contains_key(&key) && storage.value_type(key) != VALUE_TYPE_U32 {
+                refuse(STATUS_CONFLICT)
+            } else {
+                match storage.lock_wal() {
+                    Err(status) => refuse(status),
+                    Ok(_) if !storage.admits_replace(key, values.len()) => {
+                        refuse(STATUS_CAPACITY_EXCEEDED)
+                    }
+                    Ok(mut wal) => {
+                        let held = storage.live_values(key).unwrap_or_default();
+                        if held.is_empty() && values.is_empty() {
+                            (SeqAck::unchanged(STATUS_OK, storage), Some(held))
+                        } else {
+                            let mutation = Mutation::Replace { key, values };
+                            match storage.append(&mut wal, &mutation) {
+                                Err(status) => refuse(status),
+                                Ok(()) => {
+                                    mutation.apply(storage);
+                                    (SeqAck::mutated(STATUS_OK, storage), Some(held))
+                                }
+                            }
+                        }
+                    }
+                }
+            };
+            let _ = respond_to.send(answer);
+        }
+        Command::Sort {
+            key,
+            descending,
+            respond_to,
+        } => {
+            storage.touch(key);
+            let refuse = |status| {
+                storage
+                    .dead_letters
+                    .record(OP_SORT, key, descending as u32, status);
+                SeqAck::unchanged(status, storage)
+            };
+            let ack = if !storage.registry.permits_write(key) {
+                refuse(STATUS_KEY_NOT_ALLOCATED)
+            } else if storage.map.contains_key(&key) && storage.value_type(key) != VALUE_TYPE_U32 {
+                refuse(STATUS_CONFLICT)
+            } else {
+                match storage.lock_wal() {
+                    Err(status) => refuse(status),
+                    Ok(_) if !storage.map.contains_key(&key) => {
+                        SeqAck::unchanged(STATUS_NOT_FOUND, storage)
+                    }
+                    Ok(mut wal) => {
+                        match storage.append(&mut wal, &Mutation::Sort { key, descending }) {
+                            Err(status) => refuse(status),
+                            Ok(()) => {
+                                storage.sort(key, descending);
+                                SeqAck::mutated(STATUS_OK, storage)
+                            }
+                        }
+                    }
+                }
+            };
+            let _ = respond_to.send(ack);
+        }
+        Command::Rename {
+            from,
+            to,
+            merge,
+            respond_to,
+        } => {
+            storage.touch(from);
+            storage.touch(to);
+            let refuse = |status| {
+                storage
+                    .dead_letters
+                    .record(OP_RENAME, from, to.into(), status);
+                SeqAck::unchanged(status, storage)
+            };
+            let ack =
+                if !storage.registry.permits_write(from) || !storage.registry.permits_write(to) {
+                    refuse(STATUS_KEY_NOT_ALLOCATED)
+                } else {
+                    match storage.lock_wal() {
+                        Err(status) => refuse(status),
+                        Ok(_) if !storage.map.contains_key(&from) => {
+                            SeqAck::unchanged(STATUS_NOT_FOUND, storage)
+                        }
+                        Ok(_)
+                            if merge
+                                && storage.map.contains_key(&to)
+                                && storage.value_type(to) != storage.value_type(from) =>
+                        {
+                            refuse(STATUS_CONFLICT)
+                        }
+                        Ok(mut wal) => {
+                            match storage.append(&mut wal, &Mutation::Rename { from, to, merge }) {
+                                Err(status) => refuse(status),
+                                Ok(()) => {
+                                    storage.rename(from, to, merge);
+                                    SeqAck::mutated(STATUS_OK, storage)
+                                }
+                            }
+                        }
+                    }
+                };
+            let _ = respond_to.send(ack);
+        }
+        Command::Swap { a, b, respond_to } => {
+            storage.touch(a);
+            storage.touch(b);
+            let refuse = |status| {
+                storage.dead_letters.record(OP_SWAP, a, b.into(), status);
+                SeqAck::unchanged(status, storage)
+            };
+            let ack = if !storage.registry.permits_write(a) || !storage.registry.permits_write(b) {
+                refuse(STATUS_KEY_NOT_ALLOCATED)
+            } else {
+                match storage.lock_wal() {
+                    Err(status) => refuse(status),
+                    Ok(_) if !storage.map.contains_key(&a) && !storage.map.contains_key(&b) => {
+                        SeqAck::unchanged(STATUS_NOT_FOUND, storage)
+                    }
+                    Ok(mut wal) => match storage.append(&mut wal, &Mutation::Swap { a, b }) {
+                        Err(status) => refuse(status),
+                        Ok(()) => {
+                            storage.swap(a, b);
+                            SeqAck::mutated(STATUS_OK, storage)
+                        }
+                    },
+                }
+            };
+            let _ = respond_to.send(ack);
+        }
+        Command::Copy {
+            from,
+            to,
+            append,
+            respond_to,
+        } => {
+            storage.touch(from);
+            storage.touch(to);
+            let refuse = |status| {
+                storage
+                    .dead_letters
+                    .record(OP_COPY, from, to.into(), status);
+                SeqAck::unchanged(status, storage)
+            };
+            let len = |key| storage.map.get(&key).map_or(0, |values| values.len());
+            let ack = if !storage.registry.permits_write(to) {
+                refuse(STATUS_KEY_NOT_ALLOCATED)
+            } else {
+                match storage.lock_wal() {
+                    Err(status) => refuse(status),
+                    Ok(_) if !storage.map.contains_key(&from) => {
+                        SeqAck::unchanged(STATUS_NOT_FOUND, storage)
+                    }
+                    Ok(_)
+                        if append
+                            && storage.map.contains_key(&to)
+                            && storage.value_type(to) != storage.value_type(from) =>
+                    {
+                        refuse(STATUS_CONFLICT)
+                    }
+                    Ok(_)
+                        if !storage
+                            .admits_replace(to, len(from) + if append { len(to) } else { 0 }) =>
+                    {
+                        refuse(STATUS_CAPACITY_EXCEEDED)
+                    }
+                    Ok(mut wal) => {
+                        match storage.append(&mut wal, &Mutation::Copy { from, to, append }) {
+                            Err(status) => refuse(status),
+                            Ok(()) => {
+                                storage.copy(from, to, append);
+                                SeqAck::mutated(STATUS_OK, storage)
+                            }
+                        }
+                    }
+                }
+            };
+            let _ = respond_to.send(ack);
+        }
+        Command::DeleteAll { token, respond_to } => {
+            DatabaseStats::count(&storage.stats.delete_all_ops);
+            if storage.delete_all_token != Some(token) {
+                storage
+                    .dead_letters
+                    .record(OP_DELETE_ALL, 0, token, STATUS_UNAUTHORIZED);
+                let _ = respond_to.send(SeqAck::unchanged(STATUS_UNAUTHORIZED, storage));
+                return;
+            }
+            let ack = match storage.log(Mutation::DeleteAll) {
+                Ok(_wal) => {
+                    storage.clear();
+                    SeqAck::mutated(STATUS_OK, storage)
+                }
+                Err(status) => {
+                    storage.dead_letters.record(OP_DELETE_ALL, 0, 0, status);
+                    SeqAck::unchanged(status, storage)
+                }
+            };
+            let _ = respond_to.send(ack);
+        }
+        // Skipped when abandoned, as by CANCEL, or timed out while it queued.
+        Command::ListAll { respond_to, .. } if respond_to.is_closed() || expired() => {}
+        Command::ListAll {
+            snapshot,
+            respond_to,
+        } => {
+            DatabaseStats::count(&storage.stats.list_all_ops);
+            let _writes = snapshot.then(|| storage.hold_writes());
+            // Given up partway once abandoned or timed out.
+            if let Some(entries) = storage.live_entries(|| respond_to.is_closed() || expired()) {
+                let _ = respond_to.send(ListAllResponse { entries });
+            }
+        }
+        Command::ListRange { respond_to, .. } if respond_to.is_closed() || expired() => {}
+        Command::ListRange {
+            first,
+            last,
+            respond_to,
+        } => {
+            DatabaseStats::count(&storage.stats.list_all_ops);
+            let _ = respond_to.send(ListAllResponse {
+                entries: storage.live_range(first..=last),
+            });
+        }
+        Command::Tag {
+            key,
+            tag,
+            respond_to,
+        } => {
+            storage.touch(key);
+            let refuse = |status| {
+                storage.dead_letters.record(OP_TAG, key, tag.into(), status);
+                SeqAck::unchanged(status, storage)
+            };
+            let ack = if !storage.registry.permits_write(key) {
+                refuse(STATUS_KEY_NOT_ALLOCATED)
+            } else if !storage.map.contains_key(&key) {
+                SeqAck::unchanged(STATUS_NOT_FOUND, storage)
+            } else {
+                match storage.log(Mutation::Tag { key, tag }) {
+                    Ok(_wal) => {
+                        storage.set_tag(key, tag);
+                        SeqAck::mutated(STATUS_OK, storage)
+                    }
+                    Err(status) => refuse(status),
+                }
+            };
+            let _ = respond_to.send(ack);
+        }
+        Command::ListTagged { respond_to, .. } if respond_to.is_closed() || expired() => {}
+        Command::ListTagged { tag, respond_to } => {
+            DatabaseStats::count(&storage.stats.list_all_ops);
+            let entries = storage
+                .tagged(tag)
+                .into_iter()
+                .filter_map(|key| Some((key, storage.live_values(key)?)))
+                .collect();
+            let _ = respond_to.send(ListAllResponse { entries });
+        }
+        Command::DeleteTagged { tag, respond_to } => {
+            DatabaseStats::count(&storage.stats.delete_ops);
+            let refuse = |status| {
+                storage
+                    .dead_letters
+                    .record(OP_DELETE_TAGGED, tag, 0, status);
+                SeqAck::unchanged(status, storage)
+            };
+            let ack = match storage.lock_wal() {
+                Err(status) => refuse(status),
+                Ok(mut wal) => {
+                    // Chosen under the WAL lock, so no key is tagged or untagged meanwhile.
+                    let keys = storage.tagged(tag);
+                    if keys.is_empty() {
+                        SeqAck::unchanged(STATUS_NOT_FOUND, storage)
+                    } else if !keys.iter().all(|&key| storage.registry.permits_write(key)) {
+                        refuse(STATUS_KEY_NOT_ALLOCATED)
+                    } else {
+                        let mut deleted = Ok(());
+                        for key in keys {
+                            deleted = storage.append(&mut wal, &Mutation::DeleteKey { key });
+                            if deleted.is_err() {
+                                break;
+                            }
+                            storage.remove(key);
+                        }
+                        match deleted {
+                            Ok(()) => SeqAck::mutated(STATUS_OK, storage),
+                            Err(status) => refuse(status),
+                        }
+                    }
+                }
+            };
+            let _ = respond_to.send(ack);
+        }
+        Command::DeleteMany { keys, respond_to } => {
+            DatabaseStats::count(&storage.stats.delete_ops);
+            let answer = match storage.lock_wal() {
+                Err(status) => {
+                    for &key in &keys {
+                        storage.dead_letters.record(OP_DELETE_MANY, key, 0, status);
+                    }
+                    (SeqAck::unchanged(status, storage), Vec::new())
+                }
+                Ok(mut wal) => {
+                    let mut statuses = Vec::with_capacity(keys.len());
+                    let mut failed = None;
+                    let mut deleted = false;
+                    for &key in &keys {
+                        storage.touch(key);
+                        let status = if let Some(status) = failed {
+                            status
+                        } else if !storage.registry.permits_write(key) {
+                            STATUS_KEY_NOT_ALLOCATED
+                        } else if !storage.map.contains_key(&key) {
+                            STATUS_NOT_FOUND
+                        } else {
+                            match storage.append(&mut wal, &Mutation::DeleteKey { key }) {
+                                Ok(()) => {
+                                    storage.remove(key);
+                                    deleted = true;
+                                    STATUS_OK
+                                }
+                                // Keys after one the WAL refused are not tried.
+                                Err(status) => {
+                                    failed = Some(status);
+                                    status
+                                }
+                            }
+                        };
+                        if status != STATUS_OK && status != STATUS_NOT_FOUND {
+                            storage.dead_letters.record(OP_DELETE_MANY, key, 0, status);
+                        }
+                        statuses.push(status);
+                    }
+                    let ack = if deleted {
+                        SeqAck::mutated(STATUS_OK, storage)
+                    } else {
+                        SeqAck::unchanged(STATUS_OK, storage)
+                    };
+                    (ack, statuses)
+                }
+            };
+            let _ = respond_to.send(answer);
+        }
+        Command::RandomKey {
+            with_value,
+            respond_to,
+        } => {
+            let _ = respond_to.send(storage.random_key(with_value));
+        }
+        Command::Scan {
+            cursor,
+            count,
+            respond_to,
+        } => {
+            let count = if count == 0 {
+                SCAN_DEFAULT_COUNT
+            } else {
+                count
+            };
+            let _ = respond_to.send(storage.scan(cursor, count.into()));
+        }
+        Command::AwaitSeq { seq, respond_to } => {
+            let status = if storage.applied_seq() >= seq {
+                STATUS_OK
+            } else {
+                STATUS_STALE
+            };
+            let _ = respond_to.send(SeqAck::unchanged(status, storage));
+        }
+        Command::DeadLetters { drain, respond_to } => {
+            let _ = respond_to.send(storage.dead_letters.snapshot(drain));
+        }
+        Command::AllocRegister {
+            allocation,
+            respond_to,
+        } => {
+            let status = match storage.registry.register(allocation) {
+                Ok(()) => STATUS_OK,
+                Err(RegisterError::Overlaps(_)) => STATUS_CONFLICT,
+                Err(RegisterError::Invalid) => STATUS_BAD_REQUEST,
+            };
+            let _ = respond_to.send(status);
+        }
+        Command::AllocRelease {
+            first_key,
+            respond_to,
+        } => {
+            let status = if storage.registry.release(first_key) {
+                STATUS_OK
+            } else {
+                STATUS_NOT_FOUND
+            };
+            let _ = respond_to.send(status);
+        }
+        Command::AllocList { respond_to } => {
+            let _ = respond_to.send(storage.registry.list());
+        }
+        Command::GetVersioned { key, respond_to } => {
+            DatabaseStats::count(&storage.stats.get_ops);
+            storage.touch(key);
+            let (version, values) = storage.get_versioned(key);
+            storage.count_get(key, !values.is_empty());
+            let _ = respond_to.send((version, values));
+        }
+        Command::ReplaceIf {
+            key,
+            expected,
+            values,
+            respond_to,
+        } => {
+            DatabaseStats::count(&storage.stats.replace_ops);
+            storage.touch(key);
+            let count = values.len() as u32;
+            let refuse = |status| {
+                storage
+                    .dead_letters
+                    .record(OP_REPLACE_IF, key, count, status);
+                SeqAck::unchanged(status, storage)
+            };
+            let ack = if !storage.registry.permits_write(key) {
+                refuse(STATUS_KEY_NOT_ALLOCATED)
+            } else {
+                match storage.lock_wal() {
+                    Err(status) => refuse(status),
+                    Ok(_) if storage.version(key) != expected => {
+                        SeqAck::unchanged(STATUS_CONFLICT, storage)
+                    }
+                    Ok(_) if !storage.admits_replace(key, values.len()) => {
+                        refuse(STATUS_CAPACITY_EXCEEDED)
+                    }
+                    Ok(mut wal) => {
+                        let mutation = Mutation::Replace { key, values };
+                        match storage.append(&mut wal, &mutation) {
+                            Err(status) => refuse(status),
+                            Ok(()) => {
+                                let Mutation::Replace { values, .. } = mutation else {
+                                    unreachable!()
+                                };
+                                if storage.replace_if(key, expected, values) {
+                                    SeqAck::mutated(STATUS_OK, storage)
+                                } else {
+                                    SeqAck::unchanged(STATUS_CONFLICT, storage)
+                                }
+                            }
+                        }
+                    }
+                }
+            };
+            let _ = respond_to.send(ack);
+        }
+        Command::RestoreKey { key, respond_to } => {
+            storage.touch(key);
+            let refuse = |status| {
+                storage.dead_letters.record(OP_RESTORE_KEY, key, 0, status);
+                SeqAck::unchanged(status, storage)
+            };
+            let ack = match &storage.persistence {
+                None => SeqAck::unchanged(STATUS_UNAVAILABLE, storage),
+                Some(_) if !storage.registry.permits_write(key) => refuse(STATUS_KEY_NOT_ALLOCATED),
+                Some(persistence) => match persistence.lock() {
+                    Err(status) => refuse(status),
+                    // Memory-only: the files on disk no longer reflect the store.
+                    Ok(None) => SeqAck::unchanged(STATUS_UNAVAILABLE, storage),
+                    Ok(mut wal) => match persistence.deleted_values(key, &storage.caps) {
+                        Err(e) => {
+                            error!(key, error = %e, "RESTORE_KEY cannot read the key's history");
+                            SeqAck::unchanged(STATUS_INTERNAL_ERROR, storage)
+                        }
+                        Ok(None) => SeqAck::unchanged(STATUS_NOT_FOUND, storage),
+                        Ok(Some((_, values))) if !storage.admits_replace(key, values.len()) => {
+                            refuse(STATUS_CAPACITY_EXCEEDED)
+                        }
+                        Ok(Some((value_type, values))) => {
+                            let mutation = match value_type {
+                                VALUE_TYPE_U32 => Mutation::Replace { key, values },
+                                value_type => Mutation::SetTyped {
+                                    key,
+                                    value_type,
+                                    words: values,
+                                    replace: true,
+                                },
+                            };
+                            match persistence.append(&mut wal, &mutation) {
+                                Err(status) => refuse(status),
+                                Ok(()) => {
+                                    mutation.apply(storage);
+                                    SeqAck::mutated(STATUS_OK, storage)
+                                }
+                            }
+                        }
+                    },
+                },
+            };
+            let _ = respond_to.send(ack);
+        }
+        Command::Info {
+            latencies,
+            respond_to,
+        } => {
+            let _ = respond_to.send(storage.info(latencies));
+        }
+        Command::SyncFull { respond_to } if respond_to.is_closed() || expired() => {}
+        Command::SyncFull { respond_to } => {
+            let (seq, entries) = storage.consistent_copy();
+            let _ = respond_to.send(FullSyncResponse { seq, entries });
+        }
+        Command::Dump { name, respond_to } => {
+            let Some(dir) = &storage.dump_dir else {
+                let _ = respond_to.send(STATUS_UNAVAILABLE);
+                return;
+            };
+            let path = dir.join(name);
+            let dump = Dump::capture(storage);
+            let db = storage.id;
+            // The copy is taken in order with this shard's commands; only
+            // the file is written off the processor.
+            tokio::task::spawn_blocking(move || {
+                let status = match dump.write(&path) {
+                    Ok(()) => {
+                        info!(db, path = %path.display(), seq = dump.seq, keys = dump.keys.len(), "wrote dump");
+                        STATUS_OK
+                    }
+                    Err(e) => {
+                        error!(db, error = %e, path = %path.display(), "failed to write dump");
+                        STATUS_INTERNAL_ERROR
+                    }
+                };
+                let _ = respond_to.send(status);
+            });
+        }
+        Command::Restore {
+            name,
+            merge,
+            respond_to,
+        } => {
+            let refuse = |status| {
+                storage.dead_letters.record(OP_RESTORE, 0, 0, status);
+                SeqAck::unchanged(status, storage)
+            };
+            let ack = match storage
+                .dump_dir
+                .as_ref()
+                .map(|dir| Dump::read(&dir.join(&name)))
+            {
+                None => SeqAck::unchanged(STATUS_UNAVAILABLE, storage),
+                Some(Err(e)) if e.kind() == io::ErrorKind::NotFound => {
+                    SeqAck::unchanged(STATUS_NOT_FOUND, storage)
+                }
+                Some(Err(e)) => {
+                    error!(db = storage.id, error = %e, "RESTORE cannot load the dump");
+                    let status = if e.kind() == io::ErrorKind::InvalidData {
+                        STATUS_BAD_REQUEST
+                    } else {
+                        STATUS_INTERNAL_ERROR
+                    };
+                    SeqAck::unchanged(status, storage)
+                }
+                Some(Ok(dump)) => {
+                    let sizes: Vec<(u8, usize)> = dump
+                        .keys
+                        .iter()
+                        .map(|dumped| (dumped.key, dumped.values.len()))
+                        .collect();
+                    if !dump
+                        .keys
+                        .iter()
+                        .all(|dumped| storage.registry.permits_write(dumped.key))
+                    {
+                        refuse(STATUS_KEY_NOT_ALLOCATED)
+                    } else if merge
+                        && dump.keys.iter().any(|dumped| {
+                            storage.map.contains_key(&dumped.key)
+                                && storage.value_type(dumped.key) != dumped.value_type
+                        })
+                    {
+                        refuse(STATUS_CONFLICT)
+                    } else if !storage.admits_load(&sizes, !merge) {
+                        refuse(STATUS_CAPACITY_EXCEEDED)
+                    } else {
+                        match storage.log_all(&dump.mutations(merge, db::unix_ms())) {
+                            Ok(()) => {
+                                info!(
+                                    db = storage.id,
+                                    name,
+                                    merge,
+                                    seq = dump.seq,
+                                    keys = dump.keys.len(),
+                                    "restored dump"
+                                );
+                                SeqAck::mutated(STATUS_OK, storage)
+                            }
+                            Err(status) => refuse(status),
+                        }
+                    }
+                }
+            };
+            let _ = respond_to.send(ack);
+        }
+        Command::SetTyped {
+            key,
+            value_type,
+            words,
+            respond_to,
+        } => {
+            storage.count_set(key);
+            storage.touch(key);
+            let op = match value_type {
+                VALUE_TYPE_TIMED => OP_SET_TIMED,
+                _ => OP_SET_TYPED,
+            };
+            let refuse = |status| {
+                storage.dead_letters.record(op, key, 0, status);
+                SeqAck::unchanged(status, storage)
+            };
+            let ack = if !storage.registry.permits_write(key) {
+                refuse(STATUS_KEY_NOT_ALLOCATED)
+            } else if storage.map.contains_key(&key) && storage.value_type(key) != value_type {
+                refuse(STATUS_CONFLICT)
+            } else if !storage.admits_extend(key, words.len()) {
+                refuse(STATUS_CAPACITY_EXCEEDED)
+            } else {
+                let mutation = Mutation::SetTyped {
+                    key,
+                    value_type,
+                    words,
+                    replace: false,
+                };
+                match storage.log(mutation.clone()) {
+                    Ok(_wal) => {
+                        mutation.apply(storage);
+                        SeqAck::mutated(STATUS_OK, storage)
+                    }
+                    Err(status) => refuse(status),
+                }
+            };
+            let _ = respond_to.send(ack);
+        }
+        Command::GetTyped { key, respond_to } => {
+            DatabaseStats::count(&storage.stats.get_ops);
+            storage.touch(key);
+            let response = storage.live_values(key).map(|words| TypedWords {
+                value_type: storage.value_type(key),
+                words,
+            });
+            storage.count_get(key, response.is_some());
+            let _ = respond_to.send(response);
+        }
+    }
+}
+
+impl From<SeqAck> for Response {
+    fn from(ack: SeqAck) -> Self {
+        Response::Ack {
+            status: ack.status,
+            seq: ack.seq,
+        }
+    }
+}
+
+/// Why a command went unanswered.
+#[derive(Debug)]
+pub enum Unanswered {
+    /// The command processors have gone away.
+    Stopped,
+    /// The command processor panicked running it.
+    Failed,
+    /// The read was not answered within the command timeout.
+    TimedOut,
+}
+
+/// Sends a command built around a fresh reply channel and waits for the
+/// reply.
+pub async fn call<T>(
+    sender: &Dispatcher,
+    command: impl FnOnce(oneshot::Sender<T>) -> Command,
+) -> Result<T, Unanswered> {
+    let (tx, rx) = oneshot::channel();
+    let sent = Instant::now();
+    sender.send(command(tx)).map_err(|_| Unanswered::Stopped)?;
+    // Processors outlive every dispatcher, so a reply channel dropped
+    // unanswered means its read timed out or its command panicked.
+    rx.await.map_err(|_| match sender.timeout() {
+        Some(timeout) if sent.elapsed() >= timeout => Unanswered::TimedOut,
+        _ => Unanswered::Failed,
+    })
+}
+
+/// Runs a data or admin request on the engine, answering INTERNAL_ERROR when
+/// its command panicked and TIMEOUT when a read is not answered within the
+/// dispatcher's timeout; `None` means the command processors have gone away.
+/// Connection-level requests (HELLO, AUTH, SELECT_CODEC, SELECT, SLOW_LOG,
+/// PING) are handled by the connection itself.
+pub async fn execute(sender: &Dispatcher, request: Request) -> Option<Response> {
+    let failed = Response::refusal(&request, STATUS_INTERNAL_ERROR);
+    // Writes run to the end, since they may already be applied.
+    let timeout = sender.timeout().filter(|_| !request.writes());
+    let timed_out = Response::refusal(&request, STATUS_TIMEOUT);
+    let run = run_request(sender, request);
+    let answered = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, run)
+            .await
+            .unwrap_or(Err(Unanswered::TimedOut)),
+        None => run.await,
+    };
+    match answered {
+        Ok(response) => Some(response),
+        Err(Unanswered::TimedOut) if timeout.is_some() => {
+            DatabaseStats::count(&sender.storage().stats.command_timeouts);
+            Some(timed_out)
+        }
+        Err(Unanswered::Failed | Unanswered::TimedOut) => Some(failed),
+        Err(Unanswered::Stopped) => None,
+    }
+}
+
+/// Runs GET like [`execute`], except that the command processor encodes the
+/// answer in `codec` straight from the stored values into `out`, emptied
+/// first and handed back as the answer's bytes, so a caller that keeps
+/// reusing it answers GETs without copying the values or allocating. A key
+/// [`Database::present`](crate::db::Database::present) shows missing is
+/// answered NOT_FOUND on the spot instead.
+pub async fn execute_get(
+    sender: &Dispatcher,
+    key: u8,
+    codec: &'static dyn Codec,
+    mut out: Vec<u8>,
+) -> Option<Encoded> {
+    out.clear();
+    // A key the map does not hold is answered without waiting behind the
+    // shard's queue; the connection's own writes were applied before they
+    // were answered, so none is missed.
+    let storage = sender.storage();
+    if !storage.present(key) && !sender.observed() {
+        DatabaseStats::count(&storage.stats.get_ops);
+        storage.touch(key);
+        storage.count_get(key, false);
+        codec.encode_response(&Response::Status(STATUS_NOT_FOUND), &mut out);
+        return Some(Encoded {
+            status: STATUS_NOT_FOUND,
+            bytes: out,
+        });
+    }
+    let run = call(sender, |respond_to| Command::GetEncoded {
+        key,
+        codec,
+        out,
+        respond_to,
+    });
+    let answered = match sender.timeout() {
+        Some(timeout) => tokio::time::timeout(timeout, run)
+            .await
+            .unwrap_or(Err(Unanswered::TimedOut)),
+        None => run.await,
+    };
+    let status = match answered {
+        Ok(encoded) => return Some(encoded),
+        Err(Unanswered::TimedOut) => {
+            DatabaseStats::count(&sender.storage().stats.command_timeouts);
+            STATUS_TIMEOUT
+        }
+        Err(Unanswered::Failed) => STATUS_INTERNAL_ERROR,
+        Err(Unanswered::Stopped) => return None,
+    };
+    let mut bytes = Vec::new();
+    codec.encode_response(&Response::Status(status), &mut bytes);
+    Some(Encoded { status, bytes })
+}
+
+async fn run_request(sender: &Dispatcher, request: Request) -> Result<Response, Unanswered> {
+    let response = match request {
+        Request::Set { key, value } => call(sender, |respond_to| Command::Set {
+            key,
+            value,
+            mode: SetMode::Append,
+            respond_to,
+        })
+        .await?
+        .into(),
+        Request::SetSorted { key, value } => call(sender, |respond_to| Command::Set {
+            key,
+            value,
+            mode: SetMode::Sorted,
+            respond_to,
+        })
+        .await?
+        .into(),
+        Request::SetExpiring { key, value, ttl_ms } => call(sender, |respond_to| Command::Set {
+            key,
+            value,
+            mode: SetMode::Expiring { ttl_ms },
+            respond_to,
+        })
+        .await?
+        .into(),
+        Request::Get { key } => {
+            match call(sender, |respond_to| Command::Get { key, respond_to }).await? {
+                GetResponse::Found(values) => Response::Values(values),
+                GetResponse::NotFound => Response::Status(STATUS_NOT_FOUND),
+            }
+        }
+        Request::GetSortedRange { key, min, max } => {
+            match call(sender, |respond_to| Command::GetSortedRange {
+                key,
+                min,
+                max,
+                respond_to,
+            })
+            .await?
+            {
+                GetResponse::Found(values) => Response::Values(values),
+                GetResponse::NotFound => Response::Status(STATUS_NOT_FOUND),
+            }
+        }
+        Request::Aggregate { key, aggregate } => {
+            match call(sender, |respond_to| Command::Aggregate {
+                key,
+                aggregate,
+                respond_to,
+            })
+            .await?
+            {
+                Ok(result) => Response::Aggregate { result },
+                Err(status) => Response::Status(status),
+            }
+        }
+        Request::Compare { a, b, comparison } => {
+            match call(sender, |respond_to| Command::Compare {
+                a,
+                b,
+                comparison,
+                respond_to,
+            })
+            .await?
+            {
+                Ok(values) => Response::Values(values),
+                Err(status) => Response::Status(status),
+            }
+        }
+        Request::GetFilter { key, min, max } => {
+            match call(sender, |respond_to| Command::GetFilter {
+                key,
+                min,
+                max,
+                respond_to,
+            })
+            .await?
+            {
+                GetResponse::Found(values) => Response::Values(values),
+                GetResponse::NotFound => Response::Status(STATUS_NOT_FOUND),
+            }
+        }
+        Request::GetFirstN { key, n } => match call(sender, |respond_to| Command::GetEnd {
+            key,
+            n,
+            end: End::Oldest,
+            respond_to,
+        })
+        .await?
+        {
+            GetResponse::Found(values) => Response::Values(values),
+            GetResponse::NotFound => Response::Status(STATUS_NOT_FOUND),
+        },
+        Request::GetLastN { key, n } => match call(sender, |respond_to| Command::GetEnd {
+            key,
+            n,
+            end: End::Newest,
+            respond_to,
+        })
+        .await?
+        {
+            GetResponse::Found(values) => Response::Values(values),
+            GetResponse::NotFound => Response::Status(STATUS_NOT_FOUND),
+        },
+        Request::DeleteByKey { key } => call(sender, |respond_to| Command::DeleteByKey {
+            key,
+            respond_to,
+        })
+        .await?
+        .into(),
+        Request::Rename { from, to, merge } => call(sender, |respond_to| Command::Rename {
+            from,
+            to,
+            merge,
+            respond_to,
+        })
+        .await?
+        .into(),
+        Request::Swap { a, b } => call(sender, |respond_to| Command::Swap { a, b, respond_to })
+            .await?
+            .into(),
+        Request::Copy { from, to, append } => call(sender, |respond_to| Command::Copy {
+            from,
+            to,
+            append,
+            respond_to,
+        })
+        .await?
+        .into(),
+        Request::DeleteIf { key, expected } => call(sender, |respond_to| Command::DeleteIf {
+            key,
+            expected,
+            respond_to,
+        })
+        .await?
+        .into(),
+        Request::Ttl { key } => {
+            match call(sender, |respond_to| Command::Ttl { key, respond_to }).await? {
+                Some(ttl_ms) => Response::Ttl { ttl_ms },
+                None => Response::Status(STATUS_NOT_FOUND),
+            }
+        }
+        Request::KeyStats { key } => Response::KeyStats(
+            call(sender, |respond_to| Command::KeyStats { key, respond_to }).await?,
+        ),
+        Request::MemoryUsage { key } => match call(sender, |respond_to| Command::MemoryUsage {
+            key,
+            respond_to,
+        })
+        .await?
+        {
+            Some(bytes) => Response::MemoryUsage { bytes },
+            None => Response::Status(STATUS_NOT_FOUND),
+        },
+        Request::Persist { key } => call(sender, |respond_to| Command::Persist { key, respond_to })
+            .await?
+            .into(),
+        Request::Touch { key, ttl_ms } => call(sender, |respond_to| Command::Touch {
+            key,
+            ttl_ms,
+            respond_to,
+        })
+        .await?
+        .into(),
+        Request::DeleteAt { key, index } => match call(sender, |respond_to| Command::DeleteAt {
+            key,
+            index,
+            respond_to,
+        })
+        .await?
+        {
+            (ack, Some(value)) => Response::Removed {
+                seq: ack.seq,
+                value,
+            },
+            (ack, None) => ack.into(),
+        },
+        Request::Dedup { key } => {
+            match call(sender, |respond_to| Command::Dedup { key, respond_to }).await? {
+                (ack, Some(removed)) => Response::Removed {
+                    seq: ack.seq,
+                    value: removed,
+                },
+                (ack, None) => ack.into(),
+            }
+        }
+        Request::Push { key, values } => {
+            match call(sender, |respond_to| Command::Push {
+                key,
+                values,
+                respond_to,
+            })
+            .await?
+            {
+                (ack, Some(held)) => Response::Removed {
+                    seq: ack.seq,
+                    value: held,
+                },
+                (ack, None) => ack.into(),
+            }
+        }
+        Request::GetDel { key } => {
+            match call(sender, |respond_to| Command::GetDel { key, respond_to }).await? {
+                (ack, Some(values)) => Response::Taken {
+                    seq: ack.seq,
+                    values,
+                },
+                (ack, None) => ack.into(),
+            }
+        }
+        Request::GetSet { key, value } => {
+            match call(sender, |respond_to| Command::GetSet {
+                key,
+                value,
+                respond_to,
+            })
+            .await?
+            {
+                (ack, Some(values)) => Response::Taken {
+                    seq: ack.seq,
+                    values,
+                },
+                (ack, None) => ack.into(),
+            }
+        }
+        Request::Sort { key, descending } => call(sender, |respond_to| Command::Sort {
+            key,
+            descending,
+            respond_to,
+        })
+        .await?
+        .into(),
+        Request::DeleteAll { token } => call(sender, |respond_to| Command::DeleteAll {
+            token,
+            respond_to,
+        })
+        .await?
+        .into(),
+        Request::ListRange { first, last } if first > last => Response::Status(STATUS_BAD_REQUEST),
+        Request::ListRange { first, last } => Response::Entries(
+            call(sender, |respond_to| Command::ListRange {
+                first,
+                last,
+                respond_to,
+            })
+            .await?
+            .entries,
+        ),
+        Request::Tag { key, tag } => call(sender, |respond_to| Command::Tag {
+            key,
+            tag,
+            respond_to,
+        })
+        .await?
+        .into(),
+        Request::ListTagged { tag: 0 } => Response::Status(STATUS_BAD_REQUEST),
+        Request::ListTagged { tag } => Response::Entries(
+            call(sender, |respond_to| Command::ListTagged { tag, respond_to })
+                .await?
+                .entries,
+        ),
+        Request::DeleteTagged { tag: 0 } => Response::Ack {
+            status: STATUS_BAD_REQUEST,
+            seq: 0,
+        },
+        Request::DeleteTagged { tag } => call(sender, |respond_to| Command::DeleteTagged {
+            tag,
+            respond_to,
+        })
+        .await?
+        .into(),
+        Request::DeleteMany { keys } => {
+            match call(sender, |respond_to| Command::DeleteMany {
+                keys,
+                respond_to,
+            })
+            .await?
+            {
+                (ack, statuses) if ack.status == STATUS_OK => Response::DeletedMany {
+                    seq: ack.seq,
+                    statuses,
+                },
+                (ack, _) => ack.into(),
+            }
+        }
+        Request::ListAll { snapshot } => Response::Entries(
+            call(sender, |respond_to| Command::ListAll {
+                snapshot,
+                respond_to,
+            })
+            .await?
+            .entries,
+        ),
+        Request::RandomKey { with_value } => match call(sender, |respond_to| Command::RandomKey {
+            with_value,
+            respond_to,
+        })
+        .await?
+        {
+            Some((key, value)) => Response::RandomKey { key, value },
+            None => Response::Status(STATUS_NOT_FOUND),
+        },
+        Request::Scan { cursor, count } => match call(sender, |respond_to| Command::Scan {
+            cursor,
+            count,
+            respond_to,
+        })
+        .await?
+        {
+            Some((cursor, keys)) => Response::Scan { cursor, keys },
+            None => Response::Status(STATUS_BAD_REQUEST),
+        },
+        Request::AwaitSeq { seq } => {
+            call(sender, |respond_to| Command::AwaitSeq { seq, respond_to })
+                .await?
+                .into()
+        }
+        Request::DeadLetters { drain } => Response::DeadLetters(
+            call(sender, |respond_to| Command::DeadLetters {
+                drain,
+                respond_to,
+            })
+            .await?,
+        ),
+        Request::AllocRegister(allocation) => Response::Status(
+            call(sender, |respond_to| Command::AllocRegister {
+                allocation,
+                respond_to,
+            })
+            .await?,
+        ),
+        Request::AllocRelease { first_key } => Response::Status(
+            call(sender, |respond_to| Command::AllocRelease {
+                first_key,
+                respond_to,
+            })
+            .await?,
+        ),
+        Request::AllocList => Response::Allocations(
+            call(sender, |respond_to| Command::AllocList { respond_to }).await?,
+        ),
+        Request::GetVersioned { key } => {
+            let (version, values) = call(sender, |respond_to| Command::GetVersioned {
+                key,
+                respond_to,
+            })
+            .await?;
+            Response::Versioned { version, values }
+        }
+        Request::ReplaceIf {
+            key,
+            expected,
+            values,
+        } => call(sender, |respond_to| Command::ReplaceIf {
+            key,
+            expected,
+            values,
+            respond_to,
+        })
+        .await?
+        .into(),
+        Request::RestoreKey { key } => {
+            call(sender, |respond_to| Command::RestoreKey { key, respond_to })
+                .await?
+                .into()
+        }
+        Request::Info { latencies } => Response::Info(
+            call(sender, |respond_to| Command::Info {
+                latencies,
+                respond_to,
+            })
+            .await?,
+        ),
+        Request::SyncFull => {
+            let FullSyncResponse { seq, entries } =
+                call(sender, |respond_to| Command::SyncFull { respond_to }).await?;
+            Response::FullSync { seq, entries }
+        }
+        Request::Dump { name } if !dump::valid_name(&name) => Response::Status(STATUS_BAD_REQUEST),
+        Request::Dump { name } => {
+            Response::Status(call(sender, |respond_to| Command::Dump { name, respond_to }).await?)
+        }
+        Request::Restore { name, .. } if !dump::valid_name(&name) => Response::Ack {
+            status: STATUS_BAD_REQUEST,
+            seq: 0,
+        },
+        Request::Restore { name, merge } => call(sender, |respond_to| Command::Restore {
+            name,
+            merge,
+            respond_to,
+        })
+        .await?
+        .into(),
+        Request::SetTyped { key, value } => {
+            let (value_type, words) = (value.value_type(), db::to_words(&value));
+            call(sender, |respond_to| Command::SetTyped {
+                key,
+                value_type,
+                words,
+                respond_to,
+            })
+            .await?
+            .into()
+        }
+        Request::SetTimed { key, value, at_ms } => {
+            let at_ms = if at_ms == 0 { db::unix_ms() } else { at_ms };
+            call(sender, |respond_to| Command::SetTyped {
+                key,
+                value_type: VALUE_TYPE_TIMED,
+                words: timed::value_words(at_ms, value).to_vec(),
+                respond_to,
+            })
+            .await?
+            .into()
+        }
+        Request::GetTyped { key } => {
+            match call(sender, |respond_to| Command::GetTyped { key, respond_to }).await? {
+                Some(TypedWords {
+                    value_type: VALUE_TYPE_TIMED,
+                    ..
+                }) => Response::Status(STATUS_CONFLICT),
+                Some(TypedWords { value_type, words }) => {
+                    match db::from_words(value_type, &words) {
+                        Some(values) => Response::Typed { value_type, values },
+                        None => {
+                            error!(
+                                key,
+                                value_type, "stored words do not parse as the key's value type"
+                            );
+                            Response::Status(STATUS_INTERNAL_ERROR)
+                        }
+                    }
+                }
+                None => Response::Status(STATUS_NOT_FOUND),
+            }
+        }
+        Request::Hello { .. }
+        | Request::Auth { .. }
+        | Request::SelectCodec { .. }
+        | Request::Select { .. }
+        | Request::SlowLog { .. }
+        | Request::ClientList
+        | Request::ClientInfo
+        | Request::ClientKill { .. }
+        | Request::Ping
+        | Request::Keepalive
+        | Request::Pong
+        | Request::Subscribe { .. }
+        | Request::SubscribeEvents { .. }
+        | Request::Monitor
+        | Request::Multiplex
+        | Request::Cancel { .. }
+        | Request::Chunked { .. }
+        | Request::Priority { .. }
+        | Request::Config { .. }
+        | Request::Invalid { .. } => Response::Status(STATUS_BAD_REQUEST),
+    };
+    Ok(response)
+}
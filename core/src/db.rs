@@ -0,0 +1,1562 @@
+use crate::cap::Caps;
+use crate::deadletter::DeadLetters;
+use crate::latency::Latencies;
+use crate::persistence::{Mutation, Persistence, WalGuard};
+use crate::registry::Registry;
+use crate::rollup::Rollups;
+use crate::store::{Entry, Store};
+use crate::transform::Pipeline;
+use crate::values::Values;
+use dashmap::DashMap;
+use map8x32_protocol::codec::{Comparison, Info, KeyStats, KeyspaceEvent, TypedValue};
+use map8x32_protocol::*;
+use std::collections::HashSet;
+use std::ops::{Deref, RangeInclusive};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+pub const VALUE_BYTES: u64 = std::mem::size_of::<u32>() as u64;
+pub const KEY_OVERHEAD_BYTES: u64 = std::mem::size_of::<(u8, Values)>() as u64;
+const DEADLINES_OVERHEAD_BYTES: u64 = std::mem::size_of::<(u8, Vec<u64>)>() as u64;
+
+/// How many SETs queued for one key a command processor applies together
+/// unless told otherwise; see [`Database::write_batch`].
+pub const DEFAULT_WRITE_BATCH: usize = 64;
+/// Changed keys a subscriber may fall behind by before it misses some.
+const CHANGE_BACKLOG: usize = 1024;
+/// Events an event subscriber may fall behind by before it misses some.
+const EVENT_BACKLOG: usize = 1024;
+
+/// Held by a write from before it is logged until it has been applied: the
+/// WAL lock, with a data directory, and a share of the lock
+/// [`Database::hold_writes`] takes.
+pub struct WriteGuard<'a> {
+    wal: WalGuard<'a>,
+    _writes: RwLockReadGuard<'a, ()>,
+}
+
+/// Something that happened to the data other than a write to a key: values
+/// the server dropped on its own, or DELETE_ALL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// `count` of the key's values expired and were purged.
+    Expired {
+        key: u8,
+        count: u64,
+    },
+    /// `count` of the key's values were dropped to keep it within its cap.
+    Evicted {
+        key: u8,
+        count: u64,
+    },
+    DeleteAll,
+}
+
+impl Event {
+    /// The event as SUBSCRIBE_EVENTS pushes it.
+    pub fn pushed(self) -> KeyspaceEvent {
+        let (event, key, count) = match self {
+            Event::Expired { key, count } => (EVENT_EXPIRED, key, count),
+            Event::Evicted { key, count } => (EVENT_EVICTED, key, count),
+            Event::DeleteAll => (EVENT_DELETE_ALL, 0, 0),
+        };
+        KeyspaceEvent { event, key, count }
+    }
+}
+
+/// The words a typed value is stored as: one for a u32, low then high for
+/// a u64, and for a blob its length followed by its bytes packed
+/// little-endian, the last word zero-padded.
+pub fn to_words(value: &TypedValue) -> Vec<u32> {
+    match value {
+        TypedValue::U32(value) => vec![*value],
+        TypedValue::U64(value) => vec![*value as u32, (*value >> 32) as u32],
+        TypedValue::Bytes(bytes) => {
+            let mut words = vec![bytes.len() as u32];
+            words.extend(bytes.chunks(4).map(|chunk| {
+                let mut word = [0u8; 4];
+                word[..chunk.len()].copy_from_slice(chunk);
+                u32::from_le_bytes(word)
+            }));
+            words
+        }
+    }
+}
+
+/// Splits a key's stored words back into values of `value_type`, or `None`
+/// if they do not parse as that type.
+pub fn from_words(value_type: u8, mut words: &[u32]) -> Option<Vec<TypedValue>> {
+    let mut values = Vec::new();
+    while let Some((&first, rest)) = words.split_first() {
+        let value = match value_type {
+            VALUE_TYPE_U32 => {
+                words = rest;
+                TypedValue::U32(first)
+            }
+            VALUE_TYPE_U64 => {
+                let &high = rest.first()?;
+                words = &rest[1..];
+                TypedValue::U64(u64::from(first) | u64::from(high) << 32)
+            }
+            VALUE_TYPE_BYTES => {
+                let len = first as usize;
+                let packed = rest.get(..len.div_ceil(4))?;
+                words = &rest[packed.len()..];
+                let mut bytes: Vec<u8> =
+                    packed.iter().flat_map(|word| word.to_le_bytes()).collect();
+                bytes.truncate(len);
+                TypedValue::Bytes(bytes)
+            }
+            _ => return None,
+        };
+        values.push(value);
+    }
+    Some(values)
+}
+
+/// Drops values from the front of `values` until at most `cap` remain,
+/// returning how many were dropped.
+pub fn trim<T>(values: &mut Vec<T>, cap: Option<usize>) -> usize {
+    let excess = cap.map_or(0, |cap| values.len().saturating_sub(cap));
+    values.drain(..excess);
+    excess
+}
+
+/// Drops each of `values` live at `at_ms` that equals an earlier live one,
+/// along with its expiry in `deadlines`, returning how many went. Expired
+/// values are left for the sweep.
+pub fn dedup<V>(values: &mut V, deadlines: Option<&mut Vec<u64>>, at_ms: u64) -> usize
+where
+    V: Deref<Target = [u32]> + FromIterator<u32>,
+{
+    let mut seen = HashSet::new();
+    let keep: Vec<bool> = values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| {
+            deadlines
+                .as_ref()
+                .is_some_and(|deadlines| expired(deadlines[i], at_ms))
+                || seen.insert(*value)
+        })
+        .collect();
+    *values = values
+        .iter()
+        .zip(&keep)
+        .filter(|(_, &kept)| kept)
+        .map(|(value, _)| *value)
+        .collect();
+    if let Some(deadlines) = deadlines {
+        let mut marks = keep.iter();
+        deadlines.retain(|_| *marks.next().unwrap());
+    }
+    keep.iter().filter(|&&kept| !kept).count()
+}
+
+/// Sorts `values`, descending with `descending`, carrying each one's expiry
+/// in `deadlines` along with it. The sort is stable.
+pub fn sort(values: &mut [u32], deadlines: Option<&mut Vec<u64>>, descending: bool) {
+    let order = |a: &u32, b: &u32| match descending {
+        false => a.cmp(b),
+        true => b.cmp(a),
+    };
+    let Some(deadlines) = deadlines else {
+        values.sort_by(order);
+        return;
+    };
+    let mut pairs: Vec<(u32, u64)> = values
+        .iter()
+        .copied()
+        .zip(deadlines.iter().copied())
+        .collect();
+    pairs.sort_by(|a, b| order(&a.0, &b.0));
+    for (i, (value, deadline)) in pairs.into_iter().enumerate() {
+        values[i] = value;
+        deadlines[i] = deadline;
+    }
+}
+
+/// A number below `n`, drawn uniformly from the system's random source.
+fn random_below(n: usize) -> usize {
+    let bytes: [u8; 8] = ring::rand::generate(&ring::rand::SystemRandom::new())
+        .expect("system random source")
+        .expose();
+    // The modulo bias is far below what sampling a keyspace can notice.
+    (u64::from_le_bytes(bytes) % n as u64) as usize
+}
+
+pub fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Whether a value with expiry `deadline`, 0 meaning never, has expired.
+pub fn expired(deadline: u64, now_ms: u64) -> bool {
+    deadline != 0 && deadline <= now_ms
+}
+
+#[derive(Debug, Default)]
+pub struct DatabaseStats {
+    pub keys: AtomicU64,
+    pub values: AtomicU64,
+    pub set_ops: AtomicU64,
+    pub get_ops: AtomicU64,
+    pub delete_ops: AtomicU64,
+    pub delete_all_ops: AtomicU64,
+    pub list_all_ops: AtomicU64,
+    pub replace_ops: AtomicU64,
+    pub get_hits: AtomicU64,
+    pub get_misses: AtomicU64,
+    pub quota_rejections: AtomicU64,
+    pub trimmed_values: AtomicU64,
+    pub expired_values: AtomicU64,
+    /// Timed values folded into summaries by the rollup.
+    pub rolled_up_values: AtomicU64,
+    pub peak_memory_bytes: AtomicU64,
+    /// Bytes packed keys take less than they would plainly.
+    pub packed_savings: AtomicU64,
+    /// Commands sent to the command processors and not yet taken up.
+    pub queued: AtomicU64,
+    pub processor_panics: AtomicU64,
+    /// Reads abandoned and answered TIMEOUT for taking too long.
+    pub command_timeouts: AtomicU64,
+    /// SETs applied in a batch with others queued for the same key.
+    pub batched_sets: AtomicU64,
+}
+
+impl DatabaseStats {
+    pub fn memory_bytes(&self) -> u64 {
+        (self.keys.load(Ordering::Relaxed) * KEY_OVERHEAD_BYTES
+            + self.values.load(Ordering::Relaxed) * VALUE_BYTES)
+            .saturating_sub(self.packed_savings.load(Ordering::Relaxed))
+    }
+
+    /// Operation counters, labelled the way metrics and reports name them.
+    pub fn ops(&self) -> [(&'static str, &AtomicU64); 6] {
+        [
+            ("set", &self.set_ops),
+            ("get", &self.get_ops),
+            ("delete_by_key", &self.delete_ops),
+            ("delete_all", &self.delete_all_ops),
+            ("list_all", &self.list_all_ops),
+            ("replace_if", &self.replace_ops),
+        ]
+    }
+
+    fn note_memory(&self) {
+        self.peak_memory_bytes
+            .fetch_max(self.memory_bytes(), Ordering::Relaxed);
+    }
+
+    pub fn count(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// One key's KEYSTATS counters.
+#[derive(Debug, Default)]
+struct KeyCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    sets: AtomicU64,
+    last_access_ms: AtomicU64,
+}
+
+#[derive(Debug)]
+pub struct Database {
+    pub id: u8,
+    /// Every key's values, in the order `--storage-order` chose.
+    pub map: Store,
+    pub stats: DatabaseStats,
+    pub latencies: Latencies,
+    pub max_memory: Option<u64>,
+    /// The most keys the database holds; writes that would add another are
+    /// refused like those over the memory quota.
+    pub max_keys: Option<u64>,
+    /// The most values the database holds across all its keys.
+    pub max_values: Option<u64>,
+    /// Keys are packed once they hold this many values in non-decreasing
+    /// order; see [`Values`].
+    pub pack_above: Option<usize>,
+    pub transforms: Pipeline,
+    pub caps: Caps,
+    pub rollups: Rollups,
+    pub dead_letters: DeadLetters,
+    pub registry: Registry,
+    pub persistence: Option<Persistence>,
+    /// Where DUMP writes its files; without one DUMP is refused.
+    pub dump_dir: Option<PathBuf>,
+    /// The token DELETE_ALL must carry; without one DELETE_ALL is refused.
+    pub delete_all_token: Option<u32>,
+    /// The most SETs queued back to back for one key that a command
+    /// processor applies together.
+    pub write_batch: usize,
+    seq: AtomicU64,
+    versions: [AtomicU64; 256],
+    accesses: [AtomicU64; 256],
+    key_counters: [KeyCounters; 256],
+    value_types: [AtomicU8; 256],
+    /// Each key's tag, 0 for none.
+    tags: [AtomicU8; 256],
+    /// One bit per key in the map, only changed with that key's shard
+    /// locked, so a read that finds a bit clear needs no lock.
+    present: [AtomicU64; 4],
+    /// For keys holding expiring values, every value's expiry in Unix
+    /// milliseconds (0 for those that never expire), index for index.
+    deadlines: DashMap<u8, Vec<u64>>,
+    /// Shared by every write while it is logged and applied, and taken
+    /// exclusively by copies that must fall between writes.
+    writes: RwLock<()>,
+    changes: broadcast::Sender<u8>,
+    events: broadcast::Sender<Event>,
+    started: Instant,
+}
+
+impl Database {
+    pub fn new(id: u8, max_memory: Option<u64>) -> Self {
+        Database {
+            id,
+            map: Store::default(),
+            stats: DatabaseStats::default(),
+            latencies: Latencies::default(),
+            max_memory,
+            max_keys: None,
+            max_values: None,
+            pack_above: None,
+            transforms: Pipeline::default(),
+            caps: Caps::default(),
+            rollups: Rollups::default(),
+            dead_letters: DeadLetters::default(),
+            registry: Registry::default(),
+            persistence: None,
+            dump_dir: None,
+            delete_all_token: None,
+            write_batch: DEFAULT_WRITE_BATCH,
+            seq: AtomicU64::new(0),
+            versions: std::array::from_fn(|_| AtomicU64::new(0)),
+            accesses: std::array::from_fn(|_| AtomicU64::new(0)),
+            key_counters: std::array::from_fn(|_| KeyCounters::default()),
+            value_types: std::array::from_fn(|_| AtomicU8::new(VALUE_TYPE_U32)),
+            tags: std::array::from_fn(|_| AtomicU8::new(0)),
+            present: std::array::from_fn(|_| AtomicU64::new(0)),
+            deadlines: DashMap::new(),
+            writes: RwLock::new(()),
+            changes: broadcast::channel(CHANGE_BACKLOG).0,
+            events: broadcast::channel(EVENT_BACKLOG).0,
+            started: Instant::now(),
+        }
+    }
+
+    /// The INFO statistics, with every operation's latencies if asked.
+    pub fn info(&self, latencies: bool) -> Info {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        Info {
+            uptime_ms: self.started.elapsed().as_millis() as u64,
+            keys: load(&self.stats.keys),
+            values: load(&self.stats.values),
+            memory_bytes: self.stats.memory_bytes(),
+            hits: load(&self.stats.get_hits),
+            misses: load(&self.stats.get_misses),
+            ops: self
+                .stats
+                .ops()
+                .into_iter()
+                .map(|(op, counter)| (op.to_string(), load(counter)))
+                .collect(),
+            persistence: self.persistence.as_ref().map(|p| p.state()),
+            allocations: self.registry.list(),
+            quota_rejections: load(&self.stats.quota_rejections),
+            max_keys: self.max_keys,
+            max_values: self.max_values,
+            latencies: latencies.then(|| self.latencies.snapshot()),
+        }
+    }
+
+    /// Every key with its values, ordered by key, and the sequence number of
+    /// the last mutation they include, all at a single point in time.
+    pub fn consistent_copy(&self) -> (u64, Vec<(u8, Vec<u32>)>) {
+        let _writes = self.hold_writes();
+        let mut entries = self.map.collect(|key, values| (key, values.to_vec()));
+        entries.sort_unstable_by_key(|(key, _)| *key);
+        (self.applied_seq(), entries)
+    }
+
+    pub fn applied_seq(&self) -> u64 {
+        self.seq.load(Ordering::Acquire)
+    }
+
+    pub fn record_mutation(&self) -> u64 {
+        self.seq.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    fn admits(&self, additional_bytes: u64) -> bool {
+        match self.max_memory {
+            Some(limit) => self.stats.memory_bytes() + additional_bytes <= limit,
+            None => true,
+        }
+    }
+
+    /// Whether `keys` more keys and `values` more values, either of which
+    /// may be negative, stay within the key and value limits. A count that
+    /// does not grow is never refused, even over a limit lowered since.
+    fn admits_counts(&self, keys: i64, values: i64) -> bool {
+        let within = |limit: Option<u64>, counter: &AtomicU64, growth: i64| {
+            growth <= 0
+                || limit
+                    .is_none_or(|limit| counter.load(Ordering::Relaxed) + growth as u64 <= limit)
+        };
+        within(self.max_keys, &self.stats.keys, keys)
+            && within(self.max_values, &self.stats.values, values)
+    }
+
+    /// Counts a keyed command against `key`, for the keyspace heatmap and
+    /// KEYSTATS.
+    pub fn touch(&self, key: u8) {
+        self.accesses[key as usize].fetch_add(1, Ordering::Relaxed);
+        self.key_counters[key as usize]
+            .last_access_ms
+            .store(unix_ms(), Ordering::Relaxed);
+    }
+
+    /// Counts a read of `key` as a hit or a miss, for INFO and KEYSTATS.
+    pub fn count_get(&self, key: u8, hit: bool) {
+        let counters = &self.key_counters[key as usize];
+        let (total, own) = match hit {
+            true => (&self.stats.get_hits, &counters.hits),
+            false => (&self.stats.get_misses, &counters.misses),
+        };
+        DatabaseStats::count(total);
+        DatabaseStats::count(own);
+    }
+
+    pub fn count_set(&self, key: u8) {
+        DatabaseStats::count(&self.stats.set_ops);
+        DatabaseStats::count(&self.key_counters[key as usize].sets);
+    }
+
+    pub fn key_stats(&self, key: u8) -> KeyStats {
+        let counters = &self.key_counters[key as usize];
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        KeyStats {
+            hits: load(&counters.hits),
+            misses: load(&counters.misses),
+            sets: load(&counters.sets),
+            last_access_ms: load(&counters.last_access_ms),
+        }
+    }
+
+    pub fn accesses(&self, key: u8) -> u64 {
+        self.accesses[key as usize].load(Ordering::Relaxed)
+    }
+
+    /// Per-key version, bumped by every change to the key's values. Keys
+    /// that were never written are at version 0.
+    pub fn version(&self, key: u8) -> u64 {
+        self.versions[key as usize].load(Ordering::Acquire)
+    }
+
+    fn bump_version(&self, key: u8) {
+        self.versions[key as usize].fetch_add(1, Ordering::AcqRel);
+        let _ = self.changes.send(key);
+    }
+
+    /// Every key whose values change from now on, as the version bumps.
+    /// A subscriber that lags by more than the backlog is told how many
+    /// changes it missed rather than which.
+    pub fn subscribe(&self) -> broadcast::Receiver<u8> {
+        self.changes.subscribe()
+    }
+
+    /// Every [`Event`] from now on; like [`Database::subscribe`], a lagging
+    /// subscriber is told how many it missed.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    fn notify(&self, event: Event) {
+        let _ = self.events.send(event);
+    }
+
+    /// The type the key's values were written as; keys written with plain
+    /// SET, and keys that do not exist, hold u32s.
+    pub fn value_type(&self, key: u8) -> u8 {
+        self.value_types[key as usize].load(Ordering::Acquire)
+    }
+
+    fn set_value_type(&self, key: u8, value_type: u8) {
+        self.value_types[key as usize].store(value_type, Ordering::Release);
+    }
+
+    pub fn tag(&self, key: u8) -> u8 {
+        self.tags[key as usize].load(Ordering::Acquire)
+    }
+
+    /// Tags `key`, returning whether it exists; keys that do not keep no
+    /// tag.
+    pub fn set_tag(&self, key: u8, tag: u8) -> bool {
+        let exists = self.map.contains_key(&key);
+        if exists {
+            self.tags[key as usize].store(tag, Ordering::Release);
+        }
+        exists
+    }
+
+    /// The keys carrying `tag`, in key order.
+    pub fn tagged(&self, tag: u8) -> Vec<u8> {
+        (0..=u8::MAX)
+            .filter(|&key| self.tag(key) == tag && self.map.contains_key(&key))
+            .collect()
+    }
+
+    /// Every tagged key's tag.
+    pub fn copy_tags(&self) -> Vec<(u8, u8)> {
+        (0..=u8::MAX)
+            .map(|key| (key, self.tag(key)))
+            .filter(|&(key, tag)| tag != 0 && self.map.contains_key(&key))
+            .collect()
+    }
+
+    /// Whether `key` is in the map, read without locking it. A key whose
+    /// values have all expired counts until they are swept.
+    pub fn present(&self, key: u8) -> bool {
+        self.present[usize::from(key / 64)].load(Ordering::Acquire) & (1 << (key % 64)) != 0
+    }
+
+    /// Call with `key`'s map entry held, or its shard locked otherwise.
+    fn mark_present(&self, key: u8, present: bool) {
+        let (word, bit) = (&self.present[usize::from(key / 64)], 1 << (key % 64));
+        match present {
+            true => word.fetch_or(bit, Ordering::Release),
+            false => word.fetch_and(!bit, Ordering::Release),
+        };
+    }
+
+    /// Marks `key` as the map holds it now, for writes that changed it
+    /// without keeping its entry.
+    fn recheck_present(&self, key: u8) {
+        let held = self.map.get(&key);
+        self.mark_present(key, held.is_some());
+    }
+
+    /// Resets what is kept about a key that no longer exists.
+    fn forget(&self, key: u8) {
+        self.set_value_type(key, VALUE_TYPE_U32);
+        self.tags[key as usize].store(0, Ordering::Release);
+    }
+
+    pub fn get_versioned(&self, key: u8) -> (u64, Vec<u32>) {
+        (self.version(key), self.live_values(key).unwrap_or_default())
+    }
+
+    /// `key`'s values that have not expired, or `None` if none are left.
+    pub fn live_values(&self, key: u8) -> Option<Vec<u32>> {
+        let values = self.map.get(&key)?;
+        let Some(deadlines) = self.deadlines.get(&key) else {
+            return Some(values.to_vec());
+        };
+        let now_ms = unix_ms();
+        let live: Vec<u32> = values
+            .iter()
+            .zip(deadlines.iter())
+            .filter(|(_, deadline)| !expired(**deadline, now_ms))
+            .map(|(value, _)| value)
+            .collect();
+        (!live.is_empty()).then_some(live)
+    }
+
+    /// Runs `read` on `key`'s values that have not expired, as
+    /// [`Database::live_values`] would return them, in place when they are
+    /// plain and never expire, so reading them copies nothing.
+    pub fn with_live_values<T>(&self, key: u8, read: impl FnOnce(&[u32]) -> T) -> Option<T> {
+        let values = self.map.get(&key)?;
+        if let (Values::Plain(plain), false) = (&*values, self.deadlines.contains_key(&key)) {
+            return Some(read(plain));
+        }
+        drop(values);
+        self.live_values(key).map(|values| read(&values))
+    }
+
+    /// Milliseconds until `key`'s last live value expires, 0 if one of them
+    /// never does; `None` if it has no live values.
+    pub fn ttl(&self, key: u8) -> Option<u64> {
+        let now_ms = unix_ms();
+        let Some(deadlines) = self.deadlines.get(&key) else {
+            return self.holds_live_values(key, now_ms).then_some(0);
+        };
+        let live: Vec<u64> = deadlines
+            .iter()
+            .copied()
+            .filter(|&deadline| !expired(deadline, now_ms))
+            .collect();
+        if live.contains(&0) {
+            return Some(0);
+        }
+        live.iter().max().map(|deadline| deadline - now_ms)
+    }
+
+    fn holds_live_values(&self, key: u8, now_ms: u64) -> bool {
+        match self.deadlines.get(&key) {
+            None => self.map.get(&key).is_some_and(|values| !values.is_empty()),
+            Some(deadlines) => deadlines.iter().any(|&deadline| !expired(deadline, now_ms)),
+        }
+    }
+
+    /// Up to `count` keys with values that have not expired, in key order
+    /// from `cursor` on, and the cursor after the last of them; 0 once no
+    /// keys are left. `None` if `cursor` is past the keyspace.
+    pub fn scan(&self, cursor: u32, count: usize) -> Option<(u32, Vec<u8>)> {
+        let start = u8::try_from(cursor).ok()?;
+        let now_ms = unix_ms();
+        let mut keys = Vec::with_capacity(count);
+        for key in start..=u8::MAX {
+            if !self.holds_live_values(key, now_ms) {
+                continue;
+            }
+            keys.push(key);
+            if keys.len() == count {
+                return Some(((u32::from(key) + 1) % 256, keys));
+            }
+        }
+        Some((0, keys))
+    }
+
+    /// A key picked uniformly from those with values that have not expired,
+    /// with one of its values if `with_value`. Sampling a value only picks
+    /// among keys holding u32s, since a typed key's words are not values.
+    pub fn random_key(&self, with_value: bool) -> Option<(u8, Option<u32>)> {
+        let now_ms = unix_ms();
+        let keys: Vec<u8> = self.map.keys();
+        let keys: Vec<u8> = keys
+            .into_iter()
+            .filter(|&key| !with_value || self.value_type(key) == VALUE_TYPE_U32)
+            .filter(|&key| self.holds_live_values(key, now_ms))
+            .collect();
+        if keys.is_empty() {
+            return None;
+        }
+        let key = keys[random_below(keys.len())];
+        if !with_value {
+            return Some((key, None));
+        }
+        // `None` if the key's last value expired since `now_ms`.
+        let values = self.live_values(key)?;
+        Some((key, Some(values[random_below(values.len())])))
+    }
+
+    /// Every key with the values that have not expired, in key order, so
+    /// two listings of the same data compare equal. Each key is read at its
+    /// own point in time, so with writers on other shards the keys may not
+    /// all be from the same one. Given up between keys, with `None`, once
+    /// `abandoned` says so.
+    pub fn live_entries(&self, abandoned: impl Fn() -> bool) -> Option<Vec<(u8, Vec<u32>)>> {
+        let mut keys: Vec<u8> = self.map.keys();
+        keys.sort_unstable();
+        let mut entries = Vec::with_capacity(keys.len());
+        for key in keys {
+            if abandoned() {
+                return None;
+            }
+            entries.extend(self.live_values(key).map(|values| (key, values)));
+        }
+        Some(entries)
+    }
+
+    /// The keys in `keys` with the values that have not expired, in key
+    /// order.
+    pub fn live_range(&self, keys: RangeInclusive<u8>) -> Vec<(u8, Vec<u32>)> {
+        keys.filter_map(|key| Some((key, self.live_values(key)?)))
+            .collect()
+    }
+
+    /// Holds every write off until dropped, so what is read meanwhile is a
+    /// single point in time between writes. Writers on the other shards
+    /// wait, so hold it only to copy.
+    pub fn hold_writes(&self) -> RwLockWriteGuard<'_, ()> {
+        self.writes.write().unwrap()
+    }
+
+    /// Lets the locks a panic left poisoned be taken again.
+    pub fn clear_poison(&self) {
+        self.writes.clear_poison();
+        if let Some(persistence) = &self.persistence {
+            persistence.clear_poison();
+        }
+    }
+
+    /// Whether one more value for `key` fits within the memory quota and the
+    /// key and value limits. A key at its cap does not grow, so it always
+    /// does.
+    pub fn admits_push(&self, key: u8) -> bool {
+        self.is_full(key) || self.admits_extend(key, 1)
+    }
+
+    /// Whether `count` values appended to `key` one after another would
+    /// each be admitted by [`Database::admits_push`]; a refusal is not
+    /// counted.
+    pub fn admits_pushes(&self, key: u8, count: usize) -> bool {
+        self.is_full(key)
+            || (self.admits(self.extension_bytes(key, count)) && self.admits_growth(key, count))
+    }
+
+    /// Whether `key` is at its cap, so a push drops its oldest value.
+    fn is_full(&self, key: u8) -> bool {
+        self.caps
+            .get(key)
+            .is_some_and(|cap| self.map.get(&key).is_some_and(|values| values.len() >= cap))
+    }
+
+    /// Whether a key and its values, if `key` is new, and `count` more values
+    /// stay within the key and value limits.
+    fn admits_growth(&self, key: u8, count: usize) -> bool {
+        self.admits_counts(i64::from(!self.map.contains_key(&key)), count as i64)
+    }
+
+    fn extension_bytes(&self, key: u8, count: usize) -> u64 {
+        let additional = count as u64 * VALUE_BYTES;
+        if self.map.contains_key(&key) {
+            additional
+        } else {
+            KEY_OVERHEAD_BYTES + additional
+        }
+    }
+
+    /// Whether `count` more words for `key` fit within the memory quota and
+    /// the key and value limits.
+    pub fn admits_extend(&self, key: u8, count: usize) -> bool {
+        if self.admits(self.extension_bytes(key, count)) && self.admits_growth(key, count) {
+            return true;
+        }
+        DatabaseStats::count(&self.stats.quota_rejections);
+        false
+    }
+
+    pub fn push(&self, key: u8, value: u32) {
+        self.push_expiring(key, value, 0);
+    }
+
+    /// Appends `values`, which never expire, finding the key's entry and
+    /// growing it once for all of them.
+    pub fn push_all(&self, key: u8, values: &[u32]) {
+        if values.is_empty() {
+            return;
+        }
+        let mut entry = self.map.entry(key).or_default();
+        if entry.is_empty() {
+            self.mark_present(key, true);
+            self.stats.keys.fetch_add(1, Ordering::Relaxed);
+        }
+        let before = entry.len();
+        self.change(&mut entry, |stored| stored.extend(values));
+        self.pack_grown(&mut entry, before);
+        if let Some(mut deadlines) = self.deadlines.get_mut(&key) {
+            deadlines.resize(entry.len(), 0);
+        }
+        self.stats
+            .values
+            .fetch_add(values.len() as u64, Ordering::Relaxed);
+        self.trim(key, &mut entry);
+        self.stats.note_memory();
+        self.versions[key as usize].fetch_add(values.len() as u64 - 1, Ordering::AcqRel);
+        self.bump_version(key);
+    }
+
+    /// Appends `value`, which expires at `deadline` in Unix milliseconds, or
+    /// never if it is 0.
+    pub fn push_expiring(&self, key: u8, value: u32, deadline: u64) {
+        let mut entry = self.map.entry(key).or_default();
+        if entry.is_empty() {
+            self.mark_present(key, true);
+            self.stats.keys.fetch_add(1, Ordering::Relaxed);
+        }
+        let before = entry.len();
+        self.change(&mut entry, |values| values.push(value));
+        self.pack_grown(&mut entry, before);
+        self.insert_deadline(key, entry.len(), entry.len() - 1, deadline);
+        self.stats.values.fetch_add(1, Ordering::Relaxed);
+        self.trim(key, &mut entry);
+        self.stats.note_memory();
+        self.bump_version(key);
+    }
+
+    /// Records the expiry of the value just inserted at `index`, the key now
+    /// holding `len` values. Call with the key's map entry held.
+    fn insert_deadline(&self, key: u8, len: usize, index: usize, deadline: u64) {
+        match self.deadlines.get_mut(&key) {
+            Some(mut deadlines) => deadlines.insert(index, deadline),
+            None if deadline != 0 => {
+                let mut deadlines = vec![0; len - 1];
+                deadlines.insert(index, deadline);
+                self.deadlines.insert(key, deadlines);
+            }
+            None => {}
+        }
+    }
+
+    /// Applies `change` to a key's values, keeping
+    /// [`DatabaseStats::packed_savings`] up to date.
+    fn change<R>(&self, values: &mut Values, change: impl FnOnce(&mut Values) -> R) -> R {
+        let before = values.packed_savings();
+        let result = change(values);
+        let savings = &self.stats.packed_savings;
+        savings.fetch_add(values.packed_savings(), Ordering::Relaxed);
+        savings.fetch_sub(before, Ordering::Relaxed);
+        result
+    }
+
+    /// Takes what packing saved on values removed from the map off
+    /// [`DatabaseStats::packed_savings`].
+    fn forget_packing(&self, values: &Values) {
+        self.stats
+            .packed_savings
+            .fetch_sub(values.packed_savings(), Ordering::Relaxed);
+    }
+
+    /// Packs a key's values if there are at least [`Database::pack_above`]
+    /// of them.
+    fn pack(&self, values: &mut Values) {
+        if self.pack_above.is_some_and(|n| values.len() >= n) {
+            self.change(values, Values::pack);
+        }
+    }
+
+    /// Packs a key's values that grew from `before` past a multiple of
+    /// [`Database::pack_above`]. Checking only then keeps writes to a long
+    /// key that cannot be packed from scanning it every time.
+    fn pack_grown(&self, values: &mut Values, before: usize) {
+        if self
+            .pack_above
+            .is_some_and(|n| before / n != values.len() / n)
+        {
+            self.pack(values);
+        }
+    }
+
+    /// Drops the oldest values, those at the front, past `key`'s cap.
+    fn trim(&self, key: u8, values: &mut Values) {
+        let cap = self.caps.get(key);
+        let excess = cap.map_or(0, |cap| values.len().saturating_sub(cap));
+        if excess > 0 {
+            self.change(values, |values| values.drain_front(excess));
+        }
+        if let Some(mut deadlines) = self.deadlines.get_mut(&key) {
+            deadlines.drain(..excess);
+        }
+        if excess > 0 {
+            self.stats
+                .values
+                .fetch_sub(excess as u64, Ordering::Relaxed);
+            self.stats
+                .trimmed_values
+                .fetch_add(excess as u64, Ordering::Relaxed);
+            self.notify(Event::Evicted {
+                key,
+                count: excess as u64,
+            });
+        }
+    }
+
+    /// Inserts `value` after any values equal to it, binary-searching for the
+    /// spot; keeps `key` sorted if it already was.
+    pub fn insert_sorted(&self, key: u8, value: u32) {
+        let mut entry = self.map.entry(key).or_default();
+        if entry.is_empty() {
+            self.mark_present(key, true);
+            self.stats.keys.fetch_add(1, Ordering::Relaxed);
+        }
+        let before = entry.len();
+        let index = self.change(&mut entry, |values| match values.last() {
+            Some(last) if last > value => {
+                let values = values.plain();
+                let index = values.partition_point(|existing| *existing <= value);
+                values.insert(index, value);
+                index
+            }
+            _ => {
+                values.push(value);
+                before
+            }
+        });
+        self.pack_grown(&mut entry, before);
+        self.insert_deadline(key, entry.len(), index, 0);
+        self.stats.values.fetch_add(1, Ordering::Relaxed);
+        self.trim(key, &mut entry);
+        self.stats.note_memory();
+        self.bump_version(key);
+    }
+
+    /// Appends the words of typed values to `key`, which then holds
+    /// `value_type`. With no words, only an existing key's type is set.
+    pub fn extend(&self, key: u8, value_type: u8, words: &[u32]) {
+        if words.is_empty() {
+            if self.map.contains_key(&key) {
+                self.set_value_type(key, value_type);
+            }
+            return;
+        }
+        let mut entry = self.map.entry(key).or_default();
+        if entry.is_empty() {
+            self.mark_present(key, true);
+            self.stats.keys.fetch_add(1, Ordering::Relaxed);
+        }
+        let before = entry.len();
+        self.change(&mut entry, |values| values.plain().extend_from_slice(words));
+        self.pack_grown(&mut entry, before);
+        if let Some(mut deadlines) = self.deadlines.get_mut(&key) {
+            deadlines.resize(entry.len(), 0);
+        }
+        self.set_value_type(key, value_type);
+        self.stats
+            .values
+            .fetch_add(words.len() as u64, Ordering::Relaxed);
+        self.stats.note_memory();
+        self.bump_version(key);
+    }
+
+    /// Whether keys holding the given numbers of words fit within the memory
+    /// quota and the key and value limits, on top of the current contents
+    /// or, with `replace`, instead of them.
+    pub fn admits_load(&self, keys: &[(u8, usize)], replace: bool) -> bool {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let (mut bytes, mut key_count, mut value_count) = if replace {
+            (0, 0, 0)
+        } else {
+            (
+                self.stats.memory_bytes(),
+                load(&self.stats.keys),
+                load(&self.stats.values),
+            )
+        };
+        for &(key, count) in keys {
+            if replace || !self.map.contains_key(&key) {
+                bytes += KEY_OVERHEAD_BYTES;
+                key_count += 1;
+            }
+            bytes += count as u64 * VALUE_BYTES;
+            value_count += count as u64;
+        }
+        let within = |limit: Option<u64>, total: u64| limit.is_none_or(|limit| total <= limit);
+        if within(self.max_memory, bytes)
+            && within(self.max_keys, key_count)
+            && within(self.max_values, value_count)
+        {
+            return true;
+        }
+        DatabaseStats::count(&self.stats.quota_rejections);
+        false
+    }
+
+    /// Whether replacing `key`'s values with `len` values fits within the
+    /// memory quota and the key and value limits.
+    pub fn admits_replace(&self, key: u8, len: usize) -> bool {
+        let held = self.map.get(&key).map(|values| values.len());
+        let current = held.map_or(0, |held| KEY_OVERHEAD_BYTES + held as u64 * VALUE_BYTES);
+        let replacement = if len == 0 {
+            0
+        } else {
+            KEY_OVERHEAD_BYTES + len as u64 * VALUE_BYTES
+        };
+        let keys = i64::from(len > 0) - i64::from(held.is_some());
+        let values = len as i64 - held.unwrap_or(0) as i64;
+        if (replacement <= current || self.admits(replacement - current))
+            && self.admits_counts(keys, values)
+        {
+            return true;
+        }
+        DatabaseStats::count(&self.stats.quota_rejections);
+        false
+    }
+
+    /// Replaces `key`'s values if the key is still at version `expected`;
+    /// an empty `values` removes the key. The key holds u32s afterwards.
+    pub fn replace_if(&self, key: u8, expected: u64, values: Vec<u32>) -> bool {
+        let len = values.len() as u64;
+        let packed = |values: Vec<u32>| {
+            let mut values = Values::from(values);
+            self.pack(&mut values);
+            values
+        };
+        let previous = match self.map.entry(key) {
+            Entry::Occupied(mut entry) if self.version(key) == expected => {
+                self.bump_version(key);
+                self.set_value_type(key, VALUE_TYPE_U32);
+                self.deadlines.remove(&key);
+                if values.is_empty() {
+                    self.forget(key);
+                    self.mark_present(key, false);
+                    entry.remove()
+                } else {
+                    std::mem::replace(entry.get_mut(), packed(values))
+                }
+            }
+            Entry::Vacant(entry) if self.version(key) == expected => {
+                self.bump_version(key);
+                if !values.is_empty() {
+                    self.mark_present(key, true);
+                    entry.insert(packed(values));
+                }
+                Values::default()
+            }
+            _ => return false,
+        };
+        self.forget_packing(&previous);
+        match (previous.is_empty(), len == 0) {
+            (true, false) => {
+                self.stats.keys.fetch_add(1, Ordering::Relaxed);
+            }
+            (false, true) => {
+                self.stats.keys.fetch_sub(1, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+        self.stats.values.fetch_add(len, Ordering::Relaxed);
+        self.stats
+            .values
+            .fetch_sub(previous.len() as u64, Ordering::Relaxed);
+        self.stats.note_memory();
+        true
+    }
+
+    pub fn replace(&self, key: u8, values: Vec<u32>) {
+        self.replace_if(key, self.version(key), values);
+    }
+
+    /// Records `mutation` durably before it is applied; see [`Persistence::log`].
+    pub fn log(&self, mutation: Mutation) -> Result<WriteGuard<'_>, u8> {
+        let writes = self.writes.read().unwrap();
+        let wal = match &self.persistence {
+            Some(persistence) => persistence.log(&mutation)?,
+            None => None,
+        };
+        Ok(WriteGuard {
+            wal,
+            _writes: writes,
+        })
+    }
+
+    /// Logs and applies `mutations` in order under one WAL lock, so no other
+    /// write lands between them. Stops at the first that cannot be logged,
+    /// returning the status to answer with.
+    pub fn log_all(&self, mutations: &[Mutation]) -> Result<(), u8> {
+        let mut wal = self.lock_wal()?;
+        for mutation in mutations {
+            if let Some(persistence) = &self.persistence {
+                persistence.append(&mut wal.wal, mutation)?;
+            }
+            mutation.apply(self);
+        }
+        Ok(())
+    }
+
+    /// Serializes a conditional write against every other mutation; see
+    /// [`Persistence::lock`].
+    pub fn lock_wal(&self) -> Result<WriteGuard<'_>, u8> {
+        let writes = self.writes.read().unwrap();
+        let wal = match &self.persistence {
+            Some(persistence) => persistence.lock()?,
+            None => None,
+        };
+        Ok(WriteGuard {
+            wal,
+            _writes: writes,
+        })
+    }
+
+    pub fn append(&self, guard: &mut WriteGuard<'_>, mutation: &Mutation) -> Result<(), u8> {
+        match &self.persistence {
+            Some(persistence) => persistence.append(&mut guard.wal, mutation),
+            None => Ok(()),
+        }
+    }
+
+    pub fn remove(&self, key: u8) -> Option<Values> {
+        let (_, values) = self.map.remove(&key)?;
+        self.recheck_present(key);
+        self.forget_packing(&values);
+        self.deadlines.remove(&key);
+        self.bump_version(key);
+        self.forget(key);
+        self.stats.keys.fetch_sub(1, Ordering::Relaxed);
+        self.stats
+            .values
+            .fetch_sub(values.len() as u64, Ordering::Relaxed);
+        Some(values)
+    }
+
+    /// Moves `from`'s values, with their type, expiries and tag, to `to`,
+    /// replacing `to`'s values or, with `merge`, appending to them, when a
+    /// tag `to` already has is kept. Returns whether `from` existed. Caps
+    /// are not applied; the next SET trims.
+    pub fn rename(&self, from: u8, to: u8, merge: bool) -> bool {
+        if from == to {
+            return self.map.contains_key(&from);
+        }
+        let (value_type, tag) = (self.value_type(from), self.tag(from));
+        let deadlines = self.deadlines.get(&from).map(|deadlines| deadlines.clone());
+        let Some(values) = self.remove(from) else {
+            return false;
+        };
+        self.place(to, values, deadlines, value_type, tag, merge);
+        true
+    }
+
+    /// The distinct unexpired values of `a` and `b` that `comparison`
+    /// selects, each once in the order it was first stored; a key that does
+    /// not exist holds none. NOT_FOUND if neither key exists, CONFLICT if
+    /// either is typed.
+    pub fn compare(&self, a: u8, b: u8, comparison: Comparison) -> Result<Vec<u32>, u8> {
+        let (first, second) = (self.live_values(a), self.live_values(b));
+        if first.is_none() && second.is_none() {
+            return Err(STATUS_NOT_FOUND);
+        }
+        let typed = |key, values: &Option<Vec<u32>>| {
+            values.is_some() && self.value_type(key) != VALUE_TYPE_U32
+        };
+        if typed(a, &first) || typed(b, &second) {
+            return Err(STATUS_CONFLICT);
+        }
+        let (kept, other) = match comparison {
+            Comparison::OnlySecond => (second, first),
+            Comparison::OnlyFirst | Comparison::Both => (first, second),
+        };
+        let other: HashSet<u32> = other.unwrap_or_default().into_iter().collect();
+        let mut seen = HashSet::new();
+        let mut values = kept.unwrap_or_default();
+        values.retain(|value| {
+            other.contains(value) == (comparison == Comparison::Both) && seen.insert(*value)
+        });
+        Ok(values)
+    }
+
+    /// Exchanges `a`'s and `b`'s values, with their types, expiries and tags;
+    /// a key that does not exist trades places with one that does. Each key
+    /// takes its new values in one step, so no read of either sees them
+    /// half-written. Returns whether either key existed.
+    pub fn swap(&self, a: u8, b: u8) -> bool {
+        if !self.map.contains_key(&a) && !self.map.contains_key(&b) {
+            return false;
+        }
+        if a == b {
+            return true;
+        }
+        let from_a = match self.map.get(&b).map(|values| values.clone()) {
+            Some(values) => self.map.insert(a, values),
+            None => self.map.remove(&a).map(|(_, values)| values),
+        };
+        match from_a {
+            Some(values) => {
+                self.map.insert(b, values);
+            }
+            None => {
+                self.map.remove(&b);
+            }
+        }
+        self.recheck_present(a);
+        self.recheck_present(b);
+        let deadlines = (self.deadlines.remove(&a), self.deadlines.remove(&b));
+        if let Some((_, deadlines)) = deadlines.1 {
+            self.deadlines.insert(a, deadlines);
+        }
+        if let Some((_, deadlines)) = deadlines.0 {
+            self.deadlines.insert(b, deadlines);
+        }
+        let (type_a, tag_a) = (self.value_type(a), self.tag(a));
+        self.set_value_type(a, self.value_type(b));
+        self.set_value_type(b, type_a);
+        self.tags[a as usize].store(self.tag(b), Ordering::Release);
+        self.tags[b as usize].store(tag_a, Ordering::Release);
+        self.bump_version(a);
+        self.bump_version(b);
+        true
+    }
+
+    /// Copies `from`'s values, with their type, expiries and tag, to `to` as
+    /// [`Database::rename`] moves them, leaving `from` as it is.
+    pub fn copy(&self, from: u8, to: u8, append: bool) -> bool {
+        let (value_type, tag) = (self.value_type(from), self.tag(from));
+        let deadlines = self.deadlines.get(&from).map(|deadlines| deadlines.clone());
+        let Some(values) = self.map.get(&from).map(|values| values.clone()) else {
+            return false;
+        };
+        self.place(to, values, deadlines, value_type, tag, append);
+        true
+    }
+
+    /// Stores `values` at `to`, replacing its values or, with `append`,
+    /// after them.
+    fn place(
+        &self,
+        to: u8,
+        values: Values,
+        deadlines: Option<Vec<u64>>,
+        value_type: u8,
+        tag: u8,
+        append: bool,
+    ) {
+        if !append {
+            self.remove(to);
+        }
+        let len = values.len();
+        let mut entry = self.map.entry(to).or_default();
+        if entry.is_empty() {
+            self.mark_present(to, true);
+            self.stats.keys.fetch_add(1, Ordering::Relaxed);
+        }
+        let before = entry.len();
+        self.change(&mut entry, |existing| match existing.is_empty() {
+            true => *existing = values,
+            false => existing.plain().extend(values.iter()),
+        });
+        self.pack_grown(&mut entry, before);
+        match (self.deadlines.get_mut(&to), deadlines) {
+            (Some(mut existing), moved) => {
+                existing.extend(moved.unwrap_or_else(|| vec![0; len]));
+            }
+            (None, Some(moved)) => {
+                let mut all = vec![0; before];
+                all.extend(moved);
+                self.deadlines.insert(to, all);
+            }
+            (None, None) => {}
+        }
+        drop(entry);
+        self.set_value_type(to, value_type);
+        if self.tag(to) == 0 {
+            self.tags[to as usize].store(tag, Ordering::Release);
+        }
+        self.stats.values.fetch_add(len as u64, Ordering::Relaxed);
+        self.stats.note_memory();
+        self.bump_version(to);
+    }
+
+    /// Where the value at `index` among `key`'s values live at `at_ms` sits
+    /// in its vector, if there is one.
+    fn live_position(&self, key: u8, index: u32, at_ms: u64, len: usize) -> Option<usize> {
+        let position = match self.deadlines.get(&key) {
+            None => index as usize,
+            Some(deadlines) => {
+                deadlines
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, deadline)| !expired(**deadline, at_ms))
+                    .nth(index as usize)?
+                    .0
+            }
+        };
+        (position < len).then_some(position)
+    }
+
+    /// Whether `key` has a value at `index` among those live at `at_ms`.
+    pub fn has_live_at(&self, key: u8, index: u32, at_ms: u64) -> bool {
+        let Some(values) = self.map.get(&key) else {
+            return false;
+        };
+        self.live_position(key, index, at_ms, values.len())
+            .is_some()
+    }
+
+    /// Removes the value at `index` among `key`'s values that are live at
+    /// `at_ms`, returning it. A key left with no values is removed.
+    pub fn remove_at(&self, key: u8, index: u32, at_ms: u64) -> Option<u32> {
+        let Entry::Occupied(mut entry) = self.map.entry(key) else {
+            return None;
+        };
+        let position = self.live_position(key, index, at_ms, entry.get().len())?;
+        let value = self.change(entry.get_mut(), |values| values.plain().remove(position));
+        if let Some(mut deadlines) = self.deadlines.get_mut(&key) {
+            deadlines.remove(position);
+            let expiring = deadlines.iter().any(|&deadline| deadline != 0);
+            drop(deadlines);
+            if !expiring {
+                self.deadlines.remove(&key);
+            }
+        }
+        if entry.get().is_empty() {
+            self.mark_present(key, false);
+            entry.remove();
+            self.forget(key);
+            self.stats.keys.fetch_sub(1, Ordering::Relaxed);
+        }
+        self.bump_version(key);
+        self.stats.values.fetch_sub(1, Ordering::Relaxed);
+        self.stats.note_memory();
+        Some(value)
+    }
+
+    /// Removes `key`'s duplicate values as [`dedup`] does, returning how
+    /// many were removed.
+    pub fn dedup(&self, key: u8, at_ms: u64) -> usize {
+        let Some(mut values) = self.map.get_mut(&key) else {
+            return 0;
+        };
+        let mut deadlines = self.deadlines.get_mut(&key);
+        let removed = self.change(&mut values, |values| {
+            dedup(values.plain(), deadlines.as_deref_mut(), at_ms)
+        });
+        self.pack(&mut values);
+        drop(deadlines);
+        drop(values);
+        if removed > 0 {
+            self.bump_version(key);
+            self.stats
+                .values
+                .fetch_sub(removed as u64, Ordering::Relaxed);
+            self.stats.note_memory();
+        }
+        removed
+    }
+
+    /// Sorts `key`'s values as [`sort`] does. Returns whether the key exists.
+    pub fn sort(&self, key: u8, descending: bool) -> bool {
+        let Some(mut values) = self.map.get_mut(&key) else {
+            return false;
+        };
+        let mut deadlines = self.deadlines.get_mut(&key);
+        self.change(&mut values, |values| {
+            sort(values.plain(), deadlines.as_deref_mut(), descending)
+        });
+        self.pack(&mut values);
+        drop(deadlines);
+        drop(values);
+        self.bump_version(key);
+        true
+    }
+
+    /// Clears the expiry of `key`'s values, first dropping those that had
+    /// expired by `at_ms`. Returns whether any values were left.
+    pub fn persist(&self, key: u8, at_ms: u64) -> bool {
+        self.rewrite_deadlines(key, at_ms, |_| 0)
+    }
+
+    /// Moves the expiry of `key`'s expiring values to `deadline`, first
+    /// dropping those that had expired by `at_ms`; values that never expire
+    /// are left that way. Returns whether any values were left.
+    pub fn refresh_expiry(&self, key: u8, deadline: u64, at_ms: u64) -> bool {
+        self.rewrite_deadlines(key, at_ms, |old| match old {
+            0 => 0,
+            _ => deadline,
+        })
+    }
+
+    /// Drops `key`'s values expired by `at_ms` and gives the rest the expiry
+    /// `new` makes of theirs.
+    fn rewrite_deadlines(&self, key: u8, at_ms: u64, new: impl Fn(u64) -> u64) -> bool {
+        let Entry::Occupied(mut entry) = self.map.entry(key) else {
+            return false;
+        };
+        let Some((_, deadlines)) = self.deadlines.remove(&key) else {
+            return true;
+        };
+        let (kept, deadlines): (Vec<u32>, Vec<u64>) = entry
+            .get()
+            .iter()
+            .zip(&deadlines)
+            .filter(|(_, deadline)| !expired(**deadline, at_ms))
+            .map(|(value, deadline)| (value, new(*deadline)))
+            .unzip();
+        let removed = (entry.get().len() - kept.len()) as u64;
+        let left = !kept.is_empty();
+        if left {
+            self.change(entry.get_mut(), |values| *values = kept.into());
+            if deadlines.iter().any(|&deadline| deadline != 0) {
+                self.deadlines.insert(key, deadlines);
+            }
+        } else {
+            self.mark_present(key, false);
+            self.forget_packing(&entry.remove());
+            self.forget(key);
+            self.stats.keys.fetch_sub(1, Ordering::Relaxed);
+        }
+        self.bump_version(key);
+        self.stats.values.fetch_sub(removed, Ordering::Relaxed);
+        self.stats
+            .expired_values
+            .fetch_add(removed, Ordering::Relaxed);
+        if removed > 0 {
+            self.notify(Event::Expired {
+                key,
+                count: removed,
+            });
+        }
+        self.stats.note_memory();
+        left
+    }
+
+    pub fn clear(&self) {
+        let mut keys = 0;
+        let mut values = 0;
+        self.map.retain(|&key, v| {
+            self.mark_present(key, false);
+            self.bump_version(key);
+            self.forget(key);
+            keys += 1;
+            values += v.len() as u64;
+            self.forget_packing(v);
+            false
+        });
+        self.deadlines.clear();
+        self.stats.keys.fetch_sub(keys, Ordering::Relaxed);
+        self.stats.values.fetch_sub(values, Ordering::Relaxed);
+        self.notify(Event::DeleteAll);
+    }
+
+    /// The approximate bytes `key` takes: its map entry, its values and
+    /// their expiries, expired values included until they are swept.
+    pub fn memory_usage(&self, key: u8) -> Option<u64> {
+        let values = self.map.get(&key)?;
+        let deadlines = self.deadlines.get(&key).map_or(0, |deadlines| {
+            DEADLINES_OVERHEAD_BYTES + (deadlines.capacity() * std::mem::size_of::<u64>()) as u64
+        });
+        Some(KEY_OVERHEAD_BYTES + values.heap_bytes() + deadlines)
+    }
+
+    /// [`Database::memory_usage`] summed over every key.
+    pub fn total_memory_usage(&self) -> u64 {
+        let keys: Vec<u8> = self.map.keys();
+        keys.into_iter()
+            .filter_map(|key| self.memory_usage(key))
+            .sum()
+    }
+
+    /// Every key's value expiries, index for index with
+    /// [`Database::map`], for the keys holding expiring values.
+    pub fn copy_deadlines(&self) -> Vec<(u8, Vec<u64>)> {
+        self.deadlines
+            .iter()
+            .map(|entry| (*entry.key(), entry.value().clone()))
+            .collect()
+    }
+
+    /// Folds `key`'s timed values taken before `before_ms` into summaries
+    /// as [`timed::roll_up`] does, returning how many were folded. Keys of
+    /// other types are left as they are.
+    pub fn roll_up(&self, key: u8, before_ms: u64, window_ms: u64, keep: usize) -> u64 {
+        let Some(mut values) = self.map.get_mut(&key) else {
+            return 0;
+        };
+        if self.value_type(key) != VALUE_TYPE_TIMED {
+            return 0;
+        }
+        let words = values.to_vec();
+        let Some((folded, rolled)) = timed::roll_up(&words, before_ms, window_ms, keep) else {
+            return 0;
+        };
+        let len = rolled.len() as u64;
+        self.change(&mut values, |values| *values = rolled.into());
+        drop(values);
+        self.stats.values.fetch_add(len, Ordering::Relaxed);
+        self.stats
+            .values
+            .fetch_sub(words.len() as u64, Ordering::Relaxed);
+        self.stats
+            .rolled_up_values
+            .fetch_add(folded, Ordering::Relaxed);
+        self.stats.note_memory();
+        self.bump_version(key);
+        folded
+    }
+
+    /// Rolls up every timed key a rollup rule covers, logging each rollup
+    /// under `wal` first. Stops at the first that cannot be logged.
+    pub fn roll_up_due(&self, wal: &mut WriteGuard<'_>) {
+        if self.rollups.is_empty() {
+            return;
+        }
+        let now_ms = unix_ms();
+        let keys: Vec<u8> = self.map.keys();
+        for key in keys {
+            let Some(rollup) = self.rollups.get(key) else {
+                continue;
+            };
+            let before_ms = now_ms.saturating_sub(rollup.window_ms);
+            let due = self.value_type(key) == VALUE_TYPE_TIMED
+                && self.map.get(&key).is_some_and(|values| {
+                    let words = values.to_vec();
+                    timed::roll_up(&words, before_ms, rollup.window_ms, rollup.keep).is_some()
+                });
+            if !due {
+                continue;
+            }
+            let mutation = Mutation::RollUp {
+                key,
+                before_ms,
+                window_ms: rollup.window_ms,
+                keep: rollup.keep as u32,
+            };
+            if self.append(wal, &mutation).is_err() {
+                return;
+            }
+            mutation.apply(self);
+        }
+    }
+
+    /// Removes the values whose expiry has passed, returning how many went.
+    /// A key left with no values is removed.
+    pub fn sweep_expired(&self) -> u64 {
+        let now_ms = unix_ms();
+        let keys: Vec<u8> = self.deadlines.iter().map(|entry| *entry.key()).collect();
+        let mut swept = 0;
+        for key in keys {
+            let Entry::Occupied(mut entry) = self.map.entry(key) else {
+                continue;
+            };
+            let Some(mut deadlines) = self.deadlines.get_mut(&key) else {
+                continue;
+            };
+            let (kept, kept_deadlines): (Vec<u32>, Vec<u64>) = entry
+                .get()
+                .iter()
+                .zip(deadlines.iter())
+                .filter(|(_, deadline)| !expired(**deadline, now_ms))
+                .unzip();
+            let removed = (entry.get().len() - kept.len()) as u64;
+            if removed == 0 {
+                continue;
+            }
+            let expiring = kept_deadlines.iter().any(|&deadline| deadline != 0);
+            *deadlines = kept_deadlines;
+            drop(deadlines);
+            if !expiring {
+                self.deadlines.remove(&key);
+            }
+            if kept.is_empty() {
+                self.mark_present(key, false);
+                self.forget_packing(&entry.remove());
+                self.forget(key);
+                self.stats.keys.fetch_sub(1, Ordering::Relaxed);
+            } else {
+                self.change(entry.get_mut(), |values| *values = kept.into());
+            }
+            self.bump_version(key);
+            self.stats.values.fetch_sub(removed, Ordering::Relaxed);
+            self.notify(Event::Expired {
+                key,
+                count: removed,
+            });
+            swept += removed;
+        }
+        self.stats
+            .expired_values
+            .fetch_add(swept, Ordering::Relaxed);
+        swept
+    }
+}
+
+/// Purges expired values from every database each `interval`, and rolls up
+/// timed keys. A sweep holds the WAL lock so it never lands in the middle
+/// of a snapshot, and is skipped while persistence refuses writes.
+pub async fn sweep(databases: Vec<Arc<Database>>, interval: Duration) {
+    let mut tick = tokio::time::interval(interval);
+    loop {
+        tick.tick().await;
+        for database in &databases {
+            if let Ok(mut wal) = database.lock_wal() {
+                database.sweep_expired();
+                database.roll_up_due(&mut wal);
+            }
+        }
+    }
+}
@@ -0,0 +1,106 @@
+pub use map8x32_protocol::codec::DeadLetter;
+use map8x32_protocol::codec::Request;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A bounded buffer of recently rejected writes; the oldest entry is
+/// discarded once `capacity` is reached. A capacity of zero disables capture.
+#[derive(Debug, Default)]
+pub struct DeadLetters {
+    capacity: usize,
+    entries: Mutex<VecDeque<DeadLetter>>,
+}
+
+impl DeadLetters {
+    pub fn new(capacity: usize) -> Self {
+        DeadLetters {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn record(&self, op: u8, key: u8, value: u32, reason: u8) {
+        if self.capacity == 0 {
+            return;
+        }
+        let at_unix_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(DeadLetter {
+            at_unix_ms,
+            op,
+            key,
+            value,
+            reason,
+        });
+    }
+
+    /// Records `request` if it is a write, for one refused with `reason`
+    /// before it reached the engine: throttled, too large, or not allowed
+    /// on its connection.
+    pub fn record_refused(&self, request: &Request, reason: u8) {
+        if !request.writes() {
+            return;
+        }
+        let value = match *request {
+            Request::Set { value, .. }
+            | Request::SetSorted { value, .. }
+            | Request::SetExpiring { value, .. }
+            | Request::SetTimed { value, .. } => value,
+            Request::Tag { tag, .. } => tag.into(),
+            Request::Swap { b, .. } => b.into(),
+            Request::DeleteIf { expected, .. } => expected,
+            Request::DeleteAt { index, .. } => index,
+            Request::Touch { ttl_ms, .. } => ttl_ms,
+            Request::Push { ref values, .. } => values.len() as u32,
+            _ => 0,
+        };
+        self.record(request.op(), request.key().unwrap_or(0), value, reason);
+    }
+
+    pub fn snapshot(&self, drain: bool) -> Vec<DeadLetter> {
+        let mut entries = self.entries.lock().unwrap();
+        if drain {
+            entries.drain(..).collect()
+        } else {
+            entries.iter().copied().collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use map8x32_protocol::{OP_SET, OP_SWAP, STATUS_READONLY, STATUS_THROTTLED};
+
+    #[test]
+    fn keeps_the_newest_refused_writes() {
+        let letters = DeadLetters::new(2);
+        letters.record_refused(&Request::Get { key: 1 }, STATUS_THROTTLED);
+        letters.record_refused(&Request::Set { key: 1, value: 5 }, STATUS_THROTTLED);
+        letters.record_refused(&Request::Swap { a: 2, b: 3 }, STATUS_READONLY);
+        letters.record(OP_SET, 4, 6, STATUS_READONLY);
+        let kept: Vec<_> = letters
+            .snapshot(true)
+            .iter()
+            .map(|letter| (letter.op, letter.key, letter.value, letter.reason))
+            .collect();
+        assert_eq!(
+            kept,
+            [
+                (OP_SWAP, 2, 3, STATUS_READONLY),
+                (OP_SET, 4, 6, STATUS_READONLY)
+            ]
+        );
+        assert!(letters.snapshot(false).is_empty(), "drained");
+
+        let disabled = DeadLetters::new(0);
+        disabled.record(OP_SET, 1, 1, STATUS_READONLY);
+        assert!(disabled.snapshot(false).is_empty());
+    }
+}
@@ -0,0 +1,601 @@
+use crate::command::{command_processor, Command, End};
+use crate::recorder::Recorder;
+use crate::StorageType;
+use map8x32_protocol::*;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::{broadcast, mpsc};
+
+impl Command {
+    /// The opcode of the request the command runs.
+    pub fn op(&self) -> u8 {
+        match self {
+            Command::Set { mode, .. } => mode.op(),
+            Command::Get { .. } | Command::GetEncoded { .. } => OP_GET,
+            Command::GetSortedRange { .. } => OP_GET_SORTED_RANGE,
+            Command::GetFilter { .. } => OP_GET_FILTER,
+            Command::Compare { .. } => OP_COMPARE,
+            Command::Aggregate { aggregate, .. } => aggregate.op(),
+            Command::GetEnd {
+                end: End::Oldest, ..
+            } => OP_GET_FIRST_N,
+            Command::GetEnd {
+                end: End::Newest, ..
+            } => OP_GET_LAST_N,
+            Command::DeleteByKey { .. } => OP_DELETE_BY_KEY,
+            Command::DeleteIf { .. } => OP_DELETE_IF,
+            Command::Ttl { .. } => OP_TTL,
+            Command::MemoryUsage { .. } => OP_MEMUSAGE,
+            Command::KeyStats { .. } => OP_KEYSTATS,
+            Command::Persist { .. } => OP_PERSIST,
+            Command::Touch { .. } => OP_TOUCH,
+            Command::DeleteAt { .. } => OP_DELETE_AT,
+            Command::Dedup { .. } => OP_DEDUP,
+            Command::Push { .. } => OP_PUSH,
+            Command::GetDel { .. } => OP_GETDEL,
+            Command::GetSet { .. } => OP_GETSET,
+            Command::Sort { .. } => OP_SORT,
+            Command::DeleteAll { .. } => OP_DELETE_ALL,
+            Command::Rename { .. } => OP_RENAME,
+            Command::Swap { .. } => OP_SWAP,
+            Command::Copy { .. } => OP_COPY,
+            Command::ListAll { .. } => OP_LIST_ALL,
+            Command::ListRange { .. } => OP_LIST_RANGE,
+            Command::Tag { .. } => OP_TAG,
+            Command::ListTagged { .. } => OP_LIST_TAGGED,
+            Command::DeleteTagged { .. } => OP_DELETE_TAGGED,
+            Command::DeleteMany { .. } => OP_DELETE_MANY,
+            Command::RandomKey { .. } => OP_RANDOM_KEY,
+            Command::Scan { .. } => OP_SCAN,
+            Command::AwaitSeq { .. } => OP_AWAIT_SEQ,
+            Command::DeadLetters { .. } => OP_DEAD_LETTERS,
+            Command::AllocRegister { .. } => OP_ALLOC_REGISTER,
+            Command::AllocRelease { .. } => OP_ALLOC_RELEASE,
+            Command::AllocList { .. } => OP_ALLOC_LIST,
+            Command::GetVersioned { .. } => OP_GET_VERSIONED,
+            Command::ReplaceIf { .. } => OP_REPLACE_IF,
+            Command::RestoreKey { .. } => OP_RESTORE_KEY,
+            Command::Info { .. } => OP_INFO,
+            Command::SyncFull { .. } => OP_SYNC_FULL,
+            Command::SetTyped {
+                value_type: VALUE_TYPE_TIMED,
+                ..
+            } => OP_SET_TIMED,
+            Command::SetTyped { .. } => OP_SET_TYPED,
+            Command::GetTyped { .. } => OP_GET_TYPED,
+            Command::Dump { .. } => OP_DUMP,
+            Command::Restore { .. } => OP_RESTORE,
+        }
+    }
+
+    fn shard_key(&self) -> Option<u8> {
+        match self {
+            Command::Set { key, .. }
+            | Command::Get { key, .. }
+            | Command::GetEncoded { key, .. }
+            | Command::GetSortedRange { key, .. }
+            | Command::GetFilter { key, .. }
+            | Command::Aggregate { key, .. }
+            | Command::GetEnd { key, .. }
+            | Command::DeleteByKey { key, .. }
+            | Command::Tag { key, .. }
+            | Command::DeleteAt { key, .. }
+            | Command::Dedup { key, .. }
+            | Command::Push { key, .. }
+            | Command::GetDel { key, .. }
+            | Command::GetSet { key, .. }
+            | Command::Sort { key, .. }
+            | Command::DeleteIf { key, .. }
+            | Command::Ttl { key, .. }
+            | Command::Persist { key, .. }
+            | Command::Touch { key, .. }
+            | Command::GetVersioned { key, .. }
+            | Command::ReplaceIf { key, .. }
+            | Command::RestoreKey { key, .. }
+            | Command::SetTyped { key, .. }
+            | Command::GetTyped { key, .. }
+            | Command::KeyStats { key, .. } => Some(*key),
+            Command::MemoryUsage { key, .. } => *key,
+            Command::DeleteAll { .. }
+            | Command::Rename { .. }
+            | Command::Swap { .. }
+            | Command::Compare { .. }
+            | Command::Copy { .. }
+            | Command::ListAll { .. }
+            | Command::ListRange { .. }
+            | Command::ListTagged { .. }
+            | Command::DeleteTagged { .. }
+            | Command::DeleteMany { .. }
+            | Command::RandomKey { .. }
+            | Command::Scan { .. }
+            | Command::AwaitSeq { .. }
+            | Command::DeadLetters { .. }
+            | Command::AllocRegister { .. }
+            | Command::AllocRelease { .. }
+            | Command::AllocList { .. }
+            | Command::Info { .. }
+            | Command::SyncFull { .. }
+            | Command::Dump { .. }
+            | Command::Restore { .. } => None,
+        }
+    }
+}
+
+/// Which commands a command processor takes first: those of the highest
+/// class with any waiting.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// Bulk and background work that can wait.
+    Low = 0,
+    #[default]
+    Normal = 1,
+    /// Latency-sensitive traffic, typically reads.
+    High = 2,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 3] = [Priority::Low, Priority::Normal, Priority::High];
+}
+
+/// Where a traced request's phases began and ended, filled in as it goes.
+#[derive(Debug)]
+pub struct Phases {
+    pub op: u8,
+    pub key: Option<u8>,
+    pub arrived: Instant,
+    pub decoded: Instant,
+    /// When a command processor first took one of the request's commands,
+    /// and when the last of them finished. Requests the connection answers
+    /// itself never set it.
+    pub processed: Mutex<Option<(Instant, Instant)>>,
+}
+
+impl Phases {
+    /// Notes that a command processor ran one of the request's commands
+    /// from `taken` until now.
+    pub fn processed(&self, taken: Instant) {
+        let now = Instant::now();
+        let mut processed = self.processed.lock().unwrap();
+        *processed = Some(match *processed {
+            Some((first, last)) => (first.min(taken), last.max(now)),
+            None => (taken, now),
+        });
+    }
+}
+
+/// The connection a command came from, 0 for none, and the priority class
+/// it was sent at.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lane {
+    pub connection: u64,
+    pub priority: Priority,
+}
+
+/// When a command was sent, and the phases of the traced request it runs
+/// for, if it is one.
+#[derive(Debug)]
+pub struct Sent {
+    pub at: Instant,
+    pub traced: Option<Arc<Phases>>,
+    /// When a read must be answered by, under the command timeout.
+    pub deadline: Option<Instant>,
+}
+
+/// Commands on their way to a command processor, each with its lane and
+/// when it was sent.
+pub type LaneSender = mpsc::UnboundedSender<(Lane, Sent, Command)>;
+pub type LaneReceiver = mpsc::UnboundedReceiver<(Lane, Sent, Command)>;
+
+/// The commands waiting on one command processor, queued by the connection
+/// that sent them. Connections of the highest class with commands waiting
+/// take turns, one command each, so one that queues a run of LIST_ALLs
+/// holds the others up by at most one command; lower classes wait until
+/// no higher one has anything queued.
+#[derive(Debug, Default)]
+pub struct Lanes {
+    /// Each connection's waiting commands, with the class it queued them at.
+    queues: HashMap<u64, (Priority, VecDeque<(Sent, Command)>)>,
+    /// Connections with commands waiting, by class, in the order of their
+    /// next turn.
+    turns: [VecDeque<u64>; Priority::ALL.len()],
+}
+
+impl Lanes {
+    fn push(&mut self, lane: Lane, sent: Sent, command: Command) {
+        let (priority, queue) = self
+            .queues
+            .entry(lane.connection)
+            .or_insert_with(|| (lane.priority, VecDeque::new()));
+        if queue.is_empty() {
+            self.turns[*priority as usize].push_back(lane.connection);
+        }
+        queue.push_back((sent, command));
+    }
+
+    fn pop(&mut self) -> Option<(Sent, Command)> {
+        let turns = self
+            .turns
+            .iter_mut()
+            .rev()
+            .find(|turns| !turns.is_empty())?;
+        let connection = turns.pop_front()?;
+        let (_, queue) = self.queues.get_mut(&connection)?;
+        let command = queue.pop_front();
+        if queue.is_empty() {
+            self.queues.remove(&connection);
+        } else {
+            turns.push_back(connection);
+        }
+        command
+    }
+
+    /// The command [`Lanes::next`] would return now, if `takes` accepts it,
+    /// without waiting for one to be sent.
+    pub fn next_if(
+        &mut self,
+        receiver: &mut LaneReceiver,
+        takes: impl FnOnce(&Command) -> bool,
+    ) -> Option<(Sent, Command)> {
+        while let Ok((lane, sent, command)) = receiver.try_recv() {
+            self.push(lane, sent, command);
+        }
+        let turns = self.turns.iter().rev().find(|turns| !turns.is_empty())?;
+        let (_, queue) = self.queues.get(turns.front()?)?;
+        let (_, command) = queue.front()?;
+        if !takes(command) {
+            return None;
+        }
+        self.pop()
+    }
+
+    /// The next command to run and when it was sent, taking in everything
+    /// already sent first so it queues behind its own connection's
+    /// commands; `None` once every sender is gone and nothing waits.
+    ///
+    /// With commands still waiting it yields first, so connections sharing
+    /// the processor's thread get to send theirs; otherwise one sent while
+    /// a run of LIST_ALLs drains would not be seen until it had.
+    pub async fn next(&mut self, receiver: &mut LaneReceiver) -> Option<(Sent, Command)> {
+        if !self.queues.is_empty() {
+            tokio::task::yield_now().await;
+        }
+        loop {
+            while let Ok((lane, sent, command)) = receiver.try_recv() {
+                self.push(lane, sent, command);
+            }
+            if let Some(queued) = self.pop() {
+                return Some(queued);
+            }
+            let (lane, sent, command) = receiver.recv().await?;
+            self.push(lane, sent, command);
+        }
+    }
+}
+
+/// Routes commands to key-sharded command processors.
+///
+/// Each database has its own processors; a dispatcher sends to the selected
+/// one, database 0 until [`Dispatcher::select`] picks another. Commands for
+/// the same key always land on the same shard, and a connection's commands
+/// for it are applied in the order it sent them, taking turns with other
+/// connections' of the same priority class. Commands that span the keyspace
+/// run on shard 0 against the shared storage.
+#[derive(Debug, Clone)]
+pub struct Dispatcher {
+    databases: Arc<Vec<Vec<LaneSender>>>,
+    storage: Arc<Vec<StorageType>>,
+    selected: u8,
+    /// The connection commands are queued for, and at which class.
+    lane: Lane,
+    recorder: Option<Arc<Recorder>>,
+    traced: Option<Arc<Phases>>,
+    /// How long a read may take to be answered.
+    timeout: Option<Duration>,
+}
+
+impl Dispatcher {
+    pub fn spawn(shard_count: usize, databases: &[StorageType]) -> Self {
+        let storage = Arc::new(databases.to_vec());
+        let databases = databases
+            .iter()
+            .map(|storage| {
+                (0..shard_count.max(1))
+                    .map(|_| {
+                        let (sender, receiver) = mpsc::unbounded_channel();
+                        tokio::spawn(command_processor(receiver, storage.clone()));
+                        sender
+                    })
+                    .collect()
+            })
+            .collect();
+        Dispatcher {
+            databases: Arc::new(databases),
+            storage,
+            selected: 0,
+            lane: Lane::default(),
+            recorder: None,
+            traced: None,
+            timeout: None,
+        }
+    }
+
+    /// A dispatcher for database `db`, if the server has it.
+    pub fn select(&self, db: u8) -> Option<Dispatcher> {
+        (usize::from(db) < self.databases.len()).then(|| Dispatcher {
+            selected: db,
+            ..self.clone()
+        })
+    }
+
+    pub fn selected(&self) -> u8 {
+        self.selected
+    }
+
+    /// Every database's storage, not just the selected one's.
+    pub fn databases(&self) -> &[StorageType] {
+        &self.storage
+    }
+
+    /// Every key of the selected database whose values change from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<u8> {
+        self.storage().subscribe()
+    }
+
+    /// A dispatcher queueing commands as connection `id`'s, at `priority`.
+    pub fn for_connection(&self, id: u64, priority: Priority) -> Dispatcher {
+        Dispatcher {
+            lane: Lane {
+                connection: id,
+                priority,
+            },
+            ..self.clone()
+        }
+    }
+
+    /// A dispatcher queueing the same connection's commands at `priority`.
+    pub fn prioritize(&self, priority: Priority) -> Dispatcher {
+        Dispatcher {
+            lane: Lane {
+                priority,
+                ..self.lane
+            },
+            ..self.clone()
+        }
+    }
+
+    /// A dispatcher whose commands fill in the phases of a traced request.
+    pub fn traced(&self, phases: Arc<Phases>) -> Dispatcher {
+        Dispatcher {
+            traced: Some(phases),
+            ..self.clone()
+        }
+    }
+
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(Arc::new(recorder));
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Whether sent commands are written to a trace file or fill in a
+    /// traced request's phases, so must all go through the queue.
+    pub fn observed(&self) -> bool {
+        self.recorder.is_some() || self.traced.is_some()
+    }
+
+    /// The selected database's storage.
+    pub fn storage(&self) -> &StorageType {
+        &self.storage[usize::from(self.selected)]
+    }
+
+    pub fn send(&self, command: Command) -> Result<(), SendError<Command>> {
+        let shards = &self.databases[usize::from(self.selected)];
+        let shard = match command.shard_key() {
+            Some(key) => key as usize % shards.len(),
+            None => 0,
+        };
+        let _trace = self
+            .recorder
+            .as_ref()
+            .map(|r| r.record(self.selected, &command));
+        let at = Instant::now();
+        let sent = Sent {
+            at,
+            traced: self.traced.clone(),
+            deadline: self.timeout.map(|timeout| at + timeout),
+        };
+        let queued = &self.storage().stats.queued;
+        queued.fetch_add(1, Ordering::Relaxed);
+        shards[shard]
+            .send((self.lane, sent, command))
+            .map_err(|SendError((_, _, command))| {
+                queued.fetch_sub(1, Ordering::Relaxed);
+                SendError(command)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::SetMode;
+    use map8x32_protocol::codec::{Request, Response};
+    use tokio::sync::oneshot;
+
+    /// A GET of `key` from `connection`, sent now.
+    fn get(connection: u64, priority: Priority, key: u8) -> (Lane, Sent, Command) {
+        let lane = Lane {
+            connection,
+            priority,
+        };
+        let command = Command::Get {
+            key,
+            respond_to: oneshot::channel().0,
+        };
+        let sent = Sent {
+            at: Instant::now(),
+            traced: None,
+            deadline: None,
+        };
+        (lane, sent, command)
+    }
+
+    async fn drain(mut receiver: LaneReceiver) -> Vec<u8> {
+        let mut lanes = Lanes::default();
+        let mut order = Vec::new();
+        while let Some((_, command)) = lanes.next(&mut receiver).await {
+            order.extend(command.shard_key());
+        }
+        order
+    }
+
+    #[tokio::test]
+    async fn a_panicking_command_is_answered_and_its_processor_carries_on() {
+        let storage = Arc::new(crate::db::Database::new(0, None));
+        let sender = Dispatcher::spawn(1, std::slice::from_ref(&storage));
+        let poisoner = storage.clone();
+        let _ = std::thread::spawn(move || {
+            let _writes = poisoner.hold_writes();
+            panic!("poisons the write lock");
+        })
+        .join();
+        assert_eq!(
+            crate::command::execute(&sender, Request::SyncFull).await,
+            Some(Response::Status(STATUS_INTERNAL_ERROR))
+        );
+        assert_eq!(
+            crate::command::execute(&sender, Request::SyncFull).await,
+            Some(Response::FullSync {
+                seq: 0,
+                entries: Vec::new()
+            })
+        );
+        assert_eq!(storage.stats.processor_panics.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn queued_sets_for_a_key_are_applied_together_in_order() {
+        let storage = Arc::new(crate::db::Database::new(0, None));
+        let sender = Dispatcher::spawn(1, std::slice::from_ref(&storage));
+        let other = sender.for_connection(2, Priority::Normal);
+        let set = |sender: &Dispatcher, key: u8, value: u32| {
+            let (respond_to, answer) = oneshot::channel();
+            sender
+                .send(Command::Set {
+                    key,
+                    value,
+                    mode: SetMode::Append,
+                    respond_to,
+                })
+                .unwrap();
+            answer
+        };
+        // Queued before the processor first runs: three SETs for key 1 and
+        // one for key 2 from one connection, and a SET for key 1 from
+        // another. Connections still take turns, so the other's SET joins
+        // the first batch and the key 2 SET ends the second.
+        let answers = [
+            set(&sender, 1, 10),
+            set(&sender, 1, 11),
+            set(&sender, 1, 12),
+            set(&sender, 2, 20),
+            set(&other, 1, 13),
+        ];
+        let mut seqs = Vec::new();
+        for answer in answers {
+            let ack = answer.await.unwrap();
+            assert_eq!(ack.status, STATUS_OK);
+            seqs.push(ack.seq);
+        }
+        assert_eq!(seqs, [1, 3, 4, 5, 2]);
+        assert_eq!(storage.live_values(1), Some(vec![10, 13, 11, 12]));
+        assert_eq!(storage.stats.batched_sets.load(Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn connections_take_turns() {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        for key in [1, 2, 3] {
+            sender.send(get(1, Priority::Normal, key)).unwrap();
+        }
+        sender.send(get(2, Priority::Normal, 10)).unwrap();
+        sender.send(get(3, Priority::Normal, 20)).unwrap();
+        sender.send(get(2, Priority::Normal, 11)).unwrap();
+        drop(sender);
+        assert_eq!(drain(receiver).await, [1, 10, 20, 2, 11, 3]);
+    }
+
+    #[tokio::test]
+    async fn higher_classes_go_first() {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        sender.send(get(1, Priority::Low, 1)).unwrap();
+        sender.send(get(1, Priority::Low, 2)).unwrap();
+        sender.send(get(2, Priority::Normal, 10)).unwrap();
+        sender.send(get(3, Priority::High, 20)).unwrap();
+        sender.send(get(4, Priority::High, 30)).unwrap();
+        sender.send(get(3, Priority::High, 21)).unwrap();
+        drop(sender);
+        assert_eq!(drain(receiver).await, [20, 30, 21, 10, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn a_higher_class_sent_later_overtakes_what_is_queued() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let mut lanes = Lanes::default();
+        for key in [1, 2, 3] {
+            sender.send(get(1, Priority::Low, key)).unwrap();
+        }
+        sender.send(get(2, Priority::Normal, 10)).unwrap();
+        let (_, command) = lanes.next(&mut receiver).await.unwrap();
+        assert_eq!(command.shard_key(), Some(10));
+        let (_, command) = lanes.next(&mut receiver).await.unwrap();
+        assert_eq!(command.shard_key(), Some(1));
+
+        sender.send(get(3, Priority::High, 20)).unwrap();
+        sender.send(get(2, Priority::Normal, 11)).unwrap();
+        drop(sender);
+        let mut order = Vec::new();
+        while let Some((_, command)) = lanes.next(&mut receiver).await {
+            order.extend(command.shard_key());
+        }
+        assert_eq!(order, [20, 11, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn a_connection_keeps_its_class_until_its_queue_empties() {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        sender.send(get(1, Priority::Low, 1)).unwrap();
+        sender.send(get(1, Priority::High, 2)).unwrap();
+        sender.send(get(2, Priority::Normal, 10)).unwrap();
+        drop(sender);
+        assert_eq!(drain(receiver).await, [10, 1, 2], "still queued as low");
+    }
+
+    #[test]
+    fn next_if_only_looks_at_the_highest_class() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let mut lanes = Lanes::default();
+        sender.send(get(1, Priority::Low, 1)).unwrap();
+        sender.send(get(2, Priority::High, 20)).unwrap();
+        let low = |command: &Command| command.shard_key() == Some(1);
+        assert!(lanes.next_if(&mut receiver, low).is_none());
+        let (_, command) = lanes.next_if(&mut receiver, |_| true).unwrap();
+        assert_eq!(command.shard_key(), Some(20));
+        let (_, command) = lanes.next_if(&mut receiver, low).unwrap();
+        assert_eq!(command.shard_key(), Some(1));
+        assert!(lanes.next_if(&mut receiver, |_| true).is_none());
+    }
+}
@@ -0,0 +1,235 @@
+use crate::db::{self, Database};
+use crate::persistence::{sync_dir, Mutation};
+use map8x32_protocol::*;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+pub const DUMP_MAGIC: &[u8; 4] = b"M8DP";
+pub const DUMP_VERSION: u8 = 1;
+
+/// One key of a [`Dump`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DumpedKey {
+    pub key: u8,
+    pub value_type: u8,
+    pub values: Vec<u32>,
+    /// Every value's expiry in Unix milliseconds (0 for those that never
+    /// expire), or empty if none of the key's values expire.
+    pub deadlines: Vec<u64>,
+}
+
+/// A point-in-time copy of one database, as DUMP writes it.
+///
+/// The file is the magic, the format version (u8), the sequence number of
+/// the last mutation included (u64) and the key count (u32), then for each
+/// key: the key (u8), its value type (u8), the value count (u32), the
+/// values (u32 each), the expiry count (u32, 0 or the value count) and the
+/// expiries (u64 each). A CRC-32 (IEEE) of everything before it ends the
+/// file. Integers are little-endian.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dump {
+    pub seq: u64,
+    pub keys: Vec<DumpedKey>,
+}
+
+/// Whether `name` may be used as a dump file name: a plain name in the dump
+/// directory, never a path, made of ASCII letters, digits, `.`, `-` and
+/// `_`, and not starting with `.`.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= u8::MAX as usize
+        && !name.starts_with('.')
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'-' | b'_'))
+}
+
+impl Dump {
+    /// Copies `database`, ordered by key, at a single point in time as
+    /// [`Database::consistent_copy`] does.
+    pub fn capture(database: &Database) -> Dump {
+        let _writes = database.hold_writes();
+        let deadlines = database.copy_deadlines();
+        let mut keys: Vec<DumpedKey> = database.map.collect(|key, values| DumpedKey {
+            key,
+            value_type: database.value_type(key),
+            values: values.to_vec(),
+            deadlines: deadlines
+                .iter()
+                .find(|(expiring, _)| *expiring == key)
+                .map(|(_, deadlines)| deadlines.clone())
+                .unwrap_or_default(),
+        });
+        keys.sort_unstable_by_key(|dumped| dumped.key);
+        Dump {
+            seq: database.applied_seq(),
+            keys,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(DUMP_MAGIC);
+        out.push(DUMP_VERSION);
+        out.extend_from_slice(&self.seq.to_le_bytes());
+        out.extend_from_slice(&(self.keys.len() as u32).to_le_bytes());
+        for dumped in &self.keys {
+            out.extend_from_slice(&[dumped.key, dumped.value_type]);
+            out.extend_from_slice(&(dumped.values.len() as u32).to_le_bytes());
+            for value in &dumped.values {
+                out.extend_from_slice(&value.to_le_bytes());
+            }
+            out.extend_from_slice(&(dumped.deadlines.len() as u32).to_le_bytes());
+            for deadline in &dumped.deadlines {
+                out.extend_from_slice(&deadline.to_le_bytes());
+            }
+        }
+        let checksum = map8x32_protocol::crc32(&out);
+        out.extend_from_slice(&checksum.to_le_bytes());
+        out
+    }
+
+    /// Parses a dump, checking its version and checksum before anything
+    /// else, then that every key is well formed.
+    pub fn decode(bytes: &[u8]) -> io::Result<Dump> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        if bytes.len() < DUMP_MAGIC.len() + 1 || &bytes[..DUMP_MAGIC.len()] != DUMP_MAGIC {
+            return Err(invalid("not a map8x32 dump".to_string()));
+        }
+        let version = bytes[DUMP_MAGIC.len()];
+        if version != DUMP_VERSION {
+            return Err(invalid(format!(
+                "dump format version {version} is not supported (expected {DUMP_VERSION})"
+            )));
+        }
+        let truncated = || invalid("truncated dump".to_string());
+        let (body, checksum) = bytes
+            .split_last_chunk::<4>()
+            .filter(|(body, _)| body.len() > DUMP_MAGIC.len())
+            .ok_or_else(truncated)?;
+        if map8x32_protocol::crc32(body) != u32::from_le_bytes(*checksum) {
+            return Err(invalid("dump checksum mismatch".to_string()));
+        }
+
+        let mut body = &body[DUMP_MAGIC.len() + 1..];
+        let mut take = |len: usize| -> io::Result<&[u8]> {
+            if body.len() < len {
+                return Err(truncated());
+            }
+            let (taken, rest) = body.split_at(len);
+            body = rest;
+            Ok(taken)
+        };
+        let seq = u64::from_le_bytes(take(8)?.try_into().unwrap());
+        let key_count = u32::from_le_bytes(take(4)?.try_into().unwrap());
+        let mut keys: Vec<DumpedKey> = Vec::new();
+        for _ in 0..key_count {
+            let header = take(2)?;
+            let (key, value_type) = (header[0], header[1]);
+            let count = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+            let values: Vec<u32> = take(count.checked_mul(4).ok_or_else(truncated)?)?
+                .chunks_exact(4)
+                .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+                .collect();
+            let expiries = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+            let deadlines: Vec<u64> = take(expiries.checked_mul(8).ok_or_else(truncated)?)?
+                .chunks_exact(8)
+                .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+                .collect();
+            if keys.last().is_some_and(|last| last.key >= key) {
+                return Err(invalid(format!("key {key} is out of order")));
+            }
+            let parses = match value_type {
+                VALUE_TYPE_TIMED => timed::decode(&values).is_some(),
+                value_type => db::from_words(value_type, &values).is_some(),
+            };
+            if !parses {
+                return Err(invalid(format!(
+                    "key {key} does not hold values of type {value_type}"
+                )));
+            }
+            if !deadlines.is_empty()
+                && (value_type != VALUE_TYPE_U32 || deadlines.len() != values.len())
+            {
+                return Err(invalid(format!("key {key} has malformed expiries")));
+            }
+            keys.push(DumpedKey {
+                key,
+                value_type,
+                values,
+                deadlines,
+            });
+        }
+        if !body.is_empty() {
+            return Err(invalid("trailing bytes after the last key".to_string()));
+        }
+        Ok(Dump { seq, keys })
+    }
+
+    pub fn read(path: &Path) -> io::Result<Dump> {
+        Dump::decode(&fs::read(path)?)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))
+    }
+
+    /// The mutations that load the dump: a DELETE_ALL first unless
+    /// `merge`, then each key's values appended. Values that expired before
+    /// `now` are left out.
+    pub fn mutations(&self, merge: bool, now: u64) -> Vec<Mutation> {
+        let mut mutations = Vec::new();
+        if !merge {
+            mutations.push(Mutation::DeleteAll);
+        }
+        for dumped in &self.keys {
+            let key = dumped.key;
+            if dumped.deadlines.is_empty() {
+                mutations.push(Mutation::SetTyped {
+                    key,
+                    value_type: dumped.value_type,
+                    words: dumped.values.clone(),
+                    replace: false,
+                });
+                continue;
+            }
+            for (&value, &deadline) in dumped.values.iter().zip(&dumped.deadlines) {
+                match deadline {
+                    0 => mutations.push(Mutation::Set { key, value }),
+                    deadline if deadline > now => mutations.push(Mutation::SetExpiring {
+                        key,
+                        value,
+                        deadline,
+                    }),
+                    _ => {}
+                }
+            }
+        }
+        mutations
+    }
+
+    /// Writes the dump to `path` through a temporary file in the same
+    /// directory, so a crash never leaves a partial dump under that name.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let Some(name) = path.file_name() else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "dump path has no file name",
+            ));
+        };
+        let mut tmp_name = std::ffi::OsString::from(".");
+        tmp_name.push(name);
+        tmp_name.push(".tmp");
+        let tmp_path = dir.join(tmp_name);
+        let result = (|| {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(&self.encode())?;
+            file.sync_all()?;
+            fs::rename(&tmp_path, path)?;
+            sync_dir(dir)
+        })();
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        result
+    }
+}
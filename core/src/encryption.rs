@@ -0,0 +1,201 @@
+//! AES-256-GCM encryption of the snapshot and WAL, so a data directory
+//! does not give the store away to whoever can read it. Every file is
+//! sealed under a key of its own, derived with HKDF-SHA256 from one of the
+//! configured keys and a random salt in the file's header; a file's records
+//! are numbered, and the number is the nonce, so no nonce is used twice.
+
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{digest, hkdf};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Where the keys are read from when no file or descriptor is given.
+pub const KEYS_ENV: &str = "MAP8X32_ENCRYPTION_KEYS";
+/// The authentication tag ending each sealed record.
+pub const TAG_LEN: usize = 16;
+const SALT_LEN: usize = 32;
+/// `[key id: u32][salt]`, written after a sealed file's magic.
+pub const KEY_HEADER_LEN: usize = 4 + SALT_LEN;
+
+/// What a file holds, so a sealed record of one kind of file cannot be
+/// passed off as part of another.
+#[derive(Debug, Clone, Copy)]
+pub enum Purpose {
+    Snapshot,
+    Wal,
+}
+
+impl Purpose {
+    fn info(self) -> &'static [u8] {
+        match self {
+            Purpose::Snapshot => b"map8x32 snapshot",
+            Purpose::Wal => b"map8x32 wal",
+        }
+    }
+}
+
+struct MasterKey {
+    id: u32,
+    secret: [u8; 32],
+}
+
+/// The configured keys. The first seals new files and any of them opens
+/// files sealed before, so a key can be retired once no file is left
+/// sealed under it.
+pub struct Keyring {
+    keys: Vec<MasterKey>,
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.keys.iter().map(|key| format!("{:08x}", key.id)))
+            .finish()
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Where the keys come from.
+#[derive(Debug, Clone)]
+pub enum KeySource {
+    File(PathBuf),
+    Fd(i32),
+    Env,
+}
+
+impl Keyring {
+    /// Reads the keys from `source`, or `None` if the environment variable
+    /// is not set.
+    pub fn load(source: KeySource) -> io::Result<Option<Keyring>> {
+        let (text, from) = match &source {
+            KeySource::File(path) => (std::fs::read_to_string(path)?, path.display().to_string()),
+            KeySource::Fd(fd) => (
+                std::fs::read_to_string(Path::new("/dev/fd").join(fd.to_string()))?,
+                format!("descriptor {fd}"),
+            ),
+            KeySource::Env => match std::env::var(KEYS_ENV) {
+                Ok(text) => (text, KEYS_ENV.to_string()),
+                Err(std::env::VarError::NotPresent) => return Ok(None),
+                Err(e) => return Err(invalid(format!("{KEYS_ENV}: {e}"))),
+            },
+        };
+        Keyring::parse(&text)
+            .map(Some)
+            .map_err(|e| invalid(format!("{from}: {e}")))
+    }
+
+    /// Parses keys of 64 hex digits each, separated by whitespace or
+    /// commas, the current one first. `#` comments out the rest of a line.
+    pub fn parse(text: &str) -> io::Result<Keyring> {
+        let mut keys: Vec<MasterKey> = Vec::new();
+        let words = text
+            .lines()
+            .flat_map(|line| {
+                line.split('#')
+                    .next()
+                    .unwrap_or_default()
+                    .split([',', ' ', '\t'])
+            })
+            .filter(|word| !word.is_empty());
+        for word in words {
+            let secret = decode_hex(word)
+                .ok_or_else(|| invalid("a key is not 64 hex digits".to_string()))?;
+            let digest = digest::digest(&digest::SHA256, &secret);
+            let id = u32::from_be_bytes(digest.as_ref()[..4].try_into().unwrap());
+            if keys.iter().any(|key| key.id == id) {
+                return Err(invalid(format!("key {id:08x} is listed twice")));
+            }
+            keys.push(MasterKey { id, secret });
+        }
+        if keys.is_empty() {
+            return Err(invalid("no keys".to_string()));
+        }
+        Ok(Keyring { keys })
+    }
+
+    /// The ID of the key new files are sealed under.
+    pub fn current_id(&self) -> u32 {
+        self.keys[0].id
+    }
+
+    /// A key for a new file under the current key, with the header to
+    /// write ahead of what it seals.
+    pub fn seal_file(&self, purpose: Purpose) -> io::Result<(FileKey, [u8; KEY_HEADER_LEN])> {
+        let mut header = [0; KEY_HEADER_LEN];
+        header[..4].copy_from_slice(&self.current_id().to_le_bytes());
+        SystemRandom::new()
+            .fill(&mut header[4..])
+            .map_err(|_| io::Error::other("no randomness for an encryption salt"))?;
+        Ok((derive(&self.keys[0], &header[4..], purpose), header))
+    }
+
+    /// The key a file whose header is `header` was sealed under.
+    pub fn open_file(
+        &self,
+        purpose: Purpose,
+        header: &[u8; KEY_HEADER_LEN],
+    ) -> io::Result<FileKey> {
+        let id = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let key = self.keys.iter().find(|key| key.id == id).ok_or_else(|| {
+            invalid(format!(
+                "sealed under key {id:08x}, which is not among the configured keys"
+            ))
+        })?;
+        Ok(derive(key, &header[4..], purpose))
+    }
+}
+
+fn decode_hex(word: &str) -> Option<[u8; 32]> {
+    let digits = word.as_bytes();
+    if digits.len() != 64 {
+        return None;
+    }
+    let mut secret = [0; 32];
+    for (byte, pair) in secret.iter_mut().zip(digits.chunks_exact(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(secret)
+}
+
+fn derive(key: &MasterKey, salt: &[u8], purpose: Purpose) -> FileKey {
+    let info = [purpose.info()];
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, salt).extract(&key.secret);
+    let okm = prk
+        .expand(&info, &AES_256_GCM)
+        .expect("an AES-256 key is a valid HKDF output length");
+    FileKey(LessSafeKey::new(UnboundKey::from(okm)))
+}
+
+/// One file's key.
+#[derive(Debug)]
+pub struct FileKey(LessSafeKey);
+
+impl FileKey {
+    /// Encrypts `data` in place as record `index` of its file and appends
+    /// the tag. Each index must be sealed at most once.
+    pub fn seal(&self, index: u64, data: &mut Vec<u8>) {
+        self.0
+            .seal_in_place_append_tag(nonce(index), Aad::empty(), data)
+            .expect("a record is far below the AES-GCM size limit");
+    }
+
+    /// Decrypts record `index`, returning its contents, or an error if it
+    /// was changed or sealed as another record.
+    pub fn open<'a>(&self, index: u64, data: &'a mut [u8]) -> io::Result<&'a [u8]> {
+        self.0
+            .open_in_place(nonce(index), Aad::empty(), data)
+            .map(|plain| &*plain)
+            .map_err(|_| invalid(format!("record {index} fails authentication")))
+    }
+}
+
+fn nonce(index: u64) -> Nonce {
+    let mut bytes = [0; NONCE_LEN];
+    bytes[4..].copy_from_slice(&index.to_le_bytes());
+    Nonce::assume_unique_for_key(bytes)
+}
@@ -0,0 +1,155 @@
+use crate::command::{call, execute, Command, GetResponse, SetMode, Unanswered};
+use crate::db::Database;
+use crate::dispatch::Dispatcher;
+use crate::StorageType;
+use map8x32_protocol::codec::{Request, Response};
+use map8x32_protocol::*;
+use std::sync::Arc;
+
+pub type Result<T> = std::result::Result<T, StatusError>;
+
+fn refused(unanswered: Unanswered) -> StatusError {
+    match unanswered {
+        Unanswered::Stopped => StatusError::Unavailable,
+        Unanswered::Failed => StatusError::InternalError,
+        Unanswered::TimedOut => StatusError::Timeout,
+    }
+}
+
+fn acked(status: u8) -> Result<()> {
+    match status {
+        STATUS_OK => Ok(()),
+        status => Err(StatusError::new(status)),
+    }
+}
+
+/// The engine run in-process, for applications that embed it rather than
+/// talk to a server. Commands go through the same command processors and
+/// answer as they would over a socket, refusals as a [`StatusError`].
+/// Cloning an engine shares its databases and processors, which stop once
+/// every clone is dropped.
+///
+/// ```no_run
+/// # async fn demo() -> map8x32_core::Result<()> {
+/// let engine = map8x32_core::Engine::in_memory(1);
+/// engine.set(7, 1).await?;
+/// assert_eq!(engine.get(7).await?, Some(vec![1]));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Engine {
+    sender: Dispatcher,
+}
+
+impl Engine {
+    /// Starts `processors` command processors for each of `databases`,
+    /// database 0 selected. Must be called within a Tokio runtime.
+    pub fn spawn(processors: usize, databases: &[StorageType]) -> Engine {
+        Engine {
+            sender: Dispatcher::spawn(processors, databases),
+        }
+    }
+
+    /// An engine over one empty database, without persistence or a memory
+    /// limit.
+    pub fn in_memory(processors: usize) -> Engine {
+        Engine::spawn(processors, &[Arc::new(Database::new(0, None))])
+    }
+
+    /// The dispatcher commands are sent through, for running [`Command`]s
+    /// directly.
+    pub fn dispatcher(&self) -> &Dispatcher {
+        &self.sender
+    }
+
+    /// An engine working on database `db`, if there is one.
+    pub fn select(&self, db: u8) -> Option<Engine> {
+        Some(Engine {
+            sender: self.sender.select(db)?,
+        })
+    }
+
+    /// Runs any data or admin request, as a server would answer it;
+    /// connection-level requests such as HELLO answer BAD_REQUEST.
+    pub async fn request(&self, request: Request) -> Result<Response> {
+        execute(&self.sender, request)
+            .await
+            .ok_or(StatusError::Unavailable)
+    }
+
+    pub async fn set(&self, key: u8, value: u32) -> Result<()> {
+        let ack = call(&self.sender, |respond_to| Command::Set {
+            key,
+            value,
+            mode: SetMode::Append,
+            respond_to,
+        })
+        .await
+        .map_err(refused)?;
+        acked(ack.status)
+    }
+
+    pub async fn get(&self, key: u8) -> Result<Option<Vec<u32>>> {
+        match call(&self.sender, |respond_to| Command::Get { key, respond_to })
+            .await
+            .map_err(refused)?
+        {
+            GetResponse::Found(values) => Ok(Some(values)),
+            GetResponse::NotFound => Ok(None),
+        }
+    }
+
+    /// Deletes `key`, returning whether it existed.
+    pub async fn delete(&self, key: u8) -> Result<bool> {
+        let ack = call(&self.sender, |respond_to| Command::DeleteByKey {
+            key,
+            respond_to,
+        })
+        .await
+        .map_err(refused)?;
+        match ack.status {
+            STATUS_NOT_FOUND => Ok(false),
+            status => acked(status).map(|()| true),
+        }
+    }
+
+    /// Every key with its values, in key order.
+    pub async fn list_all(&self) -> Result<Vec<(u8, Vec<u32>)>> {
+        let listed = call(&self.sender, |respond_to| Command::ListAll {
+            snapshot: false,
+            respond_to,
+        })
+        .await
+        .map_err(refused)?;
+        Ok(listed.entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn commands_run_in_process() {
+        let engine = Engine::in_memory(2);
+        engine.set(7, 1).await.unwrap();
+        engine.set(7, 2).await.unwrap();
+        engine.set(3, 9).await.unwrap();
+        assert_eq!(engine.get(7).await, Ok(Some(vec![1, 2])));
+        let mut listed = engine.list_all().await.unwrap();
+        listed.sort();
+        assert_eq!(listed, [(3, vec![9]), (7, vec![1, 2])]);
+        assert_eq!(engine.delete(3).await, Ok(true));
+        assert_eq!(engine.delete(3).await, Ok(false));
+        assert_eq!(engine.get(3).await, Ok(None));
+        assert_eq!(
+            engine.request(Request::DeleteAll { token: 0 }).await,
+            Ok(Response::Ack {
+                status: STATUS_UNAUTHORIZED,
+                seq: 4
+            })
+        );
+        assert!(engine.select(1).is_none());
+    }
+}
@@ -0,0 +1,135 @@
+use crate::dispatch::{Phases, Sent};
+pub use map8x32_protocol::codec::{OpLatency, Quantiles};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Bucket `i` counts latencies below 2^i microseconds that are not in a
+/// lower one; the last also takes everything longer.
+const BUCKETS: usize = 32;
+
+#[derive(Debug)]
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    max: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            max: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    fn record(&self, latency: Duration) {
+        let micros = latency.as_micros().min(u128::from(u64::MAX)) as u64;
+        let bucket = ((u64::BITS - micros.leading_zeros()) as usize).min(BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.max.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    fn quantiles(&self) -> Quantiles {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect();
+        let count = counts.iter().sum();
+        let max = self.max.load(Ordering::Relaxed);
+        // The top of the bucket holding the `q` quantile.
+        let at = |q: f64| {
+            let rank = ((count as f64 * q).ceil() as u64).max(1);
+            let mut seen = 0;
+            let bucket = counts
+                .iter()
+                .position(|&n| {
+                    seen += n;
+                    seen >= rank
+                })
+                .unwrap_or(BUCKETS - 1);
+            ((1u64 << bucket) - 1).min(max)
+        };
+        if count == 0 {
+            return Quantiles::default();
+        }
+        Quantiles {
+            count,
+            p50: at(0.5),
+            p90: at(0.9),
+            p99: at(0.99),
+            p999: at(0.999),
+            max,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct OpHistograms {
+    wait: Histogram,
+    run: Histogram,
+}
+
+/// Per-opcode histograms of how long commands waited for their command
+/// processor and how long they then ran on it.
+#[derive(Debug)]
+pub struct Latencies {
+    ops: Vec<OpHistograms>,
+}
+
+impl Default for Latencies {
+    fn default() -> Self {
+        Latencies {
+            ops: (0..=u8::MAX).map(|_| OpHistograms::default()).collect(),
+        }
+    }
+}
+
+impl Latencies {
+    /// Starts timing a command of `op` that a processor is taking now; its
+    /// run time is recorded, and its traced request's phases filled in,
+    /// when the timer drops.
+    pub fn time(&self, op: u8, sent: Sent) -> Timer<'_> {
+        let histograms = &self.ops[usize::from(op)];
+        let started = Instant::now();
+        histograms
+            .wait
+            .record(started.saturating_duration_since(sent.at));
+        Timer {
+            histograms,
+            started,
+            traced: sent.traced,
+        }
+    }
+
+    /// The quantiles of every operation that has run, by opcode.
+    pub fn snapshot(&self) -> Vec<OpLatency> {
+        self.ops
+            .iter()
+            .enumerate()
+            .map(|(op, histograms)| OpLatency {
+                op: op as u8,
+                wait: histograms.wait.quantiles(),
+                run: histograms.run.quantiles(),
+            })
+            .filter(|latency| latency.run.count > 0)
+            .collect()
+    }
+}
+
+pub struct Timer<'a> {
+    histograms: &'a OpHistograms,
+    started: Instant,
+    traced: Option<Arc<Phases>>,
+}
+
+impl Drop for Timer<'_> {
+    fn drop(&mut self) {
+        self.histograms.run.record(self.started.elapsed());
+        if let Some(phases) = &self.traced {
+            phases.processed(self.started);
+        }
+    }
+}
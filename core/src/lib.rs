@@ -0,0 +1,27 @@
+//! The map8x32 storage engine: the databases, their persistence, and the
+//! command processors that run commands against them. [`Engine`] drives it
+//! in-process; the server puts it behind its sockets.
+
+pub mod cap;
+pub mod command;
+pub mod db;
+pub mod deadletter;
+pub mod dispatch;
+pub mod dump;
+pub mod encryption;
+mod engine;
+pub mod latency;
+pub mod persistence;
+pub mod recorder;
+pub mod registry;
+pub mod rollup;
+pub mod store;
+pub mod transform;
+pub mod values;
+
+pub use engine::{Engine, Result};
+pub use map8x32_protocol::codec::{Request, Response};
+pub use map8x32_protocol::StatusError;
+use std::sync::Arc;
+
+pub type StorageType = Arc<db::Database>;
@@ -0,0 +1,2426 @@
+use crate::cap::Caps;
+use crate::db::{self, Database};
+use crate::encryption::{FileKey, Keyring, Purpose, KEY_HEADER_LEN, TAG_LEN};
+pub use map8x32_protocol::codec::Health;
+use map8x32_protocol::codec::PersistenceState;
+use map8x32_protocol::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+const WAL_PREFIX: &str = "map8x32.wal.";
+const WAL_MAGIC: &[u8; 4] = b"M8WL";
+const WAL_HEADER_LEN: u64 = 12;
+/// A WAL segment sealed with encryption keys: the plain header, then the
+/// key header, then records of `[len: u32][index: u64][sealed: len bytes]`.
+const SEALED_WAL_MAGIC: &[u8; 4] = b"M8WX";
+const SEALED_WAL_HEADER_LEN: u64 = WAL_HEADER_LEN + KEY_HEADER_LEN as u64;
+const SNAPSHOT_FILE: &str = "map8x32.snapshot";
+const SNAPSHOT_MAGIC: &[u8; 4] = b"M8SP";
+const SNAPSHOT_VERSION: u8 = 1;
+/// Snapshots from before the format was versioned and checksummed.
+const LEGACY_SNAPSHOT_MAGIC: &[u8; 4] = b"M8SN";
+/// A snapshot sealed with encryption keys: the magic and key header, then
+/// the whole plain snapshot sealed as record 0.
+const SEALED_SNAPSHOT_MAGIC: &[u8; 4] = b"M8SX";
+const ALERT_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "snake_case")]
+pub enum FailurePolicy {
+    /// Refuse every write from the first failure until restart.
+    ReadOnly,
+    /// Keep accepting writes in memory and stop persisting them.
+    MemoryOnly,
+    /// Refuse writes while persistence fails, resuming once it succeeds again.
+    ShedWrites,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "snake_case")]
+pub enum FsyncPolicy {
+    Always,
+    Everysec,
+    Never,
+}
+
+#[derive(Debug, Clone)]
+pub enum Mutation {
+    Set {
+        key: u8,
+        value: u32,
+    },
+    InsertSorted {
+        key: u8,
+        value: u32,
+    },
+    /// Appends a value that expires at `deadline`, in Unix milliseconds.
+    SetExpiring {
+        key: u8,
+        value: u32,
+        deadline: u64,
+    },
+    DeleteKey {
+        key: u8,
+    },
+    DeleteAll,
+    Replace {
+        key: u8,
+        values: Vec<u32>,
+    },
+    /// Appends all of `values` to the key at once.
+    Push {
+        key: u8,
+        values: Vec<u32>,
+    },
+    /// Appends typed values' words to the key or, with `replace`, replaces its
+    /// values with them; either way the key then holds `value_type`.
+    SetTyped {
+        key: u8,
+        value_type: u8,
+        words: Vec<u32>,
+        replace: bool,
+    },
+    /// Removes the value at `index` among the key's values live at `at_ms`,
+    /// in Unix milliseconds, so replay counts the same values.
+    RemoveAt {
+        key: u8,
+        index: u32,
+        at_ms: u64,
+    },
+    /// Moves `from`'s values to `to`, replacing `to`'s or, with `merge`,
+    /// appending to them.
+    Rename {
+        from: u8,
+        to: u8,
+        merge: bool,
+    },
+    /// Exchanges `a`'s values, types, expiries and tags with `b`'s.
+    Swap {
+        a: u8,
+        b: u8,
+    },
+    /// Copies `from`'s values to `to` as [`Mutation::Rename`] moves them.
+    Copy {
+        from: u8,
+        to: u8,
+        append: bool,
+    },
+    /// Clears the key's expiries, dropping the values expired by `at_ms`.
+    Persist {
+        key: u8,
+        at_ms: u64,
+    },
+    /// Moves the expiry of the key's expiring values to `deadline`, dropping
+    /// the values expired by `at_ms`.
+    RefreshExpiry {
+        key: u8,
+        deadline: u64,
+        at_ms: u64,
+    },
+    /// Sorts the key's values, descending with `descending`.
+    Sort {
+        key: u8,
+        descending: bool,
+    },
+    /// Removes the key's duplicates among the values live at `at_ms`.
+    Dedup {
+        key: u8,
+        at_ms: u64,
+    },
+    Tag {
+        key: u8,
+        tag: u8,
+    },
+    /// Folds the key's timed values taken before `before_ms` into summaries
+    /// of `window_ms`-long windows, keeping the newest `keep`. Logged under
+    /// SET_TIMED's opcode, which SET_TIMED itself is not: it is logged as
+    /// SET_TYPED.
+    RollUp {
+        key: u8,
+        before_ms: u64,
+        window_ms: u64,
+        keep: u32,
+    },
+}
+
+impl Mutation {
+    fn encode(&self) -> Vec<u8> {
+        let frame = match self {
+            Mutation::Set { key, value } => Frame::new(OP_SET, *key, *value),
+            Mutation::InsertSorted { key, value } => Frame::new(OP_SET_SORTED, *key, *value),
+            Mutation::SetExpiring { key, value, .. } => Frame::new(OP_SET_EXPIRING, *key, *value),
+            Mutation::DeleteKey { key } => Frame::new(OP_DELETE_BY_KEY, *key, 0),
+            Mutation::DeleteAll => Frame::new(OP_DELETE_ALL, 0, 0),
+            Mutation::Replace { key, values } => {
+                Frame::new(OP_REPLACE_IF, *key, values.len() as u32)
+            }
+            Mutation::Push { key, values } => Frame::new(OP_PUSH, *key, values.len() as u32),
+            Mutation::SetTyped { key, words, .. } => {
+                Frame::new(OP_SET_TYPED, *key, words.len() as u32)
+            }
+            Mutation::RemoveAt { key, index, .. } => Frame::new(OP_DELETE_AT, *key, *index),
+            Mutation::Rename { from, to, .. } => Frame::new(OP_RENAME, *from, (*to).into()),
+            Mutation::Copy { from, to, .. } => Frame::new(OP_COPY, *from, (*to).into()),
+            Mutation::Swap { a, b } => Frame::new(OP_SWAP, *a, (*b).into()),
+            Mutation::Persist { key, .. } => Frame::new(OP_PERSIST, *key, 0),
+            Mutation::RefreshExpiry { key, .. } => Frame::new(OP_TOUCH, *key, 0),
+            Mutation::Dedup { key, .. } => Frame::new(OP_DEDUP, *key, 0),
+            Mutation::Sort { key, descending } => Frame::new(OP_SORT, *key, *descending as u32),
+            Mutation::Tag { key, tag } => Frame::new(OP_TAG, *key, (*tag).into()),
+            Mutation::RollUp { key, keep, .. } => Frame::new(OP_SET_TIMED, *key, *keep),
+        };
+        let mut record = frame.encode().to_vec();
+        match self {
+            Mutation::Replace { values, .. } | Mutation::Push { values, .. } => {
+                for value in values {
+                    record.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+            Mutation::SetExpiring { deadline, .. } => {
+                record.extend_from_slice(&deadline.to_le_bytes());
+            }
+            Mutation::RefreshExpiry {
+                deadline, at_ms, ..
+            } => {
+                record.extend_from_slice(&deadline.to_le_bytes());
+                record.extend_from_slice(&at_ms.to_le_bytes());
+            }
+            Mutation::RollUp {
+                before_ms,
+                window_ms,
+                ..
+            } => {
+                record.extend_from_slice(&before_ms.to_le_bytes());
+                record.extend_from_slice(&window_ms.to_le_bytes());
+            }
+            Mutation::RemoveAt { at_ms, .. }
+            | Mutation::Persist { at_ms, .. }
+            | Mutation::Dedup { at_ms, .. } => {
+                record.extend_from_slice(&at_ms.to_le_bytes());
+            }
+            Mutation::Rename { merge: flag, .. } | Mutation::Copy { append: flag, .. } => {
+                record.push(*flag as u8)
+            }
+            Mutation::SetTyped {
+                value_type,
+                words,
+                replace,
+                ..
+            } => {
+                record.extend_from_slice(&[*value_type, *replace as u8]);
+                for word in words {
+                    record.extend_from_slice(&word.to_le_bytes());
+                }
+            }
+            _ => {}
+        }
+        record
+    }
+
+    /// Decodes the record at the start of `bytes`, returning it with its
+    /// length, or `None` if the record is incomplete.
+    fn decode(bytes: &[u8]) -> io::Result<Option<(Mutation, usize)>> {
+        let Some(header) = bytes.first_chunk::<FRAME_LEN>() else {
+            return Ok(None);
+        };
+        let Frame { op, key, value } = Frame::decode(header);
+        let mutation = match op {
+            OP_SET => Mutation::Set { key, value },
+            OP_SET_SORTED => Mutation::InsertSorted { key, value },
+            OP_SET_EXPIRING => {
+                let len = FRAME_LEN + 8;
+                let Some(body) = bytes.get(FRAME_LEN..len) else {
+                    return Ok(None);
+                };
+                let deadline = u64::from_le_bytes(body.try_into().unwrap());
+                return Ok(Some((
+                    Mutation::SetExpiring {
+                        key,
+                        value,
+                        deadline,
+                    },
+                    len,
+                )));
+            }
+            OP_TOUCH => {
+                let len = FRAME_LEN + 16;
+                let Some(body) = bytes.get(FRAME_LEN..len) else {
+                    return Ok(None);
+                };
+                let (deadline, at_ms) = body.split_at(8);
+                return Ok(Some((
+                    Mutation::RefreshExpiry {
+                        key,
+                        deadline: u64::from_le_bytes(deadline.try_into().unwrap()),
+                        at_ms: u64::from_le_bytes(at_ms.try_into().unwrap()),
+                    },
+                    len,
+                )));
+            }
+            OP_SET_TIMED => {
+                let len = FRAME_LEN + 16;
+                let Some(body) = bytes.get(FRAME_LEN..len) else {
+                    return Ok(None);
+                };
+                let (before_ms, window_ms) = body.split_at(8);
+                return Ok(Some((
+                    Mutation::RollUp {
+                        key,
+                        before_ms: u64::from_le_bytes(before_ms.try_into().unwrap()),
+                        window_ms: u64::from_le_bytes(window_ms.try_into().unwrap()),
+                        keep: value,
+                    },
+                    len,
+                )));
+            }
+            OP_DELETE_AT | OP_PERSIST | OP_DEDUP => {
+                let len = FRAME_LEN + 8;
+                let Some(body) = bytes.get(FRAME_LEN..len) else {
+                    return Ok(None);
+                };
+                let at_ms = u64::from_le_bytes(body.try_into().unwrap());
+                let mutation = match op {
+                    OP_DELETE_AT => Mutation::RemoveAt {
+                        key,
+                        index: value,
+                        at_ms,
+                    },
+                    OP_PERSIST => Mutation::Persist { key, at_ms },
+                    _ => Mutation::Dedup { key, at_ms },
+                };
+                return Ok(Some((mutation, len)));
+            }
+            OP_RENAME | OP_COPY => {
+                let Some(&flag) = bytes.get(FRAME_LEN) else {
+                    return Ok(None);
+                };
+                let (from, to, flag) = (key, value as u8, flag == 1);
+                let mutation = match op {
+                    OP_RENAME => Mutation::Rename {
+                        from,
+                        to,
+                        merge: flag,
+                    },
+                    _ => Mutation::Copy {
+                        from,
+                        to,
+                        append: flag,
+                    },
+                };
+                return Ok(Some((mutation, FRAME_LEN + 1)));
+            }
+            OP_SWAP => Mutation::Swap {
+                a: key,
+                b: value as u8,
+            },
+            OP_DELETE_BY_KEY => Mutation::DeleteKey { key },
+            OP_SORT => Mutation::Sort {
+                key,
+                descending: value == 1,
+            },
+            OP_DELETE_ALL => Mutation::DeleteAll,
+            OP_TAG => Mutation::Tag {
+                key,
+                tag: value as u8,
+            },
+            OP_REPLACE_IF | OP_PUSH => {
+                let len = FRAME_LEN + value as usize * 4;
+                let Some(body) = bytes.get(FRAME_LEN..len) else {
+                    return Ok(None);
+                };
+                let values = body
+                    .chunks_exact(4)
+                    .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+                    .collect();
+                let mutation = match op {
+                    OP_PUSH => Mutation::Push { key, values },
+                    _ => Mutation::Replace { key, values },
+                };
+                return Ok(Some((mutation, len)));
+            }
+            OP_SET_TYPED => {
+                let len = FRAME_LEN + 2 + value as usize * 4;
+                let Some(body) = bytes.get(FRAME_LEN..len) else {
+                    return Ok(None);
+                };
+                let words = body[2..]
+                    .chunks_exact(4)
+                    .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+                    .collect();
+                let mutation = Mutation::SetTyped {
+                    key,
+                    value_type: body[0],
+                    words,
+                    replace: body[1] == 1,
+                };
+                return Ok(Some((mutation, len)));
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown record op {op}"),
+                ))
+            }
+        };
+        Ok(Some((mutation, FRAME_LEN)))
+    }
+
+    pub fn apply(&self, database: &Database) {
+        match *self {
+            Mutation::Set { key, value } => database.push(key, value),
+            Mutation::InsertSorted { key, value } => database.insert_sorted(key, value),
+            Mutation::SetExpiring {
+                key,
+                value,
+                deadline,
+            } => database.push_expiring(key, value, deadline),
+            Mutation::DeleteKey { key } => {
+                database.remove(key);
+            }
+            Mutation::DeleteAll => database.clear(),
+            Mutation::Replace { key, ref values } => database.replace(key, values.clone()),
+            Mutation::Push { key, ref values } => database.push_all(key, values),
+            Mutation::SetTyped {
+                key,
+                value_type,
+                ref words,
+                replace,
+            } => {
+                if replace {
+                    database.replace(key, Vec::new());
+                }
+                database.extend(key, value_type, words);
+            }
+            Mutation::RemoveAt { key, index, at_ms } => {
+                database.remove_at(key, index, at_ms);
+            }
+            Mutation::Rename { from, to, merge } => {
+                database.rename(from, to, merge);
+            }
+            Mutation::Copy { from, to, append } => {
+                database.copy(from, to, append);
+            }
+            Mutation::Swap { a, b } => {
+                database.swap(a, b);
+            }
+            Mutation::Persist { key, at_ms } => {
+                database.persist(key, at_ms);
+            }
+            Mutation::RefreshExpiry {
+                key,
+                deadline,
+                at_ms,
+            } => {
+                database.refresh_expiry(key, deadline, at_ms);
+            }
+            Mutation::Dedup { key, at_ms } => {
+                database.dedup(key, at_ms);
+            }
+            Mutation::Sort { key, descending } => {
+                database.sort(key, descending);
+            }
+            Mutation::Tag { key, tag } => {
+                database.set_tag(key, tag);
+            }
+            Mutation::RollUp {
+                key,
+                before_ms,
+                window_ms,
+                keep,
+            } => {
+                database.roll_up(key, before_ms, window_ms, keep as usize);
+            }
+        }
+    }
+}
+
+/// One key as [`Persistence::deleted_values`] replays it: its values and
+/// each one's expiry, 0 for those that never expire.
+#[derive(Debug, Clone, Default)]
+struct KeyHistory {
+    value_type: u8,
+    values: Vec<u32>,
+    deadlines: Vec<u64>,
+}
+
+impl KeyHistory {
+    fn append(&mut self, value: u32, deadline: u64, cap: Option<usize>) {
+        self.values.push(value);
+        self.deadlines.push(deadline);
+        db::trim(&mut self.values, cap);
+        db::trim(&mut self.deadlines, cap);
+    }
+
+    fn merge(&mut self, other: KeyHistory) {
+        self.values.extend(other.values);
+        self.deadlines.extend(other.deadlines);
+    }
+
+    /// Drops the values expired by `at_ms` and gives the rest the expiry
+    /// `new` makes of theirs, as [`Database::persist`] and
+    /// [`Database::refresh_expiry`] do.
+    fn rewrite_deadlines(&mut self, at_ms: u64, new: impl Fn(u64) -> u64) {
+        let (values, deadlines) = self
+            .values
+            .iter()
+            .zip(&self.deadlines)
+            .filter(|(_, deadline)| !db::expired(**deadline, at_ms))
+            .map(|(value, deadline)| (*value, new(*deadline)))
+            .unzip();
+        self.values = values;
+        self.deadlines = deadlines;
+    }
+
+    /// The values RESTORE_KEY puts back: those that were not set to expire.
+    fn restorable(self) -> Option<(u8, Vec<u32>)> {
+        let values: Vec<u32> = self
+            .values
+            .into_iter()
+            .zip(self.deadlines)
+            .filter(|(_, deadline)| *deadline == 0)
+            .map(|(value, _)| value)
+            .collect();
+        (!values.is_empty()).then_some((self.value_type, values))
+    }
+}
+
+/// Stores `history` at `to` for RENAME and COPY, after `to`'s values with
+/// `append`.
+fn place(keys: &mut HashMap<u8, KeyHistory>, to: u8, history: KeyHistory, append: bool) {
+    match keys.get_mut(&to) {
+        Some(destination) if append => destination.merge(history),
+        _ => {
+            keys.insert(to, history);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Wal {
+    file: File,
+    len: u64,
+    generation: u64,
+    sealer: Option<Sealer>,
+}
+
+/// The key a sealed segment's records are sealed with, and the index the
+/// next one is sealed as. Only a segment this process created has one, so
+/// `next` never has to be worked out from what is on disk.
+#[derive(Debug)]
+struct Sealer {
+    key: FileKey,
+    next: u64,
+}
+
+impl Wal {
+    fn create(dir: &Path, generation: u64, keyring: Option<&Keyring>) -> io::Result<Wal> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(segment_path(dir, generation))?;
+        let mut header = match keyring {
+            Some(_) => SEALED_WAL_MAGIC.to_vec(),
+            None => WAL_MAGIC.to_vec(),
+        };
+        header.extend_from_slice(&generation.to_le_bytes());
+        let sealer = match keyring {
+            Some(keyring) => {
+                let (key, key_header) = keyring.seal_file(Purpose::Wal)?;
+                header.extend_from_slice(&key_header);
+                Some(Sealer { key, next: 0 })
+            }
+            None => None,
+        };
+        file.write_all(&header)?;
+        file.sync_all()?;
+        sync_dir(dir)?;
+        Ok(Wal {
+            file,
+            len: header.len() as u64,
+            generation,
+            sealer,
+        })
+    }
+
+    /// Whether no record has been appended since the header.
+    fn is_empty(&self) -> bool {
+        let header_len = match self.sealer {
+            Some(_) => SEALED_WAL_HEADER_LEN,
+            None => WAL_HEADER_LEN,
+        };
+        self.len == header_len
+    }
+
+    fn append(&mut self, record: &[u8], fsync: FsyncPolicy) -> io::Result<()> {
+        let sealed;
+        let record = match &mut self.sealer {
+            Some(sealer) => {
+                // An index is never reused, even for a record that failed
+                // to be written.
+                let index = sealer.next;
+                sealer.next += 1;
+                let mut data = record.to_vec();
+                sealer.key.seal(index, &mut data);
+                sealed = [
+                    &(data.len() as u32).to_le_bytes()[..],
+                    &index.to_le_bytes(),
+                    &data,
+                ]
+                .concat();
+                &sealed
+            }
+            None => record,
+        };
+        let result = self.file.write_all(record).and_then(|()| match fsync {
+            FsyncPolicy::Always => self.file.sync_data(),
+            _ => Ok(()),
+        });
+        match result {
+            Ok(()) => {
+                self.len += record.len() as u64;
+                Ok(())
+            }
+            Err(e) => {
+                let _ = self.file.set_len(self.len);
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Makes files created or renamed in `dir` survive a crash.
+pub fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+fn segment_path(dir: &Path, generation: u64) -> PathBuf {
+    dir.join(format!("{WAL_PREFIX}{generation}"))
+}
+
+/// Generations of the WAL segments in `dir`, oldest first.
+fn segments(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut generations = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        if let Some(generation) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(WAL_PREFIX))
+            .and_then(|suffix| suffix.parse().ok())
+        {
+            generations.push(generation);
+        }
+    }
+    generations.sort_unstable();
+    Ok(generations)
+}
+
+/// Reads a segment's records, truncating a torn final record. Returns the
+/// open file positioned for appending, unless the segment is sealed: a torn
+/// record's index may already have sealed bytes that survive elsewhere, so
+/// nothing is sealed into the segment again and its key is not kept.
+fn read_segment(
+    dir: &Path,
+    generation: u64,
+    keyring: Option<&Keyring>,
+    recovered: &mut Vec<Mutation>,
+) -> io::Result<Option<Wal>> {
+    let path = segment_path(dir, generation);
+    let mut file = OpenOptions::new().read(true).append(true).open(&path)?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    let Some(Decoded {
+        complete,
+        sealed,
+        damage,
+    }) = decode_segment(&path, &bytes, generation, keyring, recovered)?
+    else {
+        drop(file);
+        return Wal::create(dir, generation, keyring).map(Some);
+    };
+    if let Some(damage) = damage {
+        return Err(damaged(&path, damage));
+    }
+    if complete != bytes.len() {
+        tracing::warn!(
+            bytes = bytes.len() - complete,
+            segment = %path.display(),
+            "discarding torn record at the end of the WAL"
+        );
+        file.set_len(complete as u64)?;
+    }
+    Ok((!sealed).then_some(Wal {
+        file,
+        len: complete as u64,
+        generation,
+        sealer: None,
+    }))
+}
+
+/// Reads a segment's complete records without modifying the file.
+fn read_records(
+    dir: &Path,
+    generation: u64,
+    keyring: Option<&Keyring>,
+    records: &mut Vec<Mutation>,
+) -> io::Result<()> {
+    let path = segment_path(dir, generation);
+    let bytes = fs::read(&path)?;
+    match decode_segment(&path, &bytes, generation, keyring, records)? {
+        Some(Decoded {
+            damage: Some(damage),
+            ..
+        }) => Err(damaged(&path, damage)),
+        _ => Ok(()),
+    }
+}
+
+/// What [`decode_segment`] read of a segment.
+struct Decoded {
+    /// Where the last complete record ends.
+    complete: usize,
+    /// Whether the segment is sealed.
+    sealed: bool,
+    /// Why the bytes from `complete` on are not a record, when they are
+    /// not just one cut short.
+    damage: Option<String>,
+}
+
+fn damaged(path: &Path, damage: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {damage}", path.display()),
+    )
+}
+
+/// Decodes the complete records of the segment `bytes`, stopping at the
+/// first that cannot be read. `None` if the segment is shorter than its
+/// header; an error only if it is sealed under keys that are not given.
+fn decode_segment(
+    path: &Path,
+    bytes: &[u8],
+    generation: u64,
+    keyring: Option<&Keyring>,
+    records: &mut Vec<Mutation>,
+) -> io::Result<Option<Decoded>> {
+    let invalid = |message: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {message}", path.display()),
+        )
+    };
+    let sealed = bytes.starts_with(SEALED_WAL_MAGIC);
+    let header_len = match sealed {
+        true => SEALED_WAL_HEADER_LEN,
+        false => WAL_HEADER_LEN,
+    } as usize;
+    if bytes.len() < header_len {
+        return Ok(None);
+    }
+    let damaged = |complete, damage: String| {
+        Ok(Some(Decoded {
+            complete,
+            sealed,
+            damage: Some(damage),
+        }))
+    };
+    if (!sealed && &bytes[..4] != WAL_MAGIC) || bytes[4..12] != generation.to_le_bytes() {
+        return damaged(0, "not a map8x32 WAL segment".to_string());
+    }
+    let mut complete = header_len;
+    if !sealed {
+        loop {
+            match Mutation::decode(&bytes[complete..]) {
+                Ok(Some((mutation, len))) => {
+                    records.push(mutation);
+                    complete += len;
+                }
+                Ok(None) => break,
+                Err(e) => return damaged(complete, e.to_string()),
+            }
+        }
+        return Ok(Some(Decoded {
+            complete,
+            sealed,
+            damage: None,
+        }));
+    }
+
+    let keyring = keyring.ok_or_else(|| {
+        invalid("the segment is encrypted but no encryption keys are configured".to_string())
+    })?;
+    let key = keyring
+        .open_file(Purpose::Wal, bytes[12..header_len].try_into().unwrap())
+        .map_err(|e| invalid(e.to_string()))?;
+    let mut next = 0;
+    while let Some(head) = bytes.get(complete..complete + 12) {
+        let len = u32::from_le_bytes(head[..4].try_into().unwrap()) as usize;
+        let index = u64::from_le_bytes(head[4..].try_into().unwrap());
+        let Some(sealed) = bytes.get(complete + 12..complete + 12 + len) else {
+            break;
+        };
+        let damage = if index < next {
+            Some(format!(
+                "record {index} repeats or precedes record {}",
+                next - 1
+            ))
+        } else if len < TAG_LEN {
+            Some(format!("record {index} is out of place"))
+        } else {
+            let mut data = sealed.to_vec();
+            match key.open(index, &mut data) {
+                Err(e) => Some(e.to_string()),
+                Ok(plain) => match Mutation::decode(plain) {
+                    Ok(Some((mutation, used))) if used == plain.len() => {
+                        records.push(mutation);
+                        None
+                    }
+                    _ => Some(format!("record {index} is not one mutation")),
+                },
+            }
+        };
+        if damage.is_some() {
+            return Ok(Some(Decoded {
+                complete,
+                sealed: true,
+                damage,
+            }));
+        }
+        next = index + 1;
+        complete += 12 + len;
+    }
+    Ok(Some(Decoded {
+        complete,
+        sealed: true,
+        damage: None,
+    }))
+}
+
+/// What [`check`] or [`repair`] found in each file of a data directory:
+/// the snapshot first, then the WAL segments oldest first.
+#[derive(Debug, Default)]
+pub struct Integrity {
+    pub files: Vec<FileIntegrity>,
+}
+
+#[derive(Debug)]
+pub struct FileIntegrity {
+    pub path: PathBuf,
+    pub state: FileState,
+    /// Records that read back intact.
+    pub recovered: usize,
+    /// Bytes that are not records startup can read, and so are dropped.
+    pub discarded_bytes: u64,
+    /// Where a repair kept the discarded bytes.
+    pub kept_in: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileState {
+    /// Every byte is a record that reads back.
+    Intact,
+    /// Ends in a record cut short, as a crash part-way through an append
+    /// leaves; startup drops it.
+    Torn,
+    /// A segment the snapshot already holds, which startup deletes unread.
+    Covered,
+    /// Holds bytes that are not a record, which stop startup.
+    Corrupt(String),
+    /// A segment after a corrupt one, whose records build on those lost.
+    AfterCorrupt,
+    /// A segment between the snapshot and the newest one that is gone.
+    Missing,
+}
+
+impl Integrity {
+    /// Whether startup would read every record and drop nothing.
+    pub fn is_clean(&self) -> bool {
+        self.files
+            .iter()
+            .all(|file| matches!(file.state, FileState::Intact | FileState::Covered))
+    }
+}
+
+impl FileIntegrity {
+    fn new(path: PathBuf, state: FileState) -> Self {
+        FileIntegrity {
+            path,
+            state,
+            recovered: 0,
+            discarded_bytes: 0,
+            kept_in: None,
+        }
+    }
+}
+
+impl fmt::Display for Integrity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for file in &self.files {
+            write!(f, "{}: ", file.path.display())?;
+            match &file.state {
+                FileState::Intact => write!(f, "intact")?,
+                FileState::Torn => write!(f, "ends in a torn record")?,
+                FileState::Covered => write!(f, "covered by the snapshot")?,
+                FileState::Corrupt(reason) => write!(f, "corrupt ({reason})")?,
+                FileState::AfterCorrupt => write!(f, "follows a corrupt segment")?,
+                FileState::Missing => write!(f, "missing")?,
+            }
+            if !matches!(file.state, FileState::Covered | FileState::Missing) {
+                write!(f, ", {} records recovered", file.recovered)?;
+            }
+            if file.discarded_bytes > 0 {
+                write!(f, ", {} bytes discarded", file.discarded_bytes)?;
+            }
+            if let Some(kept_in) = &file.kept_in {
+                write!(f, ", kept in {}", kept_in.display())?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads every file of the data directory `dir` as startup would, without
+/// changing any of them. Fails only when a file cannot be read at all, or
+/// is sealed under keys `keyring` does not hold.
+pub fn check(dir: &Path, keyring: Option<&Keyring>) -> io::Result<Integrity> {
+    inspect(dir, keyring, false)
+}
+
+/// Checks `dir` like [`check`], then makes it one startup reads: a corrupt
+/// snapshot is moved aside, a segment's corrupt or torn tail is cut off,
+/// and the segments after a corrupt one are moved aside, each kept next to
+/// where it was with a `.discarded` suffix.
+pub fn repair(dir: &Path, keyring: Option<&Keyring>) -> io::Result<Integrity> {
+    let integrity = inspect(dir, keyring, true)?;
+    sync_dir(dir)?;
+    Ok(integrity)
+}
+
+fn inspect(dir: &Path, keyring: Option<&Keyring>, repair: bool) -> io::Result<Integrity> {
+    let mut integrity = Integrity::default();
+    let path = dir.join(SNAPSHOT_FILE);
+    let mut snapshot_generation = None;
+    match fs::read(&path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+        Ok(bytes) => {
+            let len = bytes.len() as u64;
+            let key = snapshot_key(&bytes, keyring)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+            let mut file = FileIntegrity::new(path.clone(), FileState::Intact);
+            match open_snapshot(bytes, key.as_ref()).and_then(|bytes| decode_snapshot(&bytes)) {
+                Ok((generation, mutations)) => {
+                    snapshot_generation = Some(generation);
+                    file.recovered = mutations.len();
+                }
+                Err(e) => {
+                    file.state = FileState::Corrupt(e.to_string());
+                    file.discarded_bytes = len;
+                    if repair {
+                        let kept_in = set_aside(&path)?;
+                        fs::rename(&path, &kept_in)?;
+                        file.kept_in = Some(kept_in);
+                    }
+                }
+            }
+            integrity.files.push(file);
+        }
+    }
+
+    let mut corrupt = false;
+    let mut expected = snapshot_generation;
+    for generation in segments(dir)? {
+        let path = segment_path(dir, generation);
+        if generation < snapshot_generation.unwrap_or(0) {
+            integrity
+                .files
+                .push(FileIntegrity::new(path, FileState::Covered));
+            continue;
+        }
+        for missing in expected.unwrap_or(generation)..generation {
+            integrity.files.push(FileIntegrity::new(
+                segment_path(dir, missing),
+                FileState::Missing,
+            ));
+        }
+        expected = Some(generation + 1);
+
+        let bytes = fs::read(&path)?;
+        if corrupt {
+            let mut file = FileIntegrity::new(path.clone(), FileState::AfterCorrupt);
+            file.discarded_bytes = bytes.len() as u64;
+            if repair {
+                let kept_in = set_aside(&path)?;
+                fs::rename(&path, &kept_in)?;
+                file.kept_in = Some(kept_in);
+            }
+            integrity.files.push(file);
+            continue;
+        }
+        let mut records = Vec::new();
+        let mut file = FileIntegrity::new(path.clone(), FileState::Intact);
+        // A segment shorter than its header holds no records, and startup
+        // writes the header again.
+        let complete = match decode_segment(&path, &bytes, generation, keyring, &mut records)? {
+            None => bytes.len(),
+            Some(decoded) => {
+                if let Some(damage) = decoded.damage {
+                    file.state = FileState::Corrupt(damage);
+                    corrupt = true;
+                } else if decoded.complete < bytes.len() {
+                    file.state = FileState::Torn;
+                }
+                decoded.complete
+            }
+        };
+        file.recovered = records.len();
+        file.discarded_bytes = (bytes.len() - complete) as u64;
+        if repair && complete < bytes.len() {
+            let kept_in = set_aside(&path)?;
+            fs::write(&kept_in, &bytes[complete..])?;
+            let segment = OpenOptions::new().write(true).open(&path)?;
+            segment.set_len(complete as u64)?;
+            segment.sync_all()?;
+            file.kept_in = Some(kept_in);
+        }
+        integrity.files.push(file);
+    }
+    Ok(integrity)
+}
+
+/// A free path next to `path` to keep what a repair discards from it.
+fn set_aside(path: &Path) -> io::Result<PathBuf> {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".discarded");
+    let mut kept_in = PathBuf::from(&name);
+    let mut n = 1;
+    while fs::exists(&kept_in)? {
+        kept_in = PathBuf::from(format!("{}.{n}", name.to_string_lossy()));
+        n += 1;
+    }
+    Ok(kept_in)
+}
+
+/// Write-ahead log plus periodic snapshots of the whole store.
+///
+/// Every mutation is appended to the WAL before it is applied in memory,
+/// while holding the WAL lock. The WAL is split into numbered segments: a
+/// snapshot starts a new segment under the lock, captures the store as of
+/// that point, and is written out while writers carry on into the new
+/// segment. A snapshot of generation `n` covers every segment before `n`.
+#[derive(Debug)]
+pub struct Persistence {
+    dir: PathBuf,
+    wal: Mutex<Wal>,
+    policy: FailurePolicy,
+    fsync: FsyncPolicy,
+    health: AtomicU8,
+    snapshot_generation: AtomicU64,
+    /// Held while a finished snapshot replaces the segments it covers.
+    files: Mutex<()>,
+    /// Seconds between periodic snapshots; zero disables them.
+    pub snapshot_interval: AtomicU64,
+    /// Size of the active segment that triggers a snapshot regardless of
+    /// the interval; zero means no limit.
+    pub wal_max_bytes: AtomicU64,
+    pub failures: AtomicU64,
+    pub snapshots: AtomicU64,
+    /// Seals new snapshots and segments, and opens sealed ones.
+    keyring: Option<Arc<Keyring>>,
+}
+
+pub type WalGuard<'a> = Option<MutexGuard<'a, Wal>>;
+
+impl Persistence {
+    /// Opens (creating if needed) the data directory and returns the
+    /// persistence handle along with the mutations needed to rebuild the
+    /// store: the snapshot contents followed by the newer WAL segments.
+    /// With a `keyring`, files are sealed as they are written, and plain
+    /// ones are still read.
+    pub fn open(
+        dir: &Path,
+        policy: FailurePolicy,
+        fsync: FsyncPolicy,
+        keyring: Option<Arc<Keyring>>,
+    ) -> io::Result<(Persistence, Vec<Mutation>)> {
+        fs::create_dir_all(dir)?;
+        let (snapshot_generation, mut recovered) =
+            read_snapshot(&dir.join(SNAPSHOT_FILE), keyring.as_deref())?;
+
+        let mut active = None;
+        for generation in segments(dir)? {
+            if generation < snapshot_generation {
+                fs::remove_file(segment_path(dir, generation))?;
+            } else {
+                let wal = read_segment(dir, generation, keyring.as_deref(), &mut recovered)?;
+                active = Some((generation, wal));
+            }
+        }
+        let wal = match active {
+            Some((_, Some(wal))) if wal.sealer.is_none() && keyring.is_none() => wal,
+            // A sealed segment is not appended to after a restart, so no
+            // index is sealed twice, not even one a crash cut short: the
+            // next record is sealed in a new segment, under a new salt. Nor
+            // is a plain one once keys are configured.
+            Some((generation, _)) => Wal::create(dir, generation + 1, keyring.as_deref())?,
+            None => Wal::create(dir, snapshot_generation, keyring.as_deref())?,
+        };
+
+        let persistence = Persistence {
+            dir: dir.to_path_buf(),
+            wal: Mutex::new(wal),
+            policy,
+            fsync,
+            health: AtomicU8::new(Health::Healthy as u8),
+            snapshot_generation: AtomicU64::new(snapshot_generation),
+            files: Mutex::new(()),
+            snapshot_interval: AtomicU64::new(0),
+            wal_max_bytes: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            snapshots: AtomicU64::new(0),
+            keyring,
+        };
+        Ok((persistence, recovered))
+    }
+
+    pub fn health(&self) -> Health {
+        Health::from_u8(self.health.load(Ordering::Acquire)).unwrap_or(Health::Healthy)
+    }
+
+    /// The health and failure count INFO reports.
+    pub fn state(&self) -> PersistenceState {
+        PersistenceState {
+            health: self.health(),
+            failures: self.failures.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn clear_poison(&self) {
+        self.wal.clear_poison();
+        self.files.clear_poison();
+    }
+
+    pub fn wal_len(&self) -> u64 {
+        self.wal.lock().unwrap().len
+    }
+
+    /// Whether the active segment has reached `wal_max_bytes`.
+    fn wal_full(&self) -> bool {
+        let max = self.wal_max_bytes.load(Ordering::Relaxed);
+        max > 0 && self.wal_len() >= max
+    }
+
+    /// The active segment's generation and the offset the next append lands at.
+    pub fn wal_position(&self) -> (u64, u64) {
+        let wal = self.wal.lock().unwrap();
+        (wal.generation, wal.len)
+    }
+
+    fn set_health(&self, health: Health) {
+        let previous = Health::from_u8(self.health.swap(health as u8, Ordering::AcqRel))
+            .unwrap_or(Health::Healthy);
+        if previous != health {
+            tracing::warn!(
+                from = previous.name(),
+                to = health.name(),
+                "persistence state changed"
+            );
+        }
+    }
+
+    fn fail(&self, what: &str, error: &io::Error) -> Health {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        tracing::error!(%error, "ALERT persistence failure during {what}");
+        let health = match self.policy {
+            FailurePolicy::ReadOnly => Health::ReadOnly,
+            FailurePolicy::MemoryOnly => Health::MemoryOnly,
+            FailurePolicy::ShedWrites => Health::Shedding,
+        };
+        self.set_health(health);
+        health
+    }
+
+    /// Takes the WAL lock, which serializes every mutation against the
+    /// others and against snapshots. Returns `None` in memory-only mode and
+    /// the status to refuse writes with in read-only mode.
+    pub fn lock(&self) -> Result<WalGuard<'_>, u8> {
+        match self.health() {
+            Health::ReadOnly => Err(STATUS_READONLY),
+            Health::MemoryOnly => Ok(None),
+            Health::Healthy | Health::Shedding => Ok(Some(self.wal.lock().unwrap())),
+        }
+    }
+
+    /// Appends `mutation` through a guard from [`Persistence::lock`], which
+    /// must then be held until the mutation has been applied in memory. On
+    /// failure the status to answer the write with is returned instead.
+    pub fn append(&self, wal: &mut WalGuard<'_>, mutation: &Mutation) -> Result<(), u8> {
+        let Some(file) = wal else {
+            return Ok(());
+        };
+        match file.append(&mutation.encode(), self.fsync) {
+            Ok(()) => {
+                if self.health() == Health::Shedding {
+                    self.set_health(Health::Healthy);
+                }
+                Ok(())
+            }
+            Err(e) => match self.fail("WAL append", &e) {
+                Health::MemoryOnly => {
+                    *wal = None;
+                    Ok(())
+                }
+                Health::ReadOnly => Err(STATUS_READONLY),
+                _ => Err(STATUS_UNAVAILABLE),
+            },
+        }
+    }
+
+    pub fn log(&self, mutation: &Mutation) -> Result<WalGuard<'_>, u8> {
+        let mut wal = self.lock()?;
+        self.append(&mut wal, mutation)?;
+        Ok(wal)
+    }
+
+    /// Where the latest snapshot is. A new one replaces it in one rename, so
+    /// a reader that opened the file keeps reading the one it opened.
+    pub fn snapshot_path(&self) -> PathBuf {
+        self.dir.join(SNAPSHOT_FILE)
+    }
+
+    /// Writes a snapshot of `database` and drops the WAL segments it covers.
+    /// Writers are only paused while the store is captured, not while the
+    /// snapshot is written.
+    pub fn snapshot(&self, database: &Database) -> io::Result<()> {
+        if self.health() == Health::MemoryOnly {
+            return Ok(());
+        }
+        let result = self.write_snapshot(database);
+        match &result {
+            Ok(true) => {
+                self.snapshots.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(false) => {}
+            Err(e) => {
+                self.fail("snapshot", e);
+            }
+        }
+        result.map(|_| ())
+    }
+
+    fn write_snapshot(&self, database: &Database) -> io::Result<bool> {
+        let mut wal = self.wal.lock().unwrap();
+        if wal.is_empty() && wal.generation == self.snapshot_generation.load(Ordering::Acquire) {
+            return Ok(false);
+        }
+        let generation = wal.generation + 1;
+        wal.file.sync_data()?;
+        *wal = Wal::create(&self.dir, generation, self.keyring.as_deref())?;
+        let capture = capture(&self.dir, self.keyring.as_deref(), generation, database);
+        drop(wal);
+        let _files = self.files.lock().unwrap();
+        capture.finish()?;
+
+        self.snapshot_generation
+            .store(generation, Ordering::Release);
+        for old in segments(&self.dir)? {
+            if old < generation {
+                fs::remove_file(segment_path(&self.dir, old))?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Replays the snapshot and WAL on disk for `key` alone and returns the
+    /// value type and values it held just before the latest DELETE_BY_KEY,
+    /// DELETE_ALL or emptying REPLACE_IF that removed it, or `None` if no
+    /// such deletion is recorded since the snapshot. Appends are trimmed to
+    /// `cap` as they were when applied. Call with the WAL lock held so the
+    /// log cannot move underneath.
+    pub fn deleted_values(&self, key: u8, caps: &Caps) -> io::Result<Option<(u8, Vec<u32>)>> {
+        let _files = self.files.lock().unwrap();
+        let keyring = self.keyring.as_deref();
+        let (generation, mut history) = read_snapshot(&self.dir.join(SNAPSHOT_FILE), keyring)?;
+        for segment in segments(&self.dir)? {
+            if segment >= generation {
+                read_records(&self.dir, segment, keyring, &mut history)?;
+            }
+        }
+
+        // Every key is followed, since RENAME can carry another key's values
+        // into this one.
+        let mut keys: HashMap<u8, KeyHistory> = HashMap::new();
+        let mut deleted = None;
+        for mutation in history {
+            // The key the mutation deleted, if any.
+            let removed = match mutation {
+                Mutation::Set { key, value } => {
+                    keys.entry(key).or_default().append(value, 0, caps.get(key));
+                    None
+                }
+                Mutation::SetExpiring {
+                    key,
+                    value,
+                    deadline,
+                } => {
+                    keys.entry(key)
+                        .or_default()
+                        .append(value, deadline, caps.get(key));
+                    None
+                }
+                Mutation::InsertSorted { key, value } => {
+                    let history = keys.entry(key).or_default();
+                    let index = history
+                        .values
+                        .partition_point(|existing| *existing <= value);
+                    history.values.insert(index, value);
+                    history.deadlines.insert(index, 0);
+                    db::trim(&mut history.values, caps.get(key));
+                    db::trim(&mut history.deadlines, caps.get(key));
+                    None
+                }
+                Mutation::Push { key, values } => {
+                    let history = keys.entry(key).or_default();
+                    for value in values {
+                        history.append(value, 0, caps.get(key));
+                    }
+                    None
+                }
+                Mutation::Replace { key, values } if values.is_empty() => Some(key),
+                Mutation::Replace { key, values } => {
+                    keys.insert(
+                        key,
+                        KeyHistory {
+                            value_type: VALUE_TYPE_U32,
+                            deadlines: vec![0; values.len()],
+                            values,
+                        },
+                    );
+                    None
+                }
+                Mutation::SetTyped {
+                    key,
+                    value_type,
+                    words,
+                    replace,
+                } => {
+                    let history = keys.entry(key).or_default();
+                    if replace {
+                        history.values.clear();
+                    }
+                    if !(words.is_empty() && history.values.is_empty()) {
+                        history.values.extend(words);
+                        history.value_type = value_type;
+                    }
+                    history.deadlines.resize(history.values.len(), 0);
+                    None
+                }
+                Mutation::RemoveAt { key, index, at_ms } => {
+                    if let Some(history) = keys.get_mut(&key) {
+                        let position = history
+                            .deadlines
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, deadline)| !db::expired(**deadline, at_ms))
+                            .nth(index as usize)
+                            .map(|(position, _)| position);
+                        if let Some(position) = position {
+                            history.values.remove(position);
+                            history.deadlines.remove(position);
+                        }
+                        if history.values.is_empty() {
+                            keys.remove(&key);
+                        }
+                    }
+                    None
+                }
+                Mutation::Rename { from, to, merge } => {
+                    if let Some(source) = keys.remove(&from) {
+                        place(&mut keys, to, source, merge);
+                    }
+                    None
+                }
+                Mutation::Copy { from, to, append } => {
+                    if let Some(source) = keys.get(&from).cloned() {
+                        place(&mut keys, to, source, append);
+                    }
+                    None
+                }
+                Mutation::Swap { a, b } => {
+                    let (from_a, from_b) = (keys.remove(&a), keys.remove(&b));
+                    if let Some(history) = from_b {
+                        keys.insert(a, history);
+                    }
+                    if let Some(history) = from_a {
+                        keys.insert(b, history);
+                    }
+                    None
+                }
+                Mutation::Persist { key, at_ms } => {
+                    if let Some(history) = keys.get_mut(&key) {
+                        history.rewrite_deadlines(at_ms, |_| 0);
+                        if history.values.is_empty() {
+                            keys.remove(&key);
+                        }
+                    }
+                    None
+                }
+                Mutation::RefreshExpiry {
+                    key,
+                    deadline,
+                    at_ms,
+                } => {
+                    if let Some(history) = keys.get_mut(&key) {
+                        history.rewrite_deadlines(at_ms, |old| match old {
+                            0 => 0,
+                            _ => deadline,
+                        });
+                        if history.values.is_empty() {
+                            keys.remove(&key);
+                        }
+                    }
+                    None
+                }
+                Mutation::Dedup { key, at_ms } => {
+                    if let Some(history) = keys.get_mut(&key) {
+                        db::dedup(&mut history.values, Some(&mut history.deadlines), at_ms);
+                    }
+                    None
+                }
+                Mutation::Sort { key, descending } => {
+                    if let Some(history) = keys.get_mut(&key) {
+                        db::sort(
+                            &mut history.values,
+                            Some(&mut history.deadlines),
+                            descending,
+                        );
+                    }
+                    None
+                }
+                Mutation::Tag { .. } => None,
+                Mutation::RollUp {
+                    key,
+                    before_ms,
+                    window_ms,
+                    keep,
+                } => {
+                    if let Some(history) = keys.get_mut(&key) {
+                        let rolled =
+                            timed::roll_up(&history.values, before_ms, window_ms, keep as usize);
+                        if let (VALUE_TYPE_TIMED, Some((_, words))) = (history.value_type, rolled) {
+                            history.deadlines = vec![0; words.len()];
+                            history.values = words;
+                        }
+                    }
+                    None
+                }
+                Mutation::DeleteKey { key } => Some(key),
+                Mutation::DeleteAll => {
+                    if let Some(history) = keys.remove(&key) {
+                        deleted = history.restorable().or(deleted);
+                    }
+                    keys.clear();
+                    None
+                }
+            };
+            if let Some(removed) = removed {
+                let history = keys.remove(&removed);
+                if removed == key {
+                    deleted = history.and_then(KeyHistory::restorable).or(deleted);
+                }
+            }
+        }
+        Ok(deleted)
+    }
+
+    pub fn sync(&self) {
+        if self.health() == Health::MemoryOnly {
+            return;
+        }
+        let wal = self.wal.lock().unwrap();
+        if let Err(e) = wal.file.sync_data() {
+            drop(wal);
+            self.fail("WAL fsync", &e);
+        }
+    }
+}
+
+/// A point-in-time copy of the store on its way to disk.
+enum Capture<'a> {
+    Copied {
+        dir: &'a Path,
+        keyring: Option<&'a Keyring>,
+        generation: u64,
+        entries: Vec<(u8, Vec<u32>)>,
+        value_types: Vec<(u8, u8)>,
+        deadlines: Vec<(u8, Vec<u64>)>,
+        tags: Vec<(u8, u8)>,
+    },
+    #[cfg(all(feature = "fork-snapshot", unix))]
+    Forked(libc::pid_t),
+}
+
+impl Capture<'_> {
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Capture::Copied {
+                dir,
+                keyring,
+                generation,
+                entries,
+                value_types,
+                deadlines,
+                tags,
+            } => write_snapshot_file(
+                dir,
+                keyring,
+                generation,
+                &entries,
+                &value_types,
+                &deadlines,
+                &tags,
+            ),
+            #[cfg(all(feature = "fork-snapshot", unix))]
+            Capture::Forked(child) => wait_for_child(child),
+        }
+    }
+}
+
+fn copy_entries(database: &Database) -> Vec<(u8, Vec<u32>)> {
+    database.map.collect(|key, values| (key, values.to_vec()))
+}
+
+/// The keys holding something other than u32s, with their value types.
+fn copy_value_types(entries: &[(u8, Vec<u32>)], database: &Database) -> Vec<(u8, u8)> {
+    entries
+        .iter()
+        .map(|(key, _)| (*key, database.value_type(*key)))
+        .filter(|&(_, value_type)| value_type != VALUE_TYPE_U32)
+        .collect()
+}
+
+#[cfg(not(all(feature = "fork-snapshot", unix)))]
+fn capture<'a>(
+    dir: &'a Path,
+    keyring: Option<&'a Keyring>,
+    generation: u64,
+    database: &Database,
+) -> Capture<'a> {
+    let entries = copy_entries(database);
+    Capture::Copied {
+        dir,
+        keyring,
+        generation,
+        value_types: copy_value_types(&entries, database),
+        deadlines: database.copy_deadlines(),
+        tags: database.copy_tags(),
+        entries,
+    }
+}
+
+/// Forks a child that inherits a copy-on-write image of the store and
+/// writes the snapshot from it, so the parent only pauses for the fork.
+/// Must be called with the WAL lock held, which guarantees no mutation is
+/// half-applied in the image.
+#[cfg(all(feature = "fork-snapshot", unix))]
+fn capture<'a>(
+    dir: &'a Path,
+    keyring: Option<&'a Keyring>,
+    generation: u64,
+    database: &Database,
+) -> Capture<'a> {
+    // SAFETY: the child only reads the inherited store and writes files
+    // before `_exit`; it never returns into the runtime or runs destructors.
+    match unsafe { libc::fork() } {
+        -1 => {
+            let error = io::Error::last_os_error();
+            tracing::warn!(%error, "fork for snapshot failed; copying the store instead");
+            let entries = copy_entries(database);
+            Capture::Copied {
+                dir,
+                keyring,
+                generation,
+                value_types: copy_value_types(&entries, database),
+                deadlines: database.copy_deadlines(),
+                tags: database.copy_tags(),
+                entries,
+            }
+        }
+        0 => {
+            let entries = copy_entries(database);
+            let value_types = copy_value_types(&entries, database);
+            let deadlines = database.copy_deadlines();
+            let tags = database.copy_tags();
+            let code = match write_snapshot_file(
+                dir,
+                keyring,
+                generation,
+                &entries,
+                &value_types,
+                &deadlines,
+                &tags,
+            ) {
+                Ok(()) => 0,
+                Err(e) => {
+                    // Not through tracing: its locks may have been held by
+                    // another thread at the fork.
+                    eprintln!("map8x32: snapshot child failed: {e}");
+                    1
+                }
+            };
+            unsafe { libc::_exit(code) }
+        }
+        pid => Capture::Forked(pid),
+    }
+}
+
+#[cfg(all(feature = "fork-snapshot", unix))]
+fn wait_for_child(pid: libc::pid_t) -> io::Result<()> {
+    let mut status = 0;
+    loop {
+        // SAFETY: `pid` is a child of this process that has not been reaped.
+        if unsafe { libc::waitpid(pid, &mut status, 0) } != -1 {
+            break;
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+    if libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::other("snapshot child process failed"))
+    }
+}
+
+fn read_snapshot(path: &Path, keyring: Option<&Keyring>) -> io::Result<(u64, Vec<Mutation>)> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((0, Vec::new())),
+        Err(e) => return Err(e),
+    };
+    snapshot_key(&bytes, keyring)
+        .and_then(|key| open_snapshot(bytes, key.as_ref()))
+        .and_then(|bytes| decode_snapshot(&bytes))
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))
+}
+
+/// The key the snapshot `bytes` is sealed under, `None` if it is plain or
+/// too short to say.
+fn snapshot_key(bytes: &[u8], keyring: Option<&Keyring>) -> io::Result<Option<FileKey>> {
+    let Some(header) = bytes
+        .strip_prefix(SEALED_SNAPSHOT_MAGIC)
+        .and_then(|sealed| sealed.get(..KEY_HEADER_LEN))
+    else {
+        return Ok(None);
+    };
+    let keyring = keyring.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the snapshot is encrypted but no encryption keys are configured",
+        )
+    })?;
+    keyring
+        .open_file(Purpose::Snapshot, header.try_into().unwrap())
+        .map(Some)
+}
+
+/// The plain snapshot in `bytes`, decrypted with `key` if it is sealed.
+fn open_snapshot(mut bytes: Vec<u8>, key: Option<&FileKey>) -> io::Result<Vec<u8>> {
+    if !bytes.starts_with(SEALED_SNAPSHOT_MAGIC) {
+        return Ok(bytes);
+    }
+    // Sealed files too short for their key header have no key.
+    let key = key.ok_or_else(snapshot_truncated)?;
+    let header_len = SEALED_SNAPSHOT_MAGIC.len() + KEY_HEADER_LEN;
+    let plain = key.open(0, &mut bytes[header_len..])?.len();
+    bytes.drain(..header_len);
+    bytes.truncate(plain);
+    Ok(bytes)
+}
+
+/// Parses a snapshot into its generation and the mutations that load it,
+/// checking the version and checksum before anything else. Legacy snapshots
+/// have neither, and may end after any section older ones lacked.
+fn decode_snapshot(bytes: &[u8]) -> io::Result<(u64, Vec<Mutation>)> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let (body, legacy) = if let Some(body) = bytes.strip_prefix(LEGACY_SNAPSHOT_MAGIC) {
+        (body, true)
+    } else if let Some(rest) = bytes.strip_prefix(SNAPSHOT_MAGIC) {
+        let (&version, rest) = rest.split_first().ok_or_else(snapshot_truncated)?;
+        if version > SNAPSHOT_VERSION {
+            return Err(invalid(format!(
+                "snapshot format version {version} was written by a newer server (this one reads up to {SNAPSHOT_VERSION})"
+            )));
+        }
+        if version != SNAPSHOT_VERSION {
+            return Err(invalid(format!(
+                "snapshot format version {version} is not supported"
+            )));
+        }
+        let (body, checksum) = rest
+            .split_last_chunk::<4>()
+            .ok_or_else(snapshot_truncated)?;
+        if crc32(&bytes[..bytes.len() - 4]) != u32::from_le_bytes(*checksum) {
+            return Err(invalid("snapshot checksum mismatch".to_string()));
+        }
+        (body, false)
+    } else {
+        return Err(invalid("not a map8x32 snapshot".to_string()));
+    };
+    let mut body = Sections(body);
+    let generation = u64::from_le_bytes(body.take(8)?.try_into().unwrap());
+
+    let mut mutations = Vec::new();
+    let mut spans = [None; 256];
+    for _ in 0..body.count()? {
+        let key = body.take(1)?[0];
+        let len = body.count()?;
+        if spans[key as usize].is_some() {
+            return Err(invalid(format!("key {key} is listed twice")));
+        }
+        spans[key as usize] = Some((mutations.len(), len));
+        let values = body.take(len.checked_mul(4).ok_or_else(snapshot_truncated)?)?;
+        mutations.extend(values.chunks_exact(4).map(|value| Mutation::Set {
+            key,
+            value: u32::from_le_bytes(value.try_into().unwrap()),
+        }));
+    }
+    // Legacy snapshots written before typed values end here.
+    if legacy && body.is_empty() {
+        return Ok((generation, mutations));
+    }
+    for _ in 0..body.count()? {
+        let typed = body.take(2)?;
+        mutations.push(Mutation::SetTyped {
+            key: typed[0],
+            value_type: typed[1],
+            words: Vec::new(),
+            replace: false,
+        });
+    }
+    // And those written before expiring values here. Each key with an
+    // expiring value lists every value's expiry; the key's plain SETs above
+    // become SET_EXPIRINGs where one is set.
+    if legacy && body.is_empty() {
+        return Ok((generation, mutations));
+    }
+    for _ in 0..body.count()? {
+        let key = body.take(1)?[0];
+        let (start, len) = spans[key as usize].unwrap_or_default();
+        if body.count()? != len {
+            return Err(invalid(format!("key {key} has malformed expiries")));
+        }
+        let deadlines = body.take(len * 8)?;
+        for (mutation, deadline) in mutations[start..start + len]
+            .iter_mut()
+            .zip(deadlines.chunks_exact(8))
+        {
+            let deadline = u64::from_le_bytes(deadline.try_into().unwrap());
+            if let Mutation::Set { key, value } = *mutation {
+                if deadline != 0 {
+                    *mutation = Mutation::SetExpiring {
+                        key,
+                        value,
+                        deadline,
+                    };
+                }
+            }
+        }
+    }
+    // And those written before tags here.
+    if legacy && body.is_empty() {
+        return Ok((generation, mutations));
+    }
+    for _ in 0..body.count()? {
+        let tagged = body.take(2)?;
+        mutations.push(Mutation::Tag {
+            key: tagged[0],
+            tag: tagged[1],
+        });
+    }
+    if !body.is_empty() {
+        return Err(invalid("trailing bytes after the tags".to_string()));
+    }
+    Ok((generation, mutations))
+}
+
+fn snapshot_truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "truncated snapshot")
+}
+
+/// Takes a snapshot's fields from the front of what is left of it.
+struct Sections<'a>(&'a [u8]);
+
+impl<'a> Sections<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(snapshot_truncated());
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn count(&mut self) -> io::Result<usize> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as usize)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// The snapshot file is the magic, the format version (u8) and the WAL
+/// generation it covers up to (u64), then four sections, each a count
+/// (u32) and that many entries: every key (u8) with its value count (u32)
+/// and values (u32 each); each typed key (u8) with its value type (u8);
+/// each key with an expiring value (u8) with its expiry count (u32, the
+/// value count) and expiries (u64 each, 0 for none); and each tagged key
+/// (u8) with its tag (u8). A CRC-32 (IEEE) of everything before it ends the
+/// file. Integers are little-endian.
+fn encode_snapshot(
+    generation: u64,
+    entries: &[(u8, Vec<u32>)],
+    value_types: &[(u8, u8)],
+    deadlines: &[(u8, Vec<u64>)],
+    tags: &[(u8, u8)],
+) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(SNAPSHOT_MAGIC);
+    out.push(SNAPSHOT_VERSION);
+    out.extend_from_slice(&generation.to_le_bytes());
+    out.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+    for (key, values) in entries {
+        out.push(*key);
+        out.extend_from_slice(&(values.len() as u32).to_le_bytes());
+        for value in values {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    out.extend_from_slice(&(value_types.len() as u32).to_le_bytes());
+    for (key, value_type) in value_types {
+        out.extend_from_slice(&[*key, *value_type]);
+    }
+    out.extend_from_slice(&(deadlines.len() as u32).to_le_bytes());
+    for (key, deadlines) in deadlines {
+        out.push(*key);
+        out.extend_from_slice(&(deadlines.len() as u32).to_le_bytes());
+        for deadline in deadlines {
+            out.extend_from_slice(&deadline.to_le_bytes());
+        }
+    }
+    out.extend_from_slice(&(tags.len() as u32).to_le_bytes());
+    for (key, tag) in tags {
+        out.extend_from_slice(&[*key, *tag]);
+    }
+    let checksum = crc32(&out);
+    out.extend_from_slice(&checksum.to_le_bytes());
+    out
+}
+
+fn write_snapshot_file(
+    dir: &Path,
+    keyring: Option<&Keyring>,
+    generation: u64,
+    entries: &[(u8, Vec<u32>)],
+    value_types: &[(u8, u8)],
+    deadlines: &[(u8, Vec<u64>)],
+    tags: &[(u8, u8)],
+) -> io::Result<()> {
+    let tmp_path = dir.join(format!("{SNAPSHOT_FILE}.tmp"));
+    let result = (|| {
+        let mut snapshot = encode_snapshot(generation, entries, value_types, deadlines, tags);
+        if let Some(keyring) = keyring {
+            let (key, key_header) = keyring.seal_file(Purpose::Snapshot)?;
+            key.seal(0, &mut snapshot);
+            snapshot = [&SEALED_SNAPSHOT_MAGIC[..], &key_header, &snapshot].concat();
+        }
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&snapshot)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, dir.join(SNAPSHOT_FILE))?;
+        // The older segments are deleted next; the rename must be on disk
+        // before they are.
+        sync_dir(dir)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Background upkeep: WAL fsync, periodic snapshots, and repeated alerts
+/// while persistence is degraded.
+pub async fn maintain(database: Arc<Database>) {
+    let Some(persistence) = database.persistence.as_ref() else {
+        return;
+    };
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    let mut since_snapshot = Duration::ZERO;
+    let mut since_alert = Duration::ZERO;
+    loop {
+        tick.tick().await;
+        since_snapshot += Duration::from_secs(1);
+        since_alert += Duration::from_secs(1);
+
+        if persistence.fsync == FsyncPolicy::Everysec {
+            persistence.sync();
+        }
+        let snapshot_interval =
+            Duration::from_secs(persistence.snapshot_interval.load(Ordering::Relaxed));
+        if (!snapshot_interval.is_zero() && since_snapshot >= snapshot_interval)
+            || persistence.wal_full()
+        {
+            since_snapshot = Duration::ZERO;
+            let database = database.clone();
+            let _ = tokio::task::spawn_blocking(move || {
+                if let Some(persistence) = &database.persistence {
+                    let _ = persistence.snapshot(&database);
+                }
+            })
+            .await;
+        }
+        let health = persistence.health();
+        if health != Health::Healthy && since_alert >= ALERT_INTERVAL {
+            since_alert = Duration::ZERO;
+            tracing::error!(
+                state = health.name(),
+                failures = persistence.failures.load(Ordering::Relaxed),
+                "ALERT persistence is degraded"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh, empty directory for one test.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("map8x32-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Opens `dir` the way the server does at startup.
+    fn recover(dir: &Path) -> (Persistence, Database) {
+        let (persistence, recovered) =
+            Persistence::open(dir, FailurePolicy::ShedWrites, FsyncPolicy::Never, None).unwrap();
+        let database = Database::new(0, None);
+        for mutation in &recovered {
+            mutation.apply(&database);
+        }
+        (persistence, database)
+    }
+
+    fn write(persistence: &Persistence, database: &Database, mutation: Mutation) {
+        let _wal = persistence.log(&mutation).unwrap();
+        mutation.apply(database);
+    }
+
+    fn set(persistence: &Persistence, database: &Database, key: u8, value: u32) {
+        write(persistence, database, Mutation::Set { key, value });
+    }
+
+    fn contents(database: &Database) -> Vec<(u8, Vec<u32>)> {
+        database.consistent_copy().1
+    }
+
+    /// Every file in `dir` with its bytes, to put back later and so stage a
+    /// crash part-way through a snapshot.
+    fn files(dir: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                let bytes = fs::read(&path).unwrap();
+                (path, bytes)
+            })
+            .collect()
+    }
+
+    fn restore(files: &[(PathBuf, Vec<u8>)]) {
+        for (path, bytes) in files {
+            fs::write(path, bytes).unwrap();
+        }
+    }
+
+    #[test]
+    fn snapshot_compacts_the_log_into_a_new_segment() {
+        let dir = scratch_dir("compact");
+        let (persistence, database) = recover(&dir);
+        for value in 0..100 {
+            set(&persistence, &database, (value % 4) as u8, value);
+        }
+        write(&persistence, &database, Mutation::DeleteKey { key: 3 });
+        persistence.snapshot(&database).unwrap();
+        set(&persistence, &database, 9, 7);
+        let expected = contents(&database);
+        assert_eq!(segments(&dir).unwrap(), vec![1]);
+        assert_eq!(
+            persistence.wal_len(),
+            WAL_HEADER_LEN + FRAME_LEN as u64,
+            "only the tail after the snapshot stays in the log"
+        );
+        drop(persistence);
+
+        let (_, recovered) = recover(&dir);
+        assert_eq!(contents(&recovered), expected);
+    }
+
+    #[test]
+    fn delete_at_replays_against_the_values_live_when_it_ran() {
+        let dir = scratch_dir("delete-at");
+        let (persistence, database) = recover(&dir);
+        let at_ms = db::unix_ms();
+        let deadline = at_ms + 60_000;
+        write(
+            &persistence,
+            &database,
+            Mutation::SetExpiring {
+                key: 1,
+                value: 1,
+                deadline: 1,
+            },
+        );
+        write(
+            &persistence,
+            &database,
+            Mutation::SetExpiring {
+                key: 1,
+                value: 2,
+                deadline,
+            },
+        );
+        set(&persistence, &database, 1, 3);
+        write(
+            &persistence,
+            &database,
+            Mutation::RemoveAt {
+                key: 1,
+                index: 1,
+                at_ms,
+            },
+        );
+        assert_eq!(contents(&database), [(1, vec![1, 2])]);
+        drop(persistence);
+
+        // Replay counts the values live at `at_ms`, not those live now.
+        let (_, recovered) = recover(&dir);
+        assert_eq!(contents(&recovered), [(1, vec![1, 2])]);
+    }
+
+    #[test]
+    fn deleted_values_follow_a_rename_into_the_key() {
+        let dir = scratch_dir("rename-history");
+        let (persistence, database) = recover(&dir);
+        set(&persistence, &database, 1, 10);
+        set(&persistence, &database, 2, 20);
+        let rename = Mutation::Rename {
+            from: 1,
+            to: 2,
+            merge: true,
+        };
+        write(&persistence, &database, rename);
+        write(&persistence, &database, Mutation::DeleteKey { key: 2 });
+
+        let caps = Caps::default();
+        let restored = persistence.deleted_values(2, &caps).unwrap();
+        assert_eq!(restored, Some((VALUE_TYPE_U32, vec![20, 10])));
+        assert_eq!(persistence.deleted_values(1, &caps).unwrap(), None);
+    }
+
+    #[test]
+    fn tags_survive_a_snapshot() {
+        let dir = scratch_dir("tags");
+        let (persistence, database) = recover(&dir);
+        set(&persistence, &database, 1, 10);
+        set(&persistence, &database, 2, 20);
+        write(&persistence, &database, Mutation::Tag { key: 1, tag: 5 });
+        persistence.snapshot(&database).unwrap();
+        write(&persistence, &database, Mutation::Tag { key: 2, tag: 6 });
+        drop(persistence);
+
+        let (_, recovered) = recover(&dir);
+        assert_eq!(recovered.copy_tags(), [(1, 5), (2, 6)]);
+    }
+
+    #[test]
+    fn corrupt_and_newer_snapshots_are_refused() {
+        let dir = scratch_dir("snapshot-format");
+        let (persistence, database) = recover(&dir);
+        set(&persistence, &database, 1, 10);
+        persistence.snapshot(&database).unwrap();
+        drop(persistence);
+        let path = dir.join(SNAPSHOT_FILE);
+        let written = fs::read(&path).unwrap();
+        let refusal = |bytes: &[u8]| {
+            fs::write(&path, bytes).unwrap();
+            match Persistence::open(&dir, FailurePolicy::ShedWrites, FsyncPolicy::Never, None) {
+                Ok(_) => panic!("snapshot was loaded"),
+                Err(e) => e.to_string(),
+            }
+        };
+
+        let mut flipped = written.clone();
+        flipped[18] ^= 1;
+        assert!(refusal(&flipped).contains("checksum mismatch"));
+        let mut newer = written.clone();
+        newer[SNAPSHOT_MAGIC.len()] = SNAPSHOT_VERSION + 1;
+        assert!(refusal(&newer).contains("newer server"));
+        assert!(refusal(&written[..written.len() - 2]).contains("checksum mismatch"));
+        assert!(refusal(&written[..3]).contains("not a map8x32 snapshot"));
+
+        fs::write(&path, &written).unwrap();
+        let (_, recovered) = recover(&dir);
+        assert_eq!(contents(&recovered), [(1, vec![10])]);
+    }
+
+    #[test]
+    fn legacy_snapshots_still_load() {
+        let dir = scratch_dir("legacy-snapshot");
+        let mut legacy = LEGACY_SNAPSHOT_MAGIC.to_vec();
+        legacy.extend_from_slice(&0u64.to_le_bytes());
+        legacy.extend_from_slice(&1u32.to_le_bytes());
+        legacy.push(3);
+        legacy.extend_from_slice(&2u32.to_le_bytes());
+        legacy.extend_from_slice(&5u32.to_le_bytes());
+        legacy.extend_from_slice(&6u32.to_le_bytes());
+        fs::write(dir.join(SNAPSHOT_FILE), legacy).unwrap();
+
+        let (persistence, database) = recover(&dir);
+        assert_eq!(contents(&database), [(3, vec![5, 6])]);
+        set(&persistence, &database, 3, 7);
+        persistence.snapshot(&database).unwrap();
+        drop(persistence);
+
+        assert!(fs::read(dir.join(SNAPSHOT_FILE))
+            .unwrap()
+            .starts_with(SNAPSHOT_MAGIC));
+        let (_, recovered) = recover(&dir);
+        assert_eq!(contents(&recovered), [(3, vec![5, 6, 7])]);
+    }
+
+    #[test]
+    fn sealed_files_hide_their_values_and_open_under_a_rotated_key() {
+        let dir = scratch_dir("sealed");
+        let old = Arc::new(Keyring::parse(&"11".repeat(32)).unwrap());
+        let open = |keyring: &Arc<Keyring>| {
+            let (persistence, recovered) = Persistence::open(
+                &dir,
+                FailurePolicy::ShedWrites,
+                FsyncPolicy::Never,
+                Some(keyring.clone()),
+            )?;
+            let database = Database::new(0, None);
+            for mutation in &recovered {
+                mutation.apply(&database);
+            }
+            Ok::<_, io::Error>((persistence, database))
+        };
+        let (persistence, database) = open(&old).unwrap();
+        set(&persistence, &database, 1, 0xfeed_beef);
+        persistence.snapshot(&database).unwrap();
+        set(&persistence, &database, 2, 0xfeed_beef);
+        drop(persistence);
+        for (path, bytes) in files(&dir) {
+            assert!(
+                !bytes
+                    .windows(4)
+                    .any(|window| window == 0xfeed_beef_u32.to_le_bytes()),
+                "{} holds a value in the clear",
+                path.display()
+            );
+        }
+        assert!(
+            Persistence::open(&dir, FailurePolicy::ShedWrites, FsyncPolicy::Never, None).is_err()
+        );
+
+        // The new key seals the next snapshot; the old one still opens what
+        // came before it.
+        let rotated =
+            Arc::new(Keyring::parse(&format!("{}\n{}", "22".repeat(32), "11".repeat(32))).unwrap());
+        let (persistence, database) = open(&rotated).unwrap();
+        let expected = [(1, vec![0xfeed_beef]), (2, vec![0xfeed_beef])];
+        assert_eq!(contents(&database), expected);
+        persistence.snapshot(&database).unwrap();
+        drop(persistence);
+
+        let new = Arc::new(Keyring::parse(&"22".repeat(32)).unwrap());
+        let (_, database) = open(&new).unwrap();
+        assert_eq!(contents(&database), expected);
+        assert!(open(&old).is_err());
+    }
+
+    #[test]
+    fn crash_before_the_snapshot_is_renamed_replays_every_segment() {
+        let dir = scratch_dir("crash-rename");
+        let (persistence, database) = recover(&dir);
+        set(&persistence, &database, 1, 10);
+        persistence.snapshot(&database).unwrap();
+        set(&persistence, &database, 1, 11);
+        let before = files(&dir);
+        persistence.snapshot(&database).unwrap();
+        set(&persistence, &database, 1, 12);
+        let expected = contents(&database);
+        drop(persistence);
+
+        // The new segment exists but the snapshot covering the old one never
+        // made it past its temporary file.
+        restore(&before);
+        fs::write(dir.join(format!("{SNAPSHOT_FILE}.tmp")), b"M8SN torn").unwrap();
+        let (_, recovered) = recover(&dir);
+        assert_eq!(contents(&recovered), expected);
+    }
+
+    #[test]
+    fn crash_before_old_segments_are_removed_applies_them_once() {
+        let dir = scratch_dir("crash-remove");
+        let (persistence, database) = recover(&dir);
+        set(&persistence, &database, 2, 20);
+        set(&persistence, &database, 2, 21);
+        let before: Vec<_> = files(&dir)
+            .into_iter()
+            .filter(|(path, _)| path.extension() != Some("snapshot".as_ref()))
+            .collect();
+        persistence.snapshot(&database).unwrap();
+        set(&persistence, &database, 2, 22);
+        let expected = contents(&database);
+        drop(persistence);
+
+        // The snapshot is in place but the segment it covers is still there.
+        restore(&before);
+        assert_eq!(segments(&dir).unwrap(), vec![0, 1]);
+        let (_, recovered) = recover(&dir);
+        assert_eq!(contents(&recovered), expected);
+        assert_eq!(segments(&dir).unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn torn_final_record_is_discarded_and_the_log_stays_writable() {
+        let dir = scratch_dir("torn");
+        let (persistence, database) = recover(&dir);
+        set(&persistence, &database, 5, 50);
+        write(
+            &persistence,
+            &database,
+            Mutation::Replace {
+                key: 6,
+                values: vec![1, 2, 3],
+            },
+        );
+        let expected = contents(&database);
+        drop(persistence);
+
+        let mut torn = Mutation::Set { key: 5, value: 51 }.encode();
+        torn.truncate(torn.len() - 2);
+        let mut segment = OpenOptions::new()
+            .append(true)
+            .open(segment_path(&dir, 0))
+            .unwrap();
+        segment.write_all(&torn).unwrap();
+        drop(segment);
+
+        let (persistence, database) = recover(&dir);
+        assert_eq!(contents(&database), expected);
+        set(&persistence, &database, 5, 52);
+        let expected = contents(&database);
+        drop(persistence);
+        let (_, recovered) = recover(&dir);
+        assert_eq!(contents(&recovered), expected);
+    }
+
+    #[test]
+    fn a_torn_sealed_record_never_has_its_nonce_reused() {
+        /// The salt a record's key was derived from, and its index.
+        type Nonce = (Vec<u8>, u64);
+
+        /// Each sealed record of the segment `bytes` with the nonce it was
+        /// sealed under.
+        fn sealed_records(bytes: &[u8]) -> Vec<(Nonce, Vec<u8>)> {
+            let salt = bytes[16..SEALED_WAL_HEADER_LEN as usize].to_vec();
+            let mut records = Vec::new();
+            let mut at = SEALED_WAL_HEADER_LEN as usize;
+            while let Some(head) = bytes.get(at..at + 12) {
+                let len = u32::from_le_bytes(head[..4].try_into().unwrap()) as usize;
+                let index = u64::from_le_bytes(head[4..].try_into().unwrap());
+                let end = (at + 12 + len).min(bytes.len());
+                records.push(((salt.clone(), index), bytes[at + 12..end].to_vec()));
+                at += 12 + len;
+            }
+            records
+        }
+
+        let dir = scratch_dir("torn-sealed");
+        let keyring = Arc::new(Keyring::parse(&"33".repeat(32)).unwrap());
+        let open = || {
+            let (persistence, recovered) = Persistence::open(
+                &dir,
+                FailurePolicy::ShedWrites,
+                FsyncPolicy::Never,
+                Some(keyring.clone()),
+            )
+            .unwrap();
+            let database = Database::new(0, None);
+            for mutation in &recovered {
+                mutation.apply(&database);
+            }
+            (persistence, database)
+        };
+        let (persistence, database) = open();
+        for value in [10, 11, 12] {
+            set(&persistence, &database, 4, value);
+        }
+        let (generation, _) = persistence.wal_position();
+        drop(persistence);
+
+        // A backup taken before the crash keeps the record the crash tore.
+        let path = segment_path(&dir, generation);
+        let backup = fs::read(&path).unwrap();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(backup.len() as u64 - 5)
+            .unwrap();
+
+        let (persistence, database) = open();
+        assert_eq!(contents(&database), [(4, vec![10, 11])]);
+        set(&persistence, &database, 4, 13);
+        set(&persistence, &database, 4, 14);
+        persistence.snapshot(&database).unwrap();
+        set(&persistence, &database, 4, 15);
+        drop(persistence);
+
+        let mut sealed: HashMap<Nonce, Vec<u8>> = HashMap::new();
+        let everything = files(&dir)
+            .into_iter()
+            .filter(|(path, _)| path.extension() != Some("snapshot".as_ref()))
+            .map(|(_, bytes)| bytes)
+            .chain([backup]);
+        for bytes in everything {
+            for (nonce, data) in sealed_records(&bytes) {
+                let earlier = sealed.entry(nonce.clone()).or_insert_with(|| data.clone());
+                assert!(
+                    earlier.starts_with(&data) || data.starts_with(earlier),
+                    "record {} sealed twice under one key",
+                    nonce.1
+                );
+            }
+        }
+        let (_, database) = open();
+        assert_eq!(contents(&database), [(4, vec![10, 11, 13, 14, 15])]);
+    }
+
+    #[test]
+    fn repair_cuts_a_corrupt_tail_and_keeps_what_it_drops() {
+        let dir = scratch_dir("repair");
+        let (persistence, database) = recover(&dir);
+        set(&persistence, &database, 1, 10);
+        set(&persistence, &database, 2, 20);
+        let expected = contents(&database);
+        drop(persistence);
+        let garbage = [200, 0, 0, 0, 0, 0, 7, 7];
+        let mut segment = OpenOptions::new()
+            .append(true)
+            .open(segment_path(&dir, 0))
+            .unwrap();
+        segment.write_all(&garbage).unwrap();
+        drop(segment);
+        let mut next = Wal::create(&dir, 1, None).unwrap();
+        next.append(
+            &Mutation::Set { key: 3, value: 30 }.encode(),
+            FsyncPolicy::Never,
+        )
+        .unwrap();
+        drop(next);
+        let before = files(&dir);
+        assert!(
+            Persistence::open(&dir, FailurePolicy::ShedWrites, FsyncPolicy::Never, None).is_err()
+        );
+
+        let checked = check(&dir, None).unwrap();
+        assert!(!checked.is_clean());
+        let states: Vec<_> = checked.files.iter().map(|file| &file.state).collect();
+        assert!(matches!(
+            states[..],
+            [FileState::Corrupt(_), FileState::AfterCorrupt]
+        ));
+        assert_eq!(checked.files[0].recovered, 2);
+        assert_eq!(checked.files[0].discarded_bytes, garbage.len() as u64);
+        assert_eq!(files(&dir), before);
+
+        let repaired = repair(&dir, None).unwrap();
+        let kept: Vec<_> = repaired
+            .files
+            .iter()
+            .map(|file| fs::read(file.kept_in.as_ref().unwrap()).unwrap())
+            .collect();
+        assert_eq!(kept[0], garbage);
+        assert!(kept[1].ends_with(&Mutation::Set { key: 3, value: 30 }.encode()));
+        assert!(check(&dir, None).unwrap().is_clean());
+        let (_, recovered) = recover(&dir);
+        assert_eq!(contents(&recovered), expected);
+    }
+
+    #[test]
+    fn info_reports_the_state_a_failed_wal_append_leaves() {
+        let dir = scratch_dir("degraded");
+        let (persistence, _) =
+            Persistence::open(&dir, FailurePolicy::MemoryOnly, FsyncPolicy::Never, None).unwrap();
+        let mut database = Database::new(0, None);
+        database.persistence = Some(persistence);
+        let persistence = database.persistence.as_ref().unwrap();
+        let state = |database: &Database| database.info(false).persistence.unwrap();
+        assert_eq!(
+            state(&database),
+            PersistenceState {
+                health: Health::Healthy,
+                failures: 0
+            }
+        );
+
+        // Every write from here on lands on a full disk.
+        persistence.wal.lock().unwrap().file =
+            OpenOptions::new().write(true).open("/dev/full").unwrap();
+        let set = Mutation::Set { key: 1, value: 2 };
+        assert!(persistence.log(&set).is_ok(), "kept in memory");
+        assert_eq!(
+            state(&database),
+            PersistenceState {
+                health: Health::MemoryOnly,
+                failures: 1
+            }
+        );
+        assert_eq!(Database::new(1, None).info(false).persistence, None);
+    }
+
+    #[test]
+    fn wal_full_once_the_segment_reaches_the_limit() {
+        let dir = scratch_dir("full");
+        let (persistence, database) = recover(&dir);
+        persistence
+            .wal_max_bytes
+            .store(WAL_HEADER_LEN + 2 * FRAME_LEN as u64, Ordering::Relaxed);
+        set(&persistence, &database, 0, 1);
+        assert!(!persistence.wal_full());
+        set(&persistence, &database, 0, 2);
+        assert!(persistence.wal_full());
+        persistence.snapshot(&database).unwrap();
+        assert!(!persistence.wal_full());
+    }
+}
@@ -0,0 +1,229 @@
+use crate::command::{Command, End, SetMode};
+use crate::db;
+use map8x32_protocol::codec::{Codec, Request, NATIVE_V2};
+use map8x32_protocol::*;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+#[derive(Debug)]
+pub struct TraceFile {
+    file: File,
+    db: u8,
+}
+
+/// Appends every dispatched command to a trace file, in the order the
+/// dispatcher received them. Records use the native v2 request encoding; a
+/// SELECT record precedes the first command for a different database.
+#[derive(Debug)]
+pub struct Recorder {
+    file: Mutex<TraceFile>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Recorder> {
+        Ok(Recorder {
+            file: Mutex::new(TraceFile {
+                file: File::create(path)?,
+                db: 0,
+            }),
+        })
+    }
+
+    /// Writes `command`, sent to database `db`, and returns the lock, which
+    /// the caller holds until the command is queued so trace order matches
+    /// processing order.
+    pub fn record(&self, db: u8, command: &Command) -> MutexGuard<'_, TraceFile> {
+        let mut record = Vec::new();
+        let mut trace = self.file.lock().unwrap();
+        if trace.db != db {
+            NATIVE_V2.encode_request(&Request::Select { db }, &mut record);
+            trace.db = db;
+        }
+        NATIVE_V2.encode_request(&request(command), &mut record);
+        if let Err(e) = trace.file.write_all(&record) {
+            tracing::warn!(error = %e, "failed to write trace record");
+        }
+        trace
+    }
+}
+
+fn request(command: &Command) -> Request {
+    match command {
+        Command::Set {
+            key, value, mode, ..
+        } => match *mode {
+            SetMode::Append => Request::Set {
+                key: *key,
+                value: *value,
+            },
+            SetMode::Sorted => Request::SetSorted {
+                key: *key,
+                value: *value,
+            },
+            SetMode::Expiring { ttl_ms } => Request::SetExpiring {
+                key: *key,
+                value: *value,
+                ttl_ms,
+            },
+        },
+        Command::Get { key, .. } | Command::GetEncoded { key, .. } => Request::Get { key: *key },
+        Command::Aggregate { key, aggregate, .. } => Request::Aggregate {
+            key: *key,
+            aggregate: *aggregate,
+        },
+        Command::GetFilter { key, min, max, .. } => Request::GetFilter {
+            key: *key,
+            min: *min,
+            max: *max,
+        },
+        Command::GetSortedRange { key, min, max, .. } => Request::GetSortedRange {
+            key: *key,
+            min: *min,
+            max: *max,
+        },
+        Command::GetEnd {
+            key,
+            n,
+            end: End::Oldest,
+            ..
+        } => Request::GetFirstN { key: *key, n: *n },
+        Command::GetEnd {
+            key,
+            n,
+            end: End::Newest,
+            ..
+        } => Request::GetLastN { key: *key, n: *n },
+        Command::DeleteByKey { key, .. } => Request::DeleteByKey { key: *key },
+        Command::DeleteIf { key, expected, .. } => Request::DeleteIf {
+            key: *key,
+            expected: *expected,
+        },
+        Command::Ttl { key, .. } => Request::Ttl { key: *key },
+        Command::MemoryUsage { key, .. } => Request::MemoryUsage { key: *key },
+        Command::KeyStats { key, .. } => Request::KeyStats { key: *key },
+        Command::Persist { key, .. } => Request::Persist { key: *key },
+        Command::Swap { a, b, .. } => Request::Swap { a: *a, b: *b },
+        Command::Compare {
+            a, b, comparison, ..
+        } => Request::Compare {
+            a: *a,
+            b: *b,
+            comparison: *comparison,
+        },
+        Command::Touch { key, ttl_ms, .. } => Request::Touch {
+            key: *key,
+            ttl_ms: *ttl_ms,
+        },
+        Command::DeleteAt { key, index, .. } => Request::DeleteAt {
+            key: *key,
+            index: *index,
+        },
+        Command::Dedup { key, .. } => Request::Dedup { key: *key },
+        Command::Push { key, values, .. } => Request::Push {
+            key: *key,
+            values: values.clone(),
+        },
+        Command::GetDel { key, .. } => Request::GetDel { key: *key },
+        Command::GetSet { key, value, .. } => Request::GetSet {
+            key: *key,
+            value: *value,
+        },
+        Command::Sort {
+            key, descending, ..
+        } => Request::Sort {
+            key: *key,
+            descending: *descending,
+        },
+        Command::DeleteAll { token, .. } => Request::DeleteAll { token: *token },
+        Command::Rename {
+            from, to, merge, ..
+        } => Request::Rename {
+            from: *from,
+            to: *to,
+            merge: *merge,
+        },
+        Command::Copy {
+            from, to, append, ..
+        } => Request::Copy {
+            from: *from,
+            to: *to,
+            append: *append,
+        },
+        Command::ListAll { snapshot, .. } => Request::ListAll {
+            snapshot: *snapshot,
+        },
+        Command::ListRange { first, last, .. } => Request::ListRange {
+            first: *first,
+            last: *last,
+        },
+        Command::Tag { key, tag, .. } => Request::Tag {
+            key: *key,
+            tag: *tag,
+        },
+        Command::ListTagged { tag, .. } => Request::ListTagged { tag: *tag },
+        Command::DeleteTagged { tag, .. } => Request::DeleteTagged { tag: *tag },
+        Command::DeleteMany { keys, .. } => Request::DeleteMany { keys: keys.clone() },
+        Command::RandomKey { with_value, .. } => Request::RandomKey {
+            with_value: *with_value,
+        },
+        Command::Scan { cursor, count, .. } => Request::Scan {
+            cursor: *cursor,
+            count: *count,
+        },
+        Command::AwaitSeq { seq, .. } => Request::AwaitSeq { seq: *seq },
+        Command::DeadLetters { drain, .. } => Request::DeadLetters { drain: *drain },
+        Command::AllocRegister { allocation, .. } => Request::AllocRegister(allocation.clone()),
+        Command::AllocRelease { first_key, .. } => Request::AllocRelease {
+            first_key: *first_key,
+        },
+        Command::AllocList { .. } => Request::AllocList,
+        Command::GetVersioned { key, .. } => Request::GetVersioned { key: *key },
+        Command::ReplaceIf {
+            key,
+            expected,
+            values,
+            ..
+        } => Request::ReplaceIf {
+            key: *key,
+            expected: *expected,
+            values: values.clone(),
+        },
+        Command::RestoreKey { key, .. } => Request::RestoreKey { key: *key },
+        Command::Info { latencies, .. } => Request::Info {
+            latencies: *latencies,
+        },
+        Command::SyncFull { .. } => Request::SyncFull,
+        Command::Dump { name, .. } => Request::Dump { name: name.clone() },
+        Command::Restore { name, merge, .. } => Request::Restore {
+            name: name.clone(),
+            merge: *merge,
+        },
+        Command::SetTyped {
+            key,
+            value_type: VALUE_TYPE_TIMED,
+            words,
+            ..
+        } => match timed::decode(words).as_deref() {
+            Some(&[timed::Sample::Value { at_ms, value }]) => Request::SetTimed {
+                key: *key,
+                value,
+                at_ms,
+            },
+            _ => Request::Invalid { op: OP_SET_TIMED },
+        },
+        Command::SetTyped {
+            key,
+            value_type,
+            words,
+            ..
+        } => {
+            match db::from_words(*value_type, words).and_then(|values| values.into_iter().next()) {
+                Some(value) => Request::SetTyped { key: *key, value },
+                None => Request::Invalid { op: OP_SET_TYPED },
+            }
+        }
+        Command::GetTyped { key, .. } => Request::GetTyped { key: *key },
+    }
+}
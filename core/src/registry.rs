@@ -0,0 +1,80 @@
+pub use map8x32_protocol::codec::Allocation;
+use std::sync::RwLock;
+
+fn overlaps(a: &Allocation, b: &Allocation) -> bool {
+    a.first_key <= b.last_key && b.first_key <= a.last_key
+}
+
+#[derive(Debug)]
+pub enum RegisterError {
+    Invalid,
+    Overlaps(String),
+}
+
+impl std::fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegisterError::Invalid => {
+                write!(f, "allocation needs a name and first_key <= last_key")
+            }
+            RegisterError::Overlaps(owner) => {
+                write!(f, "key range overlaps the allocation owned by {owner}")
+            }
+        }
+    }
+}
+
+/// Records which application owns which key range. In strict mode writes to
+/// keys outside every allocation are refused.
+#[derive(Debug, Default)]
+pub struct Registry {
+    pub strict: bool,
+    allocations: RwLock<Vec<Allocation>>,
+}
+
+impl Registry {
+    pub fn new(strict: bool, allocations: Vec<Allocation>) -> Result<Registry, RegisterError> {
+        let registry = Registry {
+            strict,
+            allocations: RwLock::new(Vec::new()),
+        };
+        for allocation in allocations {
+            registry.register(allocation)?;
+        }
+        Ok(registry)
+    }
+
+    pub fn register(&self, allocation: Allocation) -> Result<(), RegisterError> {
+        if allocation.first_key > allocation.last_key || allocation.name.is_empty() {
+            return Err(RegisterError::Invalid);
+        }
+        let mut allocations = self.allocations.write().unwrap();
+        if let Some(existing) = allocations.iter().find(|a| overlaps(a, &allocation)) {
+            return Err(RegisterError::Overlaps(existing.name.clone()));
+        }
+        let position = allocations.partition_point(|a| a.first_key < allocation.first_key);
+        allocations.insert(position, allocation);
+        Ok(())
+    }
+
+    pub fn release(&self, first_key: u8) -> bool {
+        let mut allocations = self.allocations.write().unwrap();
+        let before = allocations.len();
+        allocations.retain(|a| a.first_key != first_key);
+        allocations.len() != before
+    }
+
+    pub fn list(&self) -> Vec<Allocation> {
+        self.allocations.read().unwrap().clone()
+    }
+
+    pub fn permits_write(&self, key: u8) -> bool {
+        !self.strict
+            || self
+                .allocations
+                .read()
+                .unwrap()
+                .iter()
+                .any(|a| (a.first_key..=a.last_key).contains(&key))
+    }
+}
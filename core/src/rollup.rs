@@ -0,0 +1,78 @@
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RollupRule {
+    pub first_key: u8,
+    pub last_key: u8,
+    pub window_ms: u64,
+    /// Summaries kept per key, the oldest dropped past it; all by default.
+    pub max_summaries: Option<usize>,
+}
+
+impl RollupRule {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.first_key > self.last_key {
+            return Err(format!(
+                "rollup key range {}..={} is empty",
+                self.first_key, self.last_key
+            ));
+        }
+        if self.window_ms == 0 {
+            return Err(format!(
+                "rollup for keys {}..={} needs a window of at least 1 ms",
+                self.first_key, self.last_key
+            ));
+        }
+        if self.max_summaries == Some(0) {
+            return Err(format!(
+                "rollup for keys {}..={} must keep at least one summary",
+                self.first_key, self.last_key
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// How each key's timed samples are rolled up, from the configured
+/// per-key-range rules. A key several rules cover takes the last.
+#[derive(Debug, Clone)]
+pub struct Rollups {
+    rules: [Option<Rollup>; 256],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rollup {
+    pub window_ms: u64,
+    /// 0 keeps every summary.
+    pub keep: usize,
+}
+
+impl Default for Rollups {
+    fn default() -> Self {
+        Rollups { rules: [None; 256] }
+    }
+}
+
+impl Rollups {
+    pub fn new(rules: &[RollupRule]) -> Self {
+        let mut rollups = Rollups::default();
+        for rule in rules {
+            for key in rule.first_key..=rule.last_key {
+                rollups.rules[key as usize] = Some(Rollup {
+                    window_ms: rule.window_ms,
+                    keep: rule.max_summaries.unwrap_or(0),
+                });
+            }
+        }
+        rollups
+    }
+
+    pub fn get(&self, key: u8) -> Option<Rollup> {
+        self.rules[key as usize]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.iter().all(Option::is_none)
+    }
+}
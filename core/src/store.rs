@@ -0,0 +1,294 @@
+//! The map a database keeps its keys' values in: a sharded hash map, or a
+//! B-tree under one lock that always iterates in key order.
+
+use crate::values::Values;
+use dashmap::mapref::entry::{
+    Entry as HashEntry, OccupiedEntry as HashOccupied, VacantEntry as HashVacant,
+};
+use dashmap::mapref::one::{Ref as HashRef, RefMut as HashRefMut};
+use dashmap::DashMap;
+use parking_lot::{
+    MappedRwLockReadGuard, MappedRwLockWriteGuard, RwLock, RwLockReadGuard, RwLockWriteGuard,
+};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ops::{Deref, DerefMut};
+
+/// How a database's keys are stored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "snake_case")]
+pub enum StorageOrder {
+    /// A hash map sharded by key, so writers to different shards do not
+    /// wait for each other.
+    #[default]
+    Hash,
+    /// A B-tree under one lock: every walk of the keys is in key order,
+    /// and all writers take turns.
+    Ordered,
+}
+
+#[derive(Debug)]
+pub enum Store {
+    Hash(DashMap<u8, Values>),
+    Ordered(RwLock<BTreeMap<u8, Values>>),
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Store::new(StorageOrder::default())
+    }
+}
+
+impl Store {
+    pub fn new(order: StorageOrder) -> Self {
+        match order {
+            StorageOrder::Hash => Store::Hash(DashMap::new()),
+            StorageOrder::Ordered => Store::Ordered(RwLock::new(BTreeMap::new())),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Store::Hash(map) => map.len(),
+            Store::Ordered(map) => map.read().len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains_key(&self, key: &u8) -> bool {
+        match self {
+            Store::Hash(map) => map.contains_key(key),
+            Store::Ordered(map) => map.read().contains_key(key),
+        }
+    }
+
+    pub fn get(&self, key: &u8) -> Option<Ref<'_>> {
+        match self {
+            Store::Hash(map) => map.get(key).map(Ref::Hash),
+            Store::Ordered(map) => RwLockReadGuard::try_map(map.read(), |map| map.get(key))
+                .ok()
+                .map(Ref::Ordered),
+        }
+    }
+
+    pub fn get_mut(&self, key: &u8) -> Option<RefMut<'_>> {
+        match self {
+            Store::Hash(map) => map.get_mut(key).map(RefMut::Hash),
+            Store::Ordered(map) => RwLockWriteGuard::try_map(map.write(), |map| map.get_mut(key))
+                .ok()
+                .map(RefMut::Ordered),
+        }
+    }
+
+    /// `key`'s entry, holding the key's shard, or the whole B-tree, locked
+    /// until it is dropped.
+    pub fn entry(&self, key: u8) -> Entry<'_> {
+        match self {
+            Store::Hash(map) => match map.entry(key) {
+                HashEntry::Occupied(entry) => Entry::Occupied(OccupiedEntry::Hash(entry)),
+                HashEntry::Vacant(entry) => Entry::Vacant(VacantEntry::Hash(entry)),
+            },
+            Store::Ordered(map) => {
+                let map = map.write();
+                match map.contains_key(&key) {
+                    true => Entry::Occupied(OccupiedEntry::Ordered(map, key)),
+                    false => Entry::Vacant(VacantEntry::Ordered(map, key)),
+                }
+            }
+        }
+    }
+
+    pub fn insert(&self, key: u8, values: Values) -> Option<Values> {
+        match self {
+            Store::Hash(map) => map.insert(key, values),
+            Store::Ordered(map) => map.write().insert(key, values),
+        }
+    }
+
+    pub fn remove(&self, key: &u8) -> Option<(u8, Values)> {
+        match self {
+            Store::Hash(map) => map.remove(key),
+            Store::Ordered(map) => map.write().remove_entry(key),
+        }
+    }
+
+    pub fn retain(&self, mut keep: impl FnMut(&u8, &mut Values) -> bool) {
+        match self {
+            Store::Hash(map) => map.retain(keep),
+            Store::Ordered(map) => map.write().retain(|key, values| keep(key, values)),
+        }
+    }
+
+    /// What `read` makes of every key and its values, in key order for an
+    /// ordered store. A hash store is read a shard at a time, so with
+    /// writers about the keys may not all be from the same point in time.
+    pub fn collect<T>(&self, mut read: impl FnMut(u8, &Values) -> T) -> Vec<T> {
+        match self {
+            Store::Hash(map) => map
+                .iter()
+                .map(|entry| read(*entry.key(), entry.value()))
+                .collect(),
+            Store::Ordered(map) => map
+                .read()
+                .iter()
+                .map(|(&key, values)| read(key, values))
+                .collect(),
+        }
+    }
+
+    /// Every key, in key order for an ordered store.
+    pub fn keys(&self) -> Vec<u8> {
+        self.collect(|key, _| key)
+    }
+}
+
+/// A key's values, read-locked.
+pub enum Ref<'a> {
+    Hash(HashRef<'a, u8, Values>),
+    Ordered(MappedRwLockReadGuard<'a, Values>),
+}
+
+impl Deref for Ref<'_> {
+    type Target = Values;
+
+    fn deref(&self) -> &Values {
+        match self {
+            Ref::Hash(values) => values,
+            Ref::Ordered(values) => values,
+        }
+    }
+}
+
+/// A key's values, write-locked.
+pub enum RefMut<'a> {
+    Hash(HashRefMut<'a, u8, Values>),
+    Ordered(MappedRwLockWriteGuard<'a, Values>),
+}
+
+impl Deref for RefMut<'_> {
+    type Target = Values;
+
+    fn deref(&self) -> &Values {
+        match self {
+            RefMut::Hash(values) => values,
+            RefMut::Ordered(values) => values,
+        }
+    }
+}
+
+impl DerefMut for RefMut<'_> {
+    fn deref_mut(&mut self) -> &mut Values {
+        match self {
+            RefMut::Hash(values) => values,
+            RefMut::Ordered(values) => values,
+        }
+    }
+}
+
+pub enum Entry<'a> {
+    Occupied(OccupiedEntry<'a>),
+    Vacant(VacantEntry<'a>),
+}
+
+impl<'a> Entry<'a> {
+    /// The key's values, inserting empty ones for a vacant key.
+    pub fn or_default(self) -> RefMut<'a> {
+        match self {
+            Entry::Occupied(entry) => entry.into_ref(),
+            Entry::Vacant(entry) => entry.insert(Values::default()),
+        }
+    }
+}
+
+/// A key that holds values. The ordered variant carries the B-tree's
+/// write guard and the key, which the guard keeps present.
+pub enum OccupiedEntry<'a> {
+    Hash(HashOccupied<'a, u8, Values>),
+    Ordered(RwLockWriteGuard<'a, BTreeMap<u8, Values>>, u8),
+}
+
+impl<'a> OccupiedEntry<'a> {
+    pub fn get(&self) -> &Values {
+        match self {
+            OccupiedEntry::Hash(entry) => entry.get(),
+            OccupiedEntry::Ordered(map, key) => &map[key],
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut Values {
+        match self {
+            OccupiedEntry::Hash(entry) => entry.get_mut(),
+            OccupiedEntry::Ordered(map, key) => map.get_mut(key).unwrap(),
+        }
+    }
+
+    pub fn into_ref(self) -> RefMut<'a> {
+        match self {
+            OccupiedEntry::Hash(entry) => RefMut::Hash(entry.into_ref()),
+            OccupiedEntry::Ordered(map, key) => {
+                RefMut::Ordered(RwLockWriteGuard::map(map, |map| map.get_mut(&key).unwrap()))
+            }
+        }
+    }
+
+    pub fn remove(self) -> Values {
+        match self {
+            OccupiedEntry::Hash(entry) => entry.remove(),
+            OccupiedEntry::Ordered(mut map, key) => map.remove(&key).unwrap(),
+        }
+    }
+}
+
+/// A key without values, with the same locks held as [`OccupiedEntry`].
+pub enum VacantEntry<'a> {
+    Hash(HashVacant<'a, u8, Values>),
+    Ordered(RwLockWriteGuard<'a, BTreeMap<u8, Values>>, u8),
+}
+
+impl<'a> VacantEntry<'a> {
+    pub fn insert(self, values: Values) -> RefMut<'a> {
+        match self {
+            VacantEntry::Hash(entry) => RefMut::Hash(entry.insert(values)),
+            VacantEntry::Ordered(mut map, key) => {
+                map.insert(key, values);
+                RefMut::Ordered(RwLockWriteGuard::map(map, |map| map.get_mut(&key).unwrap()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_ordered_store_walks_keys_in_order() {
+        for order in [StorageOrder::Hash, StorageOrder::Ordered] {
+            let store = Store::new(order);
+            for key in [200, 3, 97, 0, 255, 41] {
+                store.entry(key).or_default().plain().push(key.into());
+            }
+            assert_eq!(store.remove(&97).map(|(key, _)| key), Some(97));
+            let Entry::Occupied(mut entry) = store.entry(41) else {
+                panic!("key 41 is stored");
+            };
+            entry.get_mut().plain().push(42);
+            drop(entry);
+            assert_eq!(
+                store.get(&41).map(|values| values.to_vec()),
+                Some(vec![41, 42])
+            );
+            assert!(matches!(store.entry(97), Entry::Vacant(_)));
+            let mut keys = store.keys();
+            if order == StorageOrder::Ordered {
+                assert_eq!(keys, [0, 3, 41, 200, 255]);
+            }
+            keys.sort_unstable();
+            assert_eq!(keys, [0, 3, 41, 200, 255], "{order:?}");
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum Step {
+    Clamp { min: u32, max: u32 },
+    Scale { factor: f64 },
+    DropRepeats,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TransformRule {
+    pub first_key: u8,
+    pub last_key: u8,
+    pub steps: Vec<Step>,
+}
+
+impl TransformRule {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.first_key > self.last_key {
+            return Err(format!(
+                "transform key range {}..={} is empty",
+                self.first_key, self.last_key
+            ));
+        }
+        for step in &self.steps {
+            match *step {
+                Step::Clamp { min, max } if min > max => {
+                    return Err(format!("clamp min {min} is greater than max {max}"));
+                }
+                Step::Scale { factor } if !factor.is_finite() || factor < 0.0 => {
+                    return Err(format!(
+                        "scale factor {factor} must be finite and non-negative"
+                    ));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Normalizes SET values according to the configured per-key-range rules.
+#[derive(Debug, Clone, Default)]
+pub struct Pipeline {
+    rules: Vec<TransformRule>,
+}
+
+impl Pipeline {
+    pub fn new(rules: Vec<TransformRule>) -> Self {
+        Pipeline { rules }
+    }
+
+    /// Returns the value to store, or `None` if the write should be dropped.
+    /// `previous` is the key's most recently stored value.
+    pub fn apply(&self, key: u8, mut value: u32, previous: Option<u32>) -> Option<u32> {
+        let matching = self
+            .rules
+            .iter()
+            .filter(|rule| (rule.first_key..=rule.last_key).contains(&key));
+        for rule in matching {
+            for step in &rule.steps {
+                match *step {
+                    Step::Clamp { min, max } => value = value.clamp(min, max),
+                    Step::Scale { factor } => value = (value as f64 * factor).round() as u32,
+                    Step::DropRepeats => {
+                        if previous == Some(value) {
+                            return None;
+                        }
+                    }
+                }
+            }
+        }
+        Some(value)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(first_key: u8, last_key: u8, steps: &[Step]) -> TransformRule {
+        TransformRule {
+            first_key,
+            last_key,
+            steps: steps.to_vec(),
+        }
+    }
+
+    #[test]
+    fn clamp_holds_values_to_the_range() {
+        let pipeline = Pipeline::new(vec![rule(0, 255, &[Step::Clamp { min: 10, max: 20 }])]);
+        assert_eq!(pipeline.apply(1, 5, None), Some(10));
+        assert_eq!(pipeline.apply(1, 15, None), Some(15));
+        assert_eq!(pipeline.apply(1, 25, None), Some(20));
+    }
+
+    #[test]
+    fn scale_rounds_and_saturates() {
+        let pipeline = Pipeline::new(vec![rule(0, 255, &[Step::Scale { factor: 1.5 }])]);
+        assert_eq!(pipeline.apply(1, 3, None), Some(5));
+        assert_eq!(pipeline.apply(1, 2, None), Some(3));
+        assert_eq!(pipeline.apply(1, u32::MAX, None), Some(u32::MAX));
+    }
+
+    #[test]
+    fn drop_repeats_drops_only_the_previous_value() {
+        let pipeline = Pipeline::new(vec![rule(0, 255, &[Step::DropRepeats])]);
+        assert_eq!(pipeline.apply(1, 7, None), Some(7));
+        assert_eq!(pipeline.apply(1, 7, Some(7)), None);
+        assert_eq!(pipeline.apply(1, 7, Some(8)), Some(7));
+    }
+
+    #[test]
+    fn steps_and_rules_chain_in_order_for_the_keys_they_cover() {
+        let pipeline = Pipeline::new(vec![
+            rule(
+                0,
+                9,
+                &[
+                    Step::Scale { factor: 10.0 },
+                    Step::Clamp { min: 0, max: 50 },
+                ],
+            ),
+            rule(5, 9, &[Step::DropRepeats]),
+        ]);
+        assert_eq!(pipeline.apply(1, 3, None), Some(30));
+        assert_eq!(
+            pipeline.apply(1, 9, None),
+            Some(50),
+            "clamped after scaling"
+        );
+        assert_eq!(
+            pipeline.apply(1, 9, Some(50)),
+            Some(50),
+            "key 1 keeps repeats"
+        );
+        assert_eq!(
+            pipeline.apply(6, 9, Some(50)),
+            None,
+            "repeats are judged on the transformed value"
+        );
+        assert_eq!(
+            pipeline.apply(10, 9, Some(9)),
+            Some(9),
+            "no rule covers key 10"
+        );
+    }
+
+    #[test]
+    fn validate_refuses_rules_that_cannot_apply() {
+        assert!(rule(3, 2, &[]).validate().is_err());
+        assert!(rule(0, 1, &[Step::Clamp { min: 2, max: 1 }])
+            .validate()
+            .is_err());
+        assert!(rule(0, 1, &[Step::Scale { factor: -1.0 }])
+            .validate()
+            .is_err());
+        assert!(rule(0, 1, &[Step::Scale { factor: f64::NAN }])
+            .validate()
+            .is_err());
+        assert!(rule(0, 1, &[Step::DropRepeats]).validate().is_ok());
+    }
+}
@@ -0,0 +1,256 @@
+use smallvec::SmallVec;
+
+/// Values a key holds without a heap allocation of its own; most keys
+/// hold only a few.
+pub const INLINE_VALUES: usize = 4;
+
+/// Unpacked values, inline up to [`INLINE_VALUES`].
+pub type Plain = SmallVec<[u32; INLINE_VALUES]>;
+
+/// A key's values, held as a plain vector or, for long keys in
+/// non-decreasing order, packed: each value as a varint of its difference
+/// from the one before, so timestamps and IDs that grow in small steps
+/// take a byte or two instead of four.
+#[derive(Debug, Clone)]
+pub enum Values {
+    Plain(Plain),
+    /// Boxed so plain keys, the common case, do not pay for its size.
+    Packed(Box<Packed>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Packed {
+    deltas: Vec<u8>,
+    len: usize,
+    last: u32,
+}
+
+impl Default for Values {
+    fn default() -> Self {
+        Values::Plain(Plain::new())
+    }
+}
+
+impl From<Vec<u32>> for Values {
+    fn from(values: Vec<u32>) -> Self {
+        match values.len() {
+            0..=INLINE_VALUES => Values::Plain(Plain::from_slice(&values)),
+            _ => Values::Plain(Plain::from_vec(values)),
+        }
+    }
+}
+
+impl Values {
+    pub fn len(&self) -> usize {
+        match self {
+            Values::Plain(values) => values.len(),
+            Values::Packed(packed) => packed.len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn last(&self) -> Option<u32> {
+        match self {
+            Values::Plain(values) => values.last().copied(),
+            Values::Packed(packed) => Some(packed.last),
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        match self {
+            Values::Plain(values) => Iter::Plain(values.iter()),
+            Values::Packed(packed) => Iter::Packed {
+                deltas: &packed.deltas,
+                previous: 0,
+            },
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<u32> {
+        match self {
+            Values::Plain(values) => values.to_vec(),
+            Values::Packed(_) => self.iter().collect(),
+        }
+    }
+
+    /// The values as a vector to change in place, unpacking them first.
+    pub fn plain(&mut self) -> &mut Plain {
+        if let Values::Packed(_) = self {
+            *self = Values::Plain(self.iter().collect());
+        }
+        match self {
+            Values::Plain(values) => values,
+            Values::Packed(_) => unreachable!(),
+        }
+    }
+
+    /// Appends `value`, staying packed if it is no smaller than the last.
+    pub fn push(&mut self, value: u32) {
+        match self {
+            Values::Packed(packed) if value >= packed.last => {
+                write_varint(&mut packed.deltas, value - packed.last);
+                packed.len += 1;
+                packed.last = value;
+            }
+            values => values.plain().push(value),
+        }
+    }
+
+    /// Appends `values` in order as [`Values::push`] would, growing plain
+    /// values once for all of them.
+    pub fn extend(&mut self, values: &[u32]) {
+        match self {
+            Values::Plain(plain) => plain.extend_from_slice(values),
+            Values::Packed(_) => {
+                for &value in values {
+                    self.push(value);
+                }
+            }
+        }
+    }
+
+    /// Drops the first `count` values, which must be no more than there are.
+    pub fn drain_front(&mut self, count: usize) {
+        match self {
+            Values::Plain(values) => {
+                values.drain(..count);
+            }
+            Values::Packed(packed) if count == packed.len => *self = Values::default(),
+            Values::Packed(packed) => {
+                let mut rest = &packed.deltas[..];
+                let mut first = 0;
+                for _ in 0..=count {
+                    first += read_varint(&mut rest).unwrap();
+                }
+                let mut deltas = Vec::with_capacity(rest.len() + 5);
+                write_varint(&mut deltas, first);
+                deltas.extend_from_slice(rest);
+                packed.deltas = deltas;
+                packed.len -= count;
+            }
+        }
+    }
+
+    /// Packs plain values that are in non-decreasing order.
+    pub fn pack(&mut self) {
+        let Values::Plain(values) = self else {
+            return;
+        };
+        if values.is_empty() || !values.is_sorted() {
+            return;
+        }
+        let mut deltas = Vec::with_capacity(values.len());
+        let mut previous = 0;
+        for &value in values.iter() {
+            write_varint(&mut deltas, value - previous);
+            previous = value;
+        }
+        deltas.shrink_to_fit();
+        *self = Values::Packed(Box::new(Packed {
+            deltas,
+            len: values.len(),
+            last: previous,
+        }));
+    }
+
+    /// The bytes the values take outside the map entry.
+    pub fn heap_bytes(&self) -> u64 {
+        let bytes = match self {
+            Values::Plain(values) if values.spilled() => {
+                values.capacity() * std::mem::size_of::<u32>()
+            }
+            Values::Plain(_) => 0,
+            Values::Packed(packed) => std::mem::size_of::<Packed>() + packed.deltas.capacity(),
+        };
+        bytes as u64
+    }
+
+    /// The bytes packing saves over holding the values plainly.
+    pub fn packed_savings(&self) -> u64 {
+        match self {
+            Values::Plain(_) => 0,
+            Values::Packed(packed) => {
+                (packed.len * std::mem::size_of::<u32>()).saturating_sub(packed.deltas.len()) as u64
+            }
+        }
+    }
+}
+
+pub enum Iter<'a> {
+    Plain(std::slice::Iter<'a, u32>),
+    Packed { deltas: &'a [u8], previous: u32 },
+}
+
+impl Iterator for Iter<'_> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        match self {
+            Iter::Plain(values) => values.next().copied(),
+            Iter::Packed { deltas, previous } => {
+                let delta = read_varint(deltas)?;
+                *previous += delta;
+                Some(*previous)
+            }
+        }
+    }
+}
+
+/// Seven bits at a time, low bits first, the high bit set on all but the
+/// last byte.
+fn write_varint(out: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(bytes: &mut &[u8]) -> Option<u32> {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        value |= u32::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_spill_to_the_heap_past_the_inline_capacity() {
+        let mut values = Values::from(vec![1, 2, 3]);
+        values.push(4);
+        assert_eq!(values.len(), INLINE_VALUES);
+        assert_eq!(values.heap_bytes(), 0);
+
+        values.push(5);
+        assert_eq!(values.to_vec(), [1, 2, 3, 4, 5]);
+        assert!(values.heap_bytes() >= 5 * 4, "{}", values.heap_bytes());
+
+        let spilled = values.heap_bytes();
+        values.drain_front(2);
+        assert_eq!(values.to_vec(), [3, 4, 5]);
+        assert_eq!(values.heap_bytes(), spilled, "capacity is kept");
+    }
+
+    #[test]
+    fn short_vectors_start_inline() {
+        for len in 0..=INLINE_VALUES {
+            let values = Values::from(vec![7; len]);
+            assert_eq!((values.len(), values.heap_bytes()), (len, 0));
+        }
+        let values = Values::from(vec![7; INLINE_VALUES + 1]);
+        assert!(values.heap_bytes() > 0);
+    }
+}
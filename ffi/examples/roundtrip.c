@@ -0,0 +1,41 @@
+/* Build and run against a server on the default socket from the workspace root:
+ *   cargo build -p map8x32-ffi
+ *   cc ffi/examples/roundtrip.c -Iffi/include -Ltarget/debug -lmap8x32 -o roundtrip
+ *   LD_LIBRARY_PATH=target/debug ./roundtrip /tmp/map8x32.sock */
+
+#include <stdio.h>
+#include "map8x32.h"
+
+int main(int argc, char **argv) {
+  M8Client *client = m8_connect(argc > 1 ? argv[1] : "/tmp/map8x32.sock", NULL);
+  if (client == NULL) {
+    fprintf(stderr, "connect: %s\n", m8_last_error());
+    return 1;
+  }
+  if (m8_set(client, 7, 10) != 0 || m8_set(client, 7, 20) != 0) {
+    fprintf(stderr, "set: %s\n", m8_last_error());
+    return 1;
+  }
+
+  uint32_t *values;
+  size_t len;
+  if (m8_get(client, 7, &values, &len) == 1) {
+    for (size_t i = 0; i < len; i++) {
+      printf("key 7 value %u\n", values[i]);
+    }
+    m8_free_values(values, len);
+  }
+
+  M8Entry *entries;
+  size_t count;
+  if (m8_list(client, &entries, &count) == 0) {
+    for (size_t i = 0; i < count; i++) {
+      printf("key %u holds %zu values\n", entries[i].key, entries[i].len);
+    }
+    m8_free_entries(entries, count);
+  }
+
+  printf("deleted: %d\n", m8_delete(client, 7));
+  m8_free(client);
+  return 0;
+}
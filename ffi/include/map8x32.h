@@ -0,0 +1,64 @@
+/* C bindings for the map8x32 client, built from the map8x32-ffi crate.
+ * Regenerate from ffi/src/lib.rs after changing the bindings:
+ *   cbindgen --config ffi/cbindgen.toml --crate map8x32-ffi --output ffi/include/map8x32.h */
+
+#ifndef MAP8X32_H
+#define MAP8X32_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* A connection to one server, with the runtime that drives it. */
+typedef struct M8Client M8Client;
+
+/* One key and its values, as m8_list returns them. */
+typedef struct M8Entry {
+  uint8_t key;
+  uint32_t *values;
+  size_t len;
+} M8Entry;
+
+/* Connects to `address`, a Unix socket path or `tcp:<host>:<port>`, and
+ * negotiates the protocol version. With a non-null `auth_token` the
+ * connection authenticates first. Returns null on failure. */
+M8Client *m8_connect(const char *address, const char *auth_token);
+
+/* Appends `value` to `key`. Returns 0, or -1 on failure. */
+int m8_set(M8Client *client, uint8_t key, uint32_t value);
+
+/* Reads `key`'s values into `*values` and `*len`, to be released with
+ * m8_free_values. Returns 1 if the key exists, 0 if it does not (leaving
+ * `*values` null and `*len` 0), or -1 on failure. */
+int m8_get(M8Client *client, uint8_t key, uint32_t **values, size_t *len);
+
+/* Deletes `key`. Returns 1 if it existed, 0 if it did not, or -1 on
+ * failure. */
+int m8_delete(M8Client *client, uint8_t key);
+
+/* Reads every key with its values, ordered by key, into `*entries` and
+ * `*len`, to be released with m8_free_entries. Returns 0, or -1 on
+ * failure. */
+int m8_list(M8Client *client, M8Entry **entries, size_t *len);
+
+/* Closes the connection and frees the client. Null is ignored. */
+void m8_free(M8Client *client);
+
+/* Frees values returned by m8_get. */
+void m8_free_values(uint32_t *values, size_t len);
+
+/* Frees entries returned by m8_list, with their values. */
+void m8_free_entries(M8Entry *entries, size_t len);
+
+/* The message of the last failure on this thread, or null if nothing has
+ * failed. Valid until the next failing call on the same thread. */
+const char *m8_last_error(void);
+
+#ifdef __cplusplus
+} /* extern "C" */
+#endif
+
+#endif /* MAP8X32_H */
@@ -0,0 +1,248 @@
+//! C bindings for [`map8x32_client::Client`]. Every call blocks until the
+//! server answers; `include/map8x32.h` declares them.
+
+use map8x32_client::{Client, ClientConfig};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::future::Future;
+use std::io;
+use std::ptr;
+use tokio::runtime::Runtime;
+
+/// A connection to one server, with the runtime that drives it.
+pub struct M8Client {
+    runtime: Runtime,
+    client: Client,
+}
+
+/// One key and its values, as [`m8_list`] returns them.
+#[repr(C)]
+pub struct M8Entry {
+    pub key: u8,
+    pub values: *mut u32,
+    pub len: usize,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(e: &impl std::fmt::Display) {
+    let message = CString::new(e.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Runs `call` on `client`'s runtime, recording the error for
+/// [`m8_last_error`] if it fails.
+fn block_on<'a, T, F>(client: &'a mut M8Client, call: impl FnOnce(&'a mut Client) -> F) -> Option<T>
+where
+    F: Future<Output = map8x32_client::Result<T>> + 'a,
+{
+    let M8Client { runtime, client } = client;
+    match runtime.block_on(call(client)) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            set_last_error(&e);
+            None
+        }
+    }
+}
+
+/// Hands `values` to C, to be released with [`m8_free_values`].
+fn into_raw(values: Vec<u32>) -> (*mut u32, usize) {
+    let values = Box::into_raw(values.into_boxed_slice());
+    (values as *mut u32, values.len())
+}
+
+/// Takes back values handed out by [`into_raw`].
+unsafe fn from_raw(values: *mut u32, len: usize) -> Box<[u32]> {
+    Box::from_raw(ptr::slice_from_raw_parts_mut(values, len))
+}
+
+/// Connects to `address`, a Unix socket path or `tcp:<host>:<port>`, and
+/// negotiates the protocol version. With a non-null `auth_token` the
+/// connection authenticates first. Returns null on failure.
+///
+/// # Safety
+///
+/// `address` and, if not null, `auth_token` must be NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn m8_connect(
+    address: *const c_char,
+    auth_token: *const c_char,
+) -> *mut M8Client {
+    let text = |text: *const c_char| CStr::from_ptr(text).to_str().map_err(io::Error::other);
+    let connected = (|| {
+        if address.is_null() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "address is null",
+            ));
+        }
+        let address = text(address)?;
+        let mut config = match address.strip_prefix("tcp:") {
+            Some(addr) => ClientConfig::tcp(addr),
+            None => ClientConfig::unix(address.strip_prefix("unix:").unwrap_or(address)),
+        };
+        if !auth_token.is_null() {
+            config = config.with_auth_token(text(auth_token)?);
+        }
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let client = runtime.block_on(async {
+            let mut client = Client::connect(&config).await?;
+            client.hello().await?;
+            Ok::<_, io::Error>(client)
+        })?;
+        Ok(M8Client { runtime, client })
+    })();
+    match connected {
+        Ok(client) => Box::into_raw(Box::new(client)),
+        Err(e) => {
+            set_last_error(&e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Appends `value` to `key`. Returns 0, or -1 on failure.
+///
+/// # Safety
+///
+/// `client` must come from [`m8_connect`] and not have been freed.
+#[no_mangle]
+pub unsafe extern "C" fn m8_set(client: *mut M8Client, key: u8, value: u32) -> c_int {
+    match block_on(&mut *client, |client| client.set(key, value)) {
+        Some(()) => 0,
+        None => -1,
+    }
+}
+
+/// Reads `key`'s values into `*values` and `*len`, to be released with
+/// [`m8_free_values`]. Returns 1 if the key exists, 0 if it does not
+/// (leaving `*values` null and `*len` 0), or -1 on failure.
+///
+/// # Safety
+///
+/// `client` must come from [`m8_connect`] and not have been freed; `values`
+/// and `len` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn m8_get(
+    client: *mut M8Client,
+    key: u8,
+    values: *mut *mut u32,
+    len: *mut usize,
+) -> c_int {
+    *values = ptr::null_mut();
+    *len = 0;
+    match block_on(&mut *client, |client| client.get(key)) {
+        Some(Some(found)) => {
+            (*values, *len) = into_raw(found);
+            1
+        }
+        Some(None) => 0,
+        None => -1,
+    }
+}
+
+/// Deletes `key`. Returns 1 if it existed, 0 if it did not, or -1 on
+/// failure.
+///
+/// # Safety
+///
+/// `client` must come from [`m8_connect`] and not have been freed.
+#[no_mangle]
+pub unsafe extern "C" fn m8_delete(client: *mut M8Client, key: u8) -> c_int {
+    match block_on(&mut *client, |client| client.delete(key)) {
+        Some(true) => 1,
+        Some(false) => 0,
+        None => -1,
+    }
+}
+
+/// Reads every key with its values, ordered by key, into `*entries` and
+/// `*len`, to be released with [`m8_free_entries`]. Returns 0, or -1 on
+/// failure.
+///
+/// # Safety
+///
+/// `client` must come from [`m8_connect`] and not have been freed;
+/// `entries` and `len` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn m8_list(
+    client: *mut M8Client,
+    entries: *mut *mut M8Entry,
+    len: *mut usize,
+) -> c_int {
+    *entries = ptr::null_mut();
+    *len = 0;
+    let Some(mut listed) = block_on(&mut *client, |client| client.list_all()) else {
+        return -1;
+    };
+    listed.sort_unstable_by_key(|(key, _)| *key);
+    let listed: Box<[M8Entry]> = listed
+        .into_iter()
+        .map(|(key, values)| {
+            let (values, len) = into_raw(values);
+            M8Entry { key, values, len }
+        })
+        .collect();
+    *len = listed.len();
+    *entries = Box::into_raw(listed) as *mut M8Entry;
+    0
+}
+
+/// Closes the connection and frees the client. Null is ignored.
+///
+/// # Safety
+///
+/// `client` must be null or come from [`m8_connect`], and not be used
+/// again.
+#[no_mangle]
+pub unsafe extern "C" fn m8_free(client: *mut M8Client) {
+    if !client.is_null() {
+        drop(Box::from_raw(client));
+    }
+}
+
+/// Frees values returned by [`m8_get`].
+///
+/// # Safety
+///
+/// `values` and `len` must be exactly as [`m8_get`] returned them, and
+/// `values` not be used again.
+#[no_mangle]
+pub unsafe extern "C" fn m8_free_values(values: *mut u32, len: usize) {
+    if !values.is_null() {
+        drop(from_raw(values, len));
+    }
+}
+
+/// Frees entries returned by [`m8_list`], with their values.
+///
+/// # Safety
+///
+/// `entries` and `len` must be exactly as [`m8_list`] returned them, and
+/// neither they nor their values be used again.
+#[no_mangle]
+pub unsafe extern "C" fn m8_free_entries(entries: *mut M8Entry, len: usize) {
+    if entries.is_null() {
+        return;
+    }
+    let entries = Box::from_raw(ptr::slice_from_raw_parts_mut(entries, len));
+    for entry in entries.iter() {
+        drop(from_raw(entry.values, entry.len));
+    }
+}
+
+/// The message of the last failure on this thread, or null if nothing has
+/// failed. Valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn m8_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
@@ -0,0 +1,121 @@
+//! Feeds arbitrary bytes through a connection's decoding path the way the
+//! server reads a socket, answering every request so HELLO and SELECT_CODEC
+//! switch codecs mid-stream. The first byte sets how many bytes each read
+//! delivers; the rest is the stream.
+//!
+//! Beyond not panicking, this checks that every decode makes progress, that
+//! how the stream is split into reads never changes what it decodes to, and
+//! that each request and its answer survive a round trip through the codec
+//! they were read and written in.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use map8x32_protocol::codec::{split_chunk, Framing, Request, Response};
+use map8x32_protocol::{STATUS_OK, STATUS_UNAVAILABLE};
+
+/// What a connection made of its input: the requests in order, and whether
+/// it ended on a request that could not be decoded.
+#[derive(Debug, PartialEq)]
+struct Outcome {
+    requests: Vec<Request>,
+    failed: bool,
+}
+
+/// Answers `request` the way the server does when it refuses it, except
+/// HELLO, SELECT_CODEC, MULTIPLEX and CHUNKED, which go through `framing`
+/// like the server's.
+fn answer(framing: &mut Framing, request: &Request) -> Response {
+    match *request {
+        Request::Hello { version, magic } => framing.hello(version, magic),
+        Request::SelectCodec { codec } => framing.select_codec(codec),
+        Request::Multiplex => framing.multiplex(),
+        Request::Chunked { max_frame } => framing.chunk(max_frame, 64),
+        _ => Response::refusal(request, STATUS_UNAVAILABLE),
+    }
+}
+
+/// An encoded response put back together from its chunks, checking each
+/// carries the response's request ID and that nothing follows the last.
+fn unchunk(mut encoded: &[u8], id: Option<u32>) -> Vec<u8> {
+    let tag = if id.is_some() { 4 } else { 0 };
+    let mut body = Vec::new();
+    loop {
+        if let Some(id) = id {
+            assert_eq!(
+                encoded[..4],
+                id.to_le_bytes(),
+                "a chunk lost its request ID"
+            );
+        }
+        let (bytes, more, len) = split_chunk(&encoded[tag..]).expect("a whole chunk");
+        body.extend_from_slice(bytes);
+        encoded = &encoded[tag + len..];
+        if !more {
+            assert!(encoded.is_empty(), "bytes after the last chunk");
+            return body;
+        }
+    }
+}
+
+fn replay(stream: &[u8], read_len: usize) -> Outcome {
+    let mut framing = Framing::default();
+    let mut chunked = false;
+    let mut outcome = Outcome {
+        requests: Vec::new(),
+        failed: false,
+    };
+    let (mut received, mut consumed) = (0, 0);
+    while consumed < stream.len() {
+        let buffered = &stream[consumed..received];
+        match framing.decode_request(buffered) {
+            Ok(Some((request, len, id))) => {
+                assert!(
+                    len > 0 && len <= buffered.len(),
+                    "{request:?} took {len} of {} bytes",
+                    buffered.len()
+                );
+                let codec = framing.codec();
+                if !matches!(request, Request::Invalid { .. }) {
+                    let mut encoded = Vec::new();
+                    codec.encode_request(&request, &mut encoded);
+                    let decoded = codec.decode_request(&encoded).unwrap();
+                    assert_eq!(decoded, Some((request.clone(), encoded.len())));
+                }
+
+                let response = answer(&mut framing, &request);
+                let mut encoded = Vec::new();
+                framing.encode_response(id, &response, &mut encoded);
+                let tag = if id.is_some() { 4 } else { 0 };
+                let body = if chunked {
+                    unchunk(&encoded, id)
+                } else {
+                    encoded[tag..].to_vec()
+                };
+                chunked |= matches!(request, Request::Chunked { .. })
+                    && response == Response::Status(STATUS_OK);
+                let decoded = codec.decode_response(&request, &body).unwrap();
+                assert_eq!(decoded, Some((response, body.len())));
+
+                consumed += len;
+                outcome.requests.push(request);
+            }
+            Ok(None) if received == stream.len() => break,
+            Ok(None) => received = (received + read_len).min(stream.len()),
+            Err(_) => {
+                outcome.failed = true;
+                break;
+            }
+        }
+    }
+    outcome
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&read_len, stream)) = data.split_first() else {
+        return;
+    };
+    let whole = replay(stream, stream.len());
+    let split = replay(stream, read_len as usize % 16 + 1);
+    assert_eq!(whole, split, "splitting the stream changed its decoding");
+});
@@ -0,0 +1,137 @@
+//! The capture file a server started with `--capture` writes: every request
+//! it decodes, as the bytes it was sent, with when it arrived and on which
+//! connection, so the same traffic can be sent to another server. After
+//! [`CAPTURE_MAGIC`] and a version byte come records, little-endian:
+//! `[at_us: u64][connection: u64][kind: u8]`, then for a request
+//! `[len: u32][bytes]`. `at_us` counts microseconds from the capture's start.
+
+use std::io;
+
+pub const CAPTURE_MAGIC: &[u8; 8] = b"M8X32CAP";
+pub const CAPTURE_VERSION: u8 = 1;
+
+const KIND_REQUEST: u8 = 0;
+const KIND_CLOSED: u8 = 1;
+/// `at_us`, `connection` and `kind`.
+const RECORD_HEAD: usize = 17;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Captured {
+    /// A whole request, request ID and all on a multiplexed connection.
+    Request(Vec<u8>),
+    /// The connection closed, for whatever reason.
+    Closed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureRecord {
+    pub at_us: u64,
+    pub connection: u64,
+    pub event: Captured,
+}
+
+/// The bytes a capture file starts with.
+pub fn capture_header() -> Vec<u8> {
+    [CAPTURE_MAGIC.as_slice(), &[CAPTURE_VERSION]].concat()
+}
+
+impl CaptureRecord {
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.at_us.to_le_bytes());
+        out.extend_from_slice(&self.connection.to_le_bytes());
+        match &self.event {
+            Captured::Request(bytes) => {
+                out.push(KIND_REQUEST);
+                out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+                out.extend_from_slice(bytes);
+            }
+            Captured::Closed => out.push(KIND_CLOSED),
+        }
+    }
+
+    /// The record at the start of `bytes` and its length, or `None` if
+    /// `bytes` ends partway through one.
+    fn decode(bytes: &[u8]) -> io::Result<Option<(CaptureRecord, usize)>> {
+        let Some(head) = bytes.get(..RECORD_HEAD) else {
+            return Ok(None);
+        };
+        let at_us = u64::from_le_bytes(head[..8].try_into().unwrap());
+        let connection = u64::from_le_bytes(head[8..16].try_into().unwrap());
+        let (event, len) = match head[16] {
+            KIND_CLOSED => (Captured::Closed, RECORD_HEAD),
+            KIND_REQUEST => {
+                let Some(len) = bytes.get(RECORD_HEAD..RECORD_HEAD + 4) else {
+                    return Ok(None);
+                };
+                let end = RECORD_HEAD + 4 + u32::from_le_bytes(len.try_into().unwrap()) as usize;
+                match bytes.get(RECORD_HEAD + 4..end) {
+                    Some(request) => (Captured::Request(request.to_vec()), end),
+                    None => return Ok(None),
+                }
+            }
+            kind => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown capture record kind {kind}"),
+                ))
+            }
+        };
+        let record = CaptureRecord {
+            at_us,
+            connection,
+            event,
+        };
+        Ok(Some((record, len)))
+    }
+}
+
+/// Every record in a capture file's contents. A record cut short at the end,
+/// as a server killed mid-write leaves, is dropped.
+pub fn read_capture(bytes: &[u8]) -> io::Result<Vec<CaptureRecord>> {
+    let header = capture_header();
+    let Some(mut rest) = bytes.strip_prefix(header.as_slice()) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a capture file this version understands",
+        ));
+    };
+    let mut records = Vec::new();
+    while let Some((record, len)) = CaptureRecord::decode(rest)? {
+        records.push(record);
+        rest = &rest[len..];
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_round_trip_and_a_torn_tail_is_dropped() {
+        let records = [
+            CaptureRecord {
+                at_us: 5,
+                connection: 1,
+                event: Captured::Request(vec![1, 7, 1, 0, 0, 0]),
+            },
+            CaptureRecord {
+                at_us: 9,
+                connection: 2,
+                event: Captured::Request(Vec::new()),
+            },
+            CaptureRecord {
+                at_us: 12,
+                connection: 1,
+                event: Captured::Closed,
+            },
+        ];
+        let mut file = capture_header();
+        for record in &records {
+            record.encode(&mut file);
+        }
+        assert_eq!(read_capture(&file).unwrap(), records);
+        assert_eq!(read_capture(&file[..file.len() - 1]).unwrap(), records[..2]);
+        assert!(read_capture(&file[1..]).is_err());
+    }
+}
@@ -0,0 +1,246 @@
+use super::{select, Codec, CodecError, Request, Response, CODEC_NATIVE};
+use crate::*;
+
+/// One connection's place in the protocol: the codec and version its next
+/// request is read in, whether requests carry IDs, and whether responses
+/// are chunked. HELLO, SELECT_CODEC, MULTIPLEX and CHUNKED take effect once
+/// their own answer is encoded, so the answer goes out the way the request
+/// came in.
+#[derive(Debug)]
+pub struct Framing {
+    version: u8,
+    negotiated: bool,
+    codec_id: u8,
+    codec: &'static dyn Codec,
+    /// Every request and response is preceded by `[request_id: u32]`.
+    multiplexed: bool,
+    multiplexing: bool,
+    /// Every response goes out as chunks of at most this many bytes, each
+    /// `[len: u32][bytes]` with [`CHUNK_CONTINUED`] set in all but the last.
+    max_chunk: Option<u32>,
+    chunking: Option<u32>,
+    /// Responses with chunks still to go, sent a chunk at a time.
+    continuations: Vec<Continuation>,
+}
+
+/// A response encoded for a chunked connection, and how much of it is out.
+#[derive(Debug)]
+struct Continuation {
+    id: Option<u32>,
+    encoded: Vec<u8>,
+    sent: usize,
+}
+
+impl Continuation {
+    /// Appends the next chunk, returning whether it was the last.
+    fn encode_chunk(&mut self, max_chunk: u32, out: &mut Vec<u8>) -> bool {
+        let len = (self.encoded.len() - self.sent).min(max_chunk as usize);
+        let last = self.sent + len == self.encoded.len();
+        if let Some(id) = self.id {
+            out.extend_from_slice(&id.to_le_bytes());
+        }
+        let header = len as u32 | if last { 0 } else { CHUNK_CONTINUED };
+        out.extend_from_slice(&header.to_le_bytes());
+        out.extend_from_slice(&self.encoded[self.sent..self.sent + len]);
+        self.sent += len;
+        last
+    }
+}
+
+/// Splits the chunk at the front of `buf` off a chunked connection's
+/// response stream, returning its bytes, whether more of the response
+/// follows, and how much of `buf` it took; `None` if it is not all there.
+pub fn split_chunk(buf: &[u8]) -> Option<(&[u8], bool, usize)> {
+    let header = u32::from_le_bytes(buf.get(..4)?.try_into().unwrap());
+    let len = (header & !CHUNK_CONTINUED) as usize;
+    let bytes = buf.get(4..4 + len)?;
+    Some((bytes, header & CHUNK_CONTINUED != 0, 4 + len))
+}
+
+impl Default for Framing {
+    fn default() -> Self {
+        Framing {
+            version: MIN_PROTOCOL_VERSION,
+            negotiated: false,
+            codec_id: CODEC_NATIVE,
+            codec: select(CODEC_NATIVE, MIN_PROTOCOL_VERSION).unwrap(),
+            multiplexed: false,
+            multiplexing: false,
+            max_chunk: None,
+            chunking: None,
+            continuations: Vec::new(),
+        }
+    }
+}
+
+impl Framing {
+    /// The codec the next request is decoded in.
+    pub fn codec(&self) -> &'static dyn Codec {
+        self.codec
+    }
+
+    pub fn multiplexed(&self) -> bool {
+        self.multiplexed
+    }
+
+    /// Decodes the request at the front of `buf` like
+    /// [`Codec::decode_request`], along with its request ID on a
+    /// multiplexed connection.
+    pub fn decode_request(
+        &self,
+        buf: &[u8],
+    ) -> Result<Option<(Request, usize, Option<u32>)>, CodecError> {
+        if !self.multiplexed {
+            return Ok(self
+                .codec
+                .decode_request(buf)?
+                .map(|(request, len)| (request, len, None)));
+        }
+        let Some(id) = buf.get(..4) else {
+            return Ok(None);
+        };
+        let id = u32::from_le_bytes(id.try_into().unwrap());
+        Ok(self
+            .codec
+            .decode_request(&buf[4..])?
+            .map(|(request, len)| (request, 4 + len, Some(id))))
+    }
+
+    /// Answers HELLO, settling the connection's version; a bad magic or a
+    /// second HELLO is refused.
+    pub fn hello(&mut self, requested: u8, magic: u32) -> Response {
+        if magic != PROTOCOL_MAGIC || self.negotiated {
+            Response::Status(STATUS_BAD_REQUEST)
+        } else if requested < MIN_PROTOCOL_VERSION {
+            Response::UnsupportedVersion {
+                server_version: PROTOCOL_VERSION,
+            }
+        } else {
+            self.version = requested.min(PROTOCOL_VERSION);
+            self.negotiated = true;
+            Response::Hello {
+                version: self.version,
+            }
+        }
+    }
+
+    /// Answers SELECT_CODEC, refusing a codec id there is no codec for.
+    pub fn select_codec(&mut self, requested: u8) -> Response {
+        match select(requested, self.version) {
+            Some(_) => {
+                self.codec_id = requested;
+                Response::Status(STATUS_OK)
+            }
+            None => Response::Status(STATUS_BAD_REQUEST),
+        }
+    }
+
+    /// Answers MULTIPLEX; a connection that already multiplexes is refused.
+    pub fn multiplex(&mut self) -> Response {
+        if self.multiplexed || self.multiplexing {
+            Response::Status(STATUS_BAD_REQUEST)
+        } else {
+            self.multiplexing = true;
+            Response::Status(STATUS_OK)
+        }
+    }
+
+    /// Answers CHUNKED, capping chunks at `requested` bytes, or at `limit`
+    /// if that is smaller or `requested` is 0. A connection that already
+    /// chunks is refused.
+    pub fn chunk(&mut self, requested: u32, limit: u32) -> Response {
+        if self.max_chunk.is_some() || self.chunking.is_some() {
+            Response::Status(STATUS_BAD_REQUEST)
+        } else {
+            self.chunking = Some(match requested {
+                0 => limit,
+                requested => requested.min(limit),
+            });
+            Response::Status(STATUS_OK)
+        }
+    }
+
+    /// Encodes `response`, after the ID of the request it answers when it
+    /// has one, in the codec its request arrived in, then moves to whatever
+    /// codec, version and framing HELLO, SELECT_CODEC, MULTIPLEX or CHUNKED
+    /// chose. On a chunked connection every chunk goes out at once.
+    pub fn encode_response(&mut self, id: Option<u32>, response: &Response, out: &mut Vec<u8>) {
+        let codec = self.codec;
+        self.encode(id, out, false, |out| codec.encode_response(response, out));
+    }
+
+    /// Encodes `response` like [`Framing::encode_response`], except that on
+    /// a connection both chunked and multiplexed only its first chunk goes
+    /// out; [`Framing::encode_continuations`] sends the rest, interleaved
+    /// with other responses, so a large one does not hold them up.
+    pub fn queue_response(&mut self, id: Option<u32>, response: &Response, out: &mut Vec<u8>) {
+        let codec = self.codec;
+        let interleave = self.multiplexed;
+        self.encode(id, out, interleave, |out| {
+            codec.encode_response(response, out)
+        });
+    }
+
+    /// Queues a response [`Framing::codec`] already encoded elsewhere, as
+    /// [`Framing::queue_response`] would the response itself.
+    pub fn queue_encoded(&mut self, id: Option<u32>, encoded: &[u8], out: &mut Vec<u8>) {
+        let interleave = self.multiplexed;
+        self.encode(id, out, interleave, |out| out.extend_from_slice(encoded));
+    }
+
+    fn encode(
+        &mut self,
+        id: Option<u32>,
+        out: &mut Vec<u8>,
+        interleave: bool,
+        body: impl FnOnce(&mut Vec<u8>),
+    ) {
+        match self.max_chunk {
+            None => {
+                if let Some(id) = id {
+                    out.extend_from_slice(&id.to_le_bytes());
+                }
+                body(out);
+            }
+            Some(max_chunk) => {
+                let mut continuation = Continuation {
+                    id,
+                    encoded: Vec::new(),
+                    sent: 0,
+                };
+                body(&mut continuation.encoded);
+                let mut last = continuation.encode_chunk(max_chunk, out);
+                while !interleave && !last {
+                    last = continuation.encode_chunk(max_chunk, out);
+                }
+                if !last {
+                    self.continuations.push(continuation);
+                }
+            }
+        }
+        self.codec = select(self.codec_id, self.version).unwrap();
+        self.multiplexed |= self.multiplexing;
+        self.max_chunk = self.max_chunk.or(self.chunking);
+    }
+
+    /// Whether queued responses still have chunks to go.
+    pub fn continuing(&self) -> bool {
+        !self.continuations.is_empty()
+    }
+
+    /// Appends the next chunk of every response with chunks still to go.
+    pub fn encode_continuations(&mut self, out: &mut Vec<u8>) {
+        let Some(max_chunk) = self.max_chunk else {
+            return;
+        };
+        self.continuations
+            .retain_mut(|continuation| !continuation.encode_chunk(max_chunk, out));
+    }
+
+    /// Encodes a keepalive probe, tagged with [`PROBE_REQUEST_ID`] on a
+    /// multiplexed connection.
+    pub fn encode_probe(&mut self, out: &mut Vec<u8>) {
+        let id = self.multiplexed.then_some(PROBE_REQUEST_ID);
+        self.encode_response(id, &Response::Status(STATUS_PROBE), out);
+    }
+}
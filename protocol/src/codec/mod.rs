@@ -0,0 +1,2302 @@
+//! Request and response serialization. Every connection starts on the
+//! native codec at protocol version 1; HELLO moves it to a newer native
+//! version and SELECT_CODEC (within any codec) to another encoding.
+
+mod framing;
+mod msgpack;
+mod native;
+mod protobuf;
+
+pub use framing::{split_chunk, Framing};
+pub use msgpack::MsgPack;
+pub use native::Native;
+pub use protobuf::Protobuf;
+
+use crate::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+pub const CODEC_NATIVE: u8 = 0;
+pub const CODEC_MSGPACK: u8 = 1;
+pub const CODEC_PROTOBUF: u8 = 2;
+
+/// Largest length-prefixed message the msgpack and protobuf codecs accept.
+pub const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+pub const MAX_FIELD_LEN: usize = u8::MAX as usize;
+
+pub static NATIVE_V1: Native = Native::new(1);
+pub static NATIVE_V2: Native = Native::new(2);
+pub static MSGPACK: MsgPack = MsgPack;
+pub static PROTOBUF: Protobuf = Protobuf;
+
+/// The codec for `id` at the negotiated protocol `version`, which only the
+/// native codec distinguishes.
+pub fn select(id: u8, version: u8) -> Option<&'static dyn Codec> {
+    match id {
+        CODEC_NATIVE if version >= SESSION_PROTOCOL_VERSION => Some(&NATIVE_V2),
+        CODEC_NATIVE => Some(&NATIVE_V1),
+        CODEC_MSGPACK => Some(&MSGPACK),
+        CODEC_PROTOBUF => Some(&PROTOBUF),
+        _ => None,
+    }
+}
+
+pub trait Codec: fmt::Debug + Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Decodes the request at the front of `buf`, returning it with the
+    /// number of bytes it took, or `None` if more bytes are needed. An error
+    /// means the stream can't be resynchronized.
+    fn decode_request(&self, buf: &[u8]) -> Result<Option<(Request, usize)>, CodecError>;
+
+    fn encode_request(&self, request: &Request, out: &mut Vec<u8>);
+
+    /// Decodes the response to `request` at the front of `buf`, like
+    /// [`Codec::decode_request`].
+    fn decode_response(
+        &self,
+        request: &Request,
+        buf: &[u8],
+    ) -> Result<Option<(Response, usize)>, CodecError>;
+
+    fn encode_response(&self, response: &Response, out: &mut Vec<u8>);
+
+    /// Encodes the answer to a GET that found `values`, as
+    /// `Response::Values` would be, straight from wherever they are held.
+    fn encode_values(&self, values: &[u32], out: &mut Vec<u8>) {
+        self.encode_response(&Response::Values(values.to_vec()), out);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodecError {
+    /// A length or count beyond the protocol's limits.
+    Oversized,
+    Malformed(String),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Oversized => write!(f, "request exceeds protocol limits"),
+            CodecError::Malformed(reason) => write!(f, "malformed message: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Request {
+    Set {
+        key: u8,
+        value: u32,
+    },
+    Get {
+        key: u8,
+    },
+    DeleteByKey {
+        key: u8,
+    },
+    /// Refused unless `token` is the one the server is configured with.
+    DeleteAll {
+        token: u32,
+    },
+    /// Every key with its values; with `snapshot`, all as they stood at a
+    /// single point in time.
+    ListAll {
+        snapshot: bool,
+    },
+    /// Every key in `first..=last` with its values, in key order.
+    ListRange {
+        first: u8,
+        last: u8,
+    },
+    /// Gives an existing key `tag`, or with 0 clears it. A key loses its
+    /// tag when it stops existing.
+    Tag {
+        key: u8,
+        tag: u8,
+    },
+    /// Every key carrying `tag` with its values, in key order.
+    ListTagged {
+        tag: u8,
+    },
+    /// Deletes every key carrying `tag`.
+    DeleteTagged {
+        tag: u8,
+    },
+    /// Deletes each of `keys`, answered with a status per key.
+    DeleteMany {
+        keys: Vec<u8>,
+    },
+    Hello {
+        version: u8,
+        magic: u32,
+    },
+    AwaitSeq {
+        seq: u64,
+    },
+    DeadLetters {
+        drain: bool,
+    },
+    AllocRegister(Allocation),
+    AllocRelease {
+        first_key: u8,
+    },
+    AllocList,
+    GetVersioned {
+        key: u8,
+    },
+    ReplaceIf {
+        key: u8,
+        expected: u64,
+        values: Vec<u32>,
+    },
+    Auth {
+        token: Vec<u8>,
+    },
+    SelectCodec {
+        codec: u8,
+    },
+    RestoreKey {
+        key: u8,
+    },
+    /// The database's statistics; with `latencies`, each operation's
+    /// latency quantiles too.
+    Info {
+        latencies: bool,
+    },
+    SlowLog {
+        drain: bool,
+    },
+    /// Every connection the server is serving, and what each has done.
+    ClientList,
+    /// This connection, as CLIENT_LIST reports it.
+    ClientInfo,
+    /// Closes connection `id`, as CLIENT_LIST numbers it.
+    ClientKill {
+        id: u64,
+    },
+    Ping,
+    /// Asks the server to probe this connection while it is idle.
+    Keepalive,
+    /// Answers a keepalive probe; the server sends nothing back.
+    Pong,
+    /// Turns the connection into a feed of `key`'s values, each pushed as
+    /// a GET would answer it.
+    Subscribe {
+        key: u8,
+    },
+    /// Turns the connection into a feed of the events the server raises
+    /// itself, of the kinds whose `1 << EVENT_*` bits `events` sets.
+    SubscribeEvents {
+        events: u8,
+    },
+    /// Turns the connection into a feed of every command the server runs.
+    Monitor,
+    /// Tags every later request and response with a request ID, letting
+    /// the server answer requests out of order.
+    Multiplex,
+    /// Abandons the read running on this multiplexed connection under
+    /// request ID `id`, which is answered CANCELLED.
+    Cancel {
+        id: u32,
+    },
+    /// Splits every later response longer than `max_frame` bytes, or the
+    /// server's limit when that is smaller or `max_frame` is 0, into
+    /// chunks.
+    Chunked {
+        max_frame: u32,
+    },
+    /// Moves the connection's later commands to priority `class`, one of
+    /// the `PRIORITY_*` constants.
+    Priority {
+        class: u8,
+    },
+    /// With a `value`, changes the server tunable `name`; without one,
+    /// answers with its current value, or every tunable's if `name` is
+    /// empty.
+    Config {
+        name: String,
+        value: Option<String>,
+    },
+    SyncFull,
+    Select {
+        db: u8,
+    },
+    SetTyped {
+        key: u8,
+        value: TypedValue,
+    },
+    GetTyped {
+        key: u8,
+    },
+    /// Inserts `value` after any equal values, keeping a key written only
+    /// this way in ascending order.
+    SetSorted {
+        key: u8,
+        value: u32,
+    },
+    /// The values between `min` and `max` inclusive, in ascending order;
+    /// found by binary search when the key's values are sorted.
+    GetSortedRange {
+        key: u8,
+        min: u32,
+        max: u32,
+    },
+    /// Appends `value`, which expires `ttl_ms` milliseconds after the server
+    /// receives it.
+    SetExpiring {
+        key: u8,
+        value: u32,
+        ttl_ms: u32,
+    },
+    /// Appends `value` as a sample taken at `at_ms`, in Unix milliseconds,
+    /// or when the server receives it if 0.
+    SetTimed {
+        key: u8,
+        value: u32,
+        at_ms: u64,
+    },
+    /// Appends every one of `values` in one step, answering with how many
+    /// values the key then holds.
+    Push {
+        key: u8,
+        values: Vec<u32>,
+    },
+    /// Writes a point-in-time dump of the database to `name` in the
+    /// server's dump directory.
+    Dump {
+        name: String,
+    },
+    /// Loads the dump `name` from the server's dump directory, replacing the
+    /// database's contents or, with `merge`, appending to them.
+    Restore {
+        name: String,
+        merge: bool,
+    },
+    /// Removes the value at `index` among the key's values, counting from
+    /// the oldest, and answers with it.
+    DeleteAt {
+        key: u8,
+        index: u32,
+    },
+    /// The newest `n` of the key's values, oldest first.
+    GetLastN {
+        key: u8,
+        n: u32,
+    },
+    /// The oldest `n` of the key's values.
+    GetFirstN {
+        key: u8,
+        n: u32,
+    },
+    /// A key picked uniformly from those that exist, with one of its values
+    /// picked uniformly if `with_value`.
+    RandomKey {
+        with_value: bool,
+    },
+    /// The next batch of existing keys from `cursor` on, at most `count` of
+    /// them (0 for the default).
+    Scan {
+        cursor: u32,
+        count: u8,
+    },
+    /// Moves `from`'s values to `to`, replacing `to`'s or, with `merge`,
+    /// appending to them, and deletes `from`.
+    Rename {
+        from: u8,
+        to: u8,
+        merge: bool,
+    },
+    /// Exchanges `a`'s values with `b`'s.
+    Swap {
+        a: u8,
+        b: u8,
+    },
+    /// Copies `from`'s values to `to`, replacing `to`'s or, with `append`,
+    /// appending to them.
+    Copy {
+        from: u8,
+        to: u8,
+        append: bool,
+    },
+    /// Deletes the key only if it holds exactly `expected` live values.
+    DeleteIf {
+        key: u8,
+        expected: u32,
+    },
+    /// How long until the key's last value expires.
+    Ttl {
+        key: u8,
+    },
+    /// Clears the expiry of every value the key holds.
+    Persist {
+        key: u8,
+    },
+    /// Counts an access to the key without reading it, and with a nonzero
+    /// `ttl_ms` moves the expiry of each of its expiring values to that far
+    /// from now.
+    Touch {
+        key: u8,
+        ttl_ms: u32,
+    },
+    /// The values between `min` and `max` inclusive, in the order stored;
+    /// unlike [`Request::GetSortedRange`], the key need not be sorted.
+    GetFilter {
+        key: u8,
+        min: u32,
+        max: u32,
+    },
+    /// The distinct values `a` and `b` hold as `comparison` selects, in the
+    /// order first stored.
+    Compare {
+        a: u8,
+        b: u8,
+        comparison: Comparison,
+    },
+    /// Removes every value equal to an earlier one, answering with how many
+    /// went.
+    Dedup {
+        key: u8,
+    },
+    /// Deletes the key, answering with the values it held.
+    GetDel {
+        key: u8,
+    },
+    /// Replaces the key's values with `value`, or removes the key without
+    /// one, answering with the values it held.
+    GetSet {
+        key: u8,
+        value: Option<u32>,
+    },
+    /// Puts the key's values in ascending order, or descending with
+    /// `descending`; equal values keep their order.
+    Sort {
+        key: u8,
+        descending: bool,
+    },
+    /// The approximate bytes a key takes, or with no key the whole
+    /// database's.
+    MemoryUsage {
+        key: Option<u8>,
+    },
+    /// The key's access counters.
+    KeyStats {
+        key: u8,
+    },
+    /// `aggregate` over the key's values.
+    Aggregate {
+        key: u8,
+        aggregate: Aggregate,
+    },
+    /// An unknown op, or one whose payload didn't parse; answered with
+    /// BAD_REQUEST, after which the server closes the connection unless
+    /// started with `--on-protocol-error refuse`.
+    Invalid {
+        op: u8,
+    },
+}
+
+impl Request {
+    /// The native opcode.
+    pub fn op(&self) -> u8 {
+        match self {
+            Request::Set { .. } => OP_SET,
+            Request::Get { .. } => OP_GET,
+            Request::DeleteByKey { .. } => OP_DELETE_BY_KEY,
+            Request::DeleteAll { .. } => OP_DELETE_ALL,
+            Request::ListAll { .. } => OP_LIST_ALL,
+            Request::ListRange { .. } => OP_LIST_RANGE,
+            Request::Tag { .. } => OP_TAG,
+            Request::ListTagged { .. } => OP_LIST_TAGGED,
+            Request::DeleteTagged { .. } => OP_DELETE_TAGGED,
+            Request::DeleteMany { .. } => OP_DELETE_MANY,
+            Request::Hello { .. } => OP_HELLO,
+            Request::AwaitSeq { .. } => OP_AWAIT_SEQ,
+            Request::DeadLetters { .. } => OP_DEAD_LETTERS,
+            Request::AllocRegister(_) => OP_ALLOC_REGISTER,
+            Request::AllocRelease { .. } => OP_ALLOC_RELEASE,
+            Request::AllocList => OP_ALLOC_LIST,
+            Request::GetVersioned { .. } => OP_GET_VERSIONED,
+            Request::ReplaceIf { .. } => OP_REPLACE_IF,
+            Request::Auth { .. } => OP_AUTH,
+            Request::SelectCodec { .. } => OP_SELECT_CODEC,
+            Request::RestoreKey { .. } => OP_RESTORE_KEY,
+            Request::Info { .. } => OP_INFO,
+            Request::SlowLog { .. } => OP_SLOW_LOG,
+            Request::ClientList => OP_CLIENT_LIST,
+            Request::ClientInfo => OP_CLIENT_INFO,
+            Request::ClientKill { .. } => OP_CLIENT_KILL,
+            Request::Ping => OP_PING,
+            Request::Keepalive => OP_KEEPALIVE,
+            Request::Pong => OP_PONG,
+            Request::Subscribe { .. } => OP_SUBSCRIBE,
+            Request::SubscribeEvents { .. } => OP_SUBSCRIBE_EVENTS,
+            Request::Monitor => OP_MONITOR,
+            Request::Multiplex => OP_MULTIPLEX,
+            Request::Cancel { .. } => OP_CANCEL,
+            Request::Chunked { .. } => OP_CHUNKED,
+            Request::Priority { .. } => OP_PRIORITY,
+            Request::Config { .. } => OP_CONFIG,
+            Request::SyncFull => OP_SYNC_FULL,
+            Request::Select { .. } => OP_SELECT,
+            Request::SetTyped { .. } => OP_SET_TYPED,
+            Request::GetTyped { .. } => OP_GET_TYPED,
+            Request::SetSorted { .. } => OP_SET_SORTED,
+            Request::GetSortedRange { .. } => OP_GET_SORTED_RANGE,
+            Request::SetExpiring { .. } => OP_SET_EXPIRING,
+            Request::SetTimed { .. } => OP_SET_TIMED,
+            Request::Push { .. } => OP_PUSH,
+            Request::Dump { .. } => OP_DUMP,
+            Request::Restore { .. } => OP_RESTORE,
+            Request::DeleteAt { .. } => OP_DELETE_AT,
+            Request::GetLastN { .. } => OP_GET_LAST_N,
+            Request::GetFirstN { .. } => OP_GET_FIRST_N,
+            Request::RandomKey { .. } => OP_RANDOM_KEY,
+            Request::Scan { .. } => OP_SCAN,
+            Request::Rename { .. } => OP_RENAME,
+            Request::Swap { .. } => OP_SWAP,
+            Request::Copy { .. } => OP_COPY,
+            Request::DeleteIf { .. } => OP_DELETE_IF,
+            Request::Ttl { .. } => OP_TTL,
+            Request::Persist { .. } => OP_PERSIST,
+            Request::Touch { .. } => OP_TOUCH,
+            Request::GetFilter { .. } => OP_GET_FILTER,
+            Request::Compare { .. } => OP_COMPARE,
+            Request::Dedup { .. } => OP_DEDUP,
+            Request::GetDel { .. } => OP_GETDEL,
+            Request::GetSet { .. } => OP_GETSET,
+            Request::Sort { .. } => OP_SORT,
+            Request::MemoryUsage { .. } => OP_MEMUSAGE,
+            Request::KeyStats { .. } => OP_KEYSTATS,
+            Request::Aggregate { aggregate, .. } => aggregate.op(),
+            Request::Invalid { op } => *op,
+        }
+    }
+
+    /// The key a keyed command reads or writes.
+    pub fn key(&self) -> Option<u8> {
+        match self {
+            Request::Set { key, .. }
+            | Request::Get { key }
+            | Request::DeleteByKey { key }
+            | Request::Tag { key, .. }
+            | Request::GetVersioned { key }
+            | Request::ReplaceIf { key, .. }
+            | Request::RestoreKey { key }
+            | Request::SetTyped { key, .. }
+            | Request::GetTyped { key }
+            | Request::SetSorted { key, .. }
+            | Request::GetSortedRange { key, .. }
+            | Request::SetExpiring { key, .. }
+            | Request::SetTimed { key, .. }
+            | Request::Push { key, .. }
+            | Request::DeleteAt { key, .. }
+            | Request::DeleteIf { key, .. }
+            | Request::Ttl { key }
+            | Request::Persist { key }
+            | Request::Touch { key, .. }
+            | Request::GetFilter { key, .. }
+            | Request::Compare { a: key, .. }
+            | Request::Aggregate { key, .. }
+            | Request::Dedup { key }
+            | Request::GetDel { key }
+            | Request::GetSet { key, .. }
+            | Request::Sort { key, .. }
+            | Request::MemoryUsage { key: Some(key) }
+            | Request::KeyStats { key }
+            | Request::Subscribe { key }
+            | Request::GetLastN { key, .. }
+            | Request::GetFirstN { key, .. }
+            | Request::Rename { from: key, .. }
+            | Request::Swap { a: key, .. }
+            | Request::Copy { from: key, .. } => Some(*key),
+            _ => None,
+        }
+    }
+
+    /// Whether the request is answered with a write ack, which carries a
+    /// sequence number from protocol version 2 on.
+    pub fn acked(&self) -> bool {
+        matches!(
+            self,
+            Request::Set { .. }
+                | Request::SetTyped { .. }
+                | Request::SetSorted { .. }
+                | Request::SetExpiring { .. }
+                | Request::SetTimed { .. }
+                | Request::Push { .. }
+                | Request::DeleteByKey { .. }
+                | Request::Tag { .. }
+                | Request::DeleteTagged { .. }
+                | Request::DeleteMany { .. }
+                | Request::DeleteAt { .. }
+                | Request::Rename { .. }
+                | Request::Swap { .. }
+                | Request::Copy { .. }
+                | Request::DeleteIf { .. }
+                | Request::Persist { .. }
+                | Request::Touch { .. }
+                | Request::Dedup { .. }
+                | Request::GetDel { .. }
+                | Request::GetSet { .. }
+                | Request::Sort { .. }
+                | Request::DeleteAll { .. }
+                | Request::ReplaceIf { .. }
+                | Request::RestoreKey { .. }
+                | Request::Restore { .. }
+                | Request::AwaitSeq { .. }
+        )
+    }
+
+    /// Whether the request changes stored data or key allocations, and so is
+    /// refused on a read-only connection.
+    pub fn writes(&self) -> bool {
+        matches!(
+            self,
+            Request::Set { .. }
+                | Request::SetTyped { .. }
+                | Request::SetSorted { .. }
+                | Request::SetExpiring { .. }
+                | Request::SetTimed { .. }
+                | Request::Push { .. }
+                | Request::DeleteByKey { .. }
+                | Request::Tag { .. }
+                | Request::DeleteTagged { .. }
+                | Request::DeleteMany { .. }
+                | Request::DeleteAt { .. }
+                | Request::Rename { .. }
+                | Request::Swap { .. }
+                | Request::Copy { .. }
+                | Request::DeleteIf { .. }
+                | Request::Persist { .. }
+                | Request::Touch { ttl_ms: 1.., .. }
+                | Request::Dedup { .. }
+                | Request::GetDel { .. }
+                | Request::GetSet { .. }
+                | Request::Sort { .. }
+                | Request::DeleteAll { .. }
+                | Request::ReplaceIf { .. }
+                | Request::AllocRegister(_)
+                | Request::AllocRelease { .. }
+                | Request::RestoreKey { .. }
+                | Request::Dump { .. }
+                | Request::Restore { .. }
+                | Request::Config { value: Some(_), .. }
+                | Request::ClientKill { .. }
+                | Request::DeadLetters { drain: true }
+                | Request::SlowLog { drain: true }
+        )
+    }
+
+    /// RENAME or COPY from its source key and its value: the destination
+    /// key, plus 256 for the merge or append flag.
+    fn transfer(op: u8, from: u8, value: u32) -> Request {
+        let (Ok(to), flag @ (0 | 1)) = (u8::try_from(value & 0xff), value >> 8) else {
+            return Request::Invalid { op };
+        };
+        match op {
+            OP_RENAME => Request::Rename {
+                from,
+                to,
+                merge: flag == 1,
+            },
+            _ => Request::Copy {
+                from,
+                to,
+                append: flag == 1,
+            },
+        }
+    }
+
+    /// The value [`Request::transfer`] reads back.
+    fn transfer_value(to: u8, flag: bool) -> u32 {
+        u32::from(to) | (flag as u32) << 8
+    }
+
+    /// COMPARE from its key and value: the other key, plus 256 times the
+    /// comparison.
+    fn compare(op: u8, a: u8, value: u32) -> Request {
+        match (
+            u8::try_from(value & 0xff),
+            Comparison::from_flag(value >> 8),
+        ) {
+            (Ok(b), Some(comparison)) => Request::Compare { a, b, comparison },
+            _ => Request::Invalid { op },
+        }
+    }
+
+    /// SUBSCRIBE_EVENTS from its value, which must ask for at least one
+    /// event and nothing else.
+    fn subscribe_events(op: u8, value: u32) -> Request {
+        match u8::try_from(value) {
+            Ok(events) if subscribable(events) => Request::SubscribeEvents { events },
+            _ => Request::Invalid { op },
+        }
+    }
+
+    /// The value [`Request::compare`] reads back.
+    fn compare_value(b: u8, comparison: Comparison) -> u32 {
+        u32::from(b) | comparison.flag() << 8
+    }
+
+    /// CONFIG's trailer: the name, followed by `=` and the value on a SET.
+    fn config_payload(name: &str, value: Option<&str>) -> String {
+        match value {
+            Some(value) => format!("{name}={value}"),
+            None => name.to_string(),
+        }
+    }
+
+    /// CONFIG from its key, 1 to set, and its trailer.
+    fn config(op: u8, key: u8, payload: Vec<u8>) -> Request {
+        let Ok(payload) = String::from_utf8(payload) else {
+            return Request::Invalid { op };
+        };
+        match (key, payload.split_once('=')) {
+            (0, None) => Request::Config {
+                name: payload,
+                value: None,
+            },
+            (1, Some((name, value))) => Request::Config {
+                name: name.to_string(),
+                value: Some(value.to_string()),
+            },
+            _ => Request::Invalid { op },
+        }
+    }
+
+    fn validate(self) -> Result<Request, CodecError> {
+        match &self {
+            Request::ReplaceIf { values, .. } | Request::Push { values, .. }
+                if values.len() > MAX_REPLACE_VALUES as usize =>
+            {
+                Err(CodecError::Oversized)
+            }
+            Request::DeleteMany { keys } if keys.len() > MAX_DELETE_MANY_KEYS as usize => {
+                Err(CodecError::Oversized)
+            }
+            Request::Auth { token } if token.len() > MAX_ADMIN_PAYLOAD => {
+                Err(CodecError::Oversized)
+            }
+            Request::Dump { name } | Request::Restore { name, .. }
+                if name.len() > MAX_ADMIN_PAYLOAD =>
+            {
+                Err(CodecError::Oversized)
+            }
+            Request::Config { name, value }
+                if Request::config_payload(name, value.as_deref()).len() > MAX_ADMIN_PAYLOAD =>
+            {
+                Err(CodecError::Oversized)
+            }
+            Request::SetTyped {
+                value: TypedValue::Bytes(bytes),
+                ..
+            } if bytes.len() > MAX_BLOB_LEN => Err(CodecError::Oversized),
+            Request::SubscribeEvents { events } if !subscribable(*events) => Err(
+                CodecError::Malformed(format!("cannot subscribe to events {events:#b}")),
+            ),
+            _ => Ok(self),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Response {
+    Status(u8),
+    Ack {
+        status: u8,
+        seq: u64,
+    },
+    /// GET on a key that exists.
+    Values(Vec<u32>),
+    Versioned {
+        version: u64,
+        values: Vec<u32>,
+    },
+    Entries(Vec<(u8, Vec<u32>)>),
+    Hello {
+        version: u8,
+    },
+    UnsupportedVersion {
+        server_version: u8,
+    },
+    DeadLetters(Vec<DeadLetter>),
+    Allocations(Vec<Allocation>),
+    Info(Info),
+    SlowOps(Vec<SlowOp>),
+    /// CLIENT_LIST's answer, ordered by connection ID, and CLIENT_INFO's,
+    /// with the one connection.
+    Clients(Vec<ConnectedClient>),
+    /// MONITOR's answer, empty, and then each push of the commands run
+    /// since the last.
+    Monitored(Vec<MonitoredOp>),
+    /// SUBSCRIBE_EVENTS's answer, empty, and then each push of the events
+    /// raised since the last.
+    Events(Vec<KeyspaceEvent>),
+    /// CONFIG's answer to a read: each tunable asked for and its value.
+    Config(Vec<(String, String)>),
+    /// Every key with its values as of mutation sequence number `seq`,
+    /// ordered by key.
+    FullSync {
+        seq: u64,
+        entries: Vec<(u8, Vec<u32>)>,
+    },
+    /// PING's answer: the server's release version, e.g. `0.1.0`.
+    Pong {
+        server_version: String,
+    },
+    /// GET_TYPED on a key that exists; every value is of `value_type`.
+    Typed {
+        value_type: u8,
+        values: Vec<TypedValue>,
+    },
+    /// DELETE_AT's answer when it removed a value, a write ack carrying the
+    /// value taken out; also DEDUP's, carrying how many values it removed,
+    /// and PUSH's, carrying how many values the key holds after it.
+    Removed {
+        seq: u64,
+        value: u32,
+    },
+    /// GETDEL's answer when the key had values, and GETSET's when it ran, a
+    /// write ack carrying the values the key held.
+    Taken {
+        seq: u64,
+        values: Vec<u32>,
+    },
+    /// DELETE_MANY's answer when it ran, a write ack carrying each key's
+    /// status in the order the keys were asked for.
+    DeletedMany {
+        seq: u64,
+        statuses: Vec<u8>,
+    },
+    /// RANDOM_KEY's answer when the store is not empty; `value` is set when
+    /// one was asked for.
+    RandomKey {
+        key: u8,
+        value: Option<u32>,
+    },
+    /// TTL's answer for a key that exists: the milliseconds until its last
+    /// value expires, or 0 if it holds a value that never does.
+    Ttl {
+        ttl_ms: u64,
+    },
+    /// SUM, MIN, MAX, AVG or COUNT's answer for a key with values; AVG's is
+    /// the bits of an `f64`.
+    Aggregate {
+        result: u64,
+    },
+    /// MEMUSAGE's answer: the approximate bytes the key, or the database,
+    /// takes.
+    MemoryUsage {
+        bytes: u64,
+    },
+    KeyStats(KeyStats),
+    /// SCAN's batch of keys, in key order, and the cursor to continue from;
+    /// cursor 0 means the scan is complete.
+    Scan {
+        cursor: u32,
+        keys: Vec<u8>,
+    },
+}
+
+impl Response {
+    pub fn status(&self) -> u8 {
+        match self {
+            Response::Status(status) | Response::Ack { status, .. } => *status,
+            Response::UnsupportedVersion { .. } => STATUS_UNSUPPORTED_VERSION,
+            _ => STATUS_OK,
+        }
+    }
+
+    /// Answers `request` with `status` without running it.
+    pub fn refusal(request: &Request, status: u8) -> Response {
+        if request.acked() {
+            Response::Ack { status, seq: 0 }
+        } else {
+            Response::Status(status)
+        }
+    }
+
+    /// Rebuilds a response from its status and the fields an encoding that is
+    /// not self-describing carries, using `request` to tell which shape applies.
+    fn shaped(request: &Request, status: u8, fields: Fields) -> Response {
+        match request {
+            Request::DeleteAt { .. } | Request::Dedup { .. } | Request::Push { .. }
+                if status == STATUS_OK =>
+            {
+                Response::Removed {
+                    seq: fields.seq,
+                    value: fields.values.first().copied().unwrap_or_default(),
+                }
+            }
+            Request::GetDel { .. } | Request::GetSet { .. } if status == STATUS_OK => {
+                Response::Taken {
+                    seq: fields.seq,
+                    values: fields.values,
+                }
+            }
+            Request::DeleteMany { .. } if status == STATUS_OK => Response::DeletedMany {
+                seq: fields.seq,
+                statuses: fields.statuses,
+            },
+            _ if request.acked() => Response::Ack {
+                status,
+                seq: fields.seq,
+            },
+            Request::Hello { .. } if status == STATUS_UNSUPPORTED_VERSION => {
+                Response::UnsupportedVersion {
+                    server_version: fields.protocol_version,
+                }
+            }
+            _ if status != STATUS_OK => Response::Status(status),
+            Request::Get { .. }
+            | Request::GetSortedRange { .. }
+            | Request::GetFilter { .. }
+            | Request::Compare { .. }
+            | Request::GetLastN { .. }
+            | Request::GetFirstN { .. } => Response::Values(fields.values),
+            Request::GetVersioned { .. } => Response::Versioned {
+                version: fields.version,
+                values: fields.values,
+            },
+            Request::ListAll { .. } | Request::ListRange { .. } | Request::ListTagged { .. } => {
+                Response::Entries(fields.entries)
+            }
+            Request::RandomKey { .. } => {
+                let (key, values) = fields.entries.into_iter().next().unwrap_or_default();
+                Response::RandomKey {
+                    key,
+                    value: values.first().copied(),
+                }
+            }
+            Request::Scan { .. } => Response::Scan {
+                cursor: fields.cursor,
+                keys: fields.keys,
+            },
+            Request::Ttl { .. } => Response::Ttl {
+                ttl_ms: fields.ttl_ms,
+            },
+            Request::Aggregate { .. } => Response::Aggregate {
+                result: fields.result,
+            },
+            Request::MemoryUsage { .. } => Response::MemoryUsage {
+                bytes: fields.memory_bytes,
+            },
+            Request::Hello { .. } => Response::Hello {
+                version: fields.protocol_version,
+            },
+            Request::DeadLetters { .. } => Response::DeadLetters(fields.dead_letters),
+            Request::AllocList => Response::Allocations(fields.allocations),
+            Request::Info { latencies } => {
+                let mut info = fields.info;
+                // An encoding that leaves out empty fields cannot tell no
+                // latencies from none asked for.
+                if *latencies {
+                    info.latencies.get_or_insert_with(Vec::new);
+                }
+                Response::Info(info)
+            }
+            Request::KeyStats { .. } => Response::KeyStats(fields.key_stats),
+            Request::SlowLog { .. } => Response::SlowOps(fields.slow_ops),
+            Request::ClientList | Request::ClientInfo => Response::Clients(fields.clients),
+            Request::Monitor => Response::Monitored(fields.monitored),
+            Request::SubscribeEvents { .. } => Response::Events(fields.events),
+            Request::Config { value: None, .. } => Response::Config(fields.config),
+            Request::SyncFull => Response::FullSync {
+                seq: fields.seq,
+                entries: fields.entries,
+            },
+            Request::Ping => Response::Pong {
+                server_version: fields.server_version,
+            },
+            Request::GetTyped { .. } => Response::Typed {
+                value_type: fields.value_type,
+                values: fields.typed_values,
+            },
+            _ => Response::Status(status),
+        }
+    }
+}
+
+/// Response fields besides the status, for [`Response::shaped`].
+#[derive(Debug, Default)]
+struct Fields {
+    seq: u64,
+    version: u64,
+    protocol_version: u8,
+    values: Vec<u32>,
+    entries: Vec<(u8, Vec<u32>)>,
+    dead_letters: Vec<DeadLetter>,
+    allocations: Vec<Allocation>,
+    info: Info,
+    slow_ops: Vec<SlowOp>,
+    clients: Vec<ConnectedClient>,
+    monitored: Vec<MonitoredOp>,
+    events: Vec<KeyspaceEvent>,
+    config: Vec<(String, String)>,
+    value_type: u8,
+    typed_values: Vec<TypedValue>,
+    server_version: String,
+    cursor: u32,
+    keys: Vec<u8>,
+    statuses: Vec<u8>,
+    ttl_ms: u64,
+    result: u64,
+    memory_bytes: u64,
+    key_stats: KeyStats,
+}
+
+/// Server statistics returned by INFO.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Info {
+    pub uptime_ms: u64,
+    pub keys: u64,
+    pub values: u64,
+    pub memory_bytes: u64,
+    /// GET and GET_VERSIONED requests that found the key.
+    pub hits: u64,
+    pub misses: u64,
+    /// Commands executed, by operation name.
+    pub ops: Vec<(String, u64)>,
+    /// How the database's persistence is holding up; `None` without a data
+    /// directory.
+    pub persistence: Option<PersistenceState>,
+    /// Every registered key range, as ALLOC_LIST lists them.
+    pub allocations: Vec<Allocation>,
+    /// Writes refused by the memory quota or the key and value limits.
+    pub quota_rejections: u64,
+    /// The most keys the database holds; `None` for no limit.
+    pub max_keys: Option<u64>,
+    /// The most values the database holds across its keys; `None` for no
+    /// limit.
+    pub max_values: Option<u64>,
+    /// Present when asked for: the operations run since startup, by
+    /// opcode.
+    pub latencies: Option<Vec<OpLatency>>,
+}
+
+/// The state WAL and snapshot write failures have left persistence in,
+/// as `--on-persistence-failure` chose it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Health {
+    Healthy = 0,
+    ReadOnly = 1,
+    MemoryOnly = 2,
+    Shedding = 3,
+}
+
+impl Health {
+    pub const ALL: [Health; 4] = [
+        Health::Healthy,
+        Health::ReadOnly,
+        Health::MemoryOnly,
+        Health::Shedding,
+    ];
+
+    pub fn from_u8(value: u8) -> Option<Health> {
+        Health::ALL.get(usize::from(value)).copied()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Health::Healthy => "healthy",
+            Health::ReadOnly => "read_only",
+            Health::MemoryOnly => "memory_only",
+            Health::Shedding => "shedding",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PersistenceState {
+    pub health: Health,
+    /// WAL and snapshot writes that have failed since startup.
+    pub failures: u64,
+}
+
+/// How long one operation's commands waited for a command processor, and
+/// how long they then ran on it, in microseconds.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpLatency {
+    pub op: u8,
+    pub wait: Quantiles,
+    pub run: Quantiles,
+}
+
+/// A latency histogram's quantiles. The server counts latencies in
+/// power-of-two buckets and reports each quantile as the top of the bucket
+/// it falls in, so one may overstate by up to twice, though never beyond
+/// `max`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quantiles {
+    pub count: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub p999: u64,
+    pub max: u64,
+}
+
+/// A key's access counters, returned by KEYSTATS. They count from startup
+/// and are kept when the key is deleted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyStats {
+    /// Reads of the key that found it, as INFO counts hits.
+    pub hits: u64,
+    pub misses: u64,
+    /// SET requests for the key in any of their forms, SET_TYPED included,
+    /// refused ones too.
+    pub sets: u64,
+    /// When a keyed command last named the key, in Unix milliseconds; 0 if
+    /// none has.
+    pub last_access_ms: u64,
+}
+
+/// A write the server refused, kept for the DEAD_LETTERS command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub at_unix_ms: u64,
+    pub op: u8,
+    pub key: u8,
+    pub value: u32,
+    pub reason: u8,
+}
+
+/// A command whose handling took longer than the server's slow-log
+/// threshold, kept for the SLOW_LOG command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SlowOp {
+    pub at_unix_ms: u64,
+    pub op: u8,
+    /// The command's key, or 0 for commands without one.
+    pub key: u8,
+    /// From the request being decoded to its response being ready.
+    pub micros: u64,
+    /// Encoded size of the request, trailer included.
+    pub request_bytes: u32,
+}
+
+/// A connection being served, as CLIENT_LIST reports it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectedClient {
+    /// The number the server's logs give the connection.
+    pub id: u64,
+    /// The peer's user, for Unix socket connections.
+    pub uid: Option<u32>,
+    /// Who is connected, as the audit log names peers.
+    pub peer: String,
+    pub connected_unix_ms: u64,
+    pub requests: u64,
+    /// Encoded requests read and responses written.
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// The opcode of the latest request, or 0 before the first.
+    pub last_op: u8,
+}
+
+/// Whether `events` sets at least one `1 << EVENT_*` bit a subscriber can
+/// ask for, and no other.
+fn subscribable(events: u8) -> bool {
+    const ALL: u8 = 1 << EVENT_EXPIRED | 1 << EVENT_EVICTED | 1 << EVENT_DELETE_ALL;
+    events != 0 && events & !ALL == 0
+}
+
+/// Something the server did to the data on its own, as SUBSCRIBE_EVENTS
+/// pushes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyspaceEvent {
+    /// One of the `EVENT_*` constants.
+    pub event: u8,
+    /// The key the values were dropped from; 0 for DELETE_ALL and LAGGED.
+    pub key: u8,
+    /// The values that expired or were evicted, or for LAGGED the events
+    /// missed.
+    pub count: u64,
+}
+
+/// A command the server ran, as MONITOR reports it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MonitoredOp {
+    pub at_unix_ms: u64,
+    pub db: u8,
+    pub op: u8,
+    /// The command's key, or 0 for commands without one.
+    pub key: u8,
+    /// The value field of the command's native frame.
+    pub value: u32,
+    pub status: u8,
+    /// From the request being decoded to its response being ready.
+    pub micros: u64,
+    /// Who sent it, as the audit log names peers.
+    pub peer: String,
+    /// The command spelled out, e.g. `SET key=7 value=10`.
+    pub command: String,
+}
+
+/// Which of two keys' values COMPARE answers with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Comparison {
+    /// Values in the first key and not the second.
+    OnlyFirst,
+    /// Values in the second key and not the first.
+    OnlySecond,
+    /// Values in both keys.
+    Both,
+}
+
+impl Comparison {
+    pub fn flag(self) -> u32 {
+        match self {
+            Comparison::OnlyFirst => 0,
+            Comparison::OnlySecond => 1,
+            Comparison::Both => 2,
+        }
+    }
+
+    pub fn from_flag(flag: u32) -> Option<Comparison> {
+        match flag {
+            0 => Some(Comparison::OnlyFirst),
+            1 => Some(Comparison::OnlySecond),
+            2 => Some(Comparison::Both),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Comparison::OnlyFirst => "only_first",
+            Comparison::OnlySecond => "only_second",
+            Comparison::Both => "both",
+        }
+    }
+}
+
+/// What SUM, MIN, MAX, AVG and COUNT compute over a key's values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Aggregate {
+    Sum,
+    Min,
+    Max,
+    Avg,
+    Count,
+}
+
+impl Aggregate {
+    pub fn op(self) -> u8 {
+        match self {
+            Aggregate::Sum => OP_SUM,
+            Aggregate::Min => OP_MIN,
+            Aggregate::Max => OP_MAX,
+            Aggregate::Avg => OP_AVG,
+            Aggregate::Count => OP_COUNT,
+        }
+    }
+
+    pub fn from_op(op: u8) -> Option<Aggregate> {
+        match op {
+            OP_SUM => Some(Aggregate::Sum),
+            OP_MIN => Some(Aggregate::Min),
+            OP_MAX => Some(Aggregate::Max),
+            OP_AVG => Some(Aggregate::Avg),
+            OP_COUNT => Some(Aggregate::Count),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Aggregate::Sum => "SUM",
+            Aggregate::Min => "MIN",
+            Aggregate::Max => "MAX",
+            Aggregate::Avg => "AVG",
+            Aggregate::Count => "COUNT",
+        }
+    }
+}
+
+/// A value stored with SET_TYPED. A key holds values of a single type,
+/// fixed by the first one written to it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TypedValue {
+    U32(u32),
+    U64(u64),
+    /// Up to [`MAX_BLOB_LEN`] bytes.
+    Bytes(Vec<u8>),
+}
+
+impl TypedValue {
+    pub fn value_type(&self) -> u8 {
+        match self {
+            TypedValue::U32(_) => VALUE_TYPE_U32,
+            TypedValue::U64(_) => VALUE_TYPE_U64,
+            TypedValue::Bytes(_) => VALUE_TYPE_BYTES,
+        }
+    }
+
+    /// The value's bytes, little-endian for integers.
+    pub fn payload(&self) -> Vec<u8> {
+        match self {
+            TypedValue::U32(value) => value.to_le_bytes().to_vec(),
+            TypedValue::U64(value) => value.to_le_bytes().to_vec(),
+            TypedValue::Bytes(bytes) => bytes.clone(),
+        }
+    }
+
+    /// Parses [`TypedValue::payload`] back; integers must be exactly their
+    /// width.
+    pub fn from_payload(value_type: u8, payload: &[u8]) -> Option<TypedValue> {
+        match value_type {
+            VALUE_TYPE_U32 => Some(TypedValue::U32(u32::from_le_bytes(
+                payload.try_into().ok()?,
+            ))),
+            VALUE_TYPE_U64 => Some(TypedValue::U64(u64::from_le_bytes(
+                payload.try_into().ok()?,
+            ))),
+            VALUE_TYPE_BYTES if payload.len() <= MAX_BLOB_LEN => {
+                Some(TypedValue::Bytes(payload.to_vec()))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The owner of a key range, as registered with ALLOC_REGISTER or in the
+/// server's config file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Allocation {
+    pub first_key: u8,
+    pub last_key: u8,
+    pub name: String,
+    #[serde(default)]
+    pub contact: String,
+    #[serde(default)]
+    pub policy: String,
+}
+
+/// Splits a length-prefixed message off the front of `buf`.
+fn split_message(buf: &[u8]) -> Result<Option<(&[u8], usize)>, CodecError> {
+    let Some(len) = buf.get(..4) else {
+        return Ok(None);
+    };
+    let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(CodecError::Oversized);
+    }
+    Ok(buf.get(4..4 + len).map(|message| (message, 4 + len)))
+}
+
+fn frame_message(out: &mut Vec<u8>, encode: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    out.extend_from_slice(&[0; 4]);
+    encode(out);
+    let len = (out.len() - start - 4) as u32;
+    out[start..start + 4].copy_from_slice(&len.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codecs() -> [&'static dyn Codec; 4] {
+        [&NATIVE_V1, &NATIVE_V2, &MSGPACK, &PROTOBUF]
+    }
+
+    fn allocation() -> Allocation {
+        Allocation {
+            first_key: 10,
+            last_key: 19,
+            name: "billing".to_string(),
+            contact: "ops@example.com".to_string(),
+            policy: String::new(),
+        }
+    }
+
+    /// Every request with the responses it can get.
+    fn exchanges() -> Vec<(Request, Vec<Response>)> {
+        let ack = |status| Response::Ack { status, seq: 42 };
+        vec![
+            (
+                Request::Set {
+                    key: 7,
+                    value: 1337,
+                },
+                vec![ack(STATUS_OK), ack(STATUS_CAPACITY_EXCEEDED)],
+            ),
+            (
+                Request::DeleteIf {
+                    key: 9,
+                    expected: 70_000,
+                },
+                vec![ack(STATUS_OK), ack(STATUS_CONFLICT)],
+            ),
+            (
+                Request::Ttl { key: 6 },
+                vec![
+                    Response::Ttl { ttl_ms: 0 },
+                    Response::Ttl { ttl_ms: u64::MAX },
+                    Response::Status(STATUS_NOT_FOUND),
+                ],
+            ),
+            (
+                Request::Sort {
+                    key: 6,
+                    descending: true,
+                },
+                vec![ack(STATUS_OK), ack(STATUS_CONFLICT)],
+            ),
+            (
+                Request::MemoryUsage { key: Some(6) },
+                vec![
+                    Response::MemoryUsage { bytes: 56 },
+                    Response::Status(STATUS_NOT_FOUND),
+                ],
+            ),
+            (
+                Request::MemoryUsage { key: None },
+                vec![Response::MemoryUsage { bytes: u64::MAX }],
+            ),
+            (
+                Request::KeyStats { key: 6 },
+                vec![
+                    Response::KeyStats(KeyStats {
+                        hits: 3,
+                        misses: 1,
+                        sets: 2,
+                        last_access_ms: 1_700_000_000_000,
+                    }),
+                    Response::KeyStats(KeyStats::default()),
+                ],
+            ),
+            (
+                Request::Persist { key: 6 },
+                vec![ack(STATUS_OK), ack(STATUS_NOT_FOUND)],
+            ),
+            (
+                Request::Touch {
+                    key: 6,
+                    ttl_ms: 60_000,
+                },
+                vec![ack(STATUS_OK), ack(STATUS_NOT_FOUND)],
+            ),
+            (
+                Request::Get { key: 7 },
+                vec![
+                    Response::Values(vec![1, u32::MAX]),
+                    Response::Values(Vec::new()),
+                    Response::Status(STATUS_NOT_FOUND),
+                ],
+            ),
+            (
+                Request::DeleteByKey { key: 255 },
+                vec![ack(STATUS_NOT_FOUND)],
+            ),
+            (Request::DeleteAll { token: 7 }, vec![ack(STATUS_OK)]),
+            (
+                Request::Tag { key: 4, tag: 3 },
+                vec![ack(STATUS_OK), ack(STATUS_NOT_FOUND)],
+            ),
+            (
+                Request::ListTagged { tag: 3 },
+                vec![
+                    Response::Entries(vec![(4, vec![7])]),
+                    Response::Entries(Vec::new()),
+                    Response::Status(STATUS_BAD_REQUEST),
+                ],
+            ),
+            (
+                Request::DeleteTagged { tag: 3 },
+                vec![ack(STATUS_OK), ack(STATUS_NOT_FOUND)],
+            ),
+            (
+                Request::DeleteMany {
+                    keys: vec![3, 255, 3],
+                },
+                vec![
+                    Response::DeletedMany {
+                        seq: 42,
+                        statuses: vec![STATUS_OK, STATUS_KEY_NOT_ALLOCATED, STATUS_NOT_FOUND],
+                    },
+                    Response::DeletedMany {
+                        seq: 42,
+                        statuses: Vec::new(),
+                    },
+                    ack(STATUS_UNAVAILABLE),
+                ],
+            ),
+            (
+                Request::ListRange {
+                    first: 8,
+                    last: 200,
+                },
+                vec![
+                    Response::Entries(vec![(9, vec![1, 2]), (200, vec![3])]),
+                    Response::Entries(Vec::new()),
+                    Response::Status(STATUS_BAD_REQUEST),
+                ],
+            ),
+            (
+                Request::ListAll { snapshot: false },
+                vec![
+                    Response::Entries(vec![(0, vec![5]), (9, vec![1, 2, 3])]),
+                    Response::Entries(Vec::new()),
+                ],
+            ),
+            (
+                Request::Hello {
+                    version: 2,
+                    magic: PROTOCOL_MAGIC,
+                },
+                vec![
+                    Response::Hello { version: 2 },
+                    Response::UnsupportedVersion { server_version: 2 },
+                    Response::Status(STATUS_BAD_REQUEST),
+                ],
+            ),
+            (
+                Request::AwaitSeq { seq: u64::MAX },
+                vec![ack(STATUS_OK), ack(STATUS_STALE)],
+            ),
+            (
+                Request::DeadLetters { drain: true },
+                vec![Response::DeadLetters(vec![DeadLetter {
+                    at_unix_ms: 1_700_000_000_000,
+                    op: OP_SET,
+                    key: 3,
+                    value: 9,
+                    reason: STATUS_KEY_NOT_ALLOCATED,
+                }])],
+            ),
+            (
+                Request::AllocRegister(allocation()),
+                vec![
+                    Response::Status(STATUS_OK),
+                    Response::Status(STATUS_CONFLICT),
+                ],
+            ),
+            (
+                Request::AllocRelease { first_key: 10 },
+                vec![Response::Status(STATUS_NOT_FOUND)],
+            ),
+            (
+                Request::AllocList,
+                vec![Response::Allocations(vec![allocation()])],
+            ),
+            (
+                Request::GetVersioned { key: 1 },
+                vec![Response::Versioned {
+                    version: 3,
+                    values: vec![4],
+                }],
+            ),
+            (
+                Request::ReplaceIf {
+                    key: 1,
+                    expected: 3,
+                    values: vec![8, 9],
+                },
+                vec![ack(STATUS_OK), ack(STATUS_CONFLICT)],
+            ),
+            (
+                Request::Auth {
+                    token: b"secret".to_vec(),
+                },
+                vec![
+                    Response::Status(STATUS_OK),
+                    Response::Status(STATUS_UNAUTHORIZED),
+                ],
+            ),
+            (
+                Request::SelectCodec {
+                    codec: CODEC_MSGPACK,
+                },
+                vec![Response::Status(STATUS_OK)],
+            ),
+            (
+                Request::RestoreKey { key: 1 },
+                vec![ack(STATUS_OK), ack(STATUS_NOT_FOUND)],
+            ),
+            (
+                Request::Info { latencies: false },
+                vec![Response::Info(Info {
+                    uptime_ms: 1500,
+                    keys: 2,
+                    values: 3,
+                    memory_bytes: 76,
+                    hits: 4,
+                    misses: 1,
+                    ops: vec![("set".to_string(), 3), ("get".to_string(), 5)],
+                    persistence: None,
+                    allocations: Vec::new(),
+                    quota_rejections: 2,
+                    max_keys: Some(16),
+                    max_values: None,
+                    latencies: None,
+                })],
+            ),
+            (
+                Request::Info { latencies: true },
+                vec![
+                    Response::Info(Info {
+                        uptime_ms: 1500,
+                        ops: vec![("set".to_string(), 3)],
+                        persistence: Some(PersistenceState {
+                            health: Health::Shedding,
+                            failures: 2,
+                        }),
+                        allocations: vec![Allocation {
+                            first_key: 10,
+                            last_key: 19,
+                            name: "billing".to_string(),
+                            contact: "ops@example.com".to_string(),
+                            policy: String::new(),
+                        }],
+                        latencies: Some(vec![OpLatency {
+                            op: OP_SET,
+                            wait: Quantiles {
+                                count: 3,
+                                p50: 7,
+                                p90: 9,
+                                p99: 9,
+                                p999: 9,
+                                max: 9,
+                            },
+                            run: Quantiles {
+                                count: 3,
+                                p50: 31,
+                                p90: 40,
+                                p99: 40,
+                                p999: 40,
+                                max: 40,
+                            },
+                        }]),
+                        ..Info::default()
+                    }),
+                    Response::Info(Info {
+                        latencies: Some(Vec::new()),
+                        ..Info::default()
+                    }),
+                ],
+            ),
+            (
+                Request::SlowLog { drain: true },
+                vec![Response::SlowOps(vec![SlowOp {
+                    at_unix_ms: 1_700_000_000_000,
+                    op: OP_LIST_ALL,
+                    key: 0,
+                    micros: 25_000,
+                    request_bytes: 6,
+                }])],
+            ),
+            (
+                Request::ClientList,
+                vec![Response::Clients(vec![
+                    ConnectedClient {
+                        id: 1,
+                        uid: Some(1000),
+                        peer: "uid=1000 pid=42".to_string(),
+                        connected_unix_ms: 1_700_000_000_000,
+                        requests: 12,
+                        bytes_in: 72,
+                        bytes_out: 140,
+                        last_op: OP_SET,
+                    },
+                    ConnectedClient {
+                        id: 3,
+                        uid: None,
+                        peer: "addr=127.0.0.1:4000".to_string(),
+                        connected_unix_ms: 1_700_000_000_500,
+                        requests: 0,
+                        bytes_in: 0,
+                        bytes_out: 0,
+                        last_op: 0,
+                    },
+                ])],
+            ),
+            (
+                Request::ClientInfo,
+                vec![Response::Clients(vec![ConnectedClient {
+                    id: 2,
+                    uid: Some(0),
+                    peer: "uid=0 pid=1".to_string(),
+                    connected_unix_ms: 1_700_000_000_000,
+                    requests: 1,
+                    bytes_in: 6,
+                    bytes_out: 9,
+                    last_op: OP_CLIENT_INFO,
+                }])],
+            ),
+            (
+                Request::ClientKill { id: 1 << 40 },
+                vec![
+                    Response::Status(STATUS_OK),
+                    Response::Status(STATUS_NOT_FOUND),
+                    Response::Status(STATUS_READONLY),
+                ],
+            ),
+            (
+                Request::Monitor,
+                vec![
+                    Response::Monitored(Vec::new()),
+                    Response::Monitored(vec![MonitoredOp {
+                        at_unix_ms: 1_700_000_000_000,
+                        db: 2,
+                        op: OP_SET,
+                        key: 7,
+                        value: 10,
+                        status: STATUS_OK,
+                        micros: 40,
+                        peer: "uid=1000 pid=42".to_string(),
+                        command: "SET key=7 value=10".to_string(),
+                    }]),
+                    Response::Status(STATUS_UNAUTHORIZED),
+                ],
+            ),
+            (
+                Request::SubscribeEvents { events: 0b101 },
+                vec![
+                    Response::Events(Vec::new()),
+                    Response::Events(vec![
+                        KeyspaceEvent {
+                            event: EVENT_EXPIRED,
+                            key: 7,
+                            count: 2,
+                        },
+                        KeyspaceEvent {
+                            event: EVENT_LAGGED,
+                            key: 0,
+                            count: 1 << 33,
+                        },
+                    ]),
+                    Response::Status(STATUS_BAD_REQUEST),
+                ],
+            ),
+            (
+                Request::Config {
+                    name: String::new(),
+                    value: None,
+                },
+                vec![
+                    Response::Config(vec![
+                        ("log_level".to_string(), "info".to_string()),
+                        ("rate_limit".to_string(), "0".to_string()),
+                    ]),
+                    Response::Status(STATUS_READONLY),
+                ],
+            ),
+            (
+                Request::Config {
+                    name: "max_connections".to_string(),
+                    value: Some("64".to_string()),
+                },
+                vec![
+                    Response::Status(STATUS_OK),
+                    Response::Status(STATUS_BAD_REQUEST),
+                    Response::Status(STATUS_NOT_FOUND),
+                ],
+            ),
+            (
+                Request::Ping,
+                vec![Response::Pong {
+                    server_version: "0.1.0".to_string(),
+                }],
+            ),
+            (
+                Request::Keepalive,
+                vec![
+                    Response::Status(STATUS_OK),
+                    Response::Status(STATUS_NOT_FOUND),
+                    Response::Status(STATUS_PROBE),
+                ],
+            ),
+            (Request::Pong, vec![]),
+            (
+                Request::Cancel { id: 70_000 },
+                vec![
+                    Response::Status(STATUS_OK),
+                    Response::Status(STATUS_NOT_FOUND),
+                ],
+            ),
+            (
+                Request::ListAll { snapshot: true },
+                vec![Response::Status(STATUS_CANCELLED)],
+            ),
+            (
+                Request::Chunked { max_frame: 4096 },
+                vec![
+                    Response::Status(STATUS_OK),
+                    Response::Status(STATUS_BAD_REQUEST),
+                ],
+            ),
+            (
+                Request::Priority { class: 2 },
+                vec![
+                    Response::Status(STATUS_OK),
+                    Response::Status(STATUS_UNAUTHORIZED),
+                ],
+            ),
+            (
+                Request::Multiplex,
+                vec![
+                    Response::Status(STATUS_OK),
+                    Response::Status(STATUS_BAD_REQUEST),
+                ],
+            ),
+            (
+                Request::Subscribe { key: 7 },
+                vec![
+                    Response::Status(STATUS_OK),
+                    Response::Status(STATUS_UNAUTHORIZED),
+                ],
+            ),
+            (
+                Request::SetTyped {
+                    key: 8,
+                    value: TypedValue::Bytes(b"sensor-7".to_vec()),
+                },
+                vec![ack(STATUS_OK), ack(STATUS_CONFLICT)],
+            ),
+            (
+                Request::SetTyped {
+                    key: 9,
+                    value: TypedValue::U64(u64::MAX - 1),
+                },
+                vec![ack(STATUS_OK)],
+            ),
+            (
+                Request::SetSorted { key: 10, value: 42 },
+                vec![ack(STATUS_OK), ack(STATUS_CONFLICT)],
+            ),
+            (
+                Request::GetSortedRange {
+                    key: 10,
+                    min: 5,
+                    max: u32::MAX,
+                },
+                vec![
+                    Response::Values(vec![5, 42, 42]),
+                    Response::Values(Vec::new()),
+                    Response::Status(STATUS_NOT_FOUND),
+                ],
+            ),
+            (
+                Request::GetFilter {
+                    key: 10,
+                    min: 0,
+                    max: 9,
+                },
+                vec![
+                    Response::Values(vec![9, 0, 3]),
+                    Response::Status(STATUS_NOT_FOUND),
+                ],
+            ),
+            (
+                Request::Compare {
+                    a: 4,
+                    b: 255,
+                    comparison: Comparison::OnlySecond,
+                },
+                vec![
+                    Response::Values(vec![7, 1]),
+                    Response::Values(Vec::new()),
+                    Response::Status(STATUS_CONFLICT),
+                ],
+            ),
+            (
+                Request::Aggregate {
+                    key: 12,
+                    aggregate: Aggregate::Avg,
+                },
+                vec![
+                    Response::Aggregate {
+                        result: 2.5f64.to_bits(),
+                    },
+                    Response::Status(STATUS_CONFLICT),
+                ],
+            ),
+            (
+                Request::Aggregate {
+                    key: 12,
+                    aggregate: Aggregate::Sum,
+                },
+                vec![Response::Aggregate { result: u64::MAX }],
+            ),
+            (
+                Request::Dedup { key: 4 },
+                vec![
+                    Response::Removed { seq: 3, value: 2 },
+                    ack(STATUS_NOT_FOUND),
+                ],
+            ),
+            (
+                Request::SetExpiring {
+                    key: 11,
+                    value: 7,
+                    ttl_ms: 30_000,
+                },
+                vec![ack(STATUS_OK), ack(STATUS_CAPACITY_EXCEEDED)],
+            ),
+            (
+                Request::SetTimed {
+                    key: 12,
+                    value: 7,
+                    at_ms: 1_700_000_000_000,
+                },
+                vec![ack(STATUS_OK), ack(STATUS_CONFLICT)],
+            ),
+            (
+                Request::Push {
+                    key: 12,
+                    values: vec![7, 0, u32::MAX],
+                },
+                vec![
+                    Response::Removed { seq: 9, value: 5 },
+                    ack(STATUS_CAPACITY_EXCEEDED),
+                ],
+            ),
+            (
+                Request::Dump {
+                    name: "before-upgrade.m8d".to_string(),
+                },
+                vec![
+                    Response::Status(STATUS_OK),
+                    Response::Status(STATUS_UNAVAILABLE),
+                ],
+            ),
+            (
+                Request::Restore {
+                    name: "before-upgrade.m8d".to_string(),
+                    merge: true,
+                },
+                vec![ack(STATUS_OK), ack(STATUS_NOT_FOUND), ack(STATUS_CONFLICT)],
+            ),
+            (
+                Request::GetTyped { key: 8 },
+                vec![
+                    Response::Typed {
+                        value_type: VALUE_TYPE_BYTES,
+                        values: vec![
+                            TypedValue::Bytes(b"sensor-7".to_vec()),
+                            TypedValue::Bytes(Vec::new()),
+                        ],
+                    },
+                    Response::Typed {
+                        value_type: VALUE_TYPE_U64,
+                        values: vec![TypedValue::U64(1 << 40)],
+                    },
+                    Response::Typed {
+                        value_type: VALUE_TYPE_U32,
+                        values: vec![TypedValue::U32(7)],
+                    },
+                    Response::Status(STATUS_NOT_FOUND),
+                ],
+            ),
+            (
+                Request::Select { db: 3 },
+                vec![
+                    Response::Status(STATUS_OK),
+                    Response::Status(STATUS_BAD_REQUEST),
+                ],
+            ),
+            (
+                Request::GetDel { key: 4 },
+                vec![
+                    Response::Taken {
+                        seq: 12,
+                        values: vec![3, u32::MAX, 3],
+                    },
+                    ack(STATUS_NOT_FOUND),
+                    ack(STATUS_CONFLICT),
+                ],
+            ),
+            (
+                Request::GetSet {
+                    key: 4,
+                    value: Some(9),
+                },
+                vec![
+                    Response::Taken {
+                        seq: 13,
+                        values: vec![1, 2],
+                    },
+                    Response::Taken {
+                        seq: 14,
+                        values: vec![],
+                    },
+                    ack(STATUS_CAPACITY_EXCEEDED),
+                ],
+            ),
+            (
+                Request::GetSet {
+                    key: 4,
+                    value: None,
+                },
+                vec![Response::Taken {
+                    seq: 15,
+                    values: vec![7],
+                }],
+            ),
+            (
+                Request::DeleteAt { key: 4, index: 2 },
+                vec![
+                    Response::Removed {
+                        seq: 12,
+                        value: u32::MAX,
+                    },
+                    ack(STATUS_NOT_FOUND),
+                    ack(STATUS_CONFLICT),
+                ],
+            ),
+            (
+                Request::GetLastN { key: 3, n: 2 },
+                vec![
+                    Response::Values(vec![8, 9]),
+                    Response::Status(STATUS_NOT_FOUND),
+                ],
+            ),
+            (
+                Request::GetFirstN { key: 3, n: 0 },
+                vec![
+                    Response::Values(Vec::new()),
+                    Response::Status(STATUS_NOT_FOUND),
+                ],
+            ),
+            (
+                Request::RandomKey { with_value: true },
+                vec![
+                    Response::RandomKey {
+                        key: 255,
+                        value: Some(7),
+                    },
+                    Response::Status(STATUS_NOT_FOUND),
+                ],
+            ),
+            (
+                Request::RandomKey { with_value: false },
+                vec![Response::RandomKey {
+                    key: 0,
+                    value: None,
+                }],
+            ),
+            (
+                Request::Scan {
+                    cursor: 17,
+                    count: 0,
+                },
+                vec![
+                    Response::Scan {
+                        cursor: 41,
+                        keys: vec![17, 30, 40],
+                    },
+                    Response::Scan {
+                        cursor: 0,
+                        keys: Vec::new(),
+                    },
+                    Response::Status(STATUS_BAD_REQUEST),
+                ],
+            ),
+            (
+                Request::Rename {
+                    from: 1,
+                    to: 255,
+                    merge: true,
+                },
+                vec![ack(STATUS_OK), ack(STATUS_NOT_FOUND), ack(STATUS_CONFLICT)],
+            ),
+            (
+                Request::Rename {
+                    from: 0,
+                    to: 0,
+                    merge: false,
+                },
+                vec![ack(STATUS_OK)],
+            ),
+            (
+                Request::Copy {
+                    from: 200,
+                    to: 3,
+                    append: false,
+                },
+                vec![ack(STATUS_OK), ack(STATUS_CAPACITY_EXCEEDED)],
+            ),
+            (
+                Request::Swap { a: 4, b: 255 },
+                vec![ack(STATUS_OK), ack(STATUS_NOT_FOUND)],
+            ),
+            (
+                Request::SyncFull,
+                vec![
+                    Response::FullSync {
+                        seq: 9,
+                        entries: vec![(0, vec![5]), (9, vec![1, 2, 3])],
+                    },
+                    Response::FullSync {
+                        seq: 0,
+                        entries: Vec::new(),
+                    },
+                ],
+            ),
+            (
+                Request::Invalid { op: 200 },
+                vec![Response::Status(STATUS_BAD_REQUEST)],
+            ),
+        ]
+    }
+
+    /// Native v1 has no AWAIT_SEQ and drops sequence numbers from acks.
+    fn supported(codec: &dyn Codec, request: &Request) -> bool {
+        codec.name() != "native-v1" || !matches!(request, Request::AwaitSeq { .. })
+    }
+
+    fn as_delivered(codec: &dyn Codec, response: &Response) -> Response {
+        match response {
+            Response::Ack { status, .. } if codec.name() == "native-v1" => Response::Ack {
+                status: *status,
+                seq: 0,
+            },
+            Response::Removed { value, .. } if codec.name() == "native-v1" => Response::Removed {
+                seq: 0,
+                value: *value,
+            },
+            Response::Taken { values, .. } if codec.name() == "native-v1" => Response::Taken {
+                seq: 0,
+                values: values.clone(),
+            },
+            Response::DeletedMany { statuses, .. } if codec.name() == "native-v1" => {
+                Response::DeletedMany {
+                    seq: 0,
+                    statuses: statuses.clone(),
+                }
+            }
+            response => response.clone(),
+        }
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        for codec in codecs() {
+            for (request, _) in exchanges() {
+                if !supported(codec, &request) {
+                    continue;
+                }
+                let mut bytes = Vec::new();
+                codec.encode_request(&request, &mut bytes);
+                let decoded = codec.decode_request(&bytes).unwrap();
+                assert_eq!(
+                    decoded,
+                    Some((request.clone(), bytes.len())),
+                    "{}",
+                    codec.name()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        for codec in codecs() {
+            for (request, responses) in exchanges() {
+                if !supported(codec, &request) {
+                    continue;
+                }
+                for response in responses {
+                    let mut bytes = Vec::new();
+                    codec.encode_response(&response, &mut bytes);
+                    let decoded = codec.decode_response(&request, &bytes).unwrap();
+                    assert_eq!(
+                        decoded,
+                        Some((as_delivered(codec, &response), bytes.len())),
+                        "{} answering {request:?}",
+                        codec.name()
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn partial_input_waits_for_more() {
+        for codec in codecs() {
+            for (request, responses) in exchanges() {
+                if !supported(codec, &request) {
+                    continue;
+                }
+                let mut bytes = Vec::new();
+                codec.encode_request(&request, &mut bytes);
+                for end in 0..bytes.len() {
+                    assert_eq!(codec.decode_request(&bytes[..end]), Ok(None));
+                }
+                for response in responses {
+                    let mut bytes = Vec::new();
+                    codec.encode_response(&response, &mut bytes);
+                    for end in 0..bytes.len() {
+                        assert_eq!(codec.decode_response(&request, &bytes[..end]), Ok(None));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pipelined_requests_decode_in_order() {
+        for codec in codecs() {
+            let requests: Vec<Request> = exchanges()
+                .into_iter()
+                .map(|(request, _)| request)
+                .filter(|request| supported(codec, request))
+                .collect();
+            let mut bytes = Vec::new();
+            for request in &requests {
+                codec.encode_request(request, &mut bytes);
+            }
+            let mut offset = 0;
+            for request in &requests {
+                let (decoded, len) = codec.decode_request(&bytes[offset..]).unwrap().unwrap();
+                assert_eq!(&decoded, request, "{}", codec.name());
+                offset += len;
+            }
+            assert_eq!(offset, bytes.len());
+        }
+    }
+
+    #[test]
+    fn oversized_requests_are_rejected() {
+        let request = Request::ReplaceIf {
+            key: 0,
+            expected: 0,
+            values: vec![0; MAX_REPLACE_VALUES as usize + 1],
+        };
+        for codec in codecs() {
+            let mut bytes = Vec::new();
+            codec.encode_request(&request, &mut bytes);
+            assert_eq!(
+                codec.decode_request(&bytes),
+                Err(CodecError::Oversized),
+                "{}",
+                codec.name()
+            );
+        }
+
+        let mut huge = Vec::new();
+        huge.extend_from_slice(&(MAX_MESSAGE_LEN as u32 + 1).to_le_bytes());
+        assert_eq!(MSGPACK.decode_request(&huge), Err(CodecError::Oversized));
+        assert_eq!(PROTOBUF.decode_request(&huge), Err(CodecError::Oversized));
+    }
+
+    #[test]
+    fn refusals_keep_the_write_ack_shape() {
+        for codec in codecs() {
+            for (request, _) in exchanges() {
+                if !supported(codec, &request) {
+                    continue;
+                }
+                let refusal = Response::refusal(&request, STATUS_THROTTLED);
+                let mut bytes = Vec::new();
+                codec.encode_response(&refusal, &mut bytes);
+                let (decoded, _) = codec.decode_response(&request, &bytes).unwrap().unwrap();
+                assert_eq!(decoded, as_delivered(codec, &refusal), "{}", codec.name());
+            }
+        }
+    }
+
+    #[test]
+    fn encoded_values_match_the_values_response() {
+        for codec in codecs() {
+            for values in [vec![], vec![7], vec![1, u32::MAX, 3]] {
+                let (mut direct, mut built) = (Vec::new(), Vec::new());
+                codec.encode_values(&values, &mut direct);
+                codec.encode_response(&Response::Values(values), &mut built);
+                assert_eq!(direct, built, "{}", codec.name());
+            }
+        }
+    }
+
+    #[test]
+    fn corrupted_sync_full_is_rejected() {
+        let response = Response::FullSync {
+            seq: 3,
+            entries: vec![(1, vec![10, 20])],
+        };
+        for codec in [&NATIVE_V1, &NATIVE_V2] {
+            let mut bytes = Vec::new();
+            codec.encode_response(&response, &mut bytes);
+            bytes[20] ^= 0xFF;
+            assert!(matches!(
+                codec.decode_response(&Request::SyncFull, &bytes),
+                Err(CodecError::Malformed(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn native_v1_treats_await_seq_as_unknown() {
+        let mut bytes = Frame::new(OP_AWAIT_SEQ, 0, 0).encode().to_vec();
+        bytes.extend_from_slice(&7u64.to_le_bytes());
+        assert_eq!(
+            NATIVE_V1.decode_request(&bytes),
+            Ok(Some((Request::Invalid { op: OP_AWAIT_SEQ }, FRAME_LEN)))
+        );
+    }
+
+    #[test]
+    fn chunked_responses_reassemble() {
+        let mut framing = Framing::default();
+        let mut out = Vec::new();
+        let multiplexed = framing.multiplex();
+        framing.encode_response(None, &multiplexed, &mut out);
+        let chunked = framing.chunk(0, 8);
+        framing.encode_response(Some(1), &chunked, &mut out);
+        assert_eq!(out, [STATUS_OK, 1, 0, 0, 0, STATUS_OK]);
+
+        // 17 bytes each: the status, the count and three values.
+        let (long, short) = (
+            Response::Values(vec![1, 2, 3]),
+            Response::Values(vec![4, 5, 6]),
+        );
+        out.clear();
+        framing.queue_response(Some(2), &long, &mut out);
+        framing.queue_response(Some(3), &short, &mut out);
+        while framing.continuing() {
+            framing.encode_continuations(&mut out);
+        }
+        let mut chunks = Vec::new();
+        let mut bodies: [Vec<u8>; 2] = Default::default();
+        let mut rest = &out[..];
+        while let Some(id) = rest.get(..4) {
+            let id = u32::from_le_bytes(id.try_into().unwrap());
+            let (bytes, more, len) = split_chunk(&rest[4..]).unwrap();
+            chunks.push((id, bytes.len(), more));
+            bodies[id as usize - 2].extend_from_slice(bytes);
+            rest = &rest[4 + len..];
+        }
+        assert_eq!(
+            chunks,
+            [
+                (2, 8, true),
+                (3, 8, true),
+                (2, 8, true),
+                (3, 8, true),
+                (2, 1, false),
+                (3, 1, false)
+            ]
+        );
+        for (body, response) in bodies.iter().zip([long, short]) {
+            let decoded = NATIVE_V1.decode_response(&Request::Get { key: 0 }, body);
+            assert_eq!(decoded, Ok(Some((response, body.len()))));
+        }
+    }
+
+    #[test]
+    fn select_picks_native_by_version() {
+        assert_eq!(select(CODEC_NATIVE, 1).unwrap().name(), "native-v1");
+        assert_eq!(select(CODEC_NATIVE, 2).unwrap().name(), "native-v2");
+        assert_eq!(select(CODEC_MSGPACK, 1).unwrap().name(), "msgpack");
+        assert_eq!(select(CODEC_PROTOBUF, 2).unwrap().name(), "protobuf");
+        assert!(select(9, 2).is_none());
+    }
+}
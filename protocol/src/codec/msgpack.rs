@@ -0,0 +1,51 @@
+use super::{frame_message, split_message, Codec, CodecError, Request, Response};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Length-prefixed MessagePack: `[len: u32 LE]` and a map-encoded
+/// [`Request`] or [`Response`], externally tagged by variant name.
+#[derive(Debug)]
+pub struct MsgPack;
+
+fn decode<T: DeserializeOwned>(buf: &[u8]) -> Result<Option<(T, usize)>, CodecError> {
+    let Some((message, len)) = split_message(buf)? else {
+        return Ok(None);
+    };
+    let value = rmp_serde::from_slice(message).map_err(|e| CodecError::Malformed(e.to_string()))?;
+    Ok(Some((value, len)))
+}
+
+fn encode(value: &impl Serialize, out: &mut Vec<u8>) {
+    frame_message(out, |out| {
+        rmp_serde::encode::write_named(out, value).expect("msgpack encoding into a Vec")
+    });
+}
+
+impl Codec for MsgPack {
+    fn name(&self) -> &'static str {
+        "msgpack"
+    }
+
+    fn decode_request(&self, buf: &[u8]) -> Result<Option<(Request, usize)>, CodecError> {
+        match decode::<Request>(buf)? {
+            Some((request, len)) => Ok(Some((request.validate()?, len))),
+            None => Ok(None),
+        }
+    }
+
+    fn encode_request(&self, request: &Request, out: &mut Vec<u8>) {
+        encode(request, out);
+    }
+
+    fn decode_response(
+        &self,
+        _request: &Request,
+        buf: &[u8],
+    ) -> Result<Option<(Response, usize)>, CodecError> {
+        decode(buf)
+    }
+
+    fn encode_response(&self, response: &Response, out: &mut Vec<u8>) {
+        encode(response, out);
+    }
+}
@@ -0,0 +1,1232 @@
+use super::{
+    Aggregate, Allocation, Codec, CodecError, ConnectedClient, DeadLetter, Fields, Health, Info,
+    KeyStats, KeyspaceEvent, MonitoredOp, OpLatency, PersistenceState, Quantiles, Request,
+    Response, SlowOp, TypedValue, MAX_FIELD_LEN,
+};
+use crate::*;
+
+/// The fixed 6-byte frame format, followed by an op-specific trailer for
+/// AWAIT_SEQ, ALLOC_REGISTER, REPLACE_IF, PUSH, AUTH, SET_TYPED and DELETE_MANY. From version 2 on,
+/// write acks carry the sequence number and AWAIT_SEQ is understood.
+#[derive(Debug)]
+pub struct Native {
+    version: u8,
+}
+
+impl Native {
+    pub const fn new(version: u8) -> Self {
+        Native { version }
+    }
+
+    fn sessions(&self) -> bool {
+        self.version >= SESSION_PROTOCOL_VERSION
+    }
+}
+
+/// Reads little-endian fields off a buffer, yielding `None` once it runs out.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Reader { buf, pos: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.buf.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.bytes(8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn values(&mut self, count: usize) -> Option<Vec<u32>> {
+        let bytes = self.bytes(count.checked_mul(4)?)?;
+        Some(
+            bytes
+                .chunks_exact(4)
+                .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+                .collect(),
+        )
+    }
+
+    fn counted_values(&mut self) -> Option<Vec<u32>> {
+        let count = self.u32()? as usize;
+        self.values(count)
+    }
+
+    fn field(&mut self) -> Option<Vec<u8>> {
+        let len = self.u8()? as usize;
+        self.bytes(len).map(<[u8]>::to_vec)
+    }
+}
+
+/// Reads SYNC_FULL's `[len: u32][body][crc32: u32]`, checking the body
+/// against its checksum before parsing it into `fields`.
+fn read_full_sync(reader: &mut Reader<'_>, fields: &mut Fields) -> Option<Result<(), CodecError>> {
+    let len = reader.u32()? as usize;
+    let body = reader.bytes(len)?;
+    let checksum = reader.u32()?;
+    if crc32(body) != checksum {
+        return Some(Err(CodecError::Malformed(
+            "SYNC_FULL checksum mismatch".to_string(),
+        )));
+    }
+    let mut body = Reader::new(body);
+    let parsed = (|| {
+        fields.seq = body.u64()?;
+        let count = body.u32()?;
+        for _ in 0..count {
+            let key = body.u8()?;
+            fields.entries.push((key, body.counted_values()?));
+        }
+        (body.pos == len).then_some(())
+    })();
+    Some(parsed.ok_or_else(|| CodecError::Malformed("truncated SYNC_FULL body".to_string())))
+}
+
+fn push_values(out: &mut Vec<u8>, values: &[u32]) {
+    out.extend_from_slice(&(values.len() as u32).to_le_bytes());
+    // Sized up front and filled in place, which compiles to a plain copy
+    // on little-endian targets rather than a push per value.
+    let start = out.len();
+    out.resize(start + values.len() * 4, 0);
+    for (bytes, value) in out[start..].chunks_exact_mut(4).zip(values) {
+        bytes.copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+impl Allocation {
+    /// Parses an ALLOC_REGISTER payload:
+    /// `[last_key][name_len][name][contact_len][contact][policy_len][policy]`.
+    pub fn decode(first_key: u8, payload: &[u8]) -> Option<Allocation> {
+        let mut reader = Reader::new(payload);
+        let last_key = reader.u8()?;
+        let mut field = || String::from_utf8(reader.field()?).ok();
+        let allocation = Allocation {
+            first_key,
+            last_key,
+            name: field()?,
+            contact: field()?,
+            policy: field()?,
+        };
+        if reader.pos != payload.len()
+            || allocation.first_key > allocation.last_key
+            || allocation.name.is_empty()
+        {
+            return None;
+        }
+        Some(allocation)
+    }
+
+    fn encode_fields(&self, out: &mut Vec<u8>) {
+        for field in [&self.name, &self.contact, &self.policy] {
+            let bytes = &field.as_bytes()[..field.len().min(MAX_FIELD_LEN)];
+            out.push(bytes.len() as u8);
+            out.extend_from_slice(bytes);
+        }
+    }
+
+    /// Appends the ALLOC_LIST entry: `[first_key][last_key]` and the fields.
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        out.push(self.first_key);
+        out.push(self.last_key);
+        self.encode_fields(out);
+    }
+
+    fn read(reader: &mut Reader<'_>) -> Option<Result<Allocation, CodecError>> {
+        let first_key = reader.u8()?;
+        let last_key = reader.u8()?;
+        let (name, contact, policy) = (reader.field()?, reader.field()?, reader.field()?);
+        let text = |bytes| {
+            String::from_utf8(bytes)
+                .map_err(|_| CodecError::Malformed("allocation field is not UTF-8".to_string()))
+        };
+        Some((|| {
+            Ok(Allocation {
+                first_key,
+                last_key,
+                name: text(name)?,
+                contact: text(contact)?,
+                policy: text(policy)?,
+            })
+        })())
+    }
+}
+
+pub const DEAD_LETTER_LEN: usize = 15;
+
+impl DeadLetter {
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.at_unix_ms.to_le_bytes());
+        out.push(self.op);
+        out.push(self.key);
+        out.extend_from_slice(&self.value.to_le_bytes());
+        out.push(self.reason);
+    }
+
+    fn read(reader: &mut Reader<'_>) -> Option<DeadLetter> {
+        Some(DeadLetter {
+            at_unix_ms: reader.u64()?,
+            op: reader.u8()?,
+            key: reader.u8()?,
+            value: reader.u32()?,
+            reason: reader.u8()?,
+        })
+    }
+}
+
+pub const SLOW_OP_LEN: usize = 22;
+
+impl SlowOp {
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.at_unix_ms.to_le_bytes());
+        out.push(self.op);
+        out.push(self.key);
+        out.extend_from_slice(&self.micros.to_le_bytes());
+        out.extend_from_slice(&self.request_bytes.to_le_bytes());
+    }
+
+    fn read(reader: &mut Reader<'_>) -> Option<SlowOp> {
+        Some(SlowOp {
+            at_unix_ms: reader.u64()?,
+            op: reader.u8()?,
+            key: reader.u8()?,
+            micros: reader.u64()?,
+            request_bytes: reader.u32()?,
+        })
+    }
+}
+
+impl ConnectedClient {
+    /// Stands in for the uid of a peer that is not on a Unix socket; no
+    /// user has it.
+    const NO_UID: u32 = u32::MAX;
+
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.id.to_le_bytes());
+        out.extend_from_slice(&self.uid.unwrap_or(Self::NO_UID).to_le_bytes());
+        out.extend_from_slice(&self.connected_unix_ms.to_le_bytes());
+        out.extend_from_slice(&self.requests.to_le_bytes());
+        out.extend_from_slice(&self.bytes_in.to_le_bytes());
+        out.extend_from_slice(&self.bytes_out.to_le_bytes());
+        out.push(self.last_op);
+        put_field(out, &self.peer);
+    }
+
+    fn read(reader: &mut Reader<'_>) -> Option<ConnectedClient> {
+        Some(ConnectedClient {
+            id: reader.u64()?,
+            uid: Some(reader.u32()?).filter(|&uid| uid != Self::NO_UID),
+            connected_unix_ms: reader.u64()?,
+            requests: reader.u64()?,
+            bytes_in: reader.u64()?,
+            bytes_out: reader.u64()?,
+            last_op: reader.u8()?,
+            peer: String::from_utf8_lossy(&reader.field()?).into_owned(),
+        })
+    }
+}
+
+/// Writes `text` as a `[len: u8][bytes]` field, cut to [`MAX_FIELD_LEN`].
+fn put_field(out: &mut Vec<u8>, text: &str) {
+    let bytes = &text.as_bytes()[..text.len().min(MAX_FIELD_LEN)];
+    out.push(bytes.len() as u8);
+    out.extend_from_slice(bytes);
+}
+
+impl MonitoredOp {
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.at_unix_ms.to_le_bytes());
+        out.extend_from_slice(&[self.db, self.op, self.key]);
+        out.extend_from_slice(&self.value.to_le_bytes());
+        out.push(self.status);
+        out.extend_from_slice(&self.micros.to_le_bytes());
+        put_field(out, &self.peer);
+        put_field(out, &self.command);
+    }
+
+    fn read(reader: &mut Reader<'_>) -> Option<MonitoredOp> {
+        let text = |bytes: Vec<u8>| String::from_utf8_lossy(&bytes).into_owned();
+        Some(MonitoredOp {
+            at_unix_ms: reader.u64()?,
+            db: reader.u8()?,
+            op: reader.u8()?,
+            key: reader.u8()?,
+            value: reader.u32()?,
+            status: reader.u8()?,
+            micros: reader.u64()?,
+            peer: text(reader.field()?),
+            command: text(reader.field()?),
+        })
+    }
+}
+
+impl KeyspaceEvent {
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&[self.event, self.key]);
+        out.extend_from_slice(&self.count.to_le_bytes());
+    }
+
+    fn read(reader: &mut Reader<'_>) -> Option<KeyspaceEvent> {
+        Some(KeyspaceEvent {
+            event: reader.u8()?,
+            key: reader.u8()?,
+            count: reader.u64()?,
+        })
+    }
+}
+
+impl KeyStats {
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        for counter in [self.hits, self.misses, self.sets, self.last_access_ms] {
+            out.extend_from_slice(&counter.to_le_bytes());
+        }
+    }
+
+    fn read(reader: &mut Reader<'_>) -> Option<KeyStats> {
+        Some(KeyStats {
+            hits: reader.u64()?,
+            misses: reader.u64()?,
+            sets: reader.u64()?,
+            last_access_ms: reader.u64()?,
+        })
+    }
+}
+
+impl TypedValue {
+    /// Appends the value as GET_TYPED lists it: integers at their width,
+    /// blobs as `[len: u8][bytes]`.
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        if let TypedValue::Bytes(bytes) = self {
+            out.push(bytes.len() as u8);
+        }
+        out.extend_from_slice(&self.payload());
+    }
+
+    fn read(reader: &mut Reader<'_>, value_type: u8) -> Option<Result<TypedValue, CodecError>> {
+        let payload = match value_type {
+            VALUE_TYPE_U32 => reader.bytes(4)?.to_vec(),
+            VALUE_TYPE_U64 => reader.bytes(8)?.to_vec(),
+            _ => reader.field()?,
+        };
+        Some(
+            TypedValue::from_payload(value_type, &payload)
+                .ok_or_else(|| CodecError::Malformed(format!("unknown value type {value_type}"))),
+        )
+    }
+}
+
+impl Info {
+    /// Appends the INFO body: six u64 totals (`uptime_ms`, `keys`, `values`,
+    /// `memory_bytes`, `hits`, `misses`), then `[op_count: u8]` and
+    /// `[name_len: u8][name][count: u64]` per operation, then `[persisted:
+    /// u8]` and, if it is 1, `[health: u8][failures: u64]`, then
+    /// `[allocation_count: u32]` and the ALLOC_LIST entries, then
+    /// `[quota_rejections: u64][max_keys: u64][max_values: u64]` with 0 for
+    /// no limit. Latencies follow as
+    /// `[count: u8]` and `[op: u8]` then the wait and run quantiles, six u64s
+    /// each, per operation.
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        for total in [
+            self.uptime_ms,
+            self.keys,
+            self.values,
+            self.memory_bytes,
+            self.hits,
+            self.misses,
+        ] {
+            out.extend_from_slice(&total.to_le_bytes());
+        }
+        let ops = &self.ops[..self.ops.len().min(u8::MAX as usize)];
+        out.push(ops.len() as u8);
+        for (name, count) in ops {
+            let name = &name.as_bytes()[..name.len().min(MAX_FIELD_LEN)];
+            out.push(name.len() as u8);
+            out.extend_from_slice(name);
+            out.extend_from_slice(&count.to_le_bytes());
+        }
+        match self.persistence {
+            Some(persistence) => {
+                out.extend_from_slice(&[1, persistence.health as u8]);
+                out.extend_from_slice(&persistence.failures.to_le_bytes());
+            }
+            None => out.push(0),
+        }
+        out.extend_from_slice(&(self.allocations.len() as u32).to_le_bytes());
+        for allocation in &self.allocations {
+            allocation.encode_into(out);
+        }
+        for total in [
+            self.quota_rejections,
+            self.max_keys.unwrap_or(0),
+            self.max_values.unwrap_or(0),
+        ] {
+            out.extend_from_slice(&total.to_le_bytes());
+        }
+        if let Some(latencies) = &self.latencies {
+            let latencies = &latencies[..latencies.len().min(u8::MAX as usize)];
+            out.push(latencies.len() as u8);
+            for latency in latencies {
+                out.push(latency.op);
+                for quantiles in [latency.wait, latency.run] {
+                    for value in [
+                        quantiles.count,
+                        quantiles.p50,
+                        quantiles.p90,
+                        quantiles.p99,
+                        quantiles.p999,
+                        quantiles.max,
+                    ] {
+                        out.extend_from_slice(&value.to_le_bytes());
+                    }
+                }
+            }
+        }
+    }
+
+    fn read(reader: &mut Reader<'_>, latencies: bool) -> Option<Result<Info, CodecError>> {
+        let mut info = Info {
+            uptime_ms: reader.u64()?,
+            keys: reader.u64()?,
+            values: reader.u64()?,
+            memory_bytes: reader.u64()?,
+            hits: reader.u64()?,
+            misses: reader.u64()?,
+            ops: Vec::new(),
+            persistence: None,
+            allocations: Vec::new(),
+            quota_rejections: 0,
+            max_keys: None,
+            max_values: None,
+            latencies: None,
+        };
+        for _ in 0..reader.u8()? {
+            let name = reader.field()?;
+            let count = reader.u64()?;
+            match String::from_utf8(name) {
+                Ok(name) => info.ops.push((name, count)),
+                Err(_) => {
+                    return Some(Err(CodecError::Malformed(
+                        "op name is not UTF-8".to_string(),
+                    )))
+                }
+            }
+        }
+        if reader.u8()? == 1 {
+            let Some(health) = Health::from_u8(reader.u8()?) else {
+                return Some(Err(CodecError::Malformed(
+                    "unknown persistence health".to_string(),
+                )));
+            };
+            info.persistence = Some(PersistenceState {
+                health,
+                failures: reader.u64()?,
+            });
+        }
+        for _ in 0..reader.u32()? {
+            match Allocation::read(reader)? {
+                Ok(allocation) => info.allocations.push(allocation),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        let limit = |limit: u64| (limit > 0).then_some(limit);
+        info.quota_rejections = reader.u64()?;
+        info.max_keys = limit(reader.u64()?);
+        info.max_values = limit(reader.u64()?);
+        if latencies {
+            let quantiles = |reader: &mut Reader<'_>| {
+                Some(Quantiles {
+                    count: reader.u64()?,
+                    p50: reader.u64()?,
+                    p90: reader.u64()?,
+                    p99: reader.u64()?,
+                    p999: reader.u64()?,
+                    max: reader.u64()?,
+                })
+            };
+            let mut latencies = Vec::new();
+            for _ in 0..reader.u8()? {
+                latencies.push(OpLatency {
+                    op: reader.u8()?,
+                    wait: quantiles(reader)?,
+                    run: quantiles(reader)?,
+                });
+            }
+            info.latencies = Some(latencies);
+        }
+        Some(Ok(info))
+    }
+}
+
+impl Codec for Native {
+    fn name(&self) -> &'static str {
+        if self.sessions() {
+            "native-v2"
+        } else {
+            "native-v1"
+        }
+    }
+
+    fn decode_request(&self, buf: &[u8]) -> Result<Option<(Request, usize)>, CodecError> {
+        let mut reader = Reader::new(buf);
+        let Some(header) = reader.bytes(FRAME_LEN) else {
+            return Ok(None);
+        };
+        let Frame { op, key, value } = Frame::decode(header.try_into().unwrap());
+        let request = match op {
+            OP_SET => Request::Set { key, value },
+            OP_GET => Request::Get { key },
+            OP_DELETE_BY_KEY => Request::DeleteByKey { key },
+            OP_DELETE_ALL => Request::DeleteAll { token: value },
+            OP_LIST_RANGE => match u8::try_from(value) {
+                Ok(last) => Request::ListRange { first: key, last },
+                Err(_) => Request::Invalid { op },
+            },
+            OP_LIST_ALL => match key {
+                0 | 1 => Request::ListAll { snapshot: key == 1 },
+                _ => Request::Invalid { op },
+            },
+            OP_TAG => match u8::try_from(value) {
+                Ok(tag) => Request::Tag { key, tag },
+                Err(_) => Request::Invalid { op },
+            },
+            OP_SWAP => match u8::try_from(value) {
+                Ok(b) => Request::Swap { a: key, b },
+                Err(_) => Request::Invalid { op },
+            },
+            OP_COMPARE => Request::compare(op, key, value),
+            OP_LIST_TAGGED => Request::ListTagged { tag: key },
+            OP_DELETE_TAGGED => Request::DeleteTagged { tag: key },
+            OP_HELLO => Request::Hello {
+                version: key,
+                magic: value,
+            },
+            OP_AWAIT_SEQ if self.sessions() => {
+                let Some(seq) = reader.u64() else {
+                    return Ok(None);
+                };
+                Request::AwaitSeq { seq }
+            }
+            OP_DEAD_LETTERS => Request::DeadLetters { drain: value == 1 },
+            OP_ALLOC_REGISTER | OP_AUTH | OP_DUMP | OP_RESTORE | OP_CONFIG
+                if value as usize > MAX_ADMIN_PAYLOAD =>
+            {
+                return Err(CodecError::Oversized)
+            }
+            OP_ALLOC_REGISTER => {
+                let Some(payload) = reader.bytes(value as usize) else {
+                    return Ok(None);
+                };
+                match Allocation::decode(key, payload) {
+                    Some(allocation) => Request::AllocRegister(allocation),
+                    None => Request::Invalid { op },
+                }
+            }
+            OP_ALLOC_RELEASE => Request::AllocRelease { first_key: key },
+            OP_ALLOC_LIST => Request::AllocList,
+            OP_GET_VERSIONED => Request::GetVersioned { key },
+            OP_DELETE_MANY if value > MAX_DELETE_MANY_KEYS => return Err(CodecError::Oversized),
+            OP_DELETE_MANY => {
+                let Some(keys) = reader.bytes(value as usize) else {
+                    return Ok(None);
+                };
+                Request::DeleteMany {
+                    keys: keys.to_vec(),
+                }
+            }
+            OP_REPLACE_IF | OP_PUSH if value > MAX_REPLACE_VALUES => {
+                return Err(CodecError::Oversized)
+            }
+            OP_REPLACE_IF => {
+                let (Some(expected), Some(values)) = (reader.u64(), reader.values(value as usize))
+                else {
+                    return Ok(None);
+                };
+                Request::ReplaceIf {
+                    key,
+                    expected,
+                    values,
+                }
+            }
+            OP_PUSH => {
+                let Some(values) = reader.values(value as usize) else {
+                    return Ok(None);
+                };
+                Request::Push { key, values }
+            }
+            OP_AUTH => {
+                let Some(token) = reader.bytes(value as usize) else {
+                    return Ok(None);
+                };
+                Request::Auth {
+                    token: token.to_vec(),
+                }
+            }
+            OP_DUMP => {
+                let Some(name) = reader.bytes(value as usize) else {
+                    return Ok(None);
+                };
+                match String::from_utf8(name.to_vec()) {
+                    Ok(name) => Request::Dump { name },
+                    Err(_) => Request::Invalid { op },
+                }
+            }
+            OP_RESTORE => {
+                let Some(name) = reader.bytes(value as usize) else {
+                    return Ok(None);
+                };
+                match (String::from_utf8(name.to_vec()), key) {
+                    (Ok(name), 0 | 1) => Request::Restore {
+                        name,
+                        merge: key == 1,
+                    },
+                    _ => Request::Invalid { op },
+                }
+            }
+            OP_CONFIG => {
+                let Some(payload) = reader.bytes(value as usize) else {
+                    return Ok(None);
+                };
+                Request::config(op, key, payload.to_vec())
+            }
+            OP_SELECT_CODEC => Request::SelectCodec { codec: key },
+            OP_RESTORE_KEY => Request::RestoreKey { key },
+            OP_DELETE_AT => Request::DeleteAt { key, index: value },
+            OP_GET_LAST_N => Request::GetLastN { key, n: value },
+            OP_GET_FIRST_N => Request::GetFirstN { key, n: value },
+            OP_RANDOM_KEY => Request::RandomKey {
+                with_value: value == 1,
+            },
+            OP_SCAN => Request::Scan {
+                cursor: value,
+                count: key,
+            },
+            OP_RENAME | OP_COPY => Request::transfer(op, key, value),
+            OP_SUM | OP_MIN | OP_MAX | OP_AVG | OP_COUNT => Request::Aggregate {
+                key,
+                aggregate: Aggregate::from_op(op).unwrap(),
+            },
+            OP_DEDUP => Request::Dedup { key },
+            OP_GETDEL => Request::GetDel { key },
+            OP_GETSET => match value {
+                0 => Request::GetSet { key, value: None },
+                1 => {
+                    let Some(value) = reader.u32() else {
+                        return Ok(None);
+                    };
+                    Request::GetSet {
+                        key,
+                        value: Some(value),
+                    }
+                }
+                _ => Request::Invalid { op },
+            },
+            OP_KEYSTATS => Request::KeyStats { key },
+            OP_MEMUSAGE => Request::MemoryUsage {
+                key: (value != 1).then_some(key),
+            },
+            OP_SORT => Request::Sort {
+                key,
+                descending: value == 1,
+            },
+            OP_TTL => Request::Ttl { key },
+            OP_PERSIST => Request::Persist { key },
+            OP_TOUCH => Request::Touch { key, ttl_ms: value },
+            OP_DELETE_IF => Request::DeleteIf {
+                key,
+                expected: value,
+            },
+            OP_INFO => match key {
+                0 | 1 => Request::Info {
+                    latencies: key == 1,
+                },
+                _ => Request::Invalid { op },
+            },
+            OP_SLOW_LOG => Request::SlowLog { drain: value == 1 },
+            OP_CLIENT_LIST => Request::ClientList,
+            OP_CLIENT_INFO => Request::ClientInfo,
+            OP_CLIENT_KILL => {
+                let Some(id) = reader.u64() else {
+                    return Ok(None);
+                };
+                Request::ClientKill { id }
+            }
+            OP_PING => Request::Ping,
+            OP_KEEPALIVE => Request::Keepalive,
+            OP_PONG => Request::Pong,
+            OP_SUBSCRIBE => Request::Subscribe { key },
+            OP_SUBSCRIBE_EVENTS => Request::subscribe_events(op, value),
+            OP_MONITOR => Request::Monitor,
+            OP_MULTIPLEX => Request::Multiplex,
+            OP_CANCEL => Request::Cancel { id: value },
+            OP_CHUNKED => Request::Chunked { max_frame: value },
+            OP_PRIORITY => Request::Priority { class: key },
+            OP_SYNC_FULL => Request::SyncFull,
+            OP_SELECT => Request::Select { db: key },
+            OP_SET_TYPED if value as usize > 1 + MAX_BLOB_LEN => return Err(CodecError::Oversized),
+            OP_SET_TYPED => {
+                let Some(payload) = reader.bytes(value as usize) else {
+                    return Ok(None);
+                };
+                match payload.split_first().and_then(|(&value_type, payload)| {
+                    TypedValue::from_payload(value_type, payload)
+                }) {
+                    Some(value) => Request::SetTyped { key, value },
+                    None => Request::Invalid { op },
+                }
+            }
+            OP_GET_TYPED => Request::GetTyped { key },
+            OP_SET_SORTED => Request::SetSorted { key, value },
+            OP_GET_SORTED_RANGE => {
+                let Some(max) = reader.u32() else {
+                    return Ok(None);
+                };
+                Request::GetSortedRange {
+                    key,
+                    min: value,
+                    max,
+                }
+            }
+            OP_GET_FILTER => {
+                let Some(max) = reader.u32() else {
+                    return Ok(None);
+                };
+                Request::GetFilter {
+                    key,
+                    min: value,
+                    max,
+                }
+            }
+            OP_SET_EXPIRING => {
+                let Some(ttl_ms) = reader.u32() else {
+                    return Ok(None);
+                };
+                Request::SetExpiring { key, value, ttl_ms }
+            }
+            OP_SET_TIMED => {
+                let Some(at_ms) = reader.u64() else {
+                    return Ok(None);
+                };
+                Request::SetTimed { key, value, at_ms }
+            }
+            _ => Request::Invalid { op },
+        };
+        Ok(Some((request, reader.pos)))
+    }
+
+    fn encode_request(&self, request: &Request, out: &mut Vec<u8>) {
+        let mut frame =
+            |op, key, value| out.extend_from_slice(&Frame::new(op, key, value).encode());
+        match request {
+            Request::Set { key, value } => frame(OP_SET, *key, *value),
+            Request::Get { key } => frame(OP_GET, *key, 0),
+            Request::DeleteByKey { key } => frame(OP_DELETE_BY_KEY, *key, 0),
+            Request::DeleteAll { token } => frame(OP_DELETE_ALL, 0, *token),
+            Request::ListRange { first, last } => frame(OP_LIST_RANGE, *first, u32::from(*last)),
+            Request::ListAll { snapshot } => frame(OP_LIST_ALL, u8::from(*snapshot), 0),
+            Request::Tag { key, tag } => frame(OP_TAG, *key, u32::from(*tag)),
+            Request::ListTagged { tag } => frame(OP_LIST_TAGGED, *tag, 0),
+            Request::DeleteTagged { tag } => frame(OP_DELETE_TAGGED, *tag, 0),
+            Request::Hello { version, magic } => frame(OP_HELLO, *version, *magic),
+            Request::AwaitSeq { seq } => {
+                frame(OP_AWAIT_SEQ, 0, 0);
+                out.extend_from_slice(&seq.to_le_bytes());
+            }
+            Request::DeadLetters { drain } => frame(OP_DEAD_LETTERS, 0, *drain as u32),
+            Request::AllocRegister(allocation) => {
+                let mut payload = vec![allocation.last_key];
+                allocation.encode_fields(&mut payload);
+                frame(
+                    OP_ALLOC_REGISTER,
+                    allocation.first_key,
+                    payload.len() as u32,
+                );
+                out.extend_from_slice(&payload);
+            }
+            Request::AllocRelease { first_key } => frame(OP_ALLOC_RELEASE, *first_key, 0),
+            Request::AllocList => frame(OP_ALLOC_LIST, 0, 0),
+            Request::GetVersioned { key } => frame(OP_GET_VERSIONED, *key, 0),
+            Request::ReplaceIf {
+                key,
+                expected,
+                values,
+            } => {
+                frame(OP_REPLACE_IF, *key, values.len() as u32);
+                out.extend_from_slice(&expected.to_le_bytes());
+                for value in values {
+                    out.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+            Request::Push { key, values } => {
+                frame(OP_PUSH, *key, values.len() as u32);
+                for value in values {
+                    out.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+            Request::Auth { token } => {
+                frame(OP_AUTH, 0, token.len() as u32);
+                out.extend_from_slice(token);
+            }
+            Request::DeleteMany { keys } => {
+                frame(OP_DELETE_MANY, 0, keys.len() as u32);
+                out.extend_from_slice(keys);
+            }
+            Request::Dump { name } => {
+                frame(OP_DUMP, 0, name.len() as u32);
+                out.extend_from_slice(name.as_bytes());
+            }
+            Request::Restore { name, merge } => {
+                frame(OP_RESTORE, *merge as u8, name.len() as u32);
+                out.extend_from_slice(name.as_bytes());
+            }
+            Request::Config { name, value } => {
+                let payload = Request::config_payload(name, value.as_deref());
+                frame(OP_CONFIG, value.is_some() as u8, payload.len() as u32);
+                out.extend_from_slice(payload.as_bytes());
+            }
+            Request::SelectCodec { codec } => frame(OP_SELECT_CODEC, *codec, 0),
+            Request::RestoreKey { key } => frame(OP_RESTORE_KEY, *key, 0),
+            Request::DeleteAt { key, index } => frame(OP_DELETE_AT, *key, *index),
+            Request::GetLastN { key, n } => frame(OP_GET_LAST_N, *key, *n),
+            Request::GetFirstN { key, n } => frame(OP_GET_FIRST_N, *key, *n),
+            Request::RandomKey { with_value } => frame(OP_RANDOM_KEY, 0, *with_value as u32),
+            Request::Scan { cursor, count } => frame(OP_SCAN, *count, *cursor),
+            Request::Rename { from, to, merge } => {
+                frame(OP_RENAME, *from, Request::transfer_value(*to, *merge))
+            }
+            Request::Swap { a, b } => frame(OP_SWAP, *a, u32::from(*b)),
+            Request::Compare { a, b, comparison } => {
+                frame(OP_COMPARE, *a, Request::compare_value(*b, *comparison))
+            }
+            Request::Copy { from, to, append } => {
+                frame(OP_COPY, *from, Request::transfer_value(*to, *append))
+            }
+            Request::DeleteIf { key, expected } => frame(OP_DELETE_IF, *key, *expected),
+            Request::Aggregate { key, aggregate } => frame(aggregate.op(), *key, 0),
+            Request::Dedup { key } => frame(OP_DEDUP, *key, 0),
+            Request::GetDel { key } => frame(OP_GETDEL, *key, 0),
+            Request::GetSet { key, value } => {
+                frame(OP_GETSET, *key, value.is_some() as u32);
+                if let Some(value) = value {
+                    out.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+            Request::KeyStats { key } => frame(OP_KEYSTATS, *key, 0),
+            Request::MemoryUsage { key } => {
+                frame(OP_MEMUSAGE, key.unwrap_or(0), key.is_none() as u32)
+            }
+            Request::Sort { key, descending } => frame(OP_SORT, *key, *descending as u32),
+            Request::Ttl { key } => frame(OP_TTL, *key, 0),
+            Request::Persist { key } => frame(OP_PERSIST, *key, 0),
+            Request::Touch { key, ttl_ms } => frame(OP_TOUCH, *key, *ttl_ms),
+            Request::Info { latencies } => frame(OP_INFO, u8::from(*latencies), 0),
+            Request::SlowLog { drain } => frame(OP_SLOW_LOG, 0, *drain as u32),
+            Request::ClientList => frame(OP_CLIENT_LIST, 0, 0),
+            Request::ClientInfo => frame(OP_CLIENT_INFO, 0, 0),
+            Request::ClientKill { id } => {
+                frame(OP_CLIENT_KILL, 0, 0);
+                out.extend_from_slice(&id.to_le_bytes());
+            }
+            Request::Ping => frame(OP_PING, 0, 0),
+            Request::Keepalive => frame(OP_KEEPALIVE, 0, 0),
+            Request::Pong => frame(OP_PONG, 0, 0),
+            Request::Subscribe { key } => frame(OP_SUBSCRIBE, *key, 0),
+            Request::SubscribeEvents { events } => {
+                frame(OP_SUBSCRIBE_EVENTS, 0, u32::from(*events))
+            }
+            Request::Monitor => frame(OP_MONITOR, 0, 0),
+            Request::Multiplex => frame(OP_MULTIPLEX, 0, 0),
+            Request::Cancel { id } => frame(OP_CANCEL, 0, *id),
+            Request::Chunked { max_frame } => frame(OP_CHUNKED, 0, *max_frame),
+            Request::Priority { class } => frame(OP_PRIORITY, *class, 0),
+            Request::SyncFull => frame(OP_SYNC_FULL, 0, 0),
+            Request::Select { db } => frame(OP_SELECT, *db, 0),
+            Request::SetTyped { key, value } => {
+                let payload = value.payload();
+                frame(OP_SET_TYPED, *key, 1 + payload.len() as u32);
+                out.push(value.value_type());
+                out.extend_from_slice(&payload);
+            }
+            Request::GetTyped { key } => frame(OP_GET_TYPED, *key, 0),
+            Request::SetSorted { key, value } => frame(OP_SET_SORTED, *key, *value),
+            Request::GetSortedRange { key, min, max } => {
+                frame(OP_GET_SORTED_RANGE, *key, *min);
+                out.extend_from_slice(&max.to_le_bytes());
+            }
+            Request::GetFilter { key, min, max } => {
+                frame(OP_GET_FILTER, *key, *min);
+                out.extend_from_slice(&max.to_le_bytes());
+            }
+            Request::SetExpiring { key, value, ttl_ms } => {
+                frame(OP_SET_EXPIRING, *key, *value);
+                out.extend_from_slice(&ttl_ms.to_le_bytes());
+            }
+            Request::SetTimed { key, value, at_ms } => {
+                frame(OP_SET_TIMED, *key, *value);
+                out.extend_from_slice(&at_ms.to_le_bytes());
+            }
+            Request::Invalid { op } => frame(*op, 0, 0),
+        }
+    }
+
+    fn decode_response(
+        &self,
+        request: &Request,
+        buf: &[u8],
+    ) -> Result<Option<(Response, usize)>, CodecError> {
+        let mut reader = Reader::new(buf);
+        let Some(status) = reader.u8() else {
+            return Ok(None);
+        };
+        let mut fields = Fields::default();
+        let complete = match request {
+            _ if request.acked() => (|| {
+                if self.sessions() {
+                    fields.seq = reader.u64()?;
+                }
+                if matches!(
+                    request,
+                    Request::DeleteAt { .. } | Request::Dedup { .. } | Request::Push { .. }
+                ) && status == STATUS_OK
+                {
+                    fields.values = vec![reader.u32()?];
+                }
+                if matches!(request, Request::GetDel { .. } | Request::GetSet { .. })
+                    && status == STATUS_OK
+                {
+                    fields.values = reader.counted_values()?;
+                }
+                if matches!(request, Request::DeleteMany { .. }) && status == STATUS_OK {
+                    let count = reader.u32()?;
+                    fields.statuses = reader.bytes(count as usize)?.to_vec();
+                }
+                Some(())
+            })(),
+            Request::Hello { .. } if status == STATUS_UNSUPPORTED_VERSION => {
+                reader.u8().map(|version| fields.protocol_version = version)
+            }
+            _ if status != STATUS_OK => Some(()),
+            Request::Get { .. }
+            | Request::GetSortedRange { .. }
+            | Request::GetFilter { .. }
+            | Request::Compare { .. }
+            | Request::GetLastN { .. }
+            | Request::GetFirstN { .. } => {
+                reader.counted_values().map(|values| fields.values = values)
+            }
+            Request::GetVersioned { .. } => (|| {
+                fields.version = reader.u64()?;
+                fields.values = reader.counted_values()?;
+                Some(())
+            })(),
+            Request::ListAll { .. } | Request::ListRange { .. } | Request::ListTagged { .. } => {
+                (|| {
+                    let count = reader.u32()?;
+                    for _ in 0..count {
+                        let key = reader.u8()?;
+                        fields.entries.push((key, reader.counted_values()?));
+                    }
+                    Some(())
+                })()
+            }
+            Request::Hello { .. } => (|| {
+                fields.protocol_version = reader.u8()?;
+                let magic = reader.u32()?;
+                if magic != PROTOCOL_MAGIC {
+                    return Some(Err(CodecError::Malformed("bad HELLO magic".to_string())));
+                }
+                Some(Ok(()))
+            })()
+            .transpose()?,
+            Request::RandomKey { with_value } => (|| {
+                let key = reader.u8()?;
+                let values = match with_value {
+                    true => vec![reader.u32()?],
+                    false => Vec::new(),
+                };
+                fields.entries.push((key, values));
+                Some(())
+            })(),
+            Request::Ttl { .. } => reader.u64().map(|ttl_ms| fields.ttl_ms = ttl_ms),
+            Request::Aggregate { .. } => reader.u64().map(|result| fields.result = result),
+            Request::MemoryUsage { .. } => reader.u64().map(|bytes| fields.memory_bytes = bytes),
+            Request::KeyStats { .. } => {
+                KeyStats::read(&mut reader).map(|stats| fields.key_stats = stats)
+            }
+            Request::Scan { .. } => (|| {
+                fields.cursor = reader.u32()?;
+                let count = reader.u32()?;
+                fields.keys = reader.bytes(count as usize)?.to_vec();
+                Some(())
+            })(),
+            Request::DeadLetters { .. } => (|| {
+                let count = reader.u32()?;
+                for _ in 0..count {
+                    fields.dead_letters.push(DeadLetter::read(&mut reader)?);
+                }
+                Some(())
+            })(),
+            Request::AllocList => (|| {
+                let count = reader.u32()?;
+                for _ in 0..count {
+                    match Allocation::read(&mut reader)? {
+                        Ok(allocation) => fields.allocations.push(allocation),
+                        Err(e) => return Some(Err(e)),
+                    }
+                }
+                Some(Ok(()))
+            })()
+            .transpose()?,
+            Request::SlowLog { .. } => (|| {
+                let count = reader.u32()?;
+                for _ in 0..count {
+                    fields.slow_ops.push(SlowOp::read(&mut reader)?);
+                }
+                Some(())
+            })(),
+            Request::ClientList | Request::ClientInfo => (|| {
+                let count = reader.u32()?;
+                for _ in 0..count {
+                    fields.clients.push(ConnectedClient::read(&mut reader)?);
+                }
+                Some(())
+            })(),
+            Request::Monitor => (|| {
+                let count = reader.u32()?;
+                for _ in 0..count {
+                    fields.monitored.push(MonitoredOp::read(&mut reader)?);
+                }
+                Some(())
+            })(),
+            Request::SubscribeEvents { .. } => (|| {
+                let count = reader.u32()?;
+                for _ in 0..count {
+                    fields.events.push(KeyspaceEvent::read(&mut reader)?);
+                }
+                Some(())
+            })(),
+            Request::Config { value: None, .. } => (|| {
+                let text = |bytes: Vec<u8>| String::from_utf8_lossy(&bytes).into_owned();
+                for _ in 0..reader.u8()? {
+                    let name = text(reader.field()?);
+                    fields.config.push((name, text(reader.field()?)));
+                }
+                Some(())
+            })(),
+            Request::SyncFull => read_full_sync(&mut reader, &mut fields).transpose()?,
+            Request::Ping => reader.field().map(|version| {
+                fields.server_version = String::from_utf8_lossy(&version).into_owned()
+            }),
+            Request::GetTyped { .. } => (|| {
+                fields.value_type = reader.u8()?;
+                for _ in 0..reader.u32()? {
+                    match TypedValue::read(&mut reader, fields.value_type)? {
+                        Ok(value) => fields.typed_values.push(value),
+                        Err(e) => return Some(Err(e)),
+                    }
+                }
+                Some(Ok(()))
+            })()
+            .transpose()?,
+            Request::Info { latencies } => Info::read(&mut reader, *latencies)
+                .transpose()?
+                .map(|info| fields.info = info),
+            _ => Some(()),
+        };
+        Ok(complete.map(|()| (Response::shaped(request, status, fields), reader.pos)))
+    }
+
+    fn encode_values(&self, values: &[u32], out: &mut Vec<u8>) {
+        out.push(STATUS_OK);
+        push_values(out, values);
+    }
+
+    fn encode_response(&self, response: &Response, out: &mut Vec<u8>) {
+        match response {
+            Response::Status(status) => out.push(*status),
+            Response::Ack { status, seq } => {
+                out.push(*status);
+                if self.sessions() {
+                    out.extend_from_slice(&seq.to_le_bytes());
+                }
+            }
+            Response::Removed { seq, value } => {
+                out.push(STATUS_OK);
+                if self.sessions() {
+                    out.extend_from_slice(&seq.to_le_bytes());
+                }
+                out.extend_from_slice(&value.to_le_bytes());
+            }
+            Response::Taken { seq, values } => {
+                out.push(STATUS_OK);
+                if self.sessions() {
+                    out.extend_from_slice(&seq.to_le_bytes());
+                }
+                push_values(out, values);
+            }
+            Response::DeletedMany { seq, statuses } => {
+                out.push(STATUS_OK);
+                if self.sessions() {
+                    out.extend_from_slice(&seq.to_le_bytes());
+                }
+                out.extend_from_slice(&(statuses.len() as u32).to_le_bytes());
+                out.extend_from_slice(statuses);
+            }
+            Response::Values(values) => {
+                out.push(STATUS_OK);
+                push_values(out, values);
+            }
+            Response::Versioned { version, values } => {
+                out.push(STATUS_OK);
+                out.extend_from_slice(&version.to_le_bytes());
+                push_values(out, values);
+            }
+            Response::Entries(entries) => {
+                let len = entries
+                    .iter()
+                    .map(|(_, values)| 5 + values.len() * 4)
+                    .sum::<usize>();
+                out.reserve(5 + len);
+                out.push(STATUS_OK);
+                out.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+                for (key, values) in entries {
+                    out.push(*key);
+                    push_values(out, values);
+                }
+            }
+            Response::RandomKey { key, value } => {
+                out.push(STATUS_OK);
+                out.push(*key);
+                if let Some(value) = value {
+                    out.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+            Response::Ttl { ttl_ms: word }
+            | Response::Aggregate { result: word }
+            | Response::MemoryUsage { bytes: word } => {
+                out.push(STATUS_OK);
+                out.extend_from_slice(&word.to_le_bytes());
+            }
+            Response::Scan { cursor, keys } => {
+                out.push(STATUS_OK);
+                out.extend_from_slice(&cursor.to_le_bytes());
+                out.extend_from_slice(&(keys.len() as u32).to_le_bytes());
+                out.extend_from_slice(keys);
+            }
+            Response::Hello { version } => {
+                out.push(STATUS_OK);
+                out.push(*version);
+                out.extend_from_slice(&PROTOCOL_MAGIC.to_le_bytes());
+            }
+            Response::UnsupportedVersion { server_version } => {
+                out.push(STATUS_UNSUPPORTED_VERSION);
+                out.push(*server_version);
+            }
+            Response::DeadLetters(entries) => {
+                out.reserve(5 + entries.len() * DEAD_LETTER_LEN);
+                out.push(STATUS_OK);
+                out.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+                for entry in entries {
+                    entry.encode_into(out);
+                }
+            }
+            Response::Allocations(allocations) => {
+                out.push(STATUS_OK);
+                out.extend_from_slice(&(allocations.len() as u32).to_le_bytes());
+                for allocation in allocations {
+                    allocation.encode_into(out);
+                }
+            }
+            Response::Info(info) => {
+                out.push(STATUS_OK);
+                info.encode_into(out);
+            }
+            Response::KeyStats(stats) => {
+                out.push(STATUS_OK);
+                stats.encode_into(out);
+            }
+            Response::FullSync { seq, entries } => {
+                out.push(STATUS_OK);
+                let start = out.len() + 4;
+                out.extend_from_slice(&[0; 4]);
+                out.extend_from_slice(&seq.to_le_bytes());
+                out.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+                for (key, values) in entries {
+                    out.push(*key);
+                    push_values(out, values);
+                }
+                let len = (out.len() - start) as u32;
+                out[start - 4..start].copy_from_slice(&len.to_le_bytes());
+                let checksum = crc32(&out[start..]);
+                out.extend_from_slice(&checksum.to_le_bytes());
+            }
+            Response::Pong { server_version } => {
+                let version = &server_version.as_bytes()[..server_version.len().min(MAX_FIELD_LEN)];
+                out.push(STATUS_OK);
+                out.push(version.len() as u8);
+                out.extend_from_slice(version);
+            }
+            Response::Typed { value_type, values } => {
+                out.push(STATUS_OK);
+                out.push(*value_type);
+                out.extend_from_slice(&(values.len() as u32).to_le_bytes());
+                for value in values {
+                    value.encode_into(out);
+                }
+            }
+            Response::SlowOps(entries) => {
+                out.reserve(5 + entries.len() * SLOW_OP_LEN);
+                out.push(STATUS_OK);
+                out.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+                for entry in entries {
+                    entry.encode_into(out);
+                }
+            }
+            Response::Clients(clients) => {
+                out.push(STATUS_OK);
+                out.extend_from_slice(&(clients.len() as u32).to_le_bytes());
+                for client in clients {
+                    client.encode_into(out);
+                }
+            }
+            Response::Monitored(entries) => {
+                out.push(STATUS_OK);
+                out.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+                for entry in entries {
+                    entry.encode_into(out);
+                }
+            }
+            Response::Events(events) => {
+                out.push(STATUS_OK);
+                out.extend_from_slice(&(events.len() as u32).to_le_bytes());
+                for event in events {
+                    event.encode_into(out);
+                }
+            }
+            Response::Config(settings) => {
+                let settings = &settings[..settings.len().min(u8::MAX as usize)];
+                out.push(STATUS_OK);
+                out.push(settings.len() as u8);
+                for (name, value) in settings {
+                    put_field(out, name);
+                    put_field(out, value);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,1186 @@
+use super::{
+    frame_message, split_message, Aggregate, Allocation, Codec, CodecError, ConnectedClient,
+    DeadLetter, Fields, Health, Info, KeyStats, KeyspaceEvent, MonitoredOp, OpLatency,
+    PersistenceState, Quantiles, Request, Response, SlowOp, TypedValue,
+};
+use crate::*;
+use prost::Message;
+
+/// Length-prefixed protobuf: `[len: u32 LE]` and one `Request` or `Response`
+/// message from `protocol/map8x32.proto`. Requests carry the native opcode;
+/// responses are read according to the request they answer.
+#[derive(Debug)]
+pub struct Protobuf;
+
+#[derive(Clone, PartialEq, Message)]
+struct PbRequest {
+    #[prost(uint32, tag = "1")]
+    op: u32,
+    #[prost(uint32, tag = "2")]
+    key: u32,
+    #[prost(uint32, tag = "3")]
+    value: u32,
+    #[prost(uint64, tag = "4")]
+    seq: u64,
+    #[prost(uint32, repeated, tag = "5")]
+    values: Vec<u32>,
+    #[prost(bytes = "vec", tag = "6")]
+    token: Vec<u8>,
+    #[prost(message, optional, tag = "7")]
+    allocation: Option<PbAllocation>,
+    #[prost(bytes = "vec", tag = "8")]
+    payload: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct PbAllocation {
+    #[prost(uint32, tag = "1")]
+    first_key: u32,
+    #[prost(uint32, tag = "2")]
+    last_key: u32,
+    #[prost(string, tag = "3")]
+    name: String,
+    #[prost(string, tag = "4")]
+    contact: String,
+    #[prost(string, tag = "5")]
+    policy: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct PbEntry {
+    #[prost(uint32, tag = "1")]
+    key: u32,
+    #[prost(uint32, repeated, tag = "2")]
+    values: Vec<u32>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct PbDeadLetter {
+    #[prost(uint64, tag = "1")]
+    at_unix_ms: u64,
+    #[prost(uint32, tag = "2")]
+    op: u32,
+    #[prost(uint32, tag = "3")]
+    key: u32,
+    #[prost(uint32, tag = "4")]
+    value: u32,
+    #[prost(uint32, tag = "5")]
+    reason: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct PbSlowOp {
+    #[prost(uint64, tag = "1")]
+    at_unix_ms: u64,
+    #[prost(uint32, tag = "2")]
+    op: u32,
+    #[prost(uint32, tag = "3")]
+    key: u32,
+    #[prost(uint64, tag = "4")]
+    micros: u64,
+    #[prost(uint32, tag = "5")]
+    request_bytes: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct PbClient {
+    #[prost(uint64, tag = "1")]
+    id: u64,
+    #[prost(uint32, optional, tag = "2")]
+    uid: Option<u32>,
+    #[prost(string, tag = "3")]
+    peer: String,
+    #[prost(uint64, tag = "4")]
+    connected_unix_ms: u64,
+    #[prost(uint64, tag = "5")]
+    requests: u64,
+    #[prost(uint64, tag = "6")]
+    bytes_in: u64,
+    #[prost(uint64, tag = "7")]
+    bytes_out: u64,
+    #[prost(uint32, tag = "8")]
+    last_op: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct PbMonitoredOp {
+    #[prost(uint64, tag = "1")]
+    at_unix_ms: u64,
+    #[prost(uint32, tag = "2")]
+    db: u32,
+    #[prost(uint32, tag = "3")]
+    op: u32,
+    #[prost(uint32, tag = "4")]
+    key: u32,
+    #[prost(uint32, tag = "5")]
+    value: u32,
+    #[prost(uint32, tag = "6")]
+    status: u32,
+    #[prost(uint64, tag = "7")]
+    micros: u64,
+    #[prost(string, tag = "8")]
+    peer: String,
+    #[prost(string, tag = "9")]
+    command: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct PbKeyspaceEvent {
+    #[prost(uint32, tag = "1")]
+    event: u32,
+    #[prost(uint32, tag = "2")]
+    key: u32,
+    #[prost(uint64, tag = "3")]
+    count: u64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct PbSetting {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct PbOpCount {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(uint64, tag = "2")]
+    count: u64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct PbQuantiles {
+    #[prost(uint64, tag = "1")]
+    count: u64,
+    #[prost(uint64, tag = "2")]
+    p50: u64,
+    #[prost(uint64, tag = "3")]
+    p90: u64,
+    #[prost(uint64, tag = "4")]
+    p99: u64,
+    #[prost(uint64, tag = "5")]
+    p999: u64,
+    #[prost(uint64, tag = "6")]
+    max: u64,
+}
+
+impl From<Quantiles> for PbQuantiles {
+    fn from(quantiles: Quantiles) -> Self {
+        PbQuantiles {
+            count: quantiles.count,
+            p50: quantiles.p50,
+            p90: quantiles.p90,
+            p99: quantiles.p99,
+            p999: quantiles.p999,
+            max: quantiles.max,
+        }
+    }
+}
+
+impl From<PbQuantiles> for Quantiles {
+    fn from(quantiles: PbQuantiles) -> Self {
+        Quantiles {
+            count: quantiles.count,
+            p50: quantiles.p50,
+            p90: quantiles.p90,
+            p99: quantiles.p99,
+            p999: quantiles.p999,
+            max: quantiles.max,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct PbOpLatency {
+    #[prost(uint32, tag = "1")]
+    op: u32,
+    #[prost(message, optional, tag = "2")]
+    wait: Option<PbQuantiles>,
+    #[prost(message, optional, tag = "3")]
+    run: Option<PbQuantiles>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct PbInfo {
+    #[prost(uint64, tag = "1")]
+    uptime_ms: u64,
+    #[prost(uint64, tag = "2")]
+    keys: u64,
+    #[prost(uint64, tag = "3")]
+    values: u64,
+    #[prost(uint64, tag = "4")]
+    memory_bytes: u64,
+    #[prost(uint64, tag = "5")]
+    hits: u64,
+    #[prost(uint64, tag = "6")]
+    misses: u64,
+    #[prost(message, repeated, tag = "7")]
+    ops: Vec<PbOpCount>,
+    #[prost(message, repeated, tag = "8")]
+    latencies: Vec<PbOpLatency>,
+    #[prost(message, optional, tag = "9")]
+    persistence: Option<PbPersistenceState>,
+    #[prost(message, repeated, tag = "10")]
+    allocations: Vec<PbAllocation>,
+    #[prost(uint64, tag = "11")]
+    quota_rejections: u64,
+    #[prost(uint64, optional, tag = "12")]
+    max_keys: Option<u64>,
+    #[prost(uint64, optional, tag = "13")]
+    max_values: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct PbPersistenceState {
+    #[prost(uint32, tag = "1")]
+    health: u32,
+    #[prost(uint64, tag = "2")]
+    failures: u64,
+}
+
+impl From<&Info> for PbInfo {
+    fn from(info: &Info) -> Self {
+        PbInfo {
+            uptime_ms: info.uptime_ms,
+            keys: info.keys,
+            values: info.values,
+            memory_bytes: info.memory_bytes,
+            hits: info.hits,
+            misses: info.misses,
+            ops: info
+                .ops
+                .iter()
+                .map(|(name, count)| PbOpCount {
+                    name: name.clone(),
+                    count: *count,
+                })
+                .collect(),
+            latencies: info
+                .latencies
+                .iter()
+                .flatten()
+                .map(|latency| PbOpLatency {
+                    op: latency.op.into(),
+                    wait: Some(latency.wait.into()),
+                    run: Some(latency.run.into()),
+                })
+                .collect(),
+            persistence: info.persistence.map(|persistence| PbPersistenceState {
+                health: persistence.health as u32,
+                failures: persistence.failures,
+            }),
+            allocations: info.allocations.iter().map(PbAllocation::from).collect(),
+            quota_rejections: info.quota_rejections,
+            max_keys: info.max_keys,
+            max_values: info.max_values,
+        }
+    }
+}
+
+impl TryFrom<PbInfo> for Info {
+    type Error = CodecError;
+
+    fn try_from(info: PbInfo) -> Result<Self, CodecError> {
+        let latencies = info
+            .latencies
+            .into_iter()
+            .map(|latency| {
+                Ok(OpLatency {
+                    op: byte(latency.op, "op")?,
+                    wait: latency.wait.map(Quantiles::from).unwrap_or_default(),
+                    run: latency.run.map(Quantiles::from).unwrap_or_default(),
+                })
+            })
+            .collect::<Result<Vec<_>, CodecError>>()?;
+        let persistence = match info.persistence {
+            Some(persistence) => Some(PersistenceState {
+                health: Health::from_u8(byte(persistence.health, "health")?).ok_or_else(|| {
+                    CodecError::Malformed(format!("unknown health {}", persistence.health))
+                })?,
+                failures: persistence.failures,
+            }),
+            None => None,
+        };
+        Ok(Info {
+            uptime_ms: info.uptime_ms,
+            keys: info.keys,
+            values: info.values,
+            memory_bytes: info.memory_bytes,
+            hits: info.hits,
+            misses: info.misses,
+            ops: info.ops.into_iter().map(|op| (op.name, op.count)).collect(),
+            persistence,
+            allocations: info
+                .allocations
+                .into_iter()
+                .map(Allocation::try_from)
+                .collect::<Result<_, _>>()?,
+            quota_rejections: info.quota_rejections,
+            max_keys: info.max_keys,
+            max_values: info.max_values,
+            latencies: (!latencies.is_empty()).then_some(latencies),
+        })
+    }
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct PbKeyStats {
+    #[prost(uint64, tag = "1")]
+    hits: u64,
+    #[prost(uint64, tag = "2")]
+    misses: u64,
+    #[prost(uint64, tag = "3")]
+    sets: u64,
+    #[prost(uint64, tag = "4")]
+    last_access_ms: u64,
+}
+
+impl From<&KeyStats> for PbKeyStats {
+    fn from(stats: &KeyStats) -> Self {
+        PbKeyStats {
+            hits: stats.hits,
+            misses: stats.misses,
+            sets: stats.sets,
+            last_access_ms: stats.last_access_ms,
+        }
+    }
+}
+
+impl From<PbKeyStats> for KeyStats {
+    fn from(stats: PbKeyStats) -> Self {
+        KeyStats {
+            hits: stats.hits,
+            misses: stats.misses,
+            sets: stats.sets,
+            last_access_ms: stats.last_access_ms,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct PbResponse {
+    #[prost(uint32, tag = "1")]
+    status: u32,
+    #[prost(uint64, tag = "2")]
+    seq: u64,
+    #[prost(uint64, tag = "3")]
+    version: u64,
+    #[prost(uint32, repeated, tag = "4")]
+    values: Vec<u32>,
+    #[prost(message, repeated, tag = "5")]
+    entries: Vec<PbEntry>,
+    #[prost(message, repeated, tag = "6")]
+    dead_letters: Vec<PbDeadLetter>,
+    #[prost(message, repeated, tag = "7")]
+    allocations: Vec<PbAllocation>,
+    #[prost(uint32, tag = "8")]
+    protocol_version: u32,
+    #[prost(message, optional, tag = "9")]
+    info: Option<PbInfo>,
+    #[prost(message, repeated, tag = "10")]
+    slow_ops: Vec<PbSlowOp>,
+    #[prost(string, tag = "11")]
+    server_version: String,
+    #[prost(uint32, tag = "12")]
+    value_type: u32,
+    #[prost(bytes = "vec", repeated, tag = "13")]
+    typed_values: Vec<Vec<u8>>,
+    #[prost(uint32, tag = "14")]
+    cursor: u32,
+    #[prost(bytes = "vec", tag = "15")]
+    keys: Vec<u8>,
+    #[prost(uint64, tag = "16")]
+    ttl_ms: u64,
+    #[prost(uint64, tag = "17")]
+    result: u64,
+    #[prost(uint64, tag = "18")]
+    memory_bytes: u64,
+    #[prost(message, optional, tag = "19")]
+    key_stats: Option<PbKeyStats>,
+    #[prost(message, repeated, tag = "20")]
+    monitored: Vec<PbMonitoredOp>,
+    #[prost(message, repeated, tag = "21")]
+    config: Vec<PbSetting>,
+    #[prost(bytes = "vec", tag = "22")]
+    statuses: Vec<u8>,
+    #[prost(message, repeated, tag = "23")]
+    clients: Vec<PbClient>,
+    #[prost(message, repeated, tag = "24")]
+    events: Vec<PbKeyspaceEvent>,
+}
+
+fn byte(value: u32, field: &str) -> Result<u8, CodecError> {
+    u8::try_from(value).map_err(|_| CodecError::Malformed(format!("{field} {value} out of range")))
+}
+
+impl From<&Allocation> for PbAllocation {
+    fn from(allocation: &Allocation) -> Self {
+        PbAllocation {
+            first_key: allocation.first_key.into(),
+            last_key: allocation.last_key.into(),
+            name: allocation.name.clone(),
+            contact: allocation.contact.clone(),
+            policy: allocation.policy.clone(),
+        }
+    }
+}
+
+impl TryFrom<PbAllocation> for Allocation {
+    type Error = CodecError;
+
+    fn try_from(allocation: PbAllocation) -> Result<Self, CodecError> {
+        Ok(Allocation {
+            first_key: byte(allocation.first_key, "first_key")?,
+            last_key: byte(allocation.last_key, "last_key")?,
+            name: allocation.name,
+            contact: allocation.contact,
+            policy: allocation.policy,
+        })
+    }
+}
+
+impl From<&DeadLetter> for PbDeadLetter {
+    fn from(entry: &DeadLetter) -> Self {
+        PbDeadLetter {
+            at_unix_ms: entry.at_unix_ms,
+            op: entry.op.into(),
+            key: entry.key.into(),
+            value: entry.value,
+            reason: entry.reason.into(),
+        }
+    }
+}
+
+impl TryFrom<PbDeadLetter> for DeadLetter {
+    type Error = CodecError;
+
+    fn try_from(entry: PbDeadLetter) -> Result<Self, CodecError> {
+        Ok(DeadLetter {
+            at_unix_ms: entry.at_unix_ms,
+            op: byte(entry.op, "op")?,
+            key: byte(entry.key, "key")?,
+            value: entry.value,
+            reason: byte(entry.reason, "reason")?,
+        })
+    }
+}
+
+impl From<&MonitoredOp> for PbMonitoredOp {
+    fn from(entry: &MonitoredOp) -> Self {
+        PbMonitoredOp {
+            at_unix_ms: entry.at_unix_ms,
+            db: entry.db.into(),
+            op: entry.op.into(),
+            key: entry.key.into(),
+            value: entry.value,
+            status: entry.status.into(),
+            micros: entry.micros,
+            peer: entry.peer.clone(),
+            command: entry.command.clone(),
+        }
+    }
+}
+
+impl TryFrom<PbMonitoredOp> for MonitoredOp {
+    type Error = CodecError;
+
+    fn try_from(entry: PbMonitoredOp) -> Result<Self, CodecError> {
+        Ok(MonitoredOp {
+            at_unix_ms: entry.at_unix_ms,
+            db: byte(entry.db, "db")?,
+            op: byte(entry.op, "op")?,
+            key: byte(entry.key, "key")?,
+            value: entry.value,
+            status: byte(entry.status, "status")?,
+            micros: entry.micros,
+            peer: entry.peer,
+            command: entry.command,
+        })
+    }
+}
+
+impl From<&KeyspaceEvent> for PbKeyspaceEvent {
+    fn from(event: &KeyspaceEvent) -> Self {
+        PbKeyspaceEvent {
+            event: event.event.into(),
+            key: event.key.into(),
+            count: event.count,
+        }
+    }
+}
+
+impl TryFrom<PbKeyspaceEvent> for KeyspaceEvent {
+    type Error = CodecError;
+
+    fn try_from(event: PbKeyspaceEvent) -> Result<Self, CodecError> {
+        Ok(KeyspaceEvent {
+            event: byte(event.event, "event")?,
+            key: byte(event.key, "key")?,
+            count: event.count,
+        })
+    }
+}
+
+impl From<&ConnectedClient> for PbClient {
+    fn from(client: &ConnectedClient) -> Self {
+        PbClient {
+            id: client.id,
+            uid: client.uid,
+            peer: client.peer.clone(),
+            connected_unix_ms: client.connected_unix_ms,
+            requests: client.requests,
+            bytes_in: client.bytes_in,
+            bytes_out: client.bytes_out,
+            last_op: client.last_op.into(),
+        }
+    }
+}
+
+impl TryFrom<PbClient> for ConnectedClient {
+    type Error = CodecError;
+
+    fn try_from(client: PbClient) -> Result<Self, CodecError> {
+        Ok(ConnectedClient {
+            id: client.id,
+            uid: client.uid,
+            peer: client.peer,
+            connected_unix_ms: client.connected_unix_ms,
+            requests: client.requests,
+            bytes_in: client.bytes_in,
+            bytes_out: client.bytes_out,
+            last_op: byte(client.last_op, "last_op")?,
+        })
+    }
+}
+
+impl From<&SlowOp> for PbSlowOp {
+    fn from(entry: &SlowOp) -> Self {
+        PbSlowOp {
+            at_unix_ms: entry.at_unix_ms,
+            op: entry.op.into(),
+            key: entry.key.into(),
+            micros: entry.micros,
+            request_bytes: entry.request_bytes,
+        }
+    }
+}
+
+impl TryFrom<PbSlowOp> for SlowOp {
+    type Error = CodecError;
+
+    fn try_from(entry: PbSlowOp) -> Result<Self, CodecError> {
+        Ok(SlowOp {
+            at_unix_ms: entry.at_unix_ms,
+            op: byte(entry.op, "op")?,
+            key: byte(entry.key, "key")?,
+            micros: entry.micros,
+            request_bytes: entry.request_bytes,
+        })
+    }
+}
+
+impl TryFrom<PbRequest> for Request {
+    type Error = CodecError;
+
+    fn try_from(message: PbRequest) -> Result<Self, CodecError> {
+        let op = byte(message.op, "op")?;
+        let key = byte(message.key, "key")?;
+        let request = match op {
+            OP_SET => Request::Set {
+                key,
+                value: message.value,
+            },
+            OP_GET => Request::Get { key },
+            OP_DELETE_BY_KEY => Request::DeleteByKey { key },
+            OP_DELETE_ALL => Request::DeleteAll {
+                token: message.value,
+            },
+            OP_LIST_RANGE => Request::ListRange {
+                first: key,
+                last: byte(message.value, "last key")?,
+            },
+            OP_LIST_ALL => match key {
+                0 | 1 => Request::ListAll { snapshot: key == 1 },
+                _ => Request::Invalid { op },
+            },
+            OP_TAG => Request::Tag {
+                key,
+                tag: byte(message.value, "tag")?,
+            },
+            OP_SWAP => Request::Swap {
+                a: key,
+                b: byte(message.value, "key")?,
+            },
+            OP_COMPARE => Request::compare(op, key, message.value),
+            OP_LIST_TAGGED => Request::ListTagged { tag: key },
+            OP_DELETE_TAGGED => Request::DeleteTagged { tag: key },
+            OP_HELLO => Request::Hello {
+                version: key,
+                magic: message.value,
+            },
+            OP_AWAIT_SEQ => Request::AwaitSeq { seq: message.seq },
+            OP_DEAD_LETTERS => Request::DeadLetters {
+                drain: message.value == 1,
+            },
+            OP_ALLOC_REGISTER => match message.allocation {
+                Some(allocation) => Request::AllocRegister(allocation.try_into()?),
+                None => Request::Invalid { op },
+            },
+            OP_ALLOC_RELEASE => Request::AllocRelease { first_key: key },
+            OP_ALLOC_LIST => Request::AllocList,
+            OP_GET_VERSIONED => Request::GetVersioned { key },
+            OP_REPLACE_IF => Request::ReplaceIf {
+                key,
+                expected: message.seq,
+                values: message.values,
+            },
+            OP_PUSH => Request::Push {
+                key,
+                values: message.values,
+            },
+            OP_AUTH => Request::Auth {
+                token: message.token,
+            },
+            OP_SELECT_CODEC => Request::SelectCodec { codec: key },
+            OP_RESTORE_KEY => Request::RestoreKey { key },
+            OP_DELETE_AT => Request::DeleteAt {
+                key,
+                index: message.value,
+            },
+            OP_GET_LAST_N => Request::GetLastN {
+                key,
+                n: message.value,
+            },
+            OP_GET_FIRST_N => Request::GetFirstN {
+                key,
+                n: message.value,
+            },
+            OP_RANDOM_KEY => Request::RandomKey {
+                with_value: message.value == 1,
+            },
+            OP_RENAME | OP_COPY => Request::transfer(op, key, message.value),
+            OP_SUM | OP_MIN | OP_MAX | OP_AVG | OP_COUNT => Request::Aggregate {
+                key,
+                aggregate: Aggregate::from_op(op).unwrap(),
+            },
+            OP_DEDUP => Request::Dedup { key },
+            OP_GETDEL => Request::GetDel { key },
+            OP_GETSET => match message.values[..] {
+                [] => Request::GetSet { key, value: None },
+                [value] => Request::GetSet {
+                    key,
+                    value: Some(value),
+                },
+                _ => Request::Invalid { op },
+            },
+            OP_KEYSTATS => Request::KeyStats { key },
+            OP_MEMUSAGE => Request::MemoryUsage {
+                key: (message.value != 1).then_some(key),
+            },
+            OP_SORT => Request::Sort {
+                key,
+                descending: message.value == 1,
+            },
+            OP_TTL => Request::Ttl { key },
+            OP_PERSIST => Request::Persist { key },
+            OP_TOUCH => Request::Touch {
+                key,
+                ttl_ms: message.value,
+            },
+            OP_DELETE_IF => Request::DeleteIf {
+                key,
+                expected: message.value,
+            },
+            OP_SCAN => Request::Scan {
+                cursor: message.value,
+                count: key,
+            },
+            OP_INFO => match key {
+                0 | 1 => Request::Info {
+                    latencies: key == 1,
+                },
+                _ => Request::Invalid { op },
+            },
+            OP_PING => Request::Ping,
+            OP_KEEPALIVE => Request::Keepalive,
+            OP_PONG => Request::Pong,
+            OP_SUBSCRIBE => Request::Subscribe { key },
+            OP_SUBSCRIBE_EVENTS => Request::subscribe_events(op, message.value),
+            OP_MONITOR => Request::Monitor,
+            OP_MULTIPLEX => Request::Multiplex,
+            OP_CANCEL => Request::Cancel { id: message.value },
+            OP_CHUNKED => Request::Chunked {
+                max_frame: message.value,
+            },
+            OP_PRIORITY => Request::Priority { class: key },
+            OP_SYNC_FULL => Request::SyncFull,
+            OP_CONFIG => Request::config(op, key, message.payload),
+            OP_DELETE_MANY => Request::DeleteMany {
+                keys: message.payload,
+            },
+            OP_DUMP => match String::from_utf8(message.payload) {
+                Ok(name) => Request::Dump { name },
+                Err(_) => Request::Invalid { op },
+            },
+            OP_RESTORE => match (String::from_utf8(message.payload), key) {
+                (Ok(name), 0 | 1) => Request::Restore {
+                    name,
+                    merge: key == 1,
+                },
+                _ => Request::Invalid { op },
+            },
+            OP_SELECT => Request::Select { db: key },
+            OP_SET_TYPED => {
+                match TypedValue::from_payload(byte(message.value, "value type")?, &message.payload)
+                {
+                    Some(value) => Request::SetTyped { key, value },
+                    None => Request::Invalid { op },
+                }
+            }
+            OP_GET_TYPED => Request::GetTyped { key },
+            OP_SET_SORTED => Request::SetSorted {
+                key,
+                value: message.value,
+            },
+            OP_GET_SORTED_RANGE => match message.values[..] {
+                [min, max] => Request::GetSortedRange { key, min, max },
+                _ => Request::Invalid { op },
+            },
+            OP_GET_FILTER => match message.values[..] {
+                [min, max] => Request::GetFilter { key, min, max },
+                _ => Request::Invalid { op },
+            },
+            OP_SET_EXPIRING => match u32::try_from(message.seq) {
+                Ok(ttl_ms) => Request::SetExpiring {
+                    key,
+                    value: message.value,
+                    ttl_ms,
+                },
+                Err(_) => Request::Invalid { op },
+            },
+            OP_SET_TIMED => Request::SetTimed {
+                key,
+                value: message.value,
+                at_ms: message.seq,
+            },
+            OP_SLOW_LOG => Request::SlowLog {
+                drain: message.value == 1,
+            },
+            OP_CLIENT_LIST => Request::ClientList,
+            OP_CLIENT_INFO => Request::ClientInfo,
+            OP_CLIENT_KILL => Request::ClientKill { id: message.seq },
+            _ => Request::Invalid { op },
+        };
+        request.validate()
+    }
+}
+
+impl From<&Request> for PbRequest {
+    fn from(request: &Request) -> Self {
+        let op = |op: u8, key: u8, value: u32| PbRequest {
+            op: op.into(),
+            key: key.into(),
+            value,
+            ..PbRequest::default()
+        };
+        match request {
+            Request::Set { key, value } => op(OP_SET, *key, *value),
+            Request::Get { key } => op(OP_GET, *key, 0),
+            Request::DeleteByKey { key } => op(OP_DELETE_BY_KEY, *key, 0),
+            Request::DeleteAll { token } => op(OP_DELETE_ALL, 0, *token),
+            Request::ListRange { first, last } => op(OP_LIST_RANGE, *first, u32::from(*last)),
+            Request::ListAll { snapshot } => op(OP_LIST_ALL, u8::from(*snapshot), 0),
+            Request::Tag { key, tag } => op(OP_TAG, *key, u32::from(*tag)),
+            Request::ListTagged { tag } => op(OP_LIST_TAGGED, *tag, 0),
+            Request::DeleteTagged { tag } => op(OP_DELETE_TAGGED, *tag, 0),
+            Request::Hello { version, magic } => op(OP_HELLO, *version, *magic),
+            Request::AwaitSeq { seq } => PbRequest {
+                seq: *seq,
+                ..op(OP_AWAIT_SEQ, 0, 0)
+            },
+            Request::DeadLetters { drain } => op(OP_DEAD_LETTERS, 0, *drain as u32),
+            Request::AllocRegister(allocation) => PbRequest {
+                allocation: Some(allocation.into()),
+                ..op(OP_ALLOC_REGISTER, allocation.first_key, 0)
+            },
+            Request::AllocRelease { first_key } => op(OP_ALLOC_RELEASE, *first_key, 0),
+            Request::AllocList => op(OP_ALLOC_LIST, 0, 0),
+            Request::GetVersioned { key } => op(OP_GET_VERSIONED, *key, 0),
+            Request::ReplaceIf {
+                key,
+                expected,
+                values,
+            } => PbRequest {
+                seq: *expected,
+                values: values.clone(),
+                ..op(OP_REPLACE_IF, *key, 0)
+            },
+            Request::Push { key, values } => PbRequest {
+                values: values.clone(),
+                ..op(OP_PUSH, *key, 0)
+            },
+            Request::Auth { token } => PbRequest {
+                token: token.clone(),
+                ..op(OP_AUTH, 0, 0)
+            },
+            Request::SelectCodec { codec } => op(OP_SELECT_CODEC, *codec, 0),
+            Request::RestoreKey { key } => op(OP_RESTORE_KEY, *key, 0),
+            Request::DeleteAt { key, index } => op(OP_DELETE_AT, *key, *index),
+            Request::GetLastN { key, n } => op(OP_GET_LAST_N, *key, *n),
+            Request::GetFirstN { key, n } => op(OP_GET_FIRST_N, *key, *n),
+            Request::RandomKey { with_value } => op(OP_RANDOM_KEY, 0, *with_value as u32),
+            Request::Scan { cursor, count } => op(OP_SCAN, *count, *cursor),
+            Request::Rename { from, to, merge } => {
+                op(OP_RENAME, *from, Request::transfer_value(*to, *merge))
+            }
+            Request::Swap { a, b } => op(OP_SWAP, *a, u32::from(*b)),
+            Request::Compare { a, b, comparison } => {
+                op(OP_COMPARE, *a, Request::compare_value(*b, *comparison))
+            }
+            Request::Copy { from, to, append } => {
+                op(OP_COPY, *from, Request::transfer_value(*to, *append))
+            }
+            Request::DeleteIf { key, expected } => op(OP_DELETE_IF, *key, *expected),
+            Request::Aggregate { key, aggregate } => op(aggregate.op(), *key, 0),
+            Request::Dedup { key } => op(OP_DEDUP, *key, 0),
+            Request::GetDel { key } => op(OP_GETDEL, *key, 0),
+            Request::GetSet { key, value } => PbRequest {
+                values: value.iter().copied().collect(),
+                ..op(OP_GETSET, *key, 0)
+            },
+            Request::KeyStats { key } => op(OP_KEYSTATS, *key, 0),
+            Request::MemoryUsage { key } => op(OP_MEMUSAGE, key.unwrap_or(0), key.is_none() as u32),
+            Request::Sort { key, descending } => op(OP_SORT, *key, *descending as u32),
+            Request::Ttl { key } => op(OP_TTL, *key, 0),
+            Request::Persist { key } => op(OP_PERSIST, *key, 0),
+            Request::Touch { key, ttl_ms } => op(OP_TOUCH, *key, *ttl_ms),
+            Request::Info { latencies } => op(OP_INFO, u8::from(*latencies), 0),
+            Request::SlowLog { drain } => op(OP_SLOW_LOG, 0, *drain as u32),
+            Request::ClientList => op(OP_CLIENT_LIST, 0, 0),
+            Request::ClientInfo => op(OP_CLIENT_INFO, 0, 0),
+            Request::ClientKill { id } => PbRequest {
+                seq: *id,
+                ..op(OP_CLIENT_KILL, 0, 0)
+            },
+            Request::Ping => op(OP_PING, 0, 0),
+            Request::Keepalive => op(OP_KEEPALIVE, 0, 0),
+            Request::Pong => op(OP_PONG, 0, 0),
+            Request::Subscribe { key } => op(OP_SUBSCRIBE, *key, 0),
+            Request::SubscribeEvents { events } => op(OP_SUBSCRIBE_EVENTS, 0, u32::from(*events)),
+            Request::Monitor => op(OP_MONITOR, 0, 0),
+            Request::Multiplex => op(OP_MULTIPLEX, 0, 0),
+            Request::Cancel { id } => op(OP_CANCEL, 0, *id),
+            Request::Chunked { max_frame } => op(OP_CHUNKED, 0, *max_frame),
+            Request::Priority { class } => op(OP_PRIORITY, *class, 0),
+            Request::SyncFull => op(OP_SYNC_FULL, 0, 0),
+            Request::Select { db } => op(OP_SELECT, *db, 0),
+            Request::SetTyped { key, value } => PbRequest {
+                payload: value.payload(),
+                ..op(OP_SET_TYPED, *key, value.value_type().into())
+            },
+            Request::GetTyped { key } => op(OP_GET_TYPED, *key, 0),
+            Request::SetSorted { key, value } => op(OP_SET_SORTED, *key, *value),
+            Request::GetSortedRange { key, min, max } => PbRequest {
+                values: vec![*min, *max],
+                ..op(OP_GET_SORTED_RANGE, *key, 0)
+            },
+            Request::GetFilter { key, min, max } => PbRequest {
+                values: vec![*min, *max],
+                ..op(OP_GET_FILTER, *key, 0)
+            },
+            Request::SetExpiring { key, value, ttl_ms } => PbRequest {
+                seq: (*ttl_ms).into(),
+                ..op(OP_SET_EXPIRING, *key, *value)
+            },
+            Request::SetTimed { key, value, at_ms } => PbRequest {
+                seq: *at_ms,
+                ..op(OP_SET_TIMED, *key, *value)
+            },
+            Request::Config { name, value } => PbRequest {
+                payload: Request::config_payload(name, value.as_deref()).into_bytes(),
+                ..op(OP_CONFIG, value.is_some() as u8, 0)
+            },
+            Request::DeleteMany { keys } => PbRequest {
+                payload: keys.clone(),
+                ..op(OP_DELETE_MANY, 0, 0)
+            },
+            Request::Dump { name } => PbRequest {
+                payload: name.clone().into_bytes(),
+                ..op(OP_DUMP, 0, 0)
+            },
+            Request::Restore { name, merge } => PbRequest {
+                payload: name.clone().into_bytes(),
+                ..op(OP_RESTORE, *merge as u8, 0)
+            },
+            Request::Invalid { op: code } => op(*code, 0, 0),
+        }
+    }
+}
+
+impl From<&Response> for PbResponse {
+    fn from(response: &Response) -> Self {
+        let ok = PbResponse {
+            status: STATUS_OK.into(),
+            ..PbResponse::default()
+        };
+        match response {
+            Response::Status(status) => PbResponse {
+                status: (*status).into(),
+                ..PbResponse::default()
+            },
+            Response::Ack { status, seq } => PbResponse {
+                status: (*status).into(),
+                seq: *seq,
+                ..PbResponse::default()
+            },
+            Response::Values(values) => PbResponse {
+                values: values.clone(),
+                ..ok
+            },
+            Response::RandomKey { key, value } => PbResponse {
+                entries: vec![PbEntry {
+                    key: (*key).into(),
+                    values: value.iter().copied().collect(),
+                }],
+                ..ok
+            },
+            Response::Scan { cursor, keys } => PbResponse {
+                cursor: *cursor,
+                keys: keys.clone(),
+                ..ok
+            },
+            Response::MemoryUsage { bytes } => PbResponse {
+                memory_bytes: *bytes,
+                ..ok
+            },
+            Response::Aggregate { result } => PbResponse {
+                result: *result,
+                ..ok
+            },
+            Response::Ttl { ttl_ms } => PbResponse {
+                ttl_ms: *ttl_ms,
+                ..ok
+            },
+            Response::Removed { seq, value } => PbResponse {
+                seq: *seq,
+                values: vec![*value],
+                ..ok
+            },
+            Response::Taken { seq, values } => PbResponse {
+                seq: *seq,
+                values: values.clone(),
+                ..ok
+            },
+            Response::DeletedMany { seq, statuses } => PbResponse {
+                seq: *seq,
+                statuses: statuses.clone(),
+                ..ok
+            },
+            Response::Versioned { version, values } => PbResponse {
+                version: *version,
+                values: values.clone(),
+                ..ok
+            },
+            Response::Entries(entries) => PbResponse {
+                entries: entries
+                    .iter()
+                    .map(|(key, values)| PbEntry {
+                        key: (*key).into(),
+                        values: values.clone(),
+                    })
+                    .collect(),
+                ..ok
+            },
+            Response::FullSync { seq, entries } => PbResponse {
+                seq: *seq,
+                entries: entries
+                    .iter()
+                    .map(|(key, values)| PbEntry {
+                        key: (*key).into(),
+                        values: values.clone(),
+                    })
+                    .collect(),
+                ..ok
+            },
+            Response::Hello { version } => PbResponse {
+                protocol_version: (*version).into(),
+                ..ok
+            },
+            Response::UnsupportedVersion { server_version } => PbResponse {
+                status: STATUS_UNSUPPORTED_VERSION.into(),
+                protocol_version: (*server_version).into(),
+                ..PbResponse::default()
+            },
+            Response::DeadLetters(entries) => PbResponse {
+                dead_letters: entries.iter().map(PbDeadLetter::from).collect(),
+                ..ok
+            },
+            Response::Allocations(allocations) => PbResponse {
+                allocations: allocations.iter().map(PbAllocation::from).collect(),
+                ..ok
+            },
+            Response::Info(info) => PbResponse {
+                info: Some(info.into()),
+                ..ok
+            },
+            Response::KeyStats(stats) => PbResponse {
+                key_stats: Some(stats.into()),
+                ..ok
+            },
+            Response::Pong { server_version } => PbResponse {
+                server_version: server_version.clone(),
+                ..ok
+            },
+            Response::SlowOps(entries) => PbResponse {
+                slow_ops: entries.iter().map(PbSlowOp::from).collect(),
+                ..ok
+            },
+            Response::Clients(clients) => PbResponse {
+                clients: clients.iter().map(PbClient::from).collect(),
+                ..ok
+            },
+            Response::Monitored(entries) => PbResponse {
+                monitored: entries.iter().map(PbMonitoredOp::from).collect(),
+                ..ok
+            },
+            Response::Events(events) => PbResponse {
+                events: events.iter().map(PbKeyspaceEvent::from).collect(),
+                ..ok
+            },
+            Response::Config(settings) => PbResponse {
+                config: settings
+                    .iter()
+                    .map(|(name, value)| PbSetting {
+                        name: name.clone(),
+                        value: value.clone(),
+                    })
+                    .collect(),
+                ..ok
+            },
+            Response::Typed { value_type, values } => PbResponse {
+                value_type: (*value_type).into(),
+                typed_values: values.iter().map(TypedValue::payload).collect(),
+                ..ok
+            },
+        }
+    }
+}
+
+fn decode<T: Message + Default>(buf: &[u8]) -> Result<Option<(T, usize)>, CodecError> {
+    let Some((message, len)) = split_message(buf)? else {
+        return Ok(None);
+    };
+    let message = T::decode(message).map_err(|e| CodecError::Malformed(e.to_string()))?;
+    Ok(Some((message, len)))
+}
+
+fn encode(message: &impl Message, out: &mut Vec<u8>) {
+    frame_message(out, |out| {
+        message.encode(out).expect("protobuf encoding into a Vec")
+    });
+}
+
+impl Codec for Protobuf {
+    fn name(&self) -> &'static str {
+        "protobuf"
+    }
+
+    fn decode_request(&self, buf: &[u8]) -> Result<Option<(Request, usize)>, CodecError> {
+        match decode::<PbRequest>(buf)? {
+            Some((message, len)) => Ok(Some((message.try_into()?, len))),
+            None => Ok(None),
+        }
+    }
+
+    fn encode_request(&self, request: &Request, out: &mut Vec<u8>) {
+        encode(&PbRequest::from(request), out);
+    }
+
+    fn decode_response(
+        &self,
+        request: &Request,
+        buf: &[u8],
+    ) -> Result<Option<(Response, usize)>, CodecError> {
+        let Some((message, len)) = decode::<PbResponse>(buf)? else {
+            return Ok(None);
+        };
+        let value_type = byte(message.value_type, "value_type")?;
+        let fields = Fields {
+            seq: message.seq,
+            version: message.version,
+            protocol_version: byte(message.protocol_version, "protocol_version")?,
+            values: message.values,
+            entries: message
+                .entries
+                .into_iter()
+                .map(|entry| Ok((byte(entry.key, "key")?, entry.values)))
+                .collect::<Result<_, CodecError>>()?,
+            dead_letters: message
+                .dead_letters
+                .into_iter()
+                .map(DeadLetter::try_from)
+                .collect::<Result<_, _>>()?,
+            allocations: message
+                .allocations
+                .into_iter()
+                .map(Allocation::try_from)
+                .collect::<Result<_, _>>()?,
+            info: message
+                .info
+                .map(Info::try_from)
+                .transpose()?
+                .unwrap_or_default(),
+            server_version: message.server_version,
+            cursor: message.cursor,
+            keys: message.keys,
+            statuses: message.statuses,
+            ttl_ms: message.ttl_ms,
+            result: message.result,
+            memory_bytes: message.memory_bytes,
+            key_stats: message.key_stats.map(KeyStats::from).unwrap_or_default(),
+            slow_ops: message
+                .slow_ops
+                .into_iter()
+                .map(SlowOp::try_from)
+                .collect::<Result<_, _>>()?,
+            clients: message
+                .clients
+                .into_iter()
+                .map(ConnectedClient::try_from)
+                .collect::<Result<_, _>>()?,
+            monitored: message
+                .monitored
+                .into_iter()
+                .map(MonitoredOp::try_from)
+                .collect::<Result<_, _>>()?,
+            events: message
+                .events
+                .into_iter()
+                .map(KeyspaceEvent::try_from)
+                .collect::<Result<_, _>>()?,
+            config: message
+                .config
+                .into_iter()
+                .map(|setting| (setting.name, setting.value))
+                .collect(),
+            value_type,
+            typed_values: message
+                .typed_values
+                .iter()
+                .map(|payload| {
+                    TypedValue::from_payload(value_type, payload).ok_or_else(|| {
+                        CodecError::Malformed(format!("bad value of type {value_type}"))
+                    })
+                })
+                .collect::<Result<_, _>>()?,
+        };
+        let status = byte(message.status, "status")?;
+        Ok(Some((Response::shaped(request, status, fields), len)))
+    }
+
+    fn encode_response(&self, response: &Response, out: &mut Vec<u8>) {
+        encode(&PbResponse::from(response), out);
+    }
+}
@@ -0,0 +1,185 @@
+pub mod capture;
+pub mod codec;
+pub mod shm;
+mod status;
+pub mod timed;
+
+pub use status::StatusError;
+
+pub const DEFAULT_SOCKET_PATH: &str = "/tmp/map8x32.sock";
+
+pub const OP_SET: u8 = 1;
+pub const OP_GET: u8 = 2;
+pub const OP_DELETE_BY_KEY: u8 = 3;
+pub const OP_DELETE_ALL: u8 = 4;
+pub const OP_LIST_ALL: u8 = 5;
+pub const OP_HELLO: u8 = 6;
+pub const OP_AWAIT_SEQ: u8 = 7;
+pub const OP_DEAD_LETTERS: u8 = 8;
+pub const OP_ALLOC_REGISTER: u8 = 9;
+pub const OP_ALLOC_RELEASE: u8 = 10;
+pub const OP_ALLOC_LIST: u8 = 11;
+pub const OP_GET_VERSIONED: u8 = 12;
+pub const OP_REPLACE_IF: u8 = 13;
+pub const OP_AUTH: u8 = 14;
+pub const OP_SELECT_CODEC: u8 = 15;
+pub const OP_RESTORE_KEY: u8 = 16;
+pub const OP_INFO: u8 = 17;
+pub const OP_SLOW_LOG: u8 = 18;
+pub const OP_PING: u8 = 19;
+pub const OP_SYNC_FULL: u8 = 20;
+pub const OP_SELECT: u8 = 21;
+pub const OP_SET_TYPED: u8 = 22;
+pub const OP_GET_TYPED: u8 = 23;
+pub const OP_SET_SORTED: u8 = 24;
+pub const OP_GET_SORTED_RANGE: u8 = 25;
+pub const OP_SET_EXPIRING: u8 = 26;
+pub const OP_DUMP: u8 = 27;
+pub const OP_RESTORE: u8 = 28;
+pub const OP_DELETE_AT: u8 = 29;
+pub const OP_GET_LAST_N: u8 = 30;
+pub const OP_GET_FIRST_N: u8 = 31;
+pub const OP_RANDOM_KEY: u8 = 32;
+pub const OP_SCAN: u8 = 33;
+pub const OP_RENAME: u8 = 34;
+pub const OP_COPY: u8 = 35;
+pub const OP_DELETE_IF: u8 = 36;
+pub const OP_TTL: u8 = 37;
+pub const OP_PERSIST: u8 = 38;
+pub const OP_GET_FILTER: u8 = 39;
+pub const OP_SUM: u8 = 40;
+pub const OP_MIN: u8 = 41;
+pub const OP_MAX: u8 = 42;
+pub const OP_AVG: u8 = 43;
+pub const OP_COUNT: u8 = 44;
+pub const OP_DEDUP: u8 = 45;
+pub const OP_SORT: u8 = 46;
+pub const OP_MEMUSAGE: u8 = 47;
+pub const OP_KEYSTATS: u8 = 48;
+pub const OP_KEEPALIVE: u8 = 49;
+pub const OP_PONG: u8 = 50;
+pub const OP_SUBSCRIBE: u8 = 51;
+pub const OP_MONITOR: u8 = 52;
+pub const OP_CONFIG: u8 = 53;
+pub const OP_MULTIPLEX: u8 = 54;
+pub const OP_CANCEL: u8 = 55;
+pub const OP_CHUNKED: u8 = 56;
+pub const OP_PRIORITY: u8 = 57;
+pub const OP_LIST_RANGE: u8 = 58;
+pub const OP_TAG: u8 = 59;
+pub const OP_LIST_TAGGED: u8 = 60;
+pub const OP_DELETE_TAGGED: u8 = 61;
+pub const OP_TOUCH: u8 = 62;
+pub const OP_SWAP: u8 = 63;
+pub const OP_DELETE_MANY: u8 = 64;
+pub const OP_GETDEL: u8 = 65;
+pub const OP_GETSET: u8 = 66;
+pub const OP_CLIENT_LIST: u8 = 67;
+pub const OP_CLIENT_INFO: u8 = 68;
+pub const OP_CLIENT_KILL: u8 = 69;
+pub const OP_SET_TIMED: u8 = 70;
+pub const OP_COMPARE: u8 = 71;
+pub const OP_SUBSCRIBE_EVENTS: u8 = 72;
+pub const OP_PUSH: u8 = 73;
+
+pub const STATUS_NOT_FOUND: u8 = 0;
+pub const STATUS_OK: u8 = 1;
+pub const STATUS_BAD_REQUEST: u8 = 2;
+pub const STATUS_UNSUPPORTED_VERSION: u8 = 3;
+pub const STATUS_CAPACITY_EXCEEDED: u8 = 4;
+pub const STATUS_STALE: u8 = 5;
+pub const STATUS_KEY_NOT_ALLOCATED: u8 = 6;
+pub const STATUS_CONFLICT: u8 = 7;
+pub const STATUS_READONLY: u8 = 8;
+pub const STATUS_UNAVAILABLE: u8 = 9;
+pub const STATUS_THROTTLED: u8 = 10;
+pub const STATUS_UNAUTHORIZED: u8 = 11;
+pub const STATUS_INTERNAL_ERROR: u8 = 12;
+/// Never an answer: a keepalive probe the server sends unprompted to a
+/// connection that asked for them, to be answered with PONG.
+pub const STATUS_PROBE: u8 = 13;
+/// The request was abandoned with CANCEL before it was answered.
+pub const STATUS_CANCELLED: u8 = 14;
+/// The request carries more values or bytes than the server takes in one.
+pub const STATUS_PAYLOAD_TOO_LARGE: u8 = 15;
+/// The read took longer than the server's command timeout and was abandoned.
+pub const STATUS_TIMEOUT: u8 = 16;
+
+pub const VALUE_TYPE_U32: u8 = 0;
+pub const VALUE_TYPE_U64: u8 = 1;
+pub const VALUE_TYPE_BYTES: u8 = 2;
+/// Samples written with SET_TIMED, laid out as [`timed`] describes.
+pub const VALUE_TYPE_TIMED: u8 = 3;
+
+pub const PRIORITY_LOW: u8 = 0;
+pub const PRIORITY_NORMAL: u8 = 1;
+pub const PRIORITY_HIGH: u8 = 2;
+
+/// The events SUBSCRIBE_EVENTS pushes; a subscription's value has bit
+/// `1 << event` set for each it wants.
+pub const EVENT_EXPIRED: u8 = 0;
+pub const EVENT_EVICTED: u8 = 1;
+pub const EVENT_DELETE_ALL: u8 = 2;
+/// Never subscribed to: pushed to a subscriber that fell behind, in place
+/// of the events it missed.
+pub const EVENT_LAGGED: u8 = 3;
+
+pub const PROTOCOL_MAGIC: u32 = u32::from_le_bytes(*b"M832");
+pub const PROTOCOL_VERSION: u8 = 2;
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+pub const SESSION_PROTOCOL_VERSION: u8 = 2;
+
+pub const FRAME_LEN: usize = 6;
+/// The ID a multiplexed connection's keepalive probes carry; clients must
+/// not tag requests with it.
+pub const PROBE_REQUEST_ID: u32 = u32::MAX;
+/// Set in a chunk's length on a connection that sent CHUNKED when more
+/// chunks of the same response follow.
+pub const CHUNK_CONTINUED: u32 = 1 << 31;
+pub const MAX_ADMIN_PAYLOAD: usize = 1024;
+pub const MAX_REPLACE_VALUES: u32 = 1 << 16;
+pub const MAX_DELETE_MANY_KEYS: u32 = 256;
+pub const MAX_BLOB_LEN: usize = 255;
+/// The batch size SCAN uses when the request leaves it at 0.
+pub const SCAN_DEFAULT_COUNT: u8 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub op: u8,
+    pub key: u8,
+    pub value: u32,
+}
+
+impl Frame {
+    pub fn new(op: u8, key: u8, value: u32) -> Self {
+        Frame { op, key, value }
+    }
+
+    pub fn decode(buf: &[u8; FRAME_LEN]) -> Self {
+        Frame {
+            op: buf[0],
+            key: buf[1],
+            value: u32::from_le_bytes([buf[2], buf[3], buf[4], buf[5]]),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; FRAME_LEN] {
+        let mut buf = [0u8; FRAME_LEN];
+        buf[0] = self.op;
+        buf[1] = self.key;
+        buf[2..6].copy_from_slice(&self.value.to_le_bytes());
+        buf
+    }
+}
+
+/// CRC-32 (IEEE 802.3, as used by zlib and gzip), for SYNC_FULL's trailer.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
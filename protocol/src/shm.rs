@@ -0,0 +1,152 @@
+//! The shared-memory mirror of database 0 the server keeps with
+//! `--shm-mirror`, for readers on the same machine. It is a run of 32-bit
+//! words in the machine's byte order: a header of [`SHM_HEADER_WORDS`]
+//! (magic, layout version, values per slot, reserved), then one slot per
+//! key, `[seq][len][values; capacity]`.
+//!
+//! Each slot is a seqlock. The server makes `seq` odd, rewrites the slot,
+//! and makes it even again; a reader copies the slot between two reads of
+//! `seq` and retries unless both were the same even number.
+
+use std::sync::atomic::{fence, AtomicU32, Ordering};
+
+pub const SHM_MAGIC: u32 = u32::from_le_bytes(*b"M8SM");
+pub const SHM_VERSION: u32 = 1;
+pub const SHM_HEADER_WORDS: usize = 4;
+
+/// `len` of a key with no values.
+const ABSENT: u32 = u32::MAX;
+/// `len` of a key with more values than its slot holds.
+const OVERFLOW: u32 = u32::MAX - 1;
+
+/// What a key's slot held when it was read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mirrored {
+    Values(Vec<u32>),
+    NotFound,
+    /// The key has more values than a slot holds; GET it over a socket.
+    TooLarge,
+}
+
+/// A mirror laid over mapped words, shared between the server, the only
+/// writer, and any number of readers.
+#[derive(Debug, Clone, Copy)]
+pub struct ShmMirror<'a> {
+    words: &'a [AtomicU32],
+    capacity: usize,
+}
+
+impl<'a> ShmMirror<'a> {
+    /// How many words a mirror with `capacity` values per key takes.
+    pub fn words(capacity: usize) -> usize {
+        SHM_HEADER_WORDS + 256 * (2 + capacity)
+    }
+
+    /// Lays out a new mirror over `words`, which must hold
+    /// [`ShmMirror::words`] of them, with every key absent.
+    pub fn init(words: &'a [AtomicU32], capacity: usize) -> Self {
+        assert_eq!(words.len(), Self::words(capacity), "mirror size");
+        let mirror = ShmMirror { words, capacity };
+        for key in 0..=u8::MAX {
+            mirror.write(key, None);
+        }
+        for (word, value) in words.iter().zip([SHM_MAGIC, SHM_VERSION, capacity as u32]) {
+            word.store(value, Ordering::Release);
+        }
+        mirror
+    }
+
+    /// Reads the header of a mirror the server wrote, or `None` if `words`
+    /// do not hold one this version understands.
+    pub fn open(words: &'a [AtomicU32]) -> Option<Self> {
+        let header = words.get(..SHM_HEADER_WORDS)?;
+        let [magic, version, capacity] =
+            [0, 1, 2].map(|index| header[index].load(Ordering::Acquire));
+        let capacity = capacity as usize;
+        (magic == SHM_MAGIC && version == SHM_VERSION && words.len() == Self::words(capacity))
+            .then_some(ShmMirror { words, capacity })
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn slot(&self, key: u8) -> &'a [AtomicU32] {
+        let start = SHM_HEADER_WORDS + usize::from(key) * (2 + self.capacity);
+        &self.words[start..start + 2 + self.capacity]
+    }
+
+    /// Replaces `key`'s slot with `values`, or marks it absent. Only one
+    /// writer may call this at a time.
+    pub fn write(&self, key: u8, values: Option<&[u32]>) {
+        let slot = self.slot(key);
+        let seq = slot[0].load(Ordering::Relaxed);
+        slot[0].store(seq.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+        let len = match values {
+            None => ABSENT,
+            Some(values) if values.len() > self.capacity => OVERFLOW,
+            Some(values) => {
+                for (word, value) in slot[2..].iter().zip(values) {
+                    word.store(*value, Ordering::Relaxed);
+                }
+                values.len() as u32
+            }
+        };
+        slot[1].store(len, Ordering::Relaxed);
+        slot[0].store(seq.wrapping_add(2), Ordering::Release);
+    }
+
+    /// A consistent copy of `key`'s slot, spinning while the server is
+    /// rewriting it.
+    pub fn read(&self, key: u8) -> Mirrored {
+        let slot = self.slot(key);
+        loop {
+            let before = slot[0].load(Ordering::Acquire);
+            if before & 1 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let len = slot[1].load(Ordering::Relaxed);
+            let read = match len {
+                ABSENT => Mirrored::NotFound,
+                OVERFLOW => Mirrored::TooLarge,
+                len => Mirrored::Values(
+                    slot[2..]
+                        .iter()
+                        .take((len as usize).min(self.capacity))
+                        .map(|word| word.load(Ordering::Relaxed))
+                        .collect(),
+                ),
+            };
+            fence(Ordering::Acquire);
+            if slot[0].load(Ordering::Relaxed) == before {
+                return read;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_back_what_was_written() {
+        let words: Vec<AtomicU32> = (0..ShmMirror::words(4))
+            .map(|_| AtomicU32::new(0))
+            .collect();
+        let mirror = ShmMirror::init(&words, 4);
+        mirror.write(7, Some(&[1, 2, 3]));
+        mirror.write(8, Some(&[1, 2, 3, 4, 5]));
+        mirror.write(9, Some(&[4]));
+        mirror.write(9, None);
+
+        let reader = ShmMirror::open(&words).unwrap();
+        assert_eq!(reader.read(7), Mirrored::Values(vec![1, 2, 3]));
+        assert_eq!(reader.read(8), Mirrored::TooLarge);
+        assert_eq!(reader.read(9), Mirrored::NotFound);
+        assert_eq!(reader.read(0), Mirrored::NotFound);
+        assert!(ShmMirror::open(&words[..10]).is_none());
+    }
+}
@@ -0,0 +1,116 @@
+use crate::*;
+use std::fmt;
+
+/// A status the server refuses a request with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StatusError {
+    BadRequest,
+    UnsupportedVersion,
+    CapacityExceeded,
+    Stale,
+    KeyNotAllocated,
+    Conflict,
+    ReadOnly,
+    Unavailable,
+    Throttled,
+    Unauthorized,
+    InternalError,
+    Cancelled,
+    PayloadTooLarge,
+    Timeout,
+    /// A status that is not a refusal (OK, NOT_FOUND) or that this build
+    /// does not know.
+    Unexpected(u8),
+}
+
+impl StatusError {
+    pub fn new(status: u8) -> StatusError {
+        match status {
+            STATUS_BAD_REQUEST => StatusError::BadRequest,
+            STATUS_UNSUPPORTED_VERSION => StatusError::UnsupportedVersion,
+            STATUS_CAPACITY_EXCEEDED => StatusError::CapacityExceeded,
+            STATUS_STALE => StatusError::Stale,
+            STATUS_KEY_NOT_ALLOCATED => StatusError::KeyNotAllocated,
+            STATUS_CONFLICT => StatusError::Conflict,
+            STATUS_READONLY => StatusError::ReadOnly,
+            STATUS_UNAVAILABLE => StatusError::Unavailable,
+            STATUS_THROTTLED => StatusError::Throttled,
+            STATUS_UNAUTHORIZED => StatusError::Unauthorized,
+            STATUS_INTERNAL_ERROR => StatusError::InternalError,
+            STATUS_CANCELLED => StatusError::Cancelled,
+            STATUS_PAYLOAD_TOO_LARGE => StatusError::PayloadTooLarge,
+            STATUS_TIMEOUT => StatusError::Timeout,
+            status => StatusError::Unexpected(status),
+        }
+    }
+
+    pub fn status(self) -> u8 {
+        match self {
+            StatusError::BadRequest => STATUS_BAD_REQUEST,
+            StatusError::UnsupportedVersion => STATUS_UNSUPPORTED_VERSION,
+            StatusError::CapacityExceeded => STATUS_CAPACITY_EXCEEDED,
+            StatusError::Stale => STATUS_STALE,
+            StatusError::KeyNotAllocated => STATUS_KEY_NOT_ALLOCATED,
+            StatusError::Conflict => STATUS_CONFLICT,
+            StatusError::ReadOnly => STATUS_READONLY,
+            StatusError::Unavailable => STATUS_UNAVAILABLE,
+            StatusError::Throttled => STATUS_THROTTLED,
+            StatusError::Unauthorized => STATUS_UNAUTHORIZED,
+            StatusError::InternalError => STATUS_INTERNAL_ERROR,
+            StatusError::Cancelled => STATUS_CANCELLED,
+            StatusError::PayloadTooLarge => STATUS_PAYLOAD_TOO_LARGE,
+            StatusError::Timeout => STATUS_TIMEOUT,
+            StatusError::Unexpected(status) => status,
+        }
+    }
+
+    /// A snake_case name, for text protocols and logs.
+    pub fn name(self) -> &'static str {
+        match self {
+            StatusError::BadRequest => "bad_request",
+            StatusError::UnsupportedVersion => "unsupported_version",
+            StatusError::CapacityExceeded => "capacity_exceeded",
+            StatusError::Stale => "stale",
+            StatusError::KeyNotAllocated => "key_not_allocated",
+            StatusError::Conflict => "conflict",
+            StatusError::ReadOnly => "readonly",
+            StatusError::Unavailable => "unavailable",
+            StatusError::Throttled => "throttled",
+            StatusError::Unauthorized => "unauthorized",
+            StatusError::InternalError => "internal_error",
+            StatusError::Cancelled => "cancelled",
+            StatusError::PayloadTooLarge => "payload_too_large",
+            StatusError::Timeout => "timeout",
+            StatusError::Unexpected(_) => "rejected",
+        }
+    }
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            StatusError::BadRequest => "server rejected the request",
+            StatusError::UnsupportedVersion => {
+                "server does not support the requested protocol version"
+            }
+            StatusError::CapacityExceeded => "database memory quota exceeded",
+            StatusError::Stale => "server has not applied the awaited sequence number yet",
+            StatusError::KeyNotAllocated => "key is outside every registered allocation",
+            StatusError::Conflict => "key holds values of another type",
+            StatusError::ReadOnly => "server is read-only after a persistence failure",
+            StatusError::Unavailable => "server is shedding writes while persistence fails",
+            StatusError::Throttled => "request rate limit exceeded",
+            StatusError::Unauthorized => "not authenticated, or the auth token was rejected",
+            StatusError::InternalError => "server failed while handling the request",
+            StatusError::Cancelled => "request was cancelled before it was answered",
+            StatusError::PayloadTooLarge => "request is larger than the server accepts",
+            StatusError::Timeout => "request ran past the server's command timeout",
+            StatusError::Unexpected(status) => {
+                return write!(f, "unexpected status {status} from server")
+            }
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for StatusError {}
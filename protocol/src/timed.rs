@@ -0,0 +1,195 @@
+//! How a key written with SET_TIMED stores its samples, as the words GET,
+//! LIST_ALL and SYNC_FULL return for it. Each sample is a value with the
+//! time it was taken, `[at_lo][at_hi][value]`, or a summary of every value
+//! taken in one window, `[start_lo][start_hi | SUMMARY][count][sum_lo]
+//! [sum_hi][min][max]`. Times are Unix milliseconds, split low word first,
+//! and a summary's window runs from `start` for the window length of the
+//! rollup that wrote it.
+//!
+//! The rollup folds values older than the window into the summary of the
+//! window they fall in, so a key holds its summaries, oldest first, then
+//! the values too recent to fold, in the order they arrived.
+
+use std::collections::BTreeMap;
+
+/// Set in the high word of a summary's start time.
+const SUMMARY: u32 = 1 << 31;
+const VALUE_WORDS: usize = 3;
+const SUMMARY_WORDS: usize = 7;
+
+/// The values of one window, folded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    pub start_ms: u64,
+    pub count: u32,
+    pub sum: u64,
+    pub min: u32,
+    pub max: u32,
+}
+
+impl Summary {
+    fn of(start_ms: u64, value: u32) -> Summary {
+        Summary {
+            start_ms,
+            count: 1,
+            sum: value.into(),
+            min: value,
+            max: value,
+        }
+    }
+
+    fn merge(&mut self, other: &Summary) {
+        self.count = self.count.saturating_add(other.count);
+        self.sum = self.sum.saturating_add(other.sum);
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sample {
+    Value { at_ms: u64, value: u32 },
+    Summary(Summary),
+}
+
+/// The words SET_TIMED appends for `value`, taken at `at_ms`.
+pub fn value_words(at_ms: u64, value: u32) -> [u32; VALUE_WORDS] {
+    [at_ms as u32, (at_ms >> 32) as u32 & !SUMMARY, value]
+}
+
+fn push(sample: &Sample, words: &mut Vec<u32>) {
+    match *sample {
+        Sample::Value { at_ms, value } => words.extend(value_words(at_ms, value)),
+        Sample::Summary(summary) => words.extend([
+            summary.start_ms as u32,
+            (summary.start_ms >> 32) as u32 | SUMMARY,
+            summary.count,
+            summary.sum as u32,
+            (summary.sum >> 32) as u32,
+            summary.min,
+            summary.max,
+        ]),
+    }
+}
+
+/// The samples a timed key's words hold, or `None` if they are not laid
+/// out as samples.
+pub fn decode(mut words: &[u32]) -> Option<Vec<Sample>> {
+    let mut samples = Vec::new();
+    while let [low, high, ..] = *words {
+        let at_ms = u64::from(low) | u64::from(high & !SUMMARY) << 32;
+        if high & SUMMARY == 0 {
+            let &value = words.get(2)?;
+            samples.push(Sample::Value { at_ms, value });
+            words = &words[VALUE_WORDS..];
+            continue;
+        }
+        let &[count, sum_low, sum_high, min, max] = words.get(2..SUMMARY_WORDS)? else {
+            return None;
+        };
+        samples.push(Sample::Summary(Summary {
+            start_ms: at_ms,
+            count,
+            sum: u64::from(sum_low) | u64::from(sum_high) << 32,
+            min,
+            max,
+        }));
+        words = &words[SUMMARY_WORDS..];
+    }
+    words.is_empty().then_some(samples)
+}
+
+/// A timed key's words once every value taken before `before_ms` is folded
+/// into the summary of its `window_ms`-long window, keeping the newest
+/// `keep` summaries, or all with 0. Returns how many values were folded
+/// and the new words, or `None` if no value was due or the words are not
+/// samples.
+pub fn roll_up(
+    words: &[u32],
+    before_ms: u64,
+    window_ms: u64,
+    keep: usize,
+) -> Option<(u64, Vec<u32>)> {
+    let window_ms = window_ms.max(1);
+    let mut summaries = BTreeMap::new();
+    let mut recent = Vec::new();
+    let mut folded = 0;
+    for sample in decode(words)? {
+        let summary = match sample {
+            Sample::Value { at_ms, value } if at_ms < before_ms => {
+                folded += 1;
+                Summary::of(at_ms - at_ms % window_ms, value)
+            }
+            Sample::Value { .. } => {
+                recent.push(sample);
+                continue;
+            }
+            Sample::Summary(summary) => summary,
+        };
+        summaries
+            .entry(summary.start_ms)
+            .and_modify(|existing: &mut Summary| existing.merge(&summary))
+            .or_insert(summary);
+    }
+    if folded == 0 {
+        return None;
+    }
+    let dropped = match keep {
+        0 => 0,
+        keep => summaries.len().saturating_sub(keep),
+    };
+    let mut rolled = Vec::with_capacity(words.len());
+    for summary in summaries.into_values().skip(dropped) {
+        push(&Sample::Summary(summary), &mut rolled);
+    }
+    for sample in &recent {
+        push(sample, &mut rolled);
+    }
+    Some((folded, rolled))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn folds_values_older_than_the_window_by_window() {
+        let mut words = Vec::new();
+        for (at_ms, value) in [(1_000, 5), (1_500, 3), (2_100, 8), (2_900, 1), (3_200, 9)] {
+            words.extend(value_words(at_ms, value));
+        }
+        assert_eq!(roll_up(&words, 1_000, 1_000, 0), None);
+
+        let (folded, words) = roll_up(&words, 2_500, 1_000, 0).unwrap();
+        assert_eq!(folded, 3);
+        let (folded, words) = roll_up(&words, 3_000, 1_000, 0).unwrap();
+        assert_eq!(folded, 1);
+        let summary = |start_ms, count, sum, min, max| {
+            Sample::Summary(Summary {
+                start_ms,
+                count,
+                sum,
+                min,
+                max,
+            })
+        };
+        assert_eq!(
+            decode(&words).unwrap(),
+            vec![
+                summary(1_000, 2, 8, 3, 5),
+                summary(2_000, 2, 9, 1, 8),
+                Sample::Value {
+                    at_ms: 3_200,
+                    value: 9
+                },
+            ]
+        );
+
+        let (_, words) = roll_up(&words, 4_000, 1_000, 2).unwrap();
+        assert_eq!(
+            decode(&words).unwrap(),
+            vec![summary(2_000, 2, 9, 1, 8), summary(3_000, 1, 9, 9, 9)]
+        );
+        assert_eq!(decode(&words[..words.len() - 1]), None);
+    }
+}
@@ -0,0 +1,667 @@
+use crate::audit::AuditLog;
+use crate::capture::Capture;
+use crate::config::Config;
+use crate::monitor::Monitor;
+use crate::otlp::Tracer;
+use crate::slowlog::SlowLog;
+pub use map8x32_core::dispatch::Priority;
+use map8x32_protocol::codec::{ConnectedClient, Request};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Overflow {
+    /// Accept the connection, answer UNAVAILABLE and close it.
+    Reject,
+    /// Stop accepting until a slot frees; new clients wait in the listen backlog.
+    Queue,
+}
+
+/// What to do with a connection after a frame with an unknown opcode, which
+/// may mean the client and server no longer agree where frames start.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ProtocolErrorPolicy {
+    /// Answer BAD_REQUEST and close, so one bad frame cannot shift every
+    /// request after it.
+    Close,
+    /// Answer BAD_REQUEST and read on from the next frame boundary.
+    Refuse,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitBy {
+    /// Every connection gets its own bucket.
+    Connection,
+    /// Unix socket connections from the same user share a bucket; TCP
+    /// connections still get one each.
+    Uid,
+}
+
+/// What a connection may do once admitted and, if required, authenticated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Access {
+    #[default]
+    ReadWrite,
+    /// Writes are refused with READONLY; reads work as usual.
+    ReadOnly,
+    /// Read-write, and may also change the allocation registry and, under
+    /// strict allocations, DELETE_ALL across every owner's range.
+    Admin,
+}
+
+/// An extra AUTH token from the config file, with the access it grants.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Credential {
+    pub token: String,
+    #[serde(default)]
+    pub access: Access,
+}
+
+/// The priority class the config file gives connections from a Unix user.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PriorityRule {
+    pub uid: u32,
+    pub priority: Priority,
+}
+
+/// Why a connection stopped being served.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The peer closed its end, or asked to with QUIT or a WebSocket close.
+    Eof = 0,
+    /// A request that could not be decoded or had an unknown opcode.
+    ProtocolError = 1,
+    /// Idle past the idle timeout, or stalled mid-request past the frame
+    /// timeout.
+    Timeout = 2,
+    /// The server is shutting down.
+    Shutdown = 3,
+    /// Reading or writing the socket failed, typically a reset.
+    IoError = 4,
+    /// CLIENT_KILL ended it.
+    Killed = 5,
+}
+
+impl CloseReason {
+    pub fn name(self) -> &'static str {
+        match self {
+            CloseReason::Eof => "eof",
+            CloseReason::ProtocolError => "protocol_error",
+            CloseReason::Timeout => "timeout",
+            CloseReason::Shutdown => "shutdown",
+            CloseReason::IoError => "io_error",
+            CloseReason::Killed => "killed",
+        }
+    }
+
+    pub const ALL: [CloseReason; 6] = [
+        CloseReason::Eof,
+        CloseReason::ProtocolError,
+        CloseReason::Timeout,
+        CloseReason::Shutdown,
+        CloseReason::IoError,
+        CloseReason::Killed,
+    ];
+}
+
+/// Who is on the other end of a connection, as far as the transport tells:
+/// credentials for Unix sockets, the address for TCP.
+#[derive(Debug, Clone, Default)]
+pub struct Peer {
+    pub uid: Option<u32>,
+    pub pid: Option<i32>,
+    pub addr: Option<SocketAddr>,
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(uid) = self.uid {
+            parts.push(format!("uid={uid}"));
+        }
+        if let Some(pid) = self.pid {
+            parts.push(format!("pid={pid}"));
+        }
+        if let Some(addr) = self.addr {
+            parts.push(format!("addr={addr}"));
+        }
+        if parts.is_empty() {
+            return f.write_str("unknown");
+        }
+        f.write_str(&parts.join(" "))
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Both rates are `f64` bits so a reload can change them under live
+/// connections; a rate of zero means unlimited.
+#[derive(Debug)]
+struct RateLimit {
+    ops_per_sec: AtomicU64,
+    burst: AtomicU64,
+    by: RateLimitBy,
+    by_uid: Mutex<HashMap<u32, Arc<Mutex<TokenBucket>>>>,
+}
+
+impl RateLimit {
+    fn bucket(&self) -> Arc<Mutex<TokenBucket>> {
+        // Full whatever the burst turns out to be; the first refill caps it.
+        Arc::new(Mutex::new(TokenBucket {
+            tokens: f64::INFINITY,
+            refilled: Instant::now(),
+        }))
+    }
+
+    fn rate(&self) -> Option<(f64, f64)> {
+        let ops_per_sec = f64::from_bits(self.ops_per_sec.load(Ordering::Relaxed));
+        (ops_per_sec > 0.0).then(|| {
+            (
+                ops_per_sec,
+                f64::from_bits(self.burst.load(Ordering::Relaxed)),
+            )
+        })
+    }
+
+    fn set(&self, ops_per_sec: Option<f64>, burst: Option<f64>) {
+        let ops_per_sec = ops_per_sec.unwrap_or(0.0);
+        let burst = burst.unwrap_or(ops_per_sec).max(1.0);
+        self.burst.store(burst.to_bits(), Ordering::Relaxed);
+        self.ops_per_sec
+            .store(ops_per_sec.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// The connection limit as last configured. Lowering it below the number of
+/// connections being served leaves permits `owed`, retired as those close.
+#[derive(Debug, Default)]
+struct SlotLimit {
+    max: Option<usize>,
+    owed: usize,
+}
+
+/// Caps concurrently served connections across every listener and bounds
+/// how long an idle or stalled one is kept.
+#[derive(Debug)]
+pub struct Admission {
+    pub idle_timeout: Option<Duration>,
+    pub frame_timeout: Option<Duration>,
+    /// How often idle TCP connections that sent KEEPALIVE are probed, and
+    /// how long each has to answer.
+    pub keepalive: Option<Duration>,
+    /// The largest chunk a connection that sent CHUNKED is sent.
+    pub max_frame: u32,
+    max_request_values: usize,
+    max_request_bytes: usize,
+    /// How long answers wait for more pipelined requests before they are
+    /// written, and how many bytes of them are written regardless.
+    pub flush_delay: Option<Duration>,
+    pub flush_bytes: usize,
+    pub on_protocol_error: ProtocolErrorPolicy,
+    overflow: Overflow,
+    slots: Arc<Semaphore>,
+    limit: Mutex<SlotLimit>,
+    active: AtomicU64,
+    admitted: AtomicU64,
+    pub peak: AtomicU64,
+    pub rejected: AtomicU64,
+    pub idle_reaped: AtomicU64,
+    pub frame_timeouts: AtomicU64,
+    pub keepalive_timeouts: AtomicU64,
+    pub protocol_errors: AtomicU64,
+    closed: [AtomicU64; CloseReason::ALL.len()],
+    /// Set once the server starts shutting down; connections close at their
+    /// next wait for a request.
+    closing: watch::Sender<bool>,
+    rate_limit: RateLimit,
+    pub throttled: AtomicU64,
+    pub payload_rejections: AtomicU64,
+    credentials: Vec<(Vec<u8>, Access)>,
+    priorities: HashMap<u32, Priority>,
+    pub auth_failures: AtomicU64,
+    pub tls_handshake_failures: AtomicU64,
+    /// SET frames applied from, and frames dropped by, the datagram socket.
+    pub datagram_sets: AtomicU64,
+    pub datagram_dropped: AtomicU64,
+    read_only: AtomicBool,
+    pub slow_log: SlowLog,
+    pub audit: Option<AuditLog>,
+    pub monitor: Monitor,
+    pub tracer: Option<Tracer>,
+    pub capture: Option<Capture>,
+    /// Every connection being served, by ID.
+    connections: Mutex<BTreeMap<u64, Arc<Activity>>>,
+}
+
+/// What one connection has been doing, for diagnostic dumps and
+/// CLIENT_LIST.
+#[derive(Debug)]
+pub struct Activity {
+    pub peer: Peer,
+    pub admitted_at: Instant,
+    admitted_unix_ms: u64,
+    pub requests: AtomicU64,
+    /// When the latest request arrived, in milliseconds after admission.
+    pub last_request_ms: AtomicU64,
+    /// Requests the connection is waiting on answers to.
+    pub in_flight: AtomicU64,
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+    pub last_op: AtomicU8,
+    /// Set by CLIENT_KILL.
+    killed: watch::Sender<bool>,
+}
+
+impl Activity {
+    /// Counts a request `bytes` long as encoded as the connection's latest.
+    pub fn received(&self, op: u8, bytes: usize) {
+        self.last_op.store(op, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts `bytes` of answers written to the connection.
+    pub fn sent(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Resolves once CLIENT_KILL has asked for the connection to close.
+    pub async fn killed(&self) {
+        let _ = self.killed.subscribe().wait_for(|killed| *killed).await;
+    }
+
+    pub fn client(&self, id: u64) -> ConnectedClient {
+        ConnectedClient {
+            id,
+            uid: self.peer.uid,
+            peer: self.peer.to_string(),
+            connected_unix_ms: self.admitted_unix_ms,
+            requests: self.requests.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            last_op: self.last_op.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Held by a connection task for as long as the connection is served.
+#[derive(Debug)]
+pub struct Admitted {
+    /// Numbers connections in admission order, for logs.
+    pub id: u64,
+    pub peer: Peer,
+    admission: Arc<Admission>,
+    slot: Option<OwnedSemaphorePermit>,
+    bucket: Arc<Mutex<TokenBucket>>,
+    pub activity: Arc<Activity>,
+}
+
+impl Admitted {
+    pub fn admission(&self) -> &Admission {
+        &self.admission
+    }
+
+    /// Takes a token for one request. Returns `false`, and counts the
+    /// request as throttled, when the connection is over its rate limit.
+    pub fn allow(&self) -> bool {
+        let activity = &self.activity;
+        activity.requests.fetch_add(1, Ordering::Relaxed);
+        activity.last_request_ms.store(
+            activity.admitted_at.elapsed().as_millis() as u64,
+            Ordering::Relaxed,
+        );
+        let Some((ops_per_sec, burst)) = self.admission.rate_limit.rate() else {
+            return true;
+        };
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * ops_per_sec).min(burst);
+        bucket.refilled = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return true;
+        }
+        self.admission.throttled.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+
+    /// The class the config file gives the peer's user, or normal.
+    pub fn priority(&self) -> Priority {
+        self.peer
+            .uid
+            .and_then(|uid| self.admission.priorities.get(&uid).copied())
+            .unwrap_or_default()
+    }
+
+    /// Counts the connection as closed for `reason`.
+    pub fn closed(&self, reason: CloseReason) {
+        self.admission.closed[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Admitted {
+    fn drop(&mut self) {
+        self.admission.active.fetch_sub(1, Ordering::Relaxed);
+        self.admission.connections.lock().unwrap().remove(&self.id);
+        let mut limit = self.admission.limit.lock().unwrap();
+        if let (Some(slot), true) = (self.slot.take(), limit.owed > 0) {
+            limit.owed -= 1;
+            slot.forget();
+        }
+    }
+}
+
+impl Admission {
+    pub fn new(
+        config: &Config,
+        audit: Option<AuditLog>,
+        tracer: Option<Tracer>,
+        capture: Option<Capture>,
+    ) -> Arc<Self> {
+        let max = config.max_connections;
+        let rate_limit = RateLimit {
+            ops_per_sec: AtomicU64::new(0),
+            burst: AtomicU64::new(0),
+            by: config.rate_limit_by,
+            by_uid: Mutex::new(HashMap::new()),
+        };
+        rate_limit.set(config.rate_limit, config.rate_limit_burst);
+        Arc::new(Admission {
+            idle_timeout: config.idle_timeout.map(Duration::from_secs),
+            frame_timeout: Some(Duration::from_millis(config.frame_timeout_ms))
+                .filter(|timeout| !timeout.is_zero()),
+            keepalive: config
+                .keepalive_ms
+                .map(Duration::from_millis)
+                .filter(|interval| !interval.is_zero()),
+            max_frame: config.max_frame_bytes,
+            max_request_values: config.max_request_values as usize,
+            max_request_bytes: config.max_request_bytes,
+            flush_delay: Some(Duration::from_micros(config.flush_delay_us))
+                .filter(|delay| !delay.is_zero()),
+            flush_bytes: config.flush_bytes,
+            on_protocol_error: config.on_protocol_error,
+            overflow: config.connection_overflow,
+            slots: Arc::new(Semaphore::new(max.unwrap_or(Semaphore::MAX_PERMITS))),
+            limit: Mutex::new(SlotLimit { max, owed: 0 }),
+            active: AtomicU64::new(0),
+            admitted: AtomicU64::new(0),
+            peak: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            idle_reaped: AtomicU64::new(0),
+            frame_timeouts: AtomicU64::new(0),
+            keepalive_timeouts: AtomicU64::new(0),
+            protocol_errors: AtomicU64::new(0),
+            closed: std::array::from_fn(|_| AtomicU64::new(0)),
+            closing: watch::channel(false).0,
+            rate_limit,
+            throttled: AtomicU64::new(0),
+            payload_rejections: AtomicU64::new(0),
+            credentials: config
+                .auth_token
+                .iter()
+                .map(|token| (token.clone().into_bytes(), Access::Admin))
+                .chain(
+                    config.credentials.iter().map(|credential| {
+                        (credential.token.clone().into_bytes(), credential.access)
+                    }),
+                )
+                .collect(),
+            priorities: config
+                .priorities
+                .iter()
+                .map(|rule| (rule.uid, rule.priority))
+                .collect(),
+            auth_failures: AtomicU64::new(0),
+            tls_handshake_failures: AtomicU64::new(0),
+            datagram_sets: AtomicU64::new(0),
+            datagram_dropped: AtomicU64::new(0),
+            read_only: AtomicBool::new(config.read_only),
+            slow_log: SlowLog::new(
+                config.slow_log_threshold_us.map(Duration::from_micros),
+                config.slow_log_len,
+            ),
+            audit,
+            monitor: Monitor::new(),
+            tracer,
+            capture,
+            connections: Mutex::default(),
+        })
+    }
+
+    pub fn requires_auth(&self) -> bool {
+        !self.credentials.is_empty()
+    }
+
+    /// Checks `token` against every configured one in constant time and
+    /// returns the access it grants, counting failed attempts.
+    pub fn authenticate(&self, token: &[u8]) -> Option<Access> {
+        if self.credentials.is_empty() {
+            return Some(Access::Admin);
+        }
+        let mut granted = None;
+        for (expected, access) in &self.credentials {
+            let matches = expected.len() == token.len()
+                && expected
+                    .iter()
+                    .zip(token)
+                    .fold(0, |diff, (a, b)| diff | (a ^ b))
+                    == 0;
+            if matches && granted.is_none() {
+                granted = Some(*access);
+            }
+        }
+        if granted.is_none() {
+            self.auth_failures.fetch_add(1, Ordering::Relaxed);
+        }
+        granted
+    }
+
+    /// Whether the whole instance refuses writes, regardless of credentials.
+    pub fn read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    /// Whether a connection with `access` may run `request`.
+    pub fn permits(&self, access: Access, request: &Request) -> bool {
+        !request.writes() || (access != Access::ReadOnly && !self.read_only())
+    }
+
+    /// Whether `request` is only for admin connections: ALLOC_REGISTER and
+    /// ALLOC_RELEASE always, and DELETE_ALL once `strict` allocations make
+    /// every range someone's to clear.
+    pub fn needs_admin(request: &Request, strict: bool) -> bool {
+        match request {
+            Request::AllocRegister(_) | Request::AllocRelease { .. } => true,
+            Request::DeleteAll { .. } => strict,
+            _ => false,
+        }
+    }
+
+    /// Whether `request`, `request_bytes` long as encoded, is over the
+    /// request limits; counts it if so. The codecs' own limits bound what
+    /// decoding it allocated.
+    pub fn too_large(&self, request: &Request, request_bytes: usize) -> bool {
+        let values = match request {
+            Request::ReplaceIf { values, .. } | Request::Push { values, .. } => values.len(),
+            _ => 0,
+        };
+        let too_large = request_bytes > self.max_request_bytes || values > self.max_request_values;
+        if too_large {
+            self.payload_rejections.fetch_add(1, Ordering::Relaxed);
+        }
+        too_large
+    }
+
+    /// Applies a new request rate and burst to every connection, including
+    /// the ones already being served; `None` lifts the limit.
+    pub fn set_rate_limit(&self, ops_per_sec: Option<f64>, burst: Option<f64>) {
+        self.rate_limit.set(ops_per_sec, burst);
+    }
+
+    /// The request rate and burst connections are held to, if limited.
+    pub fn rate_limit(&self) -> Option<(f64, f64)> {
+        self.rate_limit.rate()
+    }
+
+    pub fn max_connections(&self) -> Option<usize> {
+        self.limit.lock().unwrap().max
+    }
+
+    /// Changes the connection limit. Connections already past a lowered
+    /// limit are left alone; new ones are turned away until enough close.
+    pub fn set_max_connections(&self, max: Option<usize>) {
+        let mut limit = self.limit.lock().unwrap();
+        let old = limit.max.unwrap_or(Semaphore::MAX_PERMITS);
+        let new = max.unwrap_or(Semaphore::MAX_PERMITS);
+        if new > old {
+            let repaid = (new - old).min(limit.owed);
+            limit.owed -= repaid;
+            self.slots.add_permits(new - old - repaid);
+        } else {
+            let retired = self.slots.forget_permits(old - new);
+            limit.owed += old - new - retired;
+        }
+        limit.max = max;
+    }
+
+    pub fn active(&self) -> u64 {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Connections closed for `reason` since startup.
+    pub fn closed(&self, reason: CloseReason) -> u64 {
+        self.closed[reason as usize].load(Ordering::Relaxed)
+    }
+
+    /// Resolves once [`Admission::close_connections`] has been called.
+    pub async fn closing(&self) {
+        let _ = self.closing.subscribe().wait_for(|closing| *closing).await;
+    }
+
+    /// Asks every connection to close once it has answered what it has
+    /// read, and waits up to `grace` for them to.
+    pub async fn close_connections(&self, grace: Duration) {
+        self.closing.send_replace(true);
+        let deadline = Instant::now() + grace;
+        while self.active() > 0 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Called before `accept`: in queue mode, waits for a free slot.
+    pub async fn queued_slot(&self) -> Option<OwnedSemaphorePermit> {
+        if self.overflow == Overflow::Queue && self.max_connections().is_some() {
+            self.slots.clone().acquire_owned().await.ok()
+        } else {
+            None
+        }
+    }
+
+    /// Called after `accept` with the result of [`Admission::queued_slot`]
+    /// and whatever is known about the peer. Returns `None` when the
+    /// connection must be turned away.
+    /// The connections being served and what each has done, by ID.
+    pub fn connections(&self) -> Vec<(u64, Arc<Activity>)> {
+        self.connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, activity)| (*id, activity.clone()))
+            .collect()
+    }
+
+    /// Asks connection `id` to close at once, dropping whatever it is in
+    /// the middle of. Returns whether it was being served.
+    pub fn kill(&self, id: u64) -> bool {
+        match self.connections.lock().unwrap().get(&id) {
+            Some(activity) => {
+                activity.killed.send_replace(true);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The connections being served, as CLIENT_LIST reports them.
+    pub fn clients(&self) -> Vec<ConnectedClient> {
+        self.connections()
+            .into_iter()
+            .map(|(id, activity)| activity.client(id))
+            .collect()
+    }
+
+    pub fn admit(
+        self: &Arc<Self>,
+        queued: Option<OwnedSemaphorePermit>,
+        peer: Peer,
+    ) -> Option<Admitted> {
+        let slot = match queued {
+            Some(queued) => queued,
+            None => match self.slots.clone().try_acquire_owned() {
+                Ok(slot) => slot,
+                Err(_) => {
+                    self.rejected.fetch_add(1, Ordering::Relaxed);
+                    return None;
+                }
+            },
+        };
+        let limit = &self.rate_limit;
+        let bucket = match (limit.by, peer.uid) {
+            (RateLimitBy::Uid, Some(uid)) => limit
+                .by_uid
+                .lock()
+                .unwrap()
+                .entry(uid)
+                .or_insert_with(|| limit.bucket())
+                .clone(),
+            _ => limit.bucket(),
+        };
+        let active = self.active.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak.fetch_max(active, Ordering::Relaxed);
+        let id = self.admitted.fetch_add(1, Ordering::Relaxed) + 1;
+        let activity = Arc::new(Activity {
+            peer: peer.clone(),
+            admitted_at: Instant::now(),
+            admitted_unix_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            requests: AtomicU64::new(0),
+            last_request_ms: AtomicU64::new(0),
+            in_flight: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            last_op: AtomicU8::new(0),
+            killed: watch::channel(false).0,
+        });
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(id, activity.clone());
+        Some(Admitted {
+            id,
+            peer,
+            admission: self.clone(),
+            slot: Some(slot),
+            bucket,
+            activity,
+        })
+    }
+}
@@ -0,0 +1,112 @@
+use crate::admission::Peer;
+use crate::trace;
+use map8x32_protocol::codec::Request;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug)]
+struct AuditFile {
+    file: File,
+    len: u64,
+}
+
+/// Appends one JSON line per write request to a file, whatever the outcome.
+/// Once the file reaches `max_bytes` it is renamed to `<path>.1`, older
+/// rotations shift up, and anything past `keep` is deleted.
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    current: Mutex<AuditFile>,
+    pub failures: AtomicU64,
+}
+
+fn open(path: &Path) -> io::Result<AuditFile> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let len = file.metadata()?.len();
+    Ok(AuditFile { file, len })
+}
+
+pub(crate) fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl AuditLog {
+    pub fn open(path: &Path, max_bytes: u64, keep: usize) -> io::Result<AuditLog> {
+        Ok(AuditLog {
+            path: path.to_path_buf(),
+            max_bytes,
+            keep,
+            current: Mutex::new(open(path)?),
+            failures: AtomicU64::new(0),
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+        path.into()
+    }
+
+    fn rotate(&self, current: &mut AuditFile) -> io::Result<()> {
+        current.file.sync_data()?;
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(self.rotated(self.keep));
+            for n in (1..self.keep).rev() {
+                match fs::rename(self.rotated(n), self.rotated(n + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        *current = open(&self.path)?;
+        Ok(())
+    }
+
+    /// Records `request` to database `db`, answered with `status`, as sent
+    /// by `peer`.
+    pub fn record(&self, peer: &Peer, db: u8, request: &Request, status: u8) {
+        let at_unix_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let key = request
+            .key()
+            .map_or_else(|| "null".to_string(), |key| key.to_string());
+        let line = format!(
+            "{{\"at_unix_ms\":{at_unix_ms},\"peer\":\"{}\",\"db\":{db},\"op\":{},\"key\":{key},\"command\":\"{}\",\"status\":{status}}}\n",
+            escape(&peer.to_string()),
+            request.op(),
+            escape(&trace::describe(request)),
+        );
+        let mut current = self.current.lock().unwrap();
+        let result = (|| {
+            if current.len > 0 && current.len + line.len() as u64 > self.max_bytes {
+                self.rotate(&mut current)?;
+            }
+            current.file.write_all(line.as_bytes())?;
+            current.len += line.len() as u64;
+            Ok::<_, io::Error>(())
+        })();
+        if let Err(e) = result {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+            tracing::error!(error = %e, path = %self.path.display(), "failed to write the audit log");
+        }
+    }
+}
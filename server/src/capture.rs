@@ -0,0 +1,58 @@
+use map8x32_protocol::capture::{capture_header, CaptureRecord, Captured};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Appends every request connections decode, with when it arrived and on
+/// which connection, and every connection closing, to a capture file for
+/// `map8x32-cli replay`.
+#[derive(Debug)]
+pub struct Capture {
+    file: Mutex<File>,
+    started: Instant,
+}
+
+impl Capture {
+    /// Creates the file readable by its owner only, since it holds every
+    /// AUTH token clients send.
+    pub fn create(path: &Path) -> io::Result<Capture> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)?;
+        file.write_all(&capture_header())?;
+        Ok(Capture {
+            file: Mutex::new(file),
+            started: Instant::now(),
+        })
+    }
+
+    /// Records `bytes`, a whole request connection `connection` just sent.
+    pub fn request(&self, connection: u64, bytes: &[u8]) {
+        self.write(connection, Captured::Request(bytes.to_vec()));
+    }
+
+    pub fn closed(&self, connection: u64) {
+        self.write(connection, Captured::Closed);
+    }
+
+    fn write(&self, connection: u64, event: Captured) {
+        // Timed under the lock so records stay in time order.
+        let mut file = self.file.lock().unwrap();
+        let record = CaptureRecord {
+            at_us: self.started.elapsed().as_micros() as u64,
+            connection,
+            event,
+        };
+        let mut out = Vec::new();
+        record.encode(&mut out);
+        if let Err(e) = file.write_all(&out) {
+            tracing::warn!(error = %e, "failed to write capture record");
+        }
+    }
+}
@@ -0,0 +1,1065 @@
+use crate::admission::{Credential, Overflow, PriorityRule, ProtocolErrorPolicy, RateLimitBy};
+use crate::cap::CapRule;
+use crate::db::DEFAULT_WRITE_BATCH;
+use crate::encryption::KeySource;
+use crate::logging::{self, LogFormat};
+use crate::persistence::{FailurePolicy, FsyncPolicy};
+use crate::registry::Allocation;
+use crate::rollup::RollupRule;
+use crate::sink::Target;
+use crate::store::StorageOrder;
+use crate::transform::TransformRule;
+use clap::{Parser, Subcommand, ValueEnum};
+use map8x32_protocol::codec::MAX_MESSAGE_LEN;
+use map8x32_protocol::{
+    CHUNK_CONTINUED, DEFAULT_SOCKET_PATH, MAX_ADMIN_PAYLOAD, MAX_REPLACE_VALUES,
+};
+use serde::Deserialize;
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "map8x32-server",
+    about = "In-memory u8 -> [u32] key-value store"
+)]
+pub struct Args {
+    #[arg(
+        long,
+        help = "TOML config file; flags given on the command line take precedence"
+    )]
+    config: Option<PathBuf>,
+    #[arg(long, help = "Unix socket path [default: /tmp/map8x32.sock]")]
+    socket: Option<String>,
+    #[arg(
+        long,
+        help = "Also accept connections on this TCP address, e.g. 127.0.0.1:7832"
+    )]
+    tcp: Option<String>,
+    #[arg(
+        long,
+        help = "Also apply SET frames sent as datagrams to this Unix datagram socket, without answering them"
+    )]
+    datagram: Option<String>,
+    #[arg(
+        long,
+        help = "Mirror database 0 into this file for lock-free reads by processes mapping it on this machine; needs --features shm-mirror"
+    )]
+    shm_mirror: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Values each key's slot in the shared-memory mirror holds; longer keys must be read over a socket [default: 64]"
+    )]
+    shm_values_per_key: Option<usize>,
+    #[arg(
+        long,
+        help = "Serve the TCP listener over TLS with this PEM certificate chain; needs --tls-key"
+    )]
+    tls_cert: Option<PathBuf>,
+    #[arg(long, help = "PEM private key for --tls-cert")]
+    tls_key: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Require TLS clients to present a certificate signed by a CA in this PEM file"
+    )]
+    tls_client_ca: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Serve Prometheus metrics over HTTP on this address, e.g. 127.0.0.1:9832"
+    )]
+    metrics: Option<String>,
+    #[arg(
+        long,
+        help = "Also speak a subset of the Redis protocol (RPUSH, LRANGE, DEL, FLUSHALL, KEYS) on this TCP address, e.g. 127.0.0.1:6379"
+    )]
+    resp: Option<String>,
+    #[arg(
+        long,
+        help = "Accept WebSocket connections taking JSON commands, with change pushes for subscribed keys, on this TCP address, e.g. 127.0.0.1:9833"
+    )]
+    websocket: Option<String>,
+    #[arg(
+        long,
+        help = "Reject SETs once a database's approximate memory reaches this many bytes"
+    )]
+    db_max_memory: Option<u64>,
+    #[arg(
+        long,
+        help = "Reject writes that would give a database more than this many keys"
+    )]
+    db_max_keys: Option<u64>,
+    #[arg(
+        long,
+        help = "Reject writes that would give a database more than this many values across its keys"
+    )]
+    db_max_values: Option<u64>,
+    #[arg(
+        long,
+        value_enum,
+        help = "Keep each database's keys in a sharded hash map, or in a B-tree that every walk of the keys reads in key order, with writers taking turns [default: hash]"
+    )]
+    storage_order: Option<StorageOrder>,
+    #[arg(
+        long,
+        help = "Pack a key's values as deltas once it holds this many in non-decreasing order, to cut memory for long lists of timestamps or IDs"
+    )]
+    pack_values_above: Option<usize>,
+    #[arg(
+        long,
+        help = "Logical databases clients can choose between with OP_SELECT, up to 256 [default: 1]"
+    )]
+    databases: Option<usize>,
+    #[arg(
+        long,
+        help = "Purge values set with SET_EXPIRING this many milliseconds after they expire at most; 0 disables [default: 1000]"
+    )]
+    expiry_sweep_ms: Option<u64>,
+    #[arg(
+        long,
+        help = "After binding, measure in-process engine throughput and latency on this host"
+    )]
+    self_test: bool,
+    #[arg(
+        long,
+        help = "Worker threads; keys are sharded across this many command processors [default: 1]"
+    )]
+    threads: Option<usize>,
+    #[arg(
+        long,
+        help = "Answer reads not answered within this many milliseconds TIMEOUT and abandon them; 0 disables [default: 0]"
+    )]
+    command_timeout_ms: Option<u64>,
+    #[arg(
+        long,
+        help = "Apply up to this many SETs queued back to back for one key together, with one WAL lock and one lookup of the key; 1 applies each on its own [default: 64]"
+    )]
+    write_batch: Option<usize>,
+    #[arg(
+        long,
+        help = "Keep the most recent N rejected writes for OP_DEAD_LETTERS [default: 0, disabled]"
+    )]
+    dead_letters: Option<usize>,
+    #[arg(
+        long,
+        help = "Keep commands that take at least this many microseconds for OP_SLOW_LOG [default: disabled]"
+    )]
+    slow_log_threshold_us: Option<u64>,
+    #[arg(long, help = "How many slow commands OP_SLOW_LOG keeps [default: 128]")]
+    slow_log_len: Option<usize>,
+    #[arg(
+        long,
+        help = "Refuse writes to keys outside every registered key-range allocation"
+    )]
+    strict_allocations: bool,
+    #[arg(
+        long,
+        value_enum,
+        help = "Socket I/O backend for the Unix listener [default: epoll]"
+    )]
+    transport: Option<Transport>,
+    #[arg(
+        long,
+        help = "Persist data to a write-ahead log and snapshots in this directory"
+    )]
+    data_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Seconds between snapshots; 0 disables them [default: 300]"
+    )]
+    snapshot_interval: Option<u64>,
+    #[arg(
+        long,
+        help = "Also snapshot, compacting the WAL, once its active segment reaches this many bytes [default: no limit]"
+    )]
+    wal_max_bytes: Option<u64>,
+    #[arg(
+        long,
+        value_enum,
+        help = "When to fsync the write-ahead log [default: everysec]"
+    )]
+    wal_fsync: Option<FsyncPolicy>,
+    #[arg(
+        long,
+        value_enum,
+        help = "What to do with writes once persistence fails [default: shed-writes]"
+    )]
+    on_persistence_failure: Option<FailurePolicy>,
+    #[arg(
+        long,
+        help = "Encrypt snapshots and the WAL with the hex keys in this file, the current one first [default: keys from MAP8X32_ENCRYPTION_KEYS, if set]"
+    )]
+    encryption_key_file: Option<PathBuf>,
+    #[arg(
+        long,
+        conflicts_with = "encryption_key_file",
+        help = "Read the encryption keys from this inherited file descriptor instead of a file"
+    )]
+    encryption_key_fd: Option<i32>,
+    #[arg(
+        long,
+        help = "Upload each snapshot to the S3-compatible endpoint at this address, e.g. 127.0.0.1:9000, over HTTP, signed with AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY; needs --features s3-upload"
+    )]
+    upload_endpoint: Option<String>,
+    #[arg(long, help = "Bucket --upload-endpoint stores snapshots in")]
+    upload_bucket: Option<String>,
+    #[arg(long, help = "Region uploads are signed for [default: us-east-1]")]
+    upload_region: Option<String>,
+    #[arg(
+        long,
+        help = "Prefix of uploaded snapshots' object names, e.g. backups/ [default: none]"
+    )]
+    upload_prefix: Option<String>,
+    #[arg(
+        long,
+        help = "Uploaded snapshots to keep per database, deleting older ones [default: 7]"
+    )]
+    upload_keep: Option<usize>,
+    #[arg(
+        long,
+        help = "Forward every change as a JSON line to pipe:<fifo>, log:<file> or an http://<host:port>/<path> webhook"
+    )]
+    change_sink: Option<String>,
+    #[arg(
+        long,
+        help = "Changes queued for --change-sink before every key is sent again instead [default: 4096]"
+    )]
+    change_sink_buffer: Option<usize>,
+    #[arg(
+        long,
+        help = "Take the listeners and data over from a server listening on this Unix socket, then listen on it for the next; needs --features handoff"
+    )]
+    handoff: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Let DUMP write dump files into this directory [default: DUMP is refused]"
+    )]
+    dump_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Load this JSON file of keys and their values, e.g. {\"1\": [10, 20]}, into database 0 before accepting connections, unless the data directory already holds data"
+    )]
+    seed: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Let DELETE_ALL clear a database when it carries this nonzero token [default: DELETE_ALL is refused]"
+    )]
+    delete_all_token: Option<u32>,
+    #[arg(
+        long,
+        help = "Serve at most this many client connections at once [default: unlimited]"
+    )]
+    max_connections: Option<usize>,
+    #[arg(
+        long,
+        value_enum,
+        help = "What to do with connections beyond --max-connections [default: reject]"
+    )]
+    connection_overflow: Option<Overflow>,
+    #[arg(
+        long,
+        help = "Close connections that send nothing for this many seconds [default: never]"
+    )]
+    idle_timeout: Option<u64>,
+    #[arg(
+        long,
+        help = "Close connections that stall this many milliseconds mid-request; 0 disables [default: 5000]"
+    )]
+    frame_timeout_ms: Option<u64>,
+    #[arg(
+        long,
+        help = "Probe idle TCP connections that asked for it with KEEPALIVE every this many milliseconds, closing any that do not answer in as long [default: off]"
+    )]
+    keepalive_ms: Option<u64>,
+    #[arg(
+        long,
+        help = "Split responses longer than this many bytes into chunks on connections that sent CHUNKED [default: 65536]"
+    )]
+    max_frame_bytes: Option<u32>,
+    #[arg(
+        long,
+        help = "Refuse REPLACE_IF and PUSH requests carrying more than this many values with PAYLOAD_TOO_LARGE [default: 65536]"
+    )]
+    max_request_values: Option<u32>,
+    #[arg(
+        long,
+        help = "Refuse requests longer than this many bytes, as encoded, with PAYLOAD_TOO_LARGE [default: 1048576]"
+    )]
+    max_request_bytes: Option<usize>,
+    #[arg(
+        long,
+        help = "Hold answers to pipelined requests up to this many microseconds for more requests to arrive, so their answers share one write [default: 0]"
+    )]
+    flush_delay_us: Option<u64>,
+    #[arg(
+        long,
+        help = "Write held answers as soon as this many bytes of them are pending [default: 65536]"
+    )]
+    flush_bytes: Option<usize>,
+    #[arg(
+        long,
+        value_enum,
+        help = "What to do with a connection after a frame with an unknown opcode [default: close]"
+    )]
+    on_protocol_error: Option<ProtocolErrorPolicy>,
+    #[arg(
+        long,
+        help = "Allow each client this many requests per second; excess requests get THROTTLED [default: unlimited]"
+    )]
+    rate_limit: Option<f64>,
+    #[arg(
+        long,
+        help = "Requests a client may burst above --rate-limit [default: the rate]"
+    )]
+    rate_limit_burst: Option<f64>,
+    #[arg(
+        long,
+        value_enum,
+        help = "Whether --rate-limit applies per connection or per Unix peer uid [default: connection]"
+    )]
+    rate_limit_by: Option<RateLimitBy>,
+    #[arg(
+        long,
+        help = "Record every command, in arrival order, to this trace file"
+    )]
+    trace: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Capture every request connections send, with its arrival time, to this file for map8x32-cli replay"
+    )]
+    capture: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Export a span per request to the OpenTelemetry collector taking OTLP/HTTP at this address, e.g. 127.0.0.1:4318; needs --features otlp"
+    )]
+    otlp_endpoint: Option<String>,
+    #[arg(
+        long,
+        help = "Share of requests --otlp-endpoint traces, from 0 to 1 [default: 1]"
+    )]
+    otlp_sample_ratio: Option<f64>,
+    #[arg(
+        long,
+        help = "On graceful shutdown, also write the final state report to this JSON file"
+    )]
+    shutdown_report: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Write the diagnostic reports SIGUSR1 asks for into this directory [default: the system temporary directory]"
+    )]
+    diagnostics_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Append every write request, with its peer and outcome, to this audit log"
+    )]
+    audit_log: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Rotate the audit log once it reaches this many bytes [default: 67108864]"
+    )]
+    audit_log_max_bytes: Option<u64>,
+    #[arg(long, help = "Rotated audit logs to keep [default: 5]")]
+    audit_log_keep: Option<usize>,
+    #[arg(
+        long,
+        help = "Require clients to send AUTH with the token in this file before any other command"
+    )]
+    auth_token_file: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Refuse every SET, DELETE and allocation change with READONLY; reads keep working"
+    )]
+    read_only: bool,
+    #[arg(
+        long,
+        help = "Log level or filter, e.g. debug or warn,map8x32_server=debug [default: info]"
+    )]
+    log_level: Option<String>,
+    #[arg(long, value_enum, help = "Log output format [default: text]")]
+    log_format: Option<LogFormat>,
+    #[arg(
+        long,
+        help = "Replay a trace file against a fresh engine instead of serving"
+    )]
+    replay: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Check the data directory's snapshots and WAL segments, print what startup would recover and discard, and exit without serving; exits 1 if anything would be discarded"
+    )]
+    check: bool,
+    #[arg(
+        long,
+        conflicts_with = "check",
+        help = "Before serving, cut corrupt and torn records off the WAL and move aside a corrupt snapshot and the segments after a corrupt record, keeping what is discarded in .discarded files, and print what was recovered and discarded"
+    )]
+    repair: bool,
+    #[arg(
+        long = "break-key",
+        requires = "replay",
+        help = "During --replay, pause only at commands touching this key (repeatable)"
+    )]
+    break_keys: Vec<u8>,
+    #[command(subcommand)]
+    pub tool: Option<Tool>,
+}
+
+/// Runs instead of serving; the server's own flags do not apply.
+#[derive(Subcommand, Debug)]
+pub enum Tool {
+    #[command(
+        about = "Drive a scratch engine in-process, without sockets, and print its SET/GET throughput and latency"
+    )]
+    Selftest {
+        #[arg(
+            long,
+            default_value_t = 20_000,
+            help = "Commands of each kind every client sends"
+        )]
+        ops: u32,
+        #[arg(
+            long,
+            default_value_t = 1,
+            help = "Command processors the keys are sharded across, as with --threads"
+        )]
+        threads: usize,
+        #[arg(
+            long,
+            default_value_t = 1,
+            help = "Clients sending commands at once, each waiting for its last answer"
+        )]
+        clients: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Epoll,
+    Uring,
+}
+
+/// A further socket or TCP address served alongside `socket` and `tcp`,
+/// declared in the config file, e.g. a root-only socket for admin tooling.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Listener {
+    pub socket: Option<String>,
+    /// Permission bits the socket file gets [default: 0o666].
+    pub mode: Option<u32>,
+    pub tcp: Option<String>,
+}
+
+impl Listener {
+    fn validate(&self) -> Result<(), String> {
+        match (&self.socket, &self.tcp) {
+            (Some(_), None) if self.mode.is_some_and(|mode| mode > 0o777) => {
+                Err("a listener's mode is permission bits, at most 0o777".to_string())
+            }
+            (Some(_), None) => Ok(()),
+            (None, Some(_)) if self.mode.is_none() => Ok(()),
+            (None, Some(_)) => Err("mode applies to socket listeners".to_string()),
+            _ => Err("each listener needs exactly one of socket and tcp".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub socket: String,
+    pub tcp: Option<String>,
+    pub listeners: Vec<Listener>,
+    pub datagram: Option<String>,
+    pub shm_mirror: Option<PathBuf>,
+    pub shm_values_per_key: usize,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_client_ca: Option<PathBuf>,
+    pub metrics: Option<String>,
+    pub resp: Option<String>,
+    pub websocket: Option<String>,
+    pub db_max_memory: Option<u64>,
+    pub db_max_keys: Option<u64>,
+    pub db_max_values: Option<u64>,
+    pub storage_order: StorageOrder,
+    pub pack_values_above: Option<usize>,
+    pub databases: usize,
+    pub expiry_sweep_ms: u64,
+    pub self_test: bool,
+    pub threads: usize,
+    pub command_timeout_ms: u64,
+    pub write_batch: usize,
+    pub dead_letters: usize,
+    pub slow_log_threshold_us: Option<u64>,
+    pub slow_log_len: usize,
+    pub strict_allocations: bool,
+    pub transport: Transport,
+    pub data_dir: Option<PathBuf>,
+    pub snapshot_interval: u64,
+    pub wal_max_bytes: Option<u64>,
+    pub wal_fsync: FsyncPolicy,
+    pub on_persistence_failure: FailurePolicy,
+    pub encryption_key_file: Option<PathBuf>,
+    #[serde(skip)]
+    pub encryption_key_fd: Option<i32>,
+    pub upload_endpoint: Option<String>,
+    pub upload_bucket: Option<String>,
+    pub upload_region: String,
+    pub upload_prefix: String,
+    pub upload_keep: usize,
+    pub change_sink: Option<String>,
+    pub change_sink_buffer: usize,
+    pub handoff: Option<PathBuf>,
+    pub dump_dir: Option<PathBuf>,
+    pub seed: Option<PathBuf>,
+    pub delete_all_token: Option<u32>,
+    pub max_connections: Option<usize>,
+    pub connection_overflow: Overflow,
+    pub idle_timeout: Option<u64>,
+    pub frame_timeout_ms: u64,
+    pub keepalive_ms: Option<u64>,
+    pub max_frame_bytes: u32,
+    pub max_request_values: u32,
+    pub max_request_bytes: usize,
+    pub flush_delay_us: u64,
+    pub flush_bytes: usize,
+    pub on_protocol_error: ProtocolErrorPolicy,
+    pub rate_limit: Option<f64>,
+    pub rate_limit_burst: Option<f64>,
+    pub rate_limit_by: RateLimitBy,
+    pub trace: Option<PathBuf>,
+    pub capture: Option<PathBuf>,
+    pub otlp_endpoint: Option<String>,
+    pub otlp_sample_ratio: f64,
+    pub shutdown_report: Option<PathBuf>,
+    pub diagnostics_dir: Option<PathBuf>,
+    pub audit_log: Option<PathBuf>,
+    pub audit_log_max_bytes: u64,
+    pub audit_log_keep: usize,
+    pub auth_token: Option<String>,
+    pub auth_token_file: Option<PathBuf>,
+    pub credentials: Vec<Credential>,
+    pub priorities: Vec<PriorityRule>,
+    pub read_only: bool,
+    pub log_level: String,
+    pub log_format: LogFormat,
+    #[serde(skip)]
+    pub replay: Option<PathBuf>,
+    #[serde(skip)]
+    pub break_keys: Vec<u8>,
+    #[serde(skip)]
+    pub check: bool,
+    #[serde(skip)]
+    pub repair: bool,
+    pub transforms: Vec<TransformRule>,
+    pub caps: Vec<CapRule>,
+    pub rollups: Vec<RollupRule>,
+    pub allocations: Vec<Allocation>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            socket: DEFAULT_SOCKET_PATH.to_string(),
+            tcp: None,
+            listeners: Vec::new(),
+            datagram: None,
+            shm_mirror: None,
+            shm_values_per_key: 64,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            metrics: None,
+            resp: None,
+            websocket: None,
+            db_max_memory: None,
+            db_max_keys: None,
+            db_max_values: None,
+            storage_order: StorageOrder::Hash,
+            pack_values_above: None,
+            databases: 1,
+            expiry_sweep_ms: 1000,
+            self_test: false,
+            threads: 1,
+            command_timeout_ms: 0,
+            write_batch: DEFAULT_WRITE_BATCH,
+            dead_letters: 0,
+            slow_log_threshold_us: None,
+            slow_log_len: 128,
+            strict_allocations: false,
+            transport: Transport::Epoll,
+            data_dir: None,
+            snapshot_interval: 300,
+            wal_max_bytes: None,
+            wal_fsync: FsyncPolicy::Everysec,
+            on_persistence_failure: FailurePolicy::ShedWrites,
+            encryption_key_file: None,
+            encryption_key_fd: None,
+            upload_endpoint: None,
+            upload_bucket: None,
+            upload_region: "us-east-1".to_string(),
+            upload_prefix: String::new(),
+            upload_keep: 7,
+            change_sink: None,
+            change_sink_buffer: 4096,
+            handoff: None,
+            dump_dir: None,
+            seed: None,
+            delete_all_token: None,
+            max_connections: None,
+            connection_overflow: Overflow::Reject,
+            idle_timeout: None,
+            frame_timeout_ms: 5000,
+            keepalive_ms: None,
+            max_frame_bytes: 64 * 1024,
+            max_request_values: MAX_REPLACE_VALUES,
+            max_request_bytes: MAX_MESSAGE_LEN,
+            flush_delay_us: 0,
+            flush_bytes: 64 * 1024,
+            on_protocol_error: ProtocolErrorPolicy::Close,
+            rate_limit: None,
+            rate_limit_burst: None,
+            rate_limit_by: RateLimitBy::Connection,
+            trace: None,
+            capture: None,
+            otlp_endpoint: None,
+            otlp_sample_ratio: 1.0,
+            shutdown_report: None,
+            diagnostics_dir: None,
+            audit_log: None,
+            audit_log_max_bytes: 64 << 20,
+            audit_log_keep: 5,
+            auth_token: None,
+            auth_token_file: None,
+            credentials: Vec::new(),
+            priorities: Vec::new(),
+            read_only: false,
+            log_level: "info".to_string(),
+            log_format: LogFormat::Text,
+            replay: None,
+            break_keys: Vec::new(),
+            check: false,
+            repair: false,
+            transforms: Vec::new(),
+            caps: Vec::new(),
+            rollups: Vec::new(),
+            allocations: Vec::new(),
+        }
+    }
+}
+
+/// The lowest `max_request_bytes`, which still lets every fixed-size request
+/// through with a request ID in front.
+const MIN_REQUEST_BYTES: usize = 64;
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+impl Config {
+    pub fn from_args(args: Args) -> io::Result<Config> {
+        let mut config = match &args.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+
+        if let Some(socket) = args.socket {
+            config.socket = socket;
+        }
+        if args.tcp.is_some() {
+            config.tcp = args.tcp;
+        }
+        if args.datagram.is_some() {
+            config.datagram = args.datagram;
+        }
+        if args.shm_mirror.is_some() {
+            config.shm_mirror = args.shm_mirror;
+        }
+        if let Some(values) = args.shm_values_per_key {
+            config.shm_values_per_key = values;
+        }
+        if !(1..=65536).contains(&config.shm_values_per_key) {
+            return Err(invalid("shm_values_per_key must be 1 to 65536".to_string()));
+        }
+        if args.tls_cert.is_some() {
+            config.tls_cert = args.tls_cert;
+        }
+        if args.tls_key.is_some() {
+            config.tls_key = args.tls_key;
+        }
+        if args.tls_client_ca.is_some() {
+            config.tls_client_ca = args.tls_client_ca;
+        }
+        if config.tls_cert.is_some() != config.tls_key.is_some() {
+            return Err(invalid(
+                "tls_cert and tls_key must be given together".to_string(),
+            ));
+        }
+        if config.tls_client_ca.is_some() && config.tls_cert.is_none() {
+            return Err(invalid(
+                "tls_client_ca needs tls_cert and tls_key".to_string(),
+            ));
+        }
+        let tcp_listeners = config.listeners.iter().filter(|l| l.tcp.is_some());
+        if config.tls_cert.is_some() && config.tcp.is_none() && tcp_listeners.count() == 0 {
+            return Err(invalid("TLS applies to TCP listeners; set tcp".to_string()));
+        }
+        if args.metrics.is_some() {
+            config.metrics = args.metrics;
+        }
+        if args.resp.is_some() {
+            config.resp = args.resp;
+        }
+        if args.websocket.is_some() {
+            config.websocket = args.websocket;
+        }
+        if args.db_max_memory.is_some() {
+            config.db_max_memory = args.db_max_memory;
+        }
+        if args.db_max_keys.is_some() {
+            config.db_max_keys = args.db_max_keys;
+        }
+        if args.db_max_values.is_some() {
+            config.db_max_values = args.db_max_values;
+        }
+        if let Some(storage_order) = args.storage_order {
+            config.storage_order = storage_order;
+        }
+        if config.db_max_keys == Some(0) || config.db_max_values == Some(0) {
+            return Err(invalid(
+                "db_max_keys and db_max_values must be at least 1".to_string(),
+            ));
+        }
+        if args.pack_values_above.is_some() {
+            config.pack_values_above = args.pack_values_above;
+        }
+        if config.pack_values_above == Some(0) {
+            return Err(invalid("pack_values_above must be at least 1".to_string()));
+        }
+        if let Some(databases) = args.databases {
+            config.databases = databases;
+        }
+        if !(1..=256).contains(&config.databases) {
+            return Err(invalid("databases must be 1 to 256".to_string()));
+        }
+        if let Some(expiry_sweep_ms) = args.expiry_sweep_ms {
+            config.expiry_sweep_ms = expiry_sweep_ms;
+        }
+        if let Some(threads) = args.threads {
+            config.threads = threads;
+        }
+        if let Some(command_timeout_ms) = args.command_timeout_ms {
+            config.command_timeout_ms = command_timeout_ms;
+        }
+        if let Some(write_batch) = args.write_batch {
+            config.write_batch = write_batch;
+        }
+        if config.write_batch == 0 {
+            return Err(invalid("write_batch must be at least 1".to_string()));
+        }
+        if let Some(dead_letters) = args.dead_letters {
+            config.dead_letters = dead_letters;
+        }
+        if args.slow_log_threshold_us.is_some() {
+            config.slow_log_threshold_us = args.slow_log_threshold_us;
+        }
+        if let Some(slow_log_len) = args.slow_log_len {
+            config.slow_log_len = slow_log_len;
+        }
+        if let Some(transport) = args.transport {
+            config.transport = transport;
+        }
+        if args.data_dir.is_some() {
+            config.data_dir = args.data_dir;
+        }
+        if let Some(snapshot_interval) = args.snapshot_interval {
+            config.snapshot_interval = snapshot_interval;
+        }
+        if args.wal_max_bytes.is_some() {
+            config.wal_max_bytes = args.wal_max_bytes;
+        }
+        if let Some(wal_fsync) = args.wal_fsync {
+            config.wal_fsync = wal_fsync;
+        }
+        if let Some(policy) = args.on_persistence_failure {
+            config.on_persistence_failure = policy;
+        }
+        if args.encryption_key_file.is_some() {
+            config.encryption_key_file = args.encryption_key_file;
+        }
+        config.encryption_key_fd = args.encryption_key_fd;
+        if config.data_dir.is_none()
+            && (config.encryption_key_file.is_some() || config.encryption_key_fd.is_some())
+        {
+            return Err(invalid(
+                "encryption keys protect the data directory; set data_dir too".to_string(),
+            ));
+        }
+        if args.upload_endpoint.is_some() {
+            config.upload_endpoint = args.upload_endpoint;
+        }
+        if args.upload_bucket.is_some() {
+            config.upload_bucket = args.upload_bucket;
+        }
+        if let Some(region) = args.upload_region {
+            config.upload_region = region;
+        }
+        if let Some(prefix) = args.upload_prefix {
+            config.upload_prefix = prefix;
+        }
+        if let Some(keep) = args.upload_keep {
+            config.upload_keep = keep;
+        }
+        if config.upload_endpoint.is_some() {
+            if config.data_dir.is_none() {
+                return Err(invalid(
+                    "upload_endpoint uploads snapshots; set data_dir too".to_string(),
+                ));
+            }
+            if config.upload_bucket.is_none() {
+                return Err(invalid("upload_endpoint needs upload_bucket".to_string()));
+            }
+            if config.upload_keep == 0 {
+                return Err(invalid("upload_keep must be at least 1".to_string()));
+            }
+        }
+        if args.change_sink.is_some() {
+            config.change_sink = args.change_sink;
+        }
+        if let Some(buffer) = args.change_sink_buffer {
+            config.change_sink_buffer = buffer;
+        }
+        if let Some(target) = &config.change_sink {
+            Target::parse(target).map_err(invalid)?;
+        }
+        if config.change_sink_buffer == 0 {
+            return Err(invalid("change_sink_buffer must be at least 1".to_string()));
+        }
+        if args.dump_dir.is_some() {
+            config.dump_dir = args.dump_dir;
+        }
+        if args.seed.is_some() {
+            config.seed = args.seed;
+        }
+        if args.delete_all_token.is_some() {
+            config.delete_all_token = args.delete_all_token;
+        }
+        if config.delete_all_token == Some(0) {
+            return Err(invalid(
+                "delete_all_token must not be 0, which DELETE_ALL frames from before the token carry".to_string(),
+            ));
+        }
+        if args.max_connections.is_some() {
+            config.max_connections = args.max_connections;
+        }
+        if let Some(overflow) = args.connection_overflow {
+            config.connection_overflow = overflow;
+        }
+        if args.idle_timeout.is_some() {
+            config.idle_timeout = args.idle_timeout;
+        }
+        if let Some(frame_timeout_ms) = args.frame_timeout_ms {
+            config.frame_timeout_ms = frame_timeout_ms;
+        }
+        if args.keepalive_ms.is_some() {
+            config.keepalive_ms = args.keepalive_ms;
+        }
+        if let Some(max_frame_bytes) = args.max_frame_bytes {
+            config.max_frame_bytes = max_frame_bytes;
+        }
+        if config.max_frame_bytes == 0 || config.max_frame_bytes >= CHUNK_CONTINUED {
+            return Err(invalid(format!(
+                "max_frame_bytes must be 1 to {}",
+                CHUNK_CONTINUED - 1
+            )));
+        }
+        if let Some(max_request_values) = args.max_request_values {
+            config.max_request_values = max_request_values;
+        }
+        if !(1..=MAX_REPLACE_VALUES).contains(&config.max_request_values) {
+            return Err(invalid(format!(
+                "max_request_values must be 1 to {MAX_REPLACE_VALUES}"
+            )));
+        }
+        if let Some(max_request_bytes) = args.max_request_bytes {
+            config.max_request_bytes = max_request_bytes;
+        }
+        if config.max_request_bytes < MIN_REQUEST_BYTES {
+            return Err(invalid(format!(
+                "max_request_bytes must be at least {MIN_REQUEST_BYTES}"
+            )));
+        }
+        if let Some(flush_delay_us) = args.flush_delay_us {
+            config.flush_delay_us = flush_delay_us;
+        }
+        if let Some(flush_bytes) = args.flush_bytes {
+            config.flush_bytes = flush_bytes;
+        }
+        if config.flush_bytes == 0 {
+            return Err(invalid("flush_bytes must be at least 1".to_string()));
+        }
+        if let Some(policy) = args.on_protocol_error {
+            config.on_protocol_error = policy;
+        }
+        if args.rate_limit.is_some() {
+            config.rate_limit = args.rate_limit;
+        }
+        if args.rate_limit_burst.is_some() {
+            config.rate_limit_burst = args.rate_limit_burst;
+        }
+        if let Some(by) = args.rate_limit_by {
+            config.rate_limit_by = by;
+        }
+        if config
+            .rate_limit
+            .is_some_and(|rate| rate.is_nan() || rate <= 0.0)
+        {
+            return Err(invalid(
+                "rate_limit must be a positive number of requests per second".to_string(),
+            ));
+        }
+        if args.trace.is_some() {
+            config.trace = args.trace;
+        }
+        if args.capture.is_some() {
+            config.capture = args.capture;
+        }
+        if args.otlp_endpoint.is_some() {
+            config.otlp_endpoint = args.otlp_endpoint;
+        }
+        if let Some(ratio) = args.otlp_sample_ratio {
+            config.otlp_sample_ratio = ratio;
+        }
+        if !(0.0..=1.0).contains(&config.otlp_sample_ratio) {
+            return Err(invalid("otlp_sample_ratio must be from 0 to 1".to_string()));
+        }
+        if args.shutdown_report.is_some() {
+            config.shutdown_report = args.shutdown_report;
+        }
+        if args.diagnostics_dir.is_some() {
+            config.diagnostics_dir = args.diagnostics_dir;
+        }
+        if args.audit_log.is_some() {
+            config.audit_log = args.audit_log;
+        }
+        if let Some(max_bytes) = args.audit_log_max_bytes {
+            config.audit_log_max_bytes = max_bytes;
+        }
+        if let Some(keep) = args.audit_log_keep {
+            config.audit_log_keep = keep;
+        }
+        if args.auth_token_file.is_some() {
+            config.auth_token_file = args.auth_token_file;
+        }
+        if let Some(path) = &config.auth_token_file {
+            let token = std::fs::read_to_string(path)
+                .map_err(|e| invalid(format!("{}: {e}", path.display())))?;
+            config.auth_token = Some(token.trim().to_string());
+        }
+        let tokens = config.auth_token.iter().chain(
+            config
+                .credentials
+                .iter()
+                .map(|credential| &credential.token),
+        );
+        for token in tokens {
+            if token.is_empty() || token.len() > MAX_ADMIN_PAYLOAD {
+                return Err(invalid(format!(
+                    "auth token must be 1 to {MAX_ADMIN_PAYLOAD} bytes"
+                )));
+            }
+        }
+        if config.datagram.is_some()
+            && (config.auth_token.is_some() || !config.credentials.is_empty())
+        {
+            return Err(invalid(
+                "the datagram socket cannot check AUTH tokens; drop datagram or the tokens"
+                    .to_string(),
+            ));
+        }
+        if let Some(level) = args.log_level {
+            config.log_level = level;
+        }
+        if let Some(format) = args.log_format {
+            config.log_format = format;
+        }
+        logging::filter(&config.log_level)?;
+        config.replay = args.replay;
+        config.break_keys = args.break_keys;
+        config.check = args.check;
+        config.repair = args.repair;
+        if (config.check || config.repair) && config.data_dir.is_none() {
+            return Err(invalid(
+                "--check and --repair read the data directory; set data_dir too".to_string(),
+            ));
+        }
+        if config.repair && config.encryption_key_fd.is_some() {
+            return Err(invalid(
+                "--repair reads the encryption keys before the server does; pass them with --encryption-key-file or the environment".to_string(),
+            ));
+        }
+        config.self_test |= args.self_test;
+        config.strict_allocations |= args.strict_allocations;
+        config.read_only |= args.read_only;
+        config.threads = config.threads.max(1);
+        if config.transport == Transport::Uring && config.threads > 1 {
+            return Err(invalid(
+                "the uring transport runs on a single thread; drop --threads".to_string(),
+            ));
+        }
+        if args.handoff.is_some() {
+            config.handoff = args.handoff;
+        }
+        if config.handoff.is_some() {
+            let unhanded = [
+                ("datagram", config.datagram.is_some()),
+                ("resp", config.resp.is_some()),
+                ("websocket", config.websocket.is_some()),
+                ("metrics", config.metrics.is_some()),
+                (
+                    "transport = \"uring\"",
+                    config.transport == Transport::Uring,
+                ),
+            ];
+            if let Some((name, _)) = unhanded.iter().find(|(_, set)| *set) {
+                return Err(invalid(format!(
+                    "handoff passes on Unix sockets and TCP listeners only; drop {name}"
+                )));
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Where the data directory's encryption keys are read from.
+    pub fn key_source(&self) -> KeySource {
+        match (&self.encryption_key_file, self.encryption_key_fd) {
+            (_, Some(fd)) => KeySource::Fd(fd),
+            (Some(path), None) => KeySource::File(path.clone()),
+            (None, None) => KeySource::Env,
+        }
+    }
+
+    pub fn load(path: &PathBuf) -> io::Result<Config> {
+        let text = std::fs::read_to_string(path)?;
+        let config: Config = toml::from_str(&text)
+            .map_err(|e| invalid(format!("{}: {}", path.display(), e.message())))?;
+        for rule in &config.transforms {
+            rule.validate()
+                .map_err(|e| invalid(format!("{}: {e}", path.display())))?;
+        }
+        for rule in &config.caps {
+            rule.validate()
+                .map_err(|e| invalid(format!("{}: {e}", path.display())))?;
+        }
+        for rule in &config.rollups {
+            rule.validate()
+                .map_err(|e| invalid(format!("{}: {e}", path.display())))?;
+        }
+        for listener in &config.listeners {
+            listener
+                .validate()
+                .map_err(|e| invalid(format!("{}: {e}", path.display())))?;
+        }
+        Ok(config)
+    }
+}
@@ -0,0 +1,66 @@
+use crate::admission::Admission;
+use crate::dispatch::Dispatcher;
+use crate::{Command, SetMode};
+use map8x32_protocol::{Frame, FRAME_LEN, OP_SET, STATUS_READONLY};
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::net::UnixDatagram;
+use tokio::sync::oneshot;
+
+/// Longest datagram read; longer ones are cut here by the kernel, and the
+/// frames past the cut are lost.
+const MAX_DATAGRAM: usize = 64 * 1024;
+
+/// Applies the SET frames in each datagram, back to back, to database 0
+/// without answering. A datagram that is not a whole number of frames, or
+/// carries any other opcode, has those frames dropped and counted; the
+/// rest still apply. Under `--read-only` every frame is dropped.
+pub async fn serve(
+    socket: UnixDatagram,
+    sender: Dispatcher,
+    admission: Arc<Admission>,
+) -> io::Result<()> {
+    let mut buf = vec![0; MAX_DATAGRAM];
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        let (mut applied, mut dropped) = (0, 0);
+        for chunk in buf[..len].chunks(FRAME_LEN) {
+            let frame = match <&[u8; FRAME_LEN]>::try_from(chunk) {
+                Ok(bytes) => Frame::decode(bytes),
+                Err(_) => {
+                    dropped += 1;
+                    continue;
+                }
+            };
+            if frame.op != OP_SET {
+                dropped += 1;
+                continue;
+            }
+            if admission.read_only() {
+                let dead_letters = &sender.storage().dead_letters;
+                dead_letters.record(OP_SET, frame.key, frame.value, STATUS_READONLY);
+                dropped += 1;
+                continue;
+            }
+            // Nobody waits for the ack; the engine ignores the closed channel.
+            let (respond_to, _) = oneshot::channel();
+            let set = Command::Set {
+                key: frame.key,
+                value: frame.value,
+                mode: SetMode::Append,
+                respond_to,
+            };
+            if sender.send(set).is_err() {
+                return Ok(());
+            }
+            applied += 1;
+        }
+        admission
+            .datagram_sets
+            .fetch_add(applied, Ordering::Relaxed);
+        admission
+            .datagram_dropped
+            .fetch_add(dropped, Ordering::Relaxed);
+    }
+}
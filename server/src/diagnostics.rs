@@ -0,0 +1,142 @@
+use crate::admission::Admission;
+use crate::db::Database;
+use std::fmt::Write;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info};
+
+/// How many of each database's keys the report lists, largest first.
+const LARGEST_KEYS: usize = 10;
+
+fn secs(duration: Duration) -> String {
+    format!("{:.1}s", duration.as_secs_f64())
+}
+
+/// A human-readable account of the server as it is now: tasks on the
+/// runtime, every connection and what it is waiting on, and each
+/// database's size, queued commands and largest keys.
+pub fn report(databases: &[Arc<Database>], admission: &Admission, uptime: Duration) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "map8x32 diagnostics, pid {}, up {}",
+        std::process::id(),
+        secs(uptime)
+    );
+
+    let metrics = tokio::runtime::Handle::current().metrics();
+    let _ = writeln!(out, "\nruntime:");
+    let _ = writeln!(out, "  workers: {}", metrics.num_workers());
+    let _ = writeln!(out, "  alive tasks: {}", metrics.num_alive_tasks());
+    let _ = writeln!(
+        out,
+        "  global queue depth: {}",
+        metrics.global_queue_depth()
+    );
+
+    let connections = admission.connections();
+    let _ = writeln!(
+        out,
+        "\nconnections: {} active, {} peak",
+        connections.len(),
+        admission.peak.load(Ordering::Relaxed)
+    );
+    for (id, activity) in &connections {
+        let age = activity.admitted_at.elapsed();
+        let requests = activity.requests.load(Ordering::Relaxed);
+        let idle = match requests {
+            0 => "no requests yet".to_string(),
+            _ => {
+                let last = Duration::from_millis(activity.last_request_ms.load(Ordering::Relaxed));
+                format!("last request {} ago", secs(age.saturating_sub(last)))
+            }
+        };
+        let _ = writeln!(
+            out,
+            "  #{id} {}: up {}, {requests} requests, {idle}, {} in flight",
+            activity.peer,
+            secs(age),
+            activity.in_flight.load(Ordering::Relaxed)
+        );
+    }
+
+    for database in databases {
+        let stats = &database.stats;
+        let _ = writeln!(out, "\ndatabase {}:", database.id);
+        let _ = writeln!(
+            out,
+            "  keys: {}, values: {}, memory: {} bytes",
+            stats.keys.load(Ordering::Relaxed),
+            stats.values.load(Ordering::Relaxed),
+            stats.memory_bytes()
+        );
+        let _ = writeln!(
+            out,
+            "  queued commands: {}",
+            stats.queued.load(Ordering::Relaxed)
+        );
+        if let Some(persistence) = &database.persistence {
+            let (generation, offset) = persistence.wal_position();
+            let _ = writeln!(
+                out,
+                "  persistence: {}, WAL at segment {generation} offset {offset}, {} snapshots",
+                persistence.health().name(),
+                persistence.snapshots.load(Ordering::Relaxed)
+            );
+        }
+        // Sizes are read once the map is let go, since measuring a key locks
+        // its shard again.
+        let lens: Vec<(u8, usize)> = database.map.collect(|key, values| (key, values.len()));
+        let mut keys: Vec<(u8, usize, u64)> = lens
+            .into_iter()
+            .filter_map(|(key, values)| Some((key, values, database.memory_usage(key)?)))
+            .collect();
+        keys.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(&b.0)));
+        if !keys.is_empty() {
+            let _ = writeln!(out, "  largest keys:");
+        }
+        for (key, values, bytes) in keys.into_iter().take(LARGEST_KEYS) {
+            let _ = writeln!(out, "    {key}: {values} values, {bytes} bytes");
+        }
+    }
+    out
+}
+
+/// Writes the report to a file of its own in `dir`, named for when it was
+/// written, returning its path.
+fn write_report(dir: &Path, report: &str) -> io::Result<PathBuf> {
+    let written = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let path = dir.join(format!("map8x32-diagnostics-{written}.txt"));
+    std::fs::write(&path, report)?;
+    Ok(path)
+}
+
+/// Writes a diagnostic report into `dir` on every SIGUSR1.
+pub async fn dump_on_signal(
+    admission: Arc<Admission>,
+    databases: Vec<Arc<Database>>,
+    started: Instant,
+    dir: PathBuf,
+) {
+    let mut user1 = match signal(SignalKind::user_defined1()) {
+        Ok(user1) => user1,
+        Err(e) => {
+            error!(error = %e, "cannot listen for SIGUSR1; diagnostic dumps are disabled");
+            return;
+        }
+    };
+    while user1.recv().await.is_some() {
+        let report = report(&databases, &admission, started.elapsed());
+        match write_report(&dir, &report) {
+            Ok(path) => info!(path = %path.display(), "wrote diagnostic report"),
+            Err(e) => error!(error = %e, dir = %dir.display(), "cannot write diagnostic report"),
+        }
+    }
+}
@@ -0,0 +1,376 @@
+//! Hands a running server's listeners and data to a newer one started with
+//! the same `--handoff` path, so a binary upgrade refuses no connections.
+//!
+//! The running server listens on the handoff socket. A new server connects
+//! and sends its database count (u16). If it matches, the running server
+//! stops accepting, closes its connections as on shutdown and syncs its
+//! logs, then sends the number of listeners (u32) with their descriptors
+//! attached as `SCM_RIGHTS`, each listener's name (u16 length, then
+//! `unix:<path>` or `tcp:<addr>`), and each database as a DUMP (u64 length,
+//! then the dump), and exits. Connections that arrive meanwhile wait in the
+//! listeners' backlogs for the new server to accept them. Integers are
+//! little-endian.
+
+use map8x32_core::dump::Dump;
+use map8x32_core::StorageType;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsFd, OwnedFd};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, UnixListener, UnixStream};
+use tracing::{info, warn};
+
+/// Listeners a handoff passes on at most, as one `SCM_RIGHTS` message may
+/// carry on Linux.
+#[cfg(feature = "handoff")]
+const MAX_LISTENERS: usize = 253;
+
+/// How long a new server has to say how many databases it has.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What a new server took over: the old one's listeners by name, and its
+/// databases' contents, both empty when there was nothing to take over.
+#[derive(Debug, Default)]
+pub struct Inherited {
+    listeners: HashMap<String, OwnedFd>,
+    pub dumps: Vec<Dump>,
+}
+
+impl Inherited {
+    /// The inherited listener on the Unix socket at `path`, if there is one.
+    pub fn unix(&mut self, path: &str) -> io::Result<Option<UnixListener>> {
+        let Some(fd) = self.listeners.remove(&format!("unix:{path}")) else {
+            return Ok(None);
+        };
+        let listener = std::os::unix::net::UnixListener::from(fd);
+        listener.set_nonblocking(true)?;
+        UnixListener::from_std(listener).map(Some)
+    }
+
+    /// The inherited listener on the TCP address `addr`, if there is one.
+    pub fn tcp(&mut self, addr: &str) -> io::Result<Option<TcpListener>> {
+        let Some(fd) = self.listeners.remove(&format!("tcp:{addr}")) else {
+            return Ok(None);
+        };
+        let listener = std::net::TcpListener::from(fd);
+        listener.set_nonblocking(true)?;
+        TcpListener::from_std(listener).map(Some)
+    }
+}
+
+/// The listeners a server would hand over, each by a descriptor of its own.
+#[derive(Debug, Default)]
+pub struct Listening(Vec<(String, OwnedFd)>);
+
+impl Listening {
+    pub fn unix(&mut self, path: &str, listener: &UnixListener) -> io::Result<()> {
+        let fd = listener.as_fd().try_clone_to_owned()?;
+        self.0.push((format!("unix:{path}"), fd));
+        Ok(())
+    }
+
+    pub fn tcp(&mut self, addr: &str, listener: &TcpListener) -> io::Result<()> {
+        let fd = listener.as_fd().try_clone_to_owned()?;
+        self.0.push((format!("tcp:{addr}"), fd));
+        Ok(())
+    }
+}
+
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "this build cannot hand over listeners; rebuild with --features handoff",
+    )
+}
+
+/// Takes over from the server listening at `path`, once it has handed over
+/// everything and gone, or returns `None` if no server listens there.
+pub async fn take_over(path: &Path, databases: usize) -> io::Result<Option<Inherited>> {
+    if !cfg!(feature = "handoff") {
+        return Err(unsupported());
+    }
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || receive(&path, databases))
+        .await
+        .map_err(io::Error::other)?
+}
+
+fn receive(path: &Path, databases: usize) -> io::Result<Option<Inherited>> {
+    let mut stream = match std::os::unix::net::UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+            ) =>
+        {
+            return Ok(None)
+        }
+        Err(e) => return Err(e),
+    };
+    info!(handoff = %path.display(), "taking over from the running server");
+    stream.write_all(&(databases as u16).to_le_bytes())?;
+    let mut count = [0u8; 4];
+    let (read, fds) = fds::receive(&stream, &mut count)?;
+    if read == 0 {
+        return Err(io::Error::other(format!(
+            "the server at {} refused the handoff; see its log",
+            path.display()
+        )));
+    }
+    stream.read_exact(&mut count[read..])?;
+    let count = u32::from_le_bytes(count) as usize;
+    if fds.len() != count {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "the handoff named {count} listeners but passed {}",
+                fds.len()
+            ),
+        ));
+    }
+    let mut listeners = HashMap::new();
+    for fd in fds {
+        let mut len = [0u8; 2];
+        stream.read_exact(&mut len)?;
+        let mut name = vec![0; usize::from(u16::from_le_bytes(len))];
+        stream.read_exact(&mut name)?;
+        listeners.insert(String::from_utf8_lossy(&name).into_owned(), fd);
+    }
+    let mut dumps = Vec::with_capacity(databases);
+    for _ in 0..databases {
+        let mut len = [0u8; 8];
+        stream.read_exact(&mut len)?;
+        let mut dump = vec![0; u64::from_le_bytes(len) as usize];
+        stream.read_exact(&mut dump)?;
+        dumps.push(Dump::decode(&dump)?);
+    }
+    // The old server closes the socket as it exits, after its last write.
+    stream.read_to_end(&mut Vec::new())?;
+    Ok(Some(Inherited { listeners, dumps }))
+}
+
+/// Listens at `path` for the next server, which only processes of this
+/// server's user may connect to.
+pub async fn listen(path: &Path) -> io::Result<UnixListener> {
+    if !cfg!(feature = "handoff") {
+        return Err(unsupported());
+    }
+    if tokio::fs::try_exists(path).await.unwrap_or(false) {
+        tokio::fs::remove_file(path).await?;
+    }
+    let listener = UnixListener::bind(path)?;
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).await?;
+    Ok(listener)
+}
+
+/// Waits for a new server with `databases` databases to connect to
+/// `listener`, turning away any other, or forever without a listener.
+pub async fn successor(listener: Option<&UnixListener>, databases: usize) -> UnixStream {
+    let Some(listener) = listener else {
+        return std::future::pending().await;
+    };
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!(error = %e, "cannot accept a handoff");
+                continue;
+            }
+        };
+        let uid = stream.peer_cred().map(|cred| cred.uid()).ok();
+        if uid != Some(fds::uid()) {
+            warn!(uid, "refused a handoff to another user's process");
+            continue;
+        }
+        let mut theirs = [0u8; 2];
+        let read = tokio::time::timeout(HELLO_TIMEOUT, stream.read_exact(&mut theirs)).await;
+        if !matches!(read, Ok(Ok(_))) {
+            continue;
+        }
+        let theirs = u16::from_le_bytes(theirs);
+        if usize::from(theirs) != databases {
+            warn!(
+                theirs,
+                ours = databases,
+                "refused a handoff to a server with a different number of databases"
+            );
+            continue;
+        }
+        return stream;
+    }
+}
+
+/// Sends `listening` and every database to the new server on `successor`.
+/// Call once connections are closed and the logs synced.
+pub async fn hand_over(
+    successor: UnixStream,
+    listening: &Listening,
+    databases: &[StorageType],
+) -> io::Result<()> {
+    let dumps: Vec<Vec<u8>> = databases
+        .iter()
+        .map(|database| Dump::capture(database).encode())
+        .collect();
+    let mut stream = successor.into_std()?;
+    stream.set_nonblocking(false)?;
+    let listeners = &listening.0;
+    let descriptors: Vec<_> = listeners.iter().map(|(_, fd)| fd.as_fd()).collect();
+    fds::send(
+        &stream,
+        &(listeners.len() as u32).to_le_bytes(),
+        &descriptors,
+    )?;
+    let mut names = Vec::new();
+    for (name, _) in listeners {
+        names.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        names.extend_from_slice(name.as_bytes());
+    }
+    stream.write_all(&names)?;
+    for dump in &dumps {
+        stream.write_all(&(dump.len() as u64).to_le_bytes())?;
+        stream.write_all(dump)?;
+    }
+    stream.flush()?;
+    info!(listeners = listeners.len(), "handed over to the new server");
+    Ok(())
+}
+
+#[cfg(feature = "handoff")]
+mod fds {
+    use super::MAX_LISTENERS;
+    use std::ffi::c_void;
+    use std::io::{self, Write};
+    use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+    use std::os::unix::net::UnixStream;
+
+    pub fn uid() -> u32 {
+        // SAFETY: getuid cannot fail.
+        unsafe { libc::getuid() }
+    }
+
+    /// Room for a control message carrying `fds` descriptors, in u64s so it
+    /// is aligned for the header.
+    fn control(fds: usize) -> Vec<u64> {
+        // SAFETY: CMSG_SPACE only computes a size.
+        let bytes = unsafe { libc::CMSG_SPACE((fds * size_of::<RawFd>()) as u32) } as usize;
+        vec![0; bytes.div_ceil(8)]
+    }
+
+    /// Writes `bytes` with `fds` attached to the first of them.
+    pub fn send(stream: &UnixStream, bytes: &[u8], fds: &[BorrowedFd<'_>]) -> io::Result<()> {
+        if fds.len() > MAX_LISTENERS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("a handoff passes on {MAX_LISTENERS} listeners at most"),
+            ));
+        }
+        let raw: Vec<RawFd> = fds.iter().map(|fd| fd.as_raw_fd()).collect();
+        let mut control = control(raw.len());
+        let mut iov = libc::iovec {
+            iov_base: bytes.as_ptr() as *mut c_void,
+            iov_len: bytes.len(),
+        };
+        // SAFETY: an all-zero msghdr is a valid empty one.
+        let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        if !raw.is_empty() {
+            message.msg_control = control.as_mut_ptr() as *mut c_void;
+            message.msg_controllen = (control.len() * 8) as _;
+            // SAFETY: the control buffer was sized for one header carrying
+            // `raw`, so the first header and its data fit within it.
+            unsafe {
+                let header = libc::CMSG_FIRSTHDR(&message);
+                (*header).cmsg_level = libc::SOL_SOCKET;
+                (*header).cmsg_type = libc::SCM_RIGHTS;
+                (*header).cmsg_len = libc::CMSG_LEN(size_of_val(&raw[..]) as u32) as _;
+                std::ptr::copy_nonoverlapping(
+                    raw.as_ptr() as *const u8,
+                    libc::CMSG_DATA(header),
+                    size_of_val(&raw[..]),
+                );
+            }
+        }
+        // SAFETY: `message` points at `iov` and `control`, which outlive the
+        // call.
+        let sent = unsafe { libc::sendmsg(stream.as_raw_fd(), &message, 0) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        (&*stream).write_all(&bytes[sent as usize..])
+    }
+
+    /// Reads into `bytes`, returning how many were read and the descriptors
+    /// that came with them.
+    pub fn receive(stream: &UnixStream, bytes: &mut [u8]) -> io::Result<(usize, Vec<OwnedFd>)> {
+        let mut control = control(MAX_LISTENERS);
+        let mut iov = libc::iovec {
+            iov_base: bytes.as_mut_ptr() as *mut c_void,
+            iov_len: bytes.len(),
+        };
+        // SAFETY: an all-zero msghdr is a valid empty one.
+        let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr() as *mut c_void;
+        message.msg_controllen = (control.len() * 8) as _;
+        // SAFETY: `message` points at `iov` and `control`, which outlive the
+        // call.
+        let read = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut message, 0) };
+        if read < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut fds = Vec::new();
+        // SAFETY: the headers are walked with the kernel's own macros over
+        // the buffer it filled, and each descriptor taken is a new one this
+        // process now owns.
+        unsafe {
+            let mut header = libc::CMSG_FIRSTHDR(&message);
+            while !header.is_null() {
+                if (*header).cmsg_level == libc::SOL_SOCKET
+                    && (*header).cmsg_type == libc::SCM_RIGHTS
+                {
+                    let len = (*header).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                    let data = libc::CMSG_DATA(header) as *const RawFd;
+                    for i in 0..len / size_of::<RawFd>() {
+                        let fd = data.add(i).read_unaligned();
+                        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                        fds.push(OwnedFd::from_raw_fd(fd));
+                    }
+                }
+                header = libc::CMSG_NXTHDR(&message, header);
+            }
+        }
+        if message.msg_flags & libc::MSG_CTRUNC != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the handoff passed more listeners than fit",
+            ));
+        }
+        Ok((read as usize, fds))
+    }
+}
+
+#[cfg(not(feature = "handoff"))]
+mod fds {
+    use super::unsupported;
+    use std::io;
+    use std::os::fd::{BorrowedFd, OwnedFd};
+    use std::os::unix::net::UnixStream;
+
+    pub fn uid() -> u32 {
+        u32::MAX
+    }
+
+    pub fn send(_: &UnixStream, _: &[u8], _: &[BorrowedFd<'_>]) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn receive(_: &UnixStream, _: &mut [u8]) -> io::Result<(usize, Vec<OwnedFd>)> {
+        Err(unsupported())
+    }
+}
@@ -0,0 +1,576 @@
+use crate::admission::{Admission, CloseReason};
+use crate::audit::escape;
+use crate::db::Database;
+use crate::persistence::{Health, Persistence};
+use std::fmt::Write;
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+#[cfg(feature = "dashboard")]
+const DASHBOARD: &str = include_str!("../assets/dashboard.html");
+
+type Gauge = (&'static str, &'static str, fn(&Database) -> u64);
+
+pub fn render_metrics(databases: &[&Database], admission: &Admission) -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(
+        out,
+        "# HELP map8x32_connections Client connections currently served.\n# TYPE map8x32_connections gauge\nmap8x32_connections {}",
+        admission.active()
+    );
+    if let Some(max) = admission.max_connections() {
+        let _ = writeln!(
+            out,
+            "# HELP map8x32_connections_max Configured connection limit.\n# TYPE map8x32_connections_max gauge\nmap8x32_connections_max {max}"
+        );
+    }
+    if let Some((ops_per_sec, burst)) = admission.rate_limit() {
+        let _ = writeln!(
+            out,
+            "# HELP map8x32_rate_limit Requests per second each connection or uid is held to.\n# TYPE map8x32_rate_limit gauge\nmap8x32_rate_limit {ops_per_sec}\n# HELP map8x32_rate_limit_burst Requests allowed at once above the rate limit.\n# TYPE map8x32_rate_limit_burst gauge\nmap8x32_rate_limit_burst {burst}"
+        );
+    }
+    let _ = writeln!(
+        out,
+        "# HELP map8x32_connections_rejected_total Connections turned away at the limit.\n# TYPE map8x32_connections_rejected_total counter\nmap8x32_connections_rejected_total {}",
+        admission.rejected.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "# HELP map8x32_connections_idle_reaped_total Connections closed by the idle timeout.\n# TYPE map8x32_connections_idle_reaped_total counter\nmap8x32_connections_idle_reaped_total {}",
+        admission.idle_reaped.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "# HELP map8x32_connections_frame_timeouts_total Connections closed after stalling mid-request.\n# TYPE map8x32_connections_frame_timeouts_total counter\nmap8x32_connections_frame_timeouts_total {}",
+        admission.frame_timeouts.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "# HELP map8x32_connections_keepalive_timeouts_total Connections closed for not answering a keepalive probe.\n# TYPE map8x32_connections_keepalive_timeouts_total counter\nmap8x32_connections_keepalive_timeouts_total {}",
+        admission.keepalive_timeouts.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "# HELP map8x32_connections_protocol_errors_total Connections closed after a request that could not be decoded or had an unknown opcode.\n# TYPE map8x32_connections_protocol_errors_total counter\nmap8x32_connections_protocol_errors_total {}",
+        admission.protocol_errors.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "# HELP map8x32_connections_closed_total Connections closed, by why they ended.\n# TYPE map8x32_connections_closed_total counter"
+    );
+    for reason in CloseReason::ALL {
+        let _ = writeln!(
+            out,
+            "map8x32_connections_closed_total{{reason=\"{}\"}} {}",
+            reason.name(),
+            admission.closed(reason)
+        );
+    }
+    let _ = writeln!(
+        out,
+        "# HELP map8x32_throttled_total Requests refused by the rate limit.\n# TYPE map8x32_throttled_total counter\nmap8x32_throttled_total {}",
+        admission.throttled.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "# HELP map8x32_payload_too_large_total Requests refused for exceeding the request limits.\n# TYPE map8x32_payload_too_large_total counter\nmap8x32_payload_too_large_total {}",
+        admission.payload_rejections.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "# HELP map8x32_auth_failures_total AUTH requests with a wrong token.\n# TYPE map8x32_auth_failures_total counter\nmap8x32_auth_failures_total {}",
+        admission.auth_failures.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "# HELP map8x32_tls_handshake_failures_total TCP connections dropped during the TLS handshake.\n# TYPE map8x32_tls_handshake_failures_total counter\nmap8x32_tls_handshake_failures_total {}",
+        admission.tls_handshake_failures.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "# HELP map8x32_datagram_sets_total SET frames applied from the datagram socket.\n# TYPE map8x32_datagram_sets_total counter\nmap8x32_datagram_sets_total {}\n# HELP map8x32_datagram_dropped_total Datagram frames dropped for a wrong opcode, a partial frame or read-only mode.\n# TYPE map8x32_datagram_dropped_total counter\nmap8x32_datagram_dropped_total {}",
+        admission.datagram_sets.load(Ordering::Relaxed),
+        admission.datagram_dropped.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "# HELP map8x32_slow_commands_total Commands that took at least the slow-log threshold.\n# TYPE map8x32_slow_commands_total counter\nmap8x32_slow_commands_total {}",
+        admission.slow_log.recorded.load(Ordering::Relaxed)
+    );
+    if let Some(audit) = &admission.audit {
+        let _ = writeln!(
+            out,
+            "# HELP map8x32_audit_failures_total Write requests the audit log failed to record.\n# TYPE map8x32_audit_failures_total counter\nmap8x32_audit_failures_total {}",
+            audit.failures.load(Ordering::Relaxed)
+        );
+    }
+    if let Some(tracer) = &admission.tracer {
+        let _ = writeln!(
+            out,
+            "# HELP map8x32_otlp_dropped_spans_total Request spans dropped because the exporter fell behind.\n# TYPE map8x32_otlp_dropped_spans_total counter\nmap8x32_otlp_dropped_spans_total {}",
+            tracer.dropped.load(Ordering::Relaxed)
+        );
+    }
+    let _ = writeln!(
+        out,
+        "# HELP map8x32_read_only Whether the instance refuses all writes.\n# TYPE map8x32_read_only gauge\nmap8x32_read_only {}",
+        u8::from(admission.read_only())
+    );
+
+    let gauges: [Gauge; 4] = [
+        ("map8x32_keys", "Number of keys stored.", |db| {
+            db.stats.keys.load(Ordering::Relaxed)
+        }),
+        (
+            "map8x32_values",
+            "Number of values stored across all keys.",
+            |db| db.stats.values.load(Ordering::Relaxed),
+        ),
+        (
+            "map8x32_memory_bytes",
+            "Approximate bytes used by keys and values.",
+            |db| db.stats.memory_bytes(),
+        ),
+        (
+            "map8x32_packed_savings_bytes",
+            "Bytes packed keys take less than they would unpacked.",
+            |db| db.stats.packed_savings.load(Ordering::Relaxed),
+        ),
+    ];
+    for (name, help, read) in gauges {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} gauge");
+        for db in databases {
+            let _ = writeln!(out, "{name}{{db=\"{}\"}} {}", db.id, read(db));
+        }
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP map8x32_memory_quota_bytes Configured memory quota.\n# TYPE map8x32_memory_quota_bytes gauge"
+    );
+    for db in databases {
+        if let Some(limit) = db.max_memory {
+            let _ = writeln!(
+                out,
+                "map8x32_memory_quota_bytes{{db=\"{}\"}} {limit}",
+                db.id
+            );
+        }
+    }
+    let _ = writeln!(
+        out,
+        "# HELP map8x32_max_keys Configured limit on a database's keys.\n# TYPE map8x32_max_keys gauge"
+    );
+    for db in databases {
+        if let Some(limit) = db.max_keys {
+            let _ = writeln!(out, "map8x32_max_keys{{db=\"{}\"}} {limit}", db.id);
+        }
+    }
+    let _ = writeln!(
+        out,
+        "# HELP map8x32_max_values Configured limit on a database's values.\n# TYPE map8x32_max_values gauge"
+    );
+    for db in databases {
+        if let Some(limit) = db.max_values {
+            let _ = writeln!(out, "map8x32_max_values{{db=\"{}\"}} {limit}", db.id);
+        }
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP map8x32_ops_total Commands executed, by operation.\n# TYPE map8x32_ops_total counter"
+    );
+    for db in databases {
+        for (op, counter) in db.stats.ops() {
+            let _ = writeln!(
+                out,
+                "map8x32_ops_total{{db=\"{}\",op=\"{op}\"}} {}",
+                db.id,
+                counter.load(Ordering::Relaxed)
+            );
+        }
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP map8x32_get_hits_total GET and GET_VERSIONED requests that found the key.\n# TYPE map8x32_get_hits_total counter"
+    );
+    for db in databases {
+        let _ = writeln!(
+            out,
+            "map8x32_get_hits_total{{db=\"{}\"}} {}",
+            db.id,
+            db.stats.get_hits.load(Ordering::Relaxed)
+        );
+    }
+    let _ = writeln!(
+        out,
+        "# HELP map8x32_get_misses_total GET and GET_VERSIONED requests for a missing key.\n# TYPE map8x32_get_misses_total counter"
+    );
+    for db in databases {
+        let _ = writeln!(
+            out,
+            "map8x32_get_misses_total{{db=\"{}\"}} {}",
+            db.id,
+            db.stats.get_misses.load(Ordering::Relaxed)
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP map8x32_quota_rejections_total Writes refused by the memory quota or the key and value limits.\n# TYPE map8x32_quota_rejections_total counter"
+    );
+    for db in databases {
+        let _ = writeln!(
+            out,
+            "map8x32_quota_rejections_total{{db=\"{}\"}} {}",
+            db.id,
+            db.stats.quota_rejections.load(Ordering::Relaxed)
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP map8x32_trimmed_values_total Values dropped from capped keys to make room for newer ones.\n# TYPE map8x32_trimmed_values_total counter"
+    );
+    for db in databases {
+        let _ = writeln!(
+            out,
+            "map8x32_trimmed_values_total{{db=\"{}\"}} {}",
+            db.id,
+            db.stats.trimmed_values.load(Ordering::Relaxed)
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP map8x32_rolled_up_values_total Timed values folded into summaries by the rollup.\n# TYPE map8x32_rolled_up_values_total counter"
+    );
+    for db in databases {
+        let _ = writeln!(
+            out,
+            "map8x32_rolled_up_values_total{{db=\"{}\"}} {}",
+            db.id,
+            db.stats.rolled_up_values.load(Ordering::Relaxed)
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP map8x32_processor_panics_total Commands whose command processor panicked running them.\n# TYPE map8x32_processor_panics_total counter"
+    );
+    for db in databases {
+        let _ = writeln!(
+            out,
+            "map8x32_processor_panics_total{{db=\"{}\"}} {}",
+            db.id,
+            db.stats.processor_panics.load(Ordering::Relaxed)
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP map8x32_command_timeouts_total Reads answered TIMEOUT for running past the command timeout.\n# TYPE map8x32_command_timeouts_total counter"
+    );
+    for db in databases {
+        let _ = writeln!(
+            out,
+            "map8x32_command_timeouts_total{{db=\"{}\"}} {}",
+            db.id,
+            db.stats.command_timeouts.load(Ordering::Relaxed)
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP map8x32_batched_sets_total SETs applied in a batch with others queued for the same key.\n# TYPE map8x32_batched_sets_total counter"
+    );
+    for db in databases {
+        let _ = writeln!(
+            out,
+            "map8x32_batched_sets_total{{db=\"{}\"}} {}",
+            db.id,
+            db.stats.batched_sets.load(Ordering::Relaxed)
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP map8x32_expired_values_total Values purged by the expiry sweep.\n# TYPE map8x32_expired_values_total counter"
+    );
+    for db in databases {
+        let _ = writeln!(
+            out,
+            "map8x32_expired_values_total{{db=\"{}\"}} {}",
+            db.id,
+            db.stats.expired_values.load(Ordering::Relaxed)
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP map8x32_key_allocation Registered key-range owners.\n# TYPE map8x32_key_allocation gauge"
+    );
+    for db in databases {
+        for allocation in db.registry.list() {
+            let _ = writeln!(
+                out,
+                "map8x32_key_allocation{{db=\"{}\",name=\"{}\",first_key=\"{}\",last_key=\"{}\"}} 1",
+                db.id,
+                escape_label(&allocation.name),
+                allocation.first_key,
+                allocation.last_key
+            );
+        }
+    }
+
+    let persisted: Vec<(u8, &Persistence)> = databases
+        .iter()
+        .filter_map(|db| db.persistence.as_ref().map(|p| (db.id, p)))
+        .collect();
+    if !persisted.is_empty() {
+        let _ = writeln!(
+            out,
+            "# HELP map8x32_persistence_state Current persistence health; 1 for the active state.\n# TYPE map8x32_persistence_state gauge"
+        );
+        for (id, persistence) in &persisted {
+            let current = persistence.health();
+            for state in Health::ALL {
+                let _ = writeln!(
+                    out,
+                    "map8x32_persistence_state{{db=\"{id}\",state=\"{}\"}} {}",
+                    state.name(),
+                    u8::from(state == current)
+                );
+            }
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP map8x32_persistence_failures_total Failed WAL appends, fsyncs and snapshots.\n# TYPE map8x32_persistence_failures_total counter"
+        );
+        for (id, persistence) in &persisted {
+            let _ = writeln!(
+                out,
+                "map8x32_persistence_failures_total{{db=\"{id}\"}} {}",
+                persistence.failures.load(Ordering::Relaxed)
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP map8x32_snapshots_total Snapshots written.\n# TYPE map8x32_snapshots_total counter"
+        );
+        for (id, persistence) in &persisted {
+            let _ = writeln!(
+                out,
+                "map8x32_snapshots_total{{db=\"{id}\"}} {}",
+                persistence.snapshots.load(Ordering::Relaxed)
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP map8x32_wal_bytes Bytes in the write-ahead log since the last snapshot.\n# TYPE map8x32_wal_bytes gauge"
+        );
+        for (id, persistence) in &persisted {
+            let _ = writeln!(
+                out,
+                "map8x32_wal_bytes{{db=\"{id}\"}} {}",
+                persistence.wal_len()
+            );
+        }
+    }
+
+    out
+}
+
+/// Per-key access counts and value counts laid out as a 16x16 grid: row
+/// `r`, column `c` is key `r * 16 + c`.
+pub fn render_heatmap(database: &Database) -> String {
+    let grid = |read: &dyn Fn(u8) -> u64| {
+        let rows: Vec<String> = (0..16u8)
+            .map(|row| {
+                let cells: Vec<String> = (0..16u8)
+                    .map(|col| read(row * 16 + col).to_string())
+                    .collect();
+                format!("[{}]", cells.join(","))
+            })
+            .collect();
+        format!("[\n    {}\n  ]", rows.join(",\n    "))
+    };
+    format!(
+        "{{\n  \"rows\": 16,\n  \"cols\": 16,\n  \"accesses\": {},\n  \"values\": {}\n}}\n",
+        grid(&|key| database.accesses(key)),
+        grid(&|key| database
+            .map
+            .get(&key)
+            .map_or(0, |values| values.len() as u64))
+    )
+}
+
+/// The INFO statistics plus the current connection count, as JSON.
+pub fn render_info(database: &Database, admission: &Admission) -> String {
+    let info = database.info(false);
+    let ops: Vec<String> = info
+        .ops
+        .iter()
+        .map(|(op, count)| format!("\"{op}\": {count}"))
+        .collect();
+    let persistence = info.persistence.map_or("null".to_string(), |persistence| {
+        format!(
+            "{{ \"health\": \"{}\", \"failures\": {} }}",
+            persistence.health.name(),
+            persistence.failures
+        )
+    });
+    let allocations: Vec<String> = info
+        .allocations
+        .iter()
+        .map(|allocation| {
+            format!(
+                "{{ \"first_key\": {}, \"last_key\": {}, \"name\": \"{}\", \"contact\": \"{}\", \"policy\": \"{}\" }}",
+                allocation.first_key,
+                allocation.last_key,
+                escape(&allocation.name),
+                escape(&allocation.contact),
+                escape(&allocation.policy)
+            )
+        })
+        .collect();
+    let limit = |limit: Option<u64>| limit.map_or("null".to_string(), |limit| limit.to_string());
+    format!(
+        "{{\n  \"uptime_ms\": {},\n  \"keys\": {},\n  \"values\": {},\n  \"max_keys\": {},\n  \"max_values\": {},\n  \"memory_bytes\": {},\n  \"hits\": {},\n  \"misses\": {},\n  \"quota_rejections\": {},\n  \"connections\": {},\n  \"ops\": {{ {} }},\n  \"persistence\": {},\n  \"allocations\": [{}]\n}}\n",
+        info.uptime_ms,
+        info.keys,
+        info.values,
+        limit(info.max_keys),
+        limit(info.max_values),
+        info.memory_bytes,
+        info.hits,
+        info.misses,
+        info.quota_rejections,
+        admission.active(),
+        ops.join(", "),
+        persistence,
+        allocations.join(", ")
+    )
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+async fn read_request_path(stream: &mut TcpStream) -> io::Result<Option<String>> {
+    let mut head = Vec::with_capacity(512);
+    let mut chunk = [0u8; 512];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 || head.len() + n > MAX_REQUEST_HEAD {
+            return Ok(None);
+        }
+        head.extend_from_slice(&chunk[..n]);
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let mut parts = head.lines().next().unwrap_or("").split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => Ok(Some(path.to_string())),
+        _ => Ok(None),
+    }
+}
+
+async fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await
+}
+
+/// Splits `?db=N` off a path; without it the path refers to database 0.
+fn database_query(path: &str) -> (&str, Option<usize>) {
+    match path.split_once('?') {
+        None => (path, Some(0)),
+        Some((path, query)) => (
+            path,
+            query.strip_prefix("db=").and_then(|db| db.parse().ok()),
+        ),
+    }
+}
+
+async fn handle_request(
+    mut stream: TcpStream,
+    databases: Vec<Arc<Database>>,
+    admission: Arc<Admission>,
+) -> io::Result<()> {
+    let path = read_request_path(&mut stream).await?;
+    let (route, db) = match path.as_deref() {
+        Some(path) => {
+            let (route, db) = database_query(path);
+            (Some(route), db.and_then(|db| databases.get(db)))
+        }
+        None => (None, None),
+    };
+    match (route, db) {
+        (Some("/metrics"), _) => {
+            let databases: Vec<&Database> = databases.iter().map(|db| db.as_ref()).collect();
+            let body = render_metrics(&databases, &admission);
+            respond(&mut stream, "200 OK", "text/plain; version=0.0.4", &body).await
+        }
+        (Some("/heatmap"), Some(database)) => {
+            let body = render_heatmap(database);
+            respond(&mut stream, "200 OK", "application/json", &body).await
+        }
+        (Some("/info"), Some(database)) => {
+            let body = render_info(database, &admission);
+            respond(&mut stream, "200 OK", "application/json", &body).await
+        }
+        #[cfg(feature = "dashboard")]
+        (Some("/" | "/dashboard"), _) => {
+            respond(&mut stream, "200 OK", "text/html; charset=utf-8", DASHBOARD).await
+        }
+        (Some(_), _) => respond(&mut stream, "404 Not Found", "text/plain", "not found\n").await,
+        (None, _) => {
+            respond(
+                &mut stream,
+                "400 Bad Request",
+                "text/plain",
+                "bad request\n",
+            )
+            .await
+        }
+    }
+}
+
+pub async fn serve(
+    listener: TcpListener,
+    databases: Vec<Arc<Database>>,
+    admission: Arc<Admission>,
+) -> io::Result<()> {
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                crate::accept_failed(e).await;
+                continue;
+            }
+        };
+        tokio::spawn(handle_request(stream, databases.clone(), admission.clone()));
+    }
+}
@@ -0,0 +1,61 @@
+use serde::Deserialize;
+use std::io::{self, IsTerminal};
+use std::sync::{Mutex, OnceLock};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+static LEVEL: Mutex<String> = Mutex::new(String::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per event, with its span fields.
+    Json,
+}
+
+/// Parses a level such as `info`, or a full filter such as
+/// `warn,map8x32_server=debug`.
+pub fn filter(level: &str) -> io::Result<EnvFilter> {
+    EnvFilter::try_new(level).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid log level {level:?}: {e}"),
+        )
+    })
+}
+
+/// Installs the global subscriber, writing to stderr.
+pub fn init(level: &str, format: LogFormat) -> io::Result<()> {
+    let (filter, handle) = reload::Layer::new(filter(level)?);
+    let ansi = io::stderr().is_terminal();
+    let text =
+        (format == LogFormat::Text).then(|| fmt::layer().with_writer(io::stderr).with_ansi(ansi));
+    let json = (format == LogFormat::Json).then(|| fmt::layer().json().with_writer(io::stderr));
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(text)
+        .with(json)
+        .try_init()
+        .map_err(io::Error::other)?;
+    let _ = FILTER.set(handle);
+    *LEVEL.lock().unwrap() = level.to_string();
+    Ok(())
+}
+
+/// Swaps the level of the subscriber installed by [`init`].
+pub fn set_level(level: &str) -> io::Result<()> {
+    let filter = filter(level)?;
+    if let Some(handle) = FILTER.get() {
+        handle.reload(filter).map_err(io::Error::other)?;
+    }
+    *LEVEL.lock().unwrap() = level.to_string();
+    Ok(())
+}
+
+/// The level last passed to [`init`] or [`set_level`].
+pub fn level() -> String {
+    LEVEL.lock().unwrap().clone()
+}
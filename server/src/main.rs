@@ -1,238 +1,1654 @@
-use dashmap::DashMap;
+mod admission;
+mod audit;
+mod capture;
+mod config;
+mod datagram;
+mod diagnostics;
+mod handoff;
+mod http;
+mod logging;
+mod monitor;
+#[cfg_attr(not(feature = "otlp"), allow(dead_code))]
+mod otlp;
+mod report;
+mod resp;
+mod seed;
+mod selftest;
+mod session;
+#[cfg(all(feature = "shm-mirror", unix))]
+mod shm;
+mod sink;
+mod slowlog;
+mod tls;
+mod trace;
+mod tunables;
+#[cfg(feature = "s3-upload")]
+mod upload;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+mod uring;
+mod websocket;
+
+use admission::{Access, Admission, Admitted, CloseReason, Peer, Priority, ProtocolErrorPolicy};
+use audit::AuditLog;
+use cap::Caps;
+use clap::Parser;
+use config::{Args, Config, Tool, Transport};
+use db::{Database, DatabaseStats, Event};
+use deadletter::DeadLetters;
+use dispatch::Dispatcher;
+use handoff::{Inherited, Listening};
+use map8x32_core::command::{call, execute, execute_get, Command, GetResponse, SetMode};
+use map8x32_core::recorder::Recorder;
+use map8x32_core::{
+    cap, db, deadletter, dispatch, encryption, persistence, registry, rollup, store, transform,
+    StorageType,
+};
+use map8x32_protocol::codec::{CodecError, Framing, KeyspaceEvent, Request, Response};
+use map8x32_protocol::*;
+use persistence::Persistence;
+use registry::Registry;
+use report::ShutdownReport;
+use rollup::Rollups;
+use std::collections::HashMap;
 use std::io;
-use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::UnixListener;
-use tokio::sync::{mpsc, oneshot};
 use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use store::Store;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UnixDatagram, UnixListener, UnixStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::oneshot;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn, Instrument};
+use transform::Pipeline;
 
-type StorageType = Arc<DashMap<u8, Vec<u32>>>;
-
-const OP_SET: u8 = 1;
-const OP_GET: u8 = 2;
-const OP_DELETE_BY_KEY: u8 = 3;
-const OP_DELETE_ALL: u8 = 4;
-const OP_LIST_ALL: u8 = 5;
+const READ_CHUNK: usize = 16 * 1024;
+/// How long shutdown waits for connections to finish what they have read.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+/// Permission bits of a Unix socket the config does not set them for, so any
+/// local user can connect.
+const SOCKET_MODE: u32 = 0o666;
+/// How long a listener waits after an accept fails for want of resources,
+/// such as file descriptors, before it tries again.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
 
-const STATUS_NOT_FOUND: u8 = 0;
-const STATUS_OK: u8 = 1;
-const STATUS_BAD_REQUEST: u8 = 2;
+/// Reads more request bytes into `input`. A new request may take up to the
+/// idle timeout to start; one already arriving must be complete by
+/// `deadline`, which the frame timeout sets when it starts. Fails with the
+/// reason to close the connection for, which is [`CloseReason::Shutdown`]
+/// once the server is shutting down and no request is partly read. With
+/// part of one in `input`, the caller answers BAD_REQUEST before closing.
+async fn fill<S>(
+    socket: &mut S,
+    input: &mut Vec<u8>,
+    deadline: &mut Option<Instant>,
+    admission: &Admission,
+) -> Result<(), CloseReason>
+where
+    S: AsyncRead + Unpin,
+{
+    input.reserve(READ_CHUNK);
+    let idle = input.is_empty();
+    let read = if idle {
+        *deadline = None;
+        let read = async {
+            match admission.idle_timeout {
+                Some(idle_timeout) => tokio::time::timeout(idle_timeout, socket.read_buf(input))
+                    .await
+                    .ok(),
+                None => Some(socket.read_buf(input).await),
+            }
+        };
+        tokio::select! {
+            biased;
+            _ = admission.closing() => return Err(CloseReason::Shutdown),
+            read = read => match read {
+                Some(read) => read,
+                None => {
+                    DatabaseStats::count(&admission.idle_reaped);
+                    debug!("closing idle connection");
+                    return Err(CloseReason::Timeout);
+                }
+            },
+        }
+    } else {
+        if deadline.is_none() {
+            *deadline = admission
+                .frame_timeout
+                .map(|frame_timeout| Instant::now() + frame_timeout);
+        }
+        match *deadline {
+            Some(deadline) => {
+                match tokio::time::timeout_at(deadline.into(), socket.read_buf(input)).await {
+                    Ok(read) => read,
+                    Err(_) => {
+                        DatabaseStats::count(&admission.frame_timeouts);
+                        debug!("closing connection after a request stalled mid-frame");
+                        return Err(CloseReason::Timeout);
+                    }
+                }
+            }
+            None => socket.read_buf(input).await,
+        }
+    };
+    match read {
+        Ok(0) => Err(CloseReason::Eof),
+        Ok(_) => Ok(()),
+        Err(e) => {
+            debug!(error = %e, "closing connection after a failed read");
+            Err(CloseReason::IoError)
+        }
+    }
+}
 
-#[derive(Debug)]
-enum Command {
-    Set { key: u8, value: u32, respond_to: oneshot::Sender<u8> },
-    Get { key: u8, respond_to: oneshot::Sender<GetResponse> },
-    DeleteByKey { key: u8, respond_to: oneshot::Sender<u8> },
-    DeleteAll { respond_to: oneshot::Sender<u8> },
-    ListAll { respond_to: oneshot::Sender<ListAllResponse> },
+/// Serves a connection inside a span carrying its id, then shuts down its
+/// write side so every answer already written reaches the peer.
+async fn handle_connection<S>(mut socket: S, sender: Dispatcher, admitted: &Admitted)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let span = tracing::info_span!("connection", id = admitted.id);
+    async {
+        debug!("connection opened");
+        let reason = tokio::select! {
+            reason = serve_requests(&mut socket, sender.for_connection(admitted.id, admitted.priority()), admitted) => reason,
+            _ = admitted.activity.killed() => CloseReason::Killed,
+        };
+        if let Some(capture) = &admitted.admission().capture {
+            capture.closed(admitted.id);
+        }
+        let _ = socket.shutdown().await;
+        admitted.closed(reason);
+        debug!(reason = reason.name(), "connection closed");
+    }
+    .instrument(span)
+    .await
 }
 
-#[derive(Debug)]
-enum GetResponse {
-    Found(Vec<u32>),
-    NotFound,
+/// Requests a multiplexed connection runs at once at most; it decodes no
+/// more until one of them is answered.
+const MAX_IN_FLIGHT: usize = 256;
+
+/// What a connection keeps of a request until it encodes the answer.
+struct Pending {
+    id: Option<u32>,
+    op: u8,
+    key: Option<u8>,
+    received: Instant,
+    request_bytes: usize,
+    audited: Option<Request>,
+    watched: Option<monitor::Watched>,
+    traced: Option<Arc<otlp::Phases>>,
 }
 
-#[derive(Debug)]
-struct ListAllResponse {
-    entries: Vec<(u8, Vec<u32>)>,
+/// The requests a multiplexed connection is running, each finishing with
+/// the database it ran on and its answer, or `None` once the engine shut
+/// down, and a way to abandon each read among them by its request ID.
+#[derive(Default)]
+struct InFlight {
+    tasks: tokio::task::JoinSet<(Pending, u8, Option<Response>)>,
+    cancels: HashMap<u32, oneshot::Sender<()>>,
 }
 
-async fn command_processor(mut receiver: mpsc::UnboundedReceiver<Command>, storage: StorageType) {
-    while let Some(command) = receiver.recv().await {
-        match command {
-            Command::Set { key, value, respond_to } => {
-                storage.entry(key).or_insert_with(Vec::new).push(value);
-                let _ = respond_to.send(STATUS_OK);
-            }
-            Command::Get { key, respond_to } => {
-                let response = if let Some(values) = storage.get(&key) {
-                    GetResponse::Found(values.clone())
-                } else {
-                    GetResponse::NotFound
-                };
-                let _ = respond_to.send(response);
-            }
-            Command::DeleteByKey { key, respond_to } => {
-                let status = if storage.remove(&key).is_some() {
-                    STATUS_OK
-                } else {
-                    STATUS_NOT_FOUND
-                };
-                let _ = respond_to.send(status);
-            }
-            Command::DeleteAll { respond_to } => {
-                storage.clear();
-                let _ = respond_to.send(STATUS_OK);
-            }
-            Command::ListAll { respond_to } => {
-                let entries: Vec<(u8, Vec<u32>)> = storage
-                    .iter()
-                    .map(|entry| (*entry.key(), entry.value().clone()))
-                    .collect();
-                let _ = respond_to.send(ListAllResponse { entries });
-            }
+impl InFlight {
+    fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// Runs `request` on `sender`'s database alongside the others. A read
+    /// can be cancelled until it is answered; a write cannot be taken back.
+    fn spawn(&mut self, sender: Dispatcher, request: Request, pending: Pending) {
+        let (cancel, cancelled) = oneshot::channel();
+        if let (Some(id), false) = (pending.id, request.writes()) {
+            self.cancels.insert(id, cancel);
+        }
+        let refusal = Response::refusal(&request, STATUS_CANCELLED);
+        let sender = match &pending.traced {
+            Some(phases) => sender.traced(phases.clone()),
+            None => sender,
+        };
+        self.tasks.spawn(async move {
+            let response = tokio::select! {
+                response = execute(&sender, request) => response,
+                Ok(()) = cancelled => Some(refusal),
+            };
+            (pending, sender.selected(), response)
+        });
+    }
+
+    /// Abandons the read running under `id`, returning whether there was one.
+    fn cancel(&mut self, id: u32) -> bool {
+        self.cancels
+            .remove(&id)
+            .is_some_and(|cancel| cancel.send(()).is_ok())
+    }
+
+    /// The next request to finish, or `None` if none are running.
+    async fn next(&mut self) -> Option<(Pending, u8, Option<Response>)> {
+        let done = self
+            .tasks
+            .join_next()
+            .await?
+            .expect("request tasks are never aborted");
+        if let Some(id) = done.0.id {
+            self.cancels.remove(&id);
         }
+        Some(done)
     }
 }
 
-async fn handle_connection(
-    mut socket: tokio::net::UnixStream,
-    sender: mpsc::UnboundedSender<Command>,
+/// Logs and records how `db` answered `pending`, then encodes the answer.
+fn answer(
+    pending: Pending,
+    db: u8,
+    response: &Response,
+    framing: &mut Framing,
+    output: &mut Vec<u8>,
+    unwritten: &mut Vec<otlp::Answered>,
+    admitted: &Admitted,
 ) {
-    let mut buf = [0u8; 6];
+    let id = pending.id;
+    record(pending, db, response.status(), unwritten, admitted);
+    framing.queue_response(id, response, output);
+}
 
-    while let Ok(_) = socket.read_exact(&mut buf).await {
-        let op = buf[0];
-        let key = buf[1];
-        let value = u32::from_le_bytes([buf[2], buf[3], buf[4], buf[5]]);
+/// Logs and records that `db` answered `pending` with `status`; a traced
+/// request then waits in `unwritten` for its answer to be written.
+fn record(
+    pending: Pending,
+    db: u8,
+    status: u8,
+    unwritten: &mut Vec<otlp::Answered>,
+    admitted: &Admitted,
+) {
+    let admission = admitted.admission();
+    let elapsed = pending.received.elapsed();
+    debug!(
+        op = pending.op,
+        key = pending.key,
+        status,
+        micros = elapsed.as_micros() as u64,
+        "command"
+    );
+    admission.slow_log.observe(
+        pending.op,
+        pending.key.unwrap_or(0),
+        elapsed,
+        pending.request_bytes,
+    );
+    if let (Some(audit), Some(request)) = (&admission.audit, &pending.audited) {
+        audit.record(&admitted.peer, db, request, status);
+    }
+    if let Some(watched) = pending.watched {
+        admission
+            .monitor
+            .finish(watched, &admitted.peer, db, status, elapsed);
+    }
+    if let Some(phases) = pending.traced {
+        unwritten.push(otlp::Answered::new(phases, db, status));
+    }
+}
 
-        match op {
-            OP_SET => {
-                let (tx, rx) = oneshot::channel();
-                if sender.send(Command::Set { key, value, respond_to: tx }).is_err() {
-                    break;
-                }
-                if let Ok(status) = rx.await {
-                    if socket.write_u8(status).await.is_err() {
-                        break;
+/// Waits for every request still running and encodes its answer, along
+/// with every chunk still to go, returning false if the engine shut down
+/// under one.
+async fn settle(
+    in_flight: &mut InFlight,
+    framing: &mut Framing,
+    output: &mut Vec<u8>,
+    unwritten: &mut Vec<otlp::Answered>,
+    admitted: &Admitted,
+) -> bool {
+    while let Some((pending, db, response)) = in_flight.next().await {
+        match response {
+            Some(response) => answer(pending, db, &response, framing, output, unwritten, admitted),
+            None => return false,
+        }
+    }
+    while framing.continuing() {
+        framing.encode_continuations(output);
+    }
+    true
+}
+
+/// Serves requests until the peer goes away, returning why the connection
+/// ended. Requests are decoded from a buffer with the connection's current
+/// codec, and responses to everything already buffered go out together
+/// before the next read, after waiting up to the flush delay for more
+/// requests to answer in the same write, or early once they reach the
+/// flush size. Data requests go to the database the connection
+/// last selected, database 0 to begin with. Once the connection sends
+/// MULTIPLEX, data requests run concurrently and are answered as they
+/// finish, while HELLO, SELECT_CODEC, CHUNKED, SUBSCRIBE and MONITOR wait
+/// for those already running. Long answers on a connection that is also
+/// chunked go out a chunk at a time, taking turns with the others.
+async fn serve_requests<S>(
+    socket: &mut S,
+    mut sender: Dispatcher,
+    admitted: &Admitted,
+) -> CloseReason
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let admission = admitted.admission();
+    let mut access = (!admission.requires_auth()).then_some(Access::Admin);
+    let mut framing = Framing::default();
+    let mut input = Vec::new();
+    let mut consumed = 0;
+    let mut output = Vec::new();
+    let mut deadline = None;
+    // Set once the connection asks for probes; `probed` while one is unanswered.
+    let mut keepalive = None;
+    let mut probed = false;
+    let mut subscribed = None;
+    // The events asked for, and the feed taken when they were, so none
+    // raised after the answer is missed.
+    let mut watching = None;
+    let mut monitoring = false;
+    let mut in_flight = InFlight::default();
+    // Set once held answers have waited the flush delay for more requests.
+    let mut lingered = false;
+    // When the first bytes still buffered arrived, and the traced requests
+    // answered but not yet written.
+    let mut arrived = None;
+    let mut unwritten = Vec::new();
+    // GET answers are encoded into this by the command processor, then
+    // copied into the output, so it is allocated once.
+    let mut encoded = Vec::new();
+
+    loop {
+        admitted
+            .activity
+            .in_flight
+            .store(in_flight.len() as u64, Ordering::Relaxed);
+        if output.len() >= admission.flush_bytes {
+            if socket.write_all(&output).await.is_err() {
+                break CloseReason::IoError;
+            }
+            admitted.activity.sent(output.len());
+            output.clear();
+            if let Some(tracer) = &admission.tracer {
+                tracer.written(&mut unwritten);
+            }
+        }
+        let (request, request_bytes, id) = match framing.decode_request(&input[consumed..]) {
+            Ok(Some(_)) if in_flight.len() >= MAX_IN_FLIGHT => {
+                let (pending, db, response) =
+                    in_flight.next().await.expect("requests are in flight");
+                match response {
+                    Some(response) => answer(
+                        pending,
+                        db,
+                        &response,
+                        &mut framing,
+                        &mut output,
+                        &mut unwritten,
+                        admitted,
+                    ),
+                    None => {
+                        let _ = socket.write_all(&output).await;
+                        break CloseReason::Shutdown;
                     }
-                } else {
-                    break;
                 }
+                continue;
             }
-            OP_GET => {
-                let (tx, rx) = oneshot::channel();
-                if sender.send(Command::Get { key, respond_to: tx }).is_err() {
-                    break;
+            Ok(Some((request, len, id))) => {
+                if let Some(capture) = &admission.capture {
+                    capture.request(admitted.id, &input[consumed..consumed + len]);
                 }
-                if let Ok(response) = rx.await {
-                    match response {
-                        GetResponse::Found(values) => {
-                            if socket.write_u8(STATUS_OK).await.is_err() {
-                                break;
-                            }
-                            if socket.write_u32_le(values.len() as u32).await.is_err() {
-                                break;
-                            }
-                            let mut write_failed = false;
-                            for &v in values.iter() {
-                                if socket.write_u32_le(v).await.is_err() {
-                                    write_failed = true;
-                                    break;
-                                }
-                            }
-                            if write_failed {
-                                break;
-                            }
+                consumed += len;
+                deadline = None;
+                (request, len, id)
+            }
+            Ok(None) => {
+                input.drain(..consumed);
+                consumed = 0;
+                if !output.is_empty() {
+                    // Requests pipelined close behind these get their answers into the same write.
+                    let linger = admission
+                        .flush_delay
+                        .filter(|_| !lingered && input.is_empty() && in_flight.is_empty());
+                    if let Some(delay) = linger {
+                        lingered = true;
+                        if let Ok(Ok(())) = tokio::time::timeout(
+                            delay,
+                            fill(socket, &mut input, &mut deadline, admission),
+                        )
+                        .await
+                        {
+                            arrived.get_or_insert_with(Instant::now);
+                            continue;
                         }
-                        GetResponse::NotFound => {
-                            if socket.write_u8(STATUS_NOT_FOUND).await.is_err() {
-                                break;
+                    }
+                    if socket.write_all(&output).await.is_err() {
+                        break CloseReason::IoError;
+                    }
+                    admitted.activity.sent(output.len());
+                    output.clear();
+                    lingered = false;
+                    if let Some(tracer) = &admission.tracer {
+                        tracer.written(&mut unwritten);
+                    }
+                }
+                let probe = keepalive.filter(|_| input.is_empty() && in_flight.is_empty());
+                let read = tokio::select! {
+                    read = fill(socket, &mut input, &mut deadline, admission) => Some(read),
+                    _ = tokio::time::sleep(probe.unwrap_or_default()), if probe.is_some() => None,
+                    Some((pending, db, response)) = in_flight.next() => {
+                        match response {
+                            Some(response) => answer(pending, db, &response, &mut framing, &mut output, &mut unwritten, admitted),
+                            None => {
+                                let _ = socket.write_all(&output).await;
+                                break CloseReason::Shutdown;
                             }
                         }
+                        continue;
+                    }
+                    _ = std::future::ready(()), if framing.continuing() => {
+                        framing.encode_continuations(&mut output);
+                        continue;
+                    }
+                };
+                match read {
+                    None if probed => {
+                        DatabaseStats::count(&admission.keepalive_timeouts);
+                        debug!("closing connection that did not answer a keepalive probe");
+                        break CloseReason::Timeout;
+                    }
+                    None => {
+                        probed = true;
+                        framing.encode_probe(&mut output);
+                        continue;
+                    }
+                    Some(Ok(())) => {
+                        probed = false;
+                        arrived.get_or_insert_with(Instant::now);
+                        continue;
+                    }
+                    Some(Err(reason)) => {
+                        // Answer what is still running before the peer is told.
+                        if settle(
+                            &mut in_flight,
+                            &mut framing,
+                            &mut output,
+                            &mut unwritten,
+                            admitted,
+                        )
+                        .await
+                        {
+                            let _ = socket.write_all(&output).await;
+                        }
+                        // Part of a request arrived: say it will not be answered.
+                        if !input.is_empty() {
+                            let _ = socket.write_u8(STATUS_BAD_REQUEST).await;
+                        }
+                        break reason;
                     }
-                } else {
-                    break;
                 }
             }
-            OP_DELETE_BY_KEY => {
-                let (tx, rx) = oneshot::channel();
-                if sender.send(Command::DeleteByKey { key, respond_to: tx }).is_err() {
-                    break;
-                }
-                if let Ok(status) = rx.await {
-                    if socket.write_u8(status).await.is_err() {
-                        break;
+            Err(e) => {
+                debug!(error = %e, codec = framing.codec().name(), "closing connection after an undecodable request");
+                DatabaseStats::count(&admission.protocol_errors);
+                // A length past the codec's limits is never read, so the
+                // stream cannot be followed past it.
+                output.push(match e {
+                    CodecError::Oversized => {
+                        DatabaseStats::count(&admission.payload_rejections);
+                        STATUS_PAYLOAD_TOO_LARGE
                     }
-                } else {
-                    break;
-                }
+                    CodecError::Malformed(_) => STATUS_BAD_REQUEST,
+                });
+                let _ = socket.write_all(&output).await;
+                break CloseReason::ProtocolError;
             }
-            OP_DELETE_ALL => {
-                let (tx, rx) = oneshot::channel();
-                if sender.send(Command::DeleteAll { respond_to: tx }).is_err() {
-                    break;
-                }
-                if let Ok(status) = rx.await {
-                    if socket.write_u8(status).await.is_err() {
-                        break;
+        };
+
+        let began = if consumed == input.len() {
+            arrived.take()
+        } else {
+            arrived
+        };
+        if matches!(request, Request::Pong) {
+            continue;
+        }
+        let (op, key, received) = (request.op(), request.key(), Instant::now());
+        admitted.activity.received(op, request_bytes);
+        let audited = admission
+            .audit
+            .as_ref()
+            .filter(|_| request.writes())
+            .map(|_| request.clone());
+        let watched = admission.monitor.start(&request);
+        let traced = admission
+            .tracer
+            .as_ref()
+            .and_then(|tracer| tracer.start_span(op, key, began.unwrap_or(received)));
+        let pending = Pending {
+            id,
+            op,
+            key,
+            received,
+            request_bytes,
+            audited,
+            watched,
+            traced,
+        };
+        let is_hello = matches!(request, Request::Hello { .. });
+        let desynced = matches!(request, Request::Invalid { .. })
+            && admission.on_protocol_error == ProtocolErrorPolicy::Close;
+        // Answers already running go out in the codec they were asked in,
+        // and before a feed takes over the connection.
+        let settles = desynced
+            || matches!(
+                request,
+                Request::Hello { .. }
+                    | Request::SelectCodec { .. }
+                    | Request::Chunked { .. }
+                    | Request::Subscribe { .. }
+                    | Request::SubscribeEvents { .. }
+                    | Request::Monitor
+            );
+        if settles
+            && !settle(
+                &mut in_flight,
+                &mut framing,
+                &mut output,
+                &mut unwritten,
+                admitted,
+            )
+            .await
+        {
+            let _ = socket.write_all(&output).await;
+            break CloseReason::Shutdown;
+        }
+        let refused = |status| {
+            sender
+                .storage()
+                .dead_letters
+                .record_refused(&request, status);
+            Response::refusal(&request, status)
+        };
+        let response = if !is_hello && !admitted.allow() {
+            refused(STATUS_THROTTLED)
+        } else if admission.too_large(&request, request_bytes) {
+            refused(STATUS_PAYLOAD_TOO_LARGE)
+        } else if access.is_none()
+            && !matches!(
+                request,
+                Request::Hello { .. }
+                    | Request::Auth { .. }
+                    | Request::SelectCodec { .. }
+                    | Request::Ping
+                    | Request::Keepalive
+            )
+        {
+            refused(STATUS_UNAUTHORIZED)
+        } else if !admission.permits(access.unwrap_or_default(), &request) {
+            refused(STATUS_READONLY)
+        } else if access != Some(Access::Admin)
+            && Admission::needs_admin(&request, sender.storage().registry.strict)
+        {
+            refused(STATUS_UNAUTHORIZED)
+        } else {
+            match request {
+                Request::Hello {
+                    version: requested,
+                    magic,
+                } => framing.hello(requested, magic),
+                Request::Auth { token } => match admission.authenticate(&token) {
+                    Some(granted) => {
+                        access = Some(granted);
+                        Response::Status(STATUS_OK)
                     }
+                    None => Response::Status(STATUS_UNAUTHORIZED),
+                },
+                Request::SelectCodec { codec: requested } => framing.select_codec(requested),
+                Request::Multiplex => framing.multiplex(),
+                Request::Chunked { max_frame } => framing.chunk(max_frame, admission.max_frame),
+                Request::Cancel { id } => Response::Status(if in_flight.cancel(id) {
+                    STATUS_OK
                 } else {
-                    break;
+                    STATUS_NOT_FOUND
+                }),
+                Request::Priority { class } => match Priority::ALL.get(usize::from(class)) {
+                    Some(&priority) if priority <= admitted.priority() => {
+                        sender = sender.prioritize(priority);
+                        Response::Status(STATUS_OK)
+                    }
+                    Some(_) => Response::Status(STATUS_UNAUTHORIZED),
+                    None => Response::Status(STATUS_BAD_REQUEST),
+                },
+                Request::Select { db } => match sender.select(db) {
+                    Some(selected) => {
+                        sender = selected;
+                        Response::Status(STATUS_OK)
+                    }
+                    None => Response::Status(STATUS_BAD_REQUEST),
+                },
+                Request::Ping => Response::Pong {
+                    server_version: env!("CARGO_PKG_VERSION").to_string(),
+                },
+                Request::Keepalive => {
+                    match admission.keepalive.filter(|_| admitted.peer.addr.is_some()) {
+                        Some(interval) => {
+                            keepalive = Some(interval);
+                            Response::Status(STATUS_OK)
+                        }
+                        None => Response::Status(STATUS_NOT_FOUND),
+                    }
                 }
-            }
-            OP_LIST_ALL => {
-                let (tx, rx) = oneshot::channel();
-                if sender.send(Command::ListAll { respond_to: tx }).is_err() {
-                    break;
+                Request::Subscribe { key } => {
+                    subscribed = Some(key);
+                    Response::Status(STATUS_OK)
                 }
-                if let Ok(response) = rx.await {
-                    if socket.write_u8(STATUS_OK).await.is_err() {
-                        break;
+                Request::SubscribeEvents { events } => {
+                    watching = Some((events, sender.storage().subscribe_events()));
+                    Response::Events(Vec::new())
+                }
+                Request::Monitor => {
+                    monitoring = true;
+                    Response::Monitored(Vec::new())
+                }
+                Request::SlowLog { drain } => Response::SlowOps(admission.slow_log.snapshot(drain)),
+                Request::ClientList => Response::Clients(admission.clients()),
+                Request::ClientInfo => {
+                    Response::Clients(vec![admitted.activity.client(admitted.id)])
+                }
+                Request::ClientKill { id: killed } => match admission.kill(killed) {
+                    true => {
+                        info!(id = killed, peer = %admitted.peer, "killed connection");
+                        Response::Status(STATUS_OK)
                     }
-                    if socket.write_u32_le(response.entries.len() as u32).await.is_err() {
-                        break;
+                    false => Response::Status(STATUS_NOT_FOUND),
+                },
+                Request::Config { name, value: None } if name.is_empty() => {
+                    Response::Config(tunables::all(admission, sender.databases()))
+                }
+                Request::Config { name, value: None } => {
+                    match tunables::get(admission, sender.databases(), &name) {
+                        Some(value) => Response::Config(vec![(name, value)]),
+                        None => Response::Status(STATUS_NOT_FOUND),
                     }
-                    let mut write_failed = false;
-                    for (key, values) in response.entries {
-                        if socket.write_u8(key).await.is_err() {
-                            write_failed = true;
-                            break;
-                        }
-                        if socket.write_u32_le(values.len() as u32).await.is_err() {
-                            write_failed = true;
-                            break;
-                        }
-                        for &v in values.iter() {
-                            if socket.write_u32_le(v).await.is_err() {
-                                write_failed = true;
-                                break;
-                            }
+                }
+                Request::Config {
+                    name,
+                    value: Some(value),
+                } => {
+                    let status = tunables::set(admission, sender.databases(), &name, &value);
+                    if status == STATUS_OK {
+                        info!(name, value, peer = %admitted.peer, "changed configuration");
+                    }
+                    Response::Status(status)
+                }
+                Request::Invalid { .. } => Response::Status(STATUS_BAD_REQUEST),
+                request if framing.multiplexed() => {
+                    in_flight.spawn(sender.clone(), request, pending);
+                    continue;
+                }
+                Request::Get { key } => {
+                    let traced = pending
+                        .traced
+                        .as_ref()
+                        .map(|phases| sender.traced(phases.clone()));
+                    match execute_get(
+                        traced.as_ref().unwrap_or(&sender),
+                        key,
+                        framing.codec(),
+                        std::mem::take(&mut encoded),
+                    )
+                    .await
+                    {
+                        Some(answer) => {
+                            let id = pending.id;
+                            record(
+                                pending,
+                                sender.selected(),
+                                answer.status,
+                                &mut unwritten,
+                                admitted,
+                            );
+                            framing.queue_encoded(id, &answer.bytes, &mut output);
+                            encoded = answer.bytes;
                         }
-                        if write_failed {
-                            break;
+                        None => {
+                            let _ = socket.write_all(&output).await;
+                            break CloseReason::Shutdown;
                         }
                     }
-                    if write_failed {
-                        break;
+                    continue;
+                }
+                request => {
+                    let traced = pending
+                        .traced
+                        .as_ref()
+                        .map(|phases| sender.traced(phases.clone()));
+                    match execute(traced.as_ref().unwrap_or(&sender), request).await {
+                        Some(response) => response,
+                        None => {
+                            let _ = socket.write_all(&output).await;
+                            break CloseReason::Shutdown;
+                        }
                     }
-                } else {
-                    break;
                 }
             }
-            _ => {
-                if socket.write_u8(STATUS_BAD_REQUEST).await.is_err() {
-                    break;
-                }
+        };
+        answer(
+            pending,
+            sender.selected(),
+            &response,
+            &mut framing,
+            &mut output,
+            &mut unwritten,
+            admitted,
+        );
+        if desynced {
+            debug!(op, "closing connection after an unknown opcode");
+            DatabaseStats::count(&admission.protocol_errors);
+            let _ = socket.write_all(&output).await;
+            break CloseReason::ProtocolError;
+        }
+        if let Some(key) = subscribed {
+            break serve_subscription(socket, &sender, key, id, &mut framing, output, admission)
+                .await;
+        }
+        if let Some((events, feed)) = watching {
+            break serve_events(socket, events, feed, id, &mut framing, output, admission).await;
+        }
+        if monitoring {
+            break serve_monitor(socket, id, &mut framing, output, admission).await;
+        }
+    }
+}
+
+/// Pushes `key`'s values, shaped as GET answers them, right away and after
+/// every change, until the peer goes away or the server shuts down. The
+/// connection reads nothing more, only watching for the peer to close, and
+/// is never reaped as idle since waiting is what it is for.
+async fn serve_subscription<S>(
+    socket: &mut S,
+    sender: &Dispatcher,
+    key: u8,
+    id: Option<u32>,
+    framing: &mut Framing,
+    mut output: Vec<u8>,
+    admission: &Admission,
+) -> CloseReason
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Subscribed before the values are read, so no change slips in between.
+    let mut changes = sender.subscribe();
+    let mut discard = vec![0; READ_CHUNK];
+    loop {
+        match execute(sender, Request::Get { key }).await {
+            Some(response) => framing.encode_response(id, &response, &mut output),
+            None => {
+                let _ = socket.write_all(&output).await;
+                return CloseReason::Shutdown;
+            }
+        }
+        if socket.write_all(&output).await.is_err() {
+            return CloseReason::IoError;
+        }
+        output.clear();
+        loop {
+            tokio::select! {
+                biased;
+                _ = admission.closing() => return CloseReason::Shutdown,
+                read = socket.read(&mut discard) => match read {
+                    Ok(0) => return CloseReason::Eof,
+                    Ok(_) => {}
+                    Err(_) => return CloseReason::IoError,
+                },
+                changed = changes.recv() => match changed {
+                    Ok(changed) if changed != key => {}
+                    // Lagging means the key may have changed unseen.
+                    Ok(_) | Err(RecvError::Lagged(_)) => break,
+                    Err(RecvError::Closed) => return CloseReason::Shutdown,
+                },
+            }
+        }
+        // Changes already queued are covered by the values about to be read.
+        while let Ok(_) | Err(TryRecvError::Lagged(_)) = changes.try_recv() {}
+    }
+}
+
+/// Events a SUBSCRIBE_EVENTS push carries at most.
+const EVENT_BATCH: usize = 256;
+
+/// Pushes every event `feed` brings whose `1 << EVENT_*` bit `events` sets,
+/// batching those that queue up while a push is written, until the peer
+/// goes away or the server shuts down. Falling behind is pushed as a LAGGED
+/// event with the count missed. Like a subscription, the connection reads
+/// nothing more and is never reaped as idle.
+async fn serve_events<S>(
+    socket: &mut S,
+    events: u8,
+    mut feed: broadcast::Receiver<Event>,
+    id: Option<u32>,
+    framing: &mut Framing,
+    mut output: Vec<u8>,
+    admission: &Admission,
+) -> CloseReason
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let wanted = |event: Event| {
+        let pushed = event.pushed();
+        (events & 1 << pushed.event != 0).then_some(pushed)
+    };
+    let lagged = |missed| KeyspaceEvent {
+        event: EVENT_LAGGED,
+        key: 0,
+        count: missed,
+    };
+    let mut discard = vec![0; READ_CHUNK];
+    loop {
+        if socket.write_all(&output).await.is_err() {
+            return CloseReason::IoError;
+        }
+        output.clear();
+        let mut batch = Vec::new();
+        while batch.is_empty() {
+            tokio::select! {
+                biased;
+                _ = admission.closing() => return CloseReason::Shutdown,
+                read = socket.read(&mut discard) => match read {
+                    Ok(0) => return CloseReason::Eof,
+                    Ok(_) => {}
+                    Err(_) => return CloseReason::IoError,
+                },
+                next = feed.recv() => match next {
+                    Ok(event) => batch.extend(wanted(event)),
+                    Err(RecvError::Lagged(missed)) => batch.push(lagged(missed)),
+                    Err(RecvError::Closed) => return CloseReason::Shutdown,
+                },
+            }
+        }
+        while batch.len() < EVENT_BATCH {
+            match feed.try_recv() {
+                Ok(event) => batch.extend(wanted(event)),
+                Err(TryRecvError::Lagged(missed)) => batch.push(lagged(missed)),
+                Err(_) => break,
+            }
+        }
+        framing.encode_response(id, &Response::Events(batch), &mut output);
+    }
+}
+
+/// Commands a monitor push carries at most.
+const MONITOR_BATCH: usize = 256;
+
+/// Pushes every command any connection runs from now on, batching those
+/// that queue up while a push is written, until the peer goes away or the
+/// server shuts down. Like a subscription, the connection reads nothing
+/// more and is never reaped as idle.
+async fn serve_monitor<S>(
+    socket: &mut S,
+    id: Option<u32>,
+    framing: &mut Framing,
+    mut output: Vec<u8>,
+    admission: &Admission,
+) -> CloseReason
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut feed = admission.monitor.subscribe();
+    let mut discard = vec![0; READ_CHUNK];
+    loop {
+        if socket.write_all(&output).await.is_err() {
+            return CloseReason::IoError;
+        }
+        output.clear();
+        let first = loop {
+            tokio::select! {
+                biased;
+                _ = admission.closing() => return CloseReason::Shutdown,
+                read = socket.read(&mut discard) => match read {
+                    Ok(0) => return CloseReason::Eof,
+                    Ok(_) => {}
+                    Err(_) => return CloseReason::IoError,
+                },
+                next = feed.recv() => match next {
+                    Ok(command) => break command,
+                    Err(RecvError::Lagged(missed)) => debug!(missed, "monitor fell behind"),
+                    Err(RecvError::Closed) => return CloseReason::Shutdown,
+                },
             }
+        };
+        let mut batch = vec![first];
+        while batch.len() < MONITOR_BATCH {
+            match feed.try_recv() {
+                Ok(command) => batch.push(command),
+                Err(TryRecvError::Lagged(_)) => {}
+                Err(_) => break,
+            }
+        }
+        framing.encode_response(id, &Response::Monitored(batch), &mut output);
+    }
+}
+
+async fn serve_connection<S>(mut socket: S, sender: Dispatcher, admitted: Option<Admitted>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match admitted {
+        Some(admitted) => handle_connection(socket, sender, &admitted).await,
+        None => {
+            debug!("connection limit reached; turning a client away");
+            let _ = socket.write_u8(STATUS_UNAVAILABLE).await;
+            let _ = socket.shutdown().await;
+        }
+    }
+}
+
+/// Completes the TLS handshake, bounded by the frame timeout, before serving
+/// the connection; a failed handshake just drops it.
+async fn serve_tls(
+    acceptor: TlsAcceptor,
+    socket: TcpStream,
+    sender: Dispatcher,
+    admission: Arc<Admission>,
+    admitted: Option<Admitted>,
+) {
+    match tls::handshake(&acceptor, socket, admission.frame_timeout).await {
+        Ok(stream) => serve_connection(stream, sender, admitted).await,
+        Err(e) => {
+            admission
+                .tls_handshake_failures
+                .fetch_add(1, Ordering::Relaxed);
+            debug!(error = %e, "TLS handshake failed");
         }
     }
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> io::Result<()> {
-    let storage: StorageType = Arc::new(DashMap::new());
-    let (sender, receiver) = mpsc::unbounded_channel();
+fn unix_peer(socket: &UnixStream) -> Peer {
+    match socket.peer_cred() {
+        Ok(cred) => Peer {
+            uid: Some(cred.uid()),
+            pid: cred.pid(),
+            addr: None,
+        },
+        Err(_) => Peer::default(),
+    }
+}
+
+/// Logs a failed accept so the listener can carry on. Only when the failure
+/// is not the one connection's, as running out of file descriptors is, does
+/// it wait out [`ACCEPT_BACKOFF`] first, rather than spin until some close.
+async fn accept_failed(e: io::Error) {
+    warn!(error = %e, "cannot accept a connection");
+    if !matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+    ) {
+        tokio::time::sleep(ACCEPT_BACKOFF).await;
+    }
+}
+
+/// Accepts on one of the config file's extra Unix sockets until shutdown;
+/// the main socket has its own loop.
+async fn accept_unix(
+    listener: UnixListener,
+    sender: Dispatcher,
+    admission: Arc<Admission>,
+) -> io::Result<()> {
+    loop {
+        let accepted = async { (admission.queued_slot().await, listener.accept().await) };
+        let (queued, accepted) = tokio::select! {
+            _ = admission.closing() => return Ok(()),
+            accepted = accepted => accepted,
+        };
+        let (socket, _) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                accept_failed(e).await;
+                continue;
+            }
+        };
+        let peer = unix_peer(&socket);
+        tokio::spawn(serve_connection(
+            socket,
+            sender.clone(),
+            admission.admit(queued, peer),
+        ));
+    }
+}
 
-    tokio::spawn(command_processor(receiver, storage.clone()));
+async fn accept_tcp(
+    listener: TcpListener,
+    sender: Dispatcher,
+    admission: Arc<Admission>,
+    tls: Option<TlsAcceptor>,
+) -> io::Result<()> {
+    loop {
+        let accepted = async { (admission.queued_slot().await, listener.accept().await) };
+        let (queued, accepted) = tokio::select! {
+            _ = admission.closing() => return Ok(()),
+            accepted = accepted => accepted,
+        };
+        let (socket, addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                accept_failed(e).await;
+                continue;
+            }
+        };
+        if let Err(e) = socket.set_nodelay(true) {
+            warn!(error = %e, %addr, "cannot set TCP_NODELAY; dropped the connection");
+            continue;
+        }
+        let admitted = admission.admit(
+            queued,
+            Peer {
+                addr: Some(addr),
+                ..Peer::default()
+            },
+        );
+        match &tls {
+            Some(acceptor) => tokio::spawn(serve_tls(
+                acceptor.clone(),
+                socket,
+                sender.clone(),
+                admission.clone(),
+                admitted,
+            )),
+            None => tokio::spawn(serve_connection(socket, sender.clone(), admitted)),
+        };
+    }
+}
 
-    let addr = "/tmp/map8x32.sock";
+fn main() -> io::Result<()> {
+    let mut args = Args::parse();
+    if let Some(Tool::Selftest {
+        ops,
+        threads,
+        clients,
+    }) = args.tool.take()
+    {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        runtime.block_on(selftest::run(selftest::Load {
+            ops,
+            threads: threads.max(1),
+            clients: clients.max(1),
+        }));
+        return Ok(());
+    }
+    let config = Config::from_args(args)?;
+    logging::init(&config.log_level, config.log_format)?;
+    if config.check || config.repair {
+        let clean = check_data_dirs(&config)?;
+        if config.check {
+            std::process::exit(if clean { 0 } else { 1 });
+        }
+    }
+    if let Some(path) = &config.replay {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        return runtime.block_on(trace::replay(&config, path));
+    }
+    match config.transport {
+        Transport::Epoll => {
+            let runtime = if config.threads > 1 {
+                tokio::runtime::Builder::new_multi_thread()
+                    .worker_threads(config.threads)
+                    .enable_all()
+                    .build()?
+            } else {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()?
+            };
+            runtime.block_on(serve(config))
+        }
+        #[cfg(all(feature = "uring", target_os = "linux"))]
+        Transport::Uring => uring::run(config),
+        #[cfg(not(all(feature = "uring", target_os = "linux")))]
+        Transport::Uring => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "this build does not include the io_uring transport; rebuild with --features uring",
+        )),
+    }
+}
 
+async fn remove_stale_socket(addr: &str) -> io::Result<()> {
     if tokio::fs::try_exists(addr).await.unwrap_or(false) {
         tokio::fs::remove_file(addr).await?;
     }
+    Ok(())
+}
 
-    let listener = UnixListener::bind(addr)?;
-    
+async fn set_socket_permissions(addr: &str, mode: u32) -> io::Result<()> {
     let mut perms = tokio::fs::metadata(addr).await?.permissions();
-    perms.set_mode(0o666);
-    tokio::fs::set_permissions(addr, perms).await?;
+    perms.set_mode(mode);
+    tokio::fs::set_permissions(addr, perms).await
+}
 
-    loop {
-        let (socket, _) = listener.accept().await?;
-        let sender_clone = sender.clone();
+/// The listener `inherited` has for the socket at `addr`, or a new one.
+async fn bind_unix(addr: &str, mode: u32, inherited: &mut Inherited) -> io::Result<UnixListener> {
+    if let Some(listener) = inherited.unix(addr)? {
+        return Ok(listener);
+    }
+    remove_stale_socket(addr).await?;
+    let listener = UnixListener::bind(addr)?;
+    set_socket_permissions(addr, mode).await?;
+    Ok(listener)
+}
+
+/// Where database `id` keeps its files. Database 0 keeps the top of the
+/// data directory, so a server that only ever had one still finds them.
+fn database_dir(data_dir: &Path, id: usize) -> PathBuf {
+    match id {
+        0 => data_dir.to_path_buf(),
+        id => data_dir.join(format!("db{id}")),
+    }
+}
+
+/// Checks each database's data directory, or with `--repair` repairs it,
+/// printing what was recovered and discarded. Returns whether every one
+/// was clean.
+fn check_data_dirs(config: &Config) -> io::Result<bool> {
+    let Some(data_dir) = &config.data_dir else {
+        return Ok(true);
+    };
+    let keyring = encryption::Keyring::load(config.key_source())?;
+    let mut clean = true;
+    for id in 0..config.databases {
+        let dir = database_dir(data_dir, id);
+        if !dir.is_dir() {
+            continue;
+        }
+        let integrity = match config.repair {
+            true => persistence::repair(&dir, keyring.as_ref())?,
+            false => persistence::check(&dir, keyring.as_ref())?,
+        };
+        print!("{integrity}");
+        clean &= integrity.is_clean();
+    }
+    Ok(clean)
+}
+
+fn build_database(config: &Config, id: u8) -> io::Result<Database> {
+    let mut database = Database::new(id, config.db_max_memory);
+    database.max_keys = config.db_max_keys;
+    database.max_values = config.db_max_values;
+    database.map = Store::new(config.storage_order);
+    database.pack_above = config.pack_values_above;
+    database.transforms = Pipeline::new(config.transforms.clone());
+    database.caps = Caps::new(&config.caps);
+    database.rollups = Rollups::new(&config.rollups);
+    database.dump_dir = config.dump_dir.clone();
+    database.delete_all_token = config.delete_all_token;
+    database.write_batch = config.write_batch;
+    database.dead_letters = DeadLetters::new(config.dead_letters);
+    database.registry = Registry::new(config.strict_allocations, config.allocations.clone())
+        .map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid key allocations in config: {e}"),
+            )
+        })?;
+    Ok(database)
+}
+
+/// What the accept loop and the shutdown path share once the server is up.
+struct Server {
+    sender: Dispatcher,
+    admission: Arc<Admission>,
+    databases: Vec<StorageType>,
+    /// The datagram socket and the config file's extra Unix sockets,
+    /// removed on shutdown.
+    sockets: Vec<String>,
+    /// The TCP listeners and extra Unix sockets, for a handoff.
+    listening: Listening,
+    started: Instant,
+}
+
+impl Server {
+    /// Closes connections once they have answered what they have read, then
+    /// flushes the logs and reports the server's final state.
+    async fn shut_down(&self, config: &Config) {
+        info!("shutting down");
+        self.admission.close_connections(SHUTDOWN_GRACE).await;
+        if self.admission.active() > 0 {
+            warn!(
+                connections = self.admission.active(),
+                "connections still open after the shutdown grace period"
+            );
+        }
+        for persistence in self
+            .databases
+            .iter()
+            .filter_map(|database| database.persistence.as_ref())
+        {
+            persistence.sync();
+        }
+        if let Some(path) = &config.shm_mirror {
+            let _ = tokio::fs::remove_file(path).await;
+        }
+        let report =
+            ShutdownReport::collect(&self.databases, &self.admission, self.started.elapsed());
+        report.log();
+        if let Some(path) = &config.shutdown_report {
+            if let Err(e) = report.write_json(path) {
+                error!(error = %e, path = %path.display(), "failed to write shutdown report");
+            }
+        }
+    }
+
+    /// Removes the sockets the server bound, unless it handed them over.
+    async fn remove_sockets(&self) {
+        for socket in &self.sockets {
+            let _ = tokio::fs::remove_file(socket).await;
+        }
+    }
+}
+
+/// Resolves on SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+        }
+        Err(_) => {
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+/// Re-reads the config file on every SIGHUP and applies the settings that can
+/// change under live connections: log level, rate limit, connection limit and
+/// snapshot interval. Flags given on the command line still win; everything
+/// else needs a restart. A file that fails to load leaves the old settings.
+async fn reload_on_hangup(admission: Arc<Admission>, databases: Vec<StorageType>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!(error = %e, "cannot listen for SIGHUP; config reloads are disabled");
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        let config = match Args::try_parse()
+            .map_err(io::Error::other)
+            .and_then(Config::from_args)
+        {
+            Ok(config) => config,
+            Err(e) => {
+                error!(error = %e, "config reload failed; keeping the current settings");
+                continue;
+            }
+        };
+        if let Err(e) = logging::set_level(&config.log_level) {
+            error!(error = %e, "config reload could not change the log level");
+        }
+        admission.set_rate_limit(config.rate_limit, config.rate_limit_burst);
+        admission.set_max_connections(config.max_connections);
+        for persistence in databases
+            .iter()
+            .filter_map(|database| database.persistence.as_ref())
+        {
+            persistence
+                .snapshot_interval
+                .store(config.snapshot_interval, Ordering::Relaxed);
+            persistence
+                .wal_max_bytes
+                .store(config.wal_max_bytes.unwrap_or(0), Ordering::Relaxed);
+        }
+        info!(
+            log_level = %config.log_level,
+            rate_limit = config.rate_limit,
+            rate_limit_burst = config.rate_limit_burst,
+            max_connections = config.max_connections,
+            snapshot_interval = config.snapshot_interval,
+            wal_max_bytes = config.wal_max_bytes,
+            "reloaded configuration"
+        );
+    }
+}
+
+async fn start(config: &Config, inherited: &mut Inherited) -> io::Result<Server> {
+    let started = Instant::now();
+    let mut databases = Vec::with_capacity(config.databases);
+    let keyring = match &config.data_dir {
+        Some(_) => encryption::Keyring::load(config.key_source())?.map(Arc::new),
+        None => None,
+    };
+    if let Some(keyring) = &keyring {
+        info!(
+            key_id = format!("{:08x}", keyring.current_id()),
+            "encrypting snapshots and the WAL"
+        );
+    }
+    for id in 0..config.databases {
+        let mut database = build_database(config, id as u8)?;
+        if let Some(data_dir) = &config.data_dir {
+            let dir = database_dir(data_dir, id);
+            let (persistence, recovered) = Persistence::open(
+                &dir,
+                config.on_persistence_failure,
+                config.wal_fsync,
+                keyring.clone(),
+            )?;
+            for mutation in &recovered {
+                mutation.apply(&database);
+            }
+            info!(db = id, data_dir = %dir.display(), mutations = recovered.len(), "recovered store from disk");
+            persistence
+                .snapshot_interval
+                .store(config.snapshot_interval, Ordering::Relaxed);
+            persistence
+                .wal_max_bytes
+                .store(config.wal_max_bytes.unwrap_or(0), Ordering::Relaxed);
+            database.persistence = Some(persistence);
+        }
+        databases.push(Arc::new(database));
+    }
+    // With a data directory, what the old server synced to it is recovered
+    // as after any restart.
+    if config.data_dir.is_none() {
+        for (database, dump) in databases.iter().zip(&inherited.dumps) {
+            for mutation in dump.mutations(false, db::unix_ms()) {
+                mutation.apply(database);
+            }
+            info!(
+                db = database.id,
+                keys = dump.keys.len(),
+                "took over data from the old server"
+            );
+        }
+    }
+    if let Some(path) = &config.seed {
+        let seed = seed::read(path)?;
+        // A seed is where a fresh store starts, so it is not loaded again
+        // over what a restart recovered.
+        match databases[0].stats.keys.load(Ordering::Relaxed) {
+            0 => {
+                let values = seed::load(&databases[0], &seed)?;
+                info!(seed = %path.display(), values, "seeded database 0");
+            }
+            keys => {
+                info!(seed = %path.display(), keys, "database 0 already holds data; not seeding it")
+            }
+        }
+    }
+    let mut sender = Dispatcher::spawn(config.threads, &databases);
+    if config.command_timeout_ms > 0 {
+        sender = sender.with_timeout(Duration::from_millis(config.command_timeout_ms));
+    }
+    if let Some(path) = &config.trace {
+        sender = sender.with_recorder(Recorder::create(path)?);
+    }
+    let audit = match &config.audit_log {
+        Some(path) => Some(AuditLog::open(
+            path,
+            config.audit_log_max_bytes,
+            config.audit_log_keep,
+        )?),
+        None => None,
+    };
+    let tracer = match &config.otlp_endpoint {
+        Some(endpoint) => Some(otlp::Tracer::start(endpoint, config.otlp_sample_ratio)?),
+        None => None,
+    };
+    let capture = match &config.capture {
+        Some(path) => Some(capture::Capture::create(path)?),
+        None => None,
+    };
+    let admission = Admission::new(config, audit, tracer, capture);
+
+    for database in databases
+        .iter()
+        .filter(|database| database.persistence.is_some())
+    {
+        tokio::spawn(persistence::maintain(database.clone()));
+    }
+    tokio::spawn(reload_on_hangup(admission.clone(), databases.clone()));
+    let diagnostics_dir = config
+        .diagnostics_dir
+        .clone()
+        .unwrap_or_else(std::env::temp_dir);
+    tokio::spawn(diagnostics::dump_on_signal(
+        admission.clone(),
+        databases.clone(),
+        started,
+        diagnostics_dir,
+    ));
+    if config.expiry_sweep_ms > 0 {
+        tokio::spawn(db::sweep(
+            databases.clone(),
+            Duration::from_millis(config.expiry_sweep_ms),
+        ));
+    }
+    if let Some(endpoint) = &config.upload_endpoint {
+        #[cfg(feature = "s3-upload")]
+        upload::start(endpoint, config, &databases)?;
+        #[cfg(not(feature = "s3-upload"))]
+        return Err(io::Error::new(io::ErrorKind::Unsupported, format!("this build cannot upload snapshots to {endpoint}; rebuild with --features s3-upload")));
+    }
+    if let Some(target) = &config.change_sink {
+        sink::start(target, config.change_sink_buffer, &databases).await?;
+    }
+    if let Some(path) = &config.shm_mirror {
+        #[cfg(all(feature = "shm-mirror", unix))]
+        tokio::spawn(shm::maintain(
+            shm::create(path, config.shm_values_per_key)?,
+            databases[0].clone(),
+        ));
+        #[cfg(not(all(feature = "shm-mirror", unix)))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "this build cannot mirror into {}; rebuild with --features shm-mirror",
+                path.display()
+            ),
+        ));
+    }
+
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::acceptor(cert, key, config.tls_client_ca.as_deref())?),
+        _ => None,
+    };
+    let listeners = config
+        .listeners
+        .iter()
+        .filter_map(|listener| listener.tcp.as_ref());
+    let mut listening = Listening::default();
+    for tcp_addr in config.tcp.iter().chain(listeners) {
+        let tcp_listener = match inherited.tcp(tcp_addr)? {
+            Some(listener) => listener,
+            None => TcpListener::bind(tcp_addr).await?,
+        };
+        listening.tcp(tcp_addr, &tcp_listener)?;
+        let accept = accept_tcp(tcp_listener, sender.clone(), admission.clone(), tls.clone());
+        let tcp_addr = tcp_addr.clone();
+        tokio::spawn(async move {
+            if let Err(e) = accept.await {
+                error!(error = %e, tcp = tcp_addr, "TCP listener failed; no longer accepting TCP connections on it");
+            }
+        });
+    }
+
+    let mut sockets = Vec::new();
+    if let Some(addr) = &config.datagram {
+        remove_stale_socket(addr).await?;
+        let socket = UnixDatagram::bind(addr)?;
+        set_socket_permissions(addr, SOCKET_MODE).await?;
+        sockets.push(addr.clone());
+        let serve = datagram::serve(socket, sender.clone(), admission.clone());
+        tokio::spawn(async move {
+            if let Err(e) = serve.await {
+                error!(error = %e, "datagram socket failed; no longer accepting datagram SETs");
+            }
+        });
+    }
+    for listener in &config.listeners {
+        let Some(addr) = &listener.socket else {
+            continue;
+        };
+        let unix_listener =
+            bind_unix(addr, listener.mode.unwrap_or(SOCKET_MODE), inherited).await?;
+        listening.unix(addr, &unix_listener)?;
+        sockets.push(addr.clone());
+        let accept = accept_unix(unix_listener, sender.clone(), admission.clone());
+        let addr = addr.clone();
+        tokio::spawn(async move {
+            if let Err(e) = accept.await {
+                error!(error = %e, socket = addr, "socket listener failed; no longer accepting connections on it");
+            }
+        });
+    }
+
+    if let Some(resp_addr) = &config.resp {
+        let resp_listener = TcpListener::bind(resp_addr).await?;
+        let serve = resp::serve(resp_listener, sender.clone(), admission.clone());
+        tokio::spawn(async move {
+            if let Err(e) = serve.await {
+                error!(error = %e, "RESP listener failed; no longer accepting Redis protocol connections");
+            }
+        });
+    }
+
+    if let Some(websocket_addr) = &config.websocket {
+        let websocket_listener = TcpListener::bind(websocket_addr).await?;
+        let serve = websocket::serve(
+            websocket_listener,
+            sender.clone(),
+            databases.clone(),
+            admission.clone(),
+        );
+        tokio::spawn(async move {
+            if let Err(e) = serve.await {
+                error!(error = %e, "WebSocket listener failed; no longer accepting WebSocket connections");
+            }
+        });
+    }
 
-        tokio::spawn(handle_connection(socket, sender_clone));
+    if let Some(metrics_addr) = &config.metrics {
+        let metrics_listener = TcpListener::bind(metrics_addr).await?;
+        let serve = http::serve(metrics_listener, databases.clone(), admission.clone());
+        tokio::spawn(async move {
+            if let Err(e) = serve.await {
+                error!(error = %e, "metrics listener failed");
+            }
+        });
+    }
+
+    if config.self_test {
+        selftest::run(selftest::Load {
+            threads: config.threads,
+            ..selftest::Load::default()
+        })
+        .await;
+    }
+
+    info!(
+        version = env!("CARGO_PKG_VERSION"),
+        socket = %config.socket,
+        tcp = config.tcp.as_deref(),
+        listeners = config.listeners.len(),
+        datagram = config.datagram.as_deref(),
+        shm_mirror = config.shm_mirror.as_ref().map(|path| path.display().to_string()),
+        change_sink = config.change_sink.as_deref(),
+        tls = config.tls_cert.is_some(),
+        metrics = config.metrics.as_deref(),
+        resp = config.resp.as_deref(),
+        websocket = config.websocket.as_deref(),
+        capture = config.capture.as_ref().map(|path| path.display().to_string()),
+        otlp_endpoint = config.otlp_endpoint.as_deref(),
+        threads = config.threads,
+        databases = config.databases,
+        transport = ?config.transport,
+        persistence = config.data_dir.is_some(),
+        "map8x32 server listening"
+    );
+    Ok(Server {
+        sender,
+        admission,
+        databases,
+        sockets,
+        listening,
+        started,
+    })
+}
+
+async fn serve(config: Config) -> io::Result<()> {
+    let addr = config.socket.as_str();
+    let mut inherited = match &config.handoff {
+        Some(path) => handoff::take_over(path, config.databases)
+            .await?
+            .unwrap_or_default(),
+        None => Inherited::default(),
+    };
+    let listener = bind_unix(addr, SOCKET_MODE, &mut inherited).await?;
+
+    let mut server = start(&config, &mut inherited).await?;
+    server.listening.unix(addr, &listener)?;
+    let handoff = match &config.handoff {
+        Some(path) => Some(handoff::listen(path).await?),
+        None => None,
+    };
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    let successor = loop {
+        let accepted = async {
+            (
+                server.admission.queued_slot().await,
+                listener.accept().await,
+            )
+        };
+        let (queued, accepted) = tokio::select! {
+            _ = &mut shutdown => break None,
+            successor = handoff::successor(handoff.as_ref(), config.databases) => break Some(successor),
+            accepted = accepted => accepted,
+        };
+        let (socket, _) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                accept_failed(e).await;
+                continue;
+            }
+        };
+        let sender_clone = server.sender.clone();
+        let peer = unix_peer(&socket);
+
+        tokio::spawn(serve_connection(
+            socket,
+            sender_clone,
+            server.admission.admit(queued, peer),
+        ));
+    };
+
+    server.shut_down(&config).await;
+    match successor {
+        Some(successor) => {
+            handoff::hand_over(successor, &server.listening, &server.databases).await?
+        }
+        None => {
+            server.remove_sockets().await;
+            let _ = tokio::fs::remove_file(addr).await;
+            if let Some(path) = &config.handoff {
+                let _ = tokio::fs::remove_file(path).await;
+            }
+        }
     }
-}
\ No newline at end of file
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use crate::admission::Peer;
+use crate::trace;
+pub use map8x32_protocol::codec::MonitoredOp;
+use map8x32_protocol::codec::{self, Request, CODEC_NATIVE};
+use map8x32_protocol::*;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+/// Commands a monitor may fall behind by before it misses some.
+const MONITOR_BACKLOG: usize = 4096;
+
+/// Fans every command a connection runs out to the connections that sent
+/// MONITOR. Commands are only described while someone is listening.
+#[derive(Debug)]
+pub struct Monitor {
+    feed: broadcast::Sender<MonitoredOp>,
+}
+
+/// The parts of a request a monitor reports, taken before it runs.
+#[derive(Debug)]
+pub struct Watched {
+    op: u8,
+    key: u8,
+    value: u32,
+    command: String,
+}
+
+impl Monitor {
+    pub fn new() -> Self {
+        Monitor {
+            feed: broadcast::channel(MONITOR_BACKLOG).0,
+        }
+    }
+
+    /// Every command from now on. A monitor that lags by more than the
+    /// backlog is told how many it missed.
+    pub fn subscribe(&self) -> broadcast::Receiver<MonitoredOp> {
+        self.feed.subscribe()
+    }
+
+    /// Notes `request` for [`Monitor::finish`], or `None` while no
+    /// connection is monitoring.
+    pub fn start(&self, request: &Request) -> Option<Watched> {
+        if self.feed.receiver_count() == 0 {
+            return None;
+        }
+        let mut frame = Vec::with_capacity(FRAME_LEN);
+        codec::select(CODEC_NATIVE, PROTOCOL_VERSION)
+            .expect("the native codec always exists")
+            .encode_request(request, &mut frame);
+        Some(Watched {
+            op: request.op(),
+            key: request.key().unwrap_or(0),
+            value: Frame::decode(frame[..FRAME_LEN].try_into().unwrap()).value,
+            command: trace::describe(request),
+        })
+    }
+
+    /// Reports a noted request, answered with `status` after `elapsed`.
+    pub fn finish(&self, watched: Watched, peer: &Peer, db: u8, status: u8, elapsed: Duration) {
+        let at_unix_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let _ = self.feed.send(MonitoredOp {
+            at_unix_ms,
+            db,
+            op: watched.op,
+            key: watched.key,
+            value: watched.value,
+            status,
+            micros: elapsed.as_micros() as u64,
+            peer: peer.to_string(),
+            command: watched.command,
+        });
+    }
+}
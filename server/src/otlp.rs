@@ -0,0 +1,310 @@
+//! A span per request, from its first bytes arriving to its answer being
+//! written, exported to an OpenTelemetry collector over OTLP/HTTP as JSON.
+//! Each has a child for each phase: `read` until the request is decoded,
+//! `queue` until a command processor takes it, `process` until the
+//! processor finishes, and `write` until its answer is written.
+
+pub use map8x32_core::dispatch::Phases;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+/// Finished spans waiting for the exporter; more are dropped.
+const QUEUE: usize = 4096;
+
+/// A traced request that has been answered, waiting for the answer to be
+/// written.
+#[derive(Debug)]
+pub struct Answered {
+    phases: Arc<Phases>,
+    db: u8,
+    status: u8,
+    at: Instant,
+}
+
+impl Answered {
+    /// Ends the processing of a request answered with `status` on `db`.
+    pub fn new(phases: Arc<Phases>, db: u8, status: u8) -> Answered {
+        Answered {
+            phases,
+            db,
+            status,
+            at: Instant::now(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Finished {
+    answered: Answered,
+    written: Instant,
+}
+
+/// Starts spans for a share of requests and hands the finished ones to the
+/// exporter.
+#[derive(Debug)]
+pub struct Tracer {
+    spans: mpsc::Sender<Finished>,
+    /// Requests traced, out of every 2^32.
+    sampled: u64,
+    ids: Ids,
+    pub dropped: AtomicU64,
+}
+
+impl Tracer {
+    /// Exports to the OTLP/HTTP collector at `endpoint`, a `host:port`,
+    /// tracing `ratio` of the requests.
+    pub fn start(endpoint: &str, ratio: f64) -> io::Result<Tracer> {
+        if !cfg!(feature = "otlp") {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "this build cannot export traces to {endpoint}; rebuild with --features otlp"
+                ),
+            ));
+        }
+        let (spans, _queued) = mpsc::channel(QUEUE);
+        #[cfg(feature = "otlp")]
+        tokio::spawn(export::run(endpoint.to_string(), _queued, Clock::now()));
+        Ok(Tracer {
+            spans,
+            sampled: (ratio * (1u64 << 32) as f64) as u64,
+            ids: Ids::seeded(),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Phases to fill in for a request of `op` on `key` whose first bytes
+    /// arrived at `arrived`, or `None` if it is not sampled.
+    pub fn start_span(&self, op: u8, key: Option<u8>, arrived: Instant) -> Option<Arc<Phases>> {
+        (self.ids.next() >> 32 < self.sampled).then(|| {
+            Arc::new(Phases {
+                op,
+                key,
+                arrived,
+                decoded: Instant::now(),
+                processed: Mutex::new(None),
+            })
+        })
+    }
+
+    /// Ends the spans of requests whose answers were just written.
+    pub fn written(&self, answered: &mut Vec<Answered>) {
+        let written = Instant::now();
+        for answered in answered.drain(..) {
+            if self.spans.try_send(Finished { answered, written }).is_err() {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Random trace and span IDs: SplitMix64 from a seed taken from the clock
+/// and the process ID.
+#[derive(Debug)]
+struct Ids(AtomicU64);
+
+impl Ids {
+    fn seeded() -> Ids {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        Ids(AtomicU64::new(
+            nanos ^ (u64::from(std::process::id()) << 32),
+        ))
+    }
+
+    fn next(&self) -> u64 {
+        let mut z = self
+            .0
+            .fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed)
+            .wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Turns `Instant`s into Unix nanoseconds, against one reading of both
+/// clocks.
+#[derive(Debug, Clone, Copy)]
+struct Clock {
+    instant: Instant,
+    unix_nanos: u64,
+}
+
+impl Clock {
+    fn now() -> Clock {
+        Clock {
+            instant: Instant::now(),
+            unix_nanos: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64,
+        }
+    }
+
+    fn unix_nanos(&self, at: Instant) -> u64 {
+        let since = at.saturating_duration_since(self.instant).as_nanos() as u64;
+        let before = self.instant.saturating_duration_since(at).as_nanos() as u64;
+        (self.unix_nanos + since).saturating_sub(before)
+    }
+}
+
+#[cfg(feature = "otlp")]
+mod export {
+    use super::{Clock, Finished, Ids};
+    use serde_json::{json, Value};
+    use std::io;
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::sync::mpsc;
+
+    /// Spans sent in one export request at most.
+    const BATCH: usize = 512;
+    /// How long spans wait to be batched with others.
+    const INTERVAL: Duration = Duration::from_secs(1);
+    /// How long the collector has to take a batch.
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Posts the spans in batches until every tracer is gone.
+    pub async fn run(endpoint: String, mut spans: mpsc::Receiver<Finished>, clock: Clock) {
+        let ids = Ids::seeded();
+        let mut batch = Vec::with_capacity(BATCH);
+        let mut tick = tokio::time::interval(INTERVAL);
+        loop {
+            let room = BATCH - batch.len();
+            let (open, due) = tokio::select! {
+                received = spans.recv_many(&mut batch, room) => (received > 0, received == room),
+                _ = tick.tick() => (true, true),
+            };
+            if (due || !open) && !batch.is_empty() {
+                let body = encode(&batch, &ids, clock).to_string();
+                let sent = batch.len();
+                batch.clear();
+                match tokio::time::timeout(TIMEOUT, post(&endpoint, body.as_bytes())).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => {
+                        tracing::debug!(error = %e, endpoint, spans = sent, "failed to export spans")
+                    }
+                    Err(_) => tracing::debug!(endpoint, spans = sent, "timed out exporting spans"),
+                }
+            }
+            if !open {
+                return;
+            }
+        }
+    }
+
+    async fn post(endpoint: &str, body: &[u8]) -> io::Result<()> {
+        let mut stream = TcpStream::connect(endpoint).await?;
+        let head = format!(
+            "POST /v1/traces HTTP/1.1\r\nHost: {endpoint}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(body).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        let status = response
+            .split(|&b| b == b' ')
+            .nth(1)
+            .and_then(|code| std::str::from_utf8(code).ok());
+        match status {
+            Some(code) if code.starts_with('2') => Ok(()),
+            status => Err(io::Error::other(format!(
+                "collector answered {}",
+                status.unwrap_or("nothing")
+            ))),
+        }
+    }
+
+    fn int(value: u64) -> Value {
+        json!({ "intValue": value.to_string() })
+    }
+
+    fn span(
+        name: &str,
+        trace_id: &str,
+        id: u64,
+        parent: Option<u64>,
+        start: u64,
+        end: u64,
+        attributes: Value,
+    ) -> Value {
+        json!({
+            "traceId": trace_id,
+            "spanId": format!("{id:016x}"),
+            "parentSpanId": parent.map(|parent| format!("{parent:016x}")).unwrap_or_default(),
+            "name": name,
+            "kind": if parent.is_some() { 1 } else { 2 },
+            "startTimeUnixNano": start.to_string(),
+            "endTimeUnixNano": end.to_string(),
+            "attributes": attributes,
+        })
+    }
+
+    /// An OTLP `ExportTraceServiceRequest` carrying `batch`.
+    fn encode(batch: &[Finished], ids: &Ids, clock: Clock) -> Value {
+        let mut spans = Vec::with_capacity(batch.len() * 5);
+        for finished in batch {
+            let answered = &finished.answered;
+            let phases = &answered.phases;
+            let trace_id = format!("{:016x}{:016x}", ids.next(), ids.next());
+            let root = ids.next();
+            let at = |instant: Instant| clock.unix_nanos(instant);
+            let mut attributes = vec![
+                json!({ "key": "map8x32.op", "value": int(u64::from(phases.op)) }),
+                json!({ "key": "map8x32.db", "value": int(u64::from(answered.db)) }),
+                json!({ "key": "map8x32.status", "value": int(u64::from(answered.status)) }),
+            ];
+            if let Some(key) = phases.key {
+                attributes.push(json!({ "key": "map8x32.key", "value": int(u64::from(key)) }));
+            }
+            spans.push(span(
+                "map8x32.request",
+                &trace_id,
+                root,
+                None,
+                at(phases.arrived),
+                at(finished.written),
+                attributes.into(),
+            ));
+            let mut child = |name, start, end| {
+                spans.push(span(
+                    name,
+                    &trace_id,
+                    ids.next(),
+                    Some(root),
+                    at(start),
+                    at(end),
+                    json!([]),
+                ))
+            };
+            child("read", phases.arrived, phases.decoded);
+            match *phases.processed.lock().unwrap() {
+                Some((taken, done)) => {
+                    child("queue", phases.decoded, taken);
+                    child("process", taken, done);
+                }
+                None => child("process", phases.decoded, answered.at),
+            }
+            child("write", answered.at, finished.written);
+        }
+        json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{ "key": "service.name", "value": { "stringValue": "map8x32" } }],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "map8x32-server", "version": env!("CARGO_PKG_VERSION") },
+                    "spans": spans,
+                }],
+            }],
+        })
+    }
+}
@@ -0,0 +1,106 @@
+use crate::admission::Admission;
+use crate::db::Database;
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Final state of the server, printed when it shuts down gracefully.
+#[derive(Debug)]
+pub struct ShutdownReport {
+    pub uptime: Duration,
+    pub ops: Vec<(&'static str, u64)>,
+    pub peak_connections: u64,
+    pub peak_memory_bytes: u64,
+    pub snapshots: Option<u64>,
+    /// WAL segment generation and byte offset of its end, for database 0.
+    pub wal_position: Option<(u64, u64)>,
+}
+
+impl ShutdownReport {
+    /// Op counts, peak memory and snapshots are summed over `databases`.
+    pub fn collect(databases: &[Arc<Database>], admission: &Admission, uptime: Duration) -> Self {
+        let mut ops: Vec<(&'static str, u64)> = Vec::new();
+        for database in databases {
+            for (i, (op, counter)) in database.stats.ops().into_iter().enumerate() {
+                match ops.get_mut(i) {
+                    Some((_, count)) => *count += counter.load(Ordering::Relaxed),
+                    None => ops.push((op, counter.load(Ordering::Relaxed))),
+                }
+            }
+        }
+        let persisted = || databases.iter().filter_map(|db| db.persistence.as_ref());
+        ShutdownReport {
+            uptime,
+            ops,
+            peak_connections: admission.peak.load(Ordering::Relaxed),
+            peak_memory_bytes: databases
+                .iter()
+                .map(|db| db.stats.peak_memory_bytes.load(Ordering::Relaxed))
+                .sum(),
+            snapshots: persisted()
+                .map(|p| p.snapshots.load(Ordering::Relaxed))
+                .reduce(|a, b| a + b),
+            wal_position: persisted().next().map(|p| p.wal_position()),
+        }
+    }
+
+    pub fn log(&self) {
+        let ops: Vec<String> = self
+            .ops
+            .iter()
+            .map(|(op, count)| format!("{op}={count}"))
+            .collect();
+        tracing::info!(
+            "shutdown after {:.1}s: ops {}",
+            self.uptime.as_secs_f64(),
+            ops.join(" ")
+        );
+        tracing::info!(
+            "shutdown: peak connections {}, peak memory {} bytes",
+            self.peak_connections,
+            self.peak_memory_bytes
+        );
+        if let (Some(snapshots), Some((generation, offset))) = (self.snapshots, self.wal_position) {
+            tracing::info!(
+                "shutdown: {snapshots} snapshots taken, WAL ends at segment {generation} offset {offset}"
+            );
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\n");
+        let _ = writeln!(out, "  \"uptime_secs\": {:.3},", self.uptime.as_secs_f64());
+        let ops: Vec<String> = self
+            .ops
+            .iter()
+            .map(|(op, count)| format!("\"{op}\": {count}"))
+            .collect();
+        let _ = writeln!(out, "  \"ops\": {{ {} }},", ops.join(", "));
+        let _ = writeln!(out, "  \"peak_connections\": {},", self.peak_connections);
+        let _ = writeln!(out, "  \"peak_memory_bytes\": {},", self.peak_memory_bytes);
+        match self.snapshots {
+            Some(snapshots) => {
+                let _ = writeln!(out, "  \"snapshots\": {snapshots},");
+            }
+            None => out.push_str("  \"snapshots\": null,\n"),
+        }
+        match self.wal_position {
+            Some((generation, offset)) => {
+                let _ = writeln!(
+                    out,
+                    "  \"wal\": {{ \"segment\": {generation}, \"offset\": {offset} }}"
+                );
+            }
+            None => out.push_str("  \"wal\": null\n"),
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    pub fn write_json(&self, path: &Path) -> io::Result<()> {
+        std::fs::write(path, self.to_json())
+    }
+}
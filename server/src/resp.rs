@@ -0,0 +1,505 @@
+use crate::admission::{Admission, Admitted, CloseReason, Peer};
+use crate::dispatch::Dispatcher;
+use crate::fill;
+use crate::session::Session;
+use map8x32_protocol::codec::{Request, Response, MAX_MESSAGE_LEN};
+use map8x32_protocol::*;
+use std::io;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, warn, Instrument};
+
+/// Arguments a single command may carry.
+const MAX_ARGS: usize = 1 << 16;
+
+/// The longest KEYS pattern matched. Keys are at most three digits, so
+/// anything longer only costs time.
+const MAX_PATTERN_LEN: usize = 1024;
+
+/// A command's arguments, the first being its name.
+type Args = Vec<Vec<u8>>;
+
+/// Splits the next complete command off `input`: a RESP array of bulk
+/// strings, as Redis clients send, or an inline command line as typed
+/// into telnet. `Ok(None)` means more bytes are needed.
+fn parse(input: &[u8]) -> Result<Option<(Args, usize)>, &'static str> {
+    fn line(input: &[u8], from: usize) -> Option<(&[u8], usize)> {
+        let len = input[from..].iter().position(|&b| b == b'\n')?;
+        let line = &input[from..from + len];
+        Some((line.strip_suffix(b"\r").unwrap_or(line), from + len + 1))
+    }
+    fn number(line: &[u8]) -> Result<i64, &'static str> {
+        std::str::from_utf8(line)
+            .ok()
+            .and_then(|text| text.parse().ok())
+            .ok_or("invalid length")
+    }
+
+    if input.first() != Some(&b'*') {
+        if !input.contains(&b'\n') {
+            return match input.len() > MAX_MESSAGE_LEN {
+                true => Err("too big inline request"),
+                false => Ok(None),
+            };
+        }
+        let (line, end) = line(input, 0).unwrap();
+        let args = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some((args, end)));
+    }
+    let Some((header, mut at)) = line(input, 1) else {
+        return Ok(None);
+    };
+    let count = number(header)?;
+    if count > MAX_ARGS as i64 {
+        return Err("too many arguments");
+    }
+    let mut args = Vec::with_capacity(count.max(0) as usize);
+    for _ in 0..count.max(0) {
+        if at >= input.len() {
+            return Ok(None);
+        }
+        if input[at] != b'$' {
+            return Err("expected '$'");
+        }
+        let Some((header, start)) = line(input, at + 1) else {
+            return Ok(None);
+        };
+        let len = number(header)?;
+        if !(0..=MAX_MESSAGE_LEN as i64).contains(&len) {
+            return Err("invalid bulk length");
+        }
+        let end = start + len as usize;
+        if input.len() < end + 2 {
+            return Ok(None);
+        }
+        args.push(input[start..end].to_vec());
+        at = end + 2;
+    }
+    Ok(Some((args, at)))
+}
+
+fn simple(out: &mut Vec<u8>, text: &str) {
+    out.extend_from_slice(format!("+{text}\r\n").as_bytes());
+}
+
+fn error(out: &mut Vec<u8>, text: &str) {
+    out.extend_from_slice(format!("-{text}\r\n").as_bytes());
+}
+
+fn integer(out: &mut Vec<u8>, n: usize) {
+    out.extend_from_slice(format!(":{n}\r\n").as_bytes());
+}
+
+fn array(out: &mut Vec<u8>, len: usize) {
+    out.extend_from_slice(format!("*{len}\r\n").as_bytes());
+}
+
+fn bulk(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+    out.extend_from_slice(bytes);
+    out.extend_from_slice(b"\r\n");
+}
+
+fn status_error(status: u8) -> &'static str {
+    match status {
+        STATUS_CAPACITY_EXCEEDED => "OOM database memory quota exceeded",
+        STATUS_KEY_NOT_ALLOCATED => "ERR key is outside every registered allocation",
+        STATUS_CONFLICT => "WRONGTYPE key holds typed values",
+        STATUS_READONLY => "READONLY You can't write against a read only instance.",
+        STATUS_UNAVAILABLE => "ERR server is shedding writes while persistence fails",
+        STATUS_THROTTLED => "ERR rate limit exceeded",
+        STATUS_UNAUTHORIZED => "NOAUTH Authentication required.",
+        STATUS_INTERNAL_ERROR => "ERR internal server error",
+        STATUS_TIMEOUT => "ERR command timed out",
+        _ => "ERR request rejected",
+    }
+}
+
+fn key(arg: &[u8]) -> Result<u8, String> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|text| text.parse().ok())
+        .ok_or_else(|| "ERR keys are integers from 0 to 255".to_string())
+}
+
+fn value(arg: &[u8]) -> Result<u32, String> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|text| text.parse().ok())
+        .ok_or_else(|| "ERR values are integers from 0 to 4294967295".to_string())
+}
+
+fn index(arg: &[u8]) -> Result<i64, String> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|text| text.parse().ok())
+        .ok_or_else(|| "ERR value is not an integer or out of range".to_string())
+}
+
+/// Whether `text` matches a KEYS pattern: `*` for any run of characters,
+/// `?` for any one, everything else literally. Only the last `*` seen is
+/// ever retried, and a run of them counts as one, so this takes time linear
+/// in the pattern for a given text.
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Just past the last `*`, and where in the text it stopped matching.
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                while pattern.get(p) == Some(&b'*') {
+                    p += 1;
+                }
+                star = Some((p, t));
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((after, matched)) => {
+                    p = after;
+                    t = matched + 1;
+                    star = Some((after, t));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// The values LRANGE's inclusive `start..=stop` selects out of `len`, either
+/// bound counting back from the end when negative.
+fn range(len: usize, start: i64, stop: i64) -> std::ops::Range<usize> {
+    let resolve = |i: i64| if i < 0 { len as i64 + i } else { i };
+    let start = resolve(start).max(0);
+    let stop = resolve(stop).min(len as i64 - 1);
+    if start > stop {
+        0..0
+    } else {
+        start as usize..stop as usize + 1
+    }
+}
+
+/// The commands understood, with their arguments parsed.
+#[derive(Debug)]
+enum Command {
+    Ping(Option<Vec<u8>>),
+    Quit,
+    Docs,
+    Auth(Vec<u8>),
+    Select(Option<u8>),
+    RPush { key: u8, values: Vec<u32> },
+    LRange { key: u8, start: i64, stop: i64 },
+    LLen { key: u8 },
+    Del { keys: Vec<u8> },
+    FlushAll { token: u32 },
+    Keys { pattern: Vec<u8> },
+}
+
+impl Command {
+    fn parse(args: &[Vec<u8>]) -> Result<Command, String> {
+        let Some((name, args)) = args.split_first() else {
+            return Err("ERR empty command".to_string());
+        };
+        let name = String::from_utf8_lossy(name).to_ascii_lowercase();
+        let arity = |min: usize, max: usize| {
+            if (min..=max).contains(&args.len()) {
+                Ok(())
+            } else {
+                Err(format!(
+                    "ERR wrong number of arguments for '{name}' command"
+                ))
+            }
+        };
+        Ok(match name.as_str() {
+            "ping" => {
+                arity(0, 1)?;
+                Command::Ping(args.first().cloned())
+            }
+            "quit" => Command::Quit,
+            // redis-cli asks for command docs on start; it copes without.
+            "command" => Command::Docs,
+            "auth" => {
+                arity(1, 2)?;
+                Command::Auth(args[args.len() - 1].clone())
+            }
+            "select" => {
+                arity(1, 1)?;
+                Command::Select(
+                    std::str::from_utf8(&args[0])
+                        .ok()
+                        .and_then(|db| db.parse().ok()),
+                )
+            }
+            "rpush" => {
+                arity(2, usize::MAX)?;
+                Command::RPush {
+                    key: key(&args[0])?,
+                    values: args[1..]
+                        .iter()
+                        .map(|arg| value(arg))
+                        .collect::<Result<_, _>>()?,
+                }
+            }
+            "lrange" => {
+                arity(3, 3)?;
+                Command::LRange {
+                    key: key(&args[0])?,
+                    start: index(&args[1])?,
+                    stop: index(&args[2])?,
+                }
+            }
+            "llen" => {
+                arity(1, 1)?;
+                Command::LLen {
+                    key: key(&args[0])?,
+                }
+            }
+            "del" => {
+                arity(1, usize::MAX)?;
+                Command::Del {
+                    keys: args.iter().map(|arg| key(arg)).collect::<Result<_, _>>()?,
+                }
+            }
+            "flushall" | "flushdb" => {
+                arity(0, 1)?;
+                // The confirmation token, if given; ASYNC and SYNC carry none.
+                let token = args
+                    .first()
+                    .and_then(|arg| std::str::from_utf8(arg).ok()?.parse().ok());
+                Command::FlushAll {
+                    token: token.unwrap_or(0),
+                }
+            }
+            "keys" => {
+                arity(1, 1)?;
+                if args[0].len() > MAX_PATTERN_LEN {
+                    return Err(format!("ERR pattern longer than {MAX_PATTERN_LEN} bytes"));
+                }
+                Command::Keys {
+                    pattern: args[0].clone(),
+                }
+            }
+            _ => return Err(format!("ERR unknown command '{name}'")),
+        })
+    }
+}
+
+/// Answers one command of `size` bytes into `out`. Returns `Some(false)`
+/// once the connection should close.
+async fn answer(
+    session: &mut Session<'_>,
+    args: Args,
+    size: usize,
+    out: &mut Vec<u8>,
+) -> Option<bool> {
+    if args.is_empty() {
+        return Some(true);
+    }
+    if !session.admitted.allow() {
+        error(out, status_error(STATUS_THROTTLED));
+        return Some(true);
+    }
+    let command = match Command::parse(&args) {
+        Ok(command) => command,
+        Err(message) => {
+            error(out, &message);
+            return Some(true);
+        }
+    };
+    if session.access.is_none()
+        && !matches!(
+            command,
+            Command::Ping(_) | Command::Quit | Command::Docs | Command::Auth(_)
+        )
+    {
+        error(out, status_error(STATUS_UNAUTHORIZED));
+        return Some(true);
+    }
+    match command {
+        Command::Ping(Some(message)) => bulk(out, &message),
+        Command::Ping(None) => simple(out, "PONG"),
+        Command::Quit => {
+            simple(out, "OK");
+            return Some(false);
+        }
+        Command::Docs => array(out, 0),
+        Command::Auth(token) => match session.authenticate(&token) {
+            true => simple(out, "OK"),
+            false => error(out, "WRONGPASS invalid username-password pair"),
+        },
+        Command::Select(db) => match db.is_some_and(|db| session.select(db)) {
+            true => simple(out, "OK"),
+            false => error(out, "ERR DB index is out of range"),
+        },
+        Command::RPush { key, values } => {
+            match session.run(Request::Push { key, values }, size).await? {
+                Response::Removed { value: held, .. } => integer(out, held as usize),
+                response => error(out, status_error(response.status())),
+            }
+        }
+        Command::LRange { key, start, stop } => match session.values(key, size).await? {
+            Ok(values) => {
+                let selected = &values[range(values.len(), start, stop)];
+                array(out, selected.len());
+                for value in selected {
+                    bulk(out, value.to_string().as_bytes());
+                }
+            }
+            Err(status) => error(out, status_error(status)),
+        },
+        Command::LLen { key } => match session.values(key, size).await? {
+            Ok(values) => integer(out, values.len()),
+            Err(status) => error(out, status_error(status)),
+        },
+        Command::Del { keys } => {
+            let mut deleted = 0;
+            for key in keys {
+                match session
+                    .run(Request::DeleteByKey { key }, size)
+                    .await?
+                    .status()
+                {
+                    STATUS_OK => deleted += 1,
+                    STATUS_NOT_FOUND => {}
+                    status => {
+                        error(out, status_error(status));
+                        return Some(true);
+                    }
+                }
+            }
+            integer(out, deleted);
+        }
+        Command::FlushAll { token } => match session
+            .run(Request::DeleteAll { token }, size)
+            .await?
+            .status()
+        {
+            STATUS_OK => simple(out, "OK"),
+            status => error(out, status_error(status)),
+        },
+        Command::Keys { pattern } => match session
+            .run(Request::ListAll { snapshot: false }, size)
+            .await?
+        {
+            Response::Entries(mut entries) => {
+                entries.sort_unstable_by_key(|(key, _)| *key);
+                let keys: Vec<String> = entries
+                    .iter()
+                    .map(|(key, _)| key.to_string())
+                    .filter(|key| glob(&pattern, key.as_bytes()))
+                    .collect();
+                array(out, keys.len());
+                for key in keys {
+                    bulk(out, key.as_bytes());
+                }
+            }
+            response => error(out, status_error(response.status())),
+        },
+    }
+    Some(true)
+}
+
+async fn serve_connection(mut socket: TcpStream, sender: Dispatcher, admitted: Admitted) {
+    let reason = tokio::select! {
+        reason = serve_commands(&mut socket, sender, &admitted) => reason,
+        _ = admitted.activity.killed() => CloseReason::Killed,
+    };
+    let _ = socket.shutdown().await;
+    admitted.closed(reason);
+    debug!(reason = reason.name(), "RESP connection closed");
+}
+
+/// Answers commands until the connection ends, returning why it did.
+async fn serve_commands(
+    socket: &mut TcpStream,
+    sender: Dispatcher,
+    admitted: &Admitted,
+) -> CloseReason {
+    let admission = admitted.admission();
+    let mut session = Session::new(sender, admitted);
+    let mut input = Vec::new();
+    let mut consumed = 0;
+    let mut output = Vec::new();
+    let mut deadline = None;
+    loop {
+        match parse(&input[consumed..]) {
+            Ok(Some((args, len))) => {
+                consumed += len;
+                deadline = None;
+                let reason = match answer(&mut session, args, len, &mut output).await {
+                    Some(true) => continue,
+                    Some(false) => CloseReason::Eof,
+                    None => CloseReason::Shutdown,
+                };
+                let _ = socket.write_all(&output).await;
+                return reason;
+            }
+            Ok(None) => {
+                if !output.is_empty() {
+                    if socket.write_all(&output).await.is_err() {
+                        return CloseReason::IoError;
+                    }
+                    admitted.activity.sent(output.len());
+                    output.clear();
+                }
+                input.drain(..consumed);
+                consumed = 0;
+                if let Err(reason) = fill(socket, &mut input, &mut deadline, admission).await {
+                    return reason;
+                }
+            }
+            Err(message) => {
+                error(&mut output, &format!("ERR Protocol error: {message}"));
+                let _ = socket.write_all(&output).await;
+                return CloseReason::ProtocolError;
+            }
+        }
+    }
+}
+
+/// Serves the Redis protocol subset on `listener`, with the same connection
+/// limits, auth and rate limits as the native listeners.
+pub async fn serve(
+    listener: TcpListener,
+    sender: Dispatcher,
+    admission: Arc<Admission>,
+) -> io::Result<()> {
+    loop {
+        let queued = admission.queued_slot().await;
+        let (mut socket, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                crate::accept_failed(e).await;
+                continue;
+            }
+        };
+        if let Err(e) = socket.set_nodelay(true) {
+            warn!(error = %e, %addr, "cannot set TCP_NODELAY; dropped a RESP connection");
+            continue;
+        }
+        let peer = Peer {
+            addr: Some(addr),
+            ..Peer::default()
+        };
+        let Some(admitted) = admission.admit(queued, peer) else {
+            debug!("connection limit reached; turning a RESP client away");
+            tokio::spawn(async move {
+                let _ = socket
+                    .write_all(b"-ERR max number of clients reached\r\n")
+                    .await;
+            });
+            continue;
+        };
+        let span = tracing::info_span!("connection", id = admitted.id, protocol = "resp");
+        let sender = sender.for_connection(admitted.id, admitted.priority());
+        tokio::spawn(serve_connection(socket, sender, admitted).instrument(span));
+    }
+}
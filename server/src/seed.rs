@@ -0,0 +1,36 @@
+use crate::db::Database;
+use crate::persistence::Mutation;
+use map8x32_protocol::StatusError;
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+/// Reads a seed file: a JSON object from keys, written as strings, to the
+/// values to append to each, in order.
+pub fn read(path: &Path) -> io::Result<BTreeMap<u8, Vec<u32>>> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+    serde_json::from_slice(&bytes).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {e}", path.display()),
+        )
+    })
+}
+
+/// Appends every seeded value to `database` through its WAL, so the seed is
+/// kept like any other write. Returns how many values were loaded.
+pub fn load(database: &Database, seed: &BTreeMap<u8, Vec<u32>>) -> io::Result<usize> {
+    let mutations: Vec<Mutation> = seed
+        .iter()
+        .flat_map(|(&key, values)| {
+            values
+                .iter()
+                .map(move |&value| Mutation::Set { key, value })
+        })
+        .collect();
+    database.log_all(&mutations).map_err(|status| {
+        io::Error::other(format!("cannot log the seed: {}", StatusError::new(status)))
+    })?;
+    Ok(mutations.len())
+}
@@ -0,0 +1,137 @@
+use crate::admission::Priority;
+use crate::dispatch::Dispatcher;
+use crate::{call, Command, SetMode};
+use map8x32_core::Engine;
+use std::time::{Duration, Instant};
+
+const SELF_TEST_OPS: u32 = 20_000;
+
+/// How hard the engine is driven: `clients` tasks each send `ops` commands
+/// of every kind, one at a time, to `threads` command processors.
+#[derive(Debug, Clone, Copy)]
+pub struct Load {
+    pub ops: u32,
+    pub threads: usize,
+    pub clients: usize,
+}
+
+impl Default for Load {
+    fn default() -> Self {
+        Load {
+            ops: SELF_TEST_OPS,
+            threads: 1,
+            clients: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Workload {
+    Set,
+    Get,
+}
+
+impl Workload {
+    fn name(self) -> &'static str {
+        match self {
+            Workload::Set => "SET",
+            Workload::Get => "GET",
+        }
+    }
+
+    /// Runs the `n`th command, returning false once the engine is gone.
+    async fn run(self, sender: &Dispatcher, n: u32) -> bool {
+        let key = n as u8;
+        match self {
+            Workload::Set => call(sender, |respond_to| Command::Set {
+                key,
+                value: n,
+                mode: SetMode::Append,
+                respond_to,
+            })
+            .await
+            .is_ok(),
+            Workload::Get => call(sender, |respond_to| Command::Get { key, respond_to })
+                .await
+                .is_ok(),
+        }
+    }
+}
+
+struct Report {
+    name: &'static str,
+    elapsed: Duration,
+    latencies: Vec<Duration>,
+}
+
+impl Report {
+    fn print(&mut self) {
+        self.latencies.sort();
+        let count = self.latencies.len();
+        if count == 0 {
+            println!("  {}: no operations completed", self.name);
+            return;
+        }
+        let percentile = |p: f64| self.latencies[((count as f64 * p) as usize).min(count - 1)];
+        let ops_per_sec = count as f64 / self.elapsed.as_secs_f64();
+        println!(
+            "  {:<8} {:>7} ops  {:>10.0} ops/sec  p50: {:?}  p99: {:?}  max: {:?}",
+            self.name,
+            count,
+            ops_per_sec,
+            percentile(0.50),
+            percentile(0.99),
+            self.latencies[count - 1]
+        );
+    }
+}
+
+/// Runs `workload` from every client at once, each on its own lane, with
+/// client `c`'s `i`th command numbered `i * clients + c` so they spread
+/// over the keyspace.
+async fn measure(sender: &Dispatcher, workload: Workload, load: Load) -> Report {
+    let started = Instant::now();
+    let clients: Vec<_> = (0..load.clients)
+        .map(|client| {
+            let sender = sender.for_connection(client as u64 + 1, Priority::Normal);
+            tokio::spawn(async move {
+                let mut latencies = Vec::with_capacity(load.ops as usize);
+                for i in 0..load.ops {
+                    let op_start = Instant::now();
+                    let n = i
+                        .wrapping_mul(load.clients as u32)
+                        .wrapping_add(client as u32);
+                    if !workload.run(&sender, n).await {
+                        break;
+                    }
+                    latencies.push(op_start.elapsed());
+                }
+                latencies
+            })
+        })
+        .collect();
+    let mut latencies = Vec::with_capacity(load.ops as usize * load.clients);
+    for client in clients {
+        latencies.extend(client.await.unwrap_or_default());
+    }
+    Report {
+        name: workload.name(),
+        elapsed: started.elapsed(),
+        latencies,
+    }
+}
+
+/// Drives a scratch engine through the same dispatcher connections use,
+/// without sockets, and prints its throughput and latency.
+pub async fn run(load: Load) {
+    let engine = Engine::in_memory(load.threads);
+    let sender = engine.dispatcher();
+
+    println!(
+        "Self-test ({} ops per command from each of {} clients, {} command processors, in-process engine):",
+        load.ops, load.clients, load.threads
+    );
+    for workload in [Workload::Set, Workload::Get] {
+        measure(sender, workload, load).await.print();
+    }
+}
@@ -0,0 +1,110 @@
+use crate::admission::{Access, Admission, Admitted};
+use crate::dispatch::Dispatcher;
+use crate::execute;
+use map8x32_protocol::codec::{Request, Response};
+use map8x32_protocol::*;
+use std::time::Instant;
+
+/// A connection on one of the gateway listeners, which translate their own
+/// commands into native requests.
+pub struct Session<'a> {
+    pub sender: Dispatcher,
+    pub admitted: &'a Admitted,
+    pub access: Option<Access>,
+}
+
+impl<'a> Session<'a> {
+    /// Unauthenticated unless the server takes no tokens.
+    pub fn new(sender: Dispatcher, admitted: &'a Admitted) -> Self {
+        let access = (!admitted.admission().requires_auth()).then_some(Access::Admin);
+        Session {
+            sender,
+            admitted,
+            access,
+        }
+    }
+
+    pub fn authenticate(&mut self, token: &[u8]) -> bool {
+        match self.admitted.admission().authenticate(token) {
+            Some(granted) => {
+                self.access = Some(granted);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn select(&mut self, db: u8) -> bool {
+        match self.sender.select(db) {
+            Some(selected) => {
+                self.sender = selected;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Runs one native request behind the checks a native connection makes,
+    /// recording it in the slow and audit logs and reporting it to monitors.
+    /// `None` means the server is shutting down.
+    pub async fn run(&self, request: Request, request_bytes: usize) -> Option<Response> {
+        let admission = self.admitted.admission();
+        let (op, key, received) = (request.op(), request.key(), Instant::now());
+        self.admitted.activity.received(op, request_bytes);
+        let audited = admission
+            .audit
+            .as_ref()
+            .filter(|_| request.writes())
+            .map(|_| request.clone());
+        let watched = admission.monitor.start(&request);
+        let refused = |status| {
+            self.sender
+                .storage()
+                .dead_letters
+                .record_refused(&request, status);
+            Response::refusal(&request, status)
+        };
+        let response = match self.access {
+            None => refused(STATUS_UNAUTHORIZED),
+            Some(access) if !admission.permits(access, &request) => refused(STATUS_READONLY),
+            Some(access)
+                if access != Access::Admin
+                    && Admission::needs_admin(&request, self.sender.storage().registry.strict) =>
+            {
+                refused(STATUS_UNAUTHORIZED)
+            }
+            Some(_) => execute(&self.sender, request).await?,
+        };
+        let elapsed = received.elapsed();
+        admission
+            .slow_log
+            .observe(op, key.unwrap_or(0), elapsed, request_bytes);
+        if let (Some(audit), Some(request)) = (&admission.audit, &audited) {
+            audit.record(
+                &self.admitted.peer,
+                self.sender.selected(),
+                request,
+                response.status(),
+            );
+        }
+        if let Some(watched) = watched {
+            admission.monitor.finish(
+                watched,
+                &self.admitted.peer,
+                self.sender.selected(),
+                response.status(),
+                elapsed,
+            );
+        }
+        Some(response)
+    }
+
+    /// The key's values, empty if it does not exist, or the failed status.
+    pub async fn values(&self, key: u8, request_bytes: usize) -> Option<Result<Vec<u32>, u8>> {
+        Some(match self.run(Request::Get { key }, request_bytes).await? {
+            Response::Values(values) => Ok(values),
+            Response::Status(STATUS_NOT_FOUND) => Ok(Vec::new()),
+            response => Err(response.status()),
+        })
+    }
+}
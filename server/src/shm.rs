@@ -0,0 +1,95 @@
+use crate::StorageType;
+use map8x32_protocol::shm::ShmMirror;
+use std::ffi::c_void;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::AtomicU32;
+use tokio::sync::broadcast::error::RecvError;
+
+/// The mirror file mapped read-write into the server, unmapped on drop.
+pub struct Mapping {
+    ptr: *mut c_void,
+    words: usize,
+}
+
+// SAFETY: the mapping is only reached through `&[AtomicU32]`, which any
+// thread may share.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    fn words(&self) -> &[AtomicU32] {
+        // SAFETY: `ptr` maps `words` page-aligned words that stay mapped
+        // until `self` drops, and every access to them is atomic.
+        unsafe { std::slice::from_raw_parts(self.ptr as *const AtomicU32, self.words) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: `ptr` and the length are what `mmap` returned and took.
+        unsafe { libc::munmap(self.ptr, self.words * 4) };
+    }
+}
+
+/// Lays out an empty mirror with `capacity` values per key in a new file,
+/// then moves it over `path`. A reader still mapping the file it replaces
+/// keeps a stale but intact copy rather than faulting on a truncated one.
+pub fn create(path: &Path, capacity: usize) -> io::Result<Mapping> {
+    let words = ShmMirror::words(capacity);
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o644)
+        .open(&tmp)?;
+    file.set_len(words as u64 * 4)?;
+    // SAFETY: a fresh shared mapping of a file just sized to fit it; the
+    // kernel picks the address, so nothing else is overwritten.
+    let ptr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            words * 4,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            file.as_raw_fd(),
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    let mapping = Mapping { ptr, words };
+    ShmMirror::init(mapping.words(), capacity);
+    fs::rename(&tmp, path)?;
+    Ok(mapping)
+}
+
+/// Copies every key of `database` into the mirror, then rewrites each key's
+/// slot as it changes, until the database goes away. Falling behind the
+/// change feed copies every key again.
+pub async fn maintain(mapping: Mapping, database: StorageType) {
+    let mut changes = database.subscribe();
+    let mirror = ShmMirror::open(mapping.words()).expect("the mirror was laid out when mapped");
+    let copy = |key| mirror.write(key, database.live_values(key).as_deref());
+    (0..=u8::MAX).for_each(copy);
+    loop {
+        match changes.recv().await {
+            Ok(key) => copy(key),
+            Err(RecvError::Lagged(missed)) => {
+                tracing::debug!(
+                    missed,
+                    "shared-memory mirror fell behind; copying every key"
+                );
+                (0..=u8::MAX).for_each(copy);
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
@@ -0,0 +1,237 @@
+//! Forwards every change to the data to an external system as it happens:
+//! a named pipe, an HTTP webhook, or an append-only log file. Each change
+//! is sent as one JSON line holding the changed key's values, so whatever
+//! reads them can mirror the map without polling LIST_ALL.
+
+use crate::db::Database;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::unix::pipe;
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+/// Changes sent in one write or request at most.
+const BATCH: usize = 512;
+/// How long a failed delivery waits before it is tried again.
+const RETRY: Duration = Duration::from_secs(1);
+/// How long a webhook has to take a batch.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where changes are sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// A FIFO some other process reads, opened again whenever it goes away.
+    Pipe(PathBuf),
+    /// A `POST` of each batch as newline-delimited JSON.
+    Webhook { host: String, path: String },
+    /// A file changes are appended to, numbered from where it ends.
+    Log(PathBuf),
+}
+
+impl Target {
+    /// `pipe:<path>`, `log:<path>` or `http://<host:port>/<path>`.
+    pub fn parse(target: &str) -> Result<Target, String> {
+        if let Some(path) = target.strip_prefix("pipe:") {
+            return Ok(Target::Pipe(path.into()));
+        }
+        if let Some(path) = target.strip_prefix("log:") {
+            return Ok(Target::Log(path.into()));
+        }
+        if let Some(rest) = target.strip_prefix("http://") {
+            let (host, path) = match rest.find('/') {
+                Some(slash) => (&rest[..slash], &rest[slash..]),
+                None => (rest, "/"),
+            };
+            if !host.is_empty() {
+                return Ok(Target::Webhook {
+                    host: host.to_string(),
+                    path: path.to_string(),
+                });
+            }
+        }
+        Err(format!(
+            "change sink {target:?} is none of pipe:<path>, log:<path> and http://<host:port>/<path>"
+        ))
+    }
+}
+
+/// A key as it was once it changed, `values` `None` if it holds none.
+#[derive(Debug)]
+struct Change {
+    db: u8,
+    key: u8,
+    version: u64,
+    values: Option<Vec<u32>>,
+}
+
+fn change(database: &Database, key: u8) -> Change {
+    let values = database.live_values(key);
+    Change {
+        db: database.id,
+        key,
+        version: database.version(key),
+        values,
+    }
+}
+
+enum Sink {
+    Pipe(PathBuf, Option<pipe::Sender>),
+    Webhook { host: String, path: String },
+    Log(File),
+}
+
+impl Sink {
+    async fn write(&mut self, body: &[u8]) -> io::Result<()> {
+        match self {
+            Sink::Pipe(path, open) => {
+                let pipe = match open {
+                    Some(pipe) => pipe,
+                    // Fails until a reader opens the other end.
+                    None => open.insert(pipe::OpenOptions::new().open_sender(&*path)?),
+                };
+                let written = pipe.write_all(body).await;
+                if written.is_err() {
+                    *open = None;
+                }
+                written
+            }
+            Sink::Webhook { host, path } => {
+                match tokio::time::timeout(TIMEOUT, post(host, path, body)).await {
+                    Ok(posted) => posted,
+                    Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")),
+                }
+            }
+            Sink::Log(file) => {
+                file.write_all(body).await?;
+                file.flush().await
+            }
+        }
+    }
+}
+
+async fn post(host: &str, path: &str, body: &[u8]) -> io::Result<()> {
+    let mut stream = TcpStream::connect(host).await?;
+    let head = format!(
+        "POST {path} HTTP/1.1\r\nHost: {host}\r\nContent-Type: application/x-ndjson\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let status = response
+        .split(|&b| b == b' ')
+        .nth(1)
+        .and_then(|code| std::str::from_utf8(code).ok());
+    match status {
+        Some(code) if code.starts_with('2') => Ok(()),
+        status => Err(io::Error::other(format!(
+            "the webhook answered {}",
+            status.unwrap_or("nothing")
+        ))),
+    }
+}
+
+/// Sends every key of `databases` to `target`, then each key again as it
+/// changes, with up to `buffer` changes queued while the target catches up.
+pub async fn start(target: &str, buffer: usize, databases: &[Arc<Database>]) -> io::Result<()> {
+    let target = Target::parse(target).map_err(io::Error::other)?;
+    let (sink, offset) = match target {
+        Target::Pipe(path) => {
+            if !std::fs::metadata(&path)?.file_type().is_fifo() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("change sink {} is not a named pipe", path.display()),
+                ));
+            }
+            (Sink::Pipe(path, None), 0)
+        }
+        Target::Webhook { host, path } => (Sink::Webhook { host, path }, 0),
+        Target::Log(path) => {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .read(true)
+                .append(true)
+                .open(&path)
+                .await?;
+            let mut logged = Vec::new();
+            file.read_to_end(&mut logged).await?;
+            let lines = logged.iter().filter(|&&byte| byte == b'\n').count();
+            (Sink::Log(file), lines as u64)
+        }
+    };
+    let (changes, queued) = mpsc::channel(buffer);
+    for database in databases {
+        tokio::spawn(follow(database.clone(), changes.clone()));
+    }
+    tokio::spawn(deliver(sink, queued, offset));
+    Ok(())
+}
+
+/// Queues every key of `database`, then each key as it changes. Falling
+/// behind the change feed, or the queue staying full for long enough to,
+/// queues every key again.
+async fn follow(database: Arc<Database>, changes: mpsc::Sender<Change>) {
+    let mut changed = database.subscribe();
+    let mut keys: Vec<u8> = (0..=u8::MAX).collect();
+    loop {
+        for key in keys.drain(..) {
+            if changes.send(change(&database, key)).await.is_err() {
+                return;
+            }
+        }
+        match changed.recv().await {
+            Ok(key) => keys.push(key),
+            Err(RecvError::Lagged(missed)) => {
+                warn!(
+                    db = database.id,
+                    missed, "change sink fell behind; sending every key"
+                );
+                keys.extend(0..=u8::MAX);
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+/// Writes the queued changes to `sink` in batches, numbering them from
+/// `offset`, and tries a failed batch again until it is taken.
+async fn deliver(mut sink: Sink, mut queued: mpsc::Receiver<Change>, mut offset: u64) {
+    let mut batch = Vec::with_capacity(BATCH);
+    let mut body = Vec::new();
+    while queued.recv_many(&mut batch, BATCH).await > 0 {
+        body.clear();
+        for change in batch.drain(..) {
+            let values = match change.values {
+                Some(values) => format!("{values:?}").replace(' ', ""),
+                None => "null".to_string(),
+            };
+            body.extend_from_slice(
+                format!(
+                    "{{\"offset\":{offset},\"db\":{},\"key\":{},\"version\":{},\"values\":{values}}}\n",
+                    change.db, change.key, change.version
+                )
+                .as_bytes(),
+            );
+            offset += 1;
+        }
+        let mut failing = false;
+        while let Err(e) = sink.write(&body).await {
+            if !failing {
+                error!(error = %e, "cannot forward changes; retrying");
+            }
+            failing = true;
+            tokio::time::sleep(RETRY).await;
+        }
+        if failing {
+            info!(offset, "forwarding changes again");
+        }
+    }
+}
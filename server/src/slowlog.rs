@@ -0,0 +1,59 @@
+pub use map8x32_protocol::codec::SlowOp;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The most recent commands that took at least `threshold` to handle; the
+/// oldest entry is discarded once `capacity` is reached. Without a threshold
+/// nothing is captured.
+#[derive(Debug)]
+pub struct SlowLog {
+    threshold: Option<Duration>,
+    capacity: usize,
+    entries: Mutex<VecDeque<SlowOp>>,
+    pub recorded: AtomicU64,
+}
+
+impl SlowLog {
+    pub fn new(threshold: Option<Duration>, capacity: usize) -> Self {
+        SlowLog {
+            threshold: threshold.filter(|_| capacity > 0),
+            capacity,
+            entries: Mutex::new(VecDeque::new()),
+            recorded: AtomicU64::new(0),
+        }
+    }
+
+    /// Keeps the command if `elapsed` reaches the threshold.
+    pub fn observe(&self, op: u8, key: u8, elapsed: Duration, request_bytes: usize) {
+        match self.threshold {
+            Some(threshold) if elapsed >= threshold => {}
+            _ => return,
+        }
+        let at_unix_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        self.recorded.fetch_add(1, Ordering::Relaxed);
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(SlowOp {
+            at_unix_ms,
+            op,
+            key,
+            micros: elapsed.as_micros() as u64,
+            request_bytes: request_bytes as u32,
+        });
+    }
+
+    pub fn snapshot(&self, drain: bool) -> Vec<SlowOp> {
+        let mut entries = self.entries.lock().unwrap();
+        if drain {
+            entries.drain(..).collect()
+        } else {
+            entries.iter().copied().collect()
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+fn invalid(path: &Path, error: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{}: {error}", path.display()),
+    )
+}
+
+fn certificates(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .map_err(|e| invalid(path, e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| invalid(path, e))?;
+    if certs.is_empty() {
+        return Err(invalid(path, "no PEM certificates found"));
+    }
+    Ok(certs)
+}
+
+/// Builds the acceptor for the TCP listener from PEM files. With
+/// `client_ca`, clients must present a certificate signed by one of the
+/// authorities in it.
+pub fn acceptor(cert: &Path, key: &Path, client_ca: Option<&Path>) -> io::Result<TlsAcceptor> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?;
+    let builder = match client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for ca in certificates(path)? {
+                roots.add(ca).map_err(|e| invalid(path, e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(|e| invalid(path, e))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|e| invalid(key, e))?;
+    let config = builder
+        .with_single_cert(certificates(cert)?, key)
+        .map_err(|e| invalid(cert, e))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Runs the server side of the handshake, giving up after `timeout`.
+pub async fn handshake(
+    acceptor: &TlsAcceptor,
+    socket: TcpStream,
+    timeout: Option<Duration>,
+) -> io::Result<TlsStream<TcpStream>> {
+    let accept = acceptor.accept(socket);
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, accept)
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?,
+        None => accept.await,
+    }
+}
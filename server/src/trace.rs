@@ -0,0 +1,262 @@
+use crate::config::Config;
+use crate::dispatch::Dispatcher;
+use crate::{build_database, call, execute, Command, GetResponse};
+use map8x32_protocol::codec::{Codec, Request, Response, NATIVE_V2};
+use map8x32_protocol::*;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+fn touches(request: &Request, key: u8) -> bool {
+    match request {
+        Request::DeleteAll { .. } => true,
+        Request::Rename { from, to, .. } | Request::Copy { from, to, .. } => {
+            *from == key || *to == key
+        }
+        Request::Swap { a, b } | Request::Compare { a, b, .. } => *a == key || *b == key,
+        Request::DeleteMany { keys } => keys.contains(&key),
+        Request::AllocRegister(allocation) => {
+            (allocation.first_key..=allocation.last_key).contains(&key)
+        }
+        request => request.key() == Some(key),
+    }
+}
+
+pub fn describe(request: &Request) -> String {
+    match request {
+        Request::Set { key, value } => format!("SET key={key} value={value}"),
+        Request::Get { key } => format!("GET key={key}"),
+        Request::SetSorted { key, value } => format!("SET_SORTED key={key} value={value}"),
+        Request::SetExpiring { key, value, ttl_ms } => {
+            format!("SET_EXPIRING key={key} value={value} ttl_ms={ttl_ms}")
+        }
+        Request::SetTimed { key, value, at_ms } => {
+            format!("SET_TIMED key={key} value={value} at_ms={at_ms}")
+        }
+        Request::GetSortedRange { key, min, max } => {
+            format!("GET_SORTED_RANGE key={key} min={min} max={max}")
+        }
+        Request::Aggregate { key, aggregate } => format!("{} key={key}", aggregate.name()),
+        Request::GetFilter { key, min, max } => {
+            format!("GET_FILTER key={key} min={min} max={max}")
+        }
+        Request::GetFirstN { key, n } => format!("GET_FIRST_N key={key} n={n}"),
+        Request::GetLastN { key, n } => format!("GET_LAST_N key={key} n={n}"),
+        Request::DeleteByKey { key } => format!("DELETE_BY_KEY key={key}"),
+        Request::DeleteAt { key, index } => format!("DELETE_AT key={key} index={index}"),
+        Request::DeleteIf { key, expected } => format!("DELETE_IF key={key} expected={expected}"),
+        Request::Ttl { key } => format!("TTL key={key}"),
+        Request::MemoryUsage { key: Some(key) } => format!("MEMUSAGE key={key}"),
+        Request::MemoryUsage { key: None } => "MEMUSAGE total".to_string(),
+        Request::KeyStats { key } => format!("KEYSTATS key={key}"),
+        Request::Persist { key } => format!("PERSIST key={key}"),
+        Request::Touch { key, ttl_ms } => format!("TOUCH key={key} ttl_ms={ttl_ms}"),
+        Request::Dedup { key } => format!("DEDUP key={key}"),
+        Request::Sort { key, descending } => format!("SORT key={key} descending={descending}"),
+        Request::DeleteAll { .. } => "DELETE_ALL".to_string(),
+        Request::Rename { from, to, merge } => format!("RENAME from={from} to={to} merge={merge}"),
+        Request::Swap { a, b } => format!("SWAP a={a} b={b}"),
+        Request::Compare { a, b, comparison } => {
+            format!("COMPARE a={a} b={b} {}", comparison.name())
+        }
+        Request::Copy { from, to, append } => format!("COPY from={from} to={to} append={append}"),
+        Request::ListAll { snapshot: false } => "LIST_ALL".to_string(),
+        Request::ListAll { snapshot: true } => "LIST_ALL snapshot".to_string(),
+        Request::ListRange { first, last } => format!("LIST_RANGE first={first} last={last}"),
+        Request::Tag { key, tag } => format!("TAG key={key} tag={tag}"),
+        Request::ListTagged { tag } => format!("LIST_TAGGED tag={tag}"),
+        Request::DeleteTagged { tag } => format!("DELETE_TAGGED tag={tag}"),
+        Request::DeleteMany { keys } => format!("DELETE_MANY keys={keys:?}"),
+        Request::GetDel { key } => format!("GETDEL key={key}"),
+        Request::GetSet { key, value } => format!("GETSET key={key} value={value:?}"),
+        Request::RandomKey { with_value } => format!("RANDOM_KEY with_value={with_value}"),
+        Request::Scan { cursor, count } => format!("SCAN cursor={cursor} count={count}"),
+        Request::AwaitSeq { seq } => format!("AWAIT_SEQ seq={seq}"),
+        Request::DeadLetters { drain } => format!("DEAD_LETTERS drain={drain}"),
+        Request::AllocRegister(a) => format!(
+            "ALLOC_REGISTER {}..={} name={:?}",
+            a.first_key, a.last_key, a.name
+        ),
+        Request::AllocRelease { first_key } => format!("ALLOC_RELEASE first_key={first_key}"),
+        Request::AllocList => "ALLOC_LIST".to_string(),
+        Request::GetVersioned { key } => format!("GET_VERSIONED key={key}"),
+        Request::ReplaceIf {
+            key,
+            expected,
+            values,
+        } => format!("REPLACE_IF key={key} expected={expected} values={values:?}"),
+        Request::Push { key, values } => format!("PUSH key={key} values={values:?}"),
+        Request::RestoreKey { key } => format!("RESTORE_KEY key={key}"),
+        Request::Info { latencies: false } => "INFO".to_string(),
+        Request::Info { latencies: true } => "INFO latencies".to_string(),
+        Request::SyncFull => "SYNC_FULL".to_string(),
+        Request::Select { db } => format!("SELECT db={db}"),
+        Request::SetTyped { key, value } => format!("SET_TYPED key={key} value={value:?}"),
+        Request::GetTyped { key } => format!("GET_TYPED key={key}"),
+        Request::Dump { name } => format!("DUMP name={name}"),
+        Request::Restore { name, merge } => format!("RESTORE name={name} merge={merge}"),
+        Request::ClientKill { id } => format!("CLIENT_KILL id={id}"),
+        Request::Config { name, value: None } => format!("CONFIG name={name}"),
+        Request::Config {
+            name,
+            value: Some(value),
+        } => format!("CONFIG name={name} value={value}"),
+        Request::Hello { .. }
+        | Request::Auth { .. }
+        | Request::SelectCodec { .. }
+        | Request::SlowLog { .. }
+        | Request::ClientList
+        | Request::ClientInfo
+        | Request::Ping
+        | Request::Keepalive
+        | Request::Pong
+        | Request::Subscribe { .. }
+        | Request::SubscribeEvents { .. }
+        | Request::Monitor
+        | Request::Multiplex
+        | Request::Cancel { .. }
+        | Request::Chunked { .. }
+        | Request::Priority { .. } => "connection request".to_string(),
+        Request::Invalid { op } => format!("UNKNOWN op={op}"),
+    }
+}
+
+fn outcome(response: &Response) -> String {
+    match response {
+        Response::Status(STATUS_NOT_FOUND) => "not found".to_string(),
+        Response::Status(status) => format!("status={status}"),
+        Response::Ack { status, seq } => format!("status={status} seq={seq}"),
+        Response::Removed { seq, value } => format!("removed {value} seq={seq}"),
+        Response::Taken { seq, values } => format!("took {values:?} seq={seq}"),
+        Response::DeletedMany { seq, statuses } => format!("statuses={statuses:?} seq={seq}"),
+        Response::RandomKey { key, value: None } => format!("key={key}"),
+        Response::RandomKey {
+            key,
+            value: Some(value),
+        } => format!("key={key} value={value}"),
+        Response::Values(values) => format!("found {values:?}"),
+        Response::Versioned { version, values } => format!("version={version} {values:?}"),
+        Response::Entries(entries) => format!("{} keys", entries.len()),
+        Response::Aggregate { result } => format!("result={result}"),
+        Response::MemoryUsage { bytes } => format!("bytes={bytes}"),
+        Response::KeyStats(stats) => format!(
+            "hits={} misses={} sets={}",
+            stats.hits, stats.misses, stats.sets
+        ),
+        Response::Ttl { ttl_ms: 0 } => "no expiry".to_string(),
+        Response::Ttl { ttl_ms } => format!("ttl={ttl_ms}ms"),
+        Response::Scan { cursor, keys } => format!("{} keys, next cursor={cursor}", keys.len()),
+        Response::DeadLetters(entries) => format!("{} dead letters", entries.len()),
+        Response::Allocations(allocations) => format!("{} allocations", allocations.len()),
+        Response::Info(info) => format!("{} keys, {} values", info.keys, info.values),
+        Response::FullSync { seq, entries } => format!("{} keys at seq={seq}", entries.len()),
+        Response::SlowOps(entries) => format!("{} slow commands", entries.len()),
+        Response::Clients(clients) => format!("{} clients", clients.len()),
+        Response::Monitored(entries) => format!("{} commands", entries.len()),
+        Response::Events(events) => format!("{} events", events.len()),
+        Response::Config(settings) => format!("{} settings", settings.len()),
+        Response::Typed { values, .. } => format!("found {values:?}"),
+        Response::Hello { .. } | Response::UnsupportedVersion { .. } | Response::Pong { .. } => {
+            "skipped".to_string()
+        }
+    }
+}
+
+async fn get(sender: &Dispatcher, key: u8) -> Option<Vec<u32>> {
+    match call(sender, |respond_to| Command::Get { key, respond_to })
+        .await
+        .ok()?
+    {
+        GetResponse::Found(values) => Some(values),
+        GetResponse::NotFound => None,
+    }
+}
+
+/// Replays a trace against a fresh engine built from `config`, on a single
+/// command processor per database. Without breakpoints every command
+/// pauses; with `--break-key` only commands touching those keys do.
+pub async fn replay(config: &Config, path: &Path) -> io::Result<()> {
+    let databases = (0..config.databases)
+        .map(|id| build_database(config, id as u8).map(Arc::new))
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut sender = Dispatcher::spawn(1, &databases);
+    let trace = std::fs::read(path)?;
+    let mut offset = 0;
+    let mut stdin = io::stdin().lock();
+    let mut stepping = config.break_keys.is_empty();
+    let mut interactive = true;
+    let mut index = 0u64;
+
+    println!("replaying {}", path.display());
+    println!("at a pause: <enter> or s = step, c = continue to next breakpoint, q = quit");
+    // A record torn off by a crash ends the trace like end of file does.
+    while let Some((request, len)) = NATIVE_V2
+        .decode_request(&trace[offset..])
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("corrupt trace: {e}")))?
+    {
+        offset += len;
+        index += 1;
+        let hit = config.break_keys.iter().any(|&key| touches(&request, key));
+        let key = request.key();
+        let description = describe(&request);
+        let response = match request {
+            Request::Invalid { .. } => "skipped".to_string(),
+            Request::Select { db } => match sender.select(db) {
+                Some(selected) => {
+                    sender = selected;
+                    outcome(&Response::Status(STATUS_OK))
+                }
+                None => outcome(&Response::Status(STATUS_BAD_REQUEST)),
+            },
+            request => match execute(&sender, request).await {
+                Some(response) => outcome(&response),
+                None => break,
+            },
+        };
+        if !(stepping || hit) {
+            continue;
+        }
+
+        println!("#{index} {description} -> {response}");
+        if let Some(key) = key {
+            let values = get(&sender, key).await.unwrap_or_default();
+            println!("    key {key} now holds {values:?}");
+        }
+        for &watched in &config.break_keys {
+            if Some(watched) != key {
+                let values = get(&sender, watched).await.unwrap_or_default();
+                println!("    key {watched} now holds {values:?}");
+            }
+        }
+        if !interactive {
+            continue;
+        }
+        print!("(replay) ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.read_line(&mut line)? == 0 {
+            // Stdin closed: report the remaining stops without pausing.
+            println!();
+            interactive = false;
+            continue;
+        }
+        match line.trim() {
+            "q" => return Ok(()),
+            "c" => stepping = false,
+            _ => stepping = true,
+        }
+    }
+
+    println!(
+        "replayed {index} commands; {} keys, {} values",
+        databases
+            .iter()
+            .map(|database| database.map.len())
+            .sum::<usize>(),
+        databases
+            .iter()
+            .flat_map(|database| database.map.collect(|_, values| values.len()))
+            .sum::<usize>()
+    );
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use crate::admission::Admission;
+use crate::persistence::Persistence;
+use crate::{logging, StorageType};
+use map8x32_protocol::{STATUS_BAD_REQUEST, STATUS_NOT_FOUND, STATUS_OK};
+use std::sync::atomic::Ordering;
+
+/// The settings CONFIG reads and changes, in the order it lists them. A
+/// limit of 0 means none, and a snapshot interval of 0 turns periodic
+/// snapshots off.
+const NAMES: [&str; 6] = [
+    "log_level",
+    "rate_limit",
+    "rate_limit_burst",
+    "max_connections",
+    "snapshot_interval",
+    "wal_max_bytes",
+];
+
+fn persisted(databases: &[StorageType]) -> impl Iterator<Item = &Persistence> {
+    databases
+        .iter()
+        .filter_map(|database| database.persistence.as_ref())
+}
+
+/// The current value of tunable `name`, or `None` if there is no such
+/// tunable. The snapshot ones exist only when the server persists.
+pub fn get(admission: &Admission, databases: &[StorageType], name: &str) -> Option<String> {
+    let rate = admission.rate_limit();
+    let snapshots = persisted(databases).next();
+    Some(match name {
+        "log_level" => logging::level(),
+        "rate_limit" => rate.map_or(0.0, |(ops_per_sec, _)| ops_per_sec).to_string(),
+        "rate_limit_burst" => rate.map_or(0.0, |(_, burst)| burst).to_string(),
+        "max_connections" => admission.max_connections().unwrap_or(0).to_string(),
+        "snapshot_interval" => snapshots?
+            .snapshot_interval
+            .load(Ordering::Relaxed)
+            .to_string(),
+        "wal_max_bytes" => snapshots?.wal_max_bytes.load(Ordering::Relaxed).to_string(),
+        _ => return None,
+    })
+}
+
+/// Every tunable the server has, with its current value.
+pub fn all(admission: &Admission, databases: &[StorageType]) -> Vec<(String, String)> {
+    NAMES
+        .iter()
+        .filter_map(|name| Some((name.to_string(), get(admission, databases, name)?)))
+        .collect()
+}
+
+/// Applies `value` to tunable `name` on every connection and database it
+/// governs, answering with CONFIG's status: NOT_FOUND for a tunable the
+/// server does not have, BAD_REQUEST for a value it cannot take. Setting
+/// `rate_limit` resets `rate_limit_burst` to the new rate, as leaving the
+/// burst out of the config does.
+pub fn set(admission: &Admission, databases: &[StorageType], name: &str, value: &str) -> u8 {
+    if get(admission, databases, name).is_none() {
+        return STATUS_NOT_FOUND;
+    }
+    let applied = match name {
+        "log_level" => logging::set_level(value).is_ok(),
+        "rate_limit" => match value.parse::<f64>() {
+            Ok(rate) if rate.is_finite() && rate >= 0.0 => {
+                admission.set_rate_limit((rate > 0.0).then_some(rate), None);
+                true
+            }
+            _ => false,
+        },
+        "rate_limit_burst" => match (value.parse::<f64>(), admission.rate_limit()) {
+            (Ok(burst), Some((ops_per_sec, _))) if burst.is_finite() && burst >= 1.0 => {
+                admission.set_rate_limit(Some(ops_per_sec), Some(burst));
+                true
+            }
+            _ => false,
+        },
+        "max_connections" => match value.parse::<usize>() {
+            Ok(max) => {
+                admission.set_max_connections((max > 0).then_some(max));
+                true
+            }
+            Err(_) => false,
+        },
+        "snapshot_interval" | "wal_max_bytes" => match value.parse::<u64>() {
+            Ok(setting) => {
+                for persistence in persisted(databases) {
+                    match name {
+                        "snapshot_interval" => &persistence.snapshot_interval,
+                        _ => &persistence.wal_max_bytes,
+                    }
+                    .store(setting, Ordering::Relaxed);
+                }
+                true
+            }
+            Err(_) => false,
+        },
+        _ => false,
+    };
+    if applied {
+        STATUS_OK
+    } else {
+        STATUS_BAD_REQUEST
+    }
+}
@@ -0,0 +1,292 @@
+//! Copies each database's snapshots to an S3-compatible bucket as they are
+//! taken, and deletes the oldest copies past a retention count. Requests
+//! are signed with AWS Signature Version 4 and sent over plain HTTP, so the
+//! endpoint is one on a trusted network or a proxy that adds TLS.
+
+use crate::config::Config;
+use crate::db::Database;
+use ring::{digest, hmac};
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{error, info};
+
+/// How often each database's snapshot count is checked.
+const POLL: Duration = Duration::from_secs(1);
+/// How long one snapshot's upload and pruning may take.
+const TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Debug)]
+struct Bucket {
+    endpoint: String,
+    name: String,
+    region: String,
+    prefix: String,
+    keep: usize,
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+fn credential(name: &str) -> io::Result<String> {
+    std::env::var(name).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("uploading snapshots needs {name} set"),
+        )
+    })
+}
+
+/// Uploads every snapshot the databases with a data directory take from now
+/// on to `endpoint`, into the bucket and under the prefix `config` names.
+pub fn start(endpoint: &str, config: &Config, databases: &[Arc<Database>]) -> io::Result<()> {
+    let bucket = Arc::new(Bucket {
+        endpoint: endpoint.to_string(),
+        name: config.upload_bucket.clone().unwrap_or_default(),
+        region: config.upload_region.clone(),
+        prefix: config.upload_prefix.clone(),
+        keep: config.upload_keep,
+        access_key: credential("AWS_ACCESS_KEY_ID")?,
+        secret_key: credential("AWS_SECRET_ACCESS_KEY")?,
+        session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+    });
+    for database in databases
+        .iter()
+        .filter(|database| database.persistence.is_some())
+    {
+        tokio::spawn(run(bucket.clone(), database.clone()));
+    }
+    Ok(())
+}
+
+async fn run(bucket: Arc<Bucket>, database: Arc<Database>) {
+    let Some(persistence) = &database.persistence else {
+        return;
+    };
+    let db = database.id;
+    let mut uploaded = persistence.snapshots.load(Ordering::Relaxed);
+    let mut tick = tokio::time::interval(POLL);
+    loop {
+        tick.tick().await;
+        let taken = persistence.snapshots.load(Ordering::Relaxed);
+        if taken == uploaded {
+            continue;
+        }
+        // Snapshots taken while one uploads are covered by the next upload,
+        // which reads the latest.
+        uploaded = taken;
+        let path = persistence.snapshot_path();
+        match tokio::time::timeout(TIMEOUT, upload(&bucket, db, &path)).await {
+            Ok(Ok((object, pruned))) => info!(
+                db,
+                bucket = bucket.name,
+                object,
+                pruned,
+                "uploaded snapshot"
+            ),
+            Ok(Err(e)) => error!(error = %e, db, bucket = bucket.name, "cannot upload snapshot"),
+            Err(_) => error!(db, bucket = bucket.name, "timed out uploading snapshot"),
+        }
+    }
+}
+
+/// Uploads the snapshot at `path` as database `db`'s newest, then deletes
+/// its uploads past the newest `keep`. Returns the new object's name and
+/// how many were deleted.
+async fn upload(bucket: &Bucket, db: u8, path: &std::path::Path) -> io::Result<(String, usize)> {
+    let snapshot = tokio::fs::read(path).await?;
+    let folder = format!("{}db{db}/", bucket.prefix);
+    let taken_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    // Fixed-width times, so names sort in the order they were uploaded.
+    let object = format!("{folder}map8x32-{taken_ms:013}.snapshot");
+    bucket.send("PUT", &object, "", &snapshot).await?;
+
+    // ListObjectsV2 lists up to 1000 names, oldest first, which is enough
+    // while every upload prunes down to `keep`.
+    let query = format!("list-type=2&prefix={}", encode(&folder, false));
+    let listing = bucket.send("GET", "", &query, &[]).await?;
+    let listing = String::from_utf8_lossy(&listing);
+    let mut uploads: Vec<&str> = listing
+        .split("<Key>")
+        .skip(1)
+        .filter_map(|rest| rest.split_once("</Key>").map(|(name, _)| name))
+        .filter(|name| {
+            name.starts_with(&format!("{folder}map8x32-")) && name.ends_with(".snapshot")
+        })
+        .collect();
+    uploads.sort_unstable();
+    let excess = uploads.len().saturating_sub(bucket.keep);
+    for old in &uploads[..excess] {
+        bucket.send("DELETE", old, "", &[]).await?;
+    }
+    Ok((object, excess))
+}
+
+impl Bucket {
+    /// Sends a signed request for `object` in the bucket, or for the bucket
+    /// itself when `object` is empty, returning the response body.
+    async fn send(
+        &self,
+        method: &str,
+        object: &str,
+        query: &str,
+        body: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        let path = match object {
+            "" => format!("/{}", self.name),
+            object => format!("/{}/{}", self.name, encode(object, true)),
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let at = amz_date(now);
+        let date = &at[..8];
+        let payload = hex(digest::digest(&digest::SHA256, body).as_ref());
+        let mut headers = vec![
+            ("host", self.endpoint.clone()),
+            ("x-amz-content-sha256", payload.clone()),
+            ("x-amz-date", at.clone()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{name}:{value}\n"))
+            .collect();
+        let signed = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical =
+            format!("{method}\n{path}\n{query}\n{canonical_headers}\n{signed}\n{payload}");
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+        let to_sign = format!(
+            "AWS4-HMAC-SHA256\n{at}\n{scope}\n{}",
+            hex(digest::digest(&digest::SHA256, canonical.as_bytes()).as_ref())
+        );
+        let mut key = sign(format!("AWS4{}", self.secret_key).as_bytes(), date);
+        for part in [self.region.as_str(), "s3", "aws4_request"] {
+            key = sign(&key, part);
+        }
+        let signature = hex(&sign(&key, &to_sign));
+
+        let target = match query {
+            "" => path,
+            query => format!("{path}?{query}"),
+        };
+        let mut head = format!("{method} {target} HTTP/1.1\r\n");
+        for (name, value) in &headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str(&format!(
+            "authorization: AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed}, Signature={signature}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+            self.access_key,
+            body.len()
+        ));
+        let mut stream = TcpStream::connect(&self.endpoint).await?;
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(body).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        parse_response(&response)
+    }
+}
+
+/// The body of a 2xx HTTP response, or an error naming the status.
+fn parse_response(response: &[u8]) -> io::Result<Vec<u8>> {
+    let text = String::from_utf8_lossy(response);
+    let Some((head, _)) = text.split_once("\r\n\r\n") else {
+        return Err(io::Error::other("the endpoint answered no HTTP response"));
+    };
+    let body = &response[head.len() + 4..];
+    let status = head.split(' ').nth(1).unwrap_or("nothing");
+    if !status.starts_with('2') {
+        return Err(io::Error::other(format!(
+            "the endpoint answered {status}: {}",
+            String::from_utf8_lossy(body).trim()
+        )));
+    }
+    let chunked = head
+        .lines()
+        .any(|line| line.eq_ignore_ascii_case("transfer-encoding: chunked"));
+    if !chunked {
+        return Ok(body.to_vec());
+    }
+    let mut decoded = Vec::new();
+    let mut rest = body;
+    loop {
+        let Some(end) = rest.windows(2).position(|pair| pair == b"\r\n") else {
+            return Err(io::Error::other(
+                "the endpoint cut a chunked response short",
+            ));
+        };
+        let size = std::str::from_utf8(&rest[..end])
+            .ok()
+            .and_then(|line| usize::from_str_radix(line.split(';').next()?.trim(), 16).ok())
+            .ok_or_else(|| io::Error::other("the endpoint sent a malformed chunk"))?;
+        rest = &rest[end + 2..];
+        if size == 0 {
+            return Ok(decoded);
+        }
+        let chunk = rest
+            .get(..size)
+            .ok_or_else(|| io::Error::other("the endpoint cut a chunked response short"))?;
+        decoded.extend_from_slice(chunk);
+        rest = rest.get(size + 2..).unwrap_or_default();
+    }
+}
+
+fn sign(key: &[u8], message: &str) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    hmac::sign(&key, message.as_bytes()).as_ref().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Percent-encodes all but the characters SigV4 leaves as they are, and
+/// `/` too when `keep_slashes`, as in object paths.
+fn encode(text: &str, keep_slashes: bool) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if keep_slashes => encoded.push('/'),
+            byte => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+/// `unix_secs` as SigV4's `YYYYMMDDTHHMMSSZ`.
+fn amz_date(unix_secs: u64) -> String {
+    let (days, secs) = (unix_secs / 86_400, unix_secs % 86_400);
+    // Howard Hinnant's days-to-civil, for days since 1970-01-01.
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}{month:02}{day:02}T{:02}{:02}{:02}Z",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
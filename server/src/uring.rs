@@ -0,0 +1,106 @@
+use crate::admission::{Admitted, Peer};
+use crate::config::Config;
+use crate::dispatch::Dispatcher;
+use crate::{
+    handle_connection, remove_stale_socket, set_socket_permissions, shutdown_signal, start,
+    Inherited, SOCKET_MODE,
+};
+use map8x32_protocol::STATUS_UNAVAILABLE;
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+use tokio_uring::net::{UnixListener, UnixStream};
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+async fn pump_in(socket: &UnixStream, mut to_handler: WriteHalf<DuplexStream>) {
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    loop {
+        let (result, returned) = socket.read(buf).await;
+        buf = returned;
+        match result {
+            Ok(0) | Err(_) => break,
+            Ok(n) => {
+                if to_handler.write_all(&buf[..n]).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    let _ = to_handler.shutdown().await;
+}
+
+async fn pump_out(socket: &UnixStream, mut from_handler: ReadHalf<DuplexStream>) {
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    loop {
+        let n = match from_handler.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        let chunk = buf[..n].to_vec();
+        let (result, _) = socket.write_all(chunk).await;
+        if result.is_err() {
+            break;
+        }
+    }
+    let _ = socket.shutdown(std::net::Shutdown::Write);
+}
+
+/// Moves bytes between an io_uring socket and the regular connection handler,
+/// which runs unchanged on the other end of an in-memory duplex pipe.
+async fn bridge(socket: UnixStream, sender: Dispatcher, admitted: Admitted) {
+    let (socket_side, handler_side) = tokio::io::duplex(BUFFER_SIZE);
+    let handler =
+        tokio_uring::spawn(async move { handle_connection(handler_side, sender, &admitted).await });
+    let (from_handler, to_handler) = tokio::io::split(socket_side);
+    tokio::join!(
+        pump_in(&socket, to_handler),
+        pump_out(&socket, from_handler)
+    );
+    let _ = handler.await;
+}
+
+async fn serve(config: Config) -> io::Result<()> {
+    let addr = config.socket.as_str();
+    remove_stale_socket(addr).await?;
+    let listener = UnixListener::bind(addr)?;
+    set_socket_permissions(addr, SOCKET_MODE).await?;
+
+    let server = start(&config, &mut Inherited::default()).await?;
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    loop {
+        let accepted = async {
+            (
+                server.admission.queued_slot().await,
+                listener.accept().await,
+            )
+        };
+        let (queued, accepted) = tokio::select! {
+            _ = &mut shutdown => break,
+            accepted = accepted => accepted,
+        };
+        let socket = match accepted {
+            Ok(socket) => socket,
+            Err(e) => {
+                crate::accept_failed(e).await;
+                continue;
+            }
+        };
+        match server.admission.admit(queued, Peer::default()) {
+            Some(admitted) => tokio_uring::spawn(bridge(socket, server.sender.clone(), admitted)),
+            None => tokio_uring::spawn(async move {
+                let _ = socket.write_all(vec![STATUS_UNAVAILABLE]).await;
+            }),
+        };
+    }
+
+    server.shut_down(&config).await;
+    server.remove_sockets().await;
+    let _ = std::fs::remove_file(addr);
+    Ok(())
+}
+
+pub fn run(config: Config) -> io::Result<()> {
+    tokio_uring::start(serve(config))
+}
@@ -0,0 +1,698 @@
+use crate::admission::{Admission, Admitted, CloseReason, Peer};
+use crate::db::{Database, Event};
+use crate::dispatch::Dispatcher;
+use crate::session::Session;
+use crate::{execute, fill};
+use map8x32_protocol::codec::{Request, Response, MAX_MESSAGE_LEN};
+use map8x32_protocol::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::io;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::{self, error::RecvError, error::TryRecvError};
+use tokio::time::{Instant, Interval};
+use tracing::{debug, warn, Instrument};
+
+/// Appended to the client's key before hashing, per RFC 6455.
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_HANDSHAKE: usize = 8 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_UNSUPPORTED_DATA: u16 = 1003;
+const CLOSE_INVALID_TEXT: u16 = 1007;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &byte)| {
+            bits | u32::from(byte) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => out.push(ALPHABET[(bits >> (18 - 6 * i) & 63) as usize] as char),
+                false => out.push('='),
+            }
+        }
+    }
+    out
+}
+
+/// The `Sec-WebSocket-Accept` answer to a handshake's `Sec-WebSocket-Key`.
+fn accept_key(key: &str) -> String {
+    let digest = ring::digest::digest(
+        &ring::digest::SHA1_FOR_LEGACY_USE_ONLY,
+        format!("{key}{HANDSHAKE_GUID}").as_bytes(),
+    );
+    base64(digest.as_ref())
+}
+
+/// The `Sec-WebSocket-Key` of an opening handshake, once the head checks
+/// out as a version 13 WebSocket upgrade.
+fn handshake_key(head: &str) -> Result<&str, &'static str> {
+    let mut lines = head.split("\r\n");
+    let mut request = lines.next().unwrap_or_default().split_whitespace();
+    if request.next() != Some("GET") {
+        return Err("expected a GET request");
+    }
+    let (mut upgrade, mut connection, mut version, mut key) = (false, false, false, None);
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let (name, value) = (name.trim().to_ascii_lowercase(), value.trim());
+        let has_token = |token: &str| {
+            value
+                .split(',')
+                .any(|part| part.trim().eq_ignore_ascii_case(token))
+        };
+        match name.as_str() {
+            "upgrade" => upgrade = has_token("websocket"),
+            "connection" => connection = has_token("upgrade"),
+            "sec-websocket-version" => version = value == "13",
+            "sec-websocket-key" => key = Some(value),
+            _ => {}
+        }
+    }
+    match key {
+        _ if !upgrade || !connection => Err("expected a WebSocket upgrade"),
+        _ if !version => Err("only WebSocket version 13 is supported"),
+        Some(key) => Ok(key),
+        None => Err("missing Sec-WebSocket-Key"),
+    }
+}
+
+#[derive(Debug)]
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Splits the next complete client frame off `input`, unmasked. `Ok(None)`
+/// means more bytes are needed; an error is the close code to fail with.
+fn parse_frame(input: &[u8]) -> Result<Option<(Frame, usize)>, u16> {
+    if input.len() < 2 {
+        return Ok(None);
+    }
+    let (fin, opcode) = (input[0] & 0x80 != 0, input[0] & 0x0f);
+    // No extensions are negotiated, so the reserved bits stay clear, and
+    // clients always mask.
+    if input[0] & 0x70 != 0 || input[1] & 0x80 == 0 {
+        return Err(CLOSE_PROTOCOL_ERROR);
+    }
+    let (len, at) = match input[1] & 0x7f {
+        126 if input.len() < 4 => return Ok(None),
+        126 => (u64::from(u16::from_be_bytes([input[2], input[3]])), 4),
+        127 if input.len() < 10 => return Ok(None),
+        127 => (u64::from_be_bytes(input[2..10].try_into().unwrap()), 10),
+        len => (u64::from(len), 2),
+    };
+    if opcode >= OPCODE_CLOSE && (!fin || len > 125) {
+        return Err(CLOSE_PROTOCOL_ERROR);
+    }
+    if len > MAX_MESSAGE_LEN as u64 {
+        return Err(CLOSE_TOO_BIG);
+    }
+    let start = at + 4;
+    let end = start + len as usize;
+    if input.len() < end {
+        return Ok(None);
+    }
+    let mask = &input[at..start];
+    let payload = input[start..end]
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| byte ^ mask[i % 4])
+        .collect();
+    Ok(Some((
+        Frame {
+            fin,
+            opcode,
+            payload,
+        },
+        end,
+    )))
+}
+
+fn frame(out: &mut Vec<u8>, opcode: u8, payload: &[u8]) {
+    out.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..=125 => out.push(len as u8),
+        len @ 126..=0xffff => {
+            out.push(126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            out.push(127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(payload);
+}
+
+fn close(out: &mut Vec<u8>, code: u16) {
+    frame(out, OPCODE_CLOSE, &code.to_be_bytes());
+}
+
+fn text(out: &mut Vec<u8>, message: &Value) {
+    frame(out, OPCODE_TEXT, message.to_string().as_bytes());
+}
+
+fn status_name(status: u8) -> &'static str {
+    match status {
+        STATUS_NOT_FOUND => "not_found",
+        STATUS_OK => "ok",
+        status => StatusError::new(status).name(),
+    }
+}
+
+/// The JSON commands understood, tagged by `op`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Command {
+    Ping,
+    Auth { token: String },
+    Select { db: u8 },
+    Set { key: u8, value: u32 },
+    Get { key: u8 },
+    Delete { key: u8 },
+    List,
+    Subscribe { keys: Vec<u8> },
+    Unsubscribe { keys: Vec<u8> },
+    SubscribeEvents { events: Vec<EventKind> },
+    UnsubscribeEvents { events: Vec<EventKind> },
+}
+
+/// The kinds of [`Event`] a connection can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum EventKind {
+    Expired,
+    Evicted,
+    DeleteAll,
+}
+
+impl EventKind {
+    fn of(event: Event) -> EventKind {
+        match event {
+            Event::Expired { .. } => EventKind::Expired,
+            Event::Evicted { .. } => EventKind::Evicted,
+            Event::DeleteAll => EventKind::DeleteAll,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            EventKind::Expired => "expired",
+            EventKind::Evicted => "evicted",
+            EventKind::DeleteAll => "delete_all",
+        }
+    }
+}
+
+/// Waits for the next changed key, or forever without subscriptions.
+async fn changed(changes: &mut Option<broadcast::Receiver<u8>>) -> Result<u8, RecvError> {
+    match changes {
+        Some(changes) => changes.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Waits for the next event, or forever without event subscriptions.
+async fn happened(events: &mut Option<broadcast::Receiver<Event>>) -> Result<Event, RecvError> {
+    match events {
+        Some(events) => events.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn tick(keepalive: &mut Option<Interval>) {
+    match keepalive {
+        Some(keepalive) => {
+            keepalive.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+struct Connection<'a> {
+    session: Session<'a>,
+    databases: &'a [Arc<Database>],
+    subscribed: BTreeSet<u8>,
+    changes: Option<broadcast::Receiver<u8>>,
+    kinds: BTreeSet<EventKind>,
+    events: Option<broadcast::Receiver<Event>>,
+}
+
+impl Connection<'_> {
+    /// Pushes the current values of `keys`, empty for those with none.
+    async fn push(&self, keys: impl IntoIterator<Item = u8>, out: &mut Vec<u8>) -> Option<()> {
+        for key in keys {
+            let values = match execute(&self.session.sender, Request::Get { key }).await? {
+                Response::Values(values) => values,
+                _ => Vec::new(),
+            };
+            let db = self.session.sender.selected();
+            text(
+                out,
+                &json!({ "event": "change", "db": db, "key": key, "values": values }),
+            );
+        }
+        Some(())
+    }
+
+    /// Pushes every subscribed key the change feed reports, `first` and
+    /// whatever else is already queued, each once.
+    async fn push_changes(
+        &mut self,
+        first: Result<u8, RecvError>,
+        out: &mut Vec<u8>,
+    ) -> Option<()> {
+        let mut keys = BTreeSet::new();
+        let mut next = first;
+        loop {
+            match next {
+                Ok(key) => {
+                    keys.insert(key);
+                }
+                // Too far behind to know which keys changed: resend them all.
+                Err(RecvError::Lagged(_)) => keys.extend(&self.subscribed),
+                Err(RecvError::Closed) => break,
+            }
+            next = match self.changes.as_mut()?.try_recv() {
+                Ok(key) => Ok(key),
+                Err(TryRecvError::Lagged(missed)) => Err(RecvError::Lagged(missed)),
+                Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+            };
+        }
+        let keys: Vec<u8> = keys.intersection(&self.subscribed).copied().collect();
+        self.push(keys, out).await
+    }
+
+    /// Pushes `event` if its kind is subscribed to, or how many events were
+    /// missed if the connection fell behind. `None` means the server is
+    /// shutting down.
+    fn push_event(&self, event: Result<Event, RecvError>, out: &mut Vec<u8>) -> Option<()> {
+        let db = self.session.sender.selected();
+        let message = match event {
+            Ok(event) if !self.kinds.contains(&EventKind::of(event)) => return Some(()),
+            Ok(event @ (Event::Expired { key, count } | Event::Evicted { key, count })) => {
+                json!({ "event": EventKind::of(event).name(), "db": db, "key": key, "count": count })
+            }
+            Ok(Event::DeleteAll) => json!({ "event": "delete_all", "db": db }),
+            Err(RecvError::Lagged(missed)) => {
+                json!({ "event": "lagged", "db": db, "missed": missed })
+            }
+            Err(RecvError::Closed) => return None,
+        };
+        text(out, &message);
+        Some(())
+    }
+
+    fn event_kinds(&self) -> Value {
+        let kinds: Vec<&str> = self.kinds.iter().map(|kind| kind.name()).collect();
+        json!({ "status": "ok", "events": kinds })
+    }
+
+    /// Answers one JSON command, followed by the current values of any keys
+    /// it subscribed to. `None` means the server is shutting down.
+    async fn answer(&mut self, message: &str, out: &mut Vec<u8>) -> Option<()> {
+        let size = message.len();
+        let mut message: Value = match serde_json::from_str(message) {
+            Ok(message) => message,
+            Err(e) => {
+                let reply = json!({ "status": "bad_request", "error": e.to_string() });
+                text(out, &reply);
+                return Some(());
+            }
+        };
+        let id = message
+            .as_object_mut()
+            .and_then(|fields| fields.remove("id"));
+        let mut added = Vec::new();
+        let mut reply = self.reply(message, size, &mut added).await?;
+        if let Some(id) = id {
+            reply["id"] = id;
+        }
+        text(out, &reply);
+        self.push(added, out).await
+    }
+
+    async fn reply(&mut self, message: Value, size: usize, added: &mut Vec<u8>) -> Option<Value> {
+        let status = |status| json!({ "status": status_name(status) });
+        if !self.session.admitted.allow() {
+            return Some(status(STATUS_THROTTLED));
+        }
+        let command = match serde_json::from_value::<Command>(message) {
+            Ok(command) => command,
+            Err(e) => return Some(json!({ "status": "bad_request", "error": e.to_string() })),
+        };
+        if self.session.access.is_none() && !matches!(command, Command::Ping | Command::Auth { .. })
+        {
+            return Some(status(STATUS_UNAUTHORIZED));
+        }
+        Some(match command {
+            Command::Ping => status(STATUS_OK),
+            Command::Auth { token } => match self.session.authenticate(token.as_bytes()) {
+                true => status(STATUS_OK),
+                false => status(STATUS_UNAUTHORIZED),
+            },
+            Command::Select { db } => match self.session.select(db) {
+                true => {
+                    self.subscribed.clear();
+                    self.changes = None;
+                    self.kinds.clear();
+                    self.events = None;
+                    status(STATUS_OK)
+                }
+                false => status(STATUS_BAD_REQUEST),
+            },
+            Command::Set { key, value } => {
+                match self.session.run(Request::Set { key, value }, size).await? {
+                    Response::Ack {
+                        status: STATUS_OK,
+                        seq,
+                    } => json!({ "status": "ok", "seq": seq }),
+                    response => status(response.status()),
+                }
+            }
+            Command::Get { key } => match self.session.run(Request::Get { key }, size).await? {
+                Response::Values(values) => json!({ "status": "ok", "values": values }),
+                response => status(response.status()),
+            },
+            Command::Delete { key } => status(
+                self.session
+                    .run(Request::DeleteByKey { key }, size)
+                    .await?
+                    .status(),
+            ),
+            Command::List => match self
+                .session
+                .run(Request::ListAll { snapshot: false }, size)
+                .await?
+            {
+                Response::Entries(entries) => {
+                    let entries: Vec<Value> = entries
+                        .into_iter()
+                        .map(|(key, values)| json!({ "key": key, "values": values }))
+                        .collect();
+                    json!({ "status": "ok", "entries": entries })
+                }
+                response => status(response.status()),
+            },
+            Command::Subscribe { keys } => {
+                if self.changes.is_none() {
+                    let db = &self.databases[usize::from(self.session.sender.selected())];
+                    self.changes = Some(db.subscribe());
+                }
+                // Subscribed before the values are read, so no change slips
+                // in between.
+                added.extend(keys.into_iter().filter(|&key| self.subscribed.insert(key)));
+                json!({ "status": "ok", "keys": self.subscribed })
+            }
+            Command::Unsubscribe { keys } => {
+                for key in keys {
+                    self.subscribed.remove(&key);
+                }
+                if self.subscribed.is_empty() {
+                    self.changes = None;
+                }
+                json!({ "status": "ok", "keys": self.subscribed })
+            }
+            Command::SubscribeEvents { events } => {
+                if self.events.is_none() {
+                    let db = &self.databases[usize::from(self.session.sender.selected())];
+                    self.events = Some(db.subscribe_events());
+                }
+                self.kinds.extend(events);
+                self.event_kinds()
+            }
+            Command::UnsubscribeEvents { events } => {
+                for kind in events {
+                    self.kinds.remove(&kind);
+                }
+                if self.kinds.is_empty() {
+                    self.events = None;
+                }
+                self.event_kinds()
+            }
+        })
+    }
+}
+
+async fn reject_handshake(socket: &mut TcpStream, status: &str, reason: &str) {
+    let response = format!(
+        "HTTP/1.1 {status}\r\nSec-WebSocket-Version: 13\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{reason}\n",
+        reason.len() + 1
+    );
+    let _ = socket.write_all(response.as_bytes()).await;
+}
+
+/// Completes the opening handshake, leaving anything the client sent after
+/// it in `input`. Fails with the reason the connection is done.
+async fn handshake(
+    socket: &mut TcpStream,
+    input: &mut Vec<u8>,
+    admission: &Admission,
+) -> Result<(), CloseReason> {
+    let mut deadline = None;
+    let end = loop {
+        if let Some(at) = input.windows(4).position(|w| w == b"\r\n\r\n") {
+            break at + 4;
+        }
+        if input.len() > MAX_HANDSHAKE {
+            reject_handshake(
+                socket,
+                "431 Request Header Fields Too Large",
+                "handshake too large",
+            )
+            .await;
+            return Err(CloseReason::ProtocolError);
+        }
+        fill(socket, input, &mut deadline, admission).await?;
+    };
+    let head = String::from_utf8_lossy(&input[..end]).into_owned();
+    input.drain(..end);
+    match handshake_key(&head) {
+        Ok(key) => {
+            let response = format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                accept_key(key)
+            );
+            socket
+                .write_all(response.as_bytes())
+                .await
+                .map_err(|_| CloseReason::IoError)
+        }
+        Err(reason) => {
+            debug!(reason, "refusing a WebSocket handshake");
+            reject_handshake(socket, "400 Bad Request", reason).await;
+            Err(CloseReason::ProtocolError)
+        }
+    }
+}
+
+async fn serve_connection(
+    mut socket: TcpStream,
+    sender: Dispatcher,
+    databases: Vec<Arc<Database>>,
+    admitted: Admitted,
+) {
+    let admission = admitted.admission();
+    let mut input = Vec::new();
+    let reason = match handshake(&mut socket, &mut input, admission).await {
+        Ok(()) => tokio::select! {
+            reason = serve_messages(&mut socket, input, sender, &databases, &admitted) => reason,
+            _ = admitted.activity.killed() => CloseReason::Killed,
+        },
+        Err(reason) => reason,
+    };
+    let _ = socket.shutdown().await;
+    admitted.closed(reason);
+    debug!(reason = reason.name(), "WebSocket connection closed");
+}
+
+/// Serves JSON commands and pushes subscribed changes and events until the
+/// connection ends, returning why it did.
+async fn serve_messages(
+    socket: &mut TcpStream,
+    mut input: Vec<u8>,
+    sender: Dispatcher,
+    databases: &[Arc<Database>],
+    admitted: &Admitted,
+) -> CloseReason {
+    let admission = admitted.admission();
+    let mut connection = Connection {
+        session: Session::new(sender, admitted),
+        databases,
+        subscribed: BTreeSet::new(),
+        changes: None,
+        kinds: BTreeSet::new(),
+        events: None,
+    };
+    // Browsers answer pings on their own, so a live page is never idle.
+    let mut keepalive = admission.idle_timeout.map(|idle_timeout| {
+        let period = idle_timeout / 2;
+        tokio::time::interval_at(Instant::now() + period, period)
+    });
+    let mut message: Option<(u8, Vec<u8>)> = None;
+    let mut consumed = 0;
+    let mut output = Vec::new();
+    let mut deadline = None;
+    loop {
+        let Frame {
+            fin,
+            opcode,
+            payload,
+        } = match parse_frame(&input[consumed..]) {
+            Ok(Some((frame, len))) => {
+                consumed += len;
+                deadline = None;
+                frame
+            }
+            Ok(None) => {
+                if !output.is_empty() {
+                    if socket.write_all(&output).await.is_err() {
+                        return CloseReason::IoError;
+                    }
+                    admitted.activity.sent(output.len());
+                    output.clear();
+                }
+                input.drain(..consumed);
+                consumed = 0;
+                tokio::select! {
+                    read = fill(socket, &mut input, &mut deadline, admission) => match read {
+                        Ok(()) => continue,
+                        Err(reason) => return reason,
+                    },
+                    change = changed(&mut connection.changes) => {
+                        if connection.push_changes(change, &mut output).await.is_none() {
+                            return CloseReason::Shutdown;
+                        }
+                    }
+                    event = happened(&mut connection.events) => {
+                        if connection.push_event(event, &mut output).is_none() {
+                            return CloseReason::Shutdown;
+                        }
+                    }
+                    _ = tick(&mut keepalive) => frame(&mut output, OPCODE_PING, &[]),
+                }
+                continue;
+            }
+            Err(code) => {
+                close(&mut output, code);
+                let _ = socket.write_all(&output).await;
+                return CloseReason::ProtocolError;
+            }
+        };
+        let failure = match opcode {
+            OPCODE_PING => {
+                frame(&mut output, OPCODE_PONG, &payload);
+                continue;
+            }
+            OPCODE_PONG => continue,
+            OPCODE_CLOSE => {
+                let code = payload
+                    .first_chunk::<2>()
+                    .map_or(CLOSE_NORMAL, |code| u16::from_be_bytes(*code));
+                close(&mut output, code);
+                let _ = socket.write_all(&output).await;
+                return CloseReason::Eof;
+            }
+            OPCODE_TEXT | OPCODE_BINARY if message.is_some() => Some(CLOSE_PROTOCOL_ERROR),
+            OPCODE_TEXT | OPCODE_BINARY => {
+                message = Some((opcode, payload));
+                None
+            }
+            OPCODE_CONTINUATION => match &mut message {
+                Some((_, data)) if data.len() + payload.len() <= MAX_MESSAGE_LEN => {
+                    data.extend_from_slice(&payload);
+                    None
+                }
+                Some(_) => Some(CLOSE_TOO_BIG),
+                None => Some(CLOSE_PROTOCOL_ERROR),
+            },
+            _ => Some(CLOSE_PROTOCOL_ERROR),
+        };
+        let failure = match failure {
+            Some(code) => Some(code),
+            None if !fin => continue,
+            None => match message.take() {
+                Some((OPCODE_TEXT, data)) => match String::from_utf8(data) {
+                    Ok(data) => match connection.answer(&data, &mut output).await {
+                        Some(()) => None,
+                        None => Some(CLOSE_NORMAL),
+                    },
+                    Err(_) => Some(CLOSE_INVALID_TEXT),
+                },
+                _ => Some(CLOSE_UNSUPPORTED_DATA),
+            },
+        };
+        if let Some(code) = failure {
+            close(&mut output, code);
+            let _ = socket.write_all(&output).await;
+            return match code {
+                CLOSE_NORMAL => CloseReason::Shutdown,
+                _ => CloseReason::ProtocolError,
+            };
+        }
+    }
+}
+
+/// Serves the JSON WebSocket gateway on `listener`, with the same connection
+/// limits, auth and rate limits as the native listeners.
+pub async fn serve(
+    listener: TcpListener,
+    sender: Dispatcher,
+    databases: Vec<Arc<Database>>,
+    admission: Arc<Admission>,
+) -> io::Result<()> {
+    loop {
+        let queued = admission.queued_slot().await;
+        let (mut socket, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                crate::accept_failed(e).await;
+                continue;
+            }
+        };
+        if let Err(e) = socket.set_nodelay(true) {
+            warn!(error = %e, %addr, "cannot set TCP_NODELAY; dropped a WebSocket connection");
+            continue;
+        }
+        let peer = Peer {
+            addr: Some(addr),
+            ..Peer::default()
+        };
+        let Some(admitted) = admission.admit(queued, peer) else {
+            debug!("connection limit reached; turning a WebSocket client away");
+            tokio::spawn(async move {
+                reject_handshake(
+                    &mut socket,
+                    "503 Service Unavailable",
+                    "max number of clients reached",
+                )
+                .await;
+            });
+            continue;
+        };
+        let span = tracing::info_span!("connection", id = admitted.id, protocol = "websocket");
+        let sender = sender.for_connection(admitted.id, admitted.priority());
+        tokio::spawn(
+            serve_connection(socket, sender, databases.clone(), admitted).instrument(span),
+        );
+    }
+}
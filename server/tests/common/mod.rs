@@ -0,0 +1,267 @@
+//! The server process and raw-socket client the integration suites share.
+
+#![allow(dead_code)]
+
+use map8x32_protocol::*;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use std::{fs, process};
+
+/// One server process in a scratch directory, killed when dropped.
+pub struct Server {
+    child: Child,
+    pub dir: PathBuf,
+    extra: Vec<String>,
+}
+
+impl Server {
+    pub fn start(extra: &[&str]) -> Self {
+        static STARTED: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "map8x32-test-{}-{}",
+            process::id(),
+            STARTED.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("dumps")).unwrap();
+        let extra: Vec<String> = extra.iter().map(|arg| arg.to_string()).collect();
+        let server = Server {
+            child: spawn(&dir, &extra),
+            dir,
+            extra,
+        };
+        server.wait_until_listening();
+        server
+    }
+
+    /// Kills the process with SIGKILL, leaving its files as they are.
+    pub fn kill(&mut self) {
+        self.child.kill().unwrap();
+        self.child.wait().unwrap();
+    }
+
+    /// Sends SIGTERM and waits for the process to exit, returning whether
+    /// it did so cleanly.
+    pub fn terminate(&mut self) -> bool {
+        self.signal("TERM");
+        self.child.wait().unwrap().success()
+    }
+
+    /// Waits for the process to exit on its own, returning whether it did
+    /// so cleanly.
+    pub fn wait(&mut self) -> bool {
+        self.child.wait().unwrap().success()
+    }
+
+    /// Sends the process the signal named `name`, e.g. `"USR1"`.
+    pub fn signal(&self, name: &str) {
+        let sent = process::Command::new("kill")
+            .args([&format!("-{name}"), &self.child.id().to_string()])
+            .status()
+            .unwrap();
+        assert!(sent.success());
+    }
+
+    /// Starts the server again on the same directory and flags, after
+    /// [`Server::kill`].
+    pub fn restart(&mut self) {
+        self.child = spawn(&self.dir, &self.extra);
+        self.wait_until_listening();
+    }
+
+    /// Starts another server on the same directory and flags while this
+    /// one runs, without waiting for it to listen.
+    pub fn successor(&self) -> Server {
+        Server {
+            child: spawn(&self.dir, &self.extra),
+            dir: self.dir.clone(),
+            extra: self.extra.clone(),
+        }
+    }
+
+    fn wait_until_listening(&self) {
+        let started = Instant::now();
+        while UnixStream::connect(self.socket()).is_err() {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "server did not start listening"
+            );
+            sleep(Duration::from_millis(20));
+        }
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.child.id()
+    }
+
+    pub fn socket(&self) -> PathBuf {
+        self.dir.join("server.sock")
+    }
+
+    pub fn connect(&self) -> Client {
+        let stream = UnixStream::connect(self.socket()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        Client(stream)
+    }
+}
+
+fn spawn(dir: &Path, extra: &[String]) -> Child {
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+    Command::new(env!("CARGO_BIN_EXE_map8x32-server"))
+        .args(["--socket", &path("server.sock")])
+        .args(["--data-dir", &path("data")])
+        .args(["--dump-dir", &path("dumps")])
+        .args([
+            "--databases",
+            "2",
+            "--dead-letters",
+            "8",
+            "--log-level",
+            "error",
+        ])
+        .args(extra)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap()
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+pub fn frame(op: u8, key: u8, value: u32) -> Vec<u8> {
+    let mut frame = vec![op, key];
+    frame.extend_from_slice(&value.to_le_bytes());
+    frame
+}
+
+/// A frame with `trailer` after it and its length as the value.
+pub fn with_trailer(op: u8, key: u8, trailer: &[u8]) -> Vec<u8> {
+    let mut frame = frame(op, key, trailer.len() as u32);
+    frame.extend_from_slice(trailer);
+    frame
+}
+
+pub struct Client(pub UnixStream);
+
+impl Client {
+    pub fn send(&mut self, bytes: &[u8]) {
+        self.0.write_all(bytes).unwrap();
+    }
+
+    pub fn read(&mut self, len: usize) -> Vec<u8> {
+        let mut bytes = vec![0; len];
+        self.0.read_exact(&mut bytes).unwrap();
+        bytes
+    }
+
+    pub fn u8(&mut self) -> u8 {
+        self.read(1)[0]
+    }
+
+    pub fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.read(4).try_into().unwrap())
+    }
+
+    pub fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.read(8).try_into().unwrap())
+    }
+
+    pub fn values(&mut self) -> Vec<u32> {
+        let count = self.u32() as usize;
+        self.read(count * 4)
+            .chunks(4)
+            .map(|value| u32::from_le_bytes(value.try_into().unwrap()))
+            .collect()
+    }
+
+    /// Sends one frame and reads a status-only answer.
+    pub fn status(&mut self, op: u8, key: u8, value: u32) -> u8 {
+        self.send(&frame(op, key, value));
+        self.u8()
+    }
+
+    pub fn get(&mut self, key: u8) -> Option<Vec<u32>> {
+        match self.status(OP_GET, key, 0) {
+            STATUS_OK => Some(self.values()),
+            status => {
+                assert_eq!(status, STATUS_NOT_FOUND);
+                None
+            }
+        }
+    }
+
+    pub fn list(&mut self) -> Vec<(u8, Vec<u32>)> {
+        self.listing(0)
+    }
+
+    /// LIST_ALL's snapshot mode.
+    pub fn snapshot(&mut self) -> Vec<(u8, Vec<u32>)> {
+        self.listing(1)
+    }
+
+    fn listing(&mut self, mode: u8) -> Vec<(u8, Vec<u32>)> {
+        assert_eq!(self.status(OP_LIST_ALL, mode, 0), STATUS_OK);
+        let count = self.u32();
+        (0..count).map(|_| (self.u8(), self.values())).collect()
+    }
+
+    pub fn hello(&mut self, version: u8) -> Vec<u8> {
+        self.send(&frame(OP_HELLO, version, PROTOCOL_MAGIC));
+        let status = self.u8();
+        let mut response = vec![status];
+        match status {
+            STATUS_OK => response.extend(self.read(5)),
+            STATUS_UNSUPPORTED_VERSION => response.push(self.u8()),
+            _ => {}
+        }
+        response
+    }
+
+    /// Whether the server has closed the connection, ending it either way.
+    pub fn closed(&mut self) -> bool {
+        let mut byte = [0];
+        match self.0.read(&mut byte) {
+            Ok(0) => true,
+            Ok(_) => false,
+            Err(e) => matches!(e.kind(), ErrorKind::ConnectionReset | ErrorKind::BrokenPipe),
+        }
+    }
+}
+
+/// SplitMix64, so any seed, zero included, gives a full-period stream.
+pub struct Rng(pub u64);
+
+impl Rng {
+    pub fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+pub fn env_u64(name: &str) -> Option<u64> {
+    let value = std::env::var(name).ok()?;
+    Some(
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("{name} must be a number")),
+    )
+}
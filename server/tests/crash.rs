@@ -0,0 +1,164 @@
+//! Kills the server with SIGKILL at random points while a writer pipelines
+//! SET and DELETE_BY_KEY at it, restarts it on the same data directory, and
+//! checks the recovered store against a model. Every acknowledged write must
+//! survive, whatever `--wal-fsync` says, since the log is written before a
+//! write is answered and a killed process leaves the page cache behind. Of
+//! the writes sent but not answered, only a prefix may have landed.
+//!
+//! Snapshots run every second and on a small log size, so kills also land
+//! during segment switches and snapshot writes. Each run starts from a fresh
+//! seed; `MAP8X32_CRASH_SEED` replays one and `MAP8X32_CRASH_ROUNDS` sets
+//! how many kills it survives (default 8).
+
+mod common;
+
+use common::{env_u64, frame, Rng, Server};
+use map8x32_protocol::*;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::thread::{self, sleep};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_ROUNDS: u64 = 8;
+const OPS_PER_ROUND: usize = 150_000;
+const PIPELINE: usize = 16;
+const KEYS: u64 = 8;
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Set { key: u8, value: u32 },
+    Delete { key: u8 },
+}
+
+impl Op {
+    fn frame(self) -> Vec<u8> {
+        match self {
+            Op::Set { key, value } => frame(OP_SET, key, value),
+            Op::Delete { key } => frame(OP_DELETE_BY_KEY, key, 0),
+        }
+    }
+
+    /// Applies the op to `model`, returning the status the server answers.
+    fn apply(self, model: &mut HashMap<u8, Vec<u32>>) -> u8 {
+        match self {
+            Op::Set { key, value } => {
+                model.entry(key).or_default().push(value);
+                STATUS_OK
+            }
+            Op::Delete { key } => match model.remove(&key) {
+                Some(_) => STATUS_OK,
+                None => STATUS_NOT_FOUND,
+            },
+        }
+    }
+}
+
+/// Mostly SETs, with deletions rare enough that keys grow across several
+/// snapshots; values count up, so a misplaced one stands out.
+fn ops(rng: &mut Rng, first_value: u32) -> Vec<Op> {
+    (0..OPS_PER_ROUND)
+        .map(|i| {
+            let key = rng.below(KEYS) as u8;
+            match rng.below(200) {
+                0 => Op::Delete { key },
+                _ => Op::Set {
+                    key,
+                    value: first_value + i as u32,
+                },
+            }
+        })
+        .collect()
+}
+
+/// Sends `ops` in pipelined batches until the connection drops. Returns the
+/// statuses answered, in order, and how many ops past them were sent, or
+/// may have been, without an answer.
+fn write_until_killed(mut stream: UnixStream, ops: &[Op]) -> (Vec<u8>, usize) {
+    let mut statuses = Vec::with_capacity(ops.len());
+    for batch in ops.chunks(PIPELINE) {
+        let bytes: Vec<u8> = batch.iter().flat_map(|op| op.frame()).collect();
+        if stream.write_all(&bytes).is_err() {
+            return (statuses, batch.len());
+        }
+        for answered in 0..batch.len() {
+            let mut status = [0];
+            if stream.read_exact(&mut status).is_err() {
+                return (statuses, batch.len() - answered);
+            }
+            statuses.push(status[0]);
+        }
+    }
+    (statuses, 0)
+}
+
+fn sorted(model: &HashMap<u8, Vec<u32>>) -> Vec<(u8, Vec<u32>)> {
+    let mut entries: Vec<_> = model
+        .iter()
+        .map(|(&key, values)| (key, values.clone()))
+        .collect();
+    entries.sort_unstable();
+    entries
+}
+
+#[test]
+fn acknowledged_writes_survive_sigkill() {
+    let seed = env_u64("MAP8X32_CRASH_SEED").unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64
+    });
+    let rounds = env_u64("MAP8X32_CRASH_ROUNDS").unwrap_or(DEFAULT_ROUNDS);
+    let context = format!("seed {seed} (MAP8X32_CRASH_SEED={seed} replays it)");
+    let mut rng = Rng(seed);
+    let mut server = Server::start(&[
+        "--snapshot-interval",
+        "1",
+        "--wal-max-bytes",
+        "16384",
+        "--wal-fsync",
+        "never",
+    ]);
+    let mut model = HashMap::new();
+    let mut next_value = 0;
+
+    for round in 0..rounds {
+        let ops = ops(&mut rng, next_value);
+        next_value += OPS_PER_ROUND as u32;
+        let stream = server.connect().0;
+        let writer = {
+            let ops = ops.clone();
+            thread::spawn(move || write_until_killed(stream, &ops))
+        };
+        sleep(Duration::from_millis(rng.below(1500)));
+        server.kill();
+        let (statuses, unanswered) = writer.join().unwrap();
+
+        for (i, (op, status)) in ops.iter().zip(&statuses).enumerate() {
+            let expected = op.apply(&mut model);
+            assert_eq!(
+                *status, expected,
+                "{context}, round {round}: op {i} {op:?} answered {status}"
+            );
+        }
+
+        server.restart();
+        let recovered = server.connect().list();
+        let mut landed = 0;
+        while sorted(&model) != recovered {
+            let Some(&op) = ops[statuses.len()..]
+                .get(landed)
+                .filter(|_| landed < unanswered)
+            else {
+                panic!(
+                    "{context}, round {round}: after {} answered and {unanswered} unanswered writes, recovered {recovered:?}, expected {:?}",
+                    statuses.len(),
+                    sorted(&model)
+                );
+            };
+            op.apply(&mut model);
+            landed += 1;
+        }
+    }
+}
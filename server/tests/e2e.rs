@@ -0,0 +1,2693 @@
+//! Drives the server binary over its Unix socket and checks every opcode's
+//! responses byte for byte.
+
+mod common;
+
+use common::{frame, with_trailer, Client, Server};
+use map8x32_protocol::capture::{self, CaptureRecord, Captured};
+use map8x32_protocol::codec::{Health, CODEC_PROTOBUF};
+use map8x32_protocol::*;
+use std::io::{Read, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::thread::sleep;
+use std::time::Duration;
+use std::{fs, process};
+
+#[test]
+fn set_appends_and_get_reads_back_in_order() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    assert_eq!(client.status(OP_SET, 7, 10), STATUS_OK);
+    assert_eq!(client.status(OP_SET, 7, 20), STATUS_OK);
+    assert_eq!(client.status(OP_SET, 7, 10), STATUS_OK);
+    client.send(&frame(OP_GET, 7, 0));
+    assert_eq!(
+        client.read(17),
+        [
+            &[STATUS_OK][..],
+            &3u32.to_le_bytes(),
+            &10u32.to_le_bytes(),
+            &20u32.to_le_bytes(),
+            &10u32.to_le_bytes()
+        ]
+        .concat()
+    );
+    assert_eq!(client.get(8), None);
+}
+
+#[test]
+fn get_finds_keys_however_they_came_and_went() {
+    let mut server = Server::start(&["--delete-all-token", "7"]);
+    let mut client = server.connect();
+    assert_eq!(client.get(1), None);
+    assert_eq!(client.status(OP_SET, 1, 10), STATUS_OK);
+    assert_eq!(client.get(1), Some(vec![10]));
+    assert_eq!(client.status(OP_SWAP, 1, 2), STATUS_OK);
+    assert_eq!((client.get(1), client.get(2)), (None, Some(vec![10])));
+    assert_eq!(client.status(OP_RENAME, 2, 3), STATUS_OK);
+    assert_eq!((client.get(2), client.get(3)), (None, Some(vec![10])));
+    assert_eq!(client.status(OP_DELETE_AT, 3, 0), STATUS_OK);
+    assert_eq!(client.u32(), 10);
+    assert_eq!(client.get(3), None);
+    for key in [4, 5] {
+        assert_eq!(client.status(OP_SET, key, 40), STATUS_OK);
+    }
+    assert_eq!(client.status(OP_DELETE_BY_KEY, 4, 0), STATUS_OK);
+    assert_eq!((client.get(4), client.get(5)), (None, Some(vec![40])));
+
+    server.kill();
+    server.restart();
+    let mut client = server.connect();
+    assert_eq!((client.get(4), client.get(5)), (None, Some(vec![40])));
+    assert_eq!(client.status(OP_DELETE_ALL, 0, 7), STATUS_OK);
+    assert_eq!(client.get(5), None);
+}
+
+#[test]
+fn get_last_n_and_get_first_n_read_one_end_of_a_key() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    for value in 1..=5 {
+        assert_eq!(client.status(OP_SET, 4, value), STATUS_OK);
+    }
+    assert_eq!(client.status(OP_GET_LAST_N, 4, 2), STATUS_OK);
+    assert_eq!(client.values(), [4, 5]);
+    assert_eq!(client.status(OP_GET_FIRST_N, 4, 2), STATUS_OK);
+    assert_eq!(client.values(), [1, 2]);
+    assert_eq!(client.status(OP_GET_LAST_N, 4, 9), STATUS_OK);
+    assert_eq!(client.values(), [1, 2, 3, 4, 5]);
+    assert_eq!(client.status(OP_GET_FIRST_N, 4, 0), STATUS_OK);
+    assert_eq!(client.values(), []);
+    assert_eq!(client.status(OP_GET_LAST_N, 5, 1), STATUS_NOT_FOUND);
+}
+
+#[test]
+fn delete_by_key_and_delete_all() {
+    let server = Server::start(&["--delete-all-token", "7"]);
+    let mut client = server.connect();
+    for key in [1, 2, 3] {
+        assert_eq!(client.status(OP_SET, key, key as u32), STATUS_OK);
+    }
+    assert_eq!(client.status(OP_DELETE_BY_KEY, 2, 0), STATUS_OK);
+    assert_eq!(client.status(OP_DELETE_BY_KEY, 2, 0), STATUS_NOT_FOUND);
+    assert_eq!(client.list(), [(1, vec![1]), (3, vec![3])]);
+    assert_eq!(client.status(OP_DELETE_ALL, 0, 0), STATUS_UNAUTHORIZED);
+    assert_eq!(client.status(OP_DELETE_ALL, 0, 8), STATUS_UNAUTHORIZED);
+    assert_eq!(client.list(), [(1, vec![1]), (3, vec![3])]);
+    assert_eq!(client.status(OP_DELETE_ALL, 0, 7), STATUS_OK);
+    assert_eq!(client.list(), []);
+}
+
+#[test]
+fn delete_at_removes_and_returns_the_value_at_an_index() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    for value in [10, 20, 30] {
+        assert_eq!(client.status(OP_SET, 5, value), STATUS_OK);
+    }
+    assert_eq!(client.status(OP_DELETE_AT, 5, 1), STATUS_OK);
+    assert_eq!(client.u32(), 20);
+    assert_eq!(client.status(OP_DELETE_AT, 5, 2), STATUS_NOT_FOUND);
+    assert_eq!(client.status(OP_DELETE_AT, 6, 0), STATUS_NOT_FOUND);
+    assert_eq!(client.get(5), Some(vec![10, 30]));
+    for expected in [10, 30] {
+        assert_eq!(client.status(OP_DELETE_AT, 5, 0), STATUS_OK);
+        assert_eq!(client.u32(), expected);
+    }
+    assert_eq!(client.get(5), None);
+    assert_eq!(client.list(), []);
+}
+
+#[test]
+fn dedup_keeps_the_first_of_each_value() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    for value in [7, 3, 7, 5, 3, 7] {
+        assert_eq!(client.status(OP_SET, 2, value), STATUS_OK);
+    }
+    assert_eq!(client.status(OP_DEDUP, 2, 0), STATUS_OK);
+    assert_eq!(client.u32(), 3);
+    assert_eq!(client.get(2), Some(vec![7, 3, 5]));
+    assert_eq!(client.status(OP_DEDUP, 2, 0), STATUS_OK);
+    assert_eq!(client.u32(), 0);
+    assert_eq!(client.status(OP_DEDUP, 8, 0), STATUS_NOT_FOUND);
+}
+
+#[test]
+fn push_appends_every_value_in_one_step() {
+    let mut server = Server::start(&[]);
+    let mut client = server.connect();
+    let push = |key, values: &[u32]| {
+        let mut request = frame(OP_PUSH, key, values.len() as u32);
+        for value in values {
+            request.extend_from_slice(&value.to_le_bytes());
+        }
+        request
+    };
+    assert_eq!(client.status(OP_SET, 4, 1), STATUS_OK);
+    client.send(&push(4, &[2, 3, 4]));
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.u32(), 4);
+    client.send(&push(4, &[]));
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.u32(), 4, "nothing to push");
+
+    client.send(&with_trailer(OP_SET_TYPED, 9, &[2, b'x']));
+    assert_eq!(client.u8(), STATUS_OK);
+    client.send(&push(9, &[1]));
+    assert_eq!(client.u8(), STATUS_CONFLICT);
+
+    server.kill();
+    server.restart();
+    let mut client = server.connect();
+    assert_eq!(
+        client.get(4),
+        Some(vec![1, 2, 3, 4]),
+        "replayed as one push"
+    );
+}
+
+#[test]
+fn getdel_takes_a_keys_values_and_deletes_it() {
+    let mut server = Server::start(&[]);
+    let mut client = server.connect();
+    for value in [10, 20, 10] {
+        assert_eq!(client.status(OP_SET, 3, value), STATUS_OK);
+    }
+    assert_eq!(client.status(OP_GETDEL, 3, 0), STATUS_OK);
+    assert_eq!(client.values(), [10, 20, 10]);
+    assert_eq!(client.status(OP_GETDEL, 3, 0), STATUS_NOT_FOUND);
+    assert_eq!(client.get(3), None);
+
+    client.send(&with_trailer(OP_SET_TYPED, 9, &[2, b'x']));
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.status(OP_GETDEL, 9, 0), STATUS_CONFLICT);
+
+    server.kill();
+    server.restart();
+    let mut client = server.connect();
+    assert_eq!(client.get(3), None);
+}
+
+#[test]
+fn getset_swaps_in_a_value_and_answers_the_old_ones() {
+    let mut server = Server::start(&[]);
+    let mut client = server.connect();
+    let getset =
+        |key, value: u32| [frame(OP_GETSET, key, 1), value.to_le_bytes().to_vec()].concat();
+    for value in [4, 5] {
+        assert_eq!(client.status(OP_SET, 6, value), STATUS_OK);
+    }
+    client.send(&getset(6, 9));
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.values(), [4, 5]);
+    assert_eq!(client.get(6), Some(vec![9]));
+    assert_eq!(client.status(OP_GETSET, 6, 0), STATUS_OK);
+    assert_eq!(client.values(), [9]);
+    assert_eq!(client.get(6), None);
+    assert_eq!(client.status(OP_GETSET, 6, 0), STATUS_OK);
+    assert_eq!(client.values(), []);
+
+    client.send(&getset(7, 1));
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.values(), []);
+    server.kill();
+    server.restart();
+    let mut client = server.connect();
+    assert_eq!(client.get(7), Some(vec![1]));
+}
+
+#[test]
+fn sort_orders_a_keys_values_in_place() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    for value in [30, 10, 20, 10] {
+        assert_eq!(client.status(OP_SET, 4, value), STATUS_OK);
+    }
+    assert_eq!(client.status(OP_SORT, 4, 1), STATUS_OK);
+    assert_eq!(client.get(4), Some(vec![30, 20, 10, 10]));
+    assert_eq!(client.status(OP_SORT, 4, 0), STATUS_OK);
+    assert_eq!(client.get(4), Some(vec![10, 10, 20, 30]));
+    assert_eq!(client.status(OP_SORT, 9, 0), STATUS_NOT_FOUND);
+}
+
+#[test]
+fn memusage_reports_a_key_and_the_total() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    assert_eq!(client.status(OP_MEMUSAGE, 3, 0), STATUS_NOT_FOUND);
+    assert_eq!(client.status(OP_SET, 3, 1), STATUS_OK);
+    assert_eq!(client.status(OP_MEMUSAGE, 3, 0), STATUS_OK);
+    let inline = client.u64();
+    for value in 0..100 {
+        assert_eq!(client.status(OP_SET, 4, value), STATUS_OK);
+    }
+    assert_eq!(client.status(OP_MEMUSAGE, 4, 0), STATUS_OK);
+    let spilled = client.u64();
+    assert!(spilled >= inline + 400, "{spilled} bytes for 100 values");
+    assert_eq!(client.status(OP_MEMUSAGE, 0, 1), STATUS_OK);
+    assert_eq!(client.u64(), inline + spilled);
+}
+
+#[test]
+fn keys_move_to_the_heap_past_four_values() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    // MEMUSAGE of key 6, its COUNT, and INFO's memory estimate.
+    let usage = |client: &mut common::Client| {
+        assert_eq!(client.status(OP_MEMUSAGE, 6, 0), STATUS_OK);
+        let key = client.u64();
+        assert_eq!(client.status(OP_COUNT, 6, 0), STATUS_OK);
+        let count = client.u64();
+        assert_eq!(client.status(OP_INFO, 0, 0), STATUS_OK);
+        client.read(24);
+        let estimate = client.u64();
+        client.read(16);
+        for _ in 0..client.u8() {
+            let len = client.u8() as usize;
+            client.read(len + 8);
+        }
+        client.read(10);
+        assert_eq!(client.u32(), 0, "allocations");
+        client.read(24);
+        (key, count, estimate)
+    };
+    for value in 1..=4 {
+        assert_eq!(client.status(OP_SET, 6, value), STATUS_OK);
+    }
+    let (inline, count, inline_estimate) = usage(&mut client);
+    assert_eq!(count, 4);
+    assert_eq!(client.status(OP_SET, 7, 1), STATUS_OK);
+    assert_eq!(client.status(OP_MEMUSAGE, 7, 0), STATUS_OK);
+    assert_eq!(client.u64(), inline, "one value or four");
+    assert_eq!(client.status(OP_DELETE_BY_KEY, 7, 0), STATUS_OK);
+
+    assert_eq!(client.status(OP_SET, 6, 5), STATUS_OK);
+    assert_eq!(client.get(6), Some(vec![1, 2, 3, 4, 5]));
+    let (spilled, count, estimate) = usage(&mut client);
+    assert_eq!(count, 5);
+    assert!(spilled >= inline + 5 * 4, "{spilled} bytes");
+    assert_eq!(estimate, inline_estimate + 4);
+
+    for index in [4, 0] {
+        assert_eq!(client.status(OP_DELETE_AT, 6, index), STATUS_OK);
+        client.u32();
+    }
+    assert_eq!(client.get(6), Some(vec![2, 3, 4]));
+    let (shrunk, count, estimate) = usage(&mut client);
+    assert_eq!(count, 3);
+    assert_eq!(shrunk, spilled, "the heap capacity is kept");
+    assert_eq!(estimate, inline_estimate - 4);
+}
+
+#[test]
+fn keystats_counts_a_keys_reads_and_writes() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    let stats = |client: &mut common::Client, key| {
+        assert_eq!(client.status(OP_KEYSTATS, key, 0), STATUS_OK);
+        [client.u64(), client.u64(), client.u64(), client.u64()]
+    };
+    assert_eq!(stats(&mut client, 5), [0, 0, 0, 0]);
+    assert_eq!(client.get(5), None);
+    assert_eq!(client.status(OP_SET, 5, 1), STATUS_OK);
+    assert_eq!(client.status(OP_SET, 5, 2), STATUS_OK);
+    assert_eq!(client.get(5), Some(vec![1, 2]));
+    let [hits, misses, sets, last_access_ms] = stats(&mut client, 5);
+    assert_eq!([hits, misses, sets], [1, 1, 2]);
+    assert!(last_access_ms > 0);
+    assert_eq!(stats(&mut client, 5)[3], last_access_ms);
+    assert_eq!(stats(&mut client, 6), [0, 0, 0, 0]);
+}
+
+#[test]
+fn packed_keys_read_and_change_like_plain_ones() {
+    let server = Server::start(&["--pack-values-above", "4"]);
+    let mut client = server.connect();
+    let mut values = vec![5, 5, 300, 70_000, u32::MAX];
+    for &value in &values {
+        assert_eq!(client.status(OP_SET, 3, value), STATUS_OK);
+    }
+    assert_eq!(client.get(3), Some(values.clone()));
+    assert_eq!(client.status(OP_SET, 3, 1), STATUS_OK);
+    values.push(1);
+    assert_eq!(client.get(3), Some(values.clone()));
+    assert_eq!(client.status(OP_SORT, 3, 0), STATUS_OK);
+    values.sort();
+    assert_eq!(client.get(3), Some(values.clone()));
+    assert_eq!(client.status(OP_DELETE_AT, 3, 2), STATUS_OK);
+    assert_eq!(client.u32(), 5);
+    values.remove(2);
+    assert_eq!(client.get(3), Some(values.clone()));
+    assert_eq!(client.list(), [(3, values)]);
+}
+
+#[test]
+fn random_key_samples_existing_keys() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    assert_eq!(client.status(OP_RANDOM_KEY, 0, 0), STATUS_NOT_FOUND);
+    assert_eq!(client.status(OP_RANDOM_KEY, 0, 1), STATUS_NOT_FOUND);
+    for (key, value) in [(3, 30), (3, 31), (9, 90)] {
+        assert_eq!(client.status(OP_SET, key, value), STATUS_OK);
+    }
+    let mut seen = Vec::new();
+    for _ in 0..200 {
+        assert_eq!(client.status(OP_RANDOM_KEY, 0, 0), STATUS_OK);
+        let key = client.u8();
+        assert!(key == 3 || key == 9, "picked key {key}");
+        assert_eq!(client.status(OP_RANDOM_KEY, 0, 1), STATUS_OK);
+        let sample = (client.u8(), client.u32());
+        assert!(
+            [(3, 30), (3, 31), (9, 90)].contains(&sample),
+            "picked {sample:?}"
+        );
+        if !seen.contains(&sample) {
+            seen.push(sample);
+        }
+    }
+    assert_eq!(seen.len(), 3, "200 samples should reach every value");
+}
+
+#[test]
+fn scan_walks_the_keyspace_in_batches() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    for key in [0, 7, 8, 100, 255] {
+        assert_eq!(client.status(OP_SET, key, 1), STATUS_OK);
+    }
+    let mut scan = |cursor, count| {
+        assert_eq!(client.status(OP_SCAN, count, cursor), STATUS_OK);
+        let next = client.u32();
+        let len = client.u32() as usize;
+        (next, client.read(len))
+    };
+    assert_eq!(scan(0, 2), (8, vec![0, 7]));
+    assert_eq!(scan(8, 2), (101, vec![8, 100]));
+    assert_eq!(scan(101, 2), (0, vec![255]));
+    assert_eq!(scan(0, 0), (0, vec![0, 7, 8, 100, 255]));
+    assert_eq!(scan(0, 5), (0, vec![0, 7, 8, 100, 255]));
+    assert_eq!(client.status(OP_SCAN, 1, 256), STATUS_BAD_REQUEST);
+}
+
+#[test]
+fn scans_come_in_the_same_order_every_run() {
+    // Every SCAN batch, then LIST_ALL.
+    let walk = |client: &mut Client| {
+        let mut batches = Vec::new();
+        let mut cursor = 0;
+        loop {
+            assert_eq!(client.status(OP_SCAN, 3, cursor), STATUS_OK);
+            cursor = client.u32();
+            let len = client.u32() as usize;
+            batches.push(client.read(len));
+            if cursor == 0 {
+                break;
+            }
+        }
+        (batches, client.list())
+    };
+    let keys = [200, 3, 97, 0, 255, 41, 128, 7];
+    let mut runs = Vec::new();
+    for order in ["hash", "ordered"] {
+        for written in [keys.to_vec(), keys.iter().rev().copied().collect()] {
+            let mut server = Server::start(&["--storage-order", order]);
+            let mut client = server.connect();
+            for key in written {
+                for value in [u32::from(key), u32::from(key) + 1] {
+                    assert_eq!(client.status(OP_SET, key, value), STATUS_OK);
+                }
+            }
+            runs.push(walk(&mut client));
+            server.kill();
+            server.restart();
+            runs.push(walk(&mut server.connect()));
+        }
+    }
+    assert!(runs.windows(2).all(|pair| pair[0] == pair[1]), "{runs:?}");
+    assert_eq!(
+        runs[0].0,
+        [vec![0, 3, 7], vec![41, 97, 128], vec![200, 255]]
+    );
+}
+
+#[test]
+fn rename_moves_or_merges_values_into_another_key() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    for (key, value) in [(1, 10), (1, 11), (2, 20), (3, 30)] {
+        assert_eq!(client.status(OP_SET, key, value), STATUS_OK);
+    }
+    assert_eq!(client.status(OP_RENAME, 1, 2), STATUS_OK);
+    assert_eq!(client.list(), [(2, vec![10, 11]), (3, vec![30])]);
+    assert_eq!(client.status(OP_RENAME, 3, 2 | 1 << 8), STATUS_OK);
+    assert_eq!(client.list(), [(2, vec![10, 11, 30])]);
+    assert_eq!(client.status(OP_RENAME, 3, 2), STATUS_NOT_FOUND);
+    assert_eq!(client.status(OP_RENAME, 2, 2), STATUS_OK);
+    assert_eq!(client.get(2), Some(vec![10, 11, 30]));
+
+    client.send(&with_trailer(OP_SET_TYPED, 9, &[2, b'x']));
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.status(OP_RENAME, 9, 2 | 1 << 8), STATUS_CONFLICT);
+    assert_eq!(client.status(OP_RENAME, 2, 9), STATUS_OK);
+    assert_eq!(client.list(), [(9, vec![10, 11, 30])]);
+}
+
+#[test]
+fn swap_exchanges_two_keys_values_and_tags() {
+    let mut server = Server::start(&[]);
+    let mut client = server.connect();
+    for (key, value) in [(1, 10), (1, 11), (2, 20)] {
+        assert_eq!(client.status(OP_SET, key, value), STATUS_OK);
+    }
+    assert_eq!(client.status(OP_TAG, 1, 5), STATUS_OK);
+    assert_eq!(client.status(OP_SWAP, 1, 2), STATUS_OK);
+    assert_eq!(client.list(), [(1, vec![20]), (2, vec![10, 11])]);
+    assert_eq!(client.status(OP_LIST_TAGGED, 5, 0), STATUS_OK);
+    assert_eq!(client.u32(), 1);
+    assert_eq!((client.u8(), client.values()), (2, vec![10, 11]));
+
+    assert_eq!(client.status(OP_SWAP, 2, 7), STATUS_OK);
+    assert_eq!(client.list(), [(1, vec![20]), (7, vec![10, 11])]);
+    assert_eq!(client.status(OP_SWAP, 8, 9), STATUS_NOT_FOUND);
+
+    server.kill();
+    server.restart();
+    let mut client = server.connect();
+    assert_eq!(client.list(), [(1, vec![20]), (7, vec![10, 11])]);
+}
+
+#[test]
+fn compare_answers_the_values_one_key_holds_and_the_other_lacks() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    for (key, value) in [
+        (1, 5),
+        (1, 3),
+        (1, 5),
+        (1, 9),
+        (2, 9),
+        (2, 4),
+        (2, 3),
+        (2, 4),
+    ] {
+        assert_eq!(client.status(OP_SET, key, value), STATUS_OK);
+    }
+    let mut compare = |a: u8, b: u8, side: u32| {
+        let status = client.status(OP_COMPARE, a, u32::from(b) | side << 8);
+        (status == STATUS_OK).then(|| client.values())
+    };
+    assert_eq!(compare(1, 2, 0), Some(vec![5]));
+    assert_eq!(compare(1, 2, 1), Some(vec![4]));
+    assert_eq!(compare(1, 2, 2), Some(vec![3, 9]));
+    assert_eq!(compare(2, 1, 2), Some(vec![9, 3]));
+    assert_eq!(compare(1, 7, 0), Some(vec![5, 3, 9]));
+    assert_eq!(compare(1, 7, 2), Some(Vec::new()));
+    assert_eq!(compare(6, 7, 0), None);
+
+    client.send(&with_trailer(OP_SET_TYPED, 3, &[2, b'x']));
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.status(OP_COMPARE, 1, 3), STATUS_CONFLICT);
+    assert_eq!(client.status(OP_COMPARE, 1, 2 | 3 << 8), STATUS_BAD_REQUEST);
+    assert!(client.closed());
+}
+
+#[test]
+fn delete_many_answers_a_status_per_key() {
+    let mut server = Server::start(&["--strict-allocations"]);
+    let mut client = server.connect();
+    client.send(&with_trailer(OP_ALLOC_REGISTER, 1, &[2, 1, b'a', 0, 0]));
+    assert_eq!(client.u8(), STATUS_OK);
+    for (key, value) in [(1, 10), (2, 20)] {
+        assert_eq!(client.status(OP_SET, key, value), STATUS_OK);
+    }
+    client.send(&with_trailer(OP_DELETE_MANY, 0, &[1, 9, 1, 2]));
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.u32(), 4);
+    assert_eq!(
+        client.read(4),
+        [
+            STATUS_OK,
+            STATUS_KEY_NOT_ALLOCATED,
+            STATUS_NOT_FOUND,
+            STATUS_OK
+        ]
+    );
+    assert_eq!(client.list(), []);
+
+    server.kill();
+    server.restart();
+    let mut client = server.connect();
+    assert_eq!(client.list(), []);
+    client.send(&frame(OP_DELETE_MANY, 0, MAX_DELETE_MANY_KEYS + 1));
+    assert_eq!(client.u8(), STATUS_PAYLOAD_TOO_LARGE);
+}
+
+#[test]
+fn copy_duplicates_values_into_another_key() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    for (key, value) in [(1, 10), (1, 11), (2, 20)] {
+        assert_eq!(client.status(OP_SET, key, value), STATUS_OK);
+    }
+    assert_eq!(client.status(OP_COPY, 1, 2 | 1 << 8), STATUS_OK);
+    assert_eq!(client.status(OP_COPY, 1, 3), STATUS_OK);
+    assert_eq!(
+        client.list(),
+        [(1, vec![10, 11]), (2, vec![20, 10, 11]), (3, vec![10, 11])]
+    );
+    assert_eq!(client.status(OP_COPY, 2, 3), STATUS_OK);
+    assert_eq!(client.get(3), Some(vec![20, 10, 11]));
+    assert_eq!(client.status(OP_COPY, 1, 1 | 1 << 8), STATUS_OK);
+    assert_eq!(client.get(1), Some(vec![10, 11, 10, 11]));
+    assert_eq!(client.status(OP_COPY, 4, 1), STATUS_NOT_FOUND);
+
+    client.send(&with_trailer(OP_SET_TYPED, 9, &[2, b'x']));
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.status(OP_COPY, 9, 1 | 1 << 8), STATUS_CONFLICT);
+    assert_eq!(client.status(OP_COPY, 1, 9), STATUS_OK);
+    assert_eq!(client.get(9), Some(vec![10, 11, 10, 11]));
+}
+
+#[test]
+fn delete_if_only_deletes_at_the_expected_count() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    assert_eq!(client.status(OP_DELETE_IF, 1, 0), STATUS_NOT_FOUND);
+    for value in [10, 11] {
+        assert_eq!(client.status(OP_SET, 1, value), STATUS_OK);
+    }
+    assert_eq!(client.status(OP_DELETE_IF, 1, 1), STATUS_CONFLICT);
+    assert_eq!(client.status(OP_DELETE_IF, 1, 3), STATUS_CONFLICT);
+    assert_eq!(client.get(1), Some(vec![10, 11]));
+    assert_eq!(client.status(OP_DELETE_IF, 1, 2), STATUS_OK);
+    assert_eq!(client.get(1), None);
+}
+
+#[test]
+fn list_all_returns_every_key_with_its_values() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    assert_eq!(client.list(), []);
+    for (key, value) in [(200, 1), (3, 2), (200, 3), (0, 4)] {
+        assert_eq!(client.status(OP_SET, key, value), STATUS_OK);
+    }
+    assert_eq!(
+        client.list(),
+        [(0, vec![4]), (3, vec![2]), (200, vec![1, 3])]
+    );
+}
+
+#[test]
+fn list_range_returns_only_the_keys_in_range() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    for (key, value) in [(200, 1), (3, 2), (200, 3), (0, 4), (255, 5)] {
+        assert_eq!(client.status(OP_SET, key, value), STATUS_OK);
+    }
+    let mut range = |first, last| {
+        assert_eq!(client.status(OP_LIST_RANGE, first, last), STATUS_OK);
+        let count = client.u32();
+        (0..count)
+            .map(|_| (client.u8(), client.values()))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(range(1, 200), [(3, vec![2]), (200, vec![1, 3])]);
+    assert_eq!(range(255, 255), [(255, vec![5])]);
+    assert_eq!(range(4, 199), []);
+    assert_eq!(range(0, 255).len(), 4);
+    assert_eq!(client.status(OP_LIST_RANGE, 9, 8), STATUS_BAD_REQUEST);
+    assert_eq!(client.status(OP_LIST_RANGE, 0, 256), STATUS_BAD_REQUEST);
+    assert!(client.closed());
+}
+
+#[test]
+fn encrypted_data_dir_survives_a_restart_without_plain_records() {
+    let keys = std::env::temp_dir().join(format!("map8x32-test-keys-{}", process::id()));
+    fs::write(&keys, format!("{}\n", "ab".repeat(32))).unwrap();
+    let mut server = Server::start(&["--encryption-key-file", keys.to_str().unwrap()]);
+    let mut client = server.connect();
+    assert_eq!(client.status(OP_SET, 7, 0x5eed_f00d), STATUS_OK);
+    assert_eq!(client.status(OP_SET, 7, 11), STATUS_OK);
+
+    server.kill();
+    for entry in fs::read_dir(server.dir.join("data")).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            continue;
+        }
+        let bytes = fs::read(path).unwrap();
+        assert!(bytes.starts_with(b"M8WX"), "every segment is sealed");
+        assert!(!bytes
+            .windows(4)
+            .any(|window| window == 0x5eed_f00d_u32.to_le_bytes()));
+    }
+    server.restart();
+    assert_eq!(server.connect().get(7), Some(vec![0x5eed_f00d, 11]));
+    fs::remove_file(keys).unwrap();
+}
+
+#[test]
+fn a_seed_file_fills_a_fresh_store_only() {
+    let seed = std::env::temp_dir().join(format!("map8x32-seed-{}.json", process::id()));
+    fs::write(&seed, r#"{"3": [30, 31], "1": [10], "200": []}"#).unwrap();
+    let mut server = Server::start(&["--seed", seed.to_str().unwrap()]);
+    let mut client = server.connect();
+    assert_eq!(client.get(1), Some(vec![10]));
+    assert_eq!(client.get(3), Some(vec![30, 31]));
+    assert_eq!(client.get(200), None);
+    assert_eq!(client.status(OP_SET, 3, 32), STATUS_OK);
+
+    // Restarted on the same data directory, the store keeps what was
+    // written since instead of starting over from the seed.
+    server.kill();
+    server.restart();
+    let mut client = server.connect();
+    assert_eq!(client.get(3), Some(vec![30, 31, 32]));
+    fs::remove_file(&seed).unwrap();
+}
+
+#[test]
+fn tagged_keys_are_listed_and_deleted_together() {
+    let mut server = Server::start(&[]);
+    let mut client = server.connect();
+    for (key, value) in [(9, 1), (4, 2), (5, 3), (9, 4)] {
+        assert_eq!(client.status(OP_SET, key, value), STATUS_OK);
+    }
+    assert_eq!(client.status(OP_TAG, 9, 3), STATUS_OK);
+    assert_eq!(client.status(OP_TAG, 4, 3), STATUS_OK);
+    assert_eq!(client.status(OP_TAG, 5, 7), STATUS_OK);
+    assert_eq!(client.status(OP_TAG, 6, 3), STATUS_NOT_FOUND);
+    // Renamed keys take their tag along.
+    assert_eq!(client.status(OP_RENAME, 5, 6), STATUS_OK);
+
+    server.kill();
+    server.restart();
+    let mut client = server.connect();
+    let mut tagged = |tag| {
+        assert_eq!(client.status(OP_LIST_TAGGED, tag, 0), STATUS_OK);
+        let count = client.u32();
+        (0..count)
+            .map(|_| (client.u8(), client.values()))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(tagged(3), [(4, vec![2]), (9, vec![1, 4])]);
+    assert_eq!(tagged(7), [(6, vec![3])]);
+    assert_eq!(tagged(8), []);
+
+    assert_eq!(client.status(OP_DELETE_TAGGED, 3, 0), STATUS_OK);
+    assert_eq!(client.list(), [(6, vec![3])]);
+    assert_eq!(client.status(OP_DELETE_TAGGED, 3, 0), STATUS_NOT_FOUND);
+    // A key deleted and written again starts untagged.
+    assert_eq!(client.status(OP_SET, 9, 5), STATUS_OK);
+    assert_eq!(client.status(OP_LIST_TAGGED, 3, 0), STATUS_OK);
+    assert_eq!(client.u32(), 0);
+    assert_eq!(client.status(OP_LIST_TAGGED, 0, 0), STATUS_BAD_REQUEST);
+}
+
+#[test]
+fn list_all_snapshots_fall_between_writes() {
+    // Keys 1 and 2 live on different processors from LIST_ALL's.
+    let server = Server::start(&["--threads", "4"]);
+    let mut writer = server.connect();
+    let mut client = server.connect();
+    let rounds = 500;
+    let writes = std::thread::spawn(move || {
+        for value in 0..rounds {
+            assert_eq!(writer.status(OP_SET, 1, value), STATUS_OK);
+            assert_eq!(writer.status(OP_SET, 2, value), STATUS_OK);
+        }
+    });
+    let len = |entries: &[(u8, Vec<u32>)], key| {
+        entries
+            .iter()
+            .find(|(listed, _)| *listed == key)
+            .map_or(0, |(_, values)| values.len())
+    };
+    while !writes.is_finished() {
+        let entries = client.snapshot();
+        let (first, second) = (len(&entries, 1), len(&entries, 2));
+        assert!(
+            first == second || first == second + 1,
+            "a snapshot caught key 1 with {first} values and key 2 with {second}"
+        );
+    }
+    writes.join().unwrap();
+    assert_eq!(client.snapshot(), client.list());
+    assert_eq!(client.status(OP_LIST_ALL, 2, 0), STATUS_BAD_REQUEST);
+}
+
+#[test]
+fn pipelined_and_split_frames() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    let pipelined = [
+        frame(OP_SET, 1, 5),
+        frame(OP_SET, 1, 6),
+        frame(OP_GET, 1, 0),
+    ]
+    .concat();
+    client.send(&pipelined);
+    assert_eq!(client.read(2), [STATUS_OK, STATUS_OK]);
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.values(), [5, 6]);
+
+    let split = frame(OP_SET, 1, 7);
+    client.send(&split[..2]);
+    sleep(Duration::from_millis(50));
+    client.send(&split[2..]);
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.get(1), Some(vec![5, 6, 7]));
+}
+
+#[test]
+fn hello_negotiates_and_version_2_acks_carry_sequence_numbers() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    assert_eq!(
+        client.hello(PROTOCOL_VERSION + 1),
+        [
+            &[STATUS_OK, PROTOCOL_VERSION][..],
+            &PROTOCOL_MAGIC.to_le_bytes()
+        ]
+        .concat()
+    );
+    assert_eq!(client.hello(2), [STATUS_BAD_REQUEST], "repeated HELLO");
+
+    client.send(&frame(OP_SET, 4, 1));
+    assert_eq!(client.u8(), STATUS_OK);
+    let first = client.u64();
+    client.send(&frame(OP_SET, 4, 2));
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.u64(), first + 1);
+    client.send(&frame(OP_DELETE_BY_KEY, 4, 0));
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.u64(), first + 2);
+
+    let mut fence = frame(OP_AWAIT_SEQ, 0, 0);
+    fence.extend_from_slice(&(first + 2).to_le_bytes());
+    client.send(&fence);
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.u64(), first + 2);
+    let mut ahead = frame(OP_AWAIT_SEQ, 0, 0);
+    ahead.extend_from_slice(&(first + 100).to_le_bytes());
+    client.send(&ahead);
+    assert_eq!(client.u8(), STATUS_STALE);
+    assert_eq!(client.u64(), first + 2);
+
+    let mut old = server.connect();
+    assert_eq!(old.hello(0), [STATUS_UNSUPPORTED_VERSION, PROTOCOL_VERSION]);
+    let mut bad_magic = server.connect();
+    assert_eq!(
+        bad_magic.status(OP_HELLO, 2, 0xdead_beef),
+        STATUS_BAD_REQUEST
+    );
+    assert_eq!(bad_magic.status(OP_SET, 4, 3), STATUS_OK, "still version 1");
+}
+
+#[test]
+fn dead_letters_record_refused_writes() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    client.send(&with_trailer(OP_SET_TYPED, 9, &[1, 1, 0, 0, 0, 0, 0, 0, 0]));
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.status(OP_SET, 9, 77), STATUS_CONFLICT);
+
+    for drain in [0, 1] {
+        assert_eq!(client.status(OP_DEAD_LETTERS, 0, drain), STATUS_OK);
+        assert_eq!(client.u32(), 1);
+        client.u64();
+        assert_eq!(
+            client.read(7),
+            [&[OP_SET, 9][..], &77u32.to_le_bytes(), &[STATUS_CONFLICT]].concat()
+        );
+    }
+    assert_eq!(client.status(OP_DEAD_LETTERS, 0, 0), STATUS_OK);
+    assert_eq!(client.u32(), 0, "drained");
+}
+
+#[test]
+fn dead_letters_record_writes_refused_on_the_connection() {
+    let letter = |op: u8, key: u8, value: u32, reason: u8| {
+        [&[op, key][..], &value.to_le_bytes(), &[reason]].concat()
+    };
+    let server = Server::start(&["--read-only"]);
+    let mut client = server.connect();
+    assert_eq!(client.status(OP_SET, 3, 42), STATUS_READONLY);
+    assert_eq!(client.status(OP_GET, 3, 0), STATUS_NOT_FOUND);
+    assert_eq!(client.status(OP_DELETE_BY_KEY, 3, 0), STATUS_READONLY);
+    assert_eq!(client.status(OP_DEAD_LETTERS, 0, 0), STATUS_OK);
+    assert_eq!(client.u32(), 2, "reads are not captured");
+    client.u64();
+    assert_eq!(client.read(7), letter(OP_SET, 3, 42, STATUS_READONLY));
+    client.u64();
+    assert_eq!(
+        client.read(7),
+        letter(OP_DELETE_BY_KEY, 3, 0, STATUS_READONLY)
+    );
+
+    let token = server.dir.join("token");
+    fs::write(&token, "s3cret\n").unwrap();
+    let server = Server::start(&["--auth-token-file", token.to_str().unwrap()]);
+    let mut client = server.connect();
+    assert_eq!(client.status(OP_SET, 5, 9), STATUS_UNAUTHORIZED);
+    client.send(&with_trailer(OP_AUTH, 0, b"s3cret"));
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.status(OP_DEAD_LETTERS, 0, 0), STATUS_OK);
+    assert_eq!(client.u32(), 1);
+    client.u64();
+    assert_eq!(client.read(7), letter(OP_SET, 5, 9, STATUS_UNAUTHORIZED));
+}
+
+#[test]
+fn allocations_register_list_and_release() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    let register = |first: u8, last: u8, name: &str| {
+        let mut payload = vec![last];
+        for field in [name, "ops@example.com", "append-only"] {
+            payload.push(field.len() as u8);
+            payload.extend_from_slice(field.as_bytes());
+        }
+        with_trailer(OP_ALLOC_REGISTER, first, &payload)
+    };
+    client.send(&register(10, 19, "billing"));
+    assert_eq!(client.u8(), STATUS_OK);
+    client.send(&register(15, 30, "overlap"));
+    assert_eq!(client.u8(), STATUS_CONFLICT);
+
+    assert_eq!(client.status(OP_ALLOC_LIST, 0, 0), STATUS_OK);
+    assert_eq!(client.u32(), 1);
+    assert_eq!(client.read(2), [10, 19]);
+    for field in ["billing", "ops@example.com", "append-only"] {
+        let len = client.u8() as usize;
+        assert_eq!(client.read(len), field.as_bytes());
+    }
+
+    assert_eq!(client.status(OP_ALLOC_RELEASE, 10, 0), STATUS_OK);
+    assert_eq!(client.status(OP_ALLOC_LIST, 0, 0), STATUS_OK);
+    assert_eq!(client.u32(), 0);
+}
+
+#[test]
+fn strict_allocations_refuse_unallocated_keys() {
+    let server = Server::start(&["--strict-allocations"]);
+    let mut client = server.connect();
+    assert_eq!(client.status(OP_SET, 50, 1), STATUS_KEY_NOT_ALLOCATED);
+    client.send(&with_trailer(OP_ALLOC_REGISTER, 50, &[50, 1, b'a', 0, 0]));
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.status(OP_SET, 50, 1), STATUS_OK);
+}
+
+#[test]
+fn only_admin_connections_change_strict_allocations() {
+    let config = std::env::temp_dir().join(format!("map8x32-admin-{}.toml", process::id()));
+    fs::write(
+        &config,
+        "strict_allocations = true\ndelete_all_token = 7\nauth_token = \"root\"\n\n\
+         [[credentials]]\ntoken = \"app\"\n",
+    )
+    .unwrap();
+    let server = Server::start(&["--config", config.to_str().unwrap()]);
+    let register = with_trailer(OP_ALLOC_REGISTER, 50, &[59, 1, b'a', 0, 0]);
+
+    let mut app = server.connect();
+    app.send(&with_trailer(OP_AUTH, 0, b"app"));
+    assert_eq!(app.u8(), STATUS_OK);
+    app.send(&register);
+    assert_eq!(app.u8(), STATUS_UNAUTHORIZED);
+    assert_eq!(app.status(OP_SET, 50, 1), STATUS_KEY_NOT_ALLOCATED);
+
+    let mut admin = server.connect();
+    admin.send(&with_trailer(OP_AUTH, 0, b"root"));
+    assert_eq!(admin.u8(), STATUS_OK);
+    admin.send(&register);
+    assert_eq!(admin.u8(), STATUS_OK);
+
+    assert_eq!(app.status(OP_SET, 50, 1), STATUS_OK);
+    assert_eq!(app.status(OP_DELETE_ALL, 0, 7), STATUS_UNAUTHORIZED);
+    assert_eq!(app.status(OP_ALLOC_RELEASE, 50, 0), STATUS_UNAUTHORIZED);
+    assert_eq!(app.get(50), Some(vec![1]));
+
+    assert_eq!(admin.status(OP_DELETE_ALL, 0, 7), STATUS_OK);
+    assert_eq!(admin.status(OP_ALLOC_RELEASE, 50, 0), STATUS_OK);
+    assert_eq!(app.get(50), None);
+
+    drop(server);
+    let _ = fs::remove_file(&config);
+}
+
+#[test]
+fn get_versioned_and_replace_if() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    assert_eq!(client.status(OP_SET, 3, 1), STATUS_OK);
+    assert_eq!(client.status(OP_GET_VERSIONED, 3, 0), STATUS_OK);
+    let version = client.u64();
+    assert_eq!(client.values(), [1]);
+
+    let replace = |expected: u64, values: &[u32]| {
+        let mut request = frame(OP_REPLACE_IF, 3, values.len() as u32);
+        request.extend_from_slice(&expected.to_le_bytes());
+        for value in values {
+            request.extend_from_slice(&value.to_le_bytes());
+        }
+        request
+    };
+    client.send(&replace(version, &[8, 9]));
+    assert_eq!(client.u8(), STATUS_OK);
+    client.send(&replace(version, &[10]));
+    assert_eq!(client.u8(), STATUS_CONFLICT, "version moved on");
+    assert_eq!(client.get(3), Some(vec![8, 9]));
+
+    assert_eq!(client.status(OP_GET_VERSIONED, 3, 0), STATUS_OK);
+    let version = client.u64();
+    assert_eq!(client.values(), [8, 9]);
+    client.send(&replace(version, &[]));
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.get(3), None, "an empty replacement removes the key");
+}
+
+#[test]
+fn reads_past_the_command_timeout_are_answered_timeout() {
+    let server = Server::start(&["--command-timeout-ms", "1", "--delete-all-token", "7"]);
+    let mut client = server.connect();
+    // Copying this many values for LIST_ALL takes well over a millisecond.
+    let mut values = Vec::with_capacity(4 + MAX_REPLACE_VALUES as usize * 4);
+    for value in 0..MAX_REPLACE_VALUES {
+        values.extend_from_slice(&value.to_le_bytes());
+    }
+    for key in 0..128 {
+        let mut request = frame(OP_REPLACE_IF, key, MAX_REPLACE_VALUES);
+        request.extend_from_slice(&0u64.to_le_bytes());
+        request.extend_from_slice(&values);
+        client.send(&request);
+        assert_eq!(client.u8(), STATUS_OK);
+    }
+    assert_eq!(client.status(OP_LIST_ALL, 0, 0), STATUS_TIMEOUT);
+    // Writes are never timed out, and the connection carries on.
+    assert_eq!(client.status(OP_SET, 200, 1), STATUS_OK);
+    assert_eq!(client.status(OP_DELETE_ALL, 0, 7), STATUS_OK);
+}
+
+#[test]
+fn auth_gates_everything_but_the_handshake_commands() {
+    let dir = std::env::temp_dir().join(format!("map8x32-e2e-token-{}", process::id()));
+    fs::write(&dir, "s3cret\n").unwrap();
+    let server = Server::start(&["--auth-token-file", dir.to_str().unwrap()]);
+    let mut client = server.connect();
+    assert_eq!(client.status(OP_SET, 1, 1), STATUS_UNAUTHORIZED);
+    assert_eq!(client.status(OP_GET, 1, 0), STATUS_UNAUTHORIZED);
+    assert_eq!(client.status(OP_PING, 0, 0), STATUS_OK);
+    let len = client.u8() as usize;
+    client.read(len);
+
+    client.send(&with_trailer(OP_AUTH, 0, b"wrong"));
+    assert_eq!(client.u8(), STATUS_UNAUTHORIZED);
+    client.send(&with_trailer(OP_AUTH, 0, b"s3cret"));
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.status(OP_SET, 1, 1), STATUS_OK);
+    assert_eq!(client.get(1), Some(vec![1]));
+    fs::remove_file(dir).unwrap();
+}
+
+#[test]
+fn read_only_refuses_writes() {
+    let server = Server::start(&["--read-only"]);
+    let mut client = server.connect();
+    assert_eq!(client.status(OP_SET, 1, 1), STATUS_READONLY);
+    assert_eq!(client.status(OP_DELETE_ALL, 0, 0), STATUS_READONLY);
+    assert_eq!(client.get(1), None);
+}
+
+#[test]
+fn select_codec_switches_after_the_answer() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    assert_eq!(client.status(OP_SELECT_CODEC, 99, 0), STATUS_BAD_REQUEST);
+    assert_eq!(client.status(OP_SELECT_CODEC, CODEC_PROTOBUF, 0), STATUS_OK);
+    // Protobuf from here on: a length-prefixed `Request { op: PING }`.
+    let ping = [0x08, OP_PING];
+    client.send(&[&(ping.len() as u32).to_le_bytes()[..], &ping].concat());
+    let len = client.u32() as usize;
+    let response = client.read(len);
+    let version = env!("CARGO_PKG_VERSION").as_bytes();
+    assert!(
+        response
+            .windows(version.len())
+            .any(|window| window == version),
+        "pong carries the version: {response:?}"
+    );
+}
+
+#[test]
+fn restore_key_brings_back_the_deleted_values() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    assert_eq!(client.status(OP_RESTORE_KEY, 6, 0), STATUS_NOT_FOUND);
+    assert_eq!(client.status(OP_SET, 6, 1), STATUS_OK);
+    assert_eq!(client.status(OP_SET, 6, 2), STATUS_OK);
+    assert_eq!(client.status(OP_DELETE_BY_KEY, 6, 0), STATUS_OK);
+    assert_eq!(client.status(OP_RESTORE_KEY, 6, 0), STATUS_OK);
+    assert_eq!(client.get(6), Some(vec![1, 2]));
+}
+
+#[test]
+fn info_counts_keys_values_and_hits() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    for value in 0..3 {
+        assert_eq!(client.status(OP_SET, 1, value), STATUS_OK);
+    }
+    assert_eq!(client.status(OP_SET, 2, 0), STATUS_OK);
+    client.get(1);
+    client.get(99);
+    client.send(&with_trailer(OP_ALLOC_REGISTER, 1, &[2, 1, b'a', 0, 0]));
+    assert_eq!(client.u8(), STATUS_OK);
+
+    assert_eq!(client.status(OP_INFO, 0, 0), STATUS_OK);
+    let _uptime_ms = client.u64();
+    assert_eq!((client.u64(), client.u64()), (2, 4), "keys and values");
+    assert!(client.u64() > 0, "memory");
+    assert_eq!((client.u64(), client.u64()), (1, 1), "hits and misses");
+    let ops = client.u8();
+    let counts: Vec<(String, u64)> = (0..ops)
+        .map(|_| {
+            let len = client.u8() as usize;
+            (String::from_utf8(client.read(len)).unwrap(), client.u64())
+        })
+        .collect();
+    assert!(counts.contains(&("set".to_string(), 4)), "{counts:?}");
+    assert_eq!(
+        client.read(10),
+        [&[1, Health::Healthy as u8][..], &0u64.to_le_bytes()].concat(),
+        "persisted, healthy and never failed"
+    );
+    assert_eq!(client.u32(), 1, "allocations");
+    assert_eq!(
+        client.read(6),
+        [1, 2, 1, b'a', 0, 0],
+        "keys 1..=2 owned by a"
+    );
+    assert_eq!(
+        (client.u64(), client.u64(), client.u64()),
+        (0, 0, 0),
+        "no quota rejections and no key or value limits"
+    );
+}
+
+#[test]
+fn info_reports_latency_quantiles_per_op() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    assert_eq!(client.status(OP_SET, 1, 1), STATUS_OK);
+    client.get(1);
+
+    assert_eq!(client.status(OP_INFO, 1, 0), STATUS_OK);
+    client.read(48);
+    for _ in 0..client.u8() {
+        let len = client.u8() as usize;
+        client.read(len + 8);
+    }
+    client.read(10);
+    assert_eq!(client.u32(), 0, "allocations");
+    client.read(24);
+    let latencies: Vec<(u8, Vec<u64>)> = (0..client.u8())
+        .map(|_| (client.u8(), (0..12).map(|_| client.u64()).collect()))
+        .collect();
+    for op in [OP_SET, OP_GET] {
+        let (_, quantiles) = latencies.iter().find(|(on, _)| *on == op).unwrap();
+        for times in quantiles.chunks(6) {
+            assert_eq!(times[0], 1, "count of op {op}");
+            assert!(times[1..].windows(2).all(|pair| pair[0] <= pair[1]));
+        }
+    }
+
+    assert_eq!(client.status(OP_INFO, 2, 0), STATUS_BAD_REQUEST);
+    assert!(client.closed());
+}
+
+#[cfg(feature = "otlp")]
+#[test]
+fn requests_are_exported_as_otlp_spans() {
+    let collector = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = collector.local_addr().unwrap().to_string();
+    let server = Server::start(&["--otlp-endpoint", &endpoint]);
+    assert_eq!(server.connect().status(OP_SET, 9, 1), STATUS_OK);
+
+    let (mut export, _) = collector.accept().unwrap();
+    export
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut request = Vec::new();
+    let body = loop {
+        let mut chunk = [0; 4096];
+        let len = export.read(&mut chunk).unwrap();
+        assert!(len > 0, "the export request ended early");
+        request.extend_from_slice(&chunk[..len]);
+        let text = String::from_utf8_lossy(&request);
+        let Some((head, body)) = text.split_once("\r\n\r\n") else {
+            continue;
+        };
+        assert!(head.starts_with("POST /v1/traces "), "{head}");
+        let length: usize = head
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .unwrap()
+            .parse()
+            .unwrap();
+        if body.len() == length {
+            break body.to_string();
+        }
+    };
+    export
+        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+        .unwrap();
+
+    for name in ["map8x32.request", "read", "queue", "process", "write"] {
+        assert!(body.contains(&format!(r#""name":"{name}""#)), "{body}");
+    }
+    let op = format!(r#"{{"key":"map8x32.op","value":{{"intValue":"{OP_SET}"}}}}"#);
+    assert!(body.contains(&op), "{body}");
+}
+
+#[cfg(feature = "s3-upload")]
+#[test]
+fn snapshots_are_uploaded_and_old_uploads_pruned() {
+    /// Reads one HTTP request, returning its head and body.
+    fn request(stream: &mut std::net::TcpStream) -> (String, Vec<u8>) {
+        let mut request = Vec::new();
+        loop {
+            let mut chunk = [0; 4096];
+            let len = stream.read(&mut chunk).unwrap();
+            assert!(len > 0, "the request ended early");
+            request.extend_from_slice(&chunk[..len]);
+            let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") else {
+                continue;
+            };
+            let head = String::from_utf8_lossy(&request[..end]).to_string();
+            let length: usize = head
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length: "))
+                .unwrap()
+                .parse()
+                .unwrap();
+            if request.len() == end + 4 + length {
+                return (head, request[end + 4..].to_vec());
+            }
+        }
+    }
+
+    let bucket = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = bucket.local_addr().unwrap().to_string();
+    std::env::set_var("AWS_ACCESS_KEY_ID", "test-key");
+    std::env::set_var("AWS_SECRET_ACCESS_KEY", "test-secret");
+    let server = Server::start(&[
+        "--snapshot-interval",
+        "1",
+        "--upload-endpoint",
+        &endpoint,
+        "--upload-bucket",
+        "backups",
+        "--upload-keep",
+        "1",
+    ]);
+    assert_eq!(server.connect().status(OP_SET, 9, 1), STATUS_OK);
+
+    let accept = || {
+        let (stream, _) = bucket.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+    };
+    let mut put = accept();
+    let (head, body) = request(&mut put);
+    assert!(head.starts_with("PUT /backups/db0/map8x32-"), "{head}");
+    assert!(
+        head.contains("authorization: AWS4-HMAC-SHA256 Credential=test-key/"),
+        "{head}"
+    );
+    assert_eq!(
+        body,
+        fs::read(server.dir.join("data/map8x32.snapshot")).unwrap()
+    );
+    let object = head.split(' ').nth(1).unwrap()["/backups/".len()..].to_string();
+    put.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+        .unwrap();
+    // The uploader reads each answer until the connection closes.
+    drop(put);
+
+    let mut list = accept();
+    let (head, _) = request(&mut list);
+    assert!(
+        head.starts_with("GET /backups?list-type=2&prefix=db0%2F "),
+        "{head}"
+    );
+    let listing = format!(
+        "<ListBucketResult><Contents><Key>db0/map8x32-0000000000001.snapshot</Key></Contents><Contents><Key>{object}</Key></Contents></ListBucketResult>"
+    );
+    list.write_all(
+        format!(
+            "HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n{listing}",
+            listing.len()
+        )
+        .as_bytes(),
+    )
+    .unwrap();
+    drop(list);
+
+    let mut delete = accept();
+    let (head, _) = request(&mut delete);
+    assert!(
+        head.starts_with("DELETE /backups/db0/map8x32-0000000000001.snapshot "),
+        "{head}"
+    );
+    delete
+        .write_all(b"HTTP/1.1 204 No Content\r\ncontent-length: 0\r\n\r\n")
+        .unwrap();
+}
+
+#[test]
+fn slow_log_keeps_commands_over_the_threshold() {
+    let server = Server::start(&["--slow-log-threshold-us", "0"]);
+    let mut client = server.connect();
+    assert_eq!(client.status(OP_SET, 42, 1), STATUS_OK);
+    assert_eq!(client.status(OP_SLOW_LOG, 0, 1), STATUS_OK);
+    let count = client.u32();
+    assert!(count >= 1);
+    let entries: Vec<Vec<u8>> = (0..count).map(|_| client.read(22)).collect();
+    let set = entries.iter().find(|entry| entry[8] == OP_SET).unwrap();
+    assert_eq!(set[9], 42);
+    assert_eq!(u32::from_le_bytes(set[18..22].try_into().unwrap()), 6);
+}
+
+#[test]
+fn client_list_reports_each_connection() {
+    let server = Server::start(&[]);
+    let mut busy = server.connect();
+    for value in [1, 2] {
+        assert_eq!(busy.status(OP_SET, 7, value), STATUS_OK);
+    }
+    let mut admin = server.connect();
+    assert_eq!(admin.status(OP_CLIENT_LIST, 0, 0), STATUS_OK);
+    assert_eq!(admin.u32(), 2);
+    let mut clients = Vec::new();
+    for _ in 0..2 {
+        let id = admin.u64();
+        let uid = admin.u32();
+        let _connected_unix_ms = admin.u64();
+        let (requests, bytes_in, bytes_out) = (admin.u64(), admin.u64(), admin.u64());
+        let last_op = admin.u8();
+        let len = admin.u8() as usize;
+        let peer = String::from_utf8(admin.read(len)).unwrap();
+        assert!(peer.starts_with(&format!("uid={uid} ")), "{peer}");
+        clients.push((id, (requests, bytes_in, bytes_out, last_op)));
+    }
+    let [(busy_id, busy_activity), (admin_id, (_, _, _, admin_last_op))] = clients[..] else {
+        unreachable!()
+    };
+    assert!(busy_id < admin_id);
+    assert_eq!(busy_activity, (2, 12, 2, OP_SET));
+    assert_eq!(admin_last_op, OP_CLIENT_LIST);
+
+    assert_eq!(busy.status(OP_CLIENT_INFO, 0, 0), STATUS_OK);
+    assert_eq!(busy.u32(), 1);
+    assert_eq!(busy.u64(), busy_id);
+}
+
+#[test]
+fn client_kill_closes_another_connection() {
+    let server = Server::start(&[]);
+    let mut stuck = server.connect();
+    assert_eq!(stuck.status(OP_CLIENT_INFO, 0, 0), STATUS_OK);
+    assert_eq!(stuck.u32(), 1);
+    let stuck_id = stuck.u64();
+    // The rest of the entry, then half a frame, so the connection waits.
+    stuck.read(37);
+    let len = stuck.u8() as usize;
+    stuck.read(len);
+    stuck.send(&[OP_SET, 1]);
+    let kill = [frame(OP_CLIENT_KILL, 0, 0), stuck_id.to_le_bytes().to_vec()].concat();
+
+    let mut admin = server.connect();
+    admin.send(&kill);
+    assert_eq!(admin.u8(), STATUS_OK);
+    assert!(stuck.closed());
+    admin.send(&kill);
+    assert_eq!(admin.u8(), STATUS_NOT_FOUND);
+    assert_eq!(admin.status(OP_SET, 1, 5), STATUS_OK);
+}
+
+#[test]
+fn check_reports_a_corrupt_wal_tail_and_repair_cuts_it_off() {
+    let mut server = Server::start(&["--repair"]);
+    let mut client = server.connect();
+    for value in [10, 11] {
+        assert_eq!(client.status(OP_SET, 4, value), STATUS_OK);
+    }
+    server.kill();
+    let data = server.dir.join("data");
+    let newest = fs::read_dir(&data)
+        .unwrap()
+        .filter_map(|entry| {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            name.strip_prefix("map8x32.wal.")?.parse::<u64>().ok()
+        })
+        .max()
+        .unwrap();
+    let segment = data.join(format!("map8x32.wal.{newest}"));
+    let mut file = fs::OpenOptions::new().append(true).open(&segment).unwrap();
+    file.write_all(&frame(200, 4, 12)).unwrap();
+    drop(file);
+
+    let check = || {
+        process::Command::new(env!("CARGO_BIN_EXE_map8x32-server"))
+            .args(["--data-dir", data.to_str().unwrap(), "--check"])
+            .output()
+            .unwrap()
+    };
+    let output = check();
+    assert_eq!(output.status.code(), Some(1));
+    let report = String::from_utf8(output.stdout).unwrap();
+    let line = report
+        .lines()
+        .find(|line| line.contains(&format!("map8x32.wal.{newest}:")))
+        .unwrap_or_else(|| panic!("no line for the segment in {report}"));
+    assert!(
+        line.contains("corrupt (") && line.contains("6 bytes discarded"),
+        "{line}"
+    );
+
+    server.restart();
+    let mut client = server.connect();
+    assert_eq!(client.get(4), Some(vec![10, 11]));
+    assert_eq!(client.status(OP_SET, 4, 13), STATUS_OK);
+    server.kill();
+    assert!(check().status.success());
+    let mut kept_in = segment.into_os_string();
+    kept_in.push(".discarded");
+    assert_eq!(fs::read(kept_in).unwrap(), frame(200, 4, 12));
+}
+
+#[test]
+fn selftest_reports_engine_throughput_without_serving() {
+    let output = process::Command::new(env!("CARGO_BIN_EXE_map8x32-server"))
+        .args([
+            "selftest",
+            "--ops",
+            "100",
+            "--threads",
+            "2",
+            "--clients",
+            "3",
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let report = String::from_utf8(output.stdout).unwrap();
+    for name in ["SET", "GET"] {
+        let line = report
+            .lines()
+            .find(|line| line.trim_start().starts_with(name))
+            .unwrap_or_else(|| panic!("no {name} line in {report}"));
+        assert!(line.contains(" 300 ops"), "{line}");
+    }
+}
+
+#[test]
+fn capture_records_requests_and_closes_per_connection() {
+    let path = std::env::temp_dir().join(format!("map8x32-capture-{}", process::id()));
+    let server = Server::start(&["--capture", path.to_str().unwrap()]);
+    let mut first = server.connect();
+    let mut second = server.connect();
+    assert_eq!(first.status(OP_SET, 4, 40), STATUS_OK);
+    second.send(&[frame(OP_SET, 5, 50), frame(OP_GET, 5, 0)].concat());
+    assert_eq!((second.u8(), second.u8()), (STATUS_OK, STATUS_OK));
+    second.values();
+    drop(first);
+    assert_eq!(second.status(OP_PING, 0, 0), STATUS_OK);
+    let len = second.u8() as usize;
+    second.read(len);
+
+    let connection_of = |captured: &[CaptureRecord], request: Vec<u8>| {
+        let request = Captured::Request(request);
+        captured
+            .iter()
+            .find(|record| record.event == request)
+            .unwrap()
+            .connection
+    };
+    let events = |captured: &[CaptureRecord], connection| -> Vec<Captured> {
+        captured
+            .iter()
+            .filter(|record| record.connection == connection)
+            .map(|record| record.event.clone())
+            .collect()
+    };
+    // The first connection's close is recorded once the server notices it.
+    let mut captured = Vec::new();
+    for _ in 0..100 {
+        captured = capture::read_capture(&fs::read(&path).unwrap()).unwrap();
+        let a = connection_of(&captured, frame(OP_SET, 4, 40));
+        if events(&captured, a).contains(&Captured::Closed) {
+            break;
+        }
+        sleep(Duration::from_millis(20));
+    }
+    let _ = fs::remove_file(&path);
+    assert!(captured
+        .windows(2)
+        .all(|pair| pair[0].at_us <= pair[1].at_us));
+    let a = connection_of(&captured, frame(OP_SET, 4, 40));
+    assert_eq!(
+        events(&captured, a),
+        [Captured::Request(frame(OP_SET, 4, 40)), Captured::Closed]
+    );
+    let b = connection_of(&captured, frame(OP_SET, 5, 50));
+    assert_eq!(
+        events(&captured, b),
+        [
+            Captured::Request(frame(OP_SET, 5, 50)),
+            Captured::Request(frame(OP_GET, 5, 0)),
+            Captured::Request(frame(OP_PING, 0, 0)),
+        ]
+    );
+}
+
+#[test]
+fn ping_answers_with_the_release() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    assert_eq!(client.status(OP_PING, 0, 0), STATUS_OK);
+    let len = client.u8() as usize;
+    assert_eq!(client.read(len), env!("CARGO_PKG_VERSION").as_bytes());
+}
+
+#[test]
+fn tcp_listener_outlives_running_out_of_file_descriptors() {
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let server = Server::start(&["--tcp", &addr.to_string()]);
+    let pid = server.pid().to_string();
+    let nofile = |limit: &str| {
+        let set = process::Command::new("prlimit")
+            .args(["--pid", &pid, &format!("--nofile={limit}:")])
+            .status()
+            .unwrap();
+        assert!(set.success());
+    };
+    let open = fs::read_dir(format!("/proc/{pid}/fd")).unwrap().count();
+    nofile(&(open + 2).to_string());
+    let flood: Vec<_> = (0..8)
+        .map(|_| std::net::TcpStream::connect(addr).unwrap())
+        .collect();
+    sleep(Duration::from_millis(300));
+    drop(flood);
+    nofile("1024");
+
+    let mut tcp = std::net::TcpStream::connect(addr).unwrap();
+    tcp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    tcp.write_all(&frame(OP_PING, 0, 0)).unwrap();
+    let mut byte = [0];
+    tcp.read_exact(&mut byte).unwrap();
+    assert_eq!(byte[0], STATUS_OK, "still accepting after EMFILE");
+}
+
+#[test]
+fn resp_pushes_at_once_and_refuses_long_patterns() {
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let server = Server::start(&["--resp", &addr.to_string()]);
+    let mut resp = std::net::TcpStream::connect(addr).unwrap();
+    resp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut command = |args: &[&[u8]], expected: &[u8]| {
+        let mut request = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            request.extend_from_slice(arg);
+            request.extend_from_slice(b"\r\n");
+        }
+        resp.write_all(&request).unwrap();
+        let mut reply = vec![0; expected.len()];
+        resp.read_exact(&mut reply).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&reply),
+            String::from_utf8_lossy(expected)
+        );
+    };
+    command(&[b"RPUSH", b"7", b"10", b"20", b"30"], b":3\r\n");
+    command(&[b"RPUSH", b"7", b"40"], b":4\r\n");
+    command(&[b"RPUSH", b"177", b"1"], b":1\r\n");
+    let both = b"*2\r\n$1\r\n7\r\n$3\r\n177\r\n";
+    command(&[b"KEYS", &[b'*'; 1024]], both);
+    command(&[b"KEYS", b"**?"], both);
+    command(&[b"KEYS", b"*7"], both);
+    command(&[b"KEYS", b"1*7"], b"*1\r\n$3\r\n177\r\n");
+    command(&[b"KEYS", b"?7"], b"*0\r\n");
+    // Once deep enough to overflow the stack when matched recursively.
+    command(
+        &[b"KEYS", &[b'*'; 200_000]],
+        b"-ERR pattern longer than 1024 bytes\r\n",
+    );
+    command(&[b"PING"], b"+PONG\r\n");
+    assert_eq!(server.connect().get(7), Some(vec![10, 20, 30, 40]));
+}
+
+#[test]
+fn keepalive_probes_idle_tcp_connections() {
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let server = Server::start(&["--tcp", &addr.to_string(), "--keepalive-ms", "100"]);
+    assert_eq!(
+        server.connect().status(OP_KEEPALIVE, 0, 0),
+        STATUS_NOT_FOUND,
+        "Unix sockets are not probed"
+    );
+
+    let mut tcp = std::net::TcpStream::connect(addr).unwrap();
+    tcp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut byte = [0];
+    tcp.write_all(&frame(OP_KEEPALIVE, 0, 0)).unwrap();
+    tcp.read_exact(&mut byte).unwrap();
+    assert_eq!(byte[0], STATUS_OK);
+    tcp.read_exact(&mut byte).unwrap();
+    assert_eq!(byte[0], STATUS_PROBE);
+    tcp.write_all(&frame(OP_PONG, 0, 0)).unwrap();
+    tcp.read_exact(&mut byte).unwrap();
+    assert_eq!(byte[0], STATUS_PROBE, "probed again after the PONG");
+    assert_eq!(
+        tcp.read(&mut byte).unwrap(),
+        0,
+        "closed when left unanswered"
+    );
+}
+
+#[test]
+fn subscribe_pushes_a_keys_values_after_each_change() {
+    let server = Server::start(&[]);
+    let mut writer = server.connect();
+    assert_eq!(writer.status(OP_SET, 7, 10), STATUS_OK);
+
+    let mut subscriber = server.connect();
+    assert_eq!(subscriber.status(OP_SUBSCRIBE, 7, 0), STATUS_OK);
+    assert_eq!(subscriber.u8(), STATUS_OK);
+    assert_eq!(subscriber.values(), vec![10], "current values come first");
+
+    assert_eq!(writer.status(OP_SET, 8, 1), STATUS_OK);
+    assert_eq!(writer.status(OP_SET, 7, 20), STATUS_OK);
+    assert_eq!(subscriber.u8(), STATUS_OK, "other keys push nothing");
+    assert_eq!(subscriber.values(), vec![10, 20]);
+    assert_eq!(writer.status(OP_DELETE_BY_KEY, 7, 0), STATUS_OK);
+    assert_eq!(subscriber.u8(), STATUS_NOT_FOUND, "pushed as GET answers");
+}
+
+#[test]
+fn monitor_reports_every_connections_commands() {
+    let server = Server::start(&[]);
+    let mut monitor = server.connect();
+    assert_eq!(monitor.status(OP_MONITOR, 0, 0), STATUS_OK);
+    assert_eq!(monitor.u32(), 0, "answered with no commands");
+
+    let mut client = server.connect();
+    assert_eq!(client.status(OP_SET, 7, 10), STATUS_OK);
+    let mut seen = Vec::new();
+    while seen.is_empty() {
+        assert_eq!(monitor.u8(), STATUS_OK);
+        for _ in 0..monitor.u32() {
+            let _at_unix_ms = monitor.u64();
+            let (db, op, key, value) = (monitor.u8(), monitor.u8(), monitor.u8(), monitor.u32());
+            let (status, _micros) = (monitor.u8(), monitor.u64());
+            let peer_len = monitor.u8() as usize;
+            let peer = String::from_utf8(monitor.read(peer_len)).unwrap();
+            let command_len = monitor.u8() as usize;
+            let command = String::from_utf8(monitor.read(command_len)).unwrap();
+            seen.push((db, op, key, value, status, peer, command));
+        }
+    }
+    let (db, op, key, value, status, peer, command) = &seen[0];
+    assert_eq!(
+        (*db, *op, *key, *value, *status),
+        (0, OP_SET, 7, 10, STATUS_OK)
+    );
+    assert!(peer.contains(&format!("pid={}", process::id())), "{peer}");
+    assert_eq!(command, "SET key=7 value=10");
+}
+
+#[test]
+fn config_reads_and_changes_tunables_at_runtime() {
+    let server = Server::start(&["--max-connections", "4"]);
+    let mut client = server.connect();
+    let settings = |client: &mut Client, name: &str| {
+        client.send(&with_trailer(OP_CONFIG, 0, name.as_bytes()));
+        assert_eq!(client.u8(), STATUS_OK);
+        (0..client.u8())
+            .map(|_| {
+                let name_len = client.u8() as usize;
+                let name = String::from_utf8(client.read(name_len)).unwrap();
+                let value_len = client.u8() as usize;
+                (name, String::from_utf8(client.read(value_len)).unwrap())
+            })
+            .collect::<Vec<_>>()
+    };
+    let set = |client: &mut Client, setting: &str| {
+        client.send(&with_trailer(OP_CONFIG, 1, setting.as_bytes()));
+        client.u8()
+    };
+    assert_eq!(
+        settings(&mut client, "max_connections"),
+        [("max_connections".to_string(), "4".to_string())]
+    );
+
+    assert_eq!(set(&mut client, "max_connections=8"), STATUS_OK);
+    assert_eq!(set(&mut client, "rate_limit=1000"), STATUS_OK);
+    assert_eq!(set(&mut client, "rate_limit=fast"), STATUS_BAD_REQUEST);
+    assert_eq!(set(&mut client, "snapshot_interval=60"), STATUS_OK);
+    assert_eq!(set(&mut client, "no_such_tunable=1"), STATUS_NOT_FOUND);
+    let all = settings(&mut client, "");
+    for setting in [
+        "max_connections=8",
+        "rate_limit=1000",
+        "rate_limit_burst=1000",
+        "snapshot_interval=60",
+    ] {
+        let (name, value) = setting.split_once('=').unwrap();
+        assert!(
+            all.contains(&(name.to_string(), value.to_string())),
+            "{setting} in {all:?}"
+        );
+    }
+
+    client.send(&with_trailer(OP_CONFIG, 0, b"no_such_tunable"));
+    assert_eq!(client.u8(), STATUS_NOT_FOUND);
+}
+
+#[test]
+fn config_file_listeners_serve_the_same_store() {
+    let temp = std::env::temp_dir();
+    let admin = temp.join(format!("map8x32-admin-{}.sock", process::id()));
+    let config = temp.join(format!("map8x32-listeners-{}.toml", process::id()));
+    fs::write(
+        &config,
+        format!(
+            "[[listeners]]\nsocket = {:?}\nmode = 0o600\n",
+            admin.to_str().unwrap()
+        ),
+    )
+    .unwrap();
+    let server = Server::start(&["--config", config.to_str().unwrap()]);
+
+    // Answered only once every listener is bound.
+    let mut client = server.connect();
+    assert_eq!(client.get(4), None);
+    let mode = fs::metadata(&admin).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    let mut admin_client = Client(UnixStream::connect(&admin).unwrap());
+    assert_eq!(admin_client.status(OP_SET, 4, 40), STATUS_OK);
+    assert_eq!(client.get(4), Some(vec![40]));
+
+    drop(server);
+    let _ = fs::remove_file(&admin);
+    let _ = fs::remove_file(&config);
+}
+
+#[test]
+fn priority_classes_stop_at_the_configured_one() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    assert_eq!(
+        client.status(OP_PRIORITY, PRIORITY_HIGH, 0),
+        STATUS_UNAUTHORIZED
+    );
+    assert_eq!(client.status(OP_PRIORITY, PRIORITY_LOW, 0), STATUS_OK);
+    assert_eq!(client.status(OP_SET, 1, 10), STATUS_OK);
+    assert_eq!(client.status(OP_PRIORITY, PRIORITY_NORMAL, 0), STATUS_OK);
+    assert_eq!(client.status(OP_PRIORITY, 3, 0), STATUS_BAD_REQUEST);
+    assert_eq!(client.get(1), Some(vec![10]));
+    drop(server);
+
+    let config = std::env::temp_dir().join(format!("map8x32-priorities-{}.toml", process::id()));
+    fs::write(&config, "").unwrap();
+    let uid = fs::metadata(&config).unwrap().uid();
+    fs::write(
+        &config,
+        format!("[[priorities]]\nuid = {uid}\npriority = \"high\"\n"),
+    )
+    .unwrap();
+    let server = Server::start(&["--config", config.to_str().unwrap()]);
+    let mut client = server.connect();
+    assert_eq!(client.status(OP_PRIORITY, PRIORITY_LOW, 0), STATUS_OK);
+    assert_eq!(client.status(OP_PRIORITY, PRIORITY_HIGH, 0), STATUS_OK);
+    assert_eq!(client.status(OP_SET, 1, 10), STATUS_OK);
+    assert_eq!(client.get(1), Some(vec![10]));
+
+    drop(server);
+    let _ = fs::remove_file(&config);
+}
+
+#[test]
+fn high_priority_commands_skip_the_low_priority_queue() {
+    let config = std::env::temp_dir().join(format!("map8x32-saturated-{}.toml", process::id()));
+    fs::write(&config, "").unwrap();
+    let uid = fs::metadata(&config).unwrap().uid();
+    fs::write(
+        &config,
+        format!("[[priorities]]\nuid = {uid}\npriority = \"high\"\n"),
+    )
+    .unwrap();
+    let server = Server::start(&["--config", config.to_str().unwrap()]);
+    let mut client = server.connect();
+    let mut values = Vec::with_capacity(8 + MAX_REPLACE_VALUES as usize * 4);
+    values.extend_from_slice(&0u64.to_le_bytes());
+    for value in 0..MAX_REPLACE_VALUES {
+        values.extend_from_slice(&value.to_le_bytes());
+    }
+    for key in [1, 2] {
+        let mut request = frame(OP_REPLACE_IF, key, MAX_REPLACE_VALUES);
+        request.extend_from_slice(&values);
+        client.send(&request);
+        assert_eq!(client.u8(), STATUS_OK);
+    }
+    // How many COMPAREs have run, from INFO's latencies.
+    let compared = |client: &mut Client| {
+        assert_eq!(client.status(OP_INFO, 1, 0), STATUS_OK);
+        client.read(48);
+        for _ in 0..client.u8() {
+            let len = client.u8() as usize;
+            client.read(len + 8);
+        }
+        client.read(10);
+        assert_eq!(client.u32(), 0, "allocations");
+        client.read(24);
+        let mut compared = 0;
+        for _ in 0..client.u8() {
+            let op = client.u8();
+            let latencies: Vec<u64> = (0..12).map(|_| client.u64()).collect();
+            if op == OP_COMPARE {
+                compared = latencies[0];
+            }
+        }
+        compared
+    };
+
+    // Four low-priority connections each queue eight COMPAREs of the two
+    // keys on the one processor at once, while the high-priority one reads.
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                let mut low = server.connect();
+                assert_eq!(low.status(OP_PRIORITY, PRIORITY_LOW, 0), STATUS_OK);
+                low.send(&frame(OP_COMPARE, 1, 2).repeat(8));
+                for _ in 0..8 {
+                    assert_eq!(low.u8(), STATUS_OK);
+                    assert_eq!(low.values(), []);
+                }
+            });
+        }
+        sleep(Duration::from_millis(20));
+        let before = compared(&mut client);
+        assert_eq!(client.get(5), None);
+        let after = compared(&mut client);
+        assert!(after < 32, "answered before the queue drained: {after}");
+        // At most the COMPARE running when each high-priority request came
+        // in held it up; taking turns with the four would have let more by.
+        assert!(
+            after - before <= 2,
+            "{} COMPAREs ran between the reads",
+            after - before
+        );
+    });
+
+    drop(server);
+    let _ = fs::remove_file(&config);
+}
+
+#[test]
+fn datagram_sets_apply_without_answers() {
+    let path = std::env::temp_dir().join(format!("map8x32-datagram-{}.sock", process::id()));
+    let server = Server::start(&["--datagram", path.to_str().unwrap()]);
+    let mut client = server.connect();
+    assert_eq!(
+        client.get(3),
+        None,
+        "answered once the datagram socket is bound"
+    );
+
+    let sender = UnixDatagram::unbound().unwrap();
+    let datagram = [
+        frame(OP_SET, 3, 30),
+        frame(OP_GET, 3, 0),
+        frame(OP_SET, 3, 31),
+    ]
+    .concat();
+    sender.send_to(&datagram, &path).unwrap();
+    sender.send_to(&frame(OP_SET, 3, 32)[..4], &path).unwrap();
+    let started = std::time::Instant::now();
+    while client.get(3) != Some(vec![30, 31]) {
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "datagram SETs never applied"
+        );
+        sleep(Duration::from_millis(10));
+    }
+
+    drop(server);
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn multiplexed_requests_are_answered_by_id() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    assert_eq!(
+        client.status(OP_MULTIPLEX, 0, 0),
+        STATUS_OK,
+        "answered untagged"
+    );
+    let tagged = |id: u32, request: Vec<u8>| [id.to_le_bytes().to_vec(), request].concat();
+    client.send(
+        &[
+            tagged(7, frame(OP_SET, 8, 80)),
+            tagged(3, frame(OP_SET, 9, 90)),
+            tagged(5, frame(OP_MULTIPLEX, 0, 0)),
+        ]
+        .concat(),
+    );
+    let mut answers: Vec<(u32, u8)> = (0..3).map(|_| (client.u32(), client.u8())).collect();
+    answers.sort_unstable();
+    assert_eq!(
+        answers,
+        [(3, STATUS_OK), (5, STATUS_BAD_REQUEST), (7, STATUS_OK)]
+    );
+
+    client.send(&tagged(11, frame(OP_GET, 8, 0)));
+    assert_eq!(client.u32(), 11);
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.values(), [80]);
+    let mut select = tagged(12, frame(OP_SELECT, 1, 0));
+    select.extend(tagged(13, frame(OP_GET, 8, 0)));
+    client.send(&select);
+    assert_eq!((client.u32(), client.u8()), (12, STATUS_OK));
+    assert_eq!((client.u32(), client.u8()), (13, STATUS_NOT_FOUND));
+}
+
+#[test]
+fn cancel_abandons_reads_in_flight() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    assert_eq!(client.status(OP_SET, 1, 10), STATUS_OK);
+    assert_eq!(
+        client.status(OP_CANCEL, 0, 1),
+        STATUS_NOT_FOUND,
+        "nothing runs"
+    );
+    assert_eq!(client.status(OP_MULTIPLEX, 0, 0), STATUS_OK);
+    let tagged = |id: u32, request: Vec<u8>| [id.to_le_bytes().to_vec(), request].concat();
+
+    // Both arrive together, so the CANCEL is read before LIST_ALL runs.
+    client.send(
+        &[
+            tagged(1, frame(OP_LIST_ALL, 0, 0)),
+            tagged(2, frame(OP_CANCEL, 0, 1)),
+        ]
+        .concat(),
+    );
+    let mut answers: Vec<(u32, u8)> = (0..2).map(|_| (client.u32(), client.u8())).collect();
+    answers.sort_unstable();
+    assert_eq!(answers, [(1, STATUS_CANCELLED), (2, STATUS_OK)]);
+
+    client.send(
+        &[
+            tagged(3, frame(OP_SET, 1, 11)),
+            tagged(4, frame(OP_CANCEL, 0, 3)),
+        ]
+        .concat(),
+    );
+    let mut answers: Vec<(u32, u8)> = (0..2).map(|_| (client.u32(), client.u8())).collect();
+    answers.sort_unstable();
+    assert_eq!(
+        answers,
+        [(3, STATUS_OK), (4, STATUS_NOT_FOUND)],
+        "writes cannot be cancelled"
+    );
+    client.send(&tagged(5, frame(OP_GET, 1, 0)));
+    assert_eq!((client.u32(), client.u8()), (5, STATUS_OK));
+    assert_eq!(client.values(), [10, 11]);
+}
+
+#[test]
+fn chunked_responses_split_at_the_frame_limit() {
+    let server = Server::start(&["--max-frame-bytes", "8"]);
+    let mut client = server.connect();
+    for value in 10..16 {
+        assert_eq!(client.status(OP_SET, 1, value), STATUS_OK);
+    }
+    assert_eq!(
+        client.status(OP_CHUNKED, 0, 1000),
+        STATUS_OK,
+        "answered whole"
+    );
+    let chunks = |client: &mut Client| {
+        let mut chunks = Vec::new();
+        let mut body = Vec::new();
+        loop {
+            let header = client.u32();
+            let len = header & !CHUNK_CONTINUED;
+            chunks.push(len);
+            body.extend(client.read(len as usize));
+            if header & CHUNK_CONTINUED == 0 {
+                return (chunks, body);
+            }
+        }
+    };
+
+    // The status, the count and six values: 29 bytes, capped at the server's 8.
+    client.send(&frame(OP_GET, 1, 0));
+    let (lens, body) = chunks(&mut client);
+    assert_eq!(lens, [8, 8, 8, 5]);
+    assert_eq!(body[0], STATUS_OK);
+    assert_eq!(body[1..5], 6u32.to_le_bytes());
+    client.send(&frame(OP_CHUNKED, 0, 0));
+    assert_eq!(chunks(&mut client), (vec![1], vec![STATUS_BAD_REQUEST]));
+
+    // Multiplexed, each chunk carries its request's ID, and answers take turns.
+    client.send(&frame(OP_MULTIPLEX, 0, 0));
+    assert_eq!(chunks(&mut client), (vec![1], vec![STATUS_OK]));
+    let tagged = |id: u32, request: Vec<u8>| [id.to_le_bytes().to_vec(), request].concat();
+    client.send(
+        &[
+            tagged(1, frame(OP_GET, 1, 0)),
+            tagged(2, frame(OP_GET, 1, 0)),
+        ]
+        .concat(),
+    );
+    let mut bodies = [Vec::new(), Vec::new()];
+    let mut finished = 0;
+    while finished < 2 {
+        let id = client.u32() as usize;
+        let header = client.u32();
+        bodies[id - 1].extend(client.read((header & !CHUNK_CONTINUED) as usize));
+        finished += usize::from(header & CHUNK_CONTINUED == 0);
+    }
+    assert_eq!(bodies, [body.clone(), body]);
+}
+
+#[cfg(feature = "shm-mirror")]
+#[test]
+fn shm_mirror_follows_writes() {
+    use map8x32_protocol::shm::{Mirrored, ShmMirror};
+    use std::sync::atomic::AtomicU32;
+
+    let path = std::env::temp_dir().join(format!("map8x32-mirror-{}", process::id()));
+    let mut server = Server::start(&[
+        "--shm-mirror",
+        path.to_str().unwrap(),
+        "--shm-values-per-key",
+        "2",
+    ]);
+    let mut client = server.connect();
+    assert_eq!(client.status(OP_SET, 4, 40), STATUS_OK);
+    for value in [50, 51, 52] {
+        assert_eq!(client.status(OP_SET, 5, value), STATUS_OK);
+    }
+    let mirrored = |key| {
+        let words: Vec<AtomicU32> = fs::read(&path)
+            .unwrap()
+            .chunks_exact(4)
+            .map(|word| AtomicU32::new(u32::from_ne_bytes(word.try_into().unwrap())))
+            .collect();
+        ShmMirror::open(&words).unwrap().read(key)
+    };
+    let started = std::time::Instant::now();
+    while mirrored(5) != Mirrored::TooLarge {
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "the mirror never caught up"
+        );
+        sleep(Duration::from_millis(10));
+    }
+    assert_eq!(mirrored(4), Mirrored::Values(vec![40]));
+    assert_eq!(mirrored(6), Mirrored::NotFound);
+
+    assert!(server.terminate());
+    assert!(!path.exists(), "the mirror is removed on shutdown");
+}
+
+#[cfg(feature = "handoff")]
+#[test]
+fn handoff_passes_the_listeners_and_data_to_the_next_server() {
+    let handoff = std::env::temp_dir().join(format!("map8x32-handoff-{}", process::id()));
+    let mut old = Server::start(&["--handoff", handoff.to_str().unwrap()]);
+    let mut client = old.connect();
+    assert_eq!(client.status(OP_SET, 3, 30), STATUS_OK);
+
+    let mut new = old.successor();
+    assert!(old.wait(), "the old server exits once it hands over");
+    assert!(client.closed());
+    let mut client = new.connect();
+    assert_eq!(client.get(3), Some(vec![30]));
+    assert_eq!(client.status(OP_SET, 3, 31), STATUS_OK);
+
+    assert!(new.terminate());
+    assert!(
+        !handoff.exists(),
+        "the handoff socket is removed on shutdown"
+    );
+}
+
+#[test]
+fn change_sink_appends_each_change_to_a_log() {
+    let path = std::env::temp_dir().join(format!("map8x32-changes-{}.log", process::id()));
+    let _ = fs::remove_file(&path);
+    let target = format!("log:{}", path.display());
+    let mut server = Server::start(&["--change-sink", &target]);
+    let logged = |lines: usize| -> Vec<String> {
+        let started = std::time::Instant::now();
+        loop {
+            let text = fs::read_to_string(&path).unwrap_or_default();
+            if text.lines().count() >= lines {
+                return text.lines().map(str::to_string).collect();
+            }
+            assert!(
+                started.elapsed() < Duration::from_secs(5),
+                "the sink never caught up"
+            );
+            sleep(Duration::from_millis(10));
+        }
+    };
+    // Every key of both databases is sent once at startup, then each change.
+    assert_eq!(logged(512).len(), 512);
+    let mut client = server.connect();
+    for value in [1, 2] {
+        assert_eq!(client.status(OP_SET, 7, value), STATUS_OK);
+    }
+    assert_eq!(client.status(OP_DELETE_BY_KEY, 7, 0), STATUS_OK);
+    let changes = logged(515);
+    assert!(changes[512].starts_with(r#"{"offset":512,"db":0,"key":7,"#));
+    assert_eq!(
+        changes[514],
+        r#"{"offset":514,"db":0,"key":7,"version":3,"values":null}"#
+    );
+
+    // Offsets carry on from where the log ends.
+    server.kill();
+    server.restart();
+    let changes = logged(515 + 512);
+    assert!(changes[515].starts_with(r#"{"offset":515,"#));
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn sync_full_is_checksummed() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    assert_eq!(client.status(OP_SET, 5, 50), STATUS_OK);
+    assert_eq!(client.status(OP_SET, 1, 10), STATUS_OK);
+    assert_eq!(client.status(OP_SYNC_FULL, 0, 0), STATUS_OK);
+    let len = client.u32() as usize;
+    let body = client.read(len);
+    assert_eq!(client.u32(), crc32(&body));
+    assert_eq!(u32::from_le_bytes(body[8..12].try_into().unwrap()), 2);
+    let expected = [
+        &[1][..],
+        &1u32.to_le_bytes(),
+        &10u32.to_le_bytes(),
+        &[5],
+        &1u32.to_le_bytes(),
+        &50u32.to_le_bytes(),
+    ]
+    .concat();
+    assert_eq!(body[12..], expected, "keys in order");
+}
+
+#[test]
+fn select_switches_databases() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    assert_eq!(client.status(OP_SET, 1, 1), STATUS_OK);
+    assert_eq!(client.status(OP_SELECT, 1, 0), STATUS_OK);
+    assert_eq!(client.get(1), None);
+    assert_eq!(client.status(OP_SET, 1, 2), STATUS_OK);
+    assert_eq!(client.status(OP_SELECT, 2, 0), STATUS_BAD_REQUEST);
+    assert_eq!(client.get(1), Some(vec![2]), "still on database 1");
+    assert_eq!(client.status(OP_SELECT, 0, 0), STATUS_OK);
+    assert_eq!(client.get(1), Some(vec![1]));
+}
+
+#[test]
+fn a_full_database_leaves_the_others_writable() {
+    let server = Server::start(&["--db-max-keys", "2", "--db-max-values", "4"]);
+    let mut client = server.connect();
+    for (key, value) in [(1, 1), (1, 2), (2, 3)] {
+        assert_eq!(client.status(OP_SET, key, value), STATUS_OK);
+    }
+    assert_eq!(
+        client.status(OP_SET, 3, 9),
+        STATUS_CAPACITY_EXCEEDED,
+        "a third key"
+    );
+    assert_eq!(client.status(OP_SET, 2, 4), STATUS_OK);
+    assert_eq!(
+        client.status(OP_SET, 2, 5),
+        STATUS_CAPACITY_EXCEEDED,
+        "a fifth value"
+    );
+    let mut request = frame(OP_PUSH, 1, 1);
+    request.extend_from_slice(&6u32.to_le_bytes());
+    client.send(&request);
+    assert_eq!(
+        client.u8(),
+        STATUS_CAPACITY_EXCEEDED,
+        "a pushed fifth value"
+    );
+
+    assert_eq!(client.status(OP_SELECT, 1, 0), STATUS_OK);
+    for (key, value) in [(1, 10), (2, 20), (2, 21)] {
+        assert_eq!(client.status(OP_SET, key, value), STATUS_OK);
+    }
+    assert_eq!(client.get(2), Some(vec![20, 21]));
+
+    assert_eq!(client.status(OP_SELECT, 0, 0), STATUS_OK);
+    assert_eq!(client.get(3), None);
+    assert_eq!(client.status(OP_DELETE_BY_KEY, 1, 0), STATUS_OK);
+    assert_eq!(
+        client.status(OP_SET, 3, 9),
+        STATUS_OK,
+        "room again once a key is gone"
+    );
+
+    // Keys, values, refusals and limits from INFO.
+    let info = |client: &mut Client| {
+        assert_eq!(client.status(OP_INFO, 0, 0), STATUS_OK);
+        client.read(8);
+        let counts = (client.u64(), client.u64());
+        client.read(24);
+        for _ in 0..client.u8() {
+            let len = client.u8() as usize;
+            client.read(len + 8);
+        }
+        client.read(10);
+        assert_eq!(client.u32(), 0, "allocations");
+        (counts, client.u64(), client.u64(), client.u64())
+    };
+    assert_eq!(info(&mut client), ((2, 3), 3, 2, 4));
+    assert_eq!(client.status(OP_SELECT, 1, 0), STATUS_OK);
+    assert_eq!(info(&mut client), ((2, 3), 0, 2, 4));
+}
+
+#[test]
+fn typed_values() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    let big = u64::MAX - 1;
+    client.send(&with_trailer(
+        OP_SET_TYPED,
+        1,
+        &[&[1][..], &big.to_le_bytes()].concat(),
+    ));
+    assert_eq!(client.u8(), STATUS_OK);
+    client.send(&with_trailer(OP_SET_TYPED, 2, b"\x02hello"));
+    assert_eq!(client.u8(), STATUS_OK);
+    client.send(&with_trailer(OP_SET_TYPED, 2, &[0, 1, 0, 0, 0]));
+    assert_eq!(client.u8(), STATUS_CONFLICT, "a key holds one type");
+
+    assert_eq!(client.status(OP_GET_TYPED, 1, 0), STATUS_OK);
+    assert_eq!(client.u8(), 1);
+    assert_eq!(client.u32(), 1);
+    assert_eq!(client.u64(), big);
+    assert_eq!(client.status(OP_GET_TYPED, 2, 0), STATUS_OK);
+    assert_eq!(
+        client.read(6),
+        [&[2][..], &1u32.to_le_bytes(), &[5]].concat()
+    );
+    assert_eq!(client.read(5), b"hello");
+}
+
+#[test]
+fn sorted_keys_and_ranges() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    for value in [30, 10, 20, 10, 40] {
+        assert_eq!(client.status(OP_SET_SORTED, 8, value), STATUS_OK);
+    }
+    assert_eq!(client.get(8), Some(vec![10, 10, 20, 30, 40]));
+    let range = |min: u32, max: u32| {
+        [
+            frame(OP_GET_SORTED_RANGE, 8, min),
+            max.to_le_bytes().to_vec(),
+        ]
+        .concat()
+    };
+    client.send(&range(15, 30));
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.values(), [20, 30]);
+    client.send(&range(41, 50));
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.values(), []);
+
+    // A plain SET appends out of order; the range still comes back sorted.
+    assert_eq!(client.status(OP_SET, 8, 25), STATUS_OK);
+    assert_eq!(client.status(OP_SET, 8, 5), STATUS_OK);
+    assert_eq!(client.status(OP_SET_SORTED, 8, 35), STATUS_OK);
+    assert_eq!(client.get(8), Some(vec![10, 10, 20, 30, 40, 25, 5, 35]));
+    client.send(&range(5, 30));
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.values(), [5, 10, 10, 20, 25, 30]);
+    client.send(&range(26, 36));
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.values(), [30, 35]);
+}
+
+#[test]
+fn get_filter_returns_values_in_range_in_stored_order() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    for value in [30, 10, 20, 10, 40] {
+        assert_eq!(client.status(OP_SET, 8, value), STATUS_OK);
+    }
+    let filter = |key: u8, min: u32, max: u32| {
+        [frame(OP_GET_FILTER, key, min), max.to_le_bytes().to_vec()].concat()
+    };
+    client.send(&filter(8, 10, 30));
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.values(), [30, 10, 20, 10]);
+    client.send(&filter(8, 41, 50));
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.values(), []);
+    client.send(&filter(9, 0, u32::MAX));
+    assert_eq!(client.u8(), STATUS_NOT_FOUND);
+}
+
+#[test]
+fn aggregates_summarize_a_keys_values() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    for value in [u32::MAX, 1, 6] {
+        assert_eq!(client.status(OP_SET, 5, value), STATUS_OK);
+    }
+    let mut aggregate = |op| {
+        assert_eq!(client.status(op, 5, 0), STATUS_OK);
+        client.u64()
+    };
+    assert_eq!(aggregate(OP_SUM), u64::from(u32::MAX) + 7);
+    assert_eq!(aggregate(OP_MIN), 1);
+    assert_eq!(aggregate(OP_MAX), u32::MAX.into());
+    assert_eq!(aggregate(OP_COUNT), 3);
+    let mean = (f64::from(u32::MAX) + 7.0) / 3.0;
+    assert_eq!(f64::from_bits(aggregate(OP_AVG)), mean);
+    assert_eq!(client.status(OP_SUM, 6, 0), STATUS_NOT_FOUND);
+
+    client.send(&with_trailer(OP_SET_TYPED, 6, &[2, b'x']));
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.status(OP_AVG, 6, 0), STATUS_CONFLICT);
+}
+
+#[test]
+fn timed_values_roll_up_into_window_summaries() {
+    let config = std::env::temp_dir().join(format!("map8x32-rollups-{}.toml", process::id()));
+    fs::write(
+        &config,
+        "[[rollups]]\nfirst_key = 220\nlast_key = 229\nwindow_ms = 1000\n",
+    )
+    .unwrap();
+    let mut server = Server::start(&[
+        "--config",
+        config.to_str().unwrap(),
+        "--expiry-sweep-ms",
+        "50",
+    ]);
+    let mut client = server.connect();
+    let timed = |key: u8, value: u32, at_ms: u64| {
+        [
+            frame(OP_SET_TIMED, key, value),
+            at_ms.to_le_bytes().to_vec(),
+        ]
+        .concat()
+    };
+    for (key, value, at_ms) in [
+        (220, 4, 5_000),
+        (220, 6, 5_500),
+        (220, 1, 7_200),
+        (220, 9, 0),
+        (3, 2, 5_000),
+    ] {
+        client.send(&timed(key, value, at_ms));
+        assert_eq!(client.u8(), STATUS_OK);
+    }
+    assert_eq!(client.status(OP_SET, 220, 1), STATUS_CONFLICT);
+    assert_eq!(client.status(OP_GET_TYPED, 220, 0), STATUS_CONFLICT);
+    sleep(Duration::from_millis(300));
+
+    let summary = |start_ms, count, sum, min, max| {
+        timed::Sample::Summary(timed::Summary {
+            start_ms,
+            count,
+            sum,
+            min,
+            max,
+        })
+    };
+    let rolled = |client: &mut Client| {
+        let samples = timed::decode(&client.get(220).unwrap()).unwrap();
+        assert!(matches!(samples[2], timed::Sample::Value { value: 9, .. }));
+        samples[..2].to_vec()
+    };
+    assert_eq!(
+        rolled(&mut client),
+        [summary(5_000, 2, 10, 4, 6), summary(7_000, 1, 1, 1, 1)]
+    );
+    assert_eq!(client.get(3), Some(timed::value_words(5_000, 2).to_vec()));
+
+    server.kill();
+    server.restart();
+    let mut client = server.connect();
+    assert_eq!(
+        rolled(&mut client),
+        [summary(5_000, 2, 10, 4, 6), summary(7_000, 1, 1, 1, 1)]
+    );
+    drop(server);
+    let _ = fs::remove_file(&config);
+}
+
+#[test]
+fn expiring_values_vanish_after_their_ttl() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    let expiring = |value: u32, ttl_ms: u32| {
+        [
+            frame(OP_SET_EXPIRING, 3, value),
+            ttl_ms.to_le_bytes().to_vec(),
+        ]
+        .concat()
+    };
+    client.send(&expiring(1, 100));
+    assert_eq!(client.u8(), STATUS_OK);
+    client.send(&expiring(2, 60_000));
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.get(3), Some(vec![1, 2]));
+    sleep(Duration::from_millis(250));
+    assert_eq!(client.get(3), Some(vec![2]));
+}
+
+#[test]
+fn event_subscribers_hear_of_expiries_and_delete_all() {
+    let server = Server::start(&["--expiry-sweep-ms", "20", "--delete-all-token", "7"]);
+    let mut subscriber = server.connect();
+    let wanted = 1 << EVENT_EXPIRED | 1 << EVENT_DELETE_ALL;
+    assert_eq!(subscriber.status(OP_SUBSCRIBE_EVENTS, 0, wanted), STATUS_OK);
+    assert_eq!(subscriber.u32(), 0);
+
+    let mut client = server.connect();
+    client.send(&[frame(OP_SET_EXPIRING, 3, 1), 50u32.to_le_bytes().to_vec()].concat());
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.status(OP_SET, 3, 2), STATUS_OK);
+    let mut event = || {
+        assert_eq!(subscriber.u8(), STATUS_OK);
+        assert_eq!(subscriber.u32(), 1);
+        (subscriber.u8(), subscriber.u8(), subscriber.u64())
+    };
+    assert_eq!(event(), (EVENT_EXPIRED, 3, 1));
+    assert_eq!(client.get(3), Some(vec![2]));
+
+    assert_eq!(client.status(OP_DELETE_ALL, 0, 7), STATUS_OK);
+    assert_eq!(event(), (EVENT_DELETE_ALL, 0, 0));
+
+    let mut malformed = server.connect();
+    assert_eq!(
+        malformed.status(OP_SUBSCRIBE_EVENTS, 0, 0),
+        STATUS_BAD_REQUEST
+    );
+}
+
+#[test]
+fn ttl_reports_and_persist_clears_a_keys_expiry() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    let expiring = |value: u32, ttl_ms: u32| {
+        [
+            frame(OP_SET_EXPIRING, 3, value),
+            ttl_ms.to_le_bytes().to_vec(),
+        ]
+        .concat()
+    };
+    assert_eq!(client.status(OP_TTL, 3, 0), STATUS_NOT_FOUND);
+    assert_eq!(client.status(OP_PERSIST, 3, 0), STATUS_NOT_FOUND);
+    client.send(&expiring(1, 100));
+    assert_eq!(client.u8(), STATUS_OK);
+    client.send(&expiring(2, 60_000));
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.status(OP_TTL, 3, 0), STATUS_OK);
+    let ttl_ms = client.u64();
+    assert!((59_000..=60_000).contains(&ttl_ms), "ttl {ttl_ms}");
+
+    sleep(Duration::from_millis(250));
+    assert_eq!(client.status(OP_PERSIST, 3, 0), STATUS_OK);
+    assert_eq!(client.status(OP_TTL, 3, 0), STATUS_OK);
+    assert_eq!(client.u64(), 0);
+    assert_eq!(client.get(3), Some(vec![2]));
+
+    assert_eq!(client.status(OP_SET, 4, 1), STATUS_OK);
+    assert_eq!(client.status(OP_TTL, 4, 0), STATUS_OK);
+    assert_eq!(client.u64(), 0);
+}
+
+#[test]
+fn touch_counts_an_access_and_resets_expiring_values() {
+    let mut server = Server::start(&[]);
+    let mut client = server.connect();
+    for value in [1, 2] {
+        client.send(
+            &[
+                frame(OP_SET_EXPIRING, 3, value),
+                200u32.to_le_bytes().to_vec(),
+            ]
+            .concat(),
+        );
+        assert_eq!(client.u8(), STATUS_OK);
+    }
+    assert_eq!(client.status(OP_TOUCH, 9, 0), STATUS_NOT_FOUND);
+    assert_eq!(client.status(OP_TOUCH, 3, 0), STATUS_OK);
+    assert_eq!(client.status(OP_KEYSTATS, 3, 0), STATUS_OK);
+    let [hits, misses] = [client.u64(), client.u64()];
+    assert_eq!([hits, misses], [0, 0], "a touch is not a read");
+    assert_eq!(client.u64(), 2);
+    assert!(client.u64() > 0, "the access is recorded");
+
+    assert_eq!(client.status(OP_TOUCH, 3, 60_000), STATUS_OK);
+    sleep(Duration::from_millis(300));
+    assert_eq!(client.get(3), Some(vec![1, 2]));
+
+    // The new expiry is logged, so it outlives a restart.
+    server.kill();
+    server.restart();
+    let mut client = server.connect();
+    assert_eq!(client.status(OP_TTL, 3, 0), STATUS_OK);
+    let ttl_ms = client.u64();
+    assert!((55_000..=60_000).contains(&ttl_ms), "ttl {ttl_ms}");
+}
+
+#[test]
+fn dump_and_restore() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    assert_eq!(client.status(OP_SET, 1, 1), STATUS_OK);
+    assert_eq!(client.status(OP_SET, 2, 2), STATUS_OK);
+    client.send(&with_trailer(OP_DUMP, 0, b"backup"));
+    assert_eq!(client.u8(), STATUS_OK);
+    assert!(server.dir.join("dumps/backup").exists());
+    client.send(&with_trailer(OP_DUMP, 0, b"../escape"));
+    assert_eq!(client.u8(), STATUS_BAD_REQUEST);
+
+    assert_eq!(client.status(OP_SET, 3, 3), STATUS_OK);
+    client.send(&with_trailer(OP_RESTORE, 0, b"backup"));
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.list(), [(1, vec![1]), (2, vec![2])], "replaced");
+    client.send(&with_trailer(OP_RESTORE, 1, b"backup"));
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(
+        client.list(),
+        [(1, vec![1, 1]), (2, vec![2, 2])],
+        "appended"
+    );
+    client.send(&with_trailer(OP_RESTORE, 0, b"missing"));
+    assert_eq!(client.u8(), STATUS_NOT_FOUND);
+}
+
+#[test]
+fn unknown_opcodes_close_the_connection() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    client.send(&[frame(200, 1, 1), frame(OP_SET, 1, 1)].concat());
+    assert_eq!(client.u8(), STATUS_BAD_REQUEST);
+    assert!(client.closed());
+    assert_eq!(server.connect().get(1), None);
+}
+
+#[test]
+fn half_closed_connections_are_answered_before_closing() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    client.send(
+        &[
+            frame(OP_SET, 1, 1),
+            frame(OP_SET, 1, 2),
+            frame(OP_GET, 1, 0),
+        ]
+        .concat(),
+    );
+    client.0.shutdown(std::net::Shutdown::Write).unwrap();
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.u8(), STATUS_OK);
+    assert_eq!(client.values(), [1, 2]);
+    assert!(client.closed());
+}
+
+#[test]
+fn shutdown_closes_idle_connections() {
+    let mut server = Server::start(&[]);
+    let mut client = server.connect();
+    assert_eq!(client.status(OP_SET, 1, 1), STATUS_OK);
+    let started = std::time::Instant::now();
+    assert!(server.terminate());
+    assert!(
+        started.elapsed() < Duration::from_secs(3),
+        "waited out the grace period"
+    );
+    assert!(client.closed());
+}
+
+#[test]
+fn sigusr1_writes_a_diagnostic_report() {
+    let reports = std::env::temp_dir().join(format!("map8x32-diagnostics-{}", process::id()));
+    fs::create_dir_all(&reports).unwrap();
+    let server = Server::start(&["--diagnostics-dir", reports.to_str().unwrap()]);
+    let mut client = server.connect();
+    for value in 0..5 {
+        assert_eq!(client.status(OP_SET, 9, value), STATUS_OK);
+    }
+    assert_eq!(client.status(OP_SET, 3, 1), STATUS_OK);
+    server.signal("USR1");
+    let started = std::time::Instant::now();
+    let report = loop {
+        let written = fs::read_dir(&reports).unwrap().next();
+        if let Some(entry) = written {
+            let entry = entry.unwrap();
+            let name = entry.file_name().into_string().unwrap();
+            assert!(name.starts_with("map8x32-diagnostics-") && name.ends_with(".txt"));
+            // The file may still be being written.
+            sleep(Duration::from_millis(100));
+            break fs::read_to_string(entry.path()).unwrap();
+        }
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "no report written"
+        );
+        sleep(Duration::from_millis(20));
+    };
+    assert!(report.contains("alive tasks: "), "{report}");
+    assert!(report.contains("connections: 1 active"), "{report}");
+    assert!(report.contains("6 requests"), "{report}");
+    assert!(report.contains("keys: 2, values: 6"), "{report}");
+    assert!(report.contains("queued commands: 0"), "{report}");
+    let largest = report.find("    9: 5 values").expect(&report);
+    assert!(largest < report.find("    3: 1 values").expect(&report));
+    fs::remove_dir_all(&reports).unwrap();
+}
+
+#[test]
+fn unknown_opcodes_can_be_refused_without_closing() {
+    let server = Server::start(&["--on-protocol-error", "refuse"]);
+    let mut client = server.connect();
+    assert_eq!(client.status(0, 1, 1), STATUS_BAD_REQUEST);
+    assert_eq!(client.status(200, 1, 1), STATUS_BAD_REQUEST);
+    assert_eq!(client.status(OP_SET, 1, 1), STATUS_OK);
+}
+
+#[test]
+fn oversized_trailers_close_the_connection() {
+    let server = Server::start(&[]);
+    for oversized in [
+        frame(OP_REPLACE_IF, 1, MAX_REPLACE_VALUES + 1),
+        frame(OP_SET_TYPED, 1, 1 + MAX_BLOB_LEN as u32 + 1),
+        frame(OP_AUTH, 0, u32::MAX),
+    ] {
+        let mut client = server.connect();
+        assert_eq!(client.status(OP_SET, 1, 1), STATUS_OK);
+        client.send(&oversized);
+        assert_eq!(client.u8(), STATUS_PAYLOAD_TOO_LARGE);
+        assert!(client.closed());
+    }
+
+    let mut client = server.connect();
+    assert_eq!(
+        client.status(OP_SET, 1, 1),
+        STATUS_OK,
+        "other connections unaffected"
+    );
+}
+
+#[test]
+fn requests_over_the_limits_are_refused() {
+    let server = Server::start(&["--max-request-values", "2", "--max-request-bytes", "64"]);
+    let mut client = server.connect();
+    let replace = |values: &[u32]| {
+        let mut trailer = 0u64.to_le_bytes().to_vec();
+        for value in values {
+            trailer.extend(value.to_le_bytes());
+        }
+        [frame(OP_REPLACE_IF, 1, values.len() as u32), trailer].concat()
+    };
+    client.send(&replace(&[1, 2, 3]));
+    assert_eq!(client.u8(), STATUS_PAYLOAD_TOO_LARGE);
+    client.send(&replace(&[1, 2]));
+    assert_eq!(client.u8(), STATUS_OK);
+    let long_name = "d".repeat(60);
+    client.send(&with_trailer(OP_DUMP, 0, long_name.as_bytes()));
+    assert_eq!(client.u8(), STATUS_PAYLOAD_TOO_LARGE);
+    assert_eq!(client.get(1), Some(vec![1, 2]), "still served");
+}
+
+#[test]
+fn answers_wait_the_flush_delay_for_pipelined_requests() {
+    let server = Server::start(&["--flush-delay-us", "300000"]);
+    let mut client = server.connect();
+    client.send(&frame(OP_SET, 1, 1));
+    client
+        .0
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    assert!(client.0.read(&mut [0]).is_err(), "held for more requests");
+    client.send(&frame(OP_SET, 1, 2));
+    client.0.set_read_timeout(None).unwrap();
+    assert_eq!(client.read(2), [STATUS_OK, STATUS_OK]);
+
+    let server = Server::start(&["--flush-delay-us", "300000", "--flush-bytes", "1"]);
+    let mut client = server.connect();
+    client.send(&frame(OP_SET, 1, 1));
+    client
+        .0
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    assert_eq!(
+        client.u8(),
+        STATUS_OK,
+        "written once the flush size is reached"
+    );
+}
+
+#[test]
+fn stalled_frames_time_out() {
+    let server = Server::start(&["--frame-timeout-ms", "200"]);
+    let mut client = server.connect();
+    client.send(&frame(OP_SET, 1, 1)[..3]);
+    assert_eq!(client.u8(), STATUS_BAD_REQUEST);
+    assert!(client.closed());
+}
@@ -0,0 +1,262 @@
+//! Runs concurrent clients against a multi-threaded server, recording when
+//! each command was sent, when it was answered and what it answered, and
+//! checks the history is linearizable: that some order of the commands, each
+//! taking effect at one instant between being sent and answered, gives every
+//! answer recorded when applied to a `Vec<u32>` per key.
+//!
+//! Linearizability is local, so each key's history is checked on its own,
+//! with DELETE_ALL and LIST_ALL split into a delete or read of every key.
+//! The search is Wing and Gong's, remembering the states already tried from
+//! each set of commands taken so it does not retry them.
+//!
+//! Each run starts from a fresh seed; `MAP8X32_LINEARIZABILITY_SEED` runs
+//! one's commands again, though not with the same timing, and
+//! `MAP8X32_LINEARIZABILITY_ROUNDS` sets how many histories are checked
+//! (default 16).
+
+mod common;
+
+use common::{env_u64, Client, Rng, Server};
+use map8x32_protocol::*;
+use std::collections::HashSet;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_ROUNDS: u64 = 16;
+const CLIENTS: u64 = 4;
+const COMMANDS_PER_CLIENT: u32 = 40;
+/// The keys every command lands on, so clients keep meeting on them.
+const KEYS: u8 = 3;
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Set { key: u8, value: u32 },
+    Get { key: u8 },
+    Delete { key: u8 },
+    DeleteAll,
+    List,
+}
+
+impl Rng {
+    /// The `n`th command of `client`. SET values name the client and the
+    /// command, so no two SETs write the same value.
+    fn op(&mut self, client: u64, n: u32) -> Op {
+        let key = self.below(u64::from(KEYS)) as u8;
+        match self.below(20) {
+            0..=8 => Op::Set {
+                key,
+                value: ((client as u32) << 24) | n,
+            },
+            9..=13 => Op::Get { key },
+            14..=16 => Op::Delete { key },
+            17 => Op::DeleteAll,
+            _ => Op::List,
+        }
+    }
+}
+
+/// What a command did to one key, as its answer tells.
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Append(u32),
+    Read(Option<Vec<u32>>),
+    Remove { existed: bool },
+    Clear,
+}
+
+#[derive(Debug, Clone)]
+struct Event {
+    step: Step,
+    sent: Duration,
+    answered: Duration,
+}
+
+/// Runs `op`, returning what it did to each key it touched.
+fn run(client: &mut Client, op: Op) -> Vec<(u8, Step)> {
+    match op {
+        Op::Set { key, value } => {
+            assert_eq!(client.status(OP_SET, key, value), STATUS_OK);
+            vec![(key, Step::Append(value))]
+        }
+        Op::Get { key } => vec![(
+            key,
+            Step::Read(match client.status(OP_GET, key, 0) {
+                STATUS_OK => Some(client.values()),
+                STATUS_NOT_FOUND => None,
+                status => panic!("GET answered {status}"),
+            }),
+        )],
+        Op::Delete { key } => {
+            let existed = match client.status(OP_DELETE_BY_KEY, key, 0) {
+                STATUS_OK => true,
+                STATUS_NOT_FOUND => false,
+                status => panic!("DELETE_BY_KEY answered {status}"),
+            };
+            vec![(key, Step::Remove { existed })]
+        }
+        Op::DeleteAll => {
+            assert_eq!(client.status(OP_DELETE_ALL, 0, 1), STATUS_OK);
+            (0..KEYS).map(|key| (key, Step::Clear)).collect()
+        }
+        Op::List => {
+            assert_eq!(client.status(OP_LIST_ALL, 0, 0), STATUS_OK);
+            let count = client.u32();
+            let entries: Vec<_> = (0..count).map(|_| (client.u8(), client.values())).collect();
+            (0..KEYS)
+                .map(|key| {
+                    let values = entries.iter().find(|(listed, _)| *listed == key);
+                    (key, Step::Read(values.map(|(_, values)| values.clone())))
+                })
+                .collect()
+        }
+    }
+}
+
+/// Runs every client's commands at once from an empty store and returns
+/// the history, as each key saw it.
+fn record(server: &Server, seed: u64) -> Vec<(u8, Event)> {
+    assert_eq!(server.connect().status(OP_DELETE_ALL, 0, 1), STATUS_OK);
+    let start = Instant::now();
+    let barrier = Arc::new(Barrier::new(CLIENTS as usize));
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|id| {
+            let mut client = server.connect();
+            let mut rng = Rng(seed ^ id.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                let mut history = Vec::new();
+                for n in 0..COMMANDS_PER_CLIENT {
+                    let op = rng.op(id, n);
+                    let sent = start.elapsed();
+                    let steps = run(&mut client, op);
+                    let answered = start.elapsed();
+                    history.extend(steps.into_iter().map(|(key, step)| {
+                        (
+                            key,
+                            Event {
+                                step,
+                                sent,
+                                answered,
+                            },
+                        )
+                    }));
+                }
+                history
+            })
+        })
+        .collect();
+    clients
+        .into_iter()
+        .flat_map(|client| client.join().unwrap())
+        .collect()
+}
+
+/// `state` after `step`, or `None` if `step`'s answer cannot follow it.
+fn apply(state: &[u32], step: &Step) -> Option<Vec<u32>> {
+    match step {
+        Step::Append(value) => Some([state, &[*value]].concat()),
+        Step::Read(values) => {
+            let stored = (!state.is_empty()).then_some(state);
+            (values.as_deref() == stored).then(|| state.to_vec())
+        }
+        Step::Remove { existed } => (*existed != state.is_empty()).then(Vec::new),
+        Step::Clear => Some(Vec::new()),
+    }
+}
+
+/// Whether `events`, all on one key, can be put in an order that respects
+/// real time and takes the key from empty through every recorded answer.
+fn linearizable(events: &[Event]) -> bool {
+    assert!(events.len() <= 128, "{} events on one key", events.len());
+    let all = u128::MAX
+        .checked_shr(128 - events.len() as u32)
+        .unwrap_or(0);
+    search(events, 0, &[], all, &mut HashSet::new())
+}
+
+/// Tries each event that may take effect next after those in `taken`, which
+/// left the key holding `state`.
+fn search(
+    events: &[Event],
+    taken: u128,
+    state: &[u32],
+    all: u128,
+    tried: &mut HashSet<(u128, Vec<u32>)>,
+) -> bool {
+    if taken == all {
+        return true;
+    }
+    if !tried.insert((taken, state.to_vec())) {
+        return false;
+    }
+    let waiting = || (0..events.len()).filter(move |&i| taken & 1 << i == 0);
+    // Anything sent after a waiting event was answered must come after it.
+    let horizon = waiting().map(|i| events[i].answered).min().unwrap();
+    waiting()
+        .filter(|&i| events[i].sent <= horizon)
+        .any(|i| match apply(state, &events[i].step) {
+            Some(next) => search(events, taken | 1 << i, &next, all, tried),
+            None => false,
+        })
+}
+
+#[test]
+fn the_checker_rejects_stale_reads() {
+    let event = |step, sent, answered| Event {
+        step,
+        sent: Duration::from_nanos(sent),
+        answered: Duration::from_nanos(answered),
+    };
+    let stale = [
+        event(Step::Append(1), 0, 10),
+        event(Step::Read(None), 20, 30),
+    ];
+    assert!(!linearizable(&stale));
+    let overlapping = [
+        event(Step::Append(1), 0, 30),
+        event(Step::Read(None), 10, 20),
+        event(Step::Read(Some(vec![1])), 15, 40),
+    ];
+    assert!(linearizable(&overlapping));
+    let reordered = [
+        event(Step::Append(1), 0, 10),
+        event(Step::Append(2), 20, 30),
+        event(Step::Read(Some(vec![2, 1])), 40, 50),
+    ];
+    assert!(!linearizable(&reordered));
+}
+
+#[test]
+fn concurrent_histories_are_linearizable() {
+    let server = Server::start(&["--threads", "4", "--delete-all-token", "1"]);
+    let seeds: Vec<u64> = match env_u64("MAP8X32_LINEARIZABILITY_SEED") {
+        Some(seed) => vec![seed],
+        None => {
+            let base = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64;
+            let rounds = env_u64("MAP8X32_LINEARIZABILITY_ROUNDS").unwrap_or(DEFAULT_ROUNDS);
+            (0..rounds).map(|round| base.wrapping_add(round)).collect()
+        }
+    };
+    for seed in seeds {
+        let history = record(&server, seed);
+        for key in 0..KEYS {
+            let mut events: Vec<Event> = history
+                .iter()
+                .filter(|(on, _)| *on == key)
+                .map(|(_, event)| event.clone())
+                .collect();
+            if linearizable(&events) {
+                continue;
+            }
+            events.sort_by_key(|event| event.sent);
+            panic!(
+                "seed {seed} (MAP8X32_LINEARIZABILITY_SEED={seed} runs its commands again): key {key}'s history is not linearizable:\n{events:#?}"
+            );
+        }
+    }
+}
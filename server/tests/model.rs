@@ -0,0 +1,176 @@
+//! Runs random sequences of SET, GET, DELETE_BY_KEY, DELETE_ALL and LIST_ALL
+//! against the server and checks every answer against a model of the store,
+//! a `HashMap<u8, Vec<u32>>`. A failing sequence is shrunk to the fewest
+//! commands that still fail and reported with its seed.
+//!
+//! Each run starts from a fresh seed; `MAP8X32_MODEL_SEED` replays one and
+//! `MAP8X32_MODEL_CASES` sets how many sequences run (default 256).
+
+mod common;
+
+use common::{env_u64, Client, Rng, Server};
+use map8x32_protocol::*;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DEFAULT_CASES: u64 = 256;
+const MAX_SEQUENCE: u64 = 64;
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Set { key: u8, value: u32 },
+    Get { key: u8 },
+    Delete { key: u8 },
+    DeleteAll,
+    List,
+}
+
+impl Rng {
+    /// Mostly a handful of keys, so commands keep meeting the same ones,
+    /// with the occasional key from anywhere in the range.
+    fn key(&mut self) -> u8 {
+        match self.below(8) {
+            0 => self.next() as u8,
+            _ => self.below(6) as u8,
+        }
+    }
+
+    fn op(&mut self) -> Op {
+        match self.below(20) {
+            0..=8 => Op::Set {
+                key: self.key(),
+                value: match self.below(4) {
+                    0 => self.below(4) as u32,
+                    _ => self.next() as u32,
+                },
+            },
+            9..=12 => Op::Get { key: self.key() },
+            13..=15 => Op::Delete { key: self.key() },
+            16 => Op::DeleteAll,
+            _ => Op::List,
+        }
+    }
+
+    fn sequence(&mut self) -> Vec<Op> {
+        let len = self.below(MAX_SEQUENCE + 1);
+        (0..len).map(|_| self.op()).collect()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Answer {
+    Status(u8),
+    Values(Option<Vec<u32>>),
+    Entries(Vec<(u8, Vec<u32>)>),
+}
+
+/// What the model says `op` answers, applying it to `model`.
+fn expected(model: &mut HashMap<u8, Vec<u32>>, op: Op) -> Answer {
+    match op {
+        Op::Set { key, value } => {
+            model.entry(key).or_default().push(value);
+            Answer::Status(STATUS_OK)
+        }
+        Op::Get { key } => Answer::Values(model.get(&key).cloned()),
+        Op::Delete { key } => Answer::Status(match model.remove(&key) {
+            Some(_) => STATUS_OK,
+            None => STATUS_NOT_FOUND,
+        }),
+        Op::DeleteAll => {
+            model.clear();
+            Answer::Status(STATUS_OK)
+        }
+        Op::List => {
+            let mut entries: Vec<_> = model
+                .iter()
+                .map(|(&key, values)| (key, values.clone()))
+                .collect();
+            entries.sort_unstable();
+            Answer::Entries(entries)
+        }
+    }
+}
+
+/// What the server answers `op`. LIST_ALL's key order is unspecified, so
+/// its entries are put in key order; a key listed twice stays listed twice,
+/// and each key's values stay in the order the server sent them.
+fn observed(client: &mut Client, op: Op) -> Answer {
+    match op {
+        Op::Set { key, value } => Answer::Status(client.status(OP_SET, key, value)),
+        Op::Get { key } => match client.status(OP_GET, key, 0) {
+            STATUS_OK => Answer::Values(Some(client.values())),
+            STATUS_NOT_FOUND => Answer::Values(None),
+            status => Answer::Status(status),
+        },
+        Op::Delete { key } => Answer::Status(client.status(OP_DELETE_BY_KEY, key, 0)),
+        Op::DeleteAll => Answer::Status(client.status(OP_DELETE_ALL, 0, 1)),
+        Op::List => {
+            assert_eq!(client.status(OP_LIST_ALL, 0, 0), STATUS_OK);
+            let count = client.u32();
+            let mut entries: Vec<_> = (0..count).map(|_| (client.u8(), client.values())).collect();
+            entries.sort_by_key(|&(key, _)| key);
+            Answer::Entries(entries)
+        }
+    }
+}
+
+/// Runs `ops` from an empty store and returns the first one the server
+/// answered differently from the model, with the model's answer and the
+/// server's.
+fn divergence(client: &mut Client, ops: &[Op]) -> Option<(usize, Answer, Answer)> {
+    assert_eq!(client.status(OP_DELETE_ALL, 0, 1), STATUS_OK);
+    let mut model = HashMap::new();
+    ops.iter().enumerate().find_map(|(i, &op)| {
+        let want = expected(&mut model, op);
+        let got = observed(client, op);
+        (want != got).then_some((i, want, got))
+    })
+}
+
+/// Drops every command that still leaves the sequence failing without it.
+fn shrink(client: &mut Client, mut ops: Vec<Op>) -> Vec<Op> {
+    let mut i = 0;
+    while i < ops.len() {
+        let mut fewer = ops.clone();
+        fewer.remove(i);
+        match divergence(client, &fewer) {
+            Some((at, _, _)) => {
+                fewer.truncate(at + 1);
+                ops = fewer;
+            }
+            None => i += 1,
+        }
+    }
+    ops
+}
+
+#[test]
+fn command_sequences_match_the_model() {
+    let server = Server::start(&["--delete-all-token", "1"]);
+    let mut client = server.connect();
+    let seeds: Vec<u64> = match env_u64("MAP8X32_MODEL_SEED") {
+        Some(seed) => vec![seed],
+        None => {
+            let base = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64;
+            let cases = env_u64("MAP8X32_MODEL_CASES").unwrap_or(DEFAULT_CASES);
+            (0..cases).map(|case| base.wrapping_add(case)).collect()
+        }
+    };
+    for seed in seeds {
+        let ops = Rng(seed).sequence();
+        let Some((at, _, _)) = divergence(&mut client, &ops) else {
+            continue;
+        };
+        let ops = shrink(&mut client, ops[..=at].to_vec());
+        let (at, want, got) = divergence(&mut client, &ops).unwrap();
+        panic!(
+            "seed {seed} (MAP8X32_MODEL_SEED={seed} replays it), shrunk to {} commands:\n{:#?}\n{:?} answered {got:?}, the model expected {want:?}",
+            ops.len(),
+            ops,
+            ops[at]
+        );
+    }
+}